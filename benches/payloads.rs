@@ -0,0 +1,132 @@
+//! Allocations of the publish and receive paths at high message rates.
+//!
+//! Run with `cargo bench --bench payloads`. Every scenario publishes [`MESSAGES`] payloads of
+//! each size, waits for every subscriber to receive them, and reports per message the
+//! allocations made in the whole process, the bytes they asked for, those bytes as a multiple of
+//! the payload size, and the rate messages were received at.
+//!
+//! Payloads are shared as `Bytes` from the publisher to the subscribers, through the envelopes
+//! that leave them as they are, the message cache of gossipsub and the peers a message is
+//! forwarded to, so that the bytes an embedded node allocates per message stay close to the
+//! payload size whatever the number of subscribers. Between nodes, a payload is still copied into
+//! every frame sent and out of every frame received, and the transport encrypting and
+//! multiplexing those frames adds buffers of its own.
+
+use async_std::task;
+use rust_crdt::{
+    testing::{next_message, test_config, TestNetwork},
+    Client, Error, NodeConfig, Subscription,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// Messages published for every scenario and size.
+const MESSAGES: usize = 2000;
+
+/// Payload sizes, in bytes.
+const SIZES: &[usize] = &[256, 4096, 65536];
+
+/// Subscriptions of the embedded node to the topic.
+const SUBSCRIBERS: usize = 8;
+
+/// Nodes of the network, one publishing and the others subscribed.
+const NODES: usize = 3;
+
+const TOPIC: &str = "bench";
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting allocations and the bytes they ask for.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() -> Result<(), Error> {
+    println!(
+        "{:<28} {:>8} {:>12} {:>14} {:>10} {:>12}",
+        "scenario", "size", "allocs/msg", "bytes/msg", "x size", "msgs/s"
+    );
+    let client = rust_crdt::node::spawn(NodeConfig {
+        embedded: true,
+        ..NodeConfig::default()
+    })?;
+    for &size in SIZES {
+        let mut subscriptions = (0..SUBSCRIBERS)
+            .map(|_| client.subscribe(TOPIC))
+            .collect::<Result<Vec<_>, _>>()?;
+        let scenario = format!("embedded, {} subscribers", SUBSCRIBERS);
+        measure(&scenario, size, &client, &mut subscriptions)?;
+    }
+
+    let mut network = TestNetwork::with_config(NODES, test_config)?;
+    network.connect_all()?;
+    let mut subscriptions = task::block_on(network.join(TOPIC))?;
+    // The publisher does not deliver its own messages.
+    subscriptions.remove(0);
+    for &size in SIZES {
+        let scenario = format!("network, {} subscribed nodes", NODES - 1);
+        measure(&scenario, size, network.node(0), &mut subscriptions)?;
+    }
+    Ok(())
+}
+
+/// Publish [`MESSAGES`] payloads of `size` bytes through `client` and receive them on every one
+/// of `subscriptions`, printing what it took.
+fn measure(
+    scenario: &str,
+    size: usize,
+    client: &Client,
+    subscriptions: &mut [Subscription],
+) -> Result<(), Error> {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let started = Instant::now();
+    task::block_on(async {
+        for _ in 0..MESSAGES {
+            client.publish(TOPIC, vec![7; size])?;
+        }
+        for subscription in subscriptions.iter_mut() {
+            for _ in 0..MESSAGES {
+                next_message(subscription).await?;
+            }
+        }
+        Ok::<_, Error>(())
+    })?;
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+    let per_message = allocated as f64 / MESSAGES as f64;
+    println!(
+        "{:<28} {:>8} {:>12.1} {:>14.0} {:>10.2} {:>12.0}",
+        scenario,
+        size,
+        allocations as f64 / MESSAGES as f64,
+        per_message,
+        per_message / size as f64,
+        MESSAGES as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
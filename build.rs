@@ -0,0 +1,28 @@
+//! Generates the bindings of the PubSubAPI gRPC service of `src/pb/pubsub.proto`, and of the
+//! server reflection service of `src/pb/reflection.proto`, see `src/pb`. Their descriptor set is
+//! written to `pubsub.bin` in `OUT_DIR`, see `pb::FILE_DESCRIPTOR_SET`.
+fn main() {
+    println!("cargo:rerun-if-changed=src/pb/pubsub.proto");
+    println!("cargo:rerun-if-changed=src/pb/reflection.proto");
+    #[cfg(feature = "grpc")]
+    {
+        let protos = ["src/pb/pubsub.proto", "src/pb/reflection.proto"];
+        tonic_build::configure()
+            .build_client(cfg!(feature = "client"))
+            .compile(&protos, &["src/pb"])
+            .expect("failed to generate the PubSubAPI bindings");
+        let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+        let status = std::process::Command::new(prost_build::protoc())
+            .arg("--include_imports")
+            .arg(format!("--descriptor_set_out={}/pubsub.bin", out_dir))
+            .arg("-Isrc/pb")
+            .arg(format!("-I{}", prost_build::protoc_include().display()))
+            .args(protos)
+            .status()
+            .expect("failed to run protoc");
+        assert!(
+            status.success(),
+            "protoc failed to write the descriptor set"
+        );
+    }
+}
@@ -1,322 +1,990 @@
-use async_std::{io, task};
-use futures::{future, prelude::*};
-use libp2p::{
-    core::{either::EitherTransport, transport::upgrade::Version, StreamMuxer},
-    gossipsub::{self, Gossipsub, GossipsubConfigBuilder, GossipsubEvent},
-    identify::{Identify, IdentifyEvent},
-    identity,
-    multiaddr::Protocol,
-    ping::{self, Ping, PingConfig, PingEvent},
-    pnet::{PnetConfig, PreSharedKey},
-    secio::SecioConfig,
-    swarm::NetworkBehaviourEventProcess,
-    tcp::TcpConfig,
-    yamux::Config as YamuxConfig,
-    Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport,
+use async_std::{future, io, task};
+use data_encoding::BASE64;
+use futures::prelude::*;
+use libp2p::{gossipsub::protocol::MessageId, identity, pnet::PreSharedKey, PeerId};
+#[cfg(feature = "gateway")]
+use rust_crdt::gateway::admin;
+#[cfg(feature = "grpc")]
+use rust_crdt::gateway::grpc;
+#[cfg(feature = "tui")]
+use rust_crdt::top::{Endpoint, TopConfig};
+use rust_crdt::{
+    archive,
+    bench::{self, BenchConfig, Recorder},
+    daemon::{self, LogConfig, Pidfile},
+    floodsub::Router,
+    health::Readiness,
+    init::{self, InitOptions},
+    liveness::EvictionPolicy,
+    manifest::Manifest,
+    migration::{MigrationConfig, MigrationEvent},
+    node,
+    peerstore::AddressBook,
+    priority::UploadLimit,
+    relay::RelayServerConfig,
+    sidecar,
+    stats::{MeshPeer, MeshRole},
+    trace::{self, TraceConfig},
+    transport::{
+        get_ipfs_config, get_ipfs_path, get_psk, ipfs_daemon_running, keypair_from_seed,
+        parse_legacy_multiaddr, sidecar_keypair,
+    },
+    Client, NodeConfig, Subscription,
 };
 use std::{
-    env,
-    error::Error,
-    fs,
-    path::Path,
+    io::Read as _,
+    path::{Path, PathBuf},
     str::FromStr,
-    task::{Context, Poll},
     time::Duration,
 };
 
-/// Builds the transport that serves as a common ground for all connections.
-pub fn build_transport(
-    key_pair: identity::Keypair,
-    psk: Option<PreSharedKey>,
-) -> impl Transport<
-    Output = (
-        PeerId,
-        impl StreamMuxer<
-                OutboundSubstream = impl Send,
-                Substream = impl Send,
-                Error = impl Into<io::Error>,
-            > + Send
-            + Sync,
-    ),
-    Error = impl Error + Send,
-    Listener = impl Send,
-    Dial = impl Send,
-    ListenerUpgrade = impl Send,
-> + Clone {
-    let secio_config = SecioConfig::new(key_pair);
-    let yamux_config = YamuxConfig::default();
-
-    let base_transport = TcpConfig::new().nodelay(true);
-    let maybe_encrypted = match psk {
-        Some(psk) => EitherTransport::Left(
-            base_transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
-        ),
-        None => EitherTransport::Right(base_transport),
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+fn main() -> Result<(), Error> {
+    // `pub --daemonize [...]` runs the node in the background, with its process id in the file at
+    // PUBSUB_PIDFILE, IPFS_PATH/pub.pid by default, and its log in PUBSUB_LOG_DIR, IPFS_PATH/logs
+    // by default, rotated daily or past PUBSUB_LOG_MAX_BYTES and keeping PUBSUB_LOG_KEEP files.
+    // `pub status` tells whether it runs and `pub stop` stops it, see `daemon`
+    let pidfile = std::env::var_os("PUBSUB_PIDFILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| get_ipfs_path().join("pub.pid"));
+    let daemonized = std::env::var_os(daemon::DAEMON_ENV).is_some();
+
+    // On Windows, `pub service install [...]` registers the node as a service, started with the
+    // arguments given and the IPFS_PATH and PUBSUB_* variables set, and `pub service uninstall`
+    // removes it. Started by the service control manager, the node runs like a detached one until
+    // the manager stops it, see `daemon::service`
+    #[cfg(windows)]
+    if std::env::args().nth(1).as_deref() == Some("service") {
+        return service_command(std::env::args().skip(2));
+    }
+    #[cfg(windows)]
+    let service = if std::env::args().any(|arg| arg == daemon::SERVICE_ARG) {
+        Some(daemon::service::Service::start(
+            daemon::service::SERVICE_NAME,
+        )?)
+    } else {
+        None
+    };
+    #[cfg(windows)]
+    let daemonized = daemonized || service.is_some();
+    if std::env::args().nth(1).as_deref() == Some("status") {
+        return status_command(&pidfile);
+    }
+    if std::env::args().nth(1).as_deref() == Some("stop") {
+        return stop_command(&pidfile);
+    }
+    if !daemonized && std::env::args().any(|arg| arg == "--daemonize") {
+        if let Some(pid) = daemon::status(&pidfile)? {
+            return Err(format!("Already running as process {}", pid).into());
+        }
+        let args = std::env::args().skip(1).filter(|arg| arg != "--daemonize");
+        let pid = daemon::spawn_detached(args)?;
+        println!("Started process {}, pidfile {}", pid, pidfile.display());
+        return Ok(());
+    }
+    let _pidfile = if daemonized {
+        daemon::init_log(log_config()?)?;
+        Some(Pidfile::take(&pidfile)?)
+    } else {
+        env_logger::init();
+        None
     };
-    maybe_encrypted
-        .upgrade(Version::V1)
-        .authenticate(secio_config)
-        .multiplex(yamux_config)
-        .timeout(Duration::from_secs(20))
-}
 
-/// Get the current ipfs repo path, either from the IPFS_PATH environment variable or
-/// from the default $HOME/.ipfs
-fn get_ipfs_path() -> Box<Path> {
-    env::var("IPFS_PATH")
-        .map(|ipfs_path| Path::new(&ipfs_path).into())
-        .unwrap_or_else(|_| {
-            env::var("HOME")
-                .map(|home| Path::new(&home).join(".ipfs"))
-                .expect("could not determine home directory")
-                .into()
-        })
-}
+    // Remember peers across restarts in the file at PUBSUB_PEER_STORE, if set. `pub peers export`
+    // prints them and `pub peers import <file>` adds those of an export, to seed new nodes
+    let peer_store = std::env::var_os("PUBSUB_PEER_STORE").map(PathBuf::from);
+    if std::env::args().nth(1).as_deref() == Some("peers") {
+        return peers_command(peer_store, std::env::args().skip(2));
+    }
 
-/// Read the pre shared key file from the given ipfs directory
-fn get_psk(path: Box<Path>) -> std::io::Result<Option<String>> {
-    let swarm_key_file = path.join("swarm.key");
-    match fs::read_to_string(swarm_key_file) {
-        Ok(text) => Ok(Some(text)),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
-        Err(e) => Err(e),
+    // `pub archive export --topic <filter> --out <file.car>` writes the messages on the topics
+    // of the journal at PUBSUB_JOURNAL to an archive, and `pub archive import <file.car>` adds
+    // those of an archive to it, see `archive`
+    if std::env::args().nth(1).as_deref() == Some("archive") {
+        return archive_command(std::env::args().skip(2));
     }
-}
 
-/// for a multiaddr that ends with a peer id, this strips this suffix. Rust-libp2p
-/// only supports dialing to an address without providing the peer id.
-fn strip_peer_id(addr: &mut Multiaddr) {
-    let last = addr.pop();
-    match last {
-        Some(Protocol::P2p(peer_id)) => {
-            let mut addr = Multiaddr::empty();
-            addr.push(Protocol::P2p(peer_id));
-            println!(
-                "removing peer id {} so this address can be dialed by rust-libp2p",
-                addr
-            );
-        }
-        Some(other) => addr.push(other),
-        _ => {}
+    // `pub init [--port <port>] [--swarm-key <hex>] [--bootstrap <addr>]... [--announce <addr>]...
+    // [--force]` sets up the repo at IPFS_PATH, with an identity and the swarm key of a new
+    // private network or of the one given, and prints the command setting up the next node of the
+    // network, see `init`
+    if std::env::args().nth(1).as_deref() == Some("init") {
+        return init_command(std::env::args().skip(2));
     }
-}
 
-/// parse a legacy multiaddr (replace ipfs with p2p), and strip the peer id
-/// so it can be dialed by rust-libp2p
-fn parse_legacy_multiaddr(text: &str) -> Result<Multiaddr, Box<dyn Error>> {
-    let sanitized = text
-        .split('/')
-        .map(|part| if part == "ipfs" { "p2p" } else { part })
-        .collect::<Vec<_>>()
-        .join("/");
-    let mut res = Multiaddr::from_str(&sanitized)?;
-    strip_peer_id(&mut res);
-    Ok(res)
-}
+    // `pub top [<host:port> | <socket>]` monitors a node serving its admin endpoint there, on
+    // the unix socket of the runtime directory by default, with the token in PUBSUB_ADMIN_TOKEN
+    if std::env::args().nth(1).as_deref() == Some("top") {
+        return top_command(std::env::args().nth(2));
+    }
+
+    // `pub debug dump [<host:port> | <socket>]` prints the swarm state of a node serving its admin
+    // endpoint there, as `pub top` finds it, to attach to bug reports, see `debug`
+    if std::env::args().nth(1).as_deref() == Some("debug") {
+        return debug_command(std::env::args().skip(2));
+    }
+
+    // `pub descriptor > pubsub.bin` writes the descriptor set of the PubSubAPI gRPC service, to
+    // generate clients from in other languages, see `pb::FILE_DESCRIPTOR_SET`
+    #[cfg(feature = "grpc")]
+    if std::env::args().nth(1).as_deref() == Some("descriptor") {
+        use std::io::Write as _;
+        std::io::stdout().write_all(rust_crdt::pb::FILE_DESCRIPTOR_SET)?;
+        return Ok(());
+    }
+
+    // `pub publish <topic> [--file <path> | --base64 <data>] [<peer addr>...]` publishes a single
+    // payload, read from stdin without either option, once a peer subscribed to the topic is found
+    let mut args = std::env::args()
+        .skip(1)
+        .filter(|arg| arg != daemon::SERVICE_ARG)
+        .peekable();
+    let one_shot = if args.next_if_eq("publish").is_some() {
+        let topic = args.next().ok_or("Expected topic")?;
+        let data = match args.next_if(|arg| arg.starts_with("--")) {
+            Some(option) => payload(&option, &args.next().ok_or("Expected payload")?)?,
+            None => {
+                let mut data = Vec::new();
+                std::io::stdin().read_to_end(&mut data)?;
+                data
+            }
+        };
+        Some((topic, data))
+    } else {
+        None
+    };
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    // `pub topics migrate <old> <new> [--overlap <secs>] [--quiet-after <secs>] [<peer addr>...]`
+    // republishes the messages of the old topic on the new one for the overlap window, a day by
+    // default, reporting when the old topic has been quiet for --quiet-after, see `migration`
+    let migration = if args.next_if_eq("topics").is_some() {
+        if args.next().as_deref() != Some("migrate") {
+            return Err("Expected `topics migrate <old> <new>`".into());
+        }
+        let from = args.next().ok_or("Expected old topic")?;
+        let to = args.next().ok_or("Expected new topic")?;
+        let mut config = MigrationConfig::new(&from, &to);
+        while let Some(option) = args.next_if(|arg| arg.starts_with("--")) {
+            let secs = args.next().ok_or("Expected seconds")?.parse()?;
+            match option.as_str() {
+                "--overlap" => config.overlap = Duration::from_secs(secs),
+                "--quiet-after" => config.quiet_after = Duration::from_secs(secs),
+                _ => return Err(format!("Unknown option {}", option).into()),
+            }
+        }
+        Some(config)
+    } else {
+        None
+    };
+
+    // Speak line-delimited JSON on stdin and stdout if PUBSUB_STDIO is "json", see `sidecar`,
+    // the rest of the output going to stderr
+    let json = std::env::var("PUBSUB_STDIO").is_ok_and(|mode| mode == "json");
+    let say = |line: String| {
+        if json {
+            eprintln!("{}", line)
+        } else {
+            println!("{}", line)
+        }
+    };
 
     let ipfs_path: Box<Path> = get_ipfs_path();
-    println!("using IPFS_PATH {:?}", ipfs_path);
-    let psk: Option<PreSharedKey> = get_psk(ipfs_path)?
+    say(format!("using IPFS_PATH {:?}", ipfs_path));
+    let psk: Option<PreSharedKey> = get_psk(&ipfs_path)?
         .map(|text| PreSharedKey::from_str(&text))
         .transpose()?;
 
-    // Create a random PeerId
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-    println!("using random peer id: {:?}", local_peer_id);
-    for psk in psk {
-        println!("using swarm key with fingerprint: {}", psk.fingerprint());
-    }
-
-    // Set up a an encrypted DNS-enabled TCP Transport over and Yamux protocol
-    let transport = build_transport(local_key.clone(), psk);
-
-    // Create a Gosspipsub topic
-    let gossipsub_topic = gossipsub::Topic::new("chat".into());
-    //let gossipsub_topic2 = gossipsub::Topic::new("c".into());
-
-    // We create a custom network behaviour that combines gossipsub, ping and identify.
-    #[derive(NetworkBehaviour)]
-    struct MyBehaviour {
-        gossipsub: Gossipsub,
-        identify: Identify,
-        ping: Ping,
-    }
-
-    impl NetworkBehaviourEventProcess<IdentifyEvent>
-        for MyBehaviour
-    {
-        // Called when `identify` produces an event.
-        fn inject_event(&mut self, event: IdentifyEvent) {
-            println!("identify: {:?}", event);
-        }
-    }
-
-    impl NetworkBehaviourEventProcess<GossipsubEvent>
-        for MyBehaviour
-    {
-        // Called when `gossipsub` produces an event.
-        fn inject_event(&mut self, event: GossipsubEvent) {
-            match event {
-                GossipsubEvent::Message(peer_id, id, message) => {
-                    println!(
-                        "Got message: {} with id: {} from peer: {:?}",
-                        String::from_utf8_lossy(&message.data),
-                        id,
-                        peer_id
-                    )
-                }           
-                _ => {}
-            }
+    // Take the identity and Bootstrap entries of the go-ipfs config in IPFS_PATH, if any, for the
+    // nodes to reach when not given. Its Addresses.Swarm are those of the go-ipfs daemon, port 4001
+    // by default, and only listened on if PUBSUB_LISTEN is "ipfs"
+    let ipfs_config = get_ipfs_config(&ipfs_path)?.unwrap_or_default();
+
+    // Derive the PeerId from PUBSUB_IDENTITY_SEED for stable test setups, or from the identity of
+    // the config, another one than the go-ipfs daemon's, or create a random one. The identity of
+    // the config itself is only taken if PUBSUB_IDENTITY is "ipfs", and while no daemon runs
+    let adopt = std::env::var("PUBSUB_IDENTITY").is_ok_and(|identity| identity == "ipfs");
+    let local_key = match (std::env::var("PUBSUB_IDENTITY_SEED"), &ipfs_config.identity) {
+        (Ok(seed), _) => keypair_from_seed(seed),
+        (Err(_), Some(_)) if adopt && ipfs_daemon_running(&ipfs_path) => {
+            return Err(format!(
+                "the go-ipfs daemon of {:?} runs with the identity of its config, stop it first",
+                ipfs_path
+            )
+            .into())
         }
+        (Err(_), Some(identity)) if adopt => identity.clone(),
+        (Err(_), Some(identity)) => sidecar_keypair(identity)?,
+        (Err(_), None) => identity::Keypair::generate_ed25519(),
+    };
+    let local_peer_id = PeerId::from(local_key.public());
+    say(format!("using peer id: {:?}", local_peer_id));
+    if let Some(psk) = psk {
+        say(format!(
+            "using swarm key with fingerprint: {}",
+            psk.fingerprint()
+        ));
     }
 
-    impl NetworkBehaviourEventProcess<PingEvent>
-        for MyBehaviour
-    {
-        // Called when `ping` produces an event.
-        fn inject_event(&mut self, event: PingEvent) {
-            use ping::handler::{PingFailure, PingSuccess};
-            match event {
-                PingEvent {
-                    peer,
-                    result: Result::Ok(PingSuccess::Ping { rtt }),
-                } => {
-                    
-                }
-                PingEvent {
-                    peer,
-                    result: Result::Ok(PingSuccess::Pong),
-                } => {
-                    println!("ping: pong from {}", peer.to_base58());
-                }
-                PingEvent {
-                    peer,
-                    result: Result::Err(PingFailure::Timeout),
-                } => {
-                    println!("ping: timeout to {}", peer.to_base58());
-                }
-                PingEvent {
-                    peer,
-                    result: Result::Err(PingFailure::Other { error }),
-                } => {
-                    println!("ping: failure with {}: {}", peer.to_base58(), error);
-                }
-            }
+    // Reach out to other nodes if specified
+    let bootstrap = args
+        .map(|to_dial| parse_legacy_multiaddr(&to_dial))
+        .collect::<Result<Vec<_>, _>>()?;
+    let bootstrap = if bootstrap.is_empty() {
+        ipfs_config.bootstrap
+    } else {
+        bootstrap
+    };
+
+    // Listen on the addresses listed in PUBSUB_LISTEN, or those of the go-ipfs config if it is
+    // "ipfs", announcing those in PUBSUB_ANNOUNCE if set
+    let listen_addrs = match std::env::var("PUBSUB_LISTEN") {
+        Ok(addrs) if addrs == "ipfs" => ipfs_config.swarm,
+        Ok(addrs) => addrs.split(',').map(str::parse).collect::<Result<_, _>>()?,
+        Err(_) => vec!["/ip4/0.0.0.0/tcp/0".parse()?],
+    };
+    let announce_addrs = std::env::var("PUBSUB_ANNOUNCE")
+        .map(|addrs| addrs.split(',').map(str::parse).collect())
+        .unwrap_or_else(|_| Ok(Vec::new()))?;
+
+    // Listen through the relays listed in PUBSUB_RELAYS, and run one on PUBSUB_RELAY_SERVER
+    let relays = std::env::var("PUBSUB_RELAYS")
+        .map(|relays| relays.split(',').map(str::parse).collect())
+        .unwrap_or_else(|_| Ok(Vec::new()))?;
+    let relay_server = std::env::var("PUBSUB_RELAY_SERVER")
+        .ok()
+        .map(|addr| {
+            Ok::<_, Error>(RelayServerConfig {
+                listen_addr: addr.parse()?,
+                ..RelayServerConfig::default()
+            })
+        })
+        .transpose()?;
+
+    // Join the topics declared in the manifest at PUBSUB_MANIFEST, or "chat" without one
+    let manifest = match std::env::var("PUBSUB_MANIFEST") {
+        Ok(path) => Manifest::from_json(&std::fs::read_to_string(path)?)?,
+        Err(_) => Manifest::from_json(r#"{"topics": {"chat": {}}}"#)?,
+    };
+
+    // Reach floodsub peers too if PUBSUB_ROUTER is "floodsub" or "both"
+    let router = match std::env::var("PUBSUB_ROUTER") {
+        Ok(router) => router.parse()?,
+        Err(_) => Router::default(),
+    };
+
+    // Keep the peers listed in PUBSUB_EXPLICIT_PEERS in every mesh, redialing them when they go
+    let explicit_peers = std::env::var("PUBSUB_EXPLICIT_PEERS")
+        .map(|peers| peers.split(',').map(str::parse).collect())
+        .unwrap_or_else(|_| Ok(Vec::new()))?;
+
+    // Trace gossip if PUBSUB_TRACE is set, streaming it to the collector it names if not empty
+    let trace = std::env::var("PUBSUB_TRACE")
+        .ok()
+        .map(|collector| TraceConfig {
+            collector: Some(collector).filter(|collector| !collector.is_empty()),
+            ..TraceConfig::default()
+        });
+
+    // Disconnect the peers whose median ping takes longer than PUBSUB_MAX_RTT_MS, if set
+    let eviction = EvictionPolicy {
+        max_rtt: std::env::var("PUBSUB_MAX_RTT_MS")
+            .ok()
+            .map(|ms| ms.parse().map(Duration::from_millis))
+            .transpose()?,
+        ..EvictionPolicy::default()
+    };
+
+    // Take part in the topic directory if PUBSUB_TOPIC_DIRECTORY is set, see `DIRECTORY`
+    let topic_directory = std::env::var_os("PUBSUB_TOPIC_DIRECTORY").is_some();
+
+    // Offer secio after noise, to reach nodes of earlier releases, if PUBSUB_LEGACY_SECIO is set
+    let legacy_secio = std::env::var_os("PUBSUB_LEGACY_SECIO").is_some();
+
+    // Only watch the swarm, refusing to publish anything, if PUBSUB_OBSERVER is set
+    let observer = std::env::var_os("PUBSUB_OBSERVER").is_some();
+
+    // Report the node ready on `/readyz` once it has PUBSUB_MIN_PEERS peers, and peers in the
+    // meshes of the topics the manifest requires
+    let readiness = Readiness {
+        min_peers: std::env::var("PUBSUB_MIN_PEERS").map_or(Ok(0), |peers| peers.parse())?,
+        ..Readiness::default()
+    };
+
+    let mut config = NodeConfig {
+        keypair: local_key,
+        psk,
+        legacy_secio,
+        listen_addrs,
+        announce_addrs,
+        bootstrap,
+        explicit_peers,
+        relays,
+        relay_server,
+        router,
+        trace,
+        eviction,
+        readiness,
+        peer_store,
+        topic_directory,
+        observer,
+        ..NodeConfig::default()
+    };
+    manifest.apply(&mut config);
+
+    // Accept payloads of up to PUBSUB_MAX_MESSAGE_SIZE bytes on the topics the manifest does not
+    // limit, rather than have gossipsub drop those beyond its max_transmit_size
+    if let Ok(limit) = std::env::var("PUBSUB_MAX_MESSAGE_SIZE") {
+        let limit = limit.parse()?;
+        config.gossipsub.max_transmit_size = config.gossipsub.max_transmit_size.max(limit);
+        config.max_message_size.push(("#".into(), limit));
+    }
+
+    // Send the messages published by the priority of their topic once the upload passes
+    // PUBSUB_UPLOAD_LIMIT bytes per second, if set, moving those waiting up a priority every
+    // PUBSUB_PRIORITY_AGING_MS, 0 for strict priorities
+    if let Ok(rate) = std::env::var("PUBSUB_UPLOAD_LIMIT") {
+        let limit = config.upload_limit.get_or_insert_with(UploadLimit::default);
+        limit.bytes_per_second = rate.parse()?;
+    }
+    if let Ok(aging) = std::env::var("PUBSUB_PRIORITY_AGING_MS") {
+        let limit = config
+            .upload_limit
+            .as_mut()
+            .ok_or("PUBSUB_PRIORITY_AGING_MS needs an upload limit")?;
+        limit.aging = Some(Duration::from_millis(aging.parse()?)).filter(|aging| !aging.is_zero());
+    }
+    let client = node::spawn(config)?;
+
+    // Serve the admin endpoint on PUBSUB_ADMIN if set, a unix socket if it is a path
+    #[cfg(feature = "gateway")]
+    if let Ok(addr) = std::env::var("PUBSUB_ADMIN") {
+        if addr.contains('/') {
+            admin::spawn_unix(&client, &addr)?;
+        } else {
+            admin::spawn(&client, addr.as_str())?;
         }
+        say(format!("Serving the admin endpoint on {}", addr));
     }
 
-    // Create a Swarm to manage peers and events
-    let mut swarm = {
-        let gossipsub_config = GossipsubConfigBuilder::default()
-            .max_transmit_size(262144)
-            .build();
-        let mut behaviour = MyBehaviour {
-            gossipsub: Gossipsub::new(local_peer_id.clone(), gossipsub_config),
-            identify: Identify::new(
-                "/ipfs/0.1.0".into(),
-                "rust-ipfs-example".into(),
-                local_key.public(),
-            ),
-            ping: Ping::new(PingConfig::new()),
-        };
+    // Serve the gRPC gateway on PUBSUB_GRPC if set, a unix socket if it is a path
+    #[cfg(feature = "grpc")]
+    if let Ok(addr) = std::env::var("PUBSUB_GRPC") {
+        if addr.contains('/') {
+            grpc::spawn_unix(&client, &addr)?;
+        } else {
+            grpc::spawn(&client, addr.as_str())?;
+        }
+        say(format!("Serving the gRPC gateway on {}", addr));
+    }
 
-        println!("Subscribing to {:?}", gossipsub_topic);
-        behaviour.gossipsub.subscribe(gossipsub_topic.clone());
-        Swarm::new(transport, behaviour, local_peer_id.clone())
-    };
+    if let Some((topic, data)) = one_shot {
+        return task::block_on(publish_once(&client, &topic, data));
+    }
+    if let Some(config) = migration {
+        return task::block_on(migrate(&client, config));
+    }
 
-    // Reach out to other nodes if specified
-    for to_dial in std::env::args().skip(1) {
-        let addr: Multiaddr = parse_legacy_multiaddr(&to_dial)?;
-        Swarm::dial_addr(&mut swarm, addr)?;
-        println!("Dialed {:?}", to_dial)
+    for topic in manifest.topics.keys() {
+        say(format!("Subscribing to {:?}", topic));
+    }
+    let subscriptions = manifest.start(&client)?;
+    if json {
+        let stdin = io::BufReader::new(io::stdin());
+        task::block_on(sidecar::serve(&client, subscriptions, stdin, io::stdout()))?;
+        return Ok(());
+    }
+    for subscription in subscriptions {
+        task::spawn(print_messages(subscription));
+    }
+    if daemonized {
+        // No terminal to read commands from: run until stopped
+        #[cfg(windows)]
+        if let Some(mut service) = service {
+            task::block_on(service.stopped());
+            return Ok(());
+        }
+        return task::block_on(future::pending());
     }
 
     // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    task::block_on(async {
+        let mut stdin = io::BufReader::new(io::stdin()).lines();
+        while let Some(line) = stdin.next().await {
+            handle_input_line(&client, line?);
+        }
+        Err("Stdin closed".into())
+    })
+}
 
-    // Listen on all interfaces and whatever port the OS assigns
-    Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse()?)?;
+fn status_command(pidfile: &Path) -> Result<(), Error> {
+    match daemon::status(pidfile)? {
+        Some(pid) => println!("Running as process {}", pid),
+        None => println!("Not running"),
+    }
+    Ok(())
+}
 
-    // Kick it off
-    let mut listening = false;
-    task::block_on(future::poll_fn(move |cx: &mut Context| {
-        
+fn stop_command(pidfile: &Path) -> Result<(), Error> {
+    match daemon::stop(pidfile)? {
+        Some(pid) => println!("Stopped process {}", pid),
+        None => println!("Not running"),
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn service_command(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let name = daemon::service::SERVICE_NAME;
+    match args.next().as_deref() {
+        Some("install") => {
+            // Services start in the environment of the system, without the variables set here
+            let env = std::env::vars()
+                .filter(|(var, _)| var.starts_with("PUBSUB_") || var == "RUST_LOG")
+                .chain(Some((
+                    "IPFS_PATH".into(),
+                    get_ipfs_path().display().to_string(),
+                )));
+            daemon::service::install(name, args, env)?;
+            println!(
+                "Installed service {}, started with `sc start {}`",
+                name, name
+            );
+        }
+        Some("uninstall") => {
+            daemon::service::uninstall(name)?;
+            println!("Removed service {}", name);
+        }
+        _ => return Err("Expected `service install [...]` or `service uninstall`".into()),
+    }
+    Ok(())
+}
 
-        loop {
-            match stdin.try_poll_next_unpin(cx)? {
-                Poll::Ready(Some(line)) => handle_input_line(&mut swarm.gossipsub, line),
-                Poll::Ready(None) => panic!("Stdin closed"),
-                Poll::Pending => break
+/// Where the detached node logs, from PUBSUB_LOG_DIR, PUBSUB_LOG_MAX_BYTES and PUBSUB_LOG_KEEP.
+fn log_config() -> Result<LogConfig, Error> {
+    let mut config = LogConfig::new(
+        std::env::var_os("PUBSUB_LOG_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| get_ipfs_path().join("logs")),
+    );
+    if let Ok(max_bytes) = std::env::var("PUBSUB_LOG_MAX_BYTES") {
+        config.max_bytes = max_bytes.parse()?;
+    }
+    if let Ok(keep) = std::env::var("PUBSUB_LOG_KEEP") {
+        config.keep = keep.parse()?;
+    }
+    Ok(config)
+}
+
+fn peers_command(
+    peer_store: Option<PathBuf>,
+    mut args: impl Iterator<Item = String>,
+) -> Result<(), Error> {
+    let path = peer_store.ok_or("PUBSUB_PEER_STORE is not set")?;
+    let mut book = AddressBook::load(&path)?;
+    match args.next().as_deref() {
+        Some("export") => println!("{}", book.to_json()),
+        Some("import") => {
+            let file = args.next().ok_or("Expected file to import")?;
+            let imported = AddressBook::from_json(&std::fs::read_to_string(file)?)?;
+            let before = book.len();
+            book.merge(imported);
+            book.save(&path)?;
+            println!("Imported {} new peers", book.len() - before);
+        }
+        _ => return Err("Expected export or import".into()),
+    }
+    Ok(())
+}
+
+fn init_command(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let mut options = InitOptions::default();
+    while let Some(option) = args.next() {
+        if option == "--force" {
+            options.force = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Expected value of {}", option))?;
+        match option.as_str() {
+            "--port" => options.port = value.parse()?,
+            "--swarm-key" => options.swarm_key = Some(init::parse_swarm_key(&value)?),
+            "--bootstrap" => options.bootstrap.push(parse_legacy_multiaddr(&value)?),
+            "--announce" => options.announce.push(parse_legacy_multiaddr(&value)?),
+            _ => {
+                return Err(format!(
+                    "Unknown option {}, expected --port, --swarm-key, --bootstrap, --announce \
+                     or --force",
+                    option
+                )
+                .into())
             }
         }
-        loop {
-            match swarm.poll_next_unpin(cx) {
-                Poll::Ready(Some(event)) => println!("{:?}", event),
-                Poll::Ready(None) => return Poll::Ready(Ok(())),
-                Poll::Pending => {
-                    if !listening {
-                        for addr in Swarm::listeners(&swarm) {
-                            println!("Address {}/ipfs/{}", addr, local_peer_id);
-                            listening = true;
-                        }
+    }
+    let repo = init::init(&get_ipfs_path(), &options)?;
+    println!("Initialized repo {}", repo.path.display());
+    println!("Peer id: {}", repo.peer_id);
+    println!(
+        "{} private network with swarm key fingerprint {}",
+        if repo.new_network {
+            "Created"
+        } else {
+            "Joined"
+        },
+        repo.swarm_key.fingerprint()
+    );
+    for addr in &repo.listen_addrs {
+        println!("Listening on {}", addr);
+    }
+    println!();
+    println!("Start this node with:");
+    println!("    {}", repo.start_command());
+    println!("Set up another node of the network with:");
+    println!("    {}", repo.join_command());
+    Ok(())
+}
+
+fn archive_command(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    let journal =
+        PathBuf::from(std::env::var_os("PUBSUB_JOURNAL").ok_or("PUBSUB_JOURNAL is not set")?);
+    match args.next().as_deref() {
+        Some("export") => {
+            let (mut topic, mut out) = (None, None);
+            while let Some(option) = args.next() {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Expected value of {}", option))?;
+                match option.as_str() {
+                    "--topic" => topic = Some(value),
+                    "--out" => out = Some(value),
+                    _ => {
+                        return Err(
+                            format!("Unknown option {}, expected --topic or --out", option).into(),
+                        )
                     }
-                    break;
+                }
+            }
+            let topic = topic.ok_or("Expected --topic")?;
+            let out = out.ok_or("Expected --out")?;
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&out)?);
+            let exported = archive::export(&journal, &topic, &mut file)?;
+            println!("Exported {} messages on {} to {}", exported, topic, out);
+        }
+        Some("import") => {
+            let file = args.next().ok_or("Expected archive to import")?;
+            let imported = archive::import(&journal, &mut std::fs::File::open(file)?)?;
+            println!("Imported {} new messages", imported);
+        }
+        _ => return Err("Expected export or import".into()),
+    }
+    Ok(())
+}
+
+/// The payload of the file at `value` after `--file`, or encoded in `value` after `--base64`.
+fn payload(option: &str, value: &str) -> Result<Vec<u8>, Error> {
+    match option {
+        "--file" => Ok(std::fs::read(value)?),
+        "--base64" => Ok(BASE64.decode(value.as_bytes())?),
+        _ => Err(format!("Unknown option {}, expected --file or --base64", option).into()),
+    }
+}
+
+/// Publish `data` on `topic` once a peer is subscribed to it, giving up after 10 seconds.
+async fn publish_once(client: &Client, topic: &str, data: Vec<u8>) -> Result<(), Error> {
+    let found = future::timeout(Duration::from_secs(10), async {
+        while client.peers_on_topic(topic).await?.is_empty() {
+            task::sleep(Duration::from_millis(100)).await;
+        }
+        Ok::<_, Error>(())
+    });
+    found
+        .await
+        .map_err(|_| format!("No peer subscribed to {}", topic))??;
+    let size = data.len();
+    client.publish(topic, data)?;
+    // Leave the message time to leave the node before it stops
+    task::sleep(Duration::from_secs(1)).await;
+    println!("Published {} bytes on {}", size, topic);
+    Ok(())
+}
+
+async fn migrate(client: &Client, config: MigrationConfig) -> Result<(), Error> {
+    println!(
+        "Migrating {} to {} for {:?}",
+        config.from, config.to, config.overlap
+    );
+    let (from, to) = (config.from.clone(), config.to.clone());
+    let mut migration = client.migrate_topic(config)?;
+    while let Some(event) = migration.next().await {
+        match event {
+            MigrationEvent::Ceased { republished } => println!(
+                "Traffic on {} ceased, {} messages republished on {}: it can be retired",
+                from, republished, to
+            ),
+            MigrationEvent::Resumed => println!("Traffic on {} resumed", from),
+            MigrationEvent::Ended {
+                republished,
+                ceased,
+            } => {
+                println!("Migration ended, {} messages republished", republished);
+                if !ceased {
+                    return Err(format!("Traffic on {} has not ceased", from).into());
                 }
             }
         }
-        Poll::Pending
-    }))
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tui")]
+fn top_command(endpoint: Option<String>) -> Result<(), Error> {
+    let defaults = TopConfig::default();
+    rust_crdt::top::run(TopConfig {
+        endpoint: match endpoint {
+            Some(path) if path.contains('/') => Endpoint::Unix(path.into()),
+            Some(addr) => Endpoint::Tcp(addr),
+            None => defaults.endpoint,
+        },
+        token: std::env::var("PUBSUB_ADMIN_TOKEN").ok(),
+        ..defaults
+    })?;
+    Ok(())
 }
 
-fn handle_input_line(gossipsub: &mut Gossipsub, line: String) {
-    let mut args = line.split(" ");
+#[cfg(not(feature = "tui"))]
+fn top_command(_endpoint: Option<String>) -> Result<(), Error> {
+    Err("top needs the tui feature".into())
+}
+
+#[cfg(feature = "gateway")]
+fn debug_command(mut args: impl Iterator<Item = String>) -> Result<(), Error> {
+    use std::io::Write as _;
+    if args.next().as_deref() != Some("dump") {
+        return Err("Expected `debug dump`".into());
+    }
+    let endpoint = match args.next() {
+        Some(endpoint) => endpoint,
+        None => admin::socket_path()
+            .unwrap_or_else(|| PathBuf::from(admin::SOCKET_NAME))
+            .display()
+            .to_string(),
+    };
+    let mut request = String::from("GET /debug HTTP/1.0\r\nHost: localhost\r\n");
+    if let Ok(token) = std::env::var("PUBSUB_ADMIN_TOKEN") {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    let mut response = Vec::new();
+    if endpoint.contains('/') {
+        let mut stream = std::os::unix::net::UnixStream::connect(&endpoint)?;
+        stream.write_all(request.as_bytes())?;
+        stream.read_to_end(&mut response)?;
+    } else {
+        let mut stream = std::net::TcpStream::connect(&endpoint)?;
+        stream.write_all(request.as_bytes())?;
+        stream.read_to_end(&mut response)?;
+    }
+    let end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or("truncated answer from the admin endpoint")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(format!("admin endpoint answered {}", status).into());
+    }
+    println!("{}", String::from_utf8_lossy(&response[end + 4..]));
+    Ok(())
+}
+
+#[cfg(not(feature = "gateway"))]
+fn debug_command(_args: impl Iterator<Item = String>) -> Result<(), Error> {
+    Err("debug needs the gateway feature".into())
+}
+
+async fn print_messages(mut subscription: Subscription) {
+    while let Some(message) = subscription.next().await {
+        println!(
+            "Got message: {} with id: {} from peer: {:?}",
+            String::from_utf8_lossy(&message.data),
+            message.id,
+            message.source
+        )
+    }
+}
+
+fn handle_input_line(client: &Client, line: String) {
+    let mut args = line.split(' ');
 
     match args.next() {
         Some("SUB") => {
-            let topic = {
-                match args.next() {
-                    Some(topic) => gossipsub::Topic::new(String::from(topic).into()),
-                    None => {
-                        eprintln!("Expected topic");
-                        return;
-                    }
+            let topic = match args.next() {
+                Some(topic) => topic,
+                None => {
+                    eprintln!("Expected topic");
+                    return;
                 }
             };
-            let x = gossipsub.subscribe(topic.clone());
-            if x == true {
-                println!("Subscribed to topic {:?}", topic);
-            } else {
-                println!("Failed to subscribe to topic");
+            match client.subscribe(topic) {
+                Ok(subscription) => {
+                    println!("Subscribed to topic {:?}", topic);
+                    task::spawn(print_messages(subscription));
+                }
+                Err(e) => println!("Failed to subscribe to topic: {}", e),
             }
         }
         Some("PUB") => {
-            let topic = {
-                match args.next() {
-                    Some(topic) => gossipsub::Topic::new(topic.into()),
-                    None => {
-                        eprintln!("Expected topic");
+            let topic = match args.next() {
+                Some(topic) => topic,
+                None => {
+                    eprintln!("Expected topic");
+                    return;
+                }
+            };
+            // The message is the rest of the line, spaces included, unless given by an option
+            let msg = match line.splitn(3, ' ').nth(2) {
+                Some(msg) => msg,
+                None => {
+                    eprintln!("Expected message, --file <path> or --base64 <data>");
+                    return;
+                }
+            };
+            let data = match msg.split_once(' ') {
+                Some((option @ ("--file" | "--base64"), value)) => match payload(option, value) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        eprintln!("Failed to read the payload: {}", e);
                         return;
                     }
+                },
+                _ => msg.as_bytes().to_vec(),
+            };
+            if let Err(e) = client.publish(topic, data) {
+                eprintln!("Failed to publish: {}", e);
+            }
+        }
+        Some("TOPICS") => {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.mesh_info().await {
+                    Ok(info) => {
+                        for topic in info.topics {
+                            println!(
+                                "{} subscribed: {} mesh: {} fanout: {} gossip: {}",
+                                topic.topic,
+                                topic.subscribed,
+                                topic.count(MeshRole::Mesh),
+                                topic.count(MeshRole::Fanout),
+                                topic.count(MeshRole::Gossip)
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to list topics: {}", e),
+                }
+            });
+        }
+        Some("PEERS") => {
+            let topic = match args.next() {
+                Some(topic) => topic.to_owned(),
+                None => {
+                    eprintln!("Expected topic");
+                    return;
                 }
             };
-            let msg = {
-                match args.next() {
-                    Some(msg) => msg,
-                    None => {
-                        eprintln!("Expected message");
-                        return;
+            let client = client.clone();
+            task::spawn(async move {
+                match client.list_peers(&topic).await {
+                    Ok(peers) => peers.iter().for_each(print_peer),
+                    Err(e) => eprintln!("Failed to list peers: {}", e),
+                }
+            });
+        }
+        Some("MESH") => {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.mesh_info().await {
+                    Ok(info) => {
+                        for topic in info.topics {
+                            println!("{} subscribed: {}", topic.topic, topic.subscribed);
+                            topic.peers.iter().for_each(print_peer);
+                        }
                     }
+                    Err(e) => eprintln!("Failed to get mesh info: {}", e),
+                }
+            });
+        }
+        Some("PRESENCE") => {
+            let topic = match args.next() {
+                Some(topic) => topic,
+                None => {
+                    eprintln!("Expected topic");
+                    return;
                 }
             };
-            gossipsub.publish(&topic.clone(), msg.as_bytes());
+            match client.presence(topic) {
+                Ok(mut presence) => {
+                    println!("Following presence on topic {:?}", topic);
+                    task::spawn(async move {
+                        while let Some(event) = presence.next().await {
+                            println!("{:?}", event);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to follow presence: {}", e),
+            }
+        }
+        Some("DIRECTORY") => {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.discover_topics().await {
+                    Ok(listings) => {
+                        for listing in listings {
+                            println!(
+                                "{} peers: {} subscribers: {} publishers: {}",
+                                listing.topic,
+                                listing.peers,
+                                listing.subscribers,
+                                listing.publishers
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to discover topics: {}", e),
+                }
+            });
         }
+        Some("REACHABILITY") => {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.reachability().await {
+                    Ok(status) => {
+                        println!("{:?}", status.reachability);
+                        for addr in status.confirmed_addrs {
+                            println!("reachable at {}", addr);
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to get reachability: {}", e),
+                }
+            });
+        }
+        Some("TOPOLOGY") => {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.describe_topology().await {
+                    Ok(topology) => {
+                        for component in topology.components {
+                            println!(
+                                "{:?} {} {:?} {}",
+                                component.kind,
+                                component.name,
+                                component.status,
+                                component.config_hash
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to describe topology: {}", e),
+                }
+            });
+        }
+        Some("COMPAT") => {
+            let client = client.clone();
+            task::spawn(async move {
+                match client.check_compat().await {
+                    Ok(report) => println!("{}", report),
+                    Err(e) => eprintln!("Failed to check compatibility: {}", e),
+                }
+            });
+        }
+        Some("TRACE") => {
+            let client = client.clone();
+            let id = args.next().map(|id| MessageId(id.to_owned()));
+            task::spawn(async move {
+                let events = match &id {
+                    Some(id) => client.trace_message(id).await,
+                    None => client.trace().await,
+                };
+                match events.and_then(|events| trace::to_json(&events)) {
+                    Ok(json) => println!("{}", String::from_utf8_lossy(&json)),
+                    Err(e) => eprintln!("Failed to read the trace: {}", e),
+                }
+            });
+        }
+        Some("BENCH") => match (args.next(), args.next()) {
+            (Some("SEND"), Some(topic)) => {
+                let config = match bench_config(&mut args) {
+                    Ok(config) => config,
+                    Err(e) => {
+                        eprintln!("Invalid benchmark parameters: {}", e);
+                        return;
+                    }
+                };
+                let client = client.clone();
+                let topic = topic.to_owned();
+                task::spawn(async move {
+                    match bench::publish(&client, &topic, &config).await {
+                        Ok(published) => println!(
+                            "run {}: published {} messages in {:?}",
+                            published.run, published.count, published.elapsed
+                        ),
+                        Err(e) => eprintln!("Failed to run the benchmark: {}", e),
+                    }
+                });
+            }
+            (Some("RECV"), Some(topic)) => match client.subscribe(topic) {
+                Ok(subscription) => {
+                    println!("Recording benchmark runs on topic {:?}", topic);
+                    task::spawn(record_bench(subscription));
+                }
+                Err(e) => eprintln!("Failed to subscribe to topic: {}", e),
+            },
+            _ => eprintln!("Expected SEND <topic> [rate] [size] [count] or RECV <topic>"),
+        },
         _ => {
-            eprintln!("expected PUB or SUB");
+            eprintln!(
+                "expected PUB, SUB, TOPICS, PEERS, MESH, DIRECTORY, PRESENCE, REACHABILITY, \
+                 TOPOLOGY, COMPAT, TRACE or BENCH"
+            );
         }
     }
-}
\ No newline at end of file
+}
+
+/// Rate, size and count of a benchmark run, each defaulting when left out.
+fn bench_config<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<BenchConfig, Error> {
+    let defaults = BenchConfig::default();
+    Ok(BenchConfig {
+        rate: args.next().map_or(Ok(defaults.rate), str::parse)?,
+        size: args.next().map_or(Ok(defaults.size), str::parse)?,
+        count: args.next().map_or(Ok(defaults.count), str::parse)?,
+    })
+}
+
+/// Print the report of every benchmark run on the subscription once it is idle for 2 seconds.
+async fn record_bench(mut subscription: Subscription) {
+    let mut recorder = Recorder::new();
+    loop {
+        match future::timeout(Duration::from_secs(1), subscription.next()).await {
+            Ok(Some(message)) => {
+                recorder.record(&message);
+            }
+            Ok(None) => break,
+            Err(_) => {}
+        }
+        for report in recorder.finished(Duration::from_secs(2)) {
+            println!("{}", report);
+        }
+    }
+    for report in recorder.reports() {
+        println!("{}", report);
+    }
+}
+
+fn print_peer(peer: &MeshPeer) {
+    println!(
+        "  {} {:?} agent: {} rtt: {}",
+        peer.peer.to_base58(),
+        peer.role,
+        peer.agent_version.as_deref().unwrap_or("?"),
+        peer.rtt
+            .map_or_else(|| "?".to_owned(), |rtt| format!("{:?}", rtt))
+    );
+}
@@ -2,105 +2,79 @@ use async_std::{io, task};
 use env_logger::{Builder, Env};
 use futures::prelude::*;
 use libp2p::gossipsub::protocol::MessageId;
-use libp2p::gossipsub::{GossipsubEvent, GossipsubMessage, Topic};
-use libp2p::{
-    gossipsub, identity,
-    PeerId,
-};
+use libp2p::gossipsub::{GossipsubConfigBuilder, GossipsubMessage};
+use rust_crdt::{node, transport::parse_legacy_multiaddr, Error, NodeConfig, NodeEvent};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::time::Duration;
-use std::{error::Error, task::{Context, Poll}};
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Error> {
     Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    // Create a random PeerId
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
-
-    // Set up an encrypted TCP Transport over the Mplex and Yamux protocols
-    let transport = libp2p::build_development_transport(local_key)?;
-
-    // Create a Gossipsub topic
-    let topic = Topic::new("test-net".into());
-
-    // Create a Swarm to manage peers and events
-    let mut swarm = {
-        // to set default parameters for gossipsub use:
-        // let gossipsub_config = gossipsub::GossipsubConfig::default();
+    // To content-address message, we can take the hash of message and use it as an ID.
+    let message_id_fn = |message: &GossipsubMessage| {
+        let mut s = DefaultHasher::new();
+        message.data.hash(&mut s);
+        MessageId(s.finish().to_string())
+    };
 
-        // To content-address message, we can take the hash of message and use it as an ID.
-        let message_id_fn = |message: &GossipsubMessage| {
-            let mut s = DefaultHasher::new();
-            message.data.hash(&mut s);
-            MessageId(s.finish().to_string())
-        };
+    // Reach out to another node if specified
+    let bootstrap = std::env::args()
+        .nth(1)
+        .map(|to_dial| parse_legacy_multiaddr(&to_dial))
+        .transpose()?;
 
-        // set custom gossipsub
-        let gossipsub_config = gossipsub::GossipsubConfigBuilder::new()
+    // Start a node with a random PeerId, listening on all interfaces and whatever port the OS
+    // assigns
+    let client = node::spawn(NodeConfig {
+        bootstrap: bootstrap.into_iter().collect(),
+        gossipsub: GossipsubConfigBuilder::new()
             .heartbeat_interval(Duration::from_secs(10))
             .message_id_fn(message_id_fn) // content-address messages. No two messages of the
             //same content will be propagated.
-            .build();
-        // build a gossipsub network behaviour
-        let mut gossipsub = gossipsub::Gossipsub::new(local_peer_id.clone(), gossipsub_config);
-        gossipsub.subscribe(topic.clone());
-        libp2p::Swarm::new(transport, gossipsub, local_peer_id)
-    };
-
-    // Listen on all interfaces and whatever port the OS assigns
-    libp2p::Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse().unwrap()).unwrap();
-
-    // Reach out to another node if specified
-    if let Some(to_dial) = std::env::args().nth(1) {
-        let dialing = to_dial.clone();
-        match to_dial.parse() {
-            Ok(to_dial) => match libp2p::Swarm::dial_addr(&mut swarm, to_dial) {
-                Ok(_) => println!("Dialed {:?}", dialing),
-                Err(e) => println!("Dial {:?} failed: {:?}", dialing, e),
-            },
-            Err(err) => println!("Failed to parse address to dial: {:?}", err),
-        }
-    }
-
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+            .build(),
+        ..NodeConfig::default()
+    })?;
+    println!("Local peer id: {:?}", client.local_peer_id());
 
-    // Kick it off
-    let mut listening = false;
-    task::block_on(future::poll_fn(move |cx: &mut Context| {
-        loop {
-            match stdin.try_poll_next_unpin(cx)? {
-                Poll::Ready(Some(line)) => swarm.publish(&topic, line.as_bytes()),
-                Poll::Ready(None) => panic!("Stdin closed"),
-                Poll::Pending => break,
-            };
-        }
+    // Follow the node, then join the topic
+    let mut events = client.events()?;
+    let _subscription = client.subscribe("test-net")?;
 
-        loop {
-            match swarm.poll_next_unpin(cx) {
-                Poll::Ready(Some(gossip_event)) => match gossip_event {
-                    GossipsubEvent::Message(peer_id, id, message) => println!(
-                        "Got message: {} with id: {} from peer: {:?}",
-                        String::from_utf8_lossy(&message.data),
-                        id,
-                        peer_id
-                    ),
-                    _ => {}
-                },
-                Poll::Ready(None) | Poll::Pending => break,
+    task::spawn(async move {
+        while let Some(event) = events.next().await {
+            match event {
+                NodeEvent::MessageReceived(message) => println!(
+                    "Got message: {} with id: {} from peer: {:?}",
+                    String::from_utf8_lossy(&message.data),
+                    message.id,
+                    message.source
+                ),
+                NodeEvent::ListenAddr(addr) => println!("Listening on {:?}", addr),
+                NodeEvent::PeerConnected(peer) => println!("Connected to {:?}", peer),
+                NodeEvent::PeerDisconnected(peer) => println!("Disconnected from {:?}", peer),
+                NodeEvent::Subscribed { peer, topic } => {
+                    println!("{:?} subscribed to {}", peer, topic)
+                }
+                NodeEvent::Unsubscribed { peer, topic } => {
+                    println!("{:?} unsubscribed from {}", peer, topic)
+                }
+                NodeEvent::GapDetected {
+                    peer,
+                    topic,
+                    missing,
+                } => println!("{:?} lost messages {:?} of {:?}", topic, missing, peer),
+                NodeEvent::Error(e) => println!("Error: {}", e),
             }
         }
+    });
 
-        if !listening {
-            for addr in libp2p::Swarm::listeners(&swarm) {
-                println!("Listening on {:?}", addr);
-                listening = true;
-            }
+    // Read full lines from stdin
+    task::block_on(async {
+        let mut stdin = io::BufReader::new(io::stdin()).lines();
+        while let Some(line) = stdin.next().await {
+            client.publish("test-net", line?)?;
         }
-
-        Poll::Pending
-    }))
-}
\ No newline at end of file
+        Err("Stdin closed".into())
+    })
+}
@@ -0,0 +1,387 @@
+//! Access tokens: topics only the peers their owner authorizes may publish on.
+//!
+//! The owner of a topic is an Ed25519 key, a [`SigningKey`](crate::delegation::SigningKey) or any
+//! other [`Signer`]. It issues an [`AccessToken`] to every peer it lets publish on the topics
+//! matching a filter, until the token expires. A peer given tokens in
+//! [`NodeConfig::access_tokens`](crate::NodeConfig::access_tokens) attaches the first one matching
+//! a topic to the messages it publishes on it, and signs them with the keypair of its peer
+//! identity, so that the token cannot be lifted onto messages of other peers. Nodes given the owner
+//! of a topic in [`NodeConfig::topic_owners`](crate::NodeConfig::topic_owners) reject the messages
+//! on it that carry no token of the owner for the topic, a token issued to another peer than their
+//! source or expired, or a bad signature.
+//!
+//! Rejected messages are neither delivered nor forwarded: a node enforcing tokens validates the
+//! messages it receives before gossipsub forwards them, as it does with
+//! [`NodeConfig::validation`](crate::NodeConfig::validation) workers. As long as the nodes of a
+//! mesh are given the owners of its topic, an unauthorized publisher thus reaches none of their
+//! subscribers. Local subscribers of an unauthorized node do not get its messages either.
+//!
+//! An authorized payload travels in an envelope: a marker, the length of a JSON header holding
+//! the token, the protobuf encoding of the public key of the publisher and its signature, the
+//! header, then the payload. The topic name is signed along with the payload, so a message
+//! replayed on another topic is rejected. A node not given the owner of a topic strips the
+//! envelope of the messages on it unchecked.
+
+use crate::{
+    delegation::{self, IdentityKey, PublicKey, Signer},
+    topic::TopicFilter,
+    Error, PubSubError,
+};
+use bytes::Bytes;
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::SystemTime;
+
+/// Start of an authorized payload. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0pla";
+
+/// Statement by the `owner` of the topics matching `topic` that `peer` may publish on them until
+/// `not_after`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessToken {
+    /// Filter of the topics the token is for.
+    pub topic: String,
+    #[serde(serialize_with = "to_base58", deserialize_with = "from_base58")]
+    pub peer: PeerId,
+    pub owner: PublicKey,
+    /// Unix time in seconds after which the token is void.
+    pub not_after: u64,
+    #[serde(
+        serialize_with = "delegation::to_hex",
+        deserialize_with = "delegation::from_hex"
+    )]
+    signature: Vec<u8>,
+}
+
+impl AccessToken {
+    /// Have `owner` let `peer` publish on the topics matching `topic` until `not_after`.
+    pub fn issue(
+        owner: &dyn Signer,
+        topic: &str,
+        peer: &PeerId,
+        not_after: SystemTime,
+    ) -> Result<Self, Error> {
+        TopicFilter::new(topic)?;
+        let not_after = delegation::unix_secs(not_after);
+        let owner_key = owner.public_key();
+        let signature = owner.sign(&AccessToken::signed(topic, peer, &owner_key, not_after))?;
+        Ok(AccessToken {
+            topic: topic.to_owned(),
+            peer: peer.clone(),
+            owner: owner_key,
+            not_after,
+            signature,
+        })
+    }
+
+    /// What the owner of a token signs.
+    fn signed(topic: &str, peer: &PeerId, owner: &PublicKey, not_after: u64) -> Vec<u8> {
+        let mut signed = b"pubsub-lite/access-token\0".to_vec();
+        signed.extend_from_slice(topic.as_bytes());
+        signed.push(0);
+        signed.extend_from_slice(peer.as_bytes());
+        signed.extend_from_slice(owner.as_bytes());
+        signed.extend_from_slice(&not_after.to_be_bytes());
+        signed
+    }
+
+    /// Check that the token is signed by its owner, has not expired at `now` and is for `topic`.
+    fn check(&self, topic: &str, now: u64) -> Result<(), Error> {
+        if now > self.not_after {
+            return Err(format!("access token of {} has expired", self.peer).into());
+        }
+        let signed = AccessToken::signed(&self.topic, &self.peer, &self.owner, self.not_after);
+        if !self.owner.verify(&signed, &self.signature) {
+            return Err(format!("access token of {} has a bad signature", self.peer).into());
+        }
+        if !TopicFilter::new(&self.topic)?.matches(topic) {
+            return Err(format!("access token of {} is not for {}", self.peer, topic).into());
+        }
+        Ok(())
+    }
+}
+
+fn to_base58<S: Serializer>(peer: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&peer.to_base58())
+}
+
+fn from_base58<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+    let peer = String::deserialize(deserializer)?;
+    peer.parse()
+        .map_err(|_| serde::de::Error::custom(format!("invalid peer id {}", peer)))
+}
+
+/// The token, public key and signature of an authorized payload.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    token: AccessToken,
+    #[serde(
+        serialize_with = "delegation::to_hex",
+        deserialize_with = "delegation::from_hex"
+    )]
+    key: Vec<u8>,
+    #[serde(
+        serialize_with = "delegation::to_hex",
+        deserialize_with = "delegation::from_hex"
+    )]
+    signature: Vec<u8>,
+}
+
+/// What the publisher of a payload on `topic` signs.
+fn signed(topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut signed = b"pubsub-lite/access\0".to_vec();
+    signed.extend_from_slice(topic.as_bytes());
+    signed.push(0);
+    signed.extend_from_slice(data);
+    signed
+}
+
+/// The tokens of a node, and the identity key it signs the messages carrying them with.
+pub(crate) struct Access {
+    key: IdentityKey,
+    tokens: Vec<(TopicFilter, AccessToken)>,
+}
+
+impl Access {
+    /// Publish with `tokens`, which must all have been issued to the peer of `key`.
+    pub fn new(key: IdentityKey, tokens: &[AccessToken]) -> Result<Self, Error> {
+        let local_peer_id = PeerId::from(key.public());
+        let tokens = tokens
+            .iter()
+            .map(|token| {
+                if token.peer != local_peer_id {
+                    return Err(format!(
+                        "access token for {} is issued to {}, not this node",
+                        token.topic, token.peer
+                    )
+                    .into());
+                }
+                Ok((TopicFilter::new(&token.topic)?, token.clone()))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Access { key, tokens })
+    }
+
+    /// The first token matching `topic`, if any.
+    fn token(&self, topic: &str) -> Option<&AccessToken> {
+        self.tokens
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .map(|(_, token)| token)
+    }
+
+    /// Put `data` published on `topic` in an envelope with the first token matching the topic,
+    /// if any.
+    pub fn attach(&self, topic: &str, data: Bytes) -> Result<Bytes, Error> {
+        let token = match self.token(topic) {
+            Some(token) => token.clone(),
+            None => return Ok(data),
+        };
+        let header = Header {
+            token,
+            key: self.key.public().into_protobuf_encoding(),
+            signature: self.key.sign(&signed(topic, &data))?,
+        };
+        let header = serde_json::to_vec(&header)?;
+        let mut envelope = Vec::with_capacity(MARKER.len() + 4 + header.len() + data.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        envelope.extend_from_slice(&header);
+        envelope.extend_from_slice(&data);
+        Ok(envelope.into())
+    }
+}
+
+/// The owner of `topic` by the first filter of `owners` matching it, if any.
+fn owner<'a>(owners: &'a [(TopicFilter, PublicKey)], topic: &str) -> Option<&'a PublicKey> {
+    owners
+        .iter()
+        .find(|(filter, _)| filter.matches(topic))
+        .map(|(_, owner)| owner)
+}
+
+/// Check that this node, publishing with `access`, may publish on `topic` as the nodes given
+/// `owners` would, without signing anything.
+pub(crate) fn check_local(
+    owners: &[(TopicFilter, PublicKey)],
+    access: Option<&Access>,
+    topic: &str,
+) -> Result<(), Error> {
+    let owner = match owner(owners, topic) {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+    let token = access
+        .and_then(|access| access.token(topic))
+        .ok_or("message carries no access token")?;
+    if token.owner != *owner {
+        return Err(format!("access token is not issued by {}", owner).into());
+    }
+    token.check(topic, delegation::now())
+}
+
+/// Check `data` received from `source` on `topic` against the owner of the first filter of
+/// `owners` matching the topic, returning its payload. Without one, an authorized payload is
+/// only taken out of its envelope.
+pub(crate) fn verify(
+    owners: &[(TopicFilter, PublicKey)],
+    source: &PeerId,
+    topic: &str,
+    data: Bytes,
+) -> Result<Bytes, Error> {
+    let owner = owner(owners, topic);
+    if !data.starts_with(MARKER) {
+        return match owner {
+            Some(_) => Err("message carries no access token".into()),
+            None => Ok(data),
+        };
+    }
+    let malformed = || PubSubError::codec("malformed authorized message");
+    let rest = &data[MARKER.len()..];
+    let len = rest.get(..4).ok_or_else(malformed)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let header = rest.get(4..4 + len).ok_or_else(malformed)?;
+    let payload = data.slice(MARKER.len() + 4 + len..);
+    let owner = match owner {
+        Some(owner) => owner,
+        None => return Ok(payload),
+    };
+    let header: Header = serde_json::from_slice(header)?;
+    if header.token.owner != *owner {
+        return Err(format!("access token is not issued by {}", owner).into());
+    }
+    header.token.check(topic, delegation::now())?;
+    if header.token.peer != *source {
+        return Err(format!(
+            "access token is issued to {}, not {}",
+            header.token.peer, source
+        )
+        .into());
+    }
+    let key = identity::PublicKey::from_protobuf_encoding(&header.key)
+        .map_err(|_| PubSubError::codec("malformed public key"))?;
+    if PeerId::from(key.clone()) != *source {
+        return Err(format!("message is not signed by {}", source).into());
+    }
+    if !key.verify(&signed(topic, &payload), &header.signature) {
+        return Err("message has a bad signature".into());
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delegation::SigningKey;
+    use crate::transport::keypair_from_seed;
+    use std::time::Duration;
+
+    struct Fixture {
+        owners: Vec<(TopicFilter, PublicKey)>,
+        owner: SigningKey,
+        publisher: identity::Keypair,
+        source: PeerId,
+    }
+
+    fn fixture() -> Fixture {
+        let owner = SigningKey::generate().unwrap();
+        let publisher = keypair_from_seed("acl publisher");
+        Fixture {
+            owners: vec![(TopicFilter::new("alerts/#").unwrap(), owner.public_key())],
+            source: PeerId::from(publisher.public()),
+            owner,
+            publisher,
+        }
+    }
+
+    fn token(owner: &SigningKey, topic: &str, peer: &PeerId, valid: Duration) -> AccessToken {
+        AccessToken::issue(owner, topic, peer, SystemTime::now() + valid).unwrap()
+    }
+
+    fn access(f: &Fixture, tokens: &[AccessToken]) -> Access {
+        Access::new(f.publisher.clone().into(), tokens).unwrap()
+    }
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    #[test]
+    fn authorized_payload_round_trips() {
+        let f = fixture();
+        let access = access(&f, &[token(&f.owner, "alerts/#", &f.source, HOUR)]);
+        let envelope = access.attach("alerts/fire", "smoke".into()).unwrap();
+        assert!(envelope.starts_with(MARKER));
+        let payload = verify(&f.owners, &f.source, "alerts/fire", envelope.clone()).unwrap();
+        assert_eq!(payload, "smoke");
+        // Nodes not given the owner only strip the envelope.
+        assert_eq!(
+            verify(&[], &f.source, "alerts/fire", envelope).unwrap(),
+            "smoke"
+        );
+        check_local(&f.owners, Some(&access), "alerts/fire").unwrap();
+    }
+
+    #[test]
+    fn owned_topics_reject_payloads_without_tokens() {
+        let f = fixture();
+        assert!(verify(&f.owners, &f.source, "alerts/fire", "smoke".into()).is_err());
+        assert!(check_local(&f.owners, None, "alerts/fire").is_err());
+        assert_eq!(
+            verify(&f.owners, &f.source, "chat", "hi".into()).unwrap(),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn tampered_and_truncated_envelopes_are_rejected() {
+        let f = fixture();
+        let access = access(&f, &[token(&f.owner, "alerts/#", &f.source, HOUR)]);
+        let envelope = access.attach("alerts/fire", "smoke".into()).unwrap();
+        let mut tampered = envelope.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(verify(&f.owners, &f.source, "alerts/fire", tampered.into()).is_err());
+        for len in [MARKER.len() + 2, MARKER.len() + 10, envelope.len() - 6] {
+            let truncated = envelope.slice(..len);
+            assert!(verify(&f.owners, &f.source, "alerts/fire", truncated).is_err());
+        }
+        // Replayed on another topic the token is for.
+        assert!(verify(&f.owners, &f.source, "alerts/flood", envelope).is_err());
+    }
+
+    #[test]
+    fn rejects_tokens_of_other_owners_peers_or_topics_and_expired_ones() {
+        let f = fixture();
+        let publish = |token: AccessToken, topic: &str, source: &PeerId| {
+            let envelope = access(&f, &[token]).attach(topic, "smoke".into()).unwrap();
+            verify(&f.owners, source, topic, envelope)
+        };
+        let stranger = SigningKey::generate().unwrap();
+        assert!(publish(
+            token(&stranger, "alerts/#", &f.source, HOUR),
+            "alerts/fire",
+            &f.source
+        )
+        .is_err());
+        let expired =
+            AccessToken::issue(&f.owner, "alerts/#", &f.source, SystemTime::now() - HOUR).unwrap();
+        assert!(publish(expired, "alerts/fire", &f.source).is_err());
+        let other = PeerId::from(keypair_from_seed("acl other").public());
+        assert!(publish(
+            token(&f.owner, "alerts/#", &f.source, HOUR),
+            "alerts/fire",
+            &other
+        )
+        .is_err());
+        // No token matches the topic, so the payload goes out bare and is rejected.
+        assert!(publish(
+            token(&f.owner, "alerts/fire", &f.source, HOUR),
+            "alerts/flood",
+            &f.source
+        )
+        .is_err());
+        assert!(Access::new(
+            keypair_from_seed("acl other").into(),
+            &[token(&f.owner, "alerts/#", &f.source, HOUR)]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Archives of the journaled history of topics, to migrate nodes or keep messages for the long
+//! term.
+//!
+//! [`export`] writes the messages of the topics matching a filter, out of the journal of a
+//! [durable subscription](crate::durable), to an archive, and [`import`] adds those of an
+//! archive to a journal, so that the next durable subscription opened on it hands them out.
+//!
+//! An archive is a [CARv1](https://ipld.io/specs/transport/car/carv1/) file, so that IPFS can
+//! store it as is, or load its blocks with `ipfs dag import`:
+//!
+//! - the header is the varint length, then the DAG-CBOR encoding, of the map
+//!   `{"roots": [<root CID>], "version": 1}`;
+//! - every block follows as the varint length of its CID and data, its CID, then its data.
+//!
+//! Every CID is a CIDv1 of the `json` codec (0x0200) and the `sha2-256` multihash of the data of
+//! its block. The first block is the root, a JSON object holding the `format`,
+//! `"pubsub-lite/archive"`, its `version`, 1, the `topic` filter the archive was exported for
+//! and how many `messages` follow. Every other block is a message, as a JSON object holding the
+//! `position` of the message in the journal, when it was `journaled` in milliseconds since the
+//! Unix epoch, its `id`, the base58 peer id of its `source`, its `topic`, its payload in base64
+//! as `data`, its `sequence_number` and, if it has them, its `origin`, as the hex `org` and
+//! `key`, when it was `published_at` in microseconds since the Unix epoch, and the W3C
+//! `traceparent` of the span that published it.
+//!
+//! Importing checks every block against its CID and skips the messages whose id is in the
+//! journal already, numbering the others past the last message journaled and the cursor. The
+//! journal must not be in use by a durable subscription meanwhile.
+
+use crate::{
+    durable::{self, Entry, InUse},
+    topic::TopicFilter,
+    Error, PubSubError,
+};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    path::Path,
+};
+
+/// Value of the `format` of the root of an archive.
+pub const FORMAT: &str = "pubsub-lite/archive";
+
+/// Version of the format written by [`export`].
+pub const VERSION: u64 = 1;
+
+/// Multicodec of JSON blocks.
+const JSON_CODEC: u64 = 0x0200;
+
+/// Multihash code of SHA-256.
+const SHA2_256: u64 = 0x12;
+
+/// The root block of an archive.
+#[derive(Serialize, Deserialize)]
+struct Root {
+    format: String,
+    version: u64,
+    topic: String,
+    messages: usize,
+}
+
+fn put_varint(value: u64, out: &mut Vec<u8>) {
+    prost::encoding::encode_varint(value, out);
+}
+
+fn take_varint(input: &mut &[u8]) -> Result<u64, Error> {
+    prost::encoding::decode_varint(input)
+        .map_err(|_| PubSubError::codec("malformed varint in archive"))
+}
+
+/// The CID of the JSON block `data`.
+fn cid(data: &[u8]) -> Vec<u8> {
+    let mut cid = Vec::with_capacity(4 + 32);
+    put_varint(1, &mut cid);
+    put_varint(JSON_CODEC, &mut cid);
+    put_varint(SHA2_256, &mut cid);
+    put_varint(32, &mut cid);
+    cid.extend_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    cid
+}
+
+/// The DAG-CBOR header of an archive whose root is `root`.
+fn header(root: &[u8]) -> Vec<u8> {
+    let mut header = vec![0xa2, 0x65];
+    header.extend_from_slice(b"roots");
+    // An array of one CID: tag 42 over the bytes of the CID after a zero byte.
+    header.extend_from_slice(&[0x81, 0xd8, 0x2a, 0x58, root.len() as u8 + 1, 0x00]);
+    header.extend_from_slice(root);
+    header.push(0x67);
+    header.extend_from_slice(b"version");
+    header.push(VERSION as u8);
+    header
+}
+
+/// Write a section of an archive: the varint length of `prefix` and `data`, then both.
+fn write_section(out: &mut impl Write, prefix: &[u8], data: &[u8]) -> Result<(), Error> {
+    let mut length = Vec::new();
+    put_varint((prefix.len() + data.len()) as u64, &mut length);
+    out.write_all(&length)?;
+    out.write_all(prefix)?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+/// Write the messages of the journal at `journal` on the topics matching `topic` to an archive,
+/// returning how many were.
+pub fn export(journal: &Path, topic: &str, out: &mut impl Write) -> Result<usize, Error> {
+    if !journal.is_file() {
+        return Err(format!("no journal {}", journal.display()).into());
+    }
+    let filter = TopicFilter::new(topic)?;
+    let (entries, _) = durable::read_entries(journal)?;
+    let blocks = entries
+        .iter()
+        .filter(|entry| filter.matches(&entry.topic))
+        .map(serde_json::to_vec)
+        .collect::<Result<Vec<_>, _>>()?;
+    let root = serde_json::to_vec(&Root {
+        format: FORMAT.to_owned(),
+        version: VERSION,
+        topic: topic.to_owned(),
+        messages: blocks.len(),
+    })?;
+    let root_cid = cid(&root);
+    write_section(out, &[], &header(&root_cid))?;
+    write_section(out, &root_cid, &root)?;
+    for block in &blocks {
+        write_section(out, &cid(block), block)?;
+    }
+    out.flush()?;
+    Ok(blocks.len())
+}
+
+/// Split the next section off `input`.
+fn take_section<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    let length = take_varint(input)? as usize;
+    if length > input.len() {
+        return Err(PubSubError::codec("archive is cut short"));
+    }
+    let (section, rest) = input.split_at(length);
+    *input = rest;
+    Ok(section)
+}
+
+/// Check a block against its CID, returning the CID and the data of the block.
+fn open_block(block: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let mut rest = block;
+    if take_varint(&mut rest)? != 1 {
+        return Err(PubSubError::codec(
+            "archive block is not addressed by a CIDv1",
+        ));
+    }
+    if take_varint(&mut rest)? != JSON_CODEC {
+        return Err(PubSubError::codec("archive block is not JSON"));
+    }
+    if take_varint(&mut rest)? != SHA2_256 || take_varint(&mut rest)? != 32 || rest.len() < 32 {
+        return Err(PubSubError::codec(
+            "archive block is not hashed with SHA-256",
+        ));
+    }
+    let (hash, data) = rest.split_at(32);
+    if digest::digest(&digest::SHA256, data).as_ref() != hash {
+        return Err(PubSubError::codec("archive block does not match its CID"));
+    }
+    Ok((&block[..block.len() - data.len()], data))
+}
+
+/// Add the messages of an archive to the journal at `journal`, returning how many were not in
+/// it yet.
+pub fn import(journal: &Path, input: &mut impl Read) -> Result<usize, Error> {
+    let mut archive = Vec::new();
+    input.read_to_end(&mut archive)?;
+    let mut rest = archive.as_slice();
+    let header = take_section(&mut rest)?;
+    let (root_cid, root) = open_block(take_section(&mut rest)?)?;
+    if !header
+        .windows(root_cid.len())
+        .any(|window| window == root_cid)
+    {
+        return Err(PubSubError::codec(
+            "the first block of the archive is not its root",
+        ));
+    }
+    let root: Root = serde_json::from_slice(root)?;
+    if root.format != FORMAT || root.version != VERSION {
+        return Err(PubSubError::codec(format!(
+            "unsupported archive {} version {}",
+            root.format, root.version
+        )));
+    }
+    let mut archived = Vec::new();
+    while !rest.is_empty() {
+        let (_, data) = open_block(take_section(&mut rest)?)?;
+        archived.push(serde_json::from_slice::<Entry>(data)?);
+    }
+    if archived.len() != root.messages {
+        return Err(PubSubError::codec(format!(
+            "archive holds {} messages rather than {}",
+            archived.len(),
+            root.messages
+        )));
+    }
+    let path = durable::canonical_path(journal)?;
+    let _in_use = InUse::take(path.clone())?;
+    let (mut entries, _) = durable::read_entries(&path)?;
+    let mut ids: HashSet<String> = entries.iter().map(|entry| entry.id.clone()).collect();
+    let mut last = entries
+        .iter()
+        .map(|entry| entry.position)
+        .max()
+        .unwrap_or(0)
+        .max(durable::read_cursor(&durable::cursor_path(&path))?);
+    let mut imported = 0;
+    for mut entry in archived {
+        if ids.insert(entry.id.clone()) {
+            last += 1;
+            entry.position = last;
+            entries.push(entry);
+            imported += 1;
+        }
+    }
+    durable::write_entries(&path, entries.iter())?;
+    Ok(imported)
+}
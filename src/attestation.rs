@@ -0,0 +1,138 @@
+//! Publisher attestation: messages signed with the key of the peer that published them, so that
+//! subscribers can tell who published a message and build authorization on top of it.
+//!
+//! Gossipsub 0.16 does not sign messages: their [`source`](crate::Message::source) is whatever
+//! the publisher claims, and any peer can publish under the peer id of another. A node attesting
+//! a topic, as listed in [`NodeConfig::attested_topics`](crate::NodeConfig::attested_topics),
+//! signs every payload it publishes on it with the keypair of its peer identity, held in memory
+//! rather than by a [`Signer`](crate::delegation::Signer), as noise needs it too. Every node
+//! receiving a signed payload checks that it is signed by the key of the source of the message
+//! and rejects it otherwise, so that a signed message is neither delivered nor forwarded under
+//! another peer id than that of its publisher. The outcome is the
+//! [`signature`](crate::Message::signature) of the delivered message;
+//! [`Client::subscribe_verified`](crate::Client::subscribe_verified) only delivers the messages
+//! whose source is verified.
+//!
+//! A signed payload travels in an envelope: a marker, the length of a JSON header holding the
+//! protobuf encoding of the public key of the publisher and its signature, the header, then the
+//! payload. The topic name is signed along with the payload, so a message replayed on another
+//! topic is rejected. The envelope goes inside the nonce of [replay protection](crate::replay),
+//! which it signs, and inside encryption and batches, so that every payload has one.
+//!
+//! Unlike [organisations](crate::delegation), attestation vouches for a peer id rather than for
+//! whoever holds a certificate: it suits meshes whose subscribers know the peer ids of the
+//! publishers they trust.
+
+use crate::{
+    delegation::{self, IdentityKey},
+    topic::TopicFilter,
+    Error, PubSubError,
+};
+use bytes::Bytes;
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Start of a signed payload. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plp";
+
+/// Whether the source of a message is vouched for, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    /// Not signed: the source is whatever the publisher claims.
+    #[default]
+    Unsigned,
+    /// Signed with the key of its source, checked by this node.
+    Verified,
+}
+
+/// The public key and signature of a signed payload.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    #[serde(
+        serialize_with = "delegation::to_hex",
+        deserialize_with = "delegation::from_hex"
+    )]
+    key: Vec<u8>,
+    #[serde(
+        serialize_with = "delegation::to_hex",
+        deserialize_with = "delegation::from_hex"
+    )]
+    signature: Vec<u8>,
+}
+
+/// What the publisher of a payload on `topic` signs.
+fn signed(topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut signed = b"pubsub-lite/attestation\0".to_vec();
+    signed.extend_from_slice(topic.as_bytes());
+    signed.push(0);
+    signed.extend_from_slice(data);
+    signed
+}
+
+/// The topics a node attests, and the identity key it signs their messages with.
+pub(crate) struct Attester {
+    key: IdentityKey,
+    topics: Vec<TopicFilter>,
+}
+
+impl Attester {
+    pub fn new(key: IdentityKey, topics: &[String]) -> Result<Self, Error> {
+        let topics = topics
+            .iter()
+            .map(|topic| Ok(TopicFilter::new(topic)?))
+            .collect::<Result<_, Error>>()?;
+        Ok(Attester { key, topics })
+    }
+
+    /// Whether the messages published on `topic` are signed.
+    pub fn attests(&self, topic: &str) -> bool {
+        self.topics.iter().any(|filter| filter.matches(topic))
+    }
+
+    /// Put `data` published on `topic` in a signed envelope, if the node attests the topic.
+    pub fn attach(&self, topic: &str, data: Bytes) -> Result<Bytes, Error> {
+        if !self.attests(topic) {
+            return Ok(data);
+        }
+        let header = Header {
+            key: self.key.public().into_protobuf_encoding(),
+            signature: self.key.sign(&signed(topic, &data))?,
+        };
+        let header = serde_json::to_vec(&header)?;
+        let mut envelope = Vec::with_capacity(MARKER.len() + 4 + header.len() + data.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        envelope.extend_from_slice(&header);
+        envelope.extend_from_slice(&data);
+        Ok(envelope.into())
+    }
+}
+
+/// Check `data` received from `source` on `topic`, returning whether it is signed and its
+/// payload. Fails if it is signed by another key than that of `source`, or badly.
+pub(crate) fn verify(
+    source: &PeerId,
+    topic: &str,
+    data: Bytes,
+) -> Result<(SignatureStatus, Bytes), Error> {
+    if !data.starts_with(MARKER) {
+        return Ok((SignatureStatus::Unsigned, data));
+    }
+    let malformed = || PubSubError::codec("malformed attested message");
+    let rest = &data[MARKER.len()..];
+    let len = rest.get(..4).ok_or_else(malformed)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let header = rest.get(4..4 + len).ok_or_else(malformed)?;
+    let payload = data.slice(MARKER.len() + 4 + len..);
+    let header: Header = serde_json::from_slice(header)?;
+    let key = identity::PublicKey::from_protobuf_encoding(&header.key)
+        .map_err(|_| PubSubError::codec("malformed public key"))?;
+    if PeerId::from(key.clone()) != *source {
+        return Err(format!("message is not signed by {}", source).into());
+    }
+    if !key.verify(&signed(topic, &payload), &header.signature) {
+        return Err("message has a bad signature".into());
+    }
+    Ok((SignatureStatus::Verified, payload))
+}
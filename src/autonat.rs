@@ -0,0 +1,519 @@
+//! Reachability of a node, learnt the way AutoNAT does: by having peers dial it back.
+//!
+//! Through identify, peers tell a node the address they see its connections come from. Behind a
+//! NAT, that address is not necessarily one peers can dial. [`AutoNat`] wraps the identify
+//! behaviour of the node and holds the observed addresses back instead of advertising them right
+//! away. Soon after a new address is observed, and every [`PROBE_INTERVAL`], the node asks up to
+//! [`PROBE_PEERS`] connected peers over [`AUTONAT_TOPIC`] to dial its candidate addresses: those
+//! observed, with the ports it listens on, and its listen addresses.
+//!
+//! A peer asked to dial back opens a new connection under a throwaway identity to each candidate
+//! on the IP address the request came from, never another one, so that it cannot be made to
+//! connect to third parties. It replies with the addresses at which it reached the node under the
+//! peer id of the node. Confirmed addresses are advertised to peers through identify, and the
+//! node is deemed publicly reachable as long as the last reply confirmed any.
+//!
+//! Addresses through a [relay](crate::relay) tell nothing about whether the node can be dialed
+//! directly, and are left out of probes.
+//!
+//! A node behind a load balancer or port forwarding is best reached at addresses it can neither
+//! listen on nor learn from its peers. Those of
+//! [`NodeConfig::announce_addrs`](crate::NodeConfig::announce_addrs), if any, are advertised
+//! through identify in place of the listen and confirmed addresses; probes still run, for the
+//! reachability they tell.
+
+use crate::{debug::ConnectionTracker, relay, transport::build_transport};
+use async_std::{future::timeout, stream, task};
+use futures::{channel::mpsc, prelude::*};
+use libp2p::{
+    core::{address_translation, ConnectedPoint},
+    identify::{Identify, IdentifyEvent},
+    identity,
+    multiaddr::Protocol,
+    pnet::PreSharedKey,
+    swarm::{
+        IntoProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters,
+        ProtocolsHandler,
+    },
+    Multiaddr, PeerId, Transport,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, iter,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Well-known topic on which nodes ask each other to dial them back, and reply.
+pub const AUTONAT_TOPIC: &str = "pubsub-lite/autonat";
+
+/// How often a node checks its reachability.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Number of peers asked to dial a node back at every check.
+pub const PROBE_PEERS: usize = 3;
+
+/// How long a peer may take to reply to a dial-back request.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long dialing a candidate address back may take.
+const DIAL_BACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most candidate addresses dialed back for a single request.
+const MAX_DIAL_BACKS: usize = 8;
+
+/// Most dial-back requests served at the same time.
+const MAX_CONCURRENT_REQUESTS: usize = 8;
+
+/// Whether a node can be dialed by its peers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// No peer replied to a dial-back request yet.
+    Unknown,
+    /// The last peer that replied reached the node at some address.
+    Public,
+    /// The last peer that replied could not reach the node at any address.
+    Private,
+}
+
+/// Reachability of a node and the addresses behind it, returned by
+/// [`Client::reachability`](crate::Client::reachability).
+#[derive(Clone, Debug)]
+pub struct ReachabilityStatus {
+    pub reachability: Reachability,
+    /// Addresses the node listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses peers observed the connections of the node come from.
+    pub observed_addrs: Vec<Multiaddr>,
+    /// Addresses peers reached the node at, advertised through identify.
+    pub confirmed_addrs: Vec<Multiaddr>,
+    /// Addresses advertised through identify in place of the others, if any.
+    pub announce_addrs: Vec<Multiaddr>,
+}
+
+/// Message exchanged on [`AUTONAT_TOPIC`], addressed to the peer `to`.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Probe {
+    /// Asks `to` to dial the sender back at `addrs`.
+    DialRequest {
+        to: String,
+        nonce: u64,
+        addrs: Vec<String>,
+    },
+    /// Tells `to` at which of the addresses of its request it was reached.
+    DialResponse {
+        to: String,
+        nonce: u64,
+        reached: Vec<String>,
+    },
+}
+
+/// Event of the [`AutoNat`] behaviour.
+#[derive(Debug)]
+pub enum AutoNatEvent {
+    Identify(Box<IdentifyEvent>),
+    /// `data` is to be sent to `peer` on [`AUTONAT_TOPIC`].
+    Send {
+        peer: PeerId,
+        data: Vec<u8>,
+    },
+}
+
+/// The outcome of serving a dial-back request.
+struct DialBack {
+    peer: PeerId,
+    nonce: u64,
+    reached: Vec<Multiaddr>,
+}
+
+/// Identify, holding observed addresses back until peers confirm they can dial them. See the
+/// [module documentation](self).
+pub struct AutoNat {
+    inner: Identify,
+    local_peer_id: PeerId,
+    /// Swarm key of the private network, needed to dial back.
+    psk: Option<PreSharedKey>,
+    /// Whether secio is offered when dialing back, see
+    /// [`NodeConfig::legacy_secio`](crate::NodeConfig::legacy_secio).
+    legacy_secio: bool,
+    /// Addresses advertised in place of the listen and confirmed ones, if not empty.
+    announce_addrs: Vec<Multiaddr>,
+    /// Endpoint of the connection to each connected peer.
+    endpoints: HashMap<PeerId, ConnectedPoint>,
+    observed: Vec<Multiaddr>,
+    confirmed: Vec<Multiaddr>,
+    reachability: Reachability,
+    /// Dial-back requests sent, by nonce, with the peer asked, the addresses to dial and when
+    /// they expire.
+    pending: HashMap<u64, (PeerId, Vec<Multiaddr>, Instant)>,
+    next_nonce: u64,
+    probe_interval: stream::Interval,
+    /// Whether to check reachability on the next poll, a new address having been observed.
+    probe_due: bool,
+    /// Dial-back requests being served.
+    serving: usize,
+    dial_backs: (
+        mpsc::UnboundedSender<DialBack>,
+        mpsc::UnboundedReceiver<DialBack>,
+    ),
+    events: VecDeque<NetworkBehaviourAction<(), AutoNatEvent>>,
+}
+
+impl AutoNat {
+    pub(crate) fn new(
+        inner: Identify,
+        local_peer_id: PeerId,
+        psk: Option<PreSharedKey>,
+        legacy_secio: bool,
+        announce_addrs: Vec<Multiaddr>,
+    ) -> Self {
+        AutoNat {
+            inner,
+            local_peer_id,
+            psk,
+            legacy_secio,
+            announce_addrs,
+            endpoints: HashMap::new(),
+            observed: Vec::new(),
+            confirmed: Vec::new(),
+            reachability: Reachability::Unknown,
+            pending: HashMap::new(),
+            // Start nonces from the clock, so that they do not repeat across restarts.
+            next_nonce: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos() as u64),
+            probe_interval: stream::interval(PROBE_INTERVAL),
+            probe_due: false,
+            serving: 0,
+            dial_backs: mpsc::unbounded(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn status(&self, listen_addrs: Vec<Multiaddr>) -> ReachabilityStatus {
+        ReachabilityStatus {
+            reachability: self.reachability,
+            listen_addrs,
+            observed_addrs: self.observed.clone(),
+            confirmed_addrs: self.confirmed.clone(),
+            announce_addrs: self.announce_addrs.clone(),
+        }
+    }
+
+    /// Endpoints of the connections to every connected peer.
+    pub(crate) fn endpoints(&self) -> impl Iterator<Item = (&PeerId, &ConnectedPoint)> {
+        self.endpoints.iter()
+    }
+
+    /// Endpoint of the connection to `peer`, if connected.
+    pub(crate) fn endpoint(&self, peer: &PeerId) -> Option<&ConnectedPoint> {
+        self.endpoints.get(peer)
+    }
+
+    /// Handle a message received on [`AUTONAT_TOPIC`] from `source`.
+    pub(crate) fn inject_probe(&mut self, source: &PeerId, data: &[u8]) {
+        match serde_json::from_slice(data) {
+            Ok(Probe::DialRequest { to, nonce, addrs }) if to == self.local_peer_id.to_base58() => {
+                self.serve(source, nonce, addrs)
+            }
+            Ok(Probe::DialResponse { to, nonce, reached })
+                if to == self.local_peer_id.to_base58() =>
+            {
+                self.confirm(source, nonce, reached)
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("ignoring malformed autonat message: {}", e),
+        }
+    }
+
+    /// Dial `requester` back at those of `addrs` on the IP address it is connected from.
+    fn serve(&mut self, requester: &PeerId, nonce: u64, addrs: Vec<String>) {
+        let ip = match self
+            .endpoints
+            .get(requester)
+            .and_then(|endpoint| remote_addr(endpoint).iter().next())
+        {
+            Some(ip @ Protocol::Ip4(_)) | Some(ip @ Protocol::Ip6(_)) => ip,
+            _ => return,
+        };
+        if self.serving >= MAX_CONCURRENT_REQUESTS {
+            log::debug!(
+                "too many dial-back requests, ignoring that of {}",
+                requester
+            );
+            return;
+        }
+        let addrs: Vec<Multiaddr> = addrs
+            .iter()
+            .filter_map(|addr| addr.parse::<Multiaddr>().ok())
+            .filter(|addr| addr.iter().next().as_ref() == Some(&ip))
+            .take(MAX_DIAL_BACKS)
+            .collect();
+        let transport = match build_transport(
+            identity::Keypair::generate_ed25519().into(),
+            self.psk,
+            self.legacy_secio,
+            ConnectionTracker::default(),
+        ) {
+            Ok(transport) => transport,
+            Err(e) => {
+                log::warn!("not dialing {} back: {}", requester, e);
+                return;
+            }
+        };
+        self.serving += 1;
+        let requester = requester.clone();
+        let dial_backs = self.dial_backs.0.clone();
+        task::spawn(async move {
+            let reached = dial_back(transport, &requester, addrs).await;
+            let _ = dial_backs.unbounded_send(DialBack {
+                peer: requester,
+                nonce,
+                reached,
+            });
+        });
+    }
+
+    /// Take in the reply of `peer` to the dial-back request `nonce`.
+    fn confirm(&mut self, peer: &PeerId, nonce: u64, reached: Vec<String>) {
+        let addrs = match self.pending.remove(&nonce) {
+            Some((asked, addrs, _)) if asked == *peer => addrs,
+            Some(request) => {
+                self.pending.insert(nonce, request);
+                return;
+            }
+            None => return,
+        };
+        let reached: Vec<Multiaddr> = reached
+            .iter()
+            .filter_map(|addr| addr.parse().ok())
+            .filter(|addr| addrs.contains(addr))
+            .collect();
+        let reachability = if reached.is_empty() {
+            Reachability::Private
+        } else {
+            Reachability::Public
+        };
+        if reachability != self.reachability {
+            log::info!("reachability changed to {:?}", reachability);
+            self.reachability = reachability;
+        }
+        for addr in reached {
+            if !self.confirmed.contains(&addr) {
+                log::info!("Reachable at {}/ipfs/{}", addr, self.local_peer_id);
+                self.confirmed.push(addr.clone());
+                self.events
+                    .push_back(NetworkBehaviourAction::ReportObservedAddr { address: addr });
+            }
+        }
+    }
+
+    /// Ask up to [`PROBE_PEERS`] connected peers to dial this node back at its candidate
+    /// addresses.
+    fn probe(&mut self, params: &mut impl PollParameters) {
+        let now = Instant::now();
+        self.pending.retain(|_, (_, _, expires)| *expires > now);
+        let listened: Vec<Multiaddr> = params
+            .listened_addresses()
+            .filter(|addr| !relay::is_circuit(addr))
+            .collect();
+        let mut addrs = listened.clone();
+        for observed in &self.observed {
+            for listen_addr in &listened {
+                if let Some(addr) = address_translation(listen_addr, observed) {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+            }
+        }
+        if addrs.is_empty() {
+            return;
+        }
+        let peers: Vec<PeerId> = self.endpoints.keys().take(PROBE_PEERS).cloned().collect();
+        for peer in peers {
+            self.next_nonce = self.next_nonce.wrapping_add(1);
+            let request = Probe::DialRequest {
+                to: peer.to_base58(),
+                nonce: self.next_nonce,
+                addrs: addrs.iter().map(Multiaddr::to_string).collect(),
+            };
+            match serde_json::to_vec(&request) {
+                Ok(data) => {
+                    self.pending.insert(
+                        self.next_nonce,
+                        (peer.clone(), addrs.clone(), now + PROBE_TIMEOUT),
+                    );
+                    self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                        AutoNatEvent::Send { peer, data },
+                    ));
+                }
+                Err(e) => log::warn!("failed to encode dial-back request: {}", e),
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for AutoNat {
+    type ProtocolsHandler = <Identify as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = AutoNatEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+        self.endpoints.insert(peer_id.clone(), endpoint.clone());
+        self.inner.inject_connected(peer_id, endpoint)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId, endpoint: ConnectedPoint) {
+        self.endpoints.remove(peer_id);
+        self.inner.inject_disconnected(peer_id, endpoint)
+    }
+
+    fn inject_node_event(
+        &mut self,
+        peer_id: PeerId,
+        event: <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.inner.inject_node_event(peer_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<(), AutoNatEvent>> {
+        while let Poll::Ready(Some(dial_back)) = self.dial_backs.1.poll_next_unpin(cx) {
+            self.serving -= 1;
+            let response = Probe::DialResponse {
+                to: dial_back.peer.to_base58(),
+                nonce: dial_back.nonce,
+                reached: dial_back.reached.iter().map(Multiaddr::to_string).collect(),
+            };
+            match serde_json::to_vec(&response) {
+                Ok(data) => self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    AutoNatEvent::Send {
+                        peer: dial_back.peer,
+                        data,
+                    },
+                )),
+                Err(e) => log::warn!("failed to encode dial-back response: {}", e),
+            }
+        }
+        while let Poll::Ready(Some(())) = self.probe_interval.poll_next_unpin(cx) {
+            self.probe_due = true;
+        }
+        if std::mem::take(&mut self.probe_due) {
+            self.probe(params);
+        }
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Poll::Ready(event);
+            }
+            let polled = if self.announce_addrs.is_empty() {
+                self.inner.poll(cx, params)
+            } else {
+                let mut announced = Announced {
+                    params: &*params,
+                    addrs: &self.announce_addrs,
+                };
+                self.inner.poll(cx, &mut announced)
+            };
+            let action = match polled {
+                Poll::Ready(action) => action,
+                Poll::Pending => return Poll::Pending,
+            };
+            return Poll::Ready(match action {
+                // Held back until confirmed.
+                NetworkBehaviourAction::ReportObservedAddr { address } => {
+                    if !relay::is_circuit(&address) && !self.observed.contains(&address) {
+                        self.observed.push(address);
+                        self.probe(params);
+                    }
+                    continue;
+                }
+                NetworkBehaviourAction::GenerateEvent(event) => {
+                    NetworkBehaviourAction::GenerateEvent(AutoNatEvent::Identify(Box::new(event)))
+                }
+                NetworkBehaviourAction::DialAddress { address } => {
+                    NetworkBehaviourAction::DialAddress { address }
+                }
+                NetworkBehaviourAction::DialPeer { peer_id } => {
+                    NetworkBehaviourAction::DialPeer { peer_id }
+                }
+                NetworkBehaviourAction::SendEvent { peer_id, event } => {
+                    NetworkBehaviourAction::SendEvent { peer_id, event }
+                }
+            });
+        }
+    }
+}
+
+/// Poll parameters of the swarm, with `addrs` as the only addresses of the node, for identify to
+/// advertise them alone.
+struct Announced<'a, P> {
+    params: &'a P,
+    addrs: &'a [Multiaddr],
+}
+
+impl<'a, P: PollParameters> PollParameters for Announced<'a, P> {
+    type SupportedProtocolsIter = P::SupportedProtocolsIter;
+    type ListenedAddressesIter = iter::Empty<Multiaddr>;
+    type ExternalAddressesIter = iter::Cloned<std::slice::Iter<'a, Multiaddr>>;
+
+    fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+        self.params.supported_protocols()
+    }
+
+    fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+        iter::empty()
+    }
+
+    fn external_addresses(&self) -> Self::ExternalAddressesIter {
+        self.addrs.iter().cloned()
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        self.params.local_peer_id()
+    }
+}
+
+/// Address of the other end of a connection.
+fn remote_addr(endpoint: &ConnectedPoint) -> &Multiaddr {
+    match endpoint {
+        ConnectedPoint::Dialer { address } => address,
+        ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+    }
+}
+
+/// Dial each of `addrs` over `transport`, returning those at which `peer` was reached.
+async fn dial_back<T, M>(transport: T, peer: &PeerId, addrs: Vec<Multiaddr>) -> Vec<Multiaddr>
+where
+    T: Transport<Output = (PeerId, M)> + Clone,
+    T::Error: fmt::Display,
+{
+    let mut reached = Vec::new();
+    for addr in addrs {
+        let dial = match transport.clone().dial(addr.clone()) {
+            Ok(dial) => dial,
+            Err(e) => {
+                log::debug!("cannot dial {} back: {}", addr, e);
+                continue;
+            }
+        };
+        match timeout(DIAL_BACK_TIMEOUT, dial).await {
+            Ok(Ok((dialed, _))) if dialed == *peer => reached.push(addr),
+            Ok(Ok((dialed, _))) => log::debug!("dialed {} back, reached {}", addr, dialed),
+            Ok(Err(e)) => log::debug!("failed to dial {} back: {}", addr, e),
+            Err(_) => log::debug!("timed out dialing {} back", addr),
+        }
+    }
+    reached
+}
@@ -0,0 +1,407 @@
+//! Accounting of the gossipsub traffic exchanged with each peer and on each topic, and rate
+//! limiting of the peers sending too much.
+//!
+//! [`Metered`] wraps the gossipsub behaviour of a node and counts the RPCs going through it. The
+//! size of an RPC is that of the messages, subscriptions and control messages it carries, not
+//! counting protobuf framing. With a [`RateLimit`], every peer gets a token bucket filled at the
+//! configured rate: a peer whose RPCs overflow it is pruned from the meshes of its topics and
+//! graylisted, its RPCs being ignored until the graylisting expires. The RPCs it lets through
+//! are also [traced](crate::trace) if the node traces gossip, and faults are injected into the
+//! RPCs exchanged if the node is configured with [`chaos`](crate::chaos).
+
+use crate::{
+    chaos::Chaos,
+    stats::ChaosStats,
+    trace::{Direction, TraceEvent, Tracer},
+};
+use libp2p::{
+    core::ConnectedPoint,
+    gossipsub::{
+        protocol::{GossipsubControlAction, GossipsubSubscriptionAction, MessageId},
+        Gossipsub, GossipsubEvent, GossipsubRpc, TopicHash,
+    },
+    swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Token bucket limiting the gossipsub traffic each peer sends.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Rate at which the bucket of a peer fills up.
+    pub bytes_per_second: u64,
+    /// Capacity of the bucket, the largest burst a peer may send. Should be at least the
+    /// gossipsub `max_transmit_size`, or large messages always overflow it.
+    pub burst: u64,
+    /// How long a peer overflowing its bucket is ignored.
+    pub graylist: Duration,
+}
+
+/// Traffic exchanged with a peer or on a topic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+/// What is known of a connected peer.
+#[derive(Default)]
+struct Peer {
+    traffic: Traffic,
+    /// Tokens left in the bucket, and when they were counted.
+    bucket: Option<(f64, Instant)>,
+    /// Topics the peer is subscribed to, pruned when it is graylisted.
+    topics: HashSet<TopicHash>,
+}
+
+/// A gossipsub behaviour accounting for its traffic and rate limiting peers.
+pub struct Metered {
+    inner: Gossipsub,
+    rate_limit: Option<RateLimit>,
+    /// The rate limit the node was started with.
+    configured: Option<RateLimit>,
+    peers: HashMap<PeerId, Peer>,
+    topics: HashMap<TopicHash, Traffic>,
+    /// Graylisted peers, until when. Kept across reconnections.
+    graylist: HashMap<PeerId, Instant>,
+    graylistings: u64,
+    /// Bytes sent to every peer since the node started.
+    bytes_out: u64,
+    tracer: Option<Tracer>,
+    chaos: Option<Chaos>,
+}
+
+impl Metered {
+    pub fn new(inner: Gossipsub, rate_limit: Option<RateLimit>) -> Self {
+        Metered {
+            inner,
+            rate_limit,
+            configured: rate_limit,
+            peers: HashMap::new(),
+            topics: HashMap::new(),
+            graylist: HashMap::new(),
+            graylistings: 0,
+            bytes_out: 0,
+            tracer: None,
+            chaos: None,
+        }
+    }
+
+    /// Traffic exchanged with each connected peer.
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &Traffic)> {
+        self.peers
+            .iter()
+            .map(|(peer, state)| (peer, &state.traffic))
+    }
+
+    /// Traffic exchanged on each topic since the node started.
+    pub fn topics(&self) -> impl Iterator<Item = (&TopicHash, &Traffic)> {
+        self.topics.iter()
+    }
+
+    /// Whether the RPCs of `peer` are being ignored.
+    pub fn is_graylisted(&self, peer: &PeerId) -> bool {
+        self.graylist
+            .get(peer)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Replace the rate limit of peers, or restore the one the node was started with if `None`.
+    /// Buckets keep their tokens, up to the new burst.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limit = rate_limit.or(self.configured);
+        if self.rate_limit.is_none() {
+            for peer in self.peers.values_mut() {
+                peer.bucket = None;
+            }
+        }
+    }
+
+    /// Trace the RPCs exchanged from now on.
+    pub(crate) fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Inject faults into the RPCs exchanged from now on.
+    pub(crate) fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(chaos);
+    }
+
+    /// Faults injected since the node started.
+    pub fn chaos(&self) -> ChaosStats {
+        self.chaos.as_ref().map(Chaos::stats).unwrap_or_default()
+    }
+
+    /// The traced events, only those naming `id` if given, or `None` if gossip is not traced.
+    pub fn trace(&self, id: Option<&MessageId>) -> Option<Vec<TraceEvent>> {
+        self.tracer.as_ref().map(|tracer| tracer.events(id))
+    }
+
+    /// Number of times a peer has been graylisted since the node started.
+    pub fn graylistings(&self) -> u64 {
+        self.graylistings
+    }
+
+    /// Bytes sent to every peer since the node started, those disconnected since included.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out
+    }
+
+    /// Take `size` bytes out of the bucket of `peer`, returning whether there were enough.
+    fn admit(&mut self, peer: &PeerId, size: usize) -> bool {
+        let limit = match self.rate_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        let now = Instant::now();
+        let state = self.peers.entry(peer.clone()).or_default();
+        let (tokens, updated) = state.bucket.unwrap_or((limit.burst as f64, now));
+        let tokens = (tokens
+            + now.duration_since(updated).as_secs_f64() * limit.bytes_per_second as f64)
+            .min(limit.burst as f64);
+        let admitted = tokens >= size as f64;
+        let left = if admitted {
+            tokens - size as f64
+        } else {
+            tokens
+        };
+        state.bucket = Some((left, now));
+        admitted
+    }
+
+    /// Ignore `peer` for a while, and prune it from the meshes of its topics.
+    fn graylist(&mut self, peer: PeerId, limit: RateLimit) {
+        log::warn!(
+            "graylisting {} for {:?}: it exceeded its rate limit",
+            peer,
+            limit.graylist
+        );
+        self.graylist
+            .insert(peer.clone(), Instant::now() + limit.graylist);
+        self.graylistings += 1;
+        let state = self.peers.entry(peer.clone()).or_default();
+        state.bucket = None;
+        let prunes = state
+            .topics
+            .iter()
+            .map(|topic_hash| GossipsubControlAction::Prune {
+                topic_hash: topic_hash.clone(),
+                peers: Vec::new(),
+            })
+            .collect();
+        // Gossipsub has no way to prune a peer of its own accord, so act as if the peer left.
+        self.inner.inject_node_event(
+            peer,
+            GossipsubRpc {
+                messages: Vec::new(),
+                subscriptions: Vec::new(),
+                control_msgs: prunes,
+            },
+        );
+    }
+
+    /// Hand an RPC received from `peer` to gossipsub, unless the peer is graylisted or exceeds
+    /// its rate limit.
+    fn receive(&mut self, peer_id: PeerId, event: GossipsubRpc) {
+        self.record(&peer_id, &event, true);
+        if self.is_graylisted(&peer_id) {
+            return;
+        }
+        if !self.admit(&peer_id, rpc_size(&event)) {
+            if let Some(limit) = self.rate_limit {
+                self.graylist(peer_id, limit);
+            }
+            return;
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(&peer_id, &event, Direction::Received);
+        }
+        self.inner.inject_node_event(peer_id, event)
+    }
+
+    /// Account for an RPC sent to `peer`.
+    fn send(
+        &mut self,
+        peer_id: PeerId,
+        event: GossipsubRpc,
+    ) -> NetworkBehaviourAction<GossipsubRpc, GossipsubEvent> {
+        self.record(&peer_id, &event, false);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record(&peer_id, &event, Direction::Sent);
+        }
+        NetworkBehaviourAction::SendEvent { peer_id, event }
+    }
+
+    fn record(&mut self, peer: &PeerId, rpc: &GossipsubRpc, inbound: bool) {
+        let size = rpc_size(rpc) as u64;
+        let messages = rpc.messages.len() as u64;
+        let state = self.peers.entry(peer.clone()).or_default();
+        let traffic = &mut state.traffic;
+        if inbound {
+            traffic.bytes_in += size;
+            traffic.messages_in += messages;
+            for subscription in &rpc.subscriptions {
+                match subscription.action {
+                    GossipsubSubscriptionAction::Subscribe => {
+                        state.topics.insert(subscription.topic_hash.clone())
+                    }
+                    GossipsubSubscriptionAction::Unsubscribe => {
+                        state.topics.remove(&subscription.topic_hash)
+                    }
+                };
+            }
+        } else {
+            traffic.bytes_out += size;
+            traffic.messages_out += messages;
+            self.bytes_out += size;
+        }
+        for message in &rpc.messages {
+            for topic in &message.topics {
+                let traffic = self.topics.entry(topic.clone()).or_default();
+                if inbound {
+                    traffic.bytes_in += message.data.len() as u64;
+                    traffic.messages_in += 1;
+                } else {
+                    traffic.bytes_out += message.data.len() as u64;
+                    traffic.messages_out += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Deref for Metered {
+    type Target = Gossipsub;
+
+    fn deref(&self) -> &Gossipsub {
+        &self.inner
+    }
+}
+
+impl DerefMut for Metered {
+    fn deref_mut(&mut self) -> &mut Gossipsub {
+        &mut self.inner
+    }
+}
+
+impl NetworkBehaviour for Metered {
+    type ProtocolsHandler = <Gossipsub as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = GossipsubEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer_id)
+    }
+
+    fn inject_connected(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+        self.inner.inject_connected(peer_id, endpoint)
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId, endpoint: ConnectedPoint) {
+        self.peers.remove(peer_id);
+        if let Some(chaos) = &mut self.chaos {
+            chaos.forget(peer_id);
+        }
+        self.graylist.retain(|_, until| *until > Instant::now());
+        self.inner.inject_disconnected(peer_id, endpoint)
+    }
+
+    fn inject_node_event(&mut self, peer_id: PeerId, event: GossipsubRpc) {
+        let event = match &mut self.chaos {
+            Some(chaos) => chaos.inject(&peer_id, event, Direction::Received),
+            None => Some(event),
+        };
+        if let Some(event) = event {
+            self.receive(peer_id, event);
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<GossipsubRpc, GossipsubEvent>> {
+        loop {
+            if let Some(Poll::Ready((peer_id, event, direction))) =
+                self.chaos.as_mut().map(|chaos| chaos.poll_due(cx))
+            {
+                match direction {
+                    Direction::Received => {
+                        self.receive(peer_id, event);
+                        continue;
+                    }
+                    Direction::Sent => return Poll::Ready(self.send(peer_id, event)),
+                }
+            }
+            let (peer_id, event) = match futures::ready!(self.inner.poll(cx, params)) {
+                NetworkBehaviourAction::SendEvent { peer_id, event } => (peer_id, event),
+                action => return Poll::Ready(action),
+            };
+            let event = match &mut self.chaos {
+                Some(chaos) => chaos.inject(&peer_id, event, Direction::Sent),
+                None => Some(event),
+            };
+            if let Some(event) = event {
+                return Poll::Ready(self.send(peer_id, event));
+            }
+        }
+    }
+}
+
+/// Size of the contents of `rpc`.
+fn rpc_size(rpc: &GossipsubRpc) -> usize {
+    let messages: usize = rpc
+        .messages
+        .iter()
+        .map(|message| {
+            message.source.as_bytes().len()
+                + message.data.len()
+                + 8
+                + message
+                    .topics
+                    .iter()
+                    .map(|t| t.as_str().len())
+                    .sum::<usize>()
+        })
+        .sum();
+    let subscriptions: usize = rpc
+        .subscriptions
+        .iter()
+        .map(|subscription| 1 + subscription.topic_hash.as_str().len())
+        .sum();
+    let control: usize = rpc
+        .control_msgs
+        .iter()
+        .map(|control| match control {
+            GossipsubControlAction::IHave {
+                topic_hash,
+                message_ids,
+            } => topic_hash.as_str().len() + message_ids.iter().map(|id| id.0.len()).sum::<usize>(),
+            GossipsubControlAction::IWant { message_ids } => {
+                message_ids.iter().map(|id| id.0.len()).sum()
+            }
+            GossipsubControlAction::Graft { topic_hash } => topic_hash.as_str().len(),
+            GossipsubControlAction::Prune { topic_hash, peers } => {
+                topic_hash.as_str().len()
+                    + peers
+                        .iter()
+                        .map(|info| {
+                            info.peer_id.as_bytes().len()
+                                + info.signed_peer_record.as_ref().map_or(0, Vec::len)
+                        })
+                        .sum::<usize>()
+            }
+        })
+        .sum();
+    messages + subscriptions + control
+}
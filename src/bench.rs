@@ -0,0 +1,247 @@
+//! Benchmarks of end-to-end latency and delivery rate, to size the gossipsub parameters of a
+//! deployment against its own network.
+//!
+//! [`publish`] sends [`BenchConfig::count`] test messages on a topic at [`BenchConfig::rate`]
+//! messages per second, each [`BenchConfig::size`] bytes long, as an ordinary client would. On
+//! the other nodes, a [`Recorder`] fed with the messages of that topic reports, for every run of
+//! every publisher, how many of its messages arrived, how many arrived more than once, and the
+//! median and 99th percentile of the time they took.
+//!
+//! A test message starts with a header of [`HEADER_LEN`] bytes: the magic `pslb`, then as
+//! big-endian 64-bit integers the id of the run, the sequence number of the message in the run,
+//! the number of messages of the run and the time it was published, in microseconds since the
+//! Unix epoch; zeros pad it to the size asked for. Latencies are thus only as accurate as the
+//! clocks of the publisher and the receiver agree, and are counted as zero when the receiver is
+//! behind.
+
+use crate::{Client, Error, Message};
+use async_std::task;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Magic test messages start with.
+const MAGIC: &[u8; 4] = b"pslb";
+
+/// Length of the header of a test message, the smallest size it can have.
+pub const HEADER_LEN: usize = 36;
+
+/// Configuration of a benchmark run.
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    /// Messages published per second.
+    pub rate: u32,
+    /// Size of a message in bytes, at least [`HEADER_LEN`].
+    pub size: usize,
+    /// Messages published in the run.
+    pub count: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            rate: 100,
+            size: 256,
+            count: 1000,
+        }
+    }
+}
+
+/// Outcome of [`publish`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Published {
+    /// Id of the run, as receivers report it.
+    pub run: u64,
+    pub count: u64,
+    pub elapsed: Duration,
+}
+
+/// Publish the test messages of a run on `topic`, pacing them to the rate of `config`.
+pub async fn publish(
+    client: &Client,
+    topic: &str,
+    config: &BenchConfig,
+) -> Result<Published, Error> {
+    if config.rate == 0 {
+        return Err("benchmark rate must be positive".into());
+    }
+    let run = now_us();
+    let start = Instant::now();
+    for seq in 0..config.count {
+        let due = start + Duration::from_secs_f64(seq as f64 / f64::from(config.rate));
+        let now = Instant::now();
+        if due > now {
+            task::sleep(due - now).await;
+        }
+        let mut data = Vec::with_capacity(config.size.max(HEADER_LEN));
+        data.extend_from_slice(MAGIC);
+        for field in &[run, seq, config.count, now_us()] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        data.resize(config.size.max(HEADER_LEN), 0);
+        client.publish(topic, data)?;
+    }
+    Ok(Published {
+        run,
+        count: config.count,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Delivery of one run, as seen by a receiver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Peer that published the run.
+    pub source: PeerId,
+    pub run: u64,
+    /// Messages published in the run.
+    pub expected: u64,
+    /// Distinct messages received.
+    pub received: u64,
+    /// Copies of messages received again.
+    pub duplicates: u64,
+    /// Median end-to-end latency of the messages received.
+    pub p50: Duration,
+    /// 99th percentile end-to-end latency of the messages received.
+    pub p99: Duration,
+}
+
+impl Report {
+    /// Share of the messages of the run received, from 0 to 1.
+    pub fn delivery_rate(&self) -> f64 {
+        if self.expected == 0 {
+            return 1.0;
+        }
+        self.received as f64 / self.expected as f64
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "run {} from {}: {}/{} delivered ({:.2}%), {} duplicates, p50 {:?}, p99 {:?}",
+            self.run,
+            self.source.to_base58(),
+            self.received,
+            self.expected,
+            self.delivery_rate() * 100.0,
+            self.duplicates,
+            self.p50,
+            self.p99
+        )
+    }
+}
+
+/// Tallies the test messages received, by publisher and run.
+#[derive(Default)]
+pub struct Recorder {
+    runs: HashMap<(PeerId, u64), Run>,
+}
+
+struct Run {
+    expected: u64,
+    seen: HashSet<u64>,
+    duplicates: u64,
+    /// Latencies of the distinct messages, in microseconds.
+    latencies: Vec<u64>,
+    last: Instant,
+    reported: bool,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    /// Tally `message`, returning whether it is a test message.
+    pub fn record(&mut self, message: &Message) -> bool {
+        let received = now_us();
+        let data = &message.data;
+        if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+            return false;
+        }
+        let field = |i: usize| {
+            let start = 4 + 8 * i;
+            data[start..start + 8]
+                .try_into()
+                .map_or(0, u64::from_be_bytes)
+        };
+        let (run, seq, expected, sent) = (field(0), field(1), field(2), field(3));
+        let entry = self
+            .runs
+            .entry((message.source.clone(), run))
+            .or_insert_with(|| Run {
+                expected,
+                seen: HashSet::new(),
+                duplicates: 0,
+                latencies: Vec::new(),
+                last: Instant::now(),
+                reported: false,
+            });
+        if entry.seen.insert(seq) {
+            entry.latencies.push(received.saturating_sub(sent));
+        } else {
+            entry.duplicates += 1;
+        }
+        entry.last = Instant::now();
+        entry.reported = false;
+        true
+    }
+
+    /// Reports of every run so far.
+    pub fn reports(&self) -> Vec<Report> {
+        self.runs
+            .iter()
+            .map(|((source, run), entry)| entry.report(source, *run))
+            .collect()
+    }
+
+    /// Reports of the runs nothing was received of for `idle`, and not reported since, as when
+    /// they are over.
+    pub fn finished(&mut self, idle: Duration) -> Vec<Report> {
+        self.runs
+            .iter_mut()
+            .filter(|(_, entry)| !entry.reported && entry.last.elapsed() >= idle)
+            .map(|((source, run), entry)| {
+                entry.reported = true;
+                entry.report(source, *run)
+            })
+            .collect()
+    }
+}
+
+impl Run {
+    fn report(&self, source: &PeerId, run: u64) -> Report {
+        let mut latencies = self.latencies.clone();
+        latencies.sort_unstable();
+        Report {
+            source: source.clone(),
+            run,
+            expected: self.expected,
+            received: self.seen.len() as u64,
+            duplicates: self.duplicates,
+            p50: percentile(&latencies, 50),
+            p99: percentile(&latencies, 99),
+        }
+    }
+}
+
+/// The `p`th percentile of sorted latencies in microseconds, by nearest rank.
+fn percentile(sorted: &[u64], p: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::from_secs(0);
+    }
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    Duration::from_micros(sorted[rank - 1])
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
@@ -0,0 +1,242 @@
+//! Bridges relaying messages between the gossipsub mesh and external messaging systems.
+//!
+//! Each bridge lives in its own module behind a cargo feature of the same name.
+//!
+//! Bridges, and sinks, report their health to the node they run on: whether they are connected
+//! to the external system, what they relayed and how far behind they are show up in
+//! [`Client::stats`], and [`Client::bridge_alerts`] tells when one has been disconnected for
+//! longer than its alert threshold.
+
+use crate::{
+    bandwidth::Traffic,
+    client::BridgeAlert,
+    flow::Monitor,
+    node::Command,
+    stats::BridgeStats,
+    topology::{Component, ComponentKind, ComponentStatus},
+    Client, Error,
+};
+use serde::Deserialize;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "multicast")]
+pub mod multicast;
+#[cfg(feature = "nats")]
+pub mod nats;
+
+/// Which way messages flow across a bridge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From the external system into the mesh.
+    Inbound,
+    /// From the mesh to the external system.
+    Outbound,
+    /// Both ways.
+    #[default]
+    Both,
+}
+
+impl Direction {
+    pub fn inbound(self) -> bool {
+        self != Direction::Outbound
+    }
+
+    pub fn outbound(self) -> bool {
+        self != Direction::Inbound
+    }
+}
+
+/// Deserialize a duration given in seconds.
+#[cfg(any(feature = "mqtt", feature = "multicast"))]
+fn secs<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    if !(secs >= 0.0 && secs.is_finite()) {
+        return Err(serde::de::Error::custom(
+            "expected a non-negative number of seconds",
+        ));
+    }
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Remembers recently relayed payloads so that a bridge does not relay them back.
+///
+/// A message relayed from one side to the other usually comes back: the external system echoes
+/// our own publishes to our subscription, and other bridges attached to the same mesh and broker
+/// relay it once more. Whatever has crossed a bridge in either direction within the window is
+/// dropped when it shows up again.
+pub struct LoopGuard {
+    window: Duration,
+    seen: HashMap<u64, Instant>,
+}
+
+impl LoopGuard {
+    pub fn new(window: Duration) -> Self {
+        LoopGuard {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Record that `payload` is being relayed for the `route` pair of topics. Returns `false` if
+    /// the same payload already crossed the bridge within the window and must not be relayed.
+    pub fn admit(&mut self, route: &str, payload: &[u8]) -> bool {
+        let now = Instant::now();
+        let window = self.window;
+        self.seen
+            .retain(|_, seen_at| now.duration_since(*seen_at) < window);
+
+        let mut hasher = DefaultHasher::new();
+        route.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        self.seen.insert(hasher.finish(), now).is_none()
+    }
+}
+
+/// Health of a bridge, updated by the bridge and reported by the node it runs on.
+#[derive(Clone)]
+pub(crate) struct Health(Arc<Mutex<HealthState>>);
+
+struct HealthState {
+    kind: ComponentKind,
+    name: String,
+    config_hash: String,
+    alert_after: Duration,
+    connected: bool,
+    /// When `connected` last changed.
+    since: Instant,
+    /// Whether the current disconnection has been alerted on.
+    alerted: bool,
+    traffic: Traffic,
+    retries: u64,
+    outbox: Monitor,
+}
+
+// Only updated by the bridges of enabled features, sinks relaying nothing into the mesh.
+#[cfg_attr(
+    not(any(feature = "mqtt", feature = "multicast", feature = "nats")),
+    allow(dead_code)
+)]
+impl Health {
+    /// Register a bridge or sink called `name` with the node of `client`, the bridge starting
+    /// out disconnected.
+    pub(crate) fn register(
+        client: &Client,
+        kind: ComponentKind,
+        name: String,
+        config_hash: String,
+        alert_after: Duration,
+        outbox: Monitor,
+    ) -> Result<Self, Error> {
+        let health = Health(Arc::new(Mutex::new(HealthState {
+            kind,
+            name,
+            config_hash,
+            alert_after,
+            connected: false,
+            since: Instant::now(),
+            alerted: false,
+            traffic: Traffic::default(),
+            retries: 0,
+            outbox,
+        })));
+        client.send(Command::RegisterBridge {
+            health: health.clone(),
+        })?;
+        Ok(health)
+    }
+
+    pub(crate) fn connected(&self) {
+        let mut state = self.0.lock().unwrap();
+        if !state.connected {
+            state.connected = true;
+            state.since = Instant::now();
+        }
+    }
+
+    /// Record that connecting failed or the connection dropped.
+    pub(crate) fn disconnected(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.retries += 1;
+        if state.connected {
+            state.connected = false;
+            state.since = Instant::now();
+        }
+    }
+
+    /// Record a message of `size` bytes relayed into the mesh.
+    pub(crate) fn relayed_in(&self, size: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.traffic.bytes_in += size as u64;
+        state.traffic.messages_in += 1;
+    }
+
+    /// Record a message of `size` bytes relayed out of the mesh.
+    pub(crate) fn relayed_out(&self, size: usize) {
+        let mut state = self.0.lock().unwrap();
+        state.traffic.bytes_out += size as u64;
+        state.traffic.messages_out += 1;
+    }
+
+    /// Whether the bridge is gone, only the node holding on to its health.
+    pub(crate) fn is_orphan(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+
+    pub(crate) fn stats(&self) -> BridgeStats {
+        let state = self.0.lock().unwrap();
+        BridgeStats {
+            name: state.name.clone(),
+            connected: state.connected,
+            since: state.since.elapsed(),
+            traffic: state.traffic,
+            retries: state.retries,
+            outbox: state.outbox.status(),
+        }
+    }
+
+    /// The bridge as listed in the topology of the node.
+    pub(crate) fn component(&self) -> Component {
+        let state = self.0.lock().unwrap();
+        Component {
+            kind: state.kind,
+            name: state.name.clone(),
+            status: if state.connected {
+                ComponentStatus::Connected
+            } else {
+                ComponentStatus::Disconnected
+            },
+            config_hash: state.config_hash.clone(),
+        }
+    }
+
+    /// The alert to raise, if the bridge just crossed its alert threshold or recovered after an
+    /// alert.
+    pub(crate) fn check(&self) -> Option<BridgeAlert> {
+        let mut state = self.0.lock().unwrap();
+        let elapsed = state.since.elapsed();
+        match (state.connected, state.alerted) {
+            (false, false) if elapsed >= state.alert_after => {
+                state.alerted = true;
+                Some(BridgeAlert::Disconnected {
+                    bridge: state.name.clone(),
+                    since: elapsed,
+                })
+            }
+            (true, true) => {
+                state.alerted = false;
+                Some(BridgeAlert::Reconnected {
+                    bridge: state.name.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
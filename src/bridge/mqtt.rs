@@ -0,0 +1,437 @@
+//! Bidirectional bridge between an MQTT broker and the gossipsub mesh.
+//!
+//! The bridge speaks just enough MQTT 3.1.1 to relay QoS 0 messages: it connects to the broker,
+//! subscribes to the MQTT side of every inbound route, forwards what it receives into the mesh
+//! and publishes the messages of outbound routes to the broker. The connection is re-established
+//! with a backoff whenever it drops.
+//!
+//! Messages of outbound routes wait in an outbox bounded by [`MqttConfig::flow`], including while
+//! the broker is unreachable. The bridge stops reading from the broker while it has more than
+//! `flow.capacity` packets left to handle.
+
+pub use super::Direction;
+use super::{Health, LoopGuard};
+use crate::{
+    flow::{self, Closer, FlowControl},
+    topic::TopicFilter,
+    topology::{self, ComponentKind},
+    Client, Error, Message,
+};
+use async_std::{
+    future::timeout,
+    io,
+    net::{Shutdown, TcpStream},
+    stream, task,
+};
+use futures::{channel::mpsc, prelude::*, stream::SelectAll};
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+/// Pairs an MQTT topic with a gossipsub topic.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// MQTT topic. Inbound-only routes may use an MQTT topic filter with `+` and `#` wildcards.
+    pub mqtt_topic: String,
+    /// Gossipsub topic.
+    pub gossipsub_topic: String,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// Configuration of an MQTT bridge. Durations are deserialized from seconds.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Broker address, as `host:port`.
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Keep alive interval negotiated with the broker.
+    #[serde(deserialize_with = "super::secs")]
+    pub keep_alive: Duration,
+    pub routes: Vec<Route>,
+    /// Payloads that crossed the bridge within this window are not relayed again, see
+    /// [`LoopGuard`].
+    #[serde(deserialize_with = "super::secs")]
+    pub loop_window: Duration,
+    pub flow: FlowControl,
+    /// How long the bridge may be disconnected before it is alerted on, see
+    /// [`Client::bridge_alerts`].
+    #[serde(deserialize_with = "super::secs")]
+    pub alert_after: Duration,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            broker: "127.0.0.1:1883".into(),
+            client_id: "pubsub-lite-bridge".into(),
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(30),
+            routes: Vec::new(),
+            loop_window: Duration::from_secs(2),
+            flow: FlowControl::default(),
+            alert_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Start relaying messages along the configured routes on a background task.
+pub fn spawn(client: &Client, config: MqttConfig) -> Result<task::JoinHandle<()>, Error> {
+    start(client, config).map(|(task, _)| task)
+}
+
+/// Start the bridge, also returning a handle stopping it: the bridge then relays the mesh
+/// messages already in its outbox if it is connected, and disconnects.
+pub(crate) fn start(
+    client: &Client,
+    config: MqttConfig,
+) -> Result<(task::JoinHandle<()>, Closer), Error> {
+    let mut mesh_messages = SelectAll::new();
+    for route in &config.routes {
+        let filter = TopicFilter::new(&route.mqtt_topic)?;
+        if filter.is_wildcard() && route.direction.outbound() {
+            return Err(format!(
+                "MQTT topic {:?} has wildcards and cannot be published to",
+                route.mqtt_topic
+            )
+            .into());
+        }
+        if route.direction.outbound() {
+            mesh_messages.push(client.subscribe(&route.gossipsub_topic)?);
+        }
+    }
+    let outbox = flow::outbox(client, mesh_messages, &config.flow);
+    let health = Health::register(
+        client,
+        ComponentKind::Bridge,
+        format!("MQTT bridge {}@{}", config.client_id, config.broker),
+        topology::config_hash(&config),
+        config.alert_after,
+        outbox.monitor(),
+    )?;
+    let closer = outbox.closer();
+    Ok((
+        task::spawn(run(client.clone(), config, outbox, health)),
+        closer,
+    ))
+}
+
+async fn run(
+    client: Client,
+    config: MqttConfig,
+    mut outbox: flow::Receiver<Message>,
+    health: Health,
+) {
+    let mut guard = LoopGuard::new(config.loop_window);
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match session(&client, &config, &mut outbox, &mut guard, &health).await {
+            Ok(()) => return,
+            Err(e) => {
+                health.disconnected();
+                log::warn!("MQTT bridge to {}: {}", config.broker, e)
+            }
+        }
+        if outbox.is_exhausted() {
+            return;
+        }
+        if started.elapsed() > Duration::from_secs(60) {
+            backoff = Duration::from_secs(1);
+        }
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
+
+/// Events driving a connected session.
+enum Event {
+    Mqtt(Packet),
+    Mesh(Box<Message>),
+    KeepAlive,
+}
+
+/// Run one connection to the broker until it fails. Returns `Ok` once the node has shut down or
+/// the bridge was stopped.
+async fn session(
+    client: &Client,
+    config: &MqttConfig,
+    outbox: &mut flow::Receiver<Message>,
+    guard: &mut LoopGuard,
+    health: &Health,
+) -> Result<(), Error> {
+    let mut stream = TcpStream::connect(&config.broker).await?;
+    write_packet(&mut stream, &connect_packet(config)).await?;
+    match timeout(config.keep_alive, read_packet(&mut stream)).await?? {
+        Packet::ConnAck { return_code: 0 } => {}
+        Packet::ConnAck { return_code } => {
+            return Err(format!("broker refused connection, return code {}", return_code).into())
+        }
+        other => return Err(format!("expected CONNACK, got {:?}", other).into()),
+    }
+    log::info!("MQTT bridge connected to {}", config.broker);
+    health.connected();
+
+    let filters: Vec<&str> = config
+        .routes
+        .iter()
+        .filter(|route| route.direction.inbound())
+        .map(|route| route.mqtt_topic.as_str())
+        .collect();
+    if !filters.is_empty() {
+        write_packet(&mut stream, &subscribe_packet(1, &filters)).await?;
+    }
+
+    let (packets_in, mut packets) = mpsc::channel(config.flow.capacity);
+    task::spawn(read_packets(stream.clone(), packets_in));
+    let mut keep_alive = stream::interval(config.keep_alive / 2);
+    let result = loop {
+        let event = futures::select! {
+            packet = packets.next() => match packet {
+                Some(Ok(packet)) => Event::Mqtt(packet),
+                Some(Err(e)) => break Err(e.into()),
+                None => break Err("connection closed".into()),
+            },
+            message = outbox.next() => match message {
+                Some(message) => Event::Mesh(Box::new(message)),
+                None => break Ok(()),
+            },
+            _ = keep_alive.next().fuse() => Event::KeepAlive,
+        };
+        let handled = match event {
+            Event::Mqtt(Packet::Publish {
+                topic,
+                packet_id,
+                payload,
+            }) => {
+                let mut relayed = Ok(());
+                for route in config
+                    .routes
+                    .iter()
+                    .filter(|route| route.direction.inbound())
+                {
+                    let matches = TopicFilter::new(&route.mqtt_topic)
+                        .is_ok_and(|filter| filter.matches(&topic));
+                    if matches && guard.admit(&route.gossipsub_topic, &payload) {
+                        relayed = client.publish(&route.gossipsub_topic, payload.clone());
+                        health.relayed_in(payload.len());
+                    }
+                }
+                match (relayed, packet_id) {
+                    (Err(e), _) => break Err(e),
+                    (Ok(()), Some(id)) => write_packet(&mut stream, &puback_packet(id)).await,
+                    (Ok(()), None) => Ok(()),
+                }
+            }
+            Event::Mqtt(Packet::Other { packet_type }) => {
+                log::trace!("MQTT bridge ignoring packet type {:#x}", packet_type);
+                Ok(())
+            }
+            Event::Mqtt(Packet::ConnAck { .. }) => Ok(()),
+            Event::Mesh(message) => {
+                let mut written = Ok(());
+                for route in config.routes.iter().filter(|route| {
+                    route.direction.outbound() && route.gossipsub_topic == message.topic
+                }) {
+                    if guard.admit(&route.gossipsub_topic, &message.data) {
+                        let packet = publish_packet(&route.mqtt_topic, &message.data);
+                        written = write_packet(&mut stream, &packet).await;
+                        if written.is_err() {
+                            break;
+                        }
+                        health.relayed_out(message.data.len());
+                    }
+                }
+                written
+            }
+            Event::KeepAlive => write_packet(&mut stream, &[PINGREQ, 0]).await,
+        };
+        if let Err(e) = handled {
+            break Err(e.into());
+        }
+    };
+    let _ = write_packet(&mut stream, &[DISCONNECT, 0]).await;
+    // Also ends the reader task.
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+async fn read_packets(mut stream: TcpStream, mut packets: mpsc::Sender<io::Result<Packet>>) {
+    loop {
+        let packet = read_packet(&mut stream).await;
+        let failed = packet.is_err();
+        // Waits while the session is behind, which leaves the broker waiting on TCP.
+        if packets.send(packet).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// The MQTT control packets the bridge cares about.
+#[derive(Debug)]
+enum Packet {
+    ConnAck {
+        return_code: u8,
+    },
+    Publish {
+        topic: String,
+        packet_id: Option<u16>,
+        payload: Vec<u8>,
+    },
+    Other {
+        packet_type: u8,
+    },
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+async fn read_packet(stream: &mut TcpStream) -> io::Result<Packet> {
+    let mut header = [0u8; 1];
+    stream.read_exact(&mut header).await?;
+
+    // Remaining length: up to four bytes of seven bits each, least significant first.
+    let mut remaining = 0usize;
+    for i in 0..4 {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await?;
+        remaining |= ((byte[0] & 0x7f) as usize) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if i == 3 {
+            return Err(invalid("malformed remaining length"));
+        }
+    }
+    let mut body = vec![0u8; remaining];
+    stream.read_exact(&mut body).await?;
+
+    match header[0] & 0xf0 {
+        CONNACK if body.len() == 2 => Ok(Packet::ConnAck {
+            return_code: body[1],
+        }),
+        PUBLISH => {
+            let qos = (header[0] >> 1) & 0x03;
+            let (topic, mut rest) = read_string(&body)?;
+            let packet_id = if qos > 0 {
+                if rest.len() < 2 {
+                    return Err(invalid("truncated PUBLISH"));
+                }
+                let id = u16::from_be_bytes([rest[0], rest[1]]);
+                rest = &rest[2..];
+                Some(id)
+            } else {
+                None
+            };
+            Ok(Packet::Publish {
+                topic,
+                packet_id,
+                payload: rest.to_vec(),
+            })
+        }
+        packet_type => Ok(Packet::Other { packet_type }),
+    }
+}
+
+fn read_string(bytes: &[u8]) -> io::Result<(String, &[u8])> {
+    if bytes.len() < 2 {
+        return Err(invalid("truncated string"));
+    }
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    let rest = &bytes[2..];
+    if rest.len() < len {
+        return Err(invalid("truncated string"));
+    }
+    let string = String::from_utf8(rest[..len].to_vec()).map_err(|_| invalid("invalid UTF-8"))?;
+    Ok((string, &rest[len..]))
+}
+
+async fn write_packet(stream: &mut TcpStream, packet: &[u8]) -> io::Result<()> {
+    stream.write_all(packet).await?;
+    stream.flush().await
+}
+
+/// Frame a packet: fixed header byte, remaining length, then the body.
+fn frame(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn push_string(body: &mut Vec<u8>, string: &str) {
+    let len = u16::try_from(string.len()).unwrap_or(u16::MAX);
+    body.extend_from_slice(&len.to_be_bytes());
+    body.extend_from_slice(&string.as_bytes()[..len as usize]);
+}
+
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_string(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+    let mut flags = 0x02; // clean session
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    let keep_alive = u16::try_from(config.keep_alive.as_secs()).unwrap_or(u16::MAX);
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    push_string(&mut body, &config.client_id);
+    if let Some(username) = &config.username {
+        push_string(&mut body, username);
+    }
+    if let Some(password) = &config.password {
+        push_string(&mut body, password);
+    }
+    frame(CONNECT, &body)
+}
+
+fn subscribe_packet(packet_id: u16, filters: &[&str]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    for filter in filters {
+        push_string(&mut body, filter);
+        body.push(0); // QoS 0
+    }
+    frame(SUBSCRIBE, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_string(&mut body, topic);
+    body.extend_from_slice(payload);
+    frame(PUBLISH, &body)
+}
+
+fn puback_packet(packet_id: u16) -> Vec<u8> {
+    frame(PUBACK, &packet_id.to_be_bytes())
+}
@@ -0,0 +1,310 @@
+//! Bridge mirroring topics onto UDP multicast groups of the local network, and the other way
+//! round, so that consumers on the same subnet, such as real-time dashboards, get messages with
+//! a single `recvfrom` and no libp2p stack.
+//!
+//! Each route pairs a gossipsub topic, or topic filter, with an IPv4 multicast group:
+//!
+//! ```json
+//! {
+//!     "interface": "192.168.1.10",
+//!     "routes": [
+//!         {"topic": "sensors/+/temp", "group": "239.1.2.3:5000", "direction": "outbound"},
+//!         {"topic": "commands", "group": "239.1.2.4:5000"}
+//!     ]
+//! }
+//! ```
+//!
+//! A datagram holds one message: its topic, a NUL byte, and its payload. Several topics can thus
+//! share a group, and a datagram received on the group of a route is published to its topic if
+//! the route's topic matches it; datagrams without a NUL byte are dropped. Messages that do not
+//! fit in a datagram, [`MAX_DATAGRAM`] bytes with their topic, are not relayed. Inbound routes
+//! on the same port share a socket, which receives the datagrams of all their groups.
+//!
+//! Multicast is unreliable: a consumer missing a datagram misses the message. Mesh messages wait
+//! for the socket in an outbox bounded by [`MulticastConfig::flow`], which only fills up when
+//! the host cannot send as fast as the mesh delivers.
+
+pub use super::Direction;
+use super::{Health, LoopGuard};
+use crate::{
+    flow::{self, Closer, FlowControl},
+    topic::TopicFilter,
+    topology::{self, ComponentKind},
+    Client, Error, Message,
+};
+use async_std::{io, net::UdpSocket, task};
+use futures::{
+    prelude::*,
+    stream::{self, SelectAll},
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddrV4},
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+/// Largest datagram sent or received, the most a UDP datagram over IPv4 can carry.
+pub const MAX_DATAGRAM: usize = 65_507;
+
+/// Pairs a gossipsub topic with a multicast group.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Gossipsub topic or topic filter. Inbound routes need a topic without wildcards, which
+    /// messages received from the group are published to.
+    pub topic: String,
+    /// Multicast group and port, such as `239.1.2.3:5000`.
+    pub group: SocketAddrV4,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// Configuration of a multicast bridge. Durations are deserialized from seconds.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MulticastConfig {
+    /// Address of the interface joining the groups of inbound routes, any interface if
+    /// unspecified.
+    pub interface: Ipv4Addr,
+    /// Time to live of the datagrams sent, 1 keeping them on the local subnet.
+    pub ttl: u32,
+    pub routes: Vec<Route>,
+    /// Payloads that crossed the bridge within this window are not relayed again, see
+    /// [`LoopGuard`].
+    #[serde(deserialize_with = "super::secs")]
+    pub loop_window: Duration,
+    pub flow: FlowControl,
+    /// How long the bridge may be without its sockets before it is alerted on, see
+    /// [`Client::bridge_alerts`].
+    #[serde(deserialize_with = "super::secs")]
+    pub alert_after: Duration,
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        MulticastConfig {
+            interface: Ipv4Addr::UNSPECIFIED,
+            ttl: 1,
+            routes: Vec::new(),
+            loop_window: Duration::from_secs(2),
+            flow: FlowControl::default(),
+            alert_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Start relaying messages along the configured routes on a background task.
+pub fn spawn(client: &Client, config: MulticastConfig) -> Result<task::JoinHandle<()>, Error> {
+    start(client, config).map(|(task, _)| task)
+}
+
+/// Start the bridge, also returning a handle stopping it: the bridge then sends the mesh
+/// messages already in its outbox and closes its sockets.
+pub(crate) fn start(
+    client: &Client,
+    config: MulticastConfig,
+) -> Result<(task::JoinHandle<()>, Closer), Error> {
+    let mut filters = Vec::with_capacity(config.routes.len());
+    let mut mesh_messages = SelectAll::new();
+    for route in &config.routes {
+        if !route.group.ip().is_multicast() {
+            return Err(format!("{} is not a multicast group", route.group).into());
+        }
+        let filter = TopicFilter::new(&route.topic)?;
+        if filter.is_wildcard() && route.direction.inbound() {
+            return Err(format!(
+                "topic {:?} has wildcards and cannot be published to",
+                route.topic
+            )
+            .into());
+        }
+        if route.direction.outbound() {
+            mesh_messages.push(if filter.is_wildcard() {
+                client.subscribe_filter(&route.topic)?
+            } else {
+                client.subscribe(&route.topic)?
+            });
+        }
+        filters.push(filter);
+    }
+    let outbox = flow::outbox(client, mesh_messages, &config.flow);
+    let health = Health::register(
+        client,
+        ComponentKind::Bridge,
+        format!("multicast bridge on {}", config.interface),
+        topology::config_hash(&config),
+        config.alert_after,
+        outbox.monitor(),
+    )?;
+    let closer = outbox.closer();
+    let task = task::spawn(run(client.clone(), config, filters, outbox, health));
+    Ok((task, closer))
+}
+
+async fn run(
+    client: Client,
+    config: MulticastConfig,
+    filters: Vec<TopicFilter>,
+    mut outbox: flow::Receiver<Message>,
+    health: Health,
+) {
+    let mut guard = LoopGuard::new(config.loop_window);
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match session(&client, &config, &filters, &mut outbox, &mut guard, &health).await {
+            Ok(()) => return,
+            Err(e) => {
+                health.disconnected();
+                log::warn!("multicast bridge on {}: {}", config.interface, e)
+            }
+        }
+        if outbox.is_exhausted() {
+            return;
+        }
+        if started.elapsed() > Duration::from_secs(60) {
+            backoff = Duration::from_secs(1);
+        }
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
+
+/// Relay messages until a socket fails. Returns `Ok` once the node has shut down or the bridge
+/// was stopped.
+async fn session(
+    client: &Client,
+    config: &MulticastConfig,
+    filters: &[TopicFilter],
+    outbox: &mut flow::Receiver<Message>,
+    guard: &mut LoopGuard,
+    health: &Health,
+) -> Result<(), Error> {
+    let sender = UdpSocket::bind(SocketAddrV4::new(config.interface, 0)).await?;
+    sender.set_multicast_ttl_v4(config.ttl)?;
+    // Keeps our own datagrams from coming back to the sockets of inbound routes.
+    sender.set_multicast_loop_v4(false)?;
+
+    // A socket per port of the inbound routes, joining every group on that port.
+    let inbound: Vec<&Route> = config
+        .routes
+        .iter()
+        .filter(|route| route.direction.inbound())
+        .collect();
+    let ports: BTreeSet<u16> = inbound.iter().map(|route| route.group.port()).collect();
+    let mut datagrams: SelectAll<Datagrams> = SelectAll::new();
+    for port in ports {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await?;
+        let groups: BTreeSet<Ipv4Addr> = inbound
+            .iter()
+            .filter(|route| route.group.port() == port)
+            .map(|route| *route.group.ip())
+            .collect();
+        for group in groups {
+            socket.join_multicast_v4(group, config.interface)?;
+        }
+        datagrams.push(receive(socket, port));
+    }
+    // Nothing inbound: wait for the outbox alone.
+    datagrams.push(Box::pin(stream::pending()));
+    log::info!("multicast bridge on {} started", config.interface);
+    health.connected();
+
+    loop {
+        let datagram = futures::select! {
+            datagram = datagrams.next() => datagram,
+            message = outbox.next() => match message {
+                Some(message) => {
+                    relay(&sender, config, filters, guard, health, message).await?;
+                    continue;
+                }
+                None => return Ok(()),
+            },
+        };
+        let (port, datagram) = match datagram {
+            Some(datagram) => datagram?,
+            None => return Err("multicast sockets closed".into()),
+        };
+        let (topic, payload) = match split(&datagram) {
+            Some(split) => split,
+            None => {
+                log::debug!("multicast bridge: dropping a datagram without a topic");
+                continue;
+            }
+        };
+        let routes = config.routes.iter().zip(filters).filter(|(route, filter)| {
+            route.direction.inbound() && route.group.port() == port && filter.matches(topic)
+        });
+        for (route, _) in routes {
+            if guard.admit(&route_key(route, topic), payload) {
+                health.relayed_in(payload.len());
+                client.publish(topic, payload.to_vec())?;
+            }
+        }
+    }
+}
+
+/// Send a mesh message to the group of every outbound route matching its topic.
+async fn relay(
+    socket: &UdpSocket,
+    config: &MulticastConfig,
+    filters: &[TopicFilter],
+    guard: &mut LoopGuard,
+    health: &Health,
+    message: Message,
+) -> io::Result<()> {
+    let len = message.topic.len() + 1 + message.data.len();
+    if len > MAX_DATAGRAM {
+        log::debug!(
+            "multicast bridge: message of {} bytes on {} does not fit in a datagram",
+            message.data.len(),
+            message.topic
+        );
+        return Ok(());
+    }
+    let routes = config
+        .routes
+        .iter()
+        .zip(filters)
+        .filter(|(route, filter)| route.direction.outbound() && filter.matches(&message.topic));
+    for (route, _) in routes {
+        if guard.admit(&route_key(route, &message.topic), &message.data) {
+            let mut datagram = Vec::with_capacity(len);
+            datagram.extend_from_slice(message.topic.as_bytes());
+            datagram.push(0);
+            datagram.extend_from_slice(&message.data);
+            socket.send_to(&datagram, route.group).await?;
+            health.relayed_out(message.data.len());
+        }
+    }
+    Ok(())
+}
+
+/// The topic and payload of a datagram.
+fn split(datagram: &[u8]) -> Option<(&str, &[u8])> {
+    let nul = datagram.iter().position(|byte| *byte == 0)?;
+    let topic = std::str::from_utf8(&datagram[..nul]).ok()?;
+    Some((topic, &datagram[nul + 1..]))
+}
+
+/// Key of a group and topic pair for the [`LoopGuard`], the same in both directions.
+fn route_key(route: &Route, topic: &str) -> String {
+    format!("{} {}", route.group, topic)
+}
+
+/// Datagrams received on a port, with the port.
+type Datagrams = Pin<Box<dyn Stream<Item = io::Result<(u16, Vec<u8>)>> + Send>>;
+
+/// The datagrams received by `socket`, bound to `port`, until it is dropped.
+fn receive(socket: UdpSocket, port: u16) -> Datagrams {
+    Box::pin(stream::unfold(socket, move |socket| async move {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let received = socket
+            .recv_from(&mut buf)
+            .await
+            .map(|(len, _)| (port, buf[..len].to_vec()));
+        Some((received, socket))
+    }))
+}
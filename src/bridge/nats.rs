@@ -0,0 +1,542 @@
+//! Bridge between a NATS server and the gossipsub mesh, configured with TOML mapping rules.
+//!
+//! Each rule pairs a gossipsub topic with a NATS subject. Wildcards map onto each other: topic
+//! levels separated by `/` correspond to subject tokens separated by `.`, `+` to `*` and `#` to
+//! `>`. When a rule has no `subject`, it is derived from the topic that way, so
+//!
+//! ```toml
+//! server = "127.0.0.1:4222"
+//!
+//! [[rule]]
+//! topic = "sensors/+/temp"
+//!
+//! [[rule]]
+//! topic = "alerts/#"
+//! subject = "ops.alerts.>"
+//! direction = "outbound"
+//! ```
+//!
+//! relays `sensors/kitchen/temp` to and from `sensors.kitchen.temp`, and publishes
+//! `alerts/disk/full` from the mesh as `ops.alerts.disk.full`. Both sides of a rule must use the
+//! same wildcards in the same order, whose matches are carried over from one name to the other.
+//!
+//! Wildcard rules relay mesh messages only on topics announced as described in the
+//! [`topic`](crate::topic) module.
+//!
+//! Mesh messages wait for the server in an outbox bounded by the `[flow]` table, see
+//! [`FlowControl`]:
+//!
+//! ```toml
+//! [flow]
+//! capacity = 10000
+//! overflow = "drop_oldest"
+//! dead_letter = "bridge/nats/dropped"
+//! ```
+
+use super::{Direction, Health, LoopGuard};
+use crate::{
+    flow::{self, Closer, FlowControl},
+    topic::TopicFilter,
+    topology::{self, ComponentKind},
+    Client, Error, Message, PubSubError,
+};
+use async_std::{
+    io::{self, BufReader},
+    net::{Shutdown, TcpStream},
+    task,
+};
+use futures::{channel::mpsc, prelude::*, stream::SelectAll};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Configuration of a NATS bridge.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+    /// Server address, as `host:port`.
+    #[serde(default = "default_server")]
+    pub server: String,
+    /// Connection name reported to the server.
+    #[serde(default = "default_name")]
+    pub name: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub pass: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Payloads that crossed the bridge within this many milliseconds are not relayed again, see
+    /// [`LoopGuard`].
+    #[serde(default = "default_loop_window_ms")]
+    pub loop_window_ms: u64,
+    #[serde(default)]
+    pub flow: FlowControl,
+    /// How many seconds the bridge may be disconnected before it is alerted on, see
+    /// [`Client::bridge_alerts`].
+    #[serde(default = "default_alert_after_secs")]
+    pub alert_after_secs: u64,
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+fn default_server() -> String {
+    "127.0.0.1:4222".into()
+}
+
+fn default_name() -> String {
+    "pubsub-lite-bridge".into()
+}
+
+fn default_loop_window_ms() -> u64 {
+    2000
+}
+
+fn default_alert_after_secs() -> u64 {
+    60
+}
+
+impl NatsConfig {
+    /// Parse a configuration written in TOML.
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        toml::from_str(text).map_err(PubSubError::config)
+    }
+}
+
+/// Pairs a gossipsub topic with a NATS subject.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Gossipsub topic or topic filter.
+    pub topic: String,
+    /// NATS subject, derived from `topic` if missing.
+    #[serde(default)]
+    pub subject: Option<String>,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// A hierarchical name with wildcards, in either naming scheme.
+#[derive(Debug)]
+struct Pattern {
+    levels: Vec<String>,
+    separator: char,
+    single: &'static str,
+    multi: &'static str,
+}
+
+/// What a wildcard matched: the single level of a `+`, or the trailing levels of a `#`.
+type Captures = Vec<Vec<String>>;
+
+impl Pattern {
+    fn topic(topic: &str) -> Self {
+        Pattern::new(topic, '/', "+", "#")
+    }
+
+    fn subject(subject: &str) -> Self {
+        Pattern::new(subject, '.', "*", ">")
+    }
+
+    fn new(name: &str, separator: char, single: &'static str, multi: &'static str) -> Self {
+        Pattern {
+            levels: name.split(separator).map(str::to_owned).collect(),
+            separator,
+            single,
+            multi,
+        }
+    }
+
+    fn as_string(&self) -> String {
+        self.levels.join(&self.separator.to_string())
+    }
+
+    /// The wildcards of the pattern, in order: `false` for single-level, `true` for multi-level.
+    fn wildcards(&self) -> Vec<bool> {
+        self.levels
+            .iter()
+            .filter(|level| *level == self.single || *level == self.multi)
+            .map(|level| level == self.multi)
+            .collect()
+    }
+
+    /// Match `name` against the pattern, returning what each wildcard matched.
+    fn captures(&self, name: &str) -> Option<Captures> {
+        let mut levels = name.split(self.separator);
+        let mut captures = Vec::new();
+        for level in &self.levels {
+            if *level == self.multi {
+                let rest: Vec<String> = levels.map(str::to_owned).collect();
+                if rest.is_empty() && self.multi == ">" {
+                    // Unlike `#`, `>` needs at least one token.
+                    return None;
+                }
+                captures.push(rest);
+                return Some(captures);
+            }
+            let matched = levels.next()?;
+            if *level == self.single {
+                captures.push(vec![matched.to_owned()]);
+            } else if level != matched {
+                return None;
+            }
+        }
+        match levels.next() {
+            Some(_) => None,
+            None => Some(captures),
+        }
+    }
+
+    /// Substitute the wildcards of the pattern with `captures`.
+    fn fill(&self, captures: &[Vec<String>]) -> String {
+        let mut captures = captures.iter();
+        let mut levels: Vec<&str> = Vec::new();
+        for level in &self.levels {
+            if *level == self.single || *level == self.multi {
+                if let Some(capture) = captures.next() {
+                    levels.extend(capture.iter().map(String::as_str));
+                }
+            } else {
+                levels.push(level);
+            }
+        }
+        levels.join(&self.separator.to_string())
+    }
+}
+
+/// A validated rule.
+#[derive(Debug)]
+struct Mapping {
+    topic: Pattern,
+    subject: Pattern,
+    direction: Direction,
+}
+
+impl Mapping {
+    fn new(rule: &Rule) -> Result<Self, Error> {
+        let filter = TopicFilter::new(&rule.topic)?;
+        let topic = Pattern::topic(filter.as_str());
+        let subject = match &rule.subject {
+            Some(subject) => Pattern::subject(subject),
+            None => {
+                if topic.levels.iter().any(|level| level.contains('.')) {
+                    return Err(format!(
+                        "cannot derive a NATS subject from topic {:?}, which contains `.`",
+                        rule.topic
+                    )
+                    .into());
+                }
+                let levels = topic.levels.iter().map(|level| match level.as_str() {
+                    "+" => "*",
+                    "#" => ">",
+                    level => level,
+                });
+                Pattern::subject(&levels.collect::<Vec<_>>().join("."))
+            }
+        };
+        if subject.levels.iter().any(|level| !is_valid_token(level)) {
+            return Err(format!("invalid NATS subject {:?}", subject.as_string()).into());
+        }
+        if topic.wildcards() != subject.wildcards() {
+            return Err(format!(
+                "topic {:?} and subject {:?} do not have the same wildcards",
+                rule.topic,
+                subject.as_string()
+            )
+            .into());
+        }
+        Ok(Mapping {
+            topic,
+            subject,
+            direction: rule.direction,
+        })
+    }
+}
+
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty() && !token.contains(|c: char| c == '.' || c.is_whitespace())
+}
+
+/// Start relaying messages according to the configured rules on a background task.
+pub fn spawn(client: &Client, config: NatsConfig) -> Result<task::JoinHandle<()>, Error> {
+    start(client, config).map(|(task, _)| task)
+}
+
+/// Start the bridge, also returning a handle stopping it: the bridge then relays the mesh
+/// messages already in its outbox if it is connected, and disconnects.
+pub(crate) fn start(
+    client: &Client,
+    config: NatsConfig,
+) -> Result<(task::JoinHandle<()>, Closer), Error> {
+    let mappings = config
+        .rules
+        .iter()
+        .map(Mapping::new)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut mesh_messages = SelectAll::new();
+    for mapping in mappings.iter().filter(|m| m.direction.outbound()) {
+        let topic = mapping.topic.as_string();
+        let subscription = if mapping.topic.wildcards().is_empty() {
+            client.subscribe(&topic)?
+        } else {
+            client.subscribe_filter(&topic)?
+        };
+        mesh_messages.push(subscription);
+    }
+    let outbox = flow::outbox(client, mesh_messages, &config.flow);
+    let health = Health::register(
+        client,
+        ComponentKind::Bridge,
+        format!("NATS bridge {}@{}", config.name, config.server),
+        topology::config_hash(&config),
+        Duration::from_secs(config.alert_after_secs),
+        outbox.monitor(),
+    )?;
+    let closer = outbox.closer();
+    let task = task::spawn(run(client.clone(), config, mappings, outbox, health));
+    Ok((task, closer))
+}
+
+async fn run(
+    client: Client,
+    config: NatsConfig,
+    mappings: Vec<Mapping>,
+    mut outbox: flow::Receiver<Message>,
+    health: Health,
+) {
+    let mut guard = LoopGuard::new(Duration::from_millis(config.loop_window_ms));
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        let started = Instant::now();
+        match session(
+            &client,
+            &config,
+            &mappings,
+            &mut outbox,
+            &mut guard,
+            &health,
+        )
+        .await
+        {
+            Ok(()) => return,
+            Err(e) => {
+                health.disconnected();
+                log::warn!("NATS bridge to {}: {}", config.server, e)
+            }
+        }
+        if outbox.is_exhausted() {
+            return;
+        }
+        if started.elapsed() > Duration::from_secs(60) {
+            backoff = Duration::from_secs(1);
+        }
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
+
+/// Options sent with `CONNECT`.
+#[derive(Serialize)]
+struct Connect<'a> {
+    verbose: bool,
+    pedantic: bool,
+    /// Keeps the server from sending our own publishes back to our subscriptions.
+    echo: bool,
+    name: &'a str,
+    lang: &'static str,
+    version: &'static str,
+    protocol: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pass: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_token: Option<&'a str>,
+}
+
+/// Operations received from the server.
+#[derive(Debug)]
+enum Op {
+    Msg {
+        subject: String,
+        sid: usize,
+        payload: Vec<u8>,
+    },
+    Ping,
+    Err(String),
+    Other,
+}
+
+/// Run one connection to the server until it fails. Returns `Ok` once the node has shut down or
+/// the bridge was stopped.
+async fn session(
+    client: &Client,
+    config: &NatsConfig,
+    mappings: &[Mapping],
+    outbox: &mut flow::Receiver<Message>,
+    guard: &mut LoopGuard,
+    health: &Health,
+) -> Result<(), Error> {
+    let mut stream = TcpStream::connect(&config.server).await?;
+    let mut reader = BufReader::new(stream.clone());
+    let mut info = String::new();
+    reader.read_line(&mut info).await?;
+    if !info.starts_with("INFO") {
+        return Err(format!("expected INFO, got {:?}", info.trim_end()).into());
+    }
+
+    let connect = Connect {
+        verbose: false,
+        pedantic: false,
+        echo: false,
+        name: &config.name,
+        lang: "rust",
+        version: env!("CARGO_PKG_VERSION"),
+        protocol: 1,
+        user: config.user.as_deref(),
+        pass: config.pass.as_deref(),
+        auth_token: config.token.as_deref(),
+    };
+    let mut handshake = format!("CONNECT {}\r\n", serde_json::to_string(&connect)?);
+    for (i, mapping) in mappings.iter().enumerate() {
+        if mapping.direction.inbound() {
+            // Subscription ids are rule indices, offset by one since NATS ids start at 1.
+            handshake.push_str(&format!(
+                "SUB {} {}\r\n",
+                mapping.subject.as_string(),
+                i + 1
+            ));
+        }
+    }
+    handshake.push_str("PING\r\n");
+    write(&mut stream, handshake.as_bytes()).await?;
+    log::info!("NATS bridge connected to {}", config.server);
+    health.connected();
+
+    let (ops_in, mut ops) = mpsc::channel(config.flow.capacity);
+    task::spawn(read_ops(reader, ops_in));
+    let result = loop {
+        let op = futures::select! {
+            op = ops.next() => op,
+            message = outbox.next() => match message {
+                Some(message) => {
+                    if let Err(e) = relay(&mut stream, mappings, guard, health, message).await {
+                        break Err(e.into());
+                    }
+                    continue;
+                }
+                None => break Ok(()),
+            },
+        };
+        match op {
+            Some(Ok(Op::Msg {
+                subject,
+                sid,
+                payload,
+            })) => {
+                let mapping = mappings.get(sid.wrapping_sub(1));
+                let captures = mapping.and_then(|m| m.subject.captures(&subject));
+                if let (Some(mapping), Some(captures)) = (mapping, captures) {
+                    let topic = mapping.topic.fill(&captures);
+                    if guard.admit(&route(&subject, &topic), &payload) {
+                        health.relayed_in(payload.len());
+                        if let Err(e) = client.publish(&topic, payload) {
+                            break Err(e);
+                        }
+                    }
+                }
+            }
+            Some(Ok(Op::Ping)) => {
+                if let Err(e) = write(&mut stream, b"PONG\r\n").await {
+                    break Err(e.into());
+                }
+            }
+            Some(Ok(Op::Err(e))) => log::warn!("NATS bridge: server error: {}", e),
+            Some(Ok(Op::Other)) => {}
+            Some(Err(e)) => break Err(e.into()),
+            None => break Err("connection closed".into()),
+        }
+    };
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Publish a mesh message to the subject of every outbound rule matching its topic.
+async fn relay(
+    stream: &mut TcpStream,
+    mappings: &[Mapping],
+    guard: &mut LoopGuard,
+    health: &Health,
+    message: Message,
+) -> io::Result<()> {
+    for mapping in mappings.iter().filter(|m| m.direction.outbound()) {
+        let captures = match mapping.topic.captures(&message.topic) {
+            Some(captures) => captures,
+            None => continue,
+        };
+        let subject = mapping.subject.fill(&captures);
+        if !subject.split('.').all(is_valid_token) {
+            log::debug!("NATS bridge: cannot relay {} as a subject", message.topic);
+            continue;
+        }
+        if guard.admit(&route(&subject, &message.topic), &message.data) {
+            let mut publish = format!("PUB {} {}\r\n", subject, message.data.len()).into_bytes();
+            publish.extend_from_slice(&message.data);
+            publish.extend_from_slice(b"\r\n");
+            write(stream, &publish).await?;
+            health.relayed_out(message.data.len());
+        }
+    }
+    Ok(())
+}
+
+/// Key of a subject and topic pair for the [`LoopGuard`], the same in both directions.
+fn route(subject: &str, topic: &str) -> String {
+    format!("{} {}", subject, topic)
+}
+
+async fn write(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(data).await?;
+    stream.flush().await
+}
+
+async fn read_ops(mut reader: BufReader<TcpStream>, mut ops: mpsc::Sender<io::Result<Op>>) {
+    loop {
+        let op = read_op(&mut reader).await;
+        let failed = op.is_err();
+        // Waits while the session is behind, which leaves the server waiting on TCP.
+        if ops.send(op).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn read_op(reader: &mut BufReader<TcpStream>) -> io::Result<Op> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut words = line.split_whitespace();
+    let op = words.next().unwrap_or_default().to_ascii_uppercase();
+    match op.as_str() {
+        "MSG" => {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let args: Vec<&str> = words.collect();
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed MSG");
+            if args.len() < 3 || args.len() > 4 {
+                return Err(invalid());
+            }
+            let sid = args[1].parse().map_err(|_| invalid())?;
+            let len: usize = args[args.len() - 1].parse().map_err(|_| invalid())?;
+            let mut payload = vec![0u8; len + 2];
+            reader.read_exact(&mut payload).await?;
+            payload.truncate(len);
+            Ok(Op::Msg {
+                subject: args[0].to_owned(),
+                sid,
+                payload,
+            })
+        }
+        "PING" => Ok(Op::Ping),
+        "-ERR" => Ok(Op::Err(line[4..].trim().to_owned())),
+        _ => Ok(Op::Other),
+    }
+}
@@ -0,0 +1,821 @@
+//! Embedded mode: publish and subscribe within a single process, without any networking.
+//!
+//! A node spawned with [`NodeConfig::embedded`] set runs a broker instead of a libp2p swarm: it
+//! neither listens nor dials, and every message published through its clients is handed to its
+//! own subscribers, wildcard ones included, after the installed plugins, publish hooks first,
+//! and the pipelines of the subscriptions. The [`Client`] API is that of a distributed node, so
+//! that an application can be developed and tested in one process and go distributed by
+//! flipping the switch.
+//!
+//! There being no peers, queries about them answer with none, direct messages can only be sent to
+//! the node itself, and the router, the explicit peers and the compression, encryption, chunking,
+//! pacing, rate limiting and timestamping policies of the configuration, which apply on the wire,
+//! are ignored, and so is [`NodeConfig::local_delivery`], local delivery being all the broker does.
+//! The jobs of a [`queue`](crate::queue), for instance, go to the workers of the same process.
+//! Delegations, trusted organisations and topic owners do apply: messages are signed and checked as
+//! they would be between nodes, so that they get the same origin, or are dropped alike, and so do
+//! attested topics, whose messages are delivered with their source verified. So do the limits on
+//! the size and the message types of payloads, publishing failing alike, and the
+//! [`retention`](crate::retention) of messages for later subscribers.
+
+use crate::acl::{self, Access};
+use crate::attestation::{Attester, SignatureStatus};
+use crate::autonat::{Reachability, ReachabilityStatus};
+use crate::bandwidth::Traffic;
+use crate::bridge::Health;
+use crate::client::{BridgeAlert, ChangeEvent, Client, Message, NodeEvent, ProtocolEvent};
+use crate::compat;
+use crate::debug::{CacheUsage, DebugDump, TopicDump};
+use crate::delegation::{self, Delegation, PublicKey};
+use crate::direct::Direct;
+use crate::directory::{Directory, TopicListing};
+use crate::flow::{self, TrySend};
+use crate::headers::Headers;
+use crate::health::{HealthReport, Readiness, TopicHealth};
+use crate::message_id::Receipts;
+use crate::node::{Command, Milestone, NodeConfig, Subscriber, HOUSEKEEPING_INTERVAL};
+#[cfg(feature = "wasm")]
+use crate::plugin::{self, Hook, Plugin};
+use crate::presence::Activity;
+use crate::retention::{Replay, Retained};
+use crate::rotation::{Continuity, ContinuityRecord};
+use crate::routing::{Routed, Routes};
+use crate::schema;
+use crate::size::SizeLimits;
+use crate::stats::{
+    ChaosStats, LocalDelivery, MemoryStats, MeshInfo, PeerExchangeStats, RecentMessages,
+    SequenceStats, SizeStats, Stats, SubscriptionStats, TopicMesh, TopicStats, ValidationStats,
+};
+use crate::topic::{self, TopicFilter};
+use crate::topology::{self, Component, ComponentKind, ComponentStatus, Registration, Topology};
+use crate::trace_context::TraceContext;
+use crate::Error;
+use async_std::{stream, task};
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use libp2p::{
+    gossipsub::{GossipsubMessage, MessageIdFn, TopicHash},
+    PeerId,
+};
+#[cfg(feature = "wasm")]
+use std::sync::Arc;
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The state of an embedded node.
+struct Broker {
+    local_peer_id: PeerId,
+    /// Whether the node is an observer, publishing nothing.
+    observer: bool,
+    message_id_fn: MessageIdFn,
+    next_sequence_number: u64,
+    /// Local subscribers, by topic.
+    subscribers: HashMap<String, Vec<Subscriber>>,
+    /// Local wildcard subscribers.
+    filters: Vec<(TopicFilter, Subscriber)>,
+    /// Delegations signing published messages, by topic filter.
+    delegation: Vec<(TopicFilter, Delegation)>,
+    /// Organisations published messages must be signed under, by topic filter.
+    trusted_orgs: Vec<(TopicFilter, PublicKey)>,
+    /// Tokens of published messages, if the node has some.
+    access: Option<Access>,
+    /// Owners whose tokens published messages must carry, by topic filter.
+    topic_owners: Vec<(TopicFilter, PublicKey)>,
+    /// Topics whose published messages are signed with the keypair of the node.
+    attester: Attester,
+    /// Topics published on, with when they last were.
+    published: HashMap<String, Instant>,
+    /// Payload bytes and messages published on each topic.
+    traffic: HashMap<String, Traffic>,
+    /// Messages handed to subscribers by topic.
+    delivered_locally: HashMap<String, LocalDelivery>,
+    /// Sizes of the payloads published, by topic.
+    sizes: HashMap<String, SizeStats>,
+    /// Messages retained for the subscribers joining later.
+    retained: Retained,
+    /// Bytes the retained payloads may take, if limited.
+    memory_budget: Option<usize>,
+    /// Retained messages dropped to stay within the memory budget.
+    evicted: u64,
+    /// The last messages handed to subscribers.
+    recent: RecentMessages,
+    /// Watchers of the peers supporting a protocol, kept so that their streams stay open.
+    protocol_watchers: Vec<mpsc::UnboundedSender<ProtocolEvent>>,
+    change_watchers: Vec<mpsc::UnboundedSender<ChangeEvent>>,
+    event_watchers: Vec<flow::Sender<NodeEvent>>,
+    /// Clients waiting for the node to subscribe to a topic.
+    milestone_waiters: Vec<(String, oneshot::Sender<Result<(), Error>>)>,
+    bridges: Vec<Health>,
+    bridge_watchers: Vec<mpsc::UnboundedSender<BridgeAlert>>,
+    transport: Component,
+    components: Vec<Registration>,
+    /// Messages waiting for subscribers whose queue is full, in order.
+    blocked: Vec<(flow::Sender<Message>, Message)>,
+    /// The rotation of the keypair of this node, if it rotated lately.
+    continuity: Continuity,
+    /// The public key of this node, the only recipient of direct messages.
+    direct: Direct,
+    /// The topics of this node, if it takes part in the topic directory.
+    directory: Option<Directory>,
+    /// What the node needs to report itself ready, of which only the topics apply.
+    readiness: Readiness,
+    #[cfg(feature = "wasm")]
+    plugins: Vec<(String, TopicFilter, Hook, Arc<Plugin>)>,
+}
+
+impl Broker {
+    fn publish(
+        &mut self,
+        topic: String,
+        data: Bytes,
+        trace_context: Option<TraceContext>,
+        headers: Headers,
+        receipts: Receipts,
+    ) {
+        #[cfg(feature = "wasm")]
+        let data = match self.run_plugins(Hook::publish, &topic, data) {
+            Some(data) => data,
+            None => return receipts.fail(&"dropped by a plugin"),
+        };
+        self.published.insert(topic.clone(), Instant::now());
+        let traffic = self.traffic.entry(topic.clone()).or_default();
+        traffic.bytes_out += data.len() as u64;
+        traffic.messages_out += 1;
+        self.sizes
+            .entry(topic.clone())
+            .or_default()
+            .record(data.len());
+        self.next_sequence_number = self.next_sequence_number.wrapping_add(1);
+        let message = GossipsubMessage {
+            source: self.local_peer_id.clone(),
+            data,
+            sequence_number: self.next_sequence_number,
+            topics: vec![TopicHash::from_raw(topic.clone())],
+            targeted: false,
+        };
+        let id = (self.message_id_fn)(&message);
+        let data = message.data;
+        let (origin, data) =
+            match acl::check_local(&self.topic_owners, self.access.as_ref(), &topic)
+                .and_then(|()| delegation::sign(&self.delegation, &topic, data))
+                .and_then(|data| delegation::verify(&self.trusted_orgs, &topic, data))
+            {
+                Ok(signed) => signed,
+                Err(e) => {
+                    log::debug!("dropping a message published on {}: {}", topic, e);
+                    return receipts.fail(&e);
+                }
+            };
+        receipts.send(&id);
+        #[cfg(feature = "wasm")]
+        let data = match self.run_plugins(Hook::receive, &topic, data) {
+            Some(data) => data,
+            None => return,
+        };
+        let signature = match self.attester.attests(&topic) {
+            true => SignatureStatus::Verified,
+            false => SignatureStatus::Unsigned,
+        };
+        let delivered = Message {
+            id,
+            source: message.source,
+            topic,
+            data,
+            sequence_number: message.sequence_number,
+            origin,
+            signature,
+            published_at: None,
+            trace_context,
+            headers,
+        };
+        let subscribers: Vec<Subscriber> = self
+            .subscribers
+            .get(&delivered.topic)
+            .into_iter()
+            .flatten()
+            .chain(
+                self.filters
+                    .iter()
+                    .filter(|(filter, _)| filter.matches(&delivered.topic))
+                    .map(|(_, subscriber)| subscriber),
+            )
+            .cloned()
+            .collect();
+        if subscribers.is_empty() {
+            return;
+        }
+        self.retained.record(&delivered);
+        if let Some(budget) = self.memory_budget {
+            self.evicted += self.retained.shrink_to(budget) as u64;
+        }
+        self.recent.record(&delivered);
+        let local = self
+            .delivered_locally
+            .entry(delivered.topic.clone())
+            .or_default();
+        local.messages += 1;
+        local.bytes += delivered.data.len() as u64;
+        for subscriber in subscribers {
+            self.offer(&subscriber, &delivered);
+        }
+    }
+
+    /// Hand a message to a subscriber, through its pipeline.
+    fn offer(&mut self, subscriber: &Subscriber, delivered: &Message) {
+        if !subscriber.accepts(delivered) {
+            return;
+        }
+        let data = match &subscriber.pipeline {
+            Some(pipeline) => match pipeline.apply(&delivered.data) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::debug!("pipeline dropped a message on {}: {}", delivered.topic, e);
+                    return;
+                }
+            },
+            None => delivered.data.clone(),
+        };
+        let message = Message {
+            data,
+            ..delivered.clone()
+        };
+        // Keep the messages of a blocked subscriber in order behind those it waits on.
+        if self
+            .blocked
+            .iter()
+            .any(|(sender, _)| sender.same_channel(&subscriber.sender))
+        {
+            self.blocked.push((subscriber.sender.clone(), message));
+            return;
+        }
+        if let TrySend::Full(message) = subscriber.sender.try_send(message) {
+            self.blocked.push((subscriber.sender.clone(), message));
+        }
+    }
+
+    /// Hand the retained messages that `replay` asks for on the topics `matches` accepts to a
+    /// new subscriber.
+    fn replay(&mut self, matches: impl Fn(&str) -> bool, replay: Replay, subscriber: &Subscriber) {
+        for message in self.retained.replay(matches, replay) {
+            self.offer(subscriber, &message);
+        }
+    }
+
+    fn subscribe(&mut self, topic: String, subscriber: Subscriber) {
+        let subscribed = self.is_subscribed(&topic);
+        self.subscribers
+            .entry(topic.clone())
+            .or_default()
+            .push(subscriber);
+        if !subscribed {
+            let (joined, waiting) = std::mem::take(&mut self.milestone_waiters)
+                .into_iter()
+                .partition(|(waited, _)| *waited == topic);
+            self.milestone_waiters = waiting;
+            for (_, reply) in joined {
+                let _ = reply.send(Ok(()));
+            }
+            self.subscription_changed(topic, true);
+        }
+    }
+
+    fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscribers.get(topic).is_some_and(|subscribers| {
+            subscribers
+                .iter()
+                .any(|subscriber| !subscriber.sender.is_closed())
+        })
+    }
+
+    fn topics(&self) -> Vec<String> {
+        self.subscribers
+            .keys()
+            .filter(|topic| self.is_subscribed(topic))
+            .cloned()
+            .collect()
+    }
+
+    /// The topics of the topic directory, only those of this node there being no peers.
+    fn discover_topics(&mut self) -> Option<Vec<TopicListing>> {
+        let topics = self.topics();
+        let directory = self.directory.as_mut()?;
+        let announcement = directory.announcement(topics, self.published.iter());
+        directory.record(self.local_peer_id.clone(), announcement);
+        Some(directory.listings())
+    }
+
+    /// Forget the local subscribers that are gone.
+    fn prune_subscribers(&mut self) {
+        self.filters
+            .retain(|(_, subscriber)| !subscriber.sender.is_closed());
+        let mut abandoned = Vec::new();
+        for (topic, subscribers) in &mut self.subscribers {
+            subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+            if subscribers.is_empty() {
+                abandoned.push(topic.clone());
+            }
+        }
+        for topic in abandoned {
+            self.subscribers.remove(&topic);
+            self.subscription_changed(topic, false);
+        }
+    }
+
+    /// Tell watchers that this node subscribed to `topic` or unsubscribed from it.
+    fn subscription_changed(&mut self, topic: String, subscribed: bool) {
+        if topic::is_internal(&topic) {
+            return;
+        }
+        let peer = self.local_peer_id.clone();
+        let event = if subscribed {
+            NodeEvent::Subscribed {
+                peer: peer.clone(),
+                topic: topic.clone(),
+            }
+        } else {
+            NodeEvent::Unsubscribed {
+                peer: peer.clone(),
+                topic: topic.clone(),
+            }
+        };
+        self.event_watchers
+            .retain(|watcher| !matches!(watcher.try_send(event.clone()), TrySend::Closed(_)));
+        let event = ChangeEvent::SubscriptionChanged {
+            peer,
+            topic,
+            subscribed,
+        };
+        self.change_watchers
+            .retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn stats(&self) -> Stats {
+        let topics = self
+            .traffic
+            .iter()
+            .map(|(topic, traffic)| TopicStats {
+                topic: topic.clone(),
+                traffic: *traffic,
+                local: self
+                    .delivered_locally
+                    .get(topic)
+                    .copied()
+                    .unwrap_or_default(),
+                sizes: self.sizes.get(topic).copied().unwrap_or_default(),
+                sequence: SequenceStats::default(),
+            })
+            .collect();
+        let mut subscriptions: Vec<SubscriptionStats> = self
+            .subscribers
+            .values()
+            .flatten()
+            .chain(self.filters.iter().map(|(_, subscriber)| subscriber))
+            .filter(|subscriber| !subscriber.sender.is_closed())
+            .map(|subscriber| SubscriptionStats {
+                id: subscriber.id,
+                topic: subscriber.topic.clone(),
+                queue: subscriber.sender.status(),
+            })
+            .collect();
+        subscriptions.sort_by_key(|subscription| subscription.id);
+        Stats {
+            peers: Vec::new(),
+            topics,
+            subscriptions,
+            bridges: self.bridges.iter().map(Health::stats).collect(),
+            graylistings: 0,
+            validation: ValidationStats::default(),
+            memory: self.memory_stats(),
+            peer_exchange: PeerExchangeStats::default(),
+            chaos: ChaosStats::default(),
+            priorities: Vec::new(),
+            recent_messages: self.recent.list(),
+        }
+    }
+
+    fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            budget: self.memory_budget,
+            message_cache: 0,
+            retained: self.retained.bytes(),
+            evicted: self.evicted,
+        }
+    }
+
+    fn debug_dump(&self) -> DebugDump {
+        let topics: BTreeSet<&String> = self
+            .subscribers
+            .keys()
+            .chain(self.published.keys())
+            .collect();
+        DebugDump {
+            peer_id: self.local_peer_id.clone(),
+            listen_addrs: Vec::new(),
+            connections: Vec::new(),
+            pending_dials: Vec::new(),
+            topics: topics
+                .into_iter()
+                .map(|topic| TopicDump {
+                    topic: topic.clone(),
+                    subscribed: self.is_subscribed(topic),
+                    mesh: Vec::new(),
+                    fanout: Vec::new(),
+                    peers: Vec::new(),
+                })
+                .collect(),
+            caches: CacheUsage {
+                memory: self.memory_stats(),
+                ..CacheUsage::default()
+            },
+        }
+    }
+
+    fn mesh_info(&self) -> MeshInfo {
+        let topics: BTreeSet<&String> = self
+            .subscribers
+            .keys()
+            .chain(self.published.keys())
+            .filter(|topic| !topic::is_internal(topic))
+            .collect();
+        MeshInfo {
+            topics: topics
+                .into_iter()
+                .map(|topic| TopicMesh {
+                    topic: topic.clone(),
+                    subscribed: self.is_subscribed(topic),
+                    peers: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn topology(&mut self) -> Topology {
+        self.components.retain(|component| !component.is_orphan());
+        self.bridges.retain(|health| !health.is_orphan());
+        let mut components = vec![self.transport.clone()];
+        components.extend(self.components.iter().map(Registration::component));
+        components.extend(self.bridges.iter().map(Health::component));
+        #[cfg(feature = "wasm")]
+        components.extend(
+            self.plugins
+                .iter()
+                .map(|(name, filter, hook, plugin)| Component {
+                    kind: ComponentKind::Plugin,
+                    name: format!("{} on {} ({})", name, filter, hook),
+                    status: ComponentStatus::Running,
+                    config_hash: plugin.hash().to_owned(),
+                }),
+        );
+        components.sort_by_key(|component| component.kind);
+        Topology { components }
+    }
+
+    /// Raise the alerts of bridges that crossed their alert threshold or recovered, and forget
+    /// the bridges that have stopped.
+    fn check_bridges(&mut self) {
+        self.bridges.retain(|health| !health.is_orphan());
+        for alert in self.bridges.iter().filter_map(Health::check) {
+            match &alert {
+                BridgeAlert::Disconnected { bridge, since } => {
+                    log::warn!("{} has been disconnected for {:?}", bridge, since)
+                }
+                BridgeAlert::Reconnected { bridge } => log::info!("{} reconnected", bridge),
+            }
+            self.bridge_watchers
+                .retain(|watcher| watcher.unbounded_send(alert.clone()).is_ok());
+        }
+    }
+
+    /// Run the plugins installed for `topic` with a hook `runs` accepts on `data`, returning
+    /// `None` if one of them drops the message.
+    #[cfg(feature = "wasm")]
+    fn run_plugins(&self, runs: fn(Hook) -> bool, topic: &str, mut data: Bytes) -> Option<Bytes> {
+        for (name, filter, hook, plugin) in &self.plugins {
+            if !runs(*hook) || !filter.matches(topic) {
+                continue;
+            }
+            data = match plugin.apply(topic, &data) {
+                Ok(data) => data?.into(),
+                Err(e) => {
+                    log::debug!("plugin {} dropped a message on {}: {}", name, topic, e);
+                    return None;
+                }
+            };
+        }
+        Some(data)
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Publish { topic, .. } | Command::PublishTo { topic, .. } if self.observer => {
+                log::warn!("observer dropping a message published on {}", topic);
+            }
+            Command::Publish {
+                topic,
+                data,
+                trace_context,
+                headers,
+                receipts,
+            } => self.publish(topic, data, trace_context, headers, receipts),
+            // The node itself is the only peer there is.
+            Command::PublishTo { topic, peers, data } => {
+                if peers.contains(&self.local_peer_id) {
+                    self.publish(topic, data, None, Headers::new(), Receipts::default())
+                }
+            }
+            Command::Subscribe {
+                topic,
+                subscriber,
+                replay,
+            } => {
+                self.replay(|retained| retained == topic, replay, &subscriber);
+                self.subscribe(topic, subscriber)
+            }
+            Command::SubscribeFilter {
+                filter,
+                subscriber,
+                replay,
+            } => {
+                self.replay(|retained| filter.matches(retained), replay, &subscriber);
+                self.filters.push((filter, subscriber))
+            }
+            Command::Topics { reply } => {
+                let _ = reply.send(self.topics());
+            }
+            Command::TopicActivity { topic, reply } => {
+                let _ = reply.send(Activity {
+                    subscribed: self.is_subscribed(&topic),
+                    last_published: self.published.get(&topic).copied(),
+                });
+            }
+            Command::Peers { reply, .. } | Command::PeersSupporting { reply, .. } => {
+                let _ = reply.send(Vec::new());
+            }
+            Command::PeerTopics { reply, .. } => {
+                let _ = reply.send(Vec::new());
+            }
+            Command::ListPeers { reply, .. } => {
+                let _ = reply.send(Vec::new());
+            }
+            Command::Stats { reply } => {
+                let _ = reply.send(self.stats());
+            }
+            Command::MeshInfo { reply } => {
+                let _ = reply.send(self.mesh_info());
+            }
+            Command::DebugDump { reply } => {
+                let _ = reply.send(self.debug_dump());
+            }
+            Command::Trace { reply, .. } => {
+                let _ = reply.send(None);
+            }
+            Command::WatchProtocol { watcher, .. } => self.protocol_watchers.push(watcher),
+            Command::WatchChanges { watcher } => self.change_watchers.push(watcher),
+            Command::WatchEvents { watcher } => self.event_watchers.push(watcher),
+            Command::RegisterBridge { health } => self.bridges.push(health),
+            Command::WatchBridges { watcher } => self.bridge_watchers.push(watcher),
+            Command::SealDirect { peers, data, reply } => {
+                let _ = reply.send(self.direct.seal(&peers, &data));
+            }
+            Command::OpenInbox { reply } => {
+                let _ = reply.send(self.direct.inbox());
+            }
+            Command::Successor { peer, reply } => {
+                let _ = reply.send(self.continuity.successor(&peer));
+            }
+            Command::DiscoverTopics { reply } => {
+                let _ = reply.send(self.discover_topics());
+            }
+            Command::WatchRotations { watcher } => self.continuity.watch(watcher),
+            Command::RegisterComponent { registration } => self.components.push(registration),
+            Command::WaitFor { milestone, reply } => match milestone {
+                Milestone::Ready | Milestone::PeerQuorum(0) => {
+                    let _ = reply.send(Ok(()));
+                }
+                Milestone::PeerQuorum(_) => {
+                    let _ = reply.send(Err("an embedded node has no peers".into()));
+                }
+                Milestone::TopicJoined(topic, _) => {
+                    if self.is_subscribed(&topic) {
+                        let _ = reply.send(Ok(()));
+                    } else {
+                        self.milestone_waiters.push((topic, reply));
+                    }
+                }
+            },
+            Command::Health { reply } => {
+                let _ = reply.send(HealthReport {
+                    listening: true,
+                    listen_addrs: Vec::new(),
+                    peers: 0,
+                    min_peers: 0,
+                    topics: self
+                        .readiness
+                        .required_topics
+                        .iter()
+                        .map(|topic| TopicHealth {
+                            topic: topic.clone(),
+                            subscribed: self.is_subscribed(topic),
+                            mesh_peers: 0,
+                            min_mesh_peers: 0,
+                        })
+                        .collect(),
+                    journals: Vec::new(),
+                });
+            }
+            Command::Reachability { reply } => {
+                let _ = reply.send(ReachabilityStatus {
+                    reachability: Reachability::Unknown,
+                    listen_addrs: Vec::new(),
+                    observed_addrs: Vec::new(),
+                    confirmed_addrs: Vec::new(),
+                    announce_addrs: Vec::new(),
+                });
+            }
+            Command::DescribeTopology { reply } => {
+                let _ = reply.send(self.topology());
+            }
+            Command::CheckCompat { reply } => {
+                let _ = reply.send(compat::check());
+            }
+            Command::Dial { .. } | Command::SetRateLimit { .. } | Command::PauseGossip { .. } => {}
+            #[cfg(feature = "wasm")]
+            Command::InstallPlugin {
+                name,
+                filter,
+                hook,
+                plugin,
+            } => {
+                self.plugins
+                    .retain(|(installed, _, _, _)| *installed != name);
+                self.plugins.push((name, filter, hook, plugin));
+            }
+            #[cfg(feature = "wasm")]
+            Command::RemovePlugin { name, reply } => {
+                let installed = self.plugins.len();
+                self.plugins
+                    .retain(|(installed, _, _, _)| *installed != name);
+                let _ = reply.send(self.plugins.len() != installed);
+            }
+            #[cfg(feature = "wasm")]
+            Command::ListPlugins { reply } => {
+                let _ = reply.send(plugin::list(&self.plugins));
+            }
+        }
+    }
+
+    /// Execute client commands until every client has been dropped.
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>, mut routed: Routed) {
+        let mut housekeeping = stream::interval(HOUSEKEEPING_INTERVAL);
+        loop {
+            if !self.blocked.is_empty() && !self.unblock(&mut commands).await {
+                return;
+            }
+            futures::select! {
+                command = commands.next() => match command {
+                    Some(command) => self.handle(command),
+                    None => return,
+                },
+                routed = routed.next().fuse() => if let Some((topic, data)) = routed {
+                    self.publish(topic, data, None, Headers::new(), Receipts::default());
+                },
+                _ = housekeeping.next().fuse() => {
+                    self.check_bridges();
+                    self.prune_subscribers();
+                }
+            }
+        }
+    }
+
+    /// Wait for blocked subscribers to take their messages, executing commands meanwhile.
+    /// Returns `false` once every client has been dropped.
+    async fn unblock(&mut self, commands: &mut mpsc::UnboundedReceiver<Command>) -> bool {
+        let blocked = std::mem::take(&mut self.blocked);
+        let delivery = async move {
+            for (sender, message) in blocked {
+                // A subscriber gone meanwhile is dropped on the next housekeeping.
+                let _ = sender.send(message).await;
+            }
+        }
+        .fuse();
+        futures::pin_mut!(delivery);
+        loop {
+            futures::select! {
+                _ = delivery => return true,
+                command = commands.next() => match command {
+                    Some(command) => self.handle(command),
+                    None => return false,
+                },
+            }
+        }
+    }
+}
+
+/// Start an embedded node on a background task and return a [`Client`] to control it.
+pub(crate) fn spawn(config: NodeConfig, routes: Routes) -> Result<Client, Error> {
+    let delegation = config
+        .delegation
+        .iter()
+        .map(|(filter, delegation)| Ok((TopicFilter::new(filter)?, delegation.clone())))
+        .collect::<Result<_, Error>>()?;
+    let trusted_orgs = config
+        .trusted_orgs
+        .iter()
+        .map(|(filter, org)| Ok((TopicFilter::new(filter)?, *org)))
+        .collect::<Result<_, Error>>()?;
+    let topic_owners = config
+        .topic_owners
+        .iter()
+        .map(|(filter, owner)| Ok((TopicFilter::new(filter)?, *owner)))
+        .collect::<Result<_, Error>>()?;
+    let identity = config.identity()?;
+    let access = match config.access_tokens.is_empty() {
+        true => None,
+        false => Some(Access::new(identity.clone(), &config.access_tokens)?),
+    };
+    let attester = Attester::new(identity.clone(), &config.attested_topics)?;
+    let size_limits = SizeLimits::parse(
+        &config.max_message_size,
+        &config.oversize,
+        config.gossipsub.max_transmit_size,
+    )?;
+    let message_types = schema::parse_types(&config.message_types)?;
+    let retention = config
+        .retention
+        .iter()
+        .map(|(filter, policy)| Ok((TopicFilter::new(filter)?, *policy)))
+        .collect::<Result<_, Error>>()?;
+    let mut continuity = Continuity::default();
+    if let Some(previous) = &config.previous_keypair {
+        continuity.accept(ContinuityRecord::new(previous, &identity)?);
+    }
+    let local_peer_id = PeerId::from(identity.public());
+    let broker = Broker {
+        local_peer_id: local_peer_id.clone(),
+        observer: config.observer,
+        message_id_fn: config.gossipsub.message_id_fn.clone(),
+        // Start sequence numbers from the clock, so that message ids do not repeat across
+        // restarts.
+        next_sequence_number: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64),
+        subscribers: HashMap::new(),
+        filters: Vec::new(),
+        delegation,
+        trusted_orgs,
+        access,
+        topic_owners,
+        attester,
+        published: HashMap::new(),
+        traffic: HashMap::new(),
+        delivered_locally: HashMap::new(),
+        sizes: HashMap::new(),
+        retained: Retained::new(
+            retention,
+            config.max_replay,
+            config.retention_storage.clone(),
+        ),
+        memory_budget: config.memory_budget,
+        evicted: 0,
+        recent: RecentMessages::default(),
+        protocol_watchers: Vec::new(),
+        change_watchers: Vec::new(),
+        event_watchers: Vec::new(),
+        milestone_waiters: Vec::new(),
+        bridges: Vec::new(),
+        bridge_watchers: Vec::new(),
+        transport: Component {
+            kind: ComponentKind::Transport,
+            name: "embedded broker".to_owned(),
+            status: ComponentStatus::Running,
+            config_hash: topology::config_hash(&"embedded"),
+        },
+        components: Vec::new(),
+        blocked: Vec::new(),
+        continuity,
+        direct: Direct::new(identity),
+        directory: config
+            .topic_directory
+            .then(|| Directory::new(config.topic_announce_interval)),
+        readiness: config.readiness,
+        #[cfg(feature = "wasm")]
+        plugins: Vec::new(),
+    };
+    let (sender, receiver) = mpsc::unbounded();
+    let client = Client::new(
+        sender,
+        local_peer_id,
+        config.subscription_bounds,
+        size_limits,
+        message_types,
+        config.observer,
+    );
+    let routed = routes.start(&client)?;
+    task::spawn(broker.run(receiver, routed));
+    Ok(client)
+}
@@ -0,0 +1,222 @@
+//! Fault injection into the gossip of a node, to test how applications bear its losses before
+//! they meet them in production.
+//!
+//! With [`NodeConfig::chaos`](crate::NodeConfig::chaos) set, every gossipsub frame the node sends
+//! or receives, an RPC carrying messages, subscriptions and control messages alike, is dropped,
+//! duplicated or delayed at the probabilities of the [`Faults`] of its direction, as a lossy
+//! network would. A delayed frame is held for a random time up to
+//! [`max_delay`](Faults::max_delay), so that frames also come out of order, and a duplicated one
+//! goes through twice. Gossipsub recovers some of the messages of lost frames through gossip, and
+//! drops duplicated ones; what it does not recover is lost to the application. Lost
+//! subscriptions and control messages disturb the meshes of topics as they would for real.
+//!
+//! Faults are drawn from a generator seeded with [`seed`](ChaosConfig::seed) if given, so that a
+//! run can be repeated as far as the timing of the network allows. The `chaos` section of a
+//! [manifest](crate::manifest) sets them:
+//!
+//! ```json
+//! {"chaos": {"inbound": {"drop": 0.05}, "outbound": {"delay": 0.2, "max_delay_ms": 500}, "seed": 7}}
+//! ```
+//!
+//! [`Stats::chaos`](crate::stats::Stats::chaos) counts the frames of every fault.
+
+use crate::{stats::ChaosStats, trace::Direction, Error, PubSubError};
+use async_std::task;
+use futures::prelude::*;
+use libp2p::{gossipsub::GossipsubRpc, PeerId};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Deserializer};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Probabilities of the faults injected into the frames of one direction, at most one per frame.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Faults {
+    /// Probability that a frame is lost.
+    pub drop: f64,
+    /// Probability that a frame goes through twice.
+    pub duplicate: f64,
+    /// Probability that a frame is held back.
+    pub delay: f64,
+    /// Longest a delayed frame is held back, in milliseconds in a manifest.
+    #[serde(rename = "max_delay_ms", deserialize_with = "millis")]
+    pub max_delay: Duration,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Faults injected into the gossip of a node, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Faults of the frames received, before the node handles them.
+    pub inbound: Faults,
+    /// Faults of the frames sent, before they leave the node.
+    pub outbound: Faults,
+    /// Seed of the faults drawn, random if not given.
+    pub seed: Option<u64>,
+}
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+/// What becomes of a frame.
+enum Fate {
+    Pass,
+    Drop,
+    Duplicate,
+    Delay(Duration),
+}
+
+/// The faults of a node, with the frames it holds back.
+pub(crate) struct Chaos {
+    config: ChaosConfig,
+    /// State of the xorshift generator the faults are drawn from, never zero.
+    state: u64,
+    /// Frames held back, with when they are let through.
+    delayed: Vec<(Instant, PeerId, GossipsubRpc, Direction)>,
+    /// Wakes the node when the first frame held back is due.
+    timer: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    stats: ChaosStats,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Result<Self, Error> {
+        for faults in &[config.inbound, config.outbound] {
+            let probabilities = [faults.drop, faults.duplicate, faults.delay];
+            if probabilities.iter().any(|p| !(0.0..=1.0).contains(p))
+                || probabilities.iter().sum::<f64>() > 1.0
+            {
+                return Err(PubSubError::config(
+                    "fault probabilities must lie between 0 and 1, and add up to at most 1",
+                ));
+            }
+        }
+        let seed = match config.seed {
+            Some(seed) => seed,
+            None => {
+                let mut seed = [0; 8];
+                SystemRandom::new()
+                    .fill(&mut seed)
+                    .map_err(|_| PubSubError::other("no randomness to seed faults with"))?;
+                u64::from_le_bytes(seed)
+            }
+        };
+        log::warn!("injecting faults into gossip: {:?}", config);
+        Ok(Chaos {
+            config,
+            state: seed.max(1),
+            delayed: Vec::new(),
+            timer: None,
+            stats: ChaosStats::default(),
+        })
+    }
+
+    /// A number drawn uniformly from `[0, 1)`.
+    fn draw(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn fate(&mut self, direction: Direction) -> Fate {
+        let faults = match direction {
+            Direction::Sent => self.config.outbound,
+            Direction::Received => self.config.inbound,
+        };
+        let draw = self.draw();
+        if draw < faults.drop {
+            Fate::Drop
+        } else if draw < faults.drop + faults.duplicate {
+            Fate::Duplicate
+        } else if draw < faults.drop + faults.duplicate + faults.delay {
+            Fate::Delay(faults.max_delay.mul_f64(self.draw()))
+        } else {
+            Fate::Pass
+        }
+    }
+
+    /// Inject a fault into a frame exchanged with `peer`, returning it if it goes through right
+    /// away. A duplicate is let through on the next [`poll_due`](Self::poll_due).
+    pub fn inject(
+        &mut self,
+        peer: &PeerId,
+        frame: GossipsubRpc,
+        direction: Direction,
+    ) -> Option<GossipsubRpc> {
+        match self.fate(direction) {
+            Fate::Pass => Some(frame),
+            Fate::Drop => {
+                self.stats.dropped += 1;
+                None
+            }
+            Fate::Duplicate => {
+                self.stats.duplicated += 1;
+                self.hold(peer, frame.clone(), direction, Duration::from_secs(0));
+                Some(frame)
+            }
+            Fate::Delay(delay) => {
+                self.stats.delayed += 1;
+                self.hold(peer, frame, direction, delay);
+                None
+            }
+        }
+    }
+
+    fn hold(&mut self, peer: &PeerId, frame: GossipsubRpc, direction: Direction, delay: Duration) {
+        self.delayed
+            .push((Instant::now() + delay, peer.clone(), frame, direction));
+        // Rearmed for the first frame due on the next poll.
+        self.timer = None;
+    }
+
+    /// Take a frame held back that is due, the earliest first.
+    pub fn poll_due(&mut self, cx: &mut Context) -> Poll<(PeerId, GossipsubRpc, Direction)> {
+        loop {
+            let (index, due) = match self
+                .delayed
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (due, ..))| *due)
+            {
+                Some((index, (due, ..))) => (index, *due),
+                None => return Poll::Pending,
+            };
+            let now = Instant::now();
+            if due <= now {
+                self.timer = None;
+                let (_, peer, frame, direction) = self.delayed.swap_remove(index);
+                return Poll::Ready((peer, frame, direction));
+            }
+            let timer = self
+                .timer
+                .get_or_insert_with(|| Box::pin(task::sleep(due - now)));
+            futures::ready!(timer.poll_unpin(cx));
+            self.timer = None;
+        }
+    }
+
+    /// Drop the frames held back for a peer that disconnected.
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.delayed.retain(|(_, held, ..)| held != peer);
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        self.stats
+    }
+}
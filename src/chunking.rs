@@ -0,0 +1,250 @@
+//! Splitting large payloads into chunks and putting them back together.
+//!
+//! Gossipsub rejects messages larger than its `max_transmit_size`. Messages published on a topic
+//! with a chunk size are split into as many messages as needed, each carrying a marker, the id of
+//! the chunked message, the index of the chunk and the number of chunks. Receiving nodes buffer
+//! the chunks, in whatever order they arrive, and deliver the payload once all of them are in,
+//! whatever their own settings. Chunked messages still incomplete after the reassembly timeout
+//! are dropped.
+
+use crate::{Error, PubSubError};
+use bytes::{Bytes, BytesMut};
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+/// Start of a chunk. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plc";
+
+/// Size of the marker, id, index and count preceding the data of a chunk.
+pub(crate) const HEADER_LEN: usize = MARKER.len() + 8 + 4 + 4;
+
+/// Upper bound on the size of a reassembled payload.
+pub(crate) const MAX_REASSEMBLED: usize = 16 * 1024 * 1024;
+
+/// Upper bound on the data buffered for all incomplete messages together.
+const MAX_BUFFERED: usize = 64 * 1024 * 1024;
+
+/// Split `data` into chunks of at most `chunk_size` bytes, header included, or return it as it
+/// is if it already fits. `chunk_size` must be larger than [`HEADER_LEN`].
+pub(crate) fn split(id: u64, data: Bytes, chunk_size: usize) -> Vec<Bytes> {
+    if data.len() <= chunk_size {
+        return vec![data];
+    }
+    let chunks = data.chunks(chunk_size - HEADER_LEN);
+    let count = chunks.len() as u32;
+    chunks
+        .enumerate()
+        .map(|(index, part)| {
+            let mut chunk = Vec::with_capacity(HEADER_LEN + part.len());
+            chunk.extend_from_slice(MARKER);
+            chunk.extend_from_slice(&id.to_be_bytes());
+            chunk.extend_from_slice(&(index as u32).to_be_bytes());
+            chunk.extend_from_slice(&count.to_be_bytes());
+            chunk.extend_from_slice(part);
+            chunk.into()
+        })
+        .collect()
+}
+
+/// The chunks received so far of a message.
+struct Partial {
+    count: u32,
+    /// Data of the chunks, sharing the buffers of the messages they came in.
+    chunks: BTreeMap<u32, Bytes>,
+    size: usize,
+    started: Instant,
+}
+
+/// Buffers of the chunked messages being received.
+pub(crate) struct Reassembler {
+    timeout: Duration,
+    partials: HashMap<(PeerId, u64), Partial>,
+    buffered: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Reassembler {
+            timeout,
+            partials: HashMap::new(),
+            buffered: 0,
+        }
+    }
+
+    /// Bytes of the chunks buffered for the messages being received.
+    pub(crate) fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Take in a received payload from `source`. Returns the payload to deliver: the payload
+    /// itself if it is not a chunk, the reassembled payload if it was the last missing chunk, or
+    /// `None` while chunks are missing.
+    pub(crate) fn accept(&mut self, source: &PeerId, data: Bytes) -> Result<Option<Bytes>, Error> {
+        if data.len() < HEADER_LEN || !data.starts_with(MARKER) {
+            return Ok(Some(data));
+        }
+        self.expire();
+        let field = |at: usize, len: usize| &data[MARKER.len() + at..MARKER.len() + at + len];
+        let id = u64::from_be_bytes(<[u8; 8]>::try_from(field(0, 8)).map_err(PubSubError::codec)?);
+        let index =
+            u32::from_be_bytes(<[u8; 4]>::try_from(field(8, 4)).map_err(PubSubError::codec)?);
+        let count =
+            u32::from_be_bytes(<[u8; 4]>::try_from(field(12, 4)).map_err(PubSubError::codec)?);
+        if index >= count {
+            return Err(PubSubError::codec(format!(
+                "chunk {} of a message of {} chunks",
+                index, count
+            )));
+        }
+
+        let key = (source.clone(), id);
+        let partial = self.partials.entry(key.clone()).or_insert_with(|| Partial {
+            count,
+            chunks: BTreeMap::new(),
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.count != count {
+            return Err(PubSubError::codec(
+                "chunks disagree on the number of chunks",
+            ));
+        }
+        if partial.chunks.contains_key(&index) {
+            return Ok(None);
+        }
+        let part = data.slice(HEADER_LEN..);
+        partial.size += part.len();
+        self.buffered += part.len();
+        partial.chunks.insert(index, part);
+        if partial.size > MAX_REASSEMBLED {
+            self.remove(&key);
+            return Err(PubSubError::codec("reassembled payload is too large"));
+        }
+        if partial.chunks.len() as u32 == count {
+            let partial = self.remove(&key).expect("partial was just updated");
+            let mut payload = BytesMut::with_capacity(partial.size);
+            for chunk in partial.chunks.values() {
+                payload.extend_from_slice(chunk);
+            }
+            return Ok(Some(payload.freeze()));
+        }
+        while self.buffered > MAX_BUFFERED {
+            let oldest = self
+                .partials
+                .iter()
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(key, _)| key.clone())
+                .expect("buffered data belongs to a partial");
+            log::debug!(
+                "dropping chunked message {} from {}: buffers are full",
+                oldest.1,
+                oldest.0
+            );
+            self.remove(&oldest);
+        }
+        Ok(None)
+    }
+
+    /// Drop the messages whose chunks did not all arrive in time.
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        let buffered = &mut self.buffered;
+        self.partials.retain(|(source, id), partial| {
+            if partial.started.elapsed() < timeout {
+                return true;
+            }
+            log::debug!(
+                "dropping chunked message {} from {}: {} of {} chunks arrived",
+                id,
+                source,
+                partial.chunks.len(),
+                partial.count
+            );
+            *buffered -= partial.size;
+            false
+        });
+    }
+
+    fn remove(&mut self, key: &(PeerId, u64)) -> Option<Partial> {
+        let partial = self.partials.remove(key)?;
+        self.buffered -= partial.size;
+        Some(partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: u64, index: u32, count: u32, part: &[u8]) -> Bytes {
+        let mut chunk = MARKER.to_vec();
+        chunk.extend_from_slice(&id.to_be_bytes());
+        chunk.extend_from_slice(&index.to_be_bytes());
+        chunk.extend_from_slice(&count.to_be_bytes());
+        chunk.extend_from_slice(part);
+        chunk.into()
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_duplicate_chunks() {
+        let source = PeerId::random();
+        let data = Bytes::from((0..100u8).collect::<Vec<_>>());
+        let mut chunks = split(7, data.clone(), HEADER_LEN + 30);
+        assert_eq!(chunks.len(), 4);
+        chunks.reverse();
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let last = chunks.pop().unwrap();
+        for chunk in chunks.iter().chain(&chunks[..1]) {
+            assert_eq!(reassembler.accept(&source, chunk.clone()).unwrap(), None);
+        }
+        assert_eq!(reassembler.accept(&source, last).unwrap(), Some(data));
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn passes_payloads_that_are_not_chunks() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let data = Bytes::from_static(b"{\"plain\":true}");
+        assert_eq!(
+            reassembler.accept(&PeerId::random(), data.clone()).unwrap(),
+            Some(data)
+        );
+    }
+
+    #[test]
+    fn rejects_index_past_count() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let result = reassembler.accept(&PeerId::random(), chunk(1, 2, 2, b"x"));
+        assert!(matches!(result, Err(PubSubError::Codec(_))));
+    }
+
+    #[test]
+    fn rejects_chunks_disagreeing_on_count() {
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        assert_eq!(
+            reassembler.accept(&source, chunk(1, 0, 3, b"x")).unwrap(),
+            None
+        );
+        let result = reassembler.accept(&source, chunk(1, 1, 2, b"y"));
+        assert!(matches!(result, Err(PubSubError::Codec(_))));
+    }
+
+    #[test]
+    fn rejects_payloads_reassembling_too_large() {
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let part = vec![0; MAX_REASSEMBLED / 2 + 1];
+        assert_eq!(
+            reassembler.accept(&source, chunk(1, 0, 3, &part)).unwrap(),
+            None
+        );
+        let result = reassembler.accept(&source, chunk(1, 1, 3, &part));
+        assert!(matches!(result, Err(PubSubError::Codec(_))));
+        assert_eq!(reassembler.buffered(), 0);
+    }
+}
@@ -0,0 +1,1071 @@
+use crate::attestation::SignatureStatus;
+use crate::autonat::ReachabilityStatus;
+use crate::compat;
+use crate::debug::DebugDump;
+use crate::delegation::Origin;
+use crate::direct::{self, DirectMessages};
+use crate::directory::TopicListing;
+use crate::durable::{self, DurableSubscription};
+use crate::flow::{self, Bounds, Overflow, QueueStatus};
+use crate::group::{self, GroupSubscription};
+use crate::headers::{HeaderFilter, Headers};
+use crate::health::HealthReport;
+use crate::lock::{self, LockGuard};
+use crate::message_id::{Published, Receipts};
+use crate::migration::{self, Migration, MigrationConfig};
+use crate::node::{Command, Milestone, Subscriber};
+use crate::observer::ReadOnly;
+use crate::pipeline::Pipeline;
+#[cfg(feature = "wasm")]
+use crate::plugin::{Hook, InstalledPlugin, Plugin};
+use crate::presence::{self, Presence};
+use crate::queue::{self, Consumer};
+use crate::reconcile::{self, ConfigWatch, DesiredState, Managed, StateDiff};
+use crate::retention::Replay;
+use crate::rotation::Rotations;
+use crate::schema::{self, Any};
+use crate::shard::{self, ShardSubscription, Shards};
+use crate::size::SizeLimits;
+use crate::state::{self, StateMap};
+use crate::stats::{MeshInfo, MeshPeer, Stats};
+use crate::storage::Storage;
+use crate::topic::TopicFilter;
+use crate::topology::Topology;
+use crate::trace::TraceEvent;
+use crate::trace_context::TraceContext;
+use crate::{Error, PubSubError};
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use libp2p::{gossipsub::protocol::MessageId, Multiaddr, PeerId};
+use std::{
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+/// A message received on a subscribed topic.
+#[derive(Clone, Debug)]
+pub struct Message {
+    /// Id of the message, see the [`message_id`](crate::message_id) module.
+    pub id: MessageId,
+    /// Peer that originally published the message.
+    pub source: PeerId,
+    /// Topic the message was delivered on.
+    pub topic: String,
+    /// Payload of the message. Cloning the message, as done for each of its subscribers, shares
+    /// the payload rather than copying it.
+    pub data: Bytes,
+    /// Sequence number assigned by the publisher.
+    pub sequence_number: u64,
+    /// Organisation the message was published under, if its topic has a trusted organisation,
+    /// see [`NodeConfig::trusted_orgs`](crate::NodeConfig::trusted_orgs).
+    pub origin: Option<Origin>,
+    /// Whether the message is signed with the key of its [`source`](Self::source), see the
+    /// [`attestation`](crate::attestation) module.
+    pub signature: SignatureStatus,
+    /// When the message was published at, by the clock of this node as far as the skew of the
+    /// publisher is known, if its topic is timestamped by the publisher, see
+    /// [`NodeConfig::clock`](crate::NodeConfig::clock).
+    pub published_at: Option<SystemTime>,
+    /// Trace context of the span that published the message, if it was published with
+    /// [`Client::publish_traced`], see the [`trace_context`](crate::trace_context) module.
+    pub trace_context: Option<TraceContext>,
+    /// Headers the message was published with, see the [`headers`](crate::headers) module.
+    pub headers: Headers,
+}
+
+impl Message {
+    /// The peer that published the message, if its signature was checked, see the
+    /// [`attestation`](crate::attestation) module.
+    pub fn verified_source(&self) -> Option<&PeerId> {
+        match self.signature {
+            SignatureStatus::Verified => Some(&self.source),
+            SignatureStatus::Unsigned => None,
+        }
+    }
+
+    /// A span for the processing of the message, continuing the trace it was published in, if
+    /// any, see the [`trace_context`](crate::trace_context) module.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> tracing::Span {
+        match &self.trace_context {
+            Some(context) => tracing::info_span!(
+                "pubsub.deliver",
+                topic = %self.topic,
+                message_id = %self.id,
+                trace_id = %context.trace_id_hex(),
+                parent_span_id = %context.span_id_hex(),
+                traceparent = %context,
+            ),
+            None => tracing::info_span!(
+                "pubsub.deliver",
+                topic = %self.topic,
+                message_id = %self.id,
+            ),
+        }
+    }
+}
+
+/// Handle to a running node.
+///
+/// Cloning a client is cheap; the node keeps running until every clone has been dropped.
+#[derive(Clone)]
+pub struct Client {
+    commands: mpsc::UnboundedSender<Command>,
+    local_peer_id: PeerId,
+    subscription_bounds: Bounds,
+    /// Limits on the size of published payloads, by topic filter.
+    size_limits: Arc<SizeLimits>,
+    /// Protobuf message types of published payloads, by topic filter.
+    message_types: Arc<Vec<(TopicFilter, String)>>,
+    /// Whether the node is an observer, refusing every publish.
+    observer: bool,
+    /// The state left by [`reconcile`](Client::reconcile).
+    managed: Arc<Mutex<Managed>>,
+}
+
+/// Id of the next subscription.
+static NEXT_SUBSCRIPTION: AtomicU64 = AtomicU64::new(1);
+
+impl Client {
+    pub(crate) fn new(
+        commands: mpsc::UnboundedSender<Command>,
+        local_peer_id: PeerId,
+        subscription_bounds: Bounds,
+        size_limits: SizeLimits,
+        message_types: Vec<(TopicFilter, String)>,
+        observer: bool,
+    ) -> Self {
+        Client {
+            commands,
+            local_peer_id,
+            subscription_bounds,
+            size_limits: Arc::new(size_limits),
+            message_types: Arc::new(message_types),
+            observer,
+            managed: Arc::default(),
+        }
+    }
+
+    /// The peer id of the node this client talks to.
+    pub fn local_peer_id(&self) -> &PeerId {
+        &self.local_peer_id
+    }
+
+    /// Publish `data` on `topic`, failing with a [`PubSubError::Publish`] caused by a
+    /// [`MessageTooLarge`](crate::size::MessageTooLarge) if it exceeds the
+    /// [`max_message_size`](crate::NodeConfig::max_message_size) of the topic and the
+    /// [`oversize`](crate::NodeConfig::oversize) policy of the topic rejects it, or by a
+    /// [`TypeMismatch`](crate::schema::TypeMismatch) if it is not of the
+    /// [`message_types`](crate::NodeConfig::message_types) of the topic, and with
+    /// [`PubSubError::Shutdown`] once the node has shut down.
+    ///
+    /// `data` is anything that turns into [`Bytes`] without a copy, such as a `Vec<u8>`, a
+    /// `String`, a static slice or `Bytes` themselves, which the node then shares with its local
+    /// subscribers and the envelopes that leave it as is.
+    pub fn publish(&self, topic: &str, data: impl Into<Bytes>) -> Result<(), Error> {
+        check_observer(self.observer, topic)?;
+        let data = data.into();
+        schema::check_type(&self.message_types, topic, &data)?;
+        let data = self.size_limits.admit(topic, data)?;
+        self.send(Command::Publish {
+            topic: topic.to_owned(),
+            data,
+            trace_context: None,
+            headers: Headers::new(),
+            receipts: Receipts::default(),
+        })
+    }
+
+    /// Publish `data` on `topic` like [`publish`](Client::publish), returning the future id of
+    /// the message, known once the node hands it to gossipsub, see the
+    /// [`message_id`](crate::message_id) module. The id fails to come if the node drops the
+    /// message first, e.g. because too many messages wait for the upload limit, or only sends it
+    /// with floodsub.
+    pub fn publish_with_id(&self, topic: &str, data: impl Into<Bytes>) -> Result<Published, Error> {
+        check_observer(self.observer, topic)?;
+        let data = data.into();
+        schema::check_type(&self.message_types, topic, &data)?;
+        let data = self.size_limits.admit(topic, data)?;
+        let (receipts, published) = Receipts::new(topic);
+        self.send(Command::Publish {
+            topic: topic.to_owned(),
+            data,
+            trace_context: None,
+            headers: Headers::new(),
+            receipts,
+        })?;
+        Ok(published)
+    }
+
+    /// Publish `data` on `topic` like [`publish`](Client::publish), carrying `headers` to the
+    /// subscribers, see the [`headers`](crate::headers) module.
+    pub fn publish_with_headers(
+        &self,
+        topic: &str,
+        data: impl Into<Bytes>,
+        headers: &Headers,
+    ) -> Result<(), Error> {
+        check_observer(self.observer, topic)?;
+        let data = data.into();
+        schema::check_type(&self.message_types, topic, &data)?;
+        let data = self.size_limits.admit(topic, data)?;
+        self.send(Command::Publish {
+            topic: topic.to_owned(),
+            data,
+            trace_context: None,
+            headers: headers.clone(),
+            receipts: Receipts::default(),
+        })
+    }
+
+    /// Publish `data` on `topic` like [`publish`](Client::publish), carrying the trace `context`
+    /// of the span publishing it to the subscribers, see the
+    /// [`trace_context`](crate::trace_context) module.
+    pub fn publish_traced(
+        &self,
+        topic: &str,
+        data: impl Into<Bytes>,
+        context: &TraceContext,
+    ) -> Result<(), Error> {
+        check_observer(self.observer, topic)?;
+        let data = data.into();
+        schema::check_type(&self.message_types, topic, &data)?;
+        let data = self.size_limits.admit(topic, data)?;
+        self.send(Command::Publish {
+            topic: topic.to_owned(),
+            data,
+            trace_context: Some(*context),
+            headers: Headers::new(),
+            receipts: Receipts::default(),
+        })
+    }
+
+    /// Publish `data` on `topic` by sending it directly to the connected `peers`, rather than to
+    /// the mesh of the topic. The recipients deliver it to their subscribers but neither forward
+    /// nor gossip it, and it carries no headers or trace context. Peers that are not connected
+    /// are skipped. Fails like [`publish`](Client::publish) if `data` is too large for the topic.
+    ///
+    /// Only peers running the gossipsub of this crate know not to forward it: others, such as
+    /// go-ipfs or the upstream rust-libp2p, flood it to their mesh as any message of the topic.
+    /// It is no way to keep a payload from the rest of the network, which
+    /// [direct messages](crate::direct) or a [`TopicKey`](crate::crypto::TopicKey) do.
+    pub fn publish_to(
+        &self,
+        topic: &str,
+        peers: &[PeerId],
+        data: impl Into<Bytes>,
+    ) -> Result<(), Error> {
+        check_observer(self.observer, topic)?;
+        let data = data.into();
+        schema::check_type(&self.message_types, topic, &data)?;
+        let data = self.size_limits.admit(topic, data)?;
+        self.send(Command::PublishTo {
+            topic: topic.to_owned(),
+            peers: peers.to_vec(),
+            data,
+        })
+    }
+
+    /// Publish a Protobuf message wrapped in a `google.protobuf.Any` on `topic`, failing like
+    /// [`publish`](Client::publish), e.g. if the type URL of `any` does not name the message type
+    /// of the topic.
+    pub fn publish_any(&self, topic: &str, any: &Any) -> Result<(), Error> {
+        self.publish(topic, any.to_bytes())
+    }
+
+    /// Subscribe to `topic`, returning a stream of every message received on it.
+    ///
+    /// The gossipsub subscription is kept alive for as long as any `Subscription` for the
+    /// topic exists. Messages wait for the subscriber in a queue bounded by the
+    /// [`subscription_bounds`](crate::NodeConfig::subscription_bounds) of the node.
+    pub fn subscribe(&self, topic: &str) -> Result<Subscription, Error> {
+        self.subscribe_with(topic, None, self.subscription_bounds, Replay::None)
+    }
+
+    /// Subscribe to `topic` like [`subscribe`](Client::subscribe), with every message run through
+    /// `pipeline` by the node before it is delivered.
+    pub fn subscribe_pipeline(
+        &self,
+        topic: &str,
+        pipeline: Pipeline,
+    ) -> Result<Subscription, Error> {
+        self.subscribe_with(
+            topic,
+            Some(Arc::new(pipeline)),
+            self.subscription_bounds,
+            Replay::None,
+        )
+    }
+
+    /// Subscribe to `topic`, or to a wildcard filter, with messages waiting for the subscriber
+    /// in a queue of its own `bounds`.
+    ///
+    /// With [`Overflow::Block`](crate::flow::Overflow::Block), the node stops taking messages
+    /// from its peers while the queue is full, holding up every other subscription and making
+    /// peers buffer what they send: only use it for consumers that keep up.
+    pub fn subscribe_bounded(&self, topic: &str, bounds: Bounds) -> Result<Subscription, Error> {
+        let filter = TopicFilter::new(topic).map_err(|e| PubSubError::subscribe(topic, e))?;
+        if filter.is_wildcard() {
+            self.subscribe_filter_with(filter, bounds, Replay::None)
+        } else {
+            self.subscribe_with(topic, None, bounds, Replay::None)
+        }
+    }
+
+    /// Subscribe to `topic`, or to a wildcard filter, like [`subscribe`](Client::subscribe),
+    /// first receiving the messages the node retained on it that `replay` asks for, as described
+    /// in the [`retention`](crate::retention) module.
+    pub fn subscribe_replay(&self, topic: &str, replay: Replay) -> Result<Subscription, Error> {
+        let filter = TopicFilter::new(topic).map_err(|e| PubSubError::subscribe(topic, e))?;
+        if filter.is_wildcard() {
+            self.subscribe_filter_with(filter, self.subscription_bounds, replay)
+        } else {
+            self.subscribe_with(topic, None, self.subscription_bounds, replay)
+        }
+    }
+
+    /// Subscribe to `topic`, or to a wildcard filter, through the journal at `journal`, resuming
+    /// after the last message acknowledged on it, as described in the
+    /// [`durable`](crate::durable) module.
+    pub fn subscribe_durable(
+        &self,
+        topic: &str,
+        journal: impl AsRef<Path>,
+    ) -> Result<DurableSubscription, Error> {
+        durable::subscribe(self, topic, journal.as_ref())
+    }
+
+    /// Subscribe to `topic` like [`subscribe_durable`](Self::subscribe_durable), through the
+    /// journal `journal` kept in `storage` rather than in a file. See the
+    /// [`storage`](crate::storage) module.
+    pub fn subscribe_durable_in(
+        &self,
+        topic: &str,
+        storage: Arc<dyn Storage>,
+        journal: &str,
+    ) -> Result<DurableSubscription, Error> {
+        durable::subscribe_in(self, topic, storage, journal)
+    }
+
+    /// Subscribe to `topic` like [`subscribe`](Client::subscribe), only delivering the messages
+    /// whose [`source`](Message::source) is verified: those signed with the key of the peer that
+    /// published them, see the [`attestation`](crate::attestation) module. Unsigned messages are
+    /// dropped.
+    pub fn subscribe_verified(&self, topic: &str) -> Result<Subscription, Error> {
+        let (subscriber, subscription) = subscriber(topic, self.subscription_bounds);
+        self.send(Command::Subscribe {
+            topic: topic.to_owned(),
+            subscriber: Subscriber {
+                verified_only: true,
+                ..subscriber
+            },
+            replay: Replay::None,
+        })?;
+        Ok(subscription)
+    }
+
+    /// Subscribe to `topic` like [`subscribe`](Client::subscribe), only delivering the messages
+    /// whose headers satisfy every one of `predicates`, such as `region=eu` or `severity>=warn`,
+    /// see the [`headers`](crate::headers) module.
+    pub fn subscribe_where(&self, topic: &str, predicates: &[&str]) -> Result<Subscription, Error> {
+        let filter = HeaderFilter::new(predicates).map_err(|e| PubSubError::subscribe(topic, e))?;
+        let (subscriber, subscription) = subscriber(topic, self.subscription_bounds);
+        self.send(Command::Subscribe {
+            topic: topic.to_owned(),
+            subscriber: Subscriber {
+                headers: Some(filter),
+                ..subscriber
+            },
+            replay: Replay::None,
+        })?;
+        Ok(subscription)
+    }
+
+    fn subscribe_with(
+        &self,
+        topic: &str,
+        pipeline: Option<Arc<Pipeline>>,
+        bounds: Bounds,
+        replay: Replay,
+    ) -> Result<Subscription, Error> {
+        let (subscriber, subscription) = subscriber(topic, bounds);
+        self.send(Command::Subscribe {
+            topic: topic.to_owned(),
+            subscriber: Subscriber {
+                pipeline,
+                ..subscriber
+            },
+            replay,
+        })?;
+        Ok(subscription)
+    }
+
+    /// Subscribe to every topic matched by a wildcard `filter` such as `sensors/+/temp` or
+    /// `logs/#`, returning a single stream of the messages received on all of them.
+    ///
+    /// Matching topics are discovered through the announcements described in the
+    /// [`topic`](crate::topic) module, so messages published before a topic is announced are
+    /// not delivered.
+    pub fn subscribe_filter(&self, filter: &str) -> Result<Subscription, Error> {
+        self.subscribe_filter_with(
+            TopicFilter::new(filter).map_err(|e| PubSubError::subscribe(filter, e))?,
+            self.subscription_bounds,
+            Replay::None,
+        )
+    }
+
+    fn subscribe_filter_with(
+        &self,
+        filter: TopicFilter,
+        bounds: Bounds,
+        replay: Replay,
+    ) -> Result<Subscription, Error> {
+        let (subscriber, subscription) = subscriber(filter.as_str(), bounds);
+        self.send(Command::SubscribeFilter {
+            filter,
+            subscriber,
+            replay,
+        })?;
+        Ok(subscription)
+    }
+
+    /// The topics this node has local subscribers for.
+    pub async fn topics(&self) -> Result<Vec<String>, Error> {
+        let (reply, topics) = oneshot::channel();
+        self.send(Command::Topics { reply })?;
+        topics.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// The connected peers subscribed to `topic`, or to any topic if `None`.
+    pub async fn peers(&self, topic: Option<&str>) -> Result<Vec<PeerId>, Error> {
+        let (reply, peers) = oneshot::channel();
+        self.send(Command::Peers {
+            topic: topic.map(str::to_owned),
+            reply,
+        })?;
+        peers.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// The connected peers that announced a subscription to `topic`, whether or not they are in
+    /// this node's mesh for it.
+    pub async fn peers_on_topic(&self, topic: &str) -> Result<Vec<PeerId>, Error> {
+        self.peers(Some(topic)).await
+    }
+
+    /// The topics the connected peer `peer` announced a subscription to, empty if it is not
+    /// connected.
+    pub async fn peer_topics(&self, peer: &PeerId) -> Result<Vec<String>, Error> {
+        let (reply, topics) = oneshot::channel();
+        self.send(Command::PeerTopics {
+            peer: peer.clone(),
+            reply,
+        })?;
+        topics.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// A snapshot of the state of the node: its peers, their traffic and the traffic on topics.
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        let (reply, stats) = oneshot::channel();
+        self.send(Command::Stats { reply })?;
+        stats.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// A snapshot of the gossip state of the node: for every topic, the peers in its mesh or
+    /// fanout and the other subscribed peers, with their agent version and ping round-trip time.
+    pub async fn mesh_info(&self) -> Result<MeshInfo, Error> {
+        let (reply, info) = oneshot::channel();
+        self.send(Command::MeshInfo { reply })?;
+        info.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// A snapshot of the swarm state of the node, for debugging: its connections, dials in
+    /// flight, the mesh and fanout of every topic and the memory held by its caches. See the
+    /// [`debug`](crate::debug) module.
+    pub async fn debug_dump(&self) -> Result<DebugDump, Error> {
+        let (reply, dump) = oneshot::channel();
+        self.send(Command::DebugDump { reply })?;
+        dump.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// The gossip events the node traced, oldest first, see the [`trace`](crate::trace) module.
+    /// Fails unless the node traces gossip.
+    pub async fn trace(&self) -> Result<Vec<TraceEvent>, Error> {
+        self.traced(None).await
+    }
+
+    /// The gossip events the node traced naming the message `id`, oldest first: its copies sent
+    /// and received, and the IHAVE and IWANT gossip about it.
+    pub async fn trace_message(&self, id: &MessageId) -> Result<Vec<TraceEvent>, Error> {
+        self.traced(Some(id.clone())).await
+    }
+
+    async fn traced(&self, message_id: Option<MessageId>) -> Result<Vec<TraceEvent>, Error> {
+        let (reply, events) = oneshot::channel();
+        self.send(Command::Trace { message_id, reply })?;
+        events
+            .await
+            .map_err(|_| Error::Shutdown)?
+            .ok_or_else(|| "the node does not trace gossip".into())
+    }
+
+    /// The connected peers subscribed to `topic` or in its fanout, and whether each is in this
+    /// node's mesh for it.
+    pub async fn list_peers(&self, topic: &str) -> Result<Vec<MeshPeer>, Error> {
+        let (reply, peers) = oneshot::channel();
+        self.send(Command::ListPeers {
+            topic: topic.to_owned(),
+            reply,
+        })?;
+        peers.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Wait until the node listens for connections, so that applications embedding it can hold
+    /// their own startup back until then. Returns right away if it already does.
+    pub async fn on_ready(&self) -> Result<(), Error> {
+        self.wait_for(Milestone::Ready).await
+    }
+
+    /// Wait until at least `quorum` peers are connected and identified. Returns right away if
+    /// they already are. An [embedded](crate::NodeConfig::embedded) node never connects to any,
+    /// and fails to wait for more than none.
+    pub async fn on_peer_quorum(&self, quorum: usize) -> Result<(), Error> {
+        self.wait_for(Milestone::PeerQuorum(quorum)).await
+    }
+
+    /// Wait until this node is subscribed to `topic` and has peers in its mesh, so that what it
+    /// publishes on the topic reaches them. Returns right away if it already is. An
+    /// [embedded](crate::NodeConfig::embedded) node only waits for the subscription.
+    pub async fn on_topic_joined(&self, topic: &str) -> Result<(), Error> {
+        self.on_mesh_peers(topic, 1).await
+    }
+
+    /// Wait until this node is subscribed to `topic` and has at least `peers` peers in its mesh.
+    pub(crate) async fn on_mesh_peers(&self, topic: &str, peers: usize) -> Result<(), Error> {
+        self.wait_for(Milestone::TopicJoined(topic.to_owned(), peers))
+            .await
+    }
+
+    async fn wait_for(&self, milestone: Milestone) -> Result<(), Error> {
+        let (reply, reached) = oneshot::channel();
+        self.send(Command::WaitFor { milestone, reply })?;
+        reached.await.map_err(|_| Error::Shutdown)?
+    }
+
+    /// The health of the node and of the journals of the process, see the
+    /// [`health`](crate::health) module.
+    pub async fn health(&self) -> Result<HealthReport, Error> {
+        let (reply, report) = oneshot::channel();
+        self.send(Command::Health { reply })?;
+        let report = report.await.map_err(|_| Error::Shutdown)?;
+        Ok(HealthReport {
+            journals: durable::journals(),
+            ..report
+        })
+    }
+
+    /// Whether the node is reachable by its peers, and the addresses it listens on, is observed
+    /// at and was reached at. See the [`autonat`](crate::autonat) module.
+    pub async fn reachability(&self) -> Result<ReachabilityStatus, Error> {
+        let (reply, status) = oneshot::channel();
+        self.send(Command::Reachability { reply })?;
+        status.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Stop gossiping about messages, e.g. once the application detects that its link is
+    /// metered: the node neither advertises the messages it has nor asks for those it missed,
+    /// and only gets and forwards those of its meshes. See the [`preset`](crate::preset) module.
+    pub fn pause_gossip(&self) -> Result<(), Error> {
+        self.send(Command::PauseGossip { paused: true })
+    }
+
+    /// Gossip again after [`pause_gossip`](Client::pause_gossip).
+    pub fn resume_gossip(&self) -> Result<(), Error> {
+        self.send(Command::PauseGossip { paused: false })
+    }
+
+    /// The components configured on the node: its transport, gateways, bridges, sinks and
+    /// plugins, with their status and the hash of their configuration.
+    pub async fn describe_topology(&self) -> Result<Topology, Error> {
+        let (reply, topology) = oneshot::channel();
+        self.send(Command::DescribeTopology { reply })?;
+        topology.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Check that the node still reads the wire formats and documents of earlier releases, by
+    /// running the vectors of the [`compat`](crate::compat) module through its receive path. An
+    /// embedded node, which has no wire, checks the codecs of the build.
+    pub async fn check_compat(&self) -> Result<compat::Report, Error> {
+        let (reply, report) = oneshot::channel();
+        self.send(Command::CheckCompat { reply })?;
+        report.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Bring the node to `desired`, returning what changed. See [`reconcile`](crate::reconcile).
+    pub fn reconcile(&self, desired: &DesiredState) -> Result<StateDiff, Error> {
+        self.managed.lock().unwrap().reconcile(self, desired, true)
+    }
+
+    /// What [`reconcile`](Client::reconcile) would change to bring the node to `desired`,
+    /// without changing anything.
+    pub fn plan(&self, desired: &DesiredState) -> Result<StateDiff, Error> {
+        self.managed.lock().unwrap().reconcile(self, desired, false)
+    }
+
+    /// Keep the node reconciled with the document at `path`, applying it now and whenever it
+    /// changes, until the returned stream of outcomes is dropped.
+    pub fn watch_config(&self, path: impl Into<PathBuf>) -> ConfigWatch {
+        reconcile::watch(self, path.into())
+    }
+
+    /// The connected peers supporting `protocol`, as they advertised through identify.
+    pub async fn peers_supporting(&self, protocol: &str) -> Result<Vec<PeerId>, Error> {
+        let (reply, peers) = oneshot::channel();
+        self.send(Command::PeersSupporting {
+            protocol: protocol.to_owned(),
+            reply,
+        })?;
+        peers.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Follow the connected peers supporting `protocol`: the returned stream first reports those
+    /// already known, then every peer that becomes available or goes away.
+    pub fn watch_protocol(&self, protocol: &str) -> Result<ProtocolWatch, Error> {
+        let (watcher, receiver) = mpsc::unbounded();
+        self.send(Command::WatchProtocol {
+            protocol: protocol.to_owned(),
+            watcher,
+        })?;
+        Ok(ProtocolWatch { receiver })
+    }
+
+    /// Follow the membership of topics: the returned stream reports every later change in the
+    /// gossipsub mesh of a topic this node is in, and every subscription or unsubscription of
+    /// this node or of a connected peer.
+    pub fn changes(&self) -> Result<Changes, Error> {
+        let (watcher, receiver) = mpsc::unbounded();
+        self.send(Command::WatchChanges { watcher })?;
+        Ok(Changes { receiver })
+    }
+
+    /// Follow what happens on the node from now on: peers connecting and disconnecting, this
+    /// node and connected peers subscribing and unsubscribing, messages received from the
+    /// network, new listen addresses and the errors the node carries on from. Events wait for
+    /// the stream in a queue as large as those of subscriptions, the oldest being dropped when it
+    /// is full. An [embedded](crate::NodeConfig::embedded) node only reports its own
+    /// subscriptions.
+    pub fn events(&self) -> Result<Events, Error> {
+        let (watcher, receiver) =
+            flow::channel(self.subscription_bounds.capacity, Overflow::DropOldest);
+        self.send(Command::WatchEvents { watcher })?;
+        Ok(Events { receiver })
+    }
+
+    /// Stream of alerts about the bridges and sinks running on the node, raised when one has
+    /// been disconnected for longer than its alert threshold and once it reconnects.
+    pub fn bridge_alerts(&self) -> Result<BridgeAlerts, Error> {
+        let (watcher, receiver) = mpsc::unbounded();
+        self.send(Command::WatchBridges { watcher })?;
+        Ok(BridgeAlerts { receiver })
+    }
+
+    /// The latest peer id `peer` rotated its keypair to, following the continuity records this
+    /// node learned, or `None` if it did not rotate. See the [`rotation`](crate::rotation) module.
+    pub async fn successor(&self, peer: &PeerId) -> Result<Option<PeerId>, Error> {
+        let (reply, successor) = oneshot::channel();
+        self.send(Command::Successor {
+            peer: peer.clone(),
+            reply,
+        })?;
+        successor.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// The topics hosted by the nodes taking part in the topic directory, this node included,
+    /// with how many nodes host each, sorted by name. Fails unless the node was started with
+    /// [`topic_directory`](crate::NodeConfig::topic_directory) set, see the
+    /// [`directory`](crate::directory) module.
+    pub async fn discover_topics(&self) -> Result<Vec<TopicListing>, Error> {
+        let (reply, listings) = oneshot::channel();
+        self.send(Command::DiscoverTopics { reply })?;
+        listings
+            .await
+            .map_err(|_| Error::Shutdown)?
+            .ok_or_else(|| "the topic directory is not enabled".into())
+    }
+
+    /// Stream of the continuity records this node learns from now on, one for every rotation of
+    /// the keypair of a peer, this node included.
+    pub fn rotations(&self) -> Result<Rotations, Error> {
+        let (watcher, receiver) = mpsc::unbounded();
+        self.send(Command::WatchRotations { watcher })?;
+        Ok(Rotations { receiver })
+    }
+
+    /// Acquire the distributed lock `name` with a lease of `ttl`, renewed until the returned
+    /// guard is dropped. See the [`lock`](crate::lock) module for the guarantees involved.
+    pub async fn lock(&self, name: &str, ttl: Duration) -> Result<LockGuard, Error> {
+        lock::acquire(self, name, ttl).await
+    }
+
+    /// Take part in the presence of `topic` and follow the peers present on it: the returned
+    /// stream reports every peer, this node included, that announces itself, changes what it
+    /// does on the topic, or leaves. See the [`presence`](crate::presence) module.
+    pub fn presence(&self, topic: &str) -> Result<Presence, Error> {
+        presence::watch(self, topic)
+    }
+
+    /// Migrate the topic `config.from` to `config.to`: republish the messages of the old topic on
+    /// the new one for the overlap window, until the returned stream is dropped, which reports
+    /// when the traffic on the old topic ceased. See the [`migration`](crate::migration) module.
+    pub fn migrate_topic(&self, config: MigrationConfig) -> Result<Migration, Error> {
+        migration::start(self, config)
+    }
+
+    /// Hold a replica of the key-value map `name`, shared with every node holding it and kept in
+    /// sync with them until dropped. See the [`state`](crate::state) module.
+    pub fn state(&self, name: &str) -> Result<StateMap, Error> {
+        state::join(self, name)
+    }
+
+    /// Send `data` to `peer` only, sealed to its public key on its inbox topic. Fails if the
+    /// node has not learned the public key of the peer. See the [`direct`](crate::direct)
+    /// module.
+    pub async fn send_to(&self, peer: &PeerId, data: impl Into<Bytes>) -> Result<(), Error> {
+        self.send_to_all(std::slice::from_ref(peer), data).await
+    }
+
+    /// Send `data` to `peers` only, like [`send_to`](Client::send_to), sealing it once for all
+    /// of them.
+    pub async fn send_to_all(&self, peers: &[PeerId], data: impl Into<Bytes>) -> Result<(), Error> {
+        direct::send(self, peers, data.into()).await
+    }
+
+    /// Subscribe to the inbox of this node: the returned stream hands out the direct messages
+    /// sealed to it. Fails unless the identity of the node is an Ed25519 key.
+    pub async fn direct_messages(&self) -> Result<DirectMessages, Error> {
+        direct::open(self).await
+    }
+
+    /// Join the queue group `group` on `topic`, sharing the messages of the topic with the other
+    /// members of the group, local or remote: each message is delivered to one member only. See
+    /// the [`group`](crate::group) module.
+    pub fn subscribe_group(&self, topic: &str, group: &str) -> Result<GroupSubscription, Error> {
+        group::join(self, topic, group)
+    }
+
+    /// Publish `data` on the shard of `key` of the sharded topic `shards`, see the
+    /// [`shard`](crate::shard) module.
+    pub fn publish_sharded(
+        &self,
+        shards: &Shards,
+        key: &[u8],
+        data: impl Into<Bytes>,
+    ) -> Result<(), Error> {
+        self.publish(&shards.topic_of(key), data)
+    }
+
+    /// Join the consumers of the sharded topic `shards` in the group `group`, sharing its shards
+    /// with the other consumers of the group, local or remote: each shard is subscribed to by
+    /// one consumer only, and shards move as consumers join and leave. See the
+    /// [`shard`](crate::shard) module.
+    pub fn subscribe_shards(
+        &self,
+        shards: &Shards,
+        group: &str,
+    ) -> Result<ShardSubscription, Error> {
+        shard::join(self, shards, group)
+    }
+
+    /// Publish `data` as a job on the work queue `queue`, returning the id of the job.
+    pub fn enqueue(&self, queue: &str, data: impl Into<Vec<u8>>) -> Result<String, Error> {
+        queue::enqueue(self, queue, data.into())
+    }
+
+    /// Join the consumer group `group` of the work queue `queue`. Jobs claimed through the
+    /// returned consumer stay hidden from the group's other workers for `visibility_timeout`
+    /// and are redelivered unless acknowledged in time. See the [`queue`](crate::queue) module.
+    pub fn consume(
+        &self,
+        queue: &str,
+        group: &str,
+        visibility_timeout: Duration,
+    ) -> Result<Consumer, Error> {
+        Consumer::new(self, queue, group, visibility_timeout)
+    }
+
+    /// Run `plugin` on the messages of every topic matching `filter` before they are delivered,
+    /// replacing the plugin previously installed as `name`. See the [`plugin`](crate::plugin)
+    /// module.
+    #[cfg(feature = "wasm")]
+    pub fn install_plugin(&self, name: &str, filter: &str, plugin: Plugin) -> Result<(), Error> {
+        self.install_hook(name, filter, Hook::Receive, plugin)
+    }
+
+    /// Run `plugin` on the messages of every topic matching `filter` that `hook` selects: before
+    /// they are sent when this node publishes them, before they are delivered, or both.
+    /// Replaces the plugin previously installed as `name`.
+    #[cfg(feature = "wasm")]
+    pub fn install_hook(
+        &self,
+        name: &str,
+        filter: &str,
+        hook: Hook,
+        plugin: Plugin,
+    ) -> Result<(), Error> {
+        self.send(Command::InstallPlugin {
+            name: name.to_owned(),
+            filter: TopicFilter::new(filter)?,
+            hook,
+            plugin: Arc::new(plugin),
+        })
+    }
+
+    /// Remove the plugin installed as `name`, returning whether there was one.
+    #[cfg(feature = "wasm")]
+    pub async fn remove_plugin(&self, name: &str) -> Result<bool, Error> {
+        let (reply, removed) = oneshot::channel();
+        self.send(Command::RemovePlugin {
+            name: name.to_owned(),
+            reply,
+        })?;
+        removed.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// The plugins installed on the node, in the order they run.
+    #[cfg(feature = "wasm")]
+    pub async fn list_plugins(&self) -> Result<Vec<InstalledPlugin>, Error> {
+        let (reply, plugins) = oneshot::channel();
+        self.send(Command::ListPlugins { reply })?;
+        plugins.await.map_err(|_| Error::Shutdown)
+    }
+
+    pub(crate) fn send(&self, command: Command) -> Result<(), Error> {
+        self.commands
+            .unbounded_send(command)
+            .map_err(|_| Error::Shutdown)
+    }
+
+    /// The checks [`publish`](Client::publish) makes, to run without holding on to the client.
+    pub(crate) fn publish_checks(&self) -> PublishChecks {
+        PublishChecks {
+            size_limits: self.size_limits.clone(),
+            message_types: self.message_types.clone(),
+            observer: self.observer,
+        }
+    }
+}
+
+/// Fail with [`ReadOnly`] to publish on `topic` through an observer.
+fn check_observer(observer: bool, topic: &str) -> Result<(), Error> {
+    match observer {
+        true => Err(PubSubError::publish(topic, ReadOnly)),
+        false => Ok(()),
+    }
+}
+
+/// Size limits and message types of the topics of a node, and whether it is an observer, as
+/// checked on publishing.
+#[derive(Clone)]
+pub(crate) struct PublishChecks {
+    size_limits: Arc<SizeLimits>,
+    message_types: Arc<Vec<(TopicFilter, String)>>,
+    observer: bool,
+}
+
+impl PublishChecks {
+    /// Check `data` to publish on `topic`, returning it as it is to be published.
+    pub fn check(&self, topic: &str, data: Bytes) -> Result<Bytes, Error> {
+        check_observer(self.observer, topic)?;
+        schema::check_type(&self.message_types, topic, &data)?;
+        Ok(self.size_limits.admit(topic, data)?)
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("local_peer_id", &self.local_peer_id)
+            .finish()
+    }
+}
+
+/// Stream of messages received on a topic, returned by [`Client::subscribe`] and
+/// [`Client::subscribe_filter`].
+pub struct Subscription {
+    id: u64,
+    topic: String,
+    receiver: flow::Receiver<Message>,
+}
+
+impl Subscription {
+    /// Id of the subscription in the [`stats`](Client::stats) of the node.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The topic, or topic filter, this subscription receives messages from.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Messages waiting to be taken from the subscription, and those dropped because it fell
+    /// behind.
+    pub fn status(&self) -> QueueStatus {
+        self.receiver.status()
+    }
+}
+
+/// Create the two ends of a subscription to `topic`.
+fn subscriber(topic: &str, bounds: Bounds) -> (Subscriber, Subscription) {
+    let id = NEXT_SUBSCRIPTION.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = flow::channel(bounds.capacity, bounds.overflow);
+    let subscriber = Subscriber {
+        id,
+        topic: topic.to_owned(),
+        sender,
+        pipeline: None,
+        verified_only: false,
+        headers: None,
+    };
+    let subscription = Subscription {
+        id,
+        topic: topic.to_owned(),
+        receiver,
+    };
+    (subscriber, subscription)
+}
+
+impl Stream for Subscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Message>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// Change in the peers supporting a protocol, reported by [`ProtocolWatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProtocolEvent {
+    /// A connected peer supports the protocol.
+    Available(PeerId),
+    /// A peer that supported the protocol disconnected or dropped it.
+    Unavailable(PeerId),
+}
+
+/// Stream of the changes in the peers supporting a protocol, returned by
+/// [`Client::watch_protocol`].
+pub struct ProtocolWatch {
+    receiver: mpsc::UnboundedReceiver<ProtocolEvent>,
+}
+
+impl Stream for ProtocolWatch {
+    type Item = ProtocolEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ProtocolEvent>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// Change in the membership of a topic, reported by [`Changes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// Peers joined or left the mesh this node forwards the messages of `topic` through.
+    TopicMeshChanged {
+        topic: String,
+        added: Vec<PeerId>,
+        removed: Vec<PeerId>,
+    },
+    /// `peer`, this node or a connected peer, subscribed to `topic` or unsubscribed from it. A
+    /// peer that disconnects is unsubscribed from all its topics.
+    SubscriptionChanged {
+        peer: PeerId,
+        topic: String,
+        subscribed: bool,
+    },
+}
+
+/// Stream of the changes in the membership of topics, returned by [`Client::changes`].
+pub struct Changes {
+    receiver: mpsc::UnboundedReceiver<ChangeEvent>,
+}
+
+impl Stream for Changes {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ChangeEvent>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// Something that happened on a node, reported by [`Events`].
+#[derive(Clone, Debug)]
+pub enum NodeEvent {
+    /// A connection to `peer` opened, the first one to it.
+    PeerConnected(PeerId),
+    /// The last connection to `peer` closed.
+    PeerDisconnected(PeerId),
+    /// `peer`, this node or a connected peer, subscribed to `topic`.
+    Subscribed { peer: PeerId, topic: String },
+    /// `peer` unsubscribed from `topic`, or disconnected while subscribed to it.
+    Unsubscribed { peer: PeerId, topic: String },
+    /// A message came from the network on a topic of this node, with its envelopes taken off,
+    /// before plugins and pipelines run on it.
+    MessageReceived(Message),
+    /// Messages numbered `missing` that `peer` published on `topic` never came, see the
+    /// [`sequence`](crate::sequence) module. They may still come late.
+    GapDetected {
+        peer: PeerId,
+        topic: String,
+        missing: Range<u64>,
+    },
+    /// The node listens on a new address.
+    ListenAddr(Multiaddr),
+    /// Something failed that the node carries on from, such as reaching an address it dialed or
+    /// encoding a message it was asked to publish.
+    Error(Arc<PubSubError>),
+}
+
+/// Stream of the events of a node, returned by [`Client::events`].
+pub struct Events {
+    receiver: flow::Receiver<NodeEvent>,
+}
+
+impl Stream for Events {
+    type Item = NodeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<NodeEvent>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// Alert about a bridge or sink, reported by [`BridgeAlerts`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BridgeAlert {
+    /// `bridge` has been disconnected for `since`, longer than its alert threshold.
+    Disconnected { bridge: String, since: Duration },
+    /// `bridge` reconnected after being alerted on.
+    Reconnected { bridge: String },
+}
+
+/// Stream of alerts about bridges and sinks, returned by [`Client::bridge_alerts`].
+pub struct BridgeAlerts {
+    receiver: mpsc::UnboundedReceiver<BridgeAlert>,
+}
+
+impl Stream for BridgeAlerts {
+    type Item = BridgeAlert;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<BridgeAlert>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,177 @@
+//! Clock skew between peers, and timestamps of messages corrected for it.
+//!
+//! Comparing the time a message was published, by the clock of its publisher, with the time it
+//! arrives, by the clock of the receiver, says more about how far apart the two clocks are than
+//! about the latency of the mesh. With [`NodeConfig::clock`](crate::NodeConfig::clock) set, a
+//! node estimates how far ahead of its own clock the clock of each of its peers is: once a peer
+//! is identified, and every [`interval`](ClockConfig::interval) after that, each of them sends
+//! the other the time it reads on [`CLOCK_TOPIC`], the reading having taken half a round trip to
+//! arrive, as the pings of the peer measure it. The skew of a peer is the median of its last
+//! [`SAMPLES`] readings, minus the time of their arrival, plus half the median round-trip time
+//! of the peer. The skew of every peer is reported by [`Client::stats`](crate::Client::stats) as
+//! [`PeerStats::clock_skew`](crate::stats::PeerStats::clock_skew) and listed by the admin
+//! endpoint.
+//!
+//! The messages a node publishes on the topics matching its
+//! [`timestamped`](ClockConfig::timestamped) filters carry the time they were published at, in
+//! an envelope: a marker, then the big-endian `u64` number of microseconds since the Unix epoch.
+//! Receiving nodes take the envelope off whatever their own configuration, and deliver the time
+//! as [`Message::published_at`](crate::Message::published_at), corrected by the skew of the
+//! publisher if it is a peer they estimated it for, so that it reads on their own clock. The
+//! envelope goes inside batches and sequence numbers, so that the time is that of the message
+//! itself, before any pacing.
+
+use crate::{liveness::PeerLiveness, topic::TopicFilter, Error};
+use bytes::Bytes;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Well-known topic on which peers send each other the time they read.
+pub const CLOCK_TOPIC: &str = "pubsub-lite/clock";
+
+/// Readings of a peer the skew of its clock is estimated from.
+pub const SAMPLES: usize = 8;
+
+/// Start of a timestamped message. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plt";
+
+const HEADER_LEN: usize = MARKER.len() + 8;
+
+/// Estimation of the clock skew of peers, and timestamps of messages, see the
+/// [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct ClockConfig {
+    /// Time between the readings sent to every peer.
+    pub interval: Duration,
+    /// Filters of the topics whose messages carry the time they were published at.
+    pub timestamped: Vec<String>,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig {
+            interval: Duration::from_secs(30),
+            timestamped: Vec::new(),
+        }
+    }
+}
+
+/// Microseconds since the Unix epoch, by the clock of this node.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as i64)
+}
+
+/// Take the envelope off a timestamped payload, returning the time it was published at in
+/// microseconds since the Unix epoch, or the payload as is otherwise.
+pub(crate) fn strip(data: Bytes) -> (Option<u64>, Bytes) {
+    if data.len() < HEADER_LEN || !data.starts_with(MARKER) {
+        return (None, data);
+    }
+    let micros = u64::from_be_bytes(data[MARKER.len()..HEADER_LEN].try_into().unwrap());
+    (Some(micros), data.slice(HEADER_LEN..))
+}
+
+/// The readings of the clocks of the peers of a node, and its timestamped topics.
+pub(crate) struct Clock {
+    interval: Duration,
+    timestamped: Vec<TopicFilter>,
+    /// The last readings of every peer, minus the time they arrived at, in microseconds.
+    readings: HashMap<PeerId, VecDeque<i64>>,
+    /// When readings were last sent to every peer.
+    last_sent: Instant,
+}
+
+impl Clock {
+    pub fn new(config: &ClockConfig) -> Result<Self, Error> {
+        if config.interval == Duration::from_secs(0) {
+            return Err("clock reading interval must be positive".into());
+        }
+        Ok(Clock {
+            interval: config.interval,
+            timestamped: config
+                .timestamped
+                .iter()
+                .map(|filter| TopicFilter::new(filter))
+                .collect::<Result<_, _>>()?,
+            readings: HashMap::new(),
+            last_sent: Instant::now(),
+        })
+    }
+
+    /// The time this node reads, to send to a peer.
+    pub fn reading(&self) -> Bytes {
+        Bytes::copy_from_slice(&now().to_be_bytes())
+    }
+
+    /// Whether readings are due to every peer, counting them as sent if so.
+    pub fn due(&mut self) -> bool {
+        if self.last_sent.elapsed() < self.interval {
+            return false;
+        }
+        self.last_sent = Instant::now();
+        true
+    }
+
+    /// Record a reading received from `peer`, directly rather than relayed.
+    pub fn accept(&mut self, peer: &PeerId, data: &[u8]) -> Result<(), Error> {
+        let reading = i64::from_be_bytes(
+            data.try_into()
+                .map_err(|_| "clock reading is not 8 bytes long")?,
+        );
+        let readings = self.readings.entry(peer.clone()).or_default();
+        if readings.len() == SAMPLES {
+            readings.pop_front();
+        }
+        readings.push_back(reading - now());
+        Ok(())
+    }
+
+    /// Forget the readings of a peer that disconnected.
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.readings.remove(peer);
+    }
+
+    /// How far ahead of the clock of this node that of `peer` is, in microseconds, negative if
+    /// behind, once it sent readings and answered pings.
+    pub fn skew(&self, peer: &PeerId, liveness: Option<&PeerLiveness>) -> Option<i64> {
+        let mut readings: Vec<i64> = self.readings.get(peer)?.iter().copied().collect();
+        readings.sort_unstable();
+        let reading = readings[(readings.len() - 1) / 2];
+        let rtt = liveness?.median()?;
+        Some(reading + rtt.as_micros() as i64 / 2)
+    }
+
+    /// Timestamp a payload published on `topic`, if the topic is timestamped.
+    pub fn stamp(&self, topic: &str, data: Bytes) -> Bytes {
+        if !self.is_timestamped(topic) {
+            return data;
+        }
+        let mut envelope = Vec::with_capacity(HEADER_LEN + data.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&(now() as u64).to_be_bytes());
+        envelope.extend_from_slice(&data);
+        envelope.into()
+    }
+
+    /// Whether the messages published on `topic` are timestamped.
+    pub fn is_timestamped(&self, topic: &str) -> bool {
+        self.timestamped.iter().any(|filter| filter.matches(topic))
+    }
+}
+
+/// The time a message stamped with `micros` by its publisher was published at, by the clock of
+/// this node if the `skew` of the publisher is known.
+pub(crate) fn published_at(micros: u64, skew: Option<i64>) -> SystemTime {
+    let micros = micros as i64 - skew.unwrap_or(0);
+    if micros < 0 {
+        UNIX_EPOCH
+    } else {
+        UNIX_EPOCH + Duration::from_micros(micros as u64)
+    }
+}
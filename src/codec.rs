@@ -0,0 +1,275 @@
+//! The envelopes of payloads, as published on the wire, and how to take them off.
+//!
+//! A payload published on a topic goes through the envelopes the policies of the topic call for,
+//! in this order: [compressed](crate::compression), [signed](crate::delegation),
+//! [sealed](crate::crypto), then split into chunks, batches of [paced](crate::pacing) payloads
+//! having been made before, of payloads [numbered](crate::sequence) before that, themselves
+//! [timestamped](crate::clock) then [authorized](crate::acl) first. Every envelope starts with a
+//! marker of its own, a NUL byte then `pl` and a letter, so that a receiver takes off whatever
+//! envelopes a payload comes in, reassembling, decrypting, verifying, decompressing and
+//! unbatching it, whatever its own policies. The node takes the numbers, tokens and timestamps
+//! off last, tracking the numbers and checking the tokens.
+//!
+//! [`encode`] and [`decode`] work on the bytes of payloads and the policies alone, with no
+//! swarm in sight. Whatever bytes remote peers send, `decode` fails or returns payloads without
+//! panicking; the property tests of this module check it on arbitrary and mangled envelopes.
+
+use crate::{
+    chunking::{self, Reassembler},
+    compression::{self, CompressionPolicy},
+    crypto::{self, TopicKey},
+    delegation::{self, Delegation, Origin, PublicKey},
+    pacing,
+    topic::TopicFilter,
+    Error, PubSubError,
+};
+use bytes::Bytes;
+use libp2p::{gossipsub::TopicHash, PeerId};
+
+/// What a received payload carries.
+pub(crate) struct Decoded {
+    pub origin: Option<Origin>,
+    /// The payloads, sharing the buffer of the received one where no envelope had to be undone
+    /// into a new one.
+    pub payloads: Vec<Bytes>,
+}
+
+/// Compress, sign, encrypt and split a payload to publish on `topic` as the policies of the
+/// topic require, returning the payloads of the messages to send. The payload is split into
+/// chunks of `chunk_size`, if given, identified by `chunked_id`.
+pub(crate) fn encode(
+    compression: &[(TopicFilter, CompressionPolicy)],
+    delegation: &[(TopicFilter, Delegation)],
+    encryption: &[(TopicFilter, TopicKey)],
+    chunk_size: Option<usize>,
+    chunked_id: u64,
+    topic: &str,
+    data: Bytes,
+) -> Result<Vec<Bytes>, Error> {
+    let data = match compression.iter().find(|(f, _)| f.matches(topic)) {
+        Some((_, policy)) => policy.compress(data),
+        None => data,
+    };
+    let data = delegation::sign(delegation, topic, data)?;
+    let data = match encryption.iter().find(|(f, _)| f.matches(topic)) {
+        Some((_, key)) => key.seal(topic, &data)?,
+        None => data,
+    };
+    Ok(match chunk_size {
+        Some(chunk_size) => chunking::split(chunked_id, data, chunk_size),
+        None => vec![data],
+    })
+}
+
+/// Take the envelopes off a payload received from `source` on `topics`, reassembling,
+/// decrypting, verifying, decompressing and unbatching it with the given keys. Returns `None`
+/// while a chunked message is incomplete.
+pub(crate) fn decode(
+    reassembler: &mut Reassembler,
+    encryption: &[(TopicFilter, TopicKey)],
+    trusted_orgs: &[(TopicFilter, PublicKey)],
+    source: &PeerId,
+    topics: &[TopicHash],
+    data: Bytes,
+) -> Result<Option<Decoded>, Error> {
+    match reassembler.accept(source, data)? {
+        Some(data) => open(encryption, trusted_orgs, topics, data).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Take the envelopes off a whole payload received on `topics`, decrypting, verifying,
+/// decompressing and unbatching it with the given keys.
+pub(crate) fn open(
+    encryption: &[(TopicFilter, TopicKey)],
+    trusted_orgs: &[(TopicFilter, PublicKey)],
+    topics: &[TopicHash],
+    data: Bytes,
+) -> Result<Decoded, Error> {
+    let key = topics.iter().find_map(|topic| {
+        encryption
+            .iter()
+            .find(|(filter, _)| filter.matches(topic.as_str()))
+            .map(|(_, key)| (topic, key))
+    });
+    let data = match key {
+        Some((topic, key)) => key.open(topic.as_str(), &data)?,
+        None if crypto::is_sealed(&data) => {
+            return Err(PubSubError::codec("no key to decrypt the message"))
+        }
+        None => data,
+    };
+    let topic = topics.first().map_or("", |topic| topic.as_str());
+    let (origin, data) = delegation::verify(trusted_orgs, topic, data)?;
+    let data = compression::decompress(data)?;
+    let payloads = pacing::unbatch(data)?;
+    Ok(Decoded { origin, payloads })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compression::Codec, delegation::SigningKey};
+    use quickcheck::{quickcheck, TestResult};
+    use std::time::{Duration, SystemTime};
+
+    const TOPIC: &str = "fuzz";
+
+    /// Markers of every envelope, to steer arbitrary bytes past the first check of each layer.
+    const MARKERS: &[&[u8]] = &[b"\0plc", b"\0ple", b"\0pls", b"\0plz", b"\0plb"];
+
+    /// Policies putting every payload on the topic in every envelope.
+    struct Policies {
+        compression: Vec<(TopicFilter, CompressionPolicy)>,
+        delegation: Vec<(TopicFilter, Delegation)>,
+        encryption: Vec<(TopicFilter, TopicKey)>,
+        trusted_orgs: Vec<(TopicFilter, PublicKey)>,
+        chunking: Vec<(TopicFilter, usize)>,
+    }
+
+    impl Policies {
+        fn new(codec: Codec, chunk_size: usize) -> Self {
+            let filter = TopicFilter::new(TOPIC).unwrap();
+            let org = SigningKey::generate().unwrap();
+            let node = SigningKey::generate().unwrap();
+            let certificate = org.certify(
+                &node.public_key(),
+                SystemTime::now() + Duration::from_secs(3600),
+            );
+            Policies {
+                compression: vec![(
+                    filter.clone(),
+                    CompressionPolicy {
+                        codec,
+                        threshold: 0,
+                    },
+                )],
+                delegation: vec![(
+                    filter.clone(),
+                    Delegation::new(node, vec![certificate]).unwrap(),
+                )],
+                encryption: vec![(filter.clone(), TopicKey::new([7; 32]))],
+                trusted_orgs: vec![(filter.clone(), org.public_key())],
+                chunking: vec![(filter, chunk_size)],
+            }
+        }
+
+        fn encode(&self, chunked_id: u64, data: Vec<u8>) -> Vec<Vec<u8>> {
+            encode(
+                &self.compression,
+                &self.delegation,
+                &self.encryption,
+                self.chunking
+                    .iter()
+                    .find(|(f, _)| f.matches(TOPIC))
+                    .map(|(_, chunk_size)| *chunk_size),
+                chunked_id,
+                TOPIC,
+                data.into(),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|frame| frame.to_vec())
+            .collect()
+        }
+
+        /// Decode `frames` in order, returning the payloads of the last one.
+        fn decode(&self, frames: Vec<Vec<u8>>) -> Result<Option<Decoded>, Error> {
+            let mut reassembler = Reassembler::new(Duration::from_secs(60));
+            let source = PeerId::random();
+            let topics = [TopicHash::from_raw(TOPIC)];
+            let mut decoded = Ok(None);
+            for frame in frames {
+                decoded = decode(
+                    &mut reassembler,
+                    &self.encryption,
+                    &self.trusted_orgs,
+                    &source,
+                    &topics,
+                    frame.into(),
+                );
+            }
+            decoded
+        }
+    }
+
+    /// Decode `data` as a node with no policies and as one with every policy would.
+    fn decode_anyhow(data: Vec<u8>) {
+        let policies = Policies::new(Codec::Zstd, 64);
+        let _ = policies.decode(vec![data.clone()]);
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let topics = [TopicHash::from_raw(TOPIC)];
+        let _ = decode(
+            &mut reassembler,
+            &[],
+            &[],
+            &PeerId::random(),
+            &topics,
+            data.into(),
+        );
+    }
+
+    quickcheck! {
+        fn arbitrary_bytes_do_not_panic(data: Vec<u8>) -> bool {
+            decode_anyhow(data);
+            true
+        }
+
+        fn arbitrary_envelopes_do_not_panic(marker: usize, body: Vec<u8>) -> bool {
+            let mut data = MARKERS[marker % MARKERS.len()].to_vec();
+            data.extend_from_slice(&body);
+            decode_anyhow(data);
+            true
+        }
+
+        fn nested_envelopes_do_not_panic(markers: Vec<usize>, body: Vec<u8>) -> bool {
+            let mut data = Vec::new();
+            for marker in markers {
+                data.extend_from_slice(MARKERS[marker % MARKERS.len()]);
+                data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            }
+            data.extend_from_slice(&body);
+            decode_anyhow(data);
+            true
+        }
+
+        fn payloads_round_trip(data: Vec<u8>, lz4: bool, chunk_size: usize) -> TestResult {
+            let codec = if lz4 { Codec::Lz4 } else { Codec::Zstd };
+            let policies = Policies::new(codec, chunking::HEADER_LEN + 1 + chunk_size % 512);
+            let frames = policies.encode(1, data.clone());
+            match policies.decode(frames) {
+                Ok(Some(decoded)) => TestResult::from_bool(
+                    decoded.payloads == [data] && decoded.origin.is_some(),
+                ),
+                Ok(None) => TestResult::error("chunks left incomplete"),
+                Err(e) => TestResult::error(e.to_string()),
+            }
+        }
+
+        fn mangled_envelopes_do_not_panic(
+            data: Vec<u8>,
+            flips: Vec<(usize, u8)>,
+            cut: usize,
+            reorder: bool
+        ) -> bool {
+            let policies = Policies::new(Codec::Lz4, 96);
+            let mut frames = policies.encode(2, data);
+            let count = frames.len();
+            for (at, bits) in flips {
+                let frame = &mut frames[at % count];
+                let len = frame.len();
+                if len > 0 {
+                    frame[(at / count) % len] ^= bits;
+                }
+            }
+            let last = frames.len() - 1;
+            let len = frames[last].len();
+            frames[last].truncate(len - cut % (len + 1));
+            if reorder {
+                frames.reverse();
+            }
+            let _ = policies.decode(frames);
+            true
+        }
+    }
+}
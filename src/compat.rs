@@ -0,0 +1,314 @@
+//! Golden wire-format vectors, and checks that a build still reads them.
+//!
+//! The crate ships, in its `vectors` directory, what nodes of earlier releases sent and stored:
+//!
+//! - envelopes, the frames of messages as published on the wire, plain, compressed, sealed,
+//!   signed, batched, chunked and all of these at once, with the payloads they carry and the
+//!   keys needed to open them;
+//! - control messages, the announcements nodes exchange on the well-known topics of topic
+//!   discovery, presence, queues, locks, AutoNAT and key rotation;
+//! - documents, the [manifests](crate::manifest), [desired states](crate::reconcile) and
+//!   [pipelines](crate::pipeline) operators keep under version control.
+//!
+//! Every generation of formats is a file of its own, `v1.json` being the first; a release
+//! changing a format adds the next generation and keeps checking the previous ones. A vector
+//! passes when its envelopes decode to its payloads, its control message decodes and encodes
+//! again to the same JSON, so that no field was renamed or dropped, and its document is
+//! accepted.
+//!
+//! [`check`] runs the vectors through the codecs of this build, as a test suite would, and
+//! [`Client::check_compat`](crate::Client::check_compat) through the receive path of a running
+//! node, e.g. to vet the binary deployed on it.
+
+use crate::{
+    autonat,
+    chunking::Reassembler,
+    codec::{self, Decoded},
+    crypto::TopicKey,
+    delegation::PublicKey,
+    lock,
+    manifest::Manifest,
+    pipeline::Pipeline,
+    presence, queue,
+    reconcile::DesiredState,
+    rotation::ContinuityRecord,
+    topic::TopicFilter,
+    Error, PubSubError,
+};
+use bytes::Bytes;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use libp2p::{gossipsub::TopicHash, PeerId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{convert::TryFrom, fmt, time::Duration};
+
+/// The generations of vectors, oldest first.
+const GENERATIONS: &[(&str, &str)] = &[("v1", include_str!("../vectors/v1.json"))];
+
+/// Outcome of checking the vectors, by vector name, e.g. `v1/envelope/chunked`.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub passed: Vec<String>,
+    /// The vectors that failed, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    fn record(&mut self, name: String, result: Result<(), Error>) {
+        match result {
+            Ok(()) => self.passed.push(name),
+            Err(e) => self.failed.push((name, e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} vectors passed, {} failed",
+            self.passed.len(),
+            self.failed.len()
+        )?;
+        for (name, reason) in &self.failed {
+            write!(f, "\n{}: {}", name, reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vectors {
+    envelopes: Vec<Envelope>,
+    control: Vec<Control>,
+    documents: Vec<Document>,
+}
+
+/// Frames making up one message, hex encoded.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope {
+    name: String,
+    topic: String,
+    /// Key of the topic, hex encoded, if the message is sealed.
+    key: Option<String>,
+    /// Organisation to verify the message against, if signed.
+    org: Option<PublicKey>,
+    frames: Vec<String>,
+    payloads: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Control {
+    name: String,
+    kind: ControlKind,
+    message: Value,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ControlKind {
+    Topics,
+    Presence,
+    Queue,
+    Lock,
+    Autonat,
+    Continuity,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    name: String,
+    kind: DocumentKind,
+    document: Value,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DocumentKind {
+    Manifest,
+    DesiredState,
+    Pipeline,
+}
+
+/// Check every vector with the codecs of this build.
+pub fn check() -> Report {
+    let mut reassembler = Reassembler::new(Duration::from_secs(60));
+    check_with(|encryption, trusted_orgs, source, topic, frame| {
+        codec::decode(
+            &mut reassembler,
+            encryption,
+            trusted_orgs,
+            source,
+            &[topic],
+            frame,
+        )
+    })
+}
+
+/// Check every vector, taking the envelopes off frames with `decode`, given the keys of the
+/// vector, the source of its frames and their topic.
+pub(crate) fn check_with(
+    mut decode: impl FnMut(
+        &[(TopicFilter, TopicKey)],
+        &[(TopicFilter, PublicKey)],
+        &PeerId,
+        TopicHash,
+        Bytes,
+    ) -> Result<Option<Decoded>, Error>,
+) -> Report {
+    let mut report = Report::default();
+    for (generation, text) in GENERATIONS {
+        let vectors: Vectors = match serde_json::from_str(text) {
+            Ok(vectors) => vectors,
+            Err(e) => {
+                report.record(generation.to_string(), Err(e.into()));
+                continue;
+            }
+        };
+        for vector in &vectors.envelopes {
+            let result = check_envelope(vector, &mut decode);
+            report.record(format!("{}/envelope/{}", generation, vector.name), result);
+        }
+        for vector in &vectors.control {
+            let result = check_control(vector);
+            report.record(format!("{}/control/{}", generation, vector.name), result);
+        }
+        for vector in &vectors.documents {
+            let result = check_document(vector);
+            report.record(format!("{}/document/{}", generation, vector.name), result);
+        }
+    }
+    report
+}
+
+fn check_envelope(
+    vector: &Envelope,
+    decode: &mut impl FnMut(
+        &[(TopicFilter, TopicKey)],
+        &[(TopicFilter, PublicKey)],
+        &PeerId,
+        TopicHash,
+        Bytes,
+    ) -> Result<Option<Decoded>, Error>,
+) -> Result<(), Error> {
+    let hex = |text: &String| HEXLOWER_PERMISSIVE.decode(text.as_bytes());
+    let filter = TopicFilter::new(&vector.topic)?;
+    let encryption = match &vector.key {
+        Some(key) => {
+            let key = <[u8; 32]>::try_from(&hex(key)?[..]).map_err(PubSubError::codec)?;
+            vec![(filter.clone(), TopicKey::new(key))]
+        }
+        None => Vec::new(),
+    };
+    let trusted_orgs: Vec<_> = vector
+        .org
+        .iter()
+        .map(|org| (filter.clone(), *org))
+        .collect();
+    // A source of its own keeps the chunks of the vector apart from any others.
+    let source = PeerId::random();
+    let mut decoded = None;
+    for (i, frame) in vector.frames.iter().enumerate() {
+        if decoded.is_some() {
+            return Err(format!("delivered before frame {}", i).into());
+        }
+        let topic = TopicHash::from_raw(vector.topic.clone());
+        decoded = decode(
+            &encryption,
+            &trusted_orgs,
+            &source,
+            topic,
+            hex(frame)?.into(),
+        )?;
+    }
+    let decoded = decoded.ok_or("the frames did not make up a message")?;
+    match (&decoded.origin, &vector.org) {
+        (Some(origin), Some(org)) if origin.org != *org => {
+            return Err(format!("signed by {} instead of {}", origin.org, org).into());
+        }
+        (None, Some(_)) => return Err("signature not checked".into()),
+        _ => {}
+    }
+    let payloads = vector
+        .payloads
+        .iter()
+        .map(hex)
+        .collect::<Result<Vec<_>, _>>()?;
+    if decoded.payloads.len() != payloads.len() {
+        return Err(format!(
+            "decoded {} payloads, not the {} expected",
+            decoded.payloads.len(),
+            payloads.len()
+        )
+        .into());
+    }
+    match decoded
+        .payloads
+        .iter()
+        .zip(&payloads)
+        .position(|(a, b)| a != b)
+    {
+        Some(i) => Err(format!("payload {} differs", i).into()),
+        None => Ok(()),
+    }
+}
+
+fn check_control(vector: &Control) -> Result<(), Error> {
+    let encoded = match vector.kind {
+        ControlKind::Topics => roundtrip::<Vec<String>>(&vector.message)?,
+        ControlKind::Presence => roundtrip::<presence::Announcement>(&vector.message)?,
+        ControlKind::Queue => roundtrip::<queue::Announcement>(&vector.message)?,
+        ControlKind::Lock => roundtrip::<lock::Announcement>(&vector.message)?,
+        ControlKind::Autonat => roundtrip::<autonat::Probe>(&vector.message)?,
+        ControlKind::Continuity => {
+            let record = ContinuityRecord::decode(&serde_json::to_vec(&vector.message)?)?;
+            serde_json::from_slice(&record.encode()?)?
+        }
+    };
+    if encoded != vector.message {
+        return Err(format!("encodes again as {}", encoded).into());
+    }
+    Ok(())
+}
+
+/// Decode `message` as a `T` and encode it again.
+fn roundtrip<T: Serialize + DeserializeOwned>(message: &Value) -> Result<Value, Error> {
+    let decoded: T = serde_json::from_value(message.clone())?;
+    Ok(serde_json::to_value(&decoded)?)
+}
+
+fn check_document(vector: &Document) -> Result<(), Error> {
+    let text = vector.document.to_string();
+    match vector.kind {
+        DocumentKind::Manifest => {
+            Manifest::from_json(&text)?;
+        }
+        DocumentKind::DesiredState => {
+            serde_json::from_str::<DesiredState>(&text)?;
+        }
+        DocumentKind::Pipeline => {
+            Pipeline::from_json(&text)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_pass() {
+        let report = check();
+        assert!(report.is_ok(), "{}", report);
+        assert!(!report.passed.is_empty());
+    }
+}
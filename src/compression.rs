@@ -0,0 +1,184 @@
+//! Transparent compression of large payloads.
+//!
+//! Messages published on a topic with a [`CompressionPolicy`] are compressed once their payload
+//! reaches the policy's threshold, so that large JSON documents shrink below gossipsub's
+//! `max_transmit_size` instead of failing to publish. A compressed payload travels in an
+//! envelope: a marker, one byte naming the [`Codec`], then the compressed data. Nodes unwrap the
+//! envelope before delivering the message, whatever their own policies, so subscribers always
+//! see the original payload. Payloads that do not shrink are sent as they are.
+
+use crate::{Error, PubSubError};
+use bytes::Bytes;
+use std::io::Read;
+
+/// Start of a compressed payload. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plz";
+
+/// Upper bound on the size of a decompressed payload.
+const MAX_DECOMPRESSED: usize = 16 * 1024 * 1024;
+
+/// Compression algorithms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Zstandard, compressing best.
+    Zstd,
+    /// LZ4, compressing fastest.
+    Lz4,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// When and how to compress the messages published on a topic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    pub codec: Codec,
+    /// Payloads of at least this many bytes are compressed.
+    pub threshold: usize,
+}
+
+impl CompressionPolicy {
+    /// Compress `data` into an envelope if it is large enough and gets smaller.
+    pub(crate) fn compress(&self, data: Bytes) -> Bytes {
+        if data.len() < self.threshold {
+            return data;
+        }
+        let compressed = match self.codec {
+            Codec::Zstd => match zstd::bulk::compress(&data, 0) {
+                Ok(compressed) => compressed,
+                Err(e) => {
+                    log::warn!("failed to compress a message: {}", e);
+                    return data;
+                }
+            },
+            Codec::Lz4 => lz4_flex::compress_prepend_size(&data),
+        };
+        if MARKER.len() + 1 + compressed.len() >= data.len() {
+            return data;
+        }
+        let mut envelope = Vec::with_capacity(MARKER.len() + 1 + compressed.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.push(self.codec.id());
+        envelope.extend_from_slice(&compressed);
+        envelope.into()
+    }
+}
+
+/// Restore the original payload of a received message, if it was compressed.
+pub(crate) fn decompress(data: Bytes) -> Result<Bytes, Error> {
+    if data.len() <= MARKER.len() || !data.starts_with(MARKER) {
+        return Ok(data);
+    }
+    let compressed = &data[MARKER.len() + 1..];
+    match Codec::from_id(data[MARKER.len()]) {
+        Some(Codec::Zstd) => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(compressed)?
+                .take(MAX_DECOMPRESSED as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() > MAX_DECOMPRESSED {
+                return Err(PubSubError::codec("decompressed payload is too large"));
+            }
+            Ok(decompressed.into())
+        }
+        Some(Codec::Lz4) => {
+            if compressed.len() < 4 {
+                return Err(PubSubError::codec("truncated LZ4 payload"));
+            }
+            let mut size = [0u8; 4];
+            size.copy_from_slice(&compressed[..4]);
+            let size = u32::from_le_bytes(size) as usize;
+            if size > MAX_DECOMPRESSED {
+                return Err(PubSubError::codec("decompressed payload is too large"));
+            }
+            lz4_flex::decompress(&compressed[4..], size)
+                .map(Bytes::from)
+                .map_err(PubSubError::codec)
+        }
+        None => Err(PubSubError::codec(format!(
+            "unknown codec {}",
+            data[MARKER.len()]
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> Bytes {
+        "{\"reading\": 21.5, \"unit\": \"celsius\"}\n"
+            .repeat(100)
+            .into()
+    }
+
+    fn policy(codec: Codec) -> CompressionPolicy {
+        CompressionPolicy {
+            codec,
+            threshold: 64,
+        }
+    }
+
+    #[test]
+    fn compressed_payloads_round_trip() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let envelope = policy(codec).compress(document());
+            assert!(envelope.starts_with(MARKER), "{:?}", codec);
+            assert!(envelope.len() < document().len(), "{:?}", codec);
+            assert_eq!(decompress(envelope).unwrap(), document(), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn small_or_incompressible_payloads_are_sent_as_they_are() {
+        let small = Bytes::from_static(b"{\"reading\": 21.5}");
+        assert_eq!(policy(Codec::Zstd).compress(small.clone()), small);
+        let mut noise = vec![0; 1024];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut noise).unwrap();
+        let noise = Bytes::from(noise);
+        assert_eq!(policy(Codec::Lz4).compress(noise.clone()), noise);
+        assert_eq!(decompress(noise.clone()).unwrap(), noise);
+    }
+
+    #[test]
+    fn rejects_truncated_and_unknown_envelopes() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let envelope = policy(codec).compress(document());
+            let truncated = envelope.slice(..envelope.len() / 2);
+            assert!(decompress(truncated).is_err(), "{:?}", codec);
+        }
+        let lz4 = policy(Codec::Lz4).compress(document());
+        assert!(decompress(lz4.slice(..MARKER.len() + 3)).is_err());
+        let mut unknown = lz4.to_vec();
+        unknown[MARKER.len()] = 9;
+        assert!(decompress(unknown.into()).is_err());
+    }
+
+    #[test]
+    fn rejects_payloads_decompressing_past_the_limit() {
+        let mut envelope = MARKER.to_vec();
+        envelope.push(Codec::Lz4.id());
+        envelope.extend_from_slice(&(MAX_DECOMPRESSED as u32 + 1).to_le_bytes());
+        envelope.extend_from_slice(&[0; 16]);
+        assert!(decompress(envelope.into()).is_err());
+        let zeros = vec![0; MAX_DECOMPRESSED + 1];
+        let mut envelope = MARKER.to_vec();
+        envelope.push(Codec::Zstd.id());
+        envelope.extend_from_slice(&zstd::bulk::compress(&zeros, 0).unwrap());
+        assert!(decompress(envelope.into()).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! End-to-end encryption of the messages of a topic.
+//!
+//! The swarm key and the transport encryption protect messages between two peers, but every peer
+//! of the swarm reads every payload it relays. Topics with a [`TopicKey`] are readable only by the
+//! nodes holding the key: payloads are encrypted with ChaCha20-Poly1305 before they are published
+//! and decrypted before they are delivered. A sealed payload travels in an envelope: a marker, a
+//! random nonce, then the ciphertext and its authentication tag. The topic name is authenticated
+//! along with the payload, so a sealed message replayed on another topic is rejected.
+//!
+//! A node holding the key of a topic drops the messages on that topic that are not sealed with
+//! it, and a node without the key drops sealed messages it cannot open.
+
+use crate::Error;
+use bytes::Bytes;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{fmt, num::NonZeroU32};
+
+/// Start of a sealed payload. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0ple";
+
+/// PBKDF2 iterations turning a passphrase into a key.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Symmetric key encrypting the messages of a topic.
+#[derive(Clone)]
+pub struct TopicKey([u8; 32]);
+
+impl TopicKey {
+    /// Use `key` as it is. Nodes sharing a topic must all use the same key.
+    pub fn new(key: [u8; 32]) -> Self {
+        TopicKey(key)
+    }
+
+    /// Derive a key from `passphrase` with PBKDF2-HMAC-SHA256, salted with `topic` so that one
+    /// passphrase yields a different key for every topic, or topic filter, it protects.
+    pub fn from_passphrase(passphrase: &str, topic: &str) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            format!("pubsub-lite/{}", topic).as_bytes(),
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        TopicKey(key)
+    }
+
+    fn aead_key(&self) -> LessSafeKey {
+        LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &self.0).expect("key has the right length"),
+        )
+    }
+
+    /// Encrypt `data` published on `topic` into an envelope, in the buffer of the envelope.
+    pub(crate) fn seal(&self, topic: &str, data: &[u8]) -> Result<Bytes, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| "failed to generate a nonce")?;
+        let header_len = MARKER.len() + NONCE_LEN;
+        let mut envelope =
+            Vec::with_capacity(header_len + data.len() + CHACHA20_POLY1305.tag_len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&nonce);
+        envelope.extend_from_slice(data);
+        let tag = self
+            .aead_key()
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(topic.as_bytes()),
+                &mut envelope[header_len..],
+            )
+            .map_err(|_| "failed to encrypt a message")?;
+        envelope.extend_from_slice(tag.as_ref());
+        Ok(envelope.into())
+    }
+
+    /// Decrypt the envelope `data` received on `topic`.
+    pub(crate) fn open(&self, topic: &str, data: &[u8]) -> Result<Bytes, Error> {
+        if !is_sealed(data) {
+            return Err("message is not encrypted".into());
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&data[MARKER.len()..MARKER.len() + NONCE_LEN]);
+        let mut in_out = data[MARKER.len() + NONCE_LEN..].to_vec();
+        let len = self
+            .aead_key()
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(topic.as_bytes()),
+                &mut in_out,
+            )
+            .map_err(|_| "failed to decrypt a message")?
+            .len();
+        in_out.truncate(len);
+        Ok(in_out.into())
+    }
+}
+
+impl fmt::Debug for TopicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TopicKey(..)")
+    }
+}
+
+/// Whether `data` is a sealed envelope.
+pub(crate) fn is_sealed(data: &[u8]) -> bool {
+    data.len() >= MARKER.len() + NONCE_LEN + CHACHA20_POLY1305.tag_len() && data.starts_with(MARKER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_payload_round_trips() {
+        let key = TopicKey::new([7; 32]);
+        let envelope = key.seal("secrets", b"launch codes").unwrap();
+        assert!(is_sealed(&envelope));
+        assert_ne!(&envelope[MARKER.len() + NONCE_LEN..], b"launch codes");
+        assert_eq!(key.open("secrets", &envelope).unwrap(), "launch codes");
+        let empty = key.seal("secrets", b"").unwrap();
+        assert_eq!(key.open("secrets", &empty).unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_tampered_truncated_and_misdirected_envelopes() {
+        let key = TopicKey::new([7; 32]);
+        let envelope = key.seal("secrets", b"launch codes").unwrap();
+        for i in [MARKER.len(), MARKER.len() + NONCE_LEN, envelope.len() - 1] {
+            let mut tampered = envelope.to_vec();
+            tampered[i] ^= 1;
+            assert!(key.open("secrets", &tampered).is_err(), "byte {}", i);
+        }
+        assert!(key
+            .open("secrets", &envelope[..envelope.len() - 1])
+            .is_err());
+        assert!(key
+            .open("secrets", &envelope[..MARKER.len() + NONCE_LEN])
+            .is_err());
+        assert!(key.open("other", &envelope).is_err());
+        assert!(TopicKey::new([8; 32]).open("secrets", &envelope).is_err());
+        assert!(key.open("secrets", b"launch codes").is_err());
+    }
+
+    #[test]
+    fn passphrase_keys_differ_per_topic() {
+        let alerts = TopicKey::from_passphrase("correct horse", "alerts");
+        let envelope = alerts.seal("alerts", b"fire").unwrap();
+        let again = TopicKey::from_passphrase("correct horse", "alerts");
+        assert_eq!(again.open("alerts", &envelope).unwrap(), "fire");
+        let chat = TopicKey::from_passphrase("correct horse", "chat");
+        assert!(chat.open("alerts", &envelope).is_err());
+    }
+}
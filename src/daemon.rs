@@ -0,0 +1,483 @@
+//! Running a node in the background without a supervisor, for small deployments.
+//!
+//! [`spawn_detached`] starts the current executable again, detached from the terminal: in a new
+//! session on Unix, without a console on Windows, with nothing on its standard input and its
+//! output discarded. The detached process takes a [`Pidfile`] holding its process id, which
+//! [`status`] reads to tell whether the node runs and [`stop`] to terminate it, and logs to a
+//! [`RotatingLog`] instead of the terminal:
+//!
+//! ```text
+//! pub --daemonize           # IPFS_PATH/pub.pid, IPFS_PATH/logs/pub.log
+//! pub status
+//! pub stop
+//! ```
+//!
+//! The pidfile holds the executable of the process next to its id. A pidfile left behind by a
+//! process that died is taken over, and a process is only stopped if it is still the one that
+//! wrote the pidfile: on Linux, its id must name a process running that executable, which a
+//! process reusing the id after a crash does not; elsewhere, the id alone tells. Stopping sends
+//! `SIGTERM` on Unix and runs `taskkill` on Windows; the node has nothing to flush, and stops at
+//! once.
+//!
+//! The log file is renamed after the UTC day it was started on when it grows past
+//! [`LogConfig::max_bytes`] or the day changes, as `pub.log.2026-10-15`, `pub.log.2026-10-15.1`
+//! and so on, the oldest being removed past [`LogConfig::keep`] files.
+//!
+//! On Windows, the node also runs as a service, started and stopped by the service control
+//! manager, see the [`service`] module.
+
+use crate::Error;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(windows)]
+pub mod service;
+
+/// Variable set in the environment of the detached process, telling it to take the pidfile and
+/// log to a file.
+pub const DAEMON_ENV: &str = "PUBSUB_DAEMON";
+
+/// Argument telling the process it is started by the service control manager of Windows.
+pub const SERVICE_ARG: &str = "--service";
+
+/// How long [`stop`] waits for the process to exit.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Start the current executable with `args` in the background, returning its process id.
+pub fn spawn_detached(args: impl IntoIterator<Item = String>) -> Result<u32, Error> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .env(DAEMON_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Out of the session of the terminal, whose hangup would otherwise end the process.
+        unsafe {
+            command.pre_exec(|| match libc::setsid() {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            });
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+    Ok(command.spawn()?.id())
+}
+
+/// A file holding the id of the running process, removed when dropped.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Write the id and executable of this process to `path`, unless another running process
+    /// holds it.
+    pub fn take(path: &Path) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let exe = std::env::current_exe()?;
+        loop {
+            // Only one of the processes starting at once creates the file.
+            match OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    write!(file, "{}\n{}\n", std::process::id(), exe.display())?;
+                    return Ok(Pidfile {
+                        path: path.to_owned(),
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+            // Empty while the process that created it writes to it.
+            if fs::metadata(path).is_ok_and(|metadata| metadata.len() == 0) {
+                let taken = format!("{} is being taken by another process", path.display());
+                return Err(taken.into());
+            }
+            if let Some(holder) = read(path)? {
+                if holder.runs() {
+                    let held = format!("{} is held by process {}", path.display(), holder.pid);
+                    return Err(held.into());
+                }
+                log::warn!("taking over {} of process {}", path.display(), holder.pid);
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Leave the file of a process that took over alone.
+        if read_pid(&self.path).ok().flatten() == Some(std::process::id()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The process a pidfile names.
+struct Holder {
+    pid: u32,
+    /// Executable of the process, missing from pidfiles written by hand.
+    exe: Option<PathBuf>,
+}
+
+impl Holder {
+    /// Whether the process still runs, and is still the one that wrote the pidfile.
+    fn runs(&self) -> bool {
+        is_running(self.pid)
+            && self
+                .exe
+                .as_deref()
+                .is_none_or(|exe| runs_executable(self.pid, exe))
+    }
+}
+
+/// What the pidfile at `path` holds, if there is one.
+fn read(path: &Path) -> Result<Option<Holder>, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = text.lines();
+    let pid = lines
+        .next()
+        .and_then(|line| line.trim().parse().ok())
+        .filter(|pid| is_valid(*pid))
+        .ok_or_else(|| format!("{} does not hold a process id", path.display()))?;
+    let exe = lines
+        .next()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from);
+    Ok(Some(Holder { pid, exe }))
+}
+
+/// The process id in the pidfile at `path`, if there is one.
+pub fn read_pid(path: &Path) -> Result<Option<u32>, Error> {
+    Ok(read(path)?.map(|holder| holder.pid))
+}
+
+/// Whether `pid` names a single process: `kill` takes 0 for the process group of the caller,
+/// and ids past `i32::MAX` turn negative as a `pid_t`, naming other groups.
+fn is_valid(pid: u32) -> bool {
+    pid != 0 && pid <= i32::MAX as u32
+}
+
+/// Whether a process of id `pid` runs.
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    if !is_valid(pid) {
+        return false;
+    }
+    // Signal 0 only checks that the process exists; EPERM means it does, as another user's.
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+pub fn is_running(pid: u32) -> bool {
+    if !is_valid(pid) {
+        return false;
+    }
+    Command::new("tasklist")
+        .args(["/NH", "/FI", &format!("PID eq {}", pid)])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+}
+
+/// Whether the process of id `pid` runs `exe`, replaced or not since it started. Processes of
+/// other users, whose executable cannot be read, do not.
+#[cfg(target_os = "linux")]
+fn runs_executable(pid: u32, exe: &Path) -> bool {
+    match fs::read_link(format!("/proc/{}/exe", pid)) {
+        Ok(running) => {
+            running == exe || running.as_os_str() == format!("{} (deleted)", exe.display()).as_str()
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn runs_executable(_pid: u32, _exe: &Path) -> bool {
+    true
+}
+
+/// The process id of the node holding the pidfile at `path`, if it runs.
+pub fn status(path: &Path) -> Result<Option<u32>, Error> {
+    Ok(read(path)?
+        .filter(|holder| holder.runs())
+        .map(|holder| holder.pid))
+}
+
+/// Terminate the node holding the pidfile at `path` and wait up to [`STOP_TIMEOUT`] for it to
+/// exit, returning its process id, or `None` if it was not running.
+pub fn stop(path: &Path) -> Result<Option<u32>, Error> {
+    let pid = match status(path)? {
+        Some(pid) => pid,
+        None => {
+            // Left behind by a process that died.
+            let _ = fs::remove_file(path);
+            return Ok(None);
+        }
+    };
+    terminate(pid)?;
+    let started = Instant::now();
+    while is_running(pid) {
+        if started.elapsed() > STOP_TIMEOUT {
+            return Err(format!("process {} did not exit", pid).into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // Terminated processes do not remove their pidfile.
+    if read_pid(path)? == Some(pid) {
+        fs::remove_file(path)?;
+    }
+    Ok(Some(pid))
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) -> Result<(), Error> {
+    if !is_valid(pid) {
+        return Err(format!("{} is not the id of a process to stop", pid).into());
+    }
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> Result<(), Error> {
+    if !is_valid(pid) {
+        return Err(format!("{} is not the id of a process to stop", pid).into());
+    }
+    let status = Command::new("taskkill")
+        .args(["/F", "/PID", &pid.to_string()])
+        .status()?;
+    if !status.success() {
+        return Err(format!("taskkill failed to stop process {}", pid).into());
+    }
+    Ok(())
+}
+
+/// Where and how much a [`RotatingLog`] writes.
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    /// Directory of the log files.
+    pub dir: PathBuf,
+    /// Name of the current log file in `dir`.
+    pub name: String,
+    /// Size past which the log file is rotated.
+    pub max_bytes: u64,
+    /// Rotated files kept, the oldest being removed past that.
+    pub keep: usize,
+}
+
+impl LogConfig {
+    /// Log to `pub.log` in `dir`, rotating it every 10 MiB and keeping 7 rotated files.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LogConfig {
+            dir: dir.into(),
+            name: "pub.log".into(),
+            max_bytes: 10 << 20,
+            keep: 7,
+        }
+    }
+}
+
+/// A log file rotated by size and by day, see the [module documentation](self).
+pub struct RotatingLog {
+    config: LogConfig,
+    file: File,
+    bytes: u64,
+    /// Days since the Unix epoch, in UTC, when the file was opened.
+    day: u64,
+}
+
+impl RotatingLog {
+    /// Open the log file, appending to it.
+    pub fn open(config: LogConfig) -> Result<Self, Error> {
+        fs::create_dir_all(&config.dir)?;
+        let path = config.dir.join(&config.name);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let bytes = file.metadata()?.len();
+        let day = file.metadata()?.created().map_or_else(|_| today(), day_of);
+        Ok(RotatingLog {
+            config,
+            file,
+            bytes,
+            day,
+        })
+    }
+
+    /// Rename the log file after its day and start a new one, removing the oldest rotated files.
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = self.config.dir.join(&self.config.name);
+        let stem = format!("{}.{}", self.config.name, date(self.day));
+        let mut rotated = self.config.dir.join(&stem);
+        let mut n = 0;
+        while rotated.exists() {
+            n += 1;
+            rotated = self.config.dir.join(format!("{}.{}", stem, n));
+        }
+        fs::rename(&path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.bytes = 0;
+        self.day = today();
+
+        let prefix = format!("{}.", self.config.name);
+        let mut old: Vec<(SystemTime, PathBuf)> = fs::read_dir(&self.config.dir)?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    return None;
+                }
+                Some((entry.metadata().ok()?.modified().ok()?, entry.path()))
+            })
+            .collect();
+        old.sort();
+        let excess = old.len().saturating_sub(self.config.keep);
+        for (_, path) in old.into_iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.bytes > 0
+            && (self.bytes + buf.len() as u64 > self.config.max_bytes || today() != self.day)
+        {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Days since the Unix epoch of `time`, in UTC.
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400)
+}
+
+fn today() -> u64 {
+    day_of(SystemTime::now())
+}
+
+/// The `YYYY-MM-DD` date of the day `days` after the Unix epoch.
+fn date(days: u64) -> String {
+    // Howard Hinnant's civil_from_days, for days past the epoch.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Logger writing the records `RUST_LOG` lets through to a [`RotatingLog`].
+struct FileLogger {
+    filter: env_logger::Logger,
+    log: Mutex<RotatingLog>,
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = now.as_secs() % 86_400;
+        let line = format!(
+            "[{}T{:02}:{:02}:{:02}.{:03}Z {} {}] {}\n",
+            date(now.as_secs() / 86_400),
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        let mut log = self.log.lock().unwrap();
+        let _ = log.write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.log.lock().unwrap().flush();
+    }
+}
+
+/// Log to a [`RotatingLog`] configured by `config`, with the levels `RUST_LOG` sets as
+/// `env_logger` reads it.
+pub fn init_log(config: LogConfig) -> Result<(), Error> {
+    let filter = env_logger::Builder::from_default_env().build();
+    let max_level = filter.filter();
+    let logger = FileLogger {
+        filter,
+        log: Mutex::new(RotatingLog::open(config)?),
+    };
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfile_is_held_once_and_taken_over_when_stale() {
+        let path = std::env::temp_dir().join(format!("pubsub-test-{}.pid", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let pidfile = Pidfile::take(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(std::process::id()));
+        assert!(Pidfile::take(&path).is_err());
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // No process runs with the largest id a pidfile may hold.
+        fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let pidfile = Pidfile::take(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(std::process::id()));
+        drop(pidfile);
+    }
+}
@@ -0,0 +1,261 @@
+//! Running the node as a Windows service, started and stopped by the service control manager.
+//!
+//! [`install`] registers the current executable as a service started with [`SERVICE_ARG`] and
+//! the arguments given, in the environment given, the manager starting services in that of the
+//! system otherwise; [`uninstall`] stops and removes it:
+//!
+//! ```text
+//! pub service install       # as SERVICE_NAME, started with the system
+//! sc start pubsub-lite
+//! sc stop pubsub-lite
+//! pub service uninstall
+//! ```
+//!
+//! Started by the manager, the process connects to it with [`Service::start`], which reports the
+//! service running. [`Service::stopped`] resolves once the manager stops the service or the
+//! system shuts down, and dropping the [`Service`] reports it stopped.
+
+use super::{SERVICE_ARG, STOP_TIMEOUT};
+use crate::Error;
+use futures::channel::oneshot;
+use std::{
+    ffi::OsStr,
+    io,
+    os::windows::ffi::OsStrExt,
+    process::Command,
+    ptr,
+    sync::{mpsc, Mutex},
+    thread::{self, JoinHandle},
+};
+use winapi::{
+    shared::{
+        minwindef::{DWORD, LPVOID},
+        winerror::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR},
+    },
+    um::{
+        winnt::{LPWSTR, SERVICE_WIN32_OWN_PROCESS},
+        winsvc::{
+            RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+            SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE,
+            SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_STATUS,
+            SERVICE_STATUS_HANDLE, SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW,
+        },
+    },
+};
+
+/// Name the node is installed as.
+pub const SERVICE_NAME: &str = "pubsub-lite";
+
+/// Register the current executable as the service `name`, started with the system, with
+/// [`SERVICE_ARG`] and `args`, and the variables of `env`.
+pub fn install(
+    name: &str,
+    args: impl IntoIterator<Item = String>,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), Error> {
+    let mut bin_path = format!("\"{}\" {}", std::env::current_exe()?.display(), SERVICE_ARG);
+    for arg in args {
+        bin_path.push_str(&format!(" \"{}\"", arg));
+    }
+    run(
+        "sc.exe",
+        &["create", name, "binPath=", &bin_path, "start=", "auto"],
+    )?;
+    // The manager reads the environment of a service from its key, one variable per string.
+    let env: Vec<_> = env
+        .into_iter()
+        .map(|(var, value)| format!("{}={}", var, value))
+        .collect();
+    if !env.is_empty() {
+        let key = format!(r"HKLM\SYSTEM\CurrentControlSet\Services\{}", name);
+        let env = env.join(r"\0");
+        let add = [
+            "add",
+            &key,
+            "/v",
+            "Environment",
+            "/t",
+            "REG_MULTI_SZ",
+            "/d",
+            &env,
+            "/f",
+        ];
+        run("reg.exe", &add)?;
+    }
+    Ok(())
+}
+
+/// Stop the service `name` if it runs, and remove it.
+pub fn uninstall(name: &str) -> Result<(), Error> {
+    // Fails if the service is not running, which is fine.
+    let _ = run("sc.exe", &["stop", name]);
+    run("sc.exe", &["delete", name])
+}
+
+fn run(program: &str, args: &[&str]) -> Result<(), Error> {
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        let out = String::from_utf8_lossy(&output.stdout);
+        return Err(format!("{} {} failed: {}", program, args[0], out.trim()).into());
+    }
+    Ok(())
+}
+
+/// The service this process runs as, reported stopped when dropped.
+pub struct Service {
+    status: StatusHandle,
+    stop: oneshot::Receiver<()>,
+    /// Lets `service_main` return, and the dispatcher with it, once the service stopped.
+    done: mpsc::Sender<()>,
+    dispatcher: Option<JoinHandle<()>>,
+}
+
+/// What `service_main` needs from [`Service::start`]: the name of the service, and where to send
+/// it once running.
+struct Starting {
+    name: Vec<u16>,
+    started: mpsc::Sender<io::Result<Service>>,
+}
+
+/// The one service of the process being started, taken by `service_main`.
+static STARTING: Mutex<Option<Starting>> = Mutex::new(None);
+
+/// Handle of the service to report its status on, valid on any thread.
+struct StatusHandle(SERVICE_STATUS_HANDLE);
+
+unsafe impl Send for StatusHandle {}
+
+impl Service {
+    /// Connect to the service control manager as the service `name` and report it running.
+    /// Fails unless the manager started the process.
+    pub fn start(name: &str) -> Result<Self, Error> {
+        let (started, running) = mpsc::channel();
+        *STARTING.lock().unwrap() = Some(Starting {
+            name: wide(name),
+            started,
+        });
+        let name = wide(name);
+        // The dispatcher runs the control handler, and returns once the service stopped.
+        let dispatcher = thread::spawn(move || {
+            let table = [
+                SERVICE_TABLE_ENTRYW {
+                    lpServiceName: name.as_ptr(),
+                    lpServiceProc: Some(service_main),
+                },
+                SERVICE_TABLE_ENTRYW {
+                    lpServiceName: ptr::null(),
+                    lpServiceProc: None,
+                },
+            ];
+            if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+                let e = io::Error::last_os_error();
+                if let Some(starting) = STARTING.lock().unwrap().take() {
+                    let _ = starting.started.send(Err(e));
+                }
+            }
+        });
+        let mut service = running.recv().map_err(|_| "service dispatcher exited")??;
+        service.dispatcher = Some(dispatcher);
+        Ok(service)
+    }
+
+    /// Wait for the manager to stop the service, reporting it stopping.
+    pub async fn stopped(&mut self) {
+        let _ = (&mut self.stop).await;
+        report(&self.status, SERVICE_STOP_PENDING);
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        report(&self.status, SERVICE_STOPPED);
+        let _ = self.done.send(());
+        if let Some(dispatcher) = self.dispatcher.take() {
+            let _ = dispatcher.join();
+        }
+    }
+}
+
+/// Entry point of the service, run by the dispatcher on a thread of its own.
+unsafe extern "system" fn service_main(_argc: DWORD, _argv: *mut LPWSTR) {
+    let starting = match STARTING.lock().unwrap().take() {
+        Some(starting) => starting,
+        None => return,
+    };
+    let (stop, stopped) = oneshot::channel();
+    // Read by the control handler until the process exits.
+    let context: &'static Mutex<Option<oneshot::Sender<()>>> =
+        Box::leak(Box::new(Mutex::new(Some(stop))));
+    let handle = RegisterServiceCtrlHandlerExW(
+        starting.name.as_ptr(),
+        Some(control_handler),
+        context as *const _ as LPVOID,
+    );
+    if handle.is_null() {
+        let _ = starting.started.send(Err(io::Error::last_os_error()));
+        return;
+    }
+    let status = StatusHandle(handle);
+    report(&status, SERVICE_RUNNING);
+    let (done, exited) = mpsc::channel();
+    let service = Service {
+        status,
+        stop: stopped,
+        done,
+        dispatcher: None,
+    };
+    if starting.started.send(Ok(service)).is_ok() {
+        let _ = exited.recv();
+    }
+}
+
+/// Handle the controls of the manager: stopping and shutting down resolve
+/// [`Service::stopped`].
+unsafe extern "system" fn control_handler(
+    control: DWORD,
+    _event_type: DWORD,
+    _event_data: LPVOID,
+    context: LPVOID,
+) -> DWORD {
+    let stop = &*(context as *const Mutex<Option<oneshot::Sender<()>>>);
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            if let Some(stop) = stop.lock().unwrap().take() {
+                let _ = stop.send(());
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// Report the service in `state` to the manager.
+fn report(status: &StatusHandle, state: DWORD) {
+    let mut service_status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: match state {
+            SERVICE_RUNNING => SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN,
+            _ => 0,
+        },
+        dwWin32ExitCode: NO_ERROR,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        dwWaitHint: match state {
+            SERVICE_STOP_PENDING => STOP_TIMEOUT.as_millis() as DWORD,
+            _ => 0,
+        },
+    };
+    if unsafe { SetServiceStatus(status.0, &mut service_status) } == 0 {
+        log::warn!(
+            "failed to report the service status: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// `s` as a nul-terminated wide string.
+fn wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
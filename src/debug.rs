@@ -0,0 +1,363 @@
+//! Debug dumps: a full snapshot of the swarm state of a node, for bug reports about stuck meshes
+//! and connections that go nowhere.
+//!
+//! [`Client::debug_dump`](crate::Client::debug_dump) returns a [`DebugDump`], served as JSON by
+//! the `GET /debug` call of the [admin endpoint](crate::gateway::admin) and printed by
+//! `pub debug dump`. Unlike [`Stats`](crate::stats::Stats), meant to be scraped, it tells
+//! everything, internal topics included:
+//!
+//! - every connection, with its address, the security and multiplexing protocols negotiated on
+//!   it, the protocols the peer said it supports through identify, and the yamux substreams
+//!   opened on it;
+//! - the dials in flight, from the first byte sent to the connection being ready;
+//! - the peers of the mesh and fanout of every topic, and the peers subscribed to it;
+//! - the memory held by the caches of the node.
+//!
+//! The transport counts dials and substreams for the [`ConnectionTracker`] given to
+//! [`build_transport`](crate::transport::build_transport).
+
+use crate::{bandwidth::Traffic, stats::MemoryStats};
+use futures::prelude::*;
+use libp2p::{
+    core::{muxing::StreamMuxer, transport::TransportError, ConnectedPoint, Transport},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// A snapshot of the swarm state of a node, returned by
+/// [`Client::debug_dump`](crate::Client::debug_dump).
+#[derive(Clone, Debug)]
+pub struct DebugDump {
+    pub peer_id: PeerId,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub connections: Vec<ConnectionDump>,
+    pub pending_dials: Vec<PendingDial>,
+    /// Every topic with a mesh, a fanout or subscribed peers, by name.
+    pub topics: Vec<TopicDump>,
+    pub caches: CacheUsage,
+}
+
+/// A connection to a peer.
+#[derive(Clone, Debug)]
+pub struct ConnectionDump {
+    pub peer: PeerId,
+    pub endpoint: ConnectedPoint,
+    /// How long ago the connection was made, if the transport tracked it.
+    pub age: Option<Duration>,
+    /// The protocol that authenticated the connection, `noise` or `secio`.
+    pub security: Option<&'static str>,
+    pub multiplexer: &'static str,
+    /// The protocols the peer supports, as told by identify.
+    pub protocols: Vec<String>,
+    pub agent_version: Option<String>,
+    pub substreams: SubstreamStats,
+    pub traffic: Traffic,
+}
+
+/// The substreams opened on a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubstreamStats {
+    /// Substreams open now.
+    pub open: u64,
+    /// Substreams the peer opened.
+    pub inbound: u64,
+    /// Substreams this node opened.
+    pub outbound: u64,
+}
+
+/// A dial not done yet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingDial {
+    pub address: Multiaddr,
+    pub elapsed: Duration,
+}
+
+/// The gossip state of a topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicDump {
+    pub topic: String,
+    /// Whether this node is subscribed to the topic.
+    pub subscribed: bool,
+    pub mesh: Vec<PeerId>,
+    pub fanout: Vec<PeerId>,
+    /// The connected peers subscribed to the topic.
+    pub peers: Vec<PeerId>,
+}
+
+/// Memory held by the caches of a node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheUsage {
+    pub memory: MemoryStats,
+    /// Ids of the messages remembered to drop their duplicates.
+    pub duplicate_cache: usize,
+    /// Bytes of the chunks of messages not reassembled yet.
+    pub reassembly: usize,
+}
+
+/// The counters of a connection, shared by its muxer and the tracker.
+struct Counters {
+    opened: Instant,
+    security: Option<&'static str>,
+    open: AtomicU64,
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+#[derive(Default)]
+struct Tracked {
+    next_dial: u64,
+    dials: HashMap<u64, (Multiaddr, Instant)>,
+    /// Security of the connections authenticated but not multiplexed yet.
+    securing: HashMap<PeerId, &'static str>,
+    connections: HashMap<PeerId, Vec<Weak<Counters>>>,
+}
+
+/// The dials in flight and the connections made by a transport, shared with the node for its
+/// debug dumps.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    inner: Arc<Mutex<Tracked>>,
+}
+
+impl ConnectionTracker {
+    /// Record the security protocol `peer` was authenticated with.
+    pub(crate) fn secured(&self, peer: &PeerId, security: &'static str) {
+        let mut tracked = self.inner.lock().unwrap();
+        tracked.securing.insert(peer.clone(), security);
+    }
+
+    /// Count the substreams of the connection to `peer` that `muxer` multiplexes.
+    pub(crate) fn track<M>(&self, peer: &PeerId, muxer: M) -> CountingMuxer<M> {
+        let mut tracked = self.inner.lock().unwrap();
+        let counters = Arc::new(Counters {
+            opened: Instant::now(),
+            security: tracked.securing.remove(peer),
+            open: AtomicU64::new(0),
+            inbound: AtomicU64::new(0),
+            outbound: AtomicU64::new(0),
+        });
+        let connections = tracked.connections.entry(peer.clone()).or_default();
+        connections.retain(|counters| counters.strong_count() > 0);
+        connections.push(Arc::downgrade(&counters));
+        CountingMuxer {
+            inner: muxer,
+            counters,
+        }
+    }
+
+    /// When the live connection to `peer` made last was made, its security and substreams.
+    pub(crate) fn connection(
+        &self,
+        peer: &PeerId,
+    ) -> Option<(Instant, Option<&'static str>, SubstreamStats)> {
+        let tracked = self.inner.lock().unwrap();
+        let counters = tracked
+            .connections
+            .get(peer)?
+            .iter()
+            .rev()
+            .find_map(Weak::upgrade)?;
+        let substreams = SubstreamStats {
+            open: counters.open.load(Ordering::Relaxed),
+            inbound: counters.inbound.load(Ordering::Relaxed),
+            outbound: counters.outbound.load(Ordering::Relaxed),
+        };
+        Some((counters.opened, counters.security, substreams))
+    }
+
+    pub(crate) fn pending_dials(&self) -> Vec<PendingDial> {
+        let tracked = self.inner.lock().unwrap();
+        let mut dials: Vec<PendingDial> = tracked
+            .dials
+            .values()
+            .map(|(address, started)| PendingDial {
+                address: address.clone(),
+                elapsed: started.elapsed(),
+            })
+            .collect();
+        dials.sort_by_key(|dial| std::cmp::Reverse(dial.elapsed));
+        dials
+    }
+
+    /// Forget the connections closed.
+    pub(crate) fn expire(&self) {
+        let mut tracked = self.inner.lock().unwrap();
+        tracked.connections.retain(|_, connections| {
+            connections.retain(|counters| counters.strong_count() > 0);
+            !connections.is_empty()
+        });
+    }
+
+    fn dial_started(&self, address: Multiaddr) -> u64 {
+        let mut tracked = self.inner.lock().unwrap();
+        let id = tracked.next_dial;
+        tracked.next_dial += 1;
+        tracked.dials.insert(id, (address, Instant::now()));
+        id
+    }
+
+    fn dial_ended(&self, id: u64) {
+        self.inner.lock().unwrap().dials.remove(&id);
+    }
+}
+
+/// A transport whose dials in flight are tracked.
+#[derive(Clone)]
+pub(crate) struct TrackDials<T> {
+    inner: T,
+    tracker: ConnectionTracker,
+}
+
+impl<T> TrackDials<T> {
+    pub(crate) fn new(inner: T, tracker: ConnectionTracker) -> Self {
+        TrackDials { inner, tracker }
+    }
+}
+
+impl<T: Transport> Transport for TrackDials<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = TrackedDial<T::Dial>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        self.inner.listen_on(addr)
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let dial = self.inner.dial(addr.clone())?;
+        Ok(TrackedDial {
+            inner: Box::pin(dial),
+            id: self.tracker.dial_started(addr),
+            tracker: self.tracker,
+        })
+    }
+}
+
+/// A dial tracked until it completes or is dropped.
+pub(crate) struct TrackedDial<F> {
+    inner: Pin<Box<F>>,
+    id: u64,
+    tracker: ConnectionTracker,
+}
+
+impl<F: Future> Future for TrackedDial<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+impl<F> Drop for TrackedDial<F> {
+    fn drop(&mut self) {
+        self.tracker.dial_ended(self.id);
+    }
+}
+
+/// A muxer counting the substreams it opens and accepts.
+pub(crate) struct CountingMuxer<M> {
+    inner: M,
+    counters: Arc<Counters>,
+}
+
+impl<M> CountingMuxer<M> {
+    fn opened<S, E>(
+        &self,
+        substream: Poll<Result<S, E>>,
+        counter: &AtomicU64,
+    ) -> Poll<Result<S, E>> {
+        if let Poll::Ready(Ok(_)) = &substream {
+            self.counters.open.fetch_add(1, Ordering::Relaxed);
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        substream
+    }
+}
+
+impl<M: StreamMuxer> StreamMuxer for CountingMuxer<M> {
+    type Substream = M::Substream;
+    type OutboundSubstream = M::OutboundSubstream;
+    type Error = M::Error;
+
+    fn poll_inbound(&self, cx: &mut Context) -> Poll<Result<Self::Substream, Self::Error>> {
+        self.opened(self.inner.poll_inbound(cx), &self.counters.inbound)
+    }
+
+    fn open_outbound(&self) -> Self::OutboundSubstream {
+        self.inner.open_outbound()
+    }
+
+    fn poll_outbound(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::OutboundSubstream,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        self.opened(self.inner.poll_outbound(cx, s), &self.counters.outbound)
+    }
+
+    fn destroy_outbound(&self, s: Self::OutboundSubstream) {
+        self.inner.destroy_outbound(s)
+    }
+
+    fn read_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        self.inner.read_substream(cx, s, buf)
+    }
+
+    fn write_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        self.inner.write_substream(cx, s, buf)
+    }
+
+    fn flush_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.flush_substream(cx, s)
+    }
+
+    fn shutdown_substream(
+        &self,
+        cx: &mut Context,
+        s: &mut Self::Substream,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.shutdown_substream(cx, s)
+    }
+
+    fn destroy_substream(&self, s: Self::Substream) {
+        self.counters.open.fetch_sub(1, Ordering::Relaxed);
+        self.inner.destroy_substream(s)
+    }
+
+    fn is_remote_acknowledged(&self) -> bool {
+        self.inner.is_remote_acknowledged()
+    }
+
+    fn close(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.close(cx)
+    }
+
+    fn flush_all(&self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner.flush_all(cx)
+    }
+}
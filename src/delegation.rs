@@ -0,0 +1,548 @@
+//! Publishing under the identity of an organisation.
+//!
+//! Gossipsub 0.16 does not sign messages: their source is whatever the publisher claims. A node
+//! given a [`Delegation`] for a topic signs the messages it publishes on it with a key of its
+//! own, and attaches the chain of [`Certificate`]s through which an organisation key vouches for
+//! that key, directly or through intermediate keys, each certificate until it expires. Subscribers
+//! only need the organisation key: a node trusting it for a topic checks the chain and signature
+//! of every message on the topic, drops those that fail, and tells the organisation and node key
+//! of the others in [`Message::origin`](crate::Message::origin). Node keys can thus rotate as
+//! often as wanted, each new key getting a certificate.
+//!
+//! A signed payload travels in an envelope: a marker, the length of a JSON header holding the
+//! chain and the signature, the header, then the payload. The topic name is signed along with the
+//! payload, so a signed message replayed on another topic is rejected. A node not trusting an
+//! organisation for a topic strips the envelope of the signed messages on it unchecked.
+//!
+//! The keys need not be in memory: a [`Signer`], such as a PKCS#11 token or an OS keystore, can
+//! issue certificates with [`Certificate::issue`] and sign messages as the key of a
+//! [`Delegation`], so that organisation and delegated keys stay off the disk of nodes. It can hold
+//! the key of the peer identity too, see
+//! [`NodeConfig::identity_signer`](crate::node::NodeConfig::identity_signer): noise then has it
+//! sign the handshake of every connection, and [attestation](crate::attestation), access tokens,
+//! peer records and continuity records are signed by it. Secio and
+//! [direct messages](crate::direct) need the private key in memory, and are refused to such
+//! nodes.
+
+use crate::{topic::TopicFilter, Error, PubSubError};
+use bytes::Bytes;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use libp2p::identity::{self, ed25519};
+use ring::{
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Start of a signed payload. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0pls";
+
+/// Upper bound on the number of certificates in a chain.
+const MAX_CHAIN: usize = 4;
+
+/// Ed25519 public key of an organisation, an intermediate or a node, written in hex.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, &self.0)
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&HEXLOWER_PERMISSIVE.encode(&self.0))
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(hex: &str) -> Result<Self, Error> {
+        let bytes = HEXLOWER_PERMISSIVE.decode(hex.as_bytes())?;
+        let key = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| "an Ed25519 public key is 32 bytes long")?;
+        Ok(PublicKey(key))
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(key: PublicKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = Error;
+
+    fn try_from(hex: String) -> Result<Self, Error> {
+        hex.parse()
+    }
+}
+
+/// Holder of an Ed25519 private key signing on behalf of this crate, such as a PKCS#11 token or
+/// an OS keystore, so that the key never leaves it. [`SigningKey`] is the one held in memory.
+pub trait Signer: Send + Sync {
+    /// The public key of the private key signing, which checks its signatures.
+    fn public_key(&self) -> PublicKey;
+
+    /// Sign `message` with Ed25519.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Ed25519 key pair of an organisation, an intermediate or a node.
+#[derive(Clone)]
+pub struct SigningKey {
+    pkcs8: Vec<u8>,
+    pair: Arc<Ed25519KeyPair>,
+}
+
+impl SigningKey {
+    pub fn generate() -> Result<Self, Error> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| "failed to generate a key")?;
+        Self::from_pkcs8(pkcs8.as_ref())
+    }
+
+    /// Load a key pair from its PKCS#8 document, as returned by [`to_pkcs8`](Self::to_pkcs8).
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, Error> {
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|e| e.to_string())?;
+        Ok(SigningKey {
+            pkcs8: pkcs8.to_vec(),
+            pair: Arc::new(pair),
+        })
+    }
+
+    pub fn to_pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.pair.public_key().as_ref());
+        PublicKey(key)
+    }
+
+    /// Vouch for `subject` until `not_after`.
+    pub fn certify(&self, subject: &PublicKey, not_after: SystemTime) -> Certificate {
+        let not_after = unix_secs(not_after);
+        let issuer = self.public_key();
+        let signature = self
+            .pair
+            .sign(&Certificate::signed(subject, &issuer, not_after))
+            .as_ref()
+            .to_vec();
+        Certificate {
+            subject: *subject,
+            issuer,
+            not_after,
+            signature,
+        }
+    }
+}
+
+impl Signer for SigningKey {
+    fn public_key(&self) -> PublicKey {
+        SigningKey::public_key(self)
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(self.pair.sign(message).as_ref().to_vec())
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigningKey({})", self.public_key())
+    }
+}
+
+/// Key pair of the peer identity of a node: an [`identity::Keypair`] held in memory, or the
+/// Ed25519 key of a [`Signer`].
+#[derive(Clone)]
+pub struct IdentityKey(Held);
+
+#[derive(Clone)]
+enum Held {
+    Keypair(identity::Keypair),
+    Signer(Arc<dyn Signer>, identity::PublicKey),
+}
+
+impl IdentityKey {
+    /// The identity of the key of `signer`.
+    pub fn from_signer(signer: Arc<dyn Signer>) -> Result<Self, Error> {
+        let public = ed25519::PublicKey::decode(signer.public_key().as_bytes())
+            .map_err(PubSubError::other)?;
+        Ok(IdentityKey(Held::Signer(
+            signer,
+            identity::PublicKey::Ed25519(public),
+        )))
+    }
+
+    pub fn public(&self) -> identity::PublicKey {
+        match &self.0 {
+            Held::Keypair(keypair) => keypair.public(),
+            Held::Signer(_, public) => public.clone(),
+        }
+    }
+
+    /// Sign `message` as the peer identity, as an [`identity::Keypair`] does.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        match &self.0 {
+            Held::Keypair(keypair) => keypair.sign(message).map_err(PubSubError::other),
+            Held::Signer(signer, _) => signer.sign(message),
+        }
+    }
+
+    /// The key pair, if it is held in memory.
+    pub fn keypair(&self) -> Option<&identity::Keypair> {
+        match &self.0 {
+            Held::Keypair(keypair) => Some(keypair),
+            Held::Signer(..) => None,
+        }
+    }
+}
+
+impl From<identity::Keypair> for IdentityKey {
+    fn from(keypair: identity::Keypair) -> Self {
+        IdentityKey(Held::Keypair(keypair))
+    }
+}
+
+/// Statement by `issuer` that `subject` may sign on its behalf until `not_after`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Certificate {
+    pub subject: PublicKey,
+    pub issuer: PublicKey,
+    /// Unix time in seconds after which the certificate is void.
+    pub not_after: u64,
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    signature: Vec<u8>,
+}
+
+impl Certificate {
+    /// Have `issuer` vouch for `subject` until `not_after`.
+    pub fn issue(
+        issuer: &dyn Signer,
+        subject: &PublicKey,
+        not_after: SystemTime,
+    ) -> Result<Self, Error> {
+        let not_after = unix_secs(not_after);
+        let issuer_key = issuer.public_key();
+        let signature = issuer.sign(&Certificate::signed(subject, &issuer_key, not_after))?;
+        Ok(Certificate {
+            subject: *subject,
+            issuer: issuer_key,
+            not_after,
+            signature,
+        })
+    }
+
+    /// What the issuer of a certificate signs.
+    fn signed(subject: &PublicKey, issuer: &PublicKey, not_after: u64) -> Vec<u8> {
+        let mut signed = b"pubsub-lite/certificate\0".to_vec();
+        signed.extend_from_slice(&subject.0);
+        signed.extend_from_slice(&issuer.0);
+        signed.extend_from_slice(&not_after.to_be_bytes());
+        signed
+    }
+
+    /// Check that the certificate is signed by its issuer and has not expired at `now`.
+    fn check(&self, now: u64) -> Result<(), Error> {
+        if now > self.not_after {
+            return Err(format!("certificate for {} has expired", self.subject).into());
+        }
+        let signed = Certificate::signed(&self.subject, &self.issuer, self.not_after);
+        if !self.issuer.verify(&signed, &self.signature) {
+            return Err(format!("certificate for {} has a bad signature", self.subject).into());
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn to_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&HEXLOWER_PERMISSIVE.encode(bytes))
+}
+
+pub(crate) fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    HEXLOWER_PERMISSIVE
+        .decode(hex.as_bytes())
+        .map_err(serde::de::Error::custom)
+}
+
+/// Check that `chain` leads from its first issuer to `key`, every certificate being valid at
+/// `now`.
+fn check_chain(chain: &[Certificate], key: &PublicKey, now: u64) -> Result<(), Error> {
+    if chain.is_empty() || chain.len() > MAX_CHAIN {
+        return Err(format!("a chain holds 1 to {} certificates", MAX_CHAIN).into());
+    }
+    for (certificate, next) in chain.iter().zip(chain.iter().skip(1)) {
+        if next.issuer != certificate.subject {
+            return Err(format!("{} is not certified by the chain", next.issuer).into());
+        }
+    }
+    if chain[chain.len() - 1].subject != *key {
+        return Err(format!("{} is not certified by the chain", key).into());
+    }
+    chain
+        .iter()
+        .try_for_each(|certificate| certificate.check(now))
+}
+
+pub(crate) fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+pub(crate) fn now() -> u64 {
+    unix_secs(SystemTime::now())
+}
+
+/// What a node needs to publish under the identity of an organisation.
+#[derive(Clone)]
+pub struct Delegation {
+    key: Arc<dyn Signer>,
+    chain: Vec<Certificate>,
+}
+
+impl Delegation {
+    /// Sign with `key`, vouched for by `chain`: the certificates from the one issued by the
+    /// organisation key to the one issued for `key`. Fails unless the chain is valid now and
+    /// `key` signs as the key it certifies.
+    pub fn new(key: impl Signer + 'static, chain: Vec<Certificate>) -> Result<Self, Error> {
+        let public_key = key.public_key();
+        check_chain(&chain, &public_key, now())?;
+        let probe = b"pubsub-lite/probe";
+        if !public_key.verify(probe, &key.sign(probe)?) {
+            return Err(format!("signer does not sign as {}", public_key).into());
+        }
+        Ok(Delegation {
+            key: Arc::new(key),
+            chain,
+        })
+    }
+
+    /// The organisation key at the root of the chain.
+    pub fn org(&self) -> &PublicKey {
+        &self.chain[0].issuer
+    }
+
+    /// Sign `data` published on `topic` into an envelope.
+    fn sign(&self, topic: &str, data: Bytes) -> Result<Bytes, Error> {
+        let header = Header {
+            chain: self.chain.clone(),
+            signature: self.key.sign(&signed(topic, &data))?,
+        };
+        let header = serde_json::to_vec(&header)?;
+        let mut envelope = Vec::with_capacity(MARKER.len() + 4 + header.len() + data.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        envelope.extend_from_slice(&header);
+        envelope.extend_from_slice(&data);
+        Ok(envelope.into())
+    }
+}
+
+impl fmt::Debug for Delegation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Delegation")
+            .field("key", &self.key.public_key())
+            .field("chain", &self.chain)
+            .finish()
+    }
+}
+
+/// The chain and signature of a signed payload.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    chain: Vec<Certificate>,
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    signature: Vec<u8>,
+}
+
+/// What the publisher of a payload on `topic` signs.
+fn signed(topic: &str, data: &[u8]) -> Vec<u8> {
+    let mut signed = b"pubsub-lite/message\0".to_vec();
+    signed.extend_from_slice(topic.as_bytes());
+    signed.push(0);
+    signed.extend_from_slice(data);
+    signed
+}
+
+/// Identity a message was published under, checked against a trusted organisation key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub org: PublicKey,
+    /// Key of the node that signed the message.
+    pub key: PublicKey,
+}
+
+/// Sign `data` published on `topic` with the delegation of the first filter matching it, if any.
+pub(crate) fn sign(
+    delegations: &[(TopicFilter, Delegation)],
+    topic: &str,
+    data: Bytes,
+) -> Result<Bytes, Error> {
+    match delegations.iter().find(|(filter, _)| filter.matches(topic)) {
+        Some((_, delegation)) => delegation.sign(topic, data),
+        None => Ok(data),
+    }
+}
+
+/// Check `data` received on `topic` against the organisation trusted by the first filter
+/// matching it, returning its origin and payload. Without one, a signed payload is only taken
+/// out of its envelope.
+pub(crate) fn verify(
+    trusted: &[(TopicFilter, PublicKey)],
+    topic: &str,
+    data: Bytes,
+) -> Result<(Option<Origin>, Bytes), Error> {
+    let org = trusted
+        .iter()
+        .find(|(filter, _)| filter.matches(topic))
+        .map(|(_, org)| org);
+    if !data.starts_with(MARKER) {
+        return match org {
+            Some(_) => Err("message is not signed".into()),
+            None => Ok((None, data)),
+        };
+    }
+    let malformed = || PubSubError::codec("malformed signed message");
+    let rest = &data[MARKER.len()..];
+    let len = rest.get(..4).ok_or_else(malformed)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let header = rest.get(4..4 + len).ok_or_else(malformed)?;
+    let payload = data.slice(MARKER.len() + 4 + len..);
+    let org = match org {
+        Some(org) => org,
+        None => return Ok((None, payload)),
+    };
+    let header: Header = serde_json::from_slice(header)?;
+    let key = header.chain.last().ok_or_else(malformed)?.subject;
+    if header.chain[0].issuer != *org {
+        return Err(format!("message is not signed under {}", org).into());
+    }
+    check_chain(&header.chain, &key, now())?;
+    if !key.verify(&signed(topic, &payload), &header.signature) {
+        return Err("message has a bad signature".into());
+    }
+    Ok((Some(Origin { org: *org, key }), payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    struct Fixture {
+        org: SigningKey,
+        trusted: Vec<(TopicFilter, PublicKey)>,
+        delegations: Vec<(TopicFilter, Delegation)>,
+    }
+
+    /// An organisation vouching for a node key through an intermediate key.
+    fn fixture() -> Fixture {
+        let org = SigningKey::generate().unwrap();
+        let intermediate = SigningKey::generate().unwrap();
+        let node = SigningKey::generate().unwrap();
+        let until = SystemTime::now() + HOUR;
+        let chain = vec![
+            org.certify(&intermediate.public_key(), until),
+            intermediate.certify(&node.public_key(), until),
+        ];
+        let delegation = Delegation::new(node, chain).unwrap();
+        Fixture {
+            trusted: vec![(TopicFilter::new("orders/#").unwrap(), org.public_key())],
+            delegations: vec![(TopicFilter::new("orders/#").unwrap(), delegation)],
+            org,
+        }
+    }
+
+    #[test]
+    fn signed_payload_round_trips() {
+        let f = fixture();
+        let envelope = sign(&f.delegations, "orders/new", "order 1".into()).unwrap();
+        let (origin, payload) = verify(&f.trusted, "orders/new", envelope.clone()).unwrap();
+        assert_eq!(payload, "order 1");
+        assert_eq!(origin.unwrap().org, f.org.public_key());
+        // Nodes not trusting the organisation only strip the envelope.
+        assert_eq!(
+            verify(&[], "orders/new", envelope).unwrap(),
+            (None, "order 1".into())
+        );
+        // Topics without a delegation are published as they are.
+        assert_eq!(sign(&f.delegations, "chat", "hi".into()).unwrap(), "hi");
+    }
+
+    #[test]
+    fn rejects_tampered_truncated_and_misdirected_envelopes() {
+        let f = fixture();
+        let envelope = sign(&f.delegations, "orders/new", "order 1".into()).unwrap();
+        let mut tampered = envelope.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(verify(&f.trusted, "orders/new", tampered.into()).is_err());
+        for len in [MARKER.len() + 2, MARKER.len() + 10, envelope.len() - 8] {
+            assert!(verify(&f.trusted, "orders/new", envelope.slice(..len)).is_err());
+        }
+        assert!(verify(&f.trusted, "orders/old", envelope.clone()).is_err());
+        let stranger = SigningKey::generate().unwrap().public_key();
+        let trusted = [(TopicFilter::new("orders/#").unwrap(), stranger)];
+        assert!(verify(&trusted, "orders/new", envelope).is_err());
+        assert!(verify(&f.trusted, "orders/new", "order 1".into()).is_err());
+    }
+
+    #[test]
+    fn delegations_need_a_valid_chain_to_their_key() {
+        let org = SigningKey::generate().unwrap();
+        let node = SigningKey::generate().unwrap();
+        let other = SigningKey::generate().unwrap();
+        let until = SystemTime::now() + HOUR;
+        let expired = org.certify(&node.public_key(), SystemTime::now() - HOUR);
+        assert!(Delegation::new(node.clone(), vec![expired]).is_err());
+        let for_other = org.certify(&other.public_key(), until);
+        assert!(Delegation::new(node.clone(), vec![for_other]).is_err());
+        let broken = vec![
+            org.certify(&other.public_key(), until),
+            org.certify(&node.public_key(), until),
+        ];
+        assert!(Delegation::new(node.clone(), broken).is_err());
+        assert!(Delegation::new(node.clone(), Vec::new()).is_err());
+        let mut forged = org.certify(&node.public_key(), until);
+        forged.not_after += 1;
+        assert!(Delegation::new(node, vec![forged]).is_err());
+    }
+
+    #[test]
+    fn identity_keys_held_by_signers_sign_as_their_peer() {
+        let signer = SigningKey::generate().unwrap();
+        let key = IdentityKey::from_signer(Arc::new(signer)).unwrap();
+        assert!(key.keypair().is_none());
+        let signature = key.sign(b"handshake").unwrap();
+        assert!(key.public().verify(b"handshake", &signature));
+        assert!(!key.public().verify(b"handshake!", &signature));
+    }
+}
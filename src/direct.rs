@@ -0,0 +1,397 @@
+//! Direct messages: payloads for one or a few peers, which no other peer can read.
+//!
+//! Every peer has an inbox, the topic [`inbox_topic`] derives from its peer id.
+//! [`Client::send_to`](crate::Client::send_to) and
+//! [`Client::send_to_all`](crate::Client::send_to_all) seal a payload to the public keys of its
+//! recipients and publish it on their inboxes, and
+//! [`Client::direct_messages`](crate::Client::direct_messages) subscribes to the inbox of the
+//! node, handing out the payloads sealed to it as [`DirectMessage`]s. Any peer may relay the
+//! messages of an inbox, but only the recipients can open them.
+//!
+//! Payloads are sealed to the Ed25519 identity keys of the recipients, converted to X25519 keys:
+//! a node can only send to peers whose public key it learned, which it does as they identify,
+//! and only nodes with an Ed25519 identity can receive. A payload is encrypted once, with
+//! ChaCha20-Poly1305 under a random key, for all its recipients: the envelope holds, after a
+//! marker, an ephemeral X25519 public key, the number of recipients and, for every recipient, the
+//! payload key encrypted under the SHA-256 hash of its Diffie-Hellman secret with the ephemeral
+//! key, then the encrypted payload. The sender signs the payload along with the ephemeral key
+//! with the keypair of its peer identity, and seals its public key and signature with the
+//! payload, so that recipients tell who sent it and cannot pass it off as sent to others.
+//!
+//! Payloads that are not sealed to the node, or whose signature does not check, are skipped.
+
+use crate::{
+    delegation::IdentityKey, node::Command, Client, Error, Message, PubSubError, Subscription,
+};
+use bytes::Bytes;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use futures::{channel::oneshot, prelude::*};
+use libp2p::{identity, PeerId};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Start of a sealed direct message. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0pld";
+
+/// Prefix of the inbox topics.
+pub const INBOX_PREFIX: &str = "pubsub-lite/inbox/";
+
+/// Most recipients of a direct message.
+pub const MAX_RECIPIENTS: usize = 1024;
+
+const TAG_LEN: usize = 16;
+
+/// Length of the payload key of a recipient, encrypted.
+const SLOT_LEN: usize = 32 + TAG_LEN;
+
+/// The inbox topic of `peer`.
+pub fn inbox_topic(peer: &PeerId) -> String {
+    format!("{}{}", INBOX_PREFIX, peer.to_base58())
+}
+
+/// A payload sealed to this node, as handed out by [`DirectMessages`].
+#[derive(Clone, Debug)]
+pub struct DirectMessage {
+    /// The peer that sent and signed the payload.
+    pub from: PeerId,
+    pub data: Bytes,
+}
+
+/// Stream of the direct messages sealed to this node, returned by
+/// [`Client::direct_messages`](crate::Client::direct_messages).
+pub struct DirectMessages {
+    subscription: Subscription,
+    inbox: Inbox,
+}
+
+impl Stream for DirectMessages {
+    type Item = DirectMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DirectMessage>> {
+        loop {
+            let message: Message = match self.subscription.poll_next_unpin(cx) {
+                Poll::Ready(Some(message)) => message,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match self.inbox.open(&message.data) {
+                Ok(message) => return Poll::Ready(Some(message)),
+                Err(e) => log::debug!("skipping a direct message: {}", e),
+            }
+        }
+    }
+}
+
+/// Seal `data` to `peers` and publish it on their inboxes.
+pub(crate) async fn send(client: &Client, peers: &[PeerId], data: Bytes) -> Result<(), Error> {
+    let (reply, sealed) = oneshot::channel();
+    client.send(Command::SealDirect {
+        peers: peers.to_vec(),
+        data,
+        reply,
+    })?;
+    let sealed = sealed.await.map_err(|_| Error::Shutdown)??;
+    for peer in peers {
+        client.publish(&inbox_topic(peer), sealed.clone())?;
+    }
+    Ok(())
+}
+
+/// Subscribe to the inbox of the node of `client`.
+pub(crate) async fn open(client: &Client) -> Result<DirectMessages, Error> {
+    let (reply, inbox) = oneshot::channel();
+    client.send(Command::OpenInbox { reply })?;
+    let inbox = inbox.await.map_err(|_| Error::Shutdown)??;
+    Ok(DirectMessages {
+        subscription: client.subscribe(&inbox_topic(client.local_peer_id()))?,
+        inbox,
+    })
+}
+
+/// The X25519 public key of an Ed25519 identity key, if it is one.
+fn x25519_public(key: &identity::PublicKey) -> Option<[u8; 32]> {
+    match key {
+        identity::PublicKey::Ed25519(key) => CompressedEdwardsY(key.encode())
+            .decompress()
+            .map(|point| point.to_montgomery().to_bytes()),
+        _ => None,
+    }
+}
+
+/// The key encrypting the payload key of the recipient of `public`, with whom the sender shares
+/// `shared`, under the ephemeral key `ephemeral`.
+fn slot_key(shared: &[u8; 32], ephemeral: &[u8], public: &[u8; 32]) -> Result<LessSafeKey, Error> {
+    if shared == &[0; 32] {
+        return Err("weak public key".into());
+    }
+    let mut hashed = b"pubsub-lite/direct\0".to_vec();
+    hashed.extend_from_slice(shared);
+    hashed.extend_from_slice(ephemeral);
+    hashed.extend_from_slice(public);
+    Ok(aead_key(digest::digest(&digest::SHA256, &hashed).as_ref()))
+}
+
+fn aead_key(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("key has the right length"))
+}
+
+/// Every key is used once, so the nonce can be the same.
+fn nonce() -> Nonce {
+    Nonce::assume_unique_for_key([0; NONCE_LEN])
+}
+
+/// What the sender of `data` signs.
+fn signed(ephemeral: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut signed = b"pubsub-lite/direct\0".to_vec();
+    signed.extend_from_slice(ephemeral);
+    signed.extend_from_slice(data);
+    signed
+}
+
+fn random() -> Result<[u8; 32], Error> {
+    let mut bytes = [0; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "failed to generate a key")?;
+    Ok(bytes)
+}
+
+/// The keys of a node sealing direct messages: its identity, and the X25519 keys of the peers
+/// it learned.
+pub(crate) struct Direct {
+    key: IdentityKey,
+    keys: HashMap<PeerId, [u8; 32]>,
+}
+
+impl Direct {
+    pub fn new(key: IdentityKey) -> Self {
+        let mut direct = Direct {
+            key,
+            keys: HashMap::new(),
+        };
+        let public = direct.key.public();
+        direct.learn(&PeerId::from(public.clone()), &public);
+        direct
+    }
+
+    /// Remember the public key `key` of `peer`, if it is its identity key.
+    pub fn learn(&mut self, peer: &PeerId, key: &identity::PublicKey) {
+        if PeerId::from(key.clone()) != *peer {
+            return;
+        }
+        if let Some(public) = x25519_public(key) {
+            self.keys.insert(peer.clone(), public);
+        }
+    }
+
+    /// The inbox of this node.
+    pub fn inbox(&self) -> Result<Inbox, Error> {
+        match self.key.keypair() {
+            Some(identity::Keypair::Ed25519(keypair)) => {
+                let seed = keypair.secret();
+                let hash = digest::digest(&digest::SHA512, seed.as_ref());
+                let mut secret = [0; 32];
+                secret.copy_from_slice(&hash.as_ref()[..32]);
+                Ok(Inbox {
+                    public: x25519(secret, X25519_BASEPOINT_BYTES),
+                    secret,
+                })
+            }
+            Some(_) => Err("direct messages need an Ed25519 identity".into()),
+            None => Err("direct messages need the identity key in memory, not in a signer".into()),
+        }
+    }
+
+    /// Seal `data` to `peers`.
+    pub fn seal(&self, peers: &[PeerId], data: &[u8]) -> Result<Bytes, Error> {
+        if peers.is_empty() || peers.len() > MAX_RECIPIENTS {
+            return Err(format!(
+                "a direct message has 1 to {} recipients, not {}",
+                MAX_RECIPIENTS,
+                peers.len()
+            )
+            .into());
+        }
+        let publics = peers
+            .iter()
+            .map(|peer| {
+                self.keys
+                    .get(peer)
+                    .ok_or_else(|| format!("public key of {} is unknown", peer))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let secret = random()?;
+        let ephemeral = x25519(secret, X25519_BASEPOINT_BYTES);
+        let payload_key = random()?;
+        let mut envelope = MARKER.to_vec();
+        envelope.extend_from_slice(&ephemeral);
+        envelope.extend_from_slice(&(peers.len() as u16).to_be_bytes());
+        for public in publics {
+            let mut slot = payload_key.to_vec();
+            slot_key(&x25519(secret, *public), &ephemeral, public)?
+                .seal_in_place_append_tag(nonce(), Aad::empty(), &mut slot)
+                .map_err(|_| "failed to encrypt a direct message")?;
+            envelope.extend_from_slice(&slot);
+        }
+        let key = self.key.public().into_protobuf_encoding();
+        let signature = self.key.sign(&signed(&ephemeral, data))?;
+        let body_start = envelope.len();
+        envelope.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        envelope.extend_from_slice(&key);
+        envelope.extend_from_slice(&(signature.len() as u16).to_be_bytes());
+        envelope.extend_from_slice(&signature);
+        envelope.extend_from_slice(data);
+        let tag = aead_key(&payload_key)
+            .seal_in_place_separate_tag(nonce(), Aad::empty(), &mut envelope[body_start..])
+            .map_err(|_| "failed to encrypt a direct message")?;
+        envelope.extend_from_slice(tag.as_ref());
+        Ok(envelope.into())
+    }
+}
+
+/// The X25519 keys of a node opening the direct messages sealed to it.
+pub(crate) struct Inbox {
+    secret: [u8; 32],
+    public: [u8; 32],
+}
+
+impl Inbox {
+    /// Open a direct message, if it is sealed to this inbox.
+    fn open(&self, data: &[u8]) -> Result<DirectMessage, Error> {
+        let malformed = || PubSubError::codec("malformed direct message");
+        if !data.starts_with(MARKER) {
+            return Err("not a direct message".into());
+        }
+        let rest = &data[MARKER.len()..];
+        let ephemeral = rest.get(..32).ok_or_else(malformed)?;
+        let count = rest.get(32..34).ok_or_else(malformed)?;
+        let count = u16::from_be_bytes([count[0], count[1]]) as usize;
+        let slots = rest.get(34..34 + count * SLOT_LEN).ok_or_else(malformed)?;
+        let mut their_public = [0; 32];
+        their_public.copy_from_slice(ephemeral);
+        let slot_key = slot_key(&x25519(self.secret, their_public), ephemeral, &self.public)?;
+        let payload_key = slots
+            .chunks(SLOT_LEN)
+            .find_map(|slot| {
+                let mut slot = slot.to_vec();
+                let len = slot_key
+                    .open_in_place(nonce(), Aad::empty(), &mut slot)
+                    .ok()?
+                    .len();
+                slot.truncate(len);
+                Some(slot)
+            })
+            .ok_or("direct message is not sealed to this node")?;
+        let mut body = rest[34 + count * SLOT_LEN..].to_vec();
+        let len = aead_key(&payload_key)
+            .open_in_place(nonce(), Aad::empty(), &mut body)
+            .map_err(|_| "failed to decrypt a direct message")?
+            .len();
+        body.truncate(len);
+        let mut body = Bytes::from(body);
+        let mut field = || -> Result<Bytes, Error> {
+            let len = body.get(..2).ok_or_else(malformed)?;
+            let len = 2 + u16::from_be_bytes([len[0], len[1]]) as usize;
+            if len > body.len() {
+                return Err(malformed());
+            }
+            let field = body.slice(2..len);
+            body = body.slice(len..);
+            Ok(field)
+        };
+        let key = identity::PublicKey::from_protobuf_encoding(&field()?)
+            .map_err(|_| PubSubError::codec("malformed public key"))?;
+        let signature = field()?;
+        if !key.verify(&signed(ephemeral, &body), &signature) {
+            return Err("direct message has a bad signature".into());
+        }
+        Ok(DirectMessage {
+            from: PeerId::from(key),
+            data: body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::keypair_from_seed;
+
+    /// A node that knows the keys of the others.
+    fn nodes(count: usize) -> Vec<(PeerId, Direct)> {
+        let keypairs: Vec<_> = (0..count)
+            .map(|i| keypair_from_seed(format!("direct {}", i)))
+            .collect();
+        keypairs
+            .iter()
+            .map(|keypair| {
+                let mut direct = Direct::new(keypair.clone().into());
+                for other in &keypairs {
+                    direct.learn(&PeerId::from(other.public()), &other.public());
+                }
+                (PeerId::from(keypair.public()), direct)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sealed_payload_opens_for_every_recipient_only() {
+        let nodes = nodes(4);
+        let (sender, direct) = &nodes[0];
+        let envelope = direct
+            .seal(&[nodes[1].0.clone(), nodes[2].0.clone()], b"meet at noon")
+            .unwrap();
+        for (_, recipient) in &nodes[1..3] {
+            let message = recipient.inbox().unwrap().open(&envelope).unwrap();
+            assert_eq!(message.from, *sender);
+            assert_eq!(message.data, "meet at noon");
+        }
+        assert!(nodes[3].1.inbox().unwrap().open(&envelope).is_err());
+        assert!(direct.inbox().unwrap().open(&envelope).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_and_truncated_envelopes() {
+        let nodes = nodes(2);
+        let envelope = nodes[0].1.seal(&[nodes[1].0.clone()], b"secret").unwrap();
+        let inbox = nodes[1].1.inbox().unwrap();
+        for i in [
+            MARKER.len(),
+            MARKER.len() + 33,
+            MARKER.len() + 40,
+            envelope.len() - 1,
+        ] {
+            let mut tampered = envelope.to_vec();
+            tampered[i] ^= 1;
+            assert!(inbox.open(&tampered).is_err(), "byte {}", i);
+        }
+        for len in [MARKER.len() + 20, MARKER.len() + 34, envelope.len() - 1] {
+            assert!(inbox.open(&envelope[..len]).is_err(), "length {}", len);
+        }
+        assert!(inbox.open(b"secret").is_err());
+    }
+
+    #[test]
+    fn seals_only_to_known_keys() {
+        let nodes = nodes(1);
+        let stranger = PeerId::from(keypair_from_seed("direct stranger").public());
+        assert!(nodes[0]
+            .1
+            .seal(std::slice::from_ref(&stranger), b"hi")
+            .is_err());
+        assert!(nodes[0].1.seal(&[], b"hi").is_err());
+        // A key is only learned for the peer it identifies.
+        let mut direct = Direct::new(keypair_from_seed("direct 0").into());
+        direct.learn(&stranger, &keypair_from_seed("direct 1").public());
+        assert!(direct.seal(&[stranger], b"hi").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+//! Topic directory: which topics the nodes of a swarm host, so that operators can find the
+//! active channels of a private swarm.
+//!
+//! The directory is opt-in. A node started with
+//! [`NodeConfig::topic_directory`](crate::NodeConfig::topic_directory) set announces on
+//! [`DIRECTORY_TOPIC`] the topics it is subscribed to and those it published on lately, every
+//! [`topic_announce_interval`](crate::NodeConfig::topic_announce_interval), whenever it
+//! subscribes to a topic or publishes on one for the first time, and to every connected peer
+//! joining the directory. It keeps the announcements of
+//! the other nodes taking part, and [`Client::discover_topics`](crate::Client::discover_topics)
+//! aggregates them, its own included, into a [`TopicListing`] per topic. A node whose
+//! announcements stop for [`MISSED_ANNOUNCEMENTS`] of its intervals, e.g. because it left or the
+//! network split, is forgotten.
+//!
+//! Only the nodes taking part are counted. A node joining hears from its connected peers right
+//! away, and from the others as they next announce themselves. Internal topics and wildcard
+//! subscriptions are never listed.
+
+use crate::{topic, Error};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Well-known topic on which the nodes taking part in the directory announce their topics.
+pub const DIRECTORY_TOPIC: &str = "pubsub-lite/directory";
+
+/// Number of announcements a node may miss before it is forgotten.
+pub const MISSED_ANNOUNCEMENTS: u32 = 3;
+
+/// Topics a node hosts, as announced on the directory topic.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Announcement {
+    pub subscribed: Vec<String>,
+    /// Topics published on within the last [`MISSED_ANNOUNCEMENTS`] intervals.
+    pub published: Vec<String>,
+    /// The node announces itself again within `interval_ms` milliseconds.
+    pub interval_ms: u64,
+}
+
+/// A topic hosted in the swarm, as listed by
+/// [`Client::discover_topics`](crate::Client::discover_topics).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TopicListing {
+    pub topic: String,
+    /// Number of nodes subscribed to the topic or publishing on it.
+    pub peers: usize,
+    /// Number of nodes subscribed to the topic.
+    pub subscribers: usize,
+    /// Number of nodes that published on the topic lately.
+    pub publishers: usize,
+}
+
+/// Announcements heard on the directory topic, with when each node is forgotten.
+pub(crate) struct Directory {
+    interval: Duration,
+    hosts: HashMap<PeerId, (Announcement, Instant)>,
+}
+
+impl Directory {
+    /// A directory announcing this node every `interval`.
+    pub fn new(interval: Duration) -> Self {
+        Directory {
+            interval,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// The announcement of a node subscribed to `subscribed`, and that published on the topics
+    /// of `published` when they say.
+    pub fn announcement<'a>(
+        &self,
+        subscribed: Vec<String>,
+        published: impl Iterator<Item = (&'a String, &'a Instant)>,
+    ) -> Announcement {
+        let window = self.interval * MISSED_ANNOUNCEMENTS;
+        Announcement {
+            subscribed,
+            published: published
+                .filter(|(_, last)| last.elapsed() < window)
+                .map(|(topic, _)| topic.clone())
+                .collect(),
+            interval_ms: self.interval.as_millis() as u64,
+        }
+    }
+
+    /// Keep the announcement `data` of `peer`.
+    pub fn accept(&mut self, peer: PeerId, data: &[u8]) -> Result<(), Error> {
+        self.record(peer, serde_json::from_slice(data)?);
+        Ok(())
+    }
+
+    pub fn record(&mut self, peer: PeerId, announcement: Announcement) {
+        let expires =
+            Instant::now() + Duration::from_millis(announcement.interval_ms) * MISSED_ANNOUNCEMENTS;
+        self.hosts.insert(peer, (announcement, expires));
+    }
+
+    /// The topics hosted by the nodes heard from lately, sorted by name.
+    pub fn listings(&mut self) -> Vec<TopicListing> {
+        let now = Instant::now();
+        self.hosts.retain(|_, (_, expires)| *expires > now);
+        let mut listings: BTreeMap<&str, TopicListing> = BTreeMap::new();
+        for (announcement, _) in self.hosts.values() {
+            let hosted: BTreeSet<&String> = announcement
+                .subscribed
+                .iter()
+                .chain(&announcement.published)
+                .filter(|topic| !topic::is_internal(topic))
+                .collect();
+            for topic in hosted {
+                let listing = listings.entry(topic).or_insert_with(|| TopicListing {
+                    topic: topic.clone(),
+                    peers: 0,
+                    subscribers: 0,
+                    publishers: 0,
+                });
+                listing.peers += 1;
+                listing.subscribers += announcement.subscribed.contains(topic) as usize;
+                listing.publishers += announcement.published.contains(topic) as usize;
+            }
+        }
+        listings.into_values().collect()
+    }
+}
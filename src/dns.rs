@@ -0,0 +1,317 @@
+//! Dialing `/dnsaddr/` addresses, such as those of the bootstrap lists of go-ipfs configs.
+//!
+//! libp2p 0.16 resolves the `/dns4/` and `/dns6/` components of an address when dialing it, but
+//! not `/dnsaddr/<domain>`, whose addresses are listed by the TXT records of
+//! `_dnsaddr.<domain>`, as `dnsaddr=<multiaddr>`. [`DnsaddrTransport`] looks them up, keeps those
+//! ending like the address dialed, typically with its `/p2p/<peer id>`, resolves those that are
+//! `/dnsaddr/` addresses in turn, and dials the others in order until one connects.
+//!
+//! The lookup asks the nameservers of `/etc/resolv.conf` over UDP, and over TCP if the answer
+//! does not fit in a datagram.
+
+use async_std::{
+    fs, io,
+    net::{SocketAddr, TcpStream, UdpSocket},
+};
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData, ResponseCode};
+use futures::{
+    future::{self, BoxFuture, Either},
+    prelude::*,
+    stream::{MapErr, MapOk},
+};
+use libp2p::{
+    core::transport::{ListenerEvent, TransportError},
+    multiaddr::Protocol,
+    Multiaddr, Transport,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{error, fmt, time::Duration};
+
+/// How long a nameserver has to answer a lookup.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How deep `/dnsaddr/` addresses may list other `/dnsaddr/` addresses.
+const MAX_DEPTH: usize = 4;
+
+/// Transport dialing `/dnsaddr/` addresses through the addresses they resolve to, and any other
+/// address directly, with the transport it wraps.
+#[derive(Clone)]
+pub struct DnsaddrTransport<T> {
+    inner: T,
+}
+
+impl<T> DnsaddrTransport<T> {
+    pub fn new(inner: T) -> Self {
+        DnsaddrTransport { inner }
+    }
+}
+
+/// Error of a [`DnsaddrTransport`].
+#[derive(Debug)]
+pub enum DnsaddrError<E> {
+    /// A `/dnsaddr/` address failed to resolve, or resolved to no address that could be dialed.
+    Resolve(io::Error),
+    /// The wrapped transport failed.
+    Underlying(E),
+}
+
+impl<E: fmt::Display> fmt::Display for DnsaddrError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsaddrError::Resolve(e) => write!(f, "failed to resolve a dnsaddr address: {}", e),
+            DnsaddrError::Underlying(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error + 'static> error::Error for DnsaddrError<E> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DnsaddrError::Resolve(e) => Some(e),
+            DnsaddrError::Underlying(e) => Some(e),
+        }
+    }
+}
+
+type MapEvent<U, E> =
+    fn(
+        ListenerEvent<U, E>,
+    ) -> ListenerEvent<future::MapErr<U, fn(E) -> DnsaddrError<E>>, DnsaddrError<E>>;
+
+impl<T> Transport for DnsaddrTransport<T>
+where
+    T: Transport + Clone + Send + 'static,
+    T::Error: Send,
+    T::Dial: Send,
+    T::Output: Send,
+{
+    type Output = T::Output;
+    type Error = DnsaddrError<T::Error>;
+    type Listener = MapErr<
+        MapOk<T::Listener, MapEvent<T::ListenerUpgrade, T::Error>>,
+        fn(T::Error) -> Self::Error,
+    >;
+    type ListenerUpgrade = future::MapErr<T::ListenerUpgrade, fn(T::Error) -> Self::Error>;
+    type Dial = Either<
+        future::MapErr<T::Dial, fn(T::Error) -> Self::Error>,
+        BoxFuture<'static, Result<T::Output, Self::Error>>,
+    >;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        let listener = self
+            .inner
+            .listen_on(addr)
+            .map_err(|e| e.map(DnsaddrError::Underlying))?;
+        Ok(listener
+            .map_ok::<_, MapEvent<_, _>>(|event| {
+                event
+                    .map(|upgrade| upgrade.map_err::<_, fn(_) -> _>(DnsaddrError::Underlying))
+                    .map_err(DnsaddrError::Underlying)
+            })
+            .map_err::<_, fn(_) -> _>(DnsaddrError::Underlying))
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        match addr.iter().next() {
+            Some(Protocol::Dnsaddr(_)) => Ok(Either::Right(dial(self.inner, addr).boxed())),
+            _ => {
+                let dial = self
+                    .inner
+                    .dial(addr)
+                    .map_err(|e| e.map(DnsaddrError::Underlying))?;
+                Ok(Either::Left(
+                    dial.map_err::<_, fn(_) -> _>(DnsaddrError::Underlying),
+                ))
+            }
+        }
+    }
+}
+
+/// Dial the addresses `addr` resolves to in order, until one connects.
+async fn dial<T: Transport + Clone>(
+    inner: T,
+    addr: Multiaddr,
+) -> Result<T::Output, DnsaddrError<T::Error>> {
+    let resolved = resolve_dnsaddr(&addr)
+        .await
+        .map_err(DnsaddrError::Resolve)?;
+    let mut last_error = None;
+    for resolved in resolved {
+        match inner.clone().dial(resolved.clone()) {
+            Ok(dial) => match dial.await {
+                Ok(output) => return Ok(output),
+                Err(e) => {
+                    log::debug!("failed to dial {}, resolved from {}", resolved, addr);
+                    last_error = Some(DnsaddrError::Underlying(e));
+                }
+            },
+            Err(TransportError::MultiaddrNotSupported(_)) => {}
+            Err(TransportError::Other(e)) => last_error = Some(DnsaddrError::Underlying(e)),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        DnsaddrError::Resolve(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolves to no address that can be dialed", addr),
+        ))
+    }))
+}
+
+/// Resolve an address starting with `/dnsaddr/<domain>` into the addresses listed for the domain
+/// that end like it, resolving the `/dnsaddr/` addresses among them in turn.
+pub async fn resolve_dnsaddr(addr: &Multiaddr) -> io::Result<Vec<Multiaddr>> {
+    resolve(addr.clone(), MAX_DEPTH).await
+}
+
+fn resolve(addr: Multiaddr, depth: usize) -> BoxFuture<'static, io::Result<Vec<Multiaddr>>> {
+    async move {
+        let mut protocols = addr.iter();
+        let domain = match protocols.next() {
+            Some(Protocol::Dnsaddr(domain)) => domain.into_owned(),
+            _ => return Ok(vec![addr.clone()]),
+        };
+        if depth == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} lists dnsaddr addresses too deep", addr),
+            ));
+        }
+        let suffix: Vec<Protocol> = protocols.collect();
+        let mut resolved = Vec::new();
+        for record in lookup_txt(&format!("_dnsaddr.{}", domain)).await? {
+            let listed = match record.strip_prefix("dnsaddr=") {
+                Some(listed) => listed,
+                None => continue,
+            };
+            let listed: Multiaddr = match listed.parse() {
+                Ok(listed) => listed,
+                Err(e) => {
+                    log::debug!("ignoring malformed address {} of {}: {}", listed, domain, e);
+                    continue;
+                }
+            };
+            if !listed.iter().collect::<Vec<_>>().ends_with(&suffix) {
+                continue;
+            }
+            match resolve(listed.clone(), depth - 1).await {
+                Ok(addrs) => resolved.extend(addrs),
+                Err(e) => log::debug!("failed to resolve {}: {}", listed, e),
+            }
+        }
+        Ok(resolved)
+    }
+    .boxed()
+}
+
+/// Look up the TXT records of `name`, asking each nameserver in turn until one answers.
+async fn lookup_txt(name: &str) -> io::Result<Vec<String>> {
+    let name = name.trim_end_matches('.');
+    if name
+        .split('.')
+        .any(|label| label.is_empty() || label.len() >= 63)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a valid domain name", name),
+        ));
+    }
+    let mut id = [0; 2];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| io::Error::other("failed to pick a query id"))?;
+    let id = u16::from_be_bytes(id);
+    let mut query = Builder::new_query(id, true);
+    query.add_question(name, false, QueryType::TXT, QueryClass::IN);
+    let query = query
+        .build()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS query too long"))?;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no nameserver configured");
+    for server in nameservers().await? {
+        match io::timeout(LOOKUP_TIMEOUT, ask(server, id, &query)).await {
+            Ok(records) => return Ok(records),
+            Err(e) => {
+                log::debug!("nameserver {} failed to look up {}: {}", server, name, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// The nameservers of `/etc/resolv.conf`.
+async fn nameservers() -> io::Result<Vec<SocketAddr>> {
+    let conf = fs::read_to_string("/etc/resolv.conf").await?;
+    Ok(conf
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(ip)) => ip.parse().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect())
+}
+
+/// Send `query` to `server` over UDP, then over TCP if the answer was truncated, and return the
+/// TXT records of the answer.
+async fn ask(server: SocketAddr, id: u16, query: &[u8]) -> io::Result<Vec<String>> {
+    let local: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.send_to(query, server).await?;
+    let mut buf = vec![0; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from != server {
+            continue;
+        }
+        match Packet::parse(&buf[..len]) {
+            Ok(packet) if packet.header.id == id && packet.header.truncated => break,
+            Ok(packet) if packet.header.id == id => return txt_records(&packet),
+            _ => continue,
+        }
+    }
+    let mut stream = TcpStream::connect(server).await?;
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    let packet = Packet::parse(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if packet.header.id != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "answer to another query",
+        ));
+    }
+    txt_records(&packet)
+}
+
+fn txt_records(packet: &Packet) -> io::Result<Vec<String>> {
+    match packet.header.response_code {
+        ResponseCode::NoError => {}
+        ResponseCode::NameError => return Ok(Vec::new()),
+        code => return Err(io::Error::other(format!("nameserver answered {:?}", code))),
+    }
+    Ok(packet
+        .answers
+        .iter()
+        .filter_map(|answer| match &answer.data {
+            RData::TXT(txt) => Some(
+                txt.iter()
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>()
+                    .concat(),
+            ),
+            _ => None,
+        })
+        .collect())
+}
@@ -0,0 +1,641 @@
+//! Subscriptions resuming where their consumer left off, across restarts.
+//!
+//! A [`DurableSubscription`], returned by [`Client::subscribe_durable`], writes every message of
+//! its topic to a journal file as soon as the node delivers it, numbering them, and hands them
+//! out in that order. Its consumer [acknowledges](DurableSubscription::ack) the messages it is
+//! done with, moving a cursor written next to the journal, in a file of the same name ending
+//! with `.cursor`. A durable subscription opened again on the same journal, after the process
+//! restarted or crashed, first hands out the journaled messages past the cursor, then new ones:
+//! the messages that made it to the journal are never skipped, and never handed out again once
+//! acknowledged.
+//!
+//! Opened again, the subscription also asks the node to [replay](crate::retention) the messages
+//! it retains since shortly before the last journaled one, catching up on those delivered while
+//! it was closed, if the node kept running or retains them. Messages journaled already, whether
+//! replayed or delivered twice by gossip, are recognized by their id and dropped.
+//!
+//! A consumer acknowledging every message once it processed it processes each at least once,
+//! and exactly once unless it stops between processing a message and acknowledging it; one
+//! committing its results along with the acknowledgement has no such window. The journal is
+//! synced to disk message by message, and compacted as acknowledged messages pile up, keeping
+//! the last [`DEDUP_WINDOW`] for their ids. Messages not taken by the consumer yet are held in
+//! memory as well as journaled. A journal is used by one subscription at a time.
+//!
+//! Opened with [`Client::subscribe_durable_in`], the journal and its cursor are kept in a
+//! [`Storage`] instead, under the name of the journal, see the [`storage`](crate::storage)
+//! module. Compacting it prunes the messages of its log.
+
+use crate::{
+    attestation::SignatureStatus,
+    client::{Client, Subscription},
+    delegation::{Origin, PublicKey},
+    headers::Headers,
+    retention::Replay,
+    storage::Storage,
+    Error, Message,
+};
+use async_std::task;
+use data_encoding::BASE64;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use libp2p::gossipsub::MessageId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Journaled messages whose ids are remembered to drop them when delivered again, acknowledged
+/// or not.
+pub const DEDUP_WINDOW: usize = 4096;
+
+/// How long before the last journaled message the messages retained by the node are replayed
+/// from when a subscription is opened again.
+pub const REPLAY_OVERLAP: Duration = Duration::from_secs(60);
+
+/// Journals open in this process, with how far each got.
+static OPEN_JOURNALS: Mutex<Vec<(PathBuf, Arc<Progress>)>> = Mutex::new(Vec::new());
+
+/// How far a journal got, shared by its subscription, its journaling task and [`journals`].
+#[derive(Default)]
+struct Progress {
+    /// Position of the last journaled message.
+    journaled: AtomicU64,
+    /// Position of the last acknowledged message.
+    acknowledged: AtomicU64,
+    /// Why journaling stopped, if it failed.
+    error: Mutex<Option<String>>,
+}
+
+/// The state of a journal open in this process, as reported by [`journals`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalStatus {
+    /// The journal file, or `journal/<name>` for a journal kept in a storage.
+    pub path: PathBuf,
+    /// Size of the journal file in bytes, 0 for a journal kept in a storage.
+    pub bytes: u64,
+    /// Position of the last journaled message.
+    pub journaled: u64,
+    /// Position of the last acknowledged message, 0 if none was.
+    pub acknowledged: u64,
+    /// Why the journal is unusable, if it is: writing to it failed, which stops its
+    /// subscription, or its file cannot be read.
+    pub error: Option<String>,
+}
+
+/// The journals of the durable subscriptions open in this process.
+pub fn journals() -> Vec<JournalStatus> {
+    let open = OPEN_JOURNALS.lock().unwrap();
+    open.iter()
+        .map(|(path, progress)| {
+            let error = progress.error.lock().unwrap().clone();
+            let (bytes, error) = match fs::metadata(path) {
+                Ok(metadata) => (metadata.len(), error),
+                // Nothing was journaled yet.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (0, error),
+                Err(e) => (0, error.or_else(|| Some(e.to_string()))),
+            };
+            JournalStatus {
+                path: path.clone(),
+                bytes,
+                journaled: progress.journaled.load(Ordering::SeqCst),
+                acknowledged: progress.acknowledged.load(Ordering::SeqCst),
+                error,
+            }
+        })
+        .collect()
+}
+
+/// A journaled message, one per line.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) position: u64,
+    /// Milliseconds since the Unix epoch when the message was journaled.
+    journaled: u64,
+    pub(crate) id: String,
+    source: String,
+    pub(crate) topic: String,
+    /// Base64 payload.
+    data: String,
+    sequence_number: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<OriginEntry>,
+    /// Whether the source of the message was verified, unsigned in journals written before.
+    #[serde(default)]
+    signature: SignatureStatus,
+    /// Microseconds since the Unix epoch when the message was published, if timestamped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    published_at: Option<u64>,
+    /// W3C `traceparent` of the span that published the message, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    traceparent: Option<String>,
+    /// Headers the message was published with.
+    #[serde(default, skip_serializing_if = "Headers::is_empty")]
+    headers: Headers,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OriginEntry {
+    org: PublicKey,
+    key: PublicKey,
+}
+
+impl Entry {
+    pub(crate) fn new(position: u64, message: &Message) -> Self {
+        Entry {
+            position,
+            journaled: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            id: message.id.0.clone(),
+            source: message.source.to_base58(),
+            topic: message.topic.clone(),
+            data: BASE64.encode(&message.data),
+            sequence_number: message.sequence_number,
+            origin: message.origin.map(|origin| OriginEntry {
+                org: origin.org,
+                key: origin.key,
+            }),
+            signature: message.signature,
+            published_at: message.published_at.and_then(|at| {
+                at.duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|since| since.as_micros() as u64)
+            }),
+            traceparent: message.trace_context.map(|context| context.to_string()),
+            headers: message.headers.clone(),
+        }
+    }
+
+    /// When the message was journaled.
+    pub(crate) fn journaled_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.journaled)
+    }
+
+    pub(crate) fn message(&self) -> Result<Message, Error> {
+        Ok(Message {
+            id: MessageId(self.id.clone()),
+            source: self
+                .source
+                .parse()
+                .map_err(|_| format!("invalid peer id {}", self.source))?,
+            topic: self.topic.clone(),
+            data: BASE64.decode(self.data.as_bytes())?.into(),
+            sequence_number: self.sequence_number,
+            origin: self.origin.as_ref().map(|origin| Origin {
+                org: origin.org,
+                key: origin.key,
+            }),
+            signature: self.signature,
+            published_at: self
+                .published_at
+                .map(|micros| UNIX_EPOCH + Duration::from_micros(micros)),
+            trace_context: self.traceparent.as_deref().map(str::parse).transpose()?,
+            headers: self.headers.clone(),
+        })
+    }
+}
+
+/// Journaled messages past the cursor, with their positions.
+type Backlog = Vec<(u64, Message)>;
+
+/// A journal taken by a subscription, released when dropped.
+pub(crate) struct InUse(PathBuf, Arc<Progress>);
+
+impl InUse {
+    pub(crate) fn take(path: PathBuf) -> Result<Self, Error> {
+        let mut open = OPEN_JOURNALS.lock().unwrap();
+        if open.iter().any(|(open, _)| *open == path) {
+            return Err(format!("journal {} is in use", path.display()).into());
+        }
+        let progress = Arc::new(Progress::default());
+        open.push((path.clone(), progress.clone()));
+        Ok(InUse(path, progress))
+    }
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        OPEN_JOURNALS
+            .lock()
+            .unwrap()
+            .retain(|(open, _)| *open != self.0);
+    }
+}
+
+/// Where a journal keeps its messages and its cursor.
+#[derive(Clone)]
+enum Backend {
+    /// The journal file at the path, the cursor in a file next to it.
+    File(PathBuf),
+    /// The log `journal/<name>` of a storage, and the cursor of the same name.
+    Storage(Arc<dyn Storage>, String),
+}
+
+impl Backend {
+    fn read_cursor(&self) -> Result<u64, Error> {
+        match self {
+            Backend::File(path) => read_cursor(&cursor_path(path)),
+            Backend::Storage(storage, log) => storage.cursor(log),
+        }
+    }
+
+    fn write_cursor(&self, position: u64) -> Result<(), Error> {
+        match self {
+            Backend::File(path) => {
+                let path = cursor_path(path);
+                let mut tmp = path.as_os_str().to_owned();
+                tmp.push(".tmp");
+                fs::write(&tmp, position.to_string())?;
+                fs::rename(&tmp, &path)?;
+                Ok(())
+            }
+            Backend::Storage(storage, log) => storage.set_cursor(log, position),
+        }
+    }
+}
+
+/// The journal of a subscription, written by its journaling task.
+struct Journal {
+    path: PathBuf,
+    _in_use: InUse,
+    backend: Backend,
+    /// The journal file, if the journal is one.
+    file: Option<File>,
+    /// Position of the last journaled message.
+    last: u64,
+    /// Position of the first message kept.
+    first: u64,
+    /// Ids of the last journaled messages, oldest first.
+    recent: VecDeque<String>,
+    ids: HashSet<String>,
+    /// Positions of the last journaled and acknowledged messages, and why journaling failed.
+    progress: Arc<Progress>,
+}
+
+impl Journal {
+    /// Open the journal taken with `in_use`, kept in `backend`, returning it with the messages
+    /// past `cursor` and when the last message was journaled.
+    fn open(
+        in_use: InUse,
+        backend: Backend,
+        cursor: u64,
+    ) -> Result<(Self, Backlog, Option<SystemTime>), Error> {
+        let path = in_use.0.clone();
+        let (entries, file) = match &backend {
+            Backend::File(path) => {
+                let (entries, truncated) = read_entries(path)?;
+                if truncated {
+                    log::warn!("dropping the truncated end of journal {}", path.display());
+                    write_entries(path, entries.iter())?;
+                }
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let entries = entries
+                    .iter()
+                    .map(|entry| Ok((entry.position, entry.journaled_at(), entry.message()?)))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                (entries, Some(file))
+            }
+            Backend::Storage(storage, log) => {
+                // Only what is past the cursor or in the deduplication window is needed.
+                let last = storage.last(log)?;
+                let from = (cursor + 1).min(last.saturating_sub(DEDUP_WINDOW as u64) + 1);
+                let entries = storage
+                    .scan(log, from..u64::MAX)?
+                    .into_iter()
+                    .map(|stored| (stored.seq, stored.stored_at, stored.message))
+                    .collect();
+                (entries, None)
+            }
+        };
+        let progress = in_use.1.clone();
+        progress.acknowledged.store(cursor, Ordering::SeqCst);
+        let mut journal = Journal {
+            path,
+            _in_use: in_use,
+            backend,
+            file,
+            last: cursor,
+            first: entries
+                .first()
+                .map_or(cursor + 1, |(position, _, _)| *position),
+            recent: VecDeque::new(),
+            ids: HashSet::new(),
+            progress,
+        };
+        if let Backend::Storage(storage, log) = &journal.backend {
+            journal.last = journal.last.max(storage.last(log)?);
+        }
+        let journaled = entries.last().map(|(_, journaled, _)| *journaled);
+        let mut backlog = Vec::new();
+        for (position, _, message) in entries {
+            journal.last = journal.last.max(position);
+            journal.remember(message.id.0.clone());
+            if position > cursor {
+                backlog.push((position, message));
+            }
+        }
+        journal
+            .progress
+            .journaled
+            .store(journal.last, Ordering::SeqCst);
+        Ok((journal, backlog, journaled))
+    }
+
+    fn remember(&mut self, id: String) {
+        if self.recent.len() >= DEDUP_WINDOW {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.clone());
+        self.recent.push_back(id);
+    }
+
+    /// Write `message` to the journal and sync it, returning its position.
+    fn append(&mut self, message: &Message) -> Result<u64, Error> {
+        let position = match (&self.backend, &mut self.file) {
+            (Backend::Storage(storage, log), _) => storage.append(log, message)?,
+            (Backend::File(_), Some(file)) => {
+                let position = self.last + 1;
+                let mut line = serde_json::to_vec(&Entry::new(position, message))?;
+                line.push(b'\n');
+                file.write_all(&line)?;
+                file.sync_data()?;
+                position
+            }
+            (Backend::File(path), None) => {
+                return Err(format!("journal {} is not open", path.display()).into())
+            }
+        };
+        self.last = position;
+        self.progress.journaled.store(position, Ordering::SeqCst);
+        self.remember(message.id.0.clone());
+        if self.compactable() >= DEDUP_WINDOW as u64 {
+            if let Err(e) = self.compact() {
+                log::warn!("failed to compact journal {}: {}", self.path.display(), e);
+            }
+        }
+        Ok(position)
+    }
+
+    /// Number of messages at the start of the file that are acknowledged and out of the
+    /// deduplication window.
+    fn compactable(&self) -> u64 {
+        let keep_from = self
+            .progress
+            .acknowledged
+            .load(Ordering::SeqCst)
+            .min(self.last.saturating_sub(DEDUP_WINDOW as u64))
+            + 1;
+        keep_from.saturating_sub(self.first)
+    }
+
+    /// Rewrite the journal without the messages [`compactable`](Self::compactable) counts.
+    fn compact(&mut self) -> Result<(), Error> {
+        let keep_from = self.first + self.compactable();
+        match &self.backend {
+            Backend::File(path) => {
+                let (entries, _) = read_entries(path)?;
+                let kept = entries.iter().filter(|entry| entry.position >= keep_from);
+                write_entries(path, kept)?;
+                self.file = Some(OpenOptions::new().append(true).open(path)?);
+            }
+            Backend::Storage(storage, log) => {
+                storage.prune(log, keep_from)?;
+            }
+        }
+        self.first = keep_from;
+        Ok(())
+    }
+}
+
+/// The entries of the journal at `path`, none if there is no such file, and whether its last
+/// line was cut short by a crash, which is left out.
+pub(crate) fn read_entries(path: &Path) -> Result<(Vec<Entry>, bool), Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+        Err(e) => return Err(e.into()),
+    };
+    let lines = BufReader::new(file)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut entries = Vec::with_capacity(lines.len());
+    for (index, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if index + 1 == lines.len() => return Ok((entries, true)),
+            Err(e) => return Err(format!("invalid journal {}: {}", path.display(), e).into()),
+        }
+    }
+    Ok((entries, false))
+}
+
+/// Replace the journal at `path` with `entries` at once.
+pub(crate) fn write_entries<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = &'a Entry>,
+) -> Result<(), Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    for entry in entries {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The cursor file of the journal at `path`.
+pub(crate) fn cursor_path(path: &Path) -> PathBuf {
+    let mut cursor = path.as_os_str().to_owned();
+    cursor.push(".cursor");
+    PathBuf::from(cursor)
+}
+
+pub(crate) fn read_cursor(path: &Path) -> Result<u64, Error> {
+    match fs::read_to_string(path) {
+        Ok(cursor) => cursor
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid cursor {}: {}", path.display(), e).into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A subscription journaling its messages and resuming from the last one acknowledged, see the
+/// [module documentation](self).
+pub struct DurableSubscription {
+    path: PathBuf,
+    backend: Backend,
+    receiver: mpsc::UnboundedReceiver<Result<(u64, Message), Error>>,
+    /// Positions of the messages handed out and not acknowledged yet, oldest first.
+    unacked: VecDeque<(MessageId, u64)>,
+    progress: Arc<Progress>,
+    /// Stops the journaling task when dropped.
+    _stop: oneshot::Sender<()>,
+}
+
+impl DurableSubscription {
+    /// The journal of the subscription, `journal/<name>` for a journal kept in a storage.
+    pub fn journal(&self) -> &Path {
+        &self.path
+    }
+
+    /// Position of the last acknowledged message, 0 if none was.
+    pub fn cursor(&self) -> u64 {
+        self.progress.acknowledged.load(Ordering::SeqCst)
+    }
+
+    /// Acknowledge `message` and every message handed out before it, writing the cursor to disk,
+    /// or to the storage of the journal. Fails if `message` was not handed out by this
+    /// subscription or was acknowledged already.
+    pub fn ack(&mut self, message: &Message) -> Result<(), Error> {
+        let index = self
+            .unacked
+            .iter()
+            .position(|(id, _)| *id == message.id)
+            .ok_or_else(|| format!("message {} is not waiting for acknowledgement", message.id))?;
+        let position = self.unacked[index].1;
+        self.backend.write_cursor(position)?;
+        self.unacked.drain(..=index);
+        self.progress.acknowledged.store(position, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Stream for DurableSubscription {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match self.receiver.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok((position, message)))) => {
+                self.unacked.push_back((message.id.clone(), position));
+                Poll::Ready(Some(Ok(message)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The absolute path of the journal at `path`, the one it is known by while in use.
+pub(crate) fn canonical_path(path: &Path) -> Result<PathBuf, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("journal {} is not a file", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(dir)?.join(name))
+}
+
+/// Open the journal at `path` and subscribe to `topic` through it.
+pub(crate) fn subscribe(
+    client: &Client,
+    topic: &str,
+    path: &Path,
+) -> Result<DurableSubscription, Error> {
+    let path = canonical_path(path)?;
+    open(client, topic, path.clone(), Backend::File(path))
+}
+
+/// Open the journal `name` kept in `storage` and subscribe to `topic` through it.
+pub(crate) fn subscribe_in(
+    client: &Client,
+    topic: &str,
+    storage: Arc<dyn Storage>,
+    name: &str,
+) -> Result<DurableSubscription, Error> {
+    let log = format!("journal/{}", name);
+    open(
+        client,
+        topic,
+        PathBuf::from(&log),
+        Backend::Storage(storage, log),
+    )
+}
+
+fn open(
+    client: &Client,
+    topic: &str,
+    path: PathBuf,
+    backend: Backend,
+) -> Result<DurableSubscription, Error> {
+    let in_use = InUse::take(path.clone())?;
+    let cursor = backend.read_cursor()?;
+    let (journal, backlog, journaled) = Journal::open(in_use, backend.clone(), cursor)?;
+    let replay = match journaled {
+        Some(journaled) => Replay::Since(journaled - REPLAY_OVERLAP),
+        None => Replay::None,
+    };
+    let subscription = client.subscribe_replay(topic, replay)?;
+    let (sender, receiver) = mpsc::unbounded();
+    for entry in backlog {
+        let _ = sender.unbounded_send(Ok(entry));
+    }
+    let (stop, stopped) = oneshot::channel();
+    let progress = journal.progress.clone();
+    task::spawn(run_journal(journal, subscription, sender, stopped));
+    Ok(DurableSubscription {
+        path,
+        backend,
+        receiver,
+        unacked: VecDeque::new(),
+        progress,
+        _stop: stop,
+    })
+}
+
+/// Journal the messages of `subscription` and hand them to the durable subscription, until it
+/// is dropped. A journal that could not be written to stays taken, and reported failing by
+/// [`journals`], until then.
+async fn run_journal(
+    mut journal: Journal,
+    mut subscription: Subscription,
+    sender: mpsc::UnboundedSender<Result<(u64, Message), Error>>,
+    stopped: oneshot::Receiver<()>,
+) {
+    let mut stopped = stopped.fuse();
+    loop {
+        let message = futures::select! {
+            message = subscription.next().fuse() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = stopped => break,
+        };
+        if journal.ids.contains(&message.id.0) {
+            continue;
+        }
+        let journaled = journal.append(&message).map(|position| (position, message));
+        let failed = journaled.as_ref().err().map(ToString::to_string);
+        if sender.unbounded_send(journaled).is_err() {
+            break;
+        }
+        if let Some(e) = failed {
+            log::warn!("failed to journal to {}: {}", journal.path.display(), e);
+            *journal.progress.error.lock().unwrap() = Some(e);
+            let _ = stopped.await;
+            break;
+        }
+    }
+}
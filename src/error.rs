@@ -0,0 +1,198 @@
+//! Errors of the library.
+//!
+//! Every fallible function of the crate fails with a [`PubSubError`], whose variants tell what
+//! failed: the transport, dialing a peer, publishing or subscribing, encoding or decoding, or the
+//! configuration. The error that caused it, of whatever type, is its
+//! [`source`](std::error::Error::source), for those who need to tell further:
+//!
+//! ```ignore
+//! match client.publish("alerts", payload) {
+//!     Err(PubSubError::Publish { cause, .. }) if cause.is::<MessageTooLarge>() => shrink(),
+//!     Err(PubSubError::Shutdown) => restart(),
+//!     result => result?,
+//! }
+//! ```
+//!
+//! Failures the node carries on from, once the command that led to them is accepted, such as a
+//! dial or the encoding of a message failing, are reported as
+//! [`NodeEvent::Error`](crate::NodeEvent::Error).
+
+use crate::{schema::TypeMismatch, size::MessageTooLarge, topic::InvalidFilter};
+use libp2p::Multiaddr;
+use std::{fmt, io};
+use thiserror::Error;
+
+/// The error that caused a [`PubSubError`], of any type.
+pub type Cause = Box<dyn std::error::Error + Send + Sync>;
+
+/// An error of the library, see the [module documentation](self).
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PubSubError {
+    /// Setting up the transport, or listening on an address, failed.
+    #[error("transport: {0}")]
+    Transport(#[source] Cause),
+    /// Dialing `addr` failed.
+    #[error("failed to dial {addr}: {cause}")]
+    Dial {
+        addr: Multiaddr,
+        #[source]
+        cause: Cause,
+    },
+    /// Publishing on `topic` failed, e.g. with a [`MessageTooLarge`] or a [`TypeMismatch`].
+    #[error("failed to publish on {topic}: {cause}")]
+    Publish {
+        topic: String,
+        #[source]
+        cause: Cause,
+    },
+    /// Subscribing to `topic`, a topic or a filter, failed.
+    #[error("failed to subscribe to {topic}: {cause}")]
+    Subscribe {
+        topic: String,
+        #[source]
+        cause: Cause,
+    },
+    /// Encoding or decoding failed: JSON, Protobuf, base64, or an envelope of a message.
+    #[error("codec: {0}")]
+    Codec(#[source] Cause),
+    /// The configuration is invalid.
+    #[error("invalid configuration: {0}")]
+    Config(#[source] Cause),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The node shut down, every client having been dropped.
+    #[error("node has shut down")]
+    Shutdown,
+    /// Anything else.
+    #[error(transparent)]
+    Other(Cause),
+}
+
+impl PubSubError {
+    /// A [`Codec`](Self::Codec) error.
+    pub fn codec(cause: impl Into<Cause>) -> Self {
+        PubSubError::Codec(cause.into())
+    }
+
+    /// A [`Config`](Self::Config) error.
+    pub fn config(cause: impl Into<Cause>) -> Self {
+        PubSubError::Config(cause.into())
+    }
+
+    /// A [`Transport`](Self::Transport) error.
+    pub fn transport(cause: impl Into<Cause>) -> Self {
+        PubSubError::Transport(cause.into())
+    }
+
+    /// A [`Dial`](Self::Dial) error of `addr`.
+    pub fn dial(addr: Multiaddr, cause: impl Into<Cause>) -> Self {
+        PubSubError::Dial {
+            addr,
+            cause: cause.into(),
+        }
+    }
+
+    /// An [`Other`](Self::Other) error.
+    pub fn other(cause: impl Into<Cause>) -> Self {
+        PubSubError::Other(cause.into())
+    }
+
+    /// A [`Publish`](Self::Publish) error on `topic`.
+    pub fn publish(topic: impl Into<String>, cause: impl Into<Cause>) -> Self {
+        PubSubError::Publish {
+            topic: topic.into(),
+            cause: cause.into(),
+        }
+    }
+
+    /// A [`Subscribe`](Self::Subscribe) error on `topic`.
+    pub fn subscribe(topic: impl Into<String>, cause: impl Into<Cause>) -> Self {
+        PubSubError::Subscribe {
+            topic: topic.into(),
+            cause: cause.into(),
+        }
+    }
+
+    /// The cause of the error if it is of type `E`, e.g. [`MessageTooLarge`].
+    pub fn cause<E: std::error::Error + 'static>(&self) -> Option<&E> {
+        match self {
+            PubSubError::Transport(cause)
+            | PubSubError::Dial { cause, .. }
+            | PubSubError::Publish { cause, .. }
+            | PubSubError::Subscribe { cause, .. }
+            | PubSubError::Codec(cause)
+            | PubSubError::Config(cause)
+            | PubSubError::Other(cause) => cause.downcast_ref(),
+            PubSubError::Io(_) | PubSubError::Shutdown => None,
+        }
+    }
+}
+
+/// A plain message, the cause of errors built from strings.
+#[derive(Debug)]
+struct Message(String);
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Message {}
+
+/// An [`Other`](PubSubError::Other) error described by `message`, for failures no other variant
+/// fits: decoding ones are [`codec`](PubSubError::codec) errors.
+impl From<String> for PubSubError {
+    fn from(message: String) -> Self {
+        PubSubError::Other(Box::new(Message(message)))
+    }
+}
+
+impl From<&str> for PubSubError {
+    fn from(message: &str) -> Self {
+        message.to_owned().into()
+    }
+}
+
+impl From<Cause> for PubSubError {
+    fn from(cause: Cause) -> Self {
+        PubSubError::Other(cause)
+    }
+}
+
+impl From<MessageTooLarge> for PubSubError {
+    fn from(e: MessageTooLarge) -> Self {
+        PubSubError::publish(e.topic.clone(), e)
+    }
+}
+
+impl From<TypeMismatch> for PubSubError {
+    fn from(e: TypeMismatch) -> Self {
+        PubSubError::publish(e.topic.clone(), e)
+    }
+}
+
+impl From<InvalidFilter> for PubSubError {
+    fn from(e: InvalidFilter) -> Self {
+        PubSubError::config(e)
+    }
+}
+
+impl From<async_std::future::TimeoutError> for PubSubError {
+    fn from(e: async_std::future::TimeoutError) -> Self {
+        PubSubError::Io(io::Error::new(io::ErrorKind::TimedOut, e))
+    }
+}
+
+impl From<serde_json::Error> for PubSubError {
+    fn from(e: serde_json::Error) -> Self {
+        PubSubError::codec(e)
+    }
+}
+
+impl From<data_encoding::DecodeError> for PubSubError {
+    fn from(e: data_encoding::DecodeError) -> Self {
+        PubSubError::codec(e)
+    }
+}
@@ -0,0 +1,131 @@
+//! Floodsub alongside or instead of gossipsub, to share topics with peers speaking floodsub
+//! only, such as go-ipfs nodes running the default router of `--enable-pubsub-experiment`.
+//!
+//! The [`Router`] of [`NodeConfig::router`](crate::NodeConfig::router) tells which protocols
+//! carry the messages of the topics of the node. Floodsub sends every message to every
+//! connected peer subscribed to its topic, so it only suits small swarms; peers are told of the
+//! floodsub subscriptions of the node once identify shows they speak [`FLOODSUB_PROTOCOL`].
+//! Messages received through floodsub are decoded and delivered like those received through
+//! gossipsub.
+//!
+//! The well-known topics of the node stay on gossipsub, which every node of this crate runs,
+//! and so do messages published to some peers only, floodsub having no way to address peers.
+//! Floodsub traffic is neither metered nor rate limited.
+//!
+//! With [`Router::Both`], a message published by a node running both routers reaches the other
+//! such nodes twice. A node running both routers delivers such a message once: a copy coming
+//! through one router, from the same source, on the same topics and with the same payload as a
+//! copy that came through the other router within [`TWIN_WINDOW`], is dropped.
+
+use crate::Error;
+use libp2p::{
+    floodsub::FloodsubMessage,
+    gossipsub::{GossipsubMessage, TopicHash},
+    PeerId,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Protocol floodsub peers announce through identify.
+pub const FLOODSUB_PROTOCOL: &str = "/floodsub/1.0.0";
+
+/// How long a message received through one router waits for its copy through the other.
+pub const TWIN_WINDOW: Duration = Duration::from_secs(120);
+
+/// Protocols carrying the messages of the topics of a node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Router {
+    #[default]
+    Gossipsub,
+    Floodsub,
+    /// Both, every message being published through both.
+    Both,
+}
+
+impl Router {
+    pub fn gossipsub(self) -> bool {
+        self != Router::Floodsub
+    }
+
+    pub fn floodsub(self) -> bool {
+        self != Router::Gossipsub
+    }
+}
+
+impl FromStr for Router {
+    type Err = Error;
+
+    fn from_str(router: &str) -> Result<Self, Error> {
+        match router {
+            "gossipsub" => Ok(Router::Gossipsub),
+            "floodsub" => Ok(Router::Floodsub),
+            "both" => Ok(Router::Both),
+            _ => Err(format!("unknown router {}", router).into()),
+        }
+    }
+}
+
+/// The message as gossipsub would have delivered it, with the last 8 bytes of the floodsub
+/// sequence number as its own.
+pub(crate) fn to_gossipsub(message: FloodsubMessage) -> GossipsubMessage {
+    let tail = &message.sequence_number[message.sequence_number.len().saturating_sub(8)..];
+    let mut sequence_number = [0; 8];
+    sequence_number[8 - tail.len()..].copy_from_slice(tail);
+    GossipsubMessage {
+        source: message.source,
+        data: message.data.into(),
+        sequence_number: u64::from_be_bytes(sequence_number),
+        topics: message
+            .topics
+            .into_iter()
+            .map(|topic| TopicHash::from_raw(topic.id()))
+            .collect(),
+        targeted: false,
+    }
+}
+
+/// Messages received through one router only so far, by source and digest of their topics and
+/// payload, with how many copies came through gossipsub and floodsub.
+#[derive(Default)]
+pub(crate) struct Twins {
+    pending: HashMap<(PeerId, u64), (u32, u32, Instant)>,
+}
+
+impl Twins {
+    /// Whether `message`, received through floodsub if `flooded` or else through gossipsub,
+    /// is the copy of a message received through the other router.
+    pub fn is_twin(&mut self, message: &GossipsubMessage, flooded: bool) -> bool {
+        let now = Instant::now();
+        let mut hasher = DefaultHasher::new();
+        message.topics.hash(&mut hasher);
+        message.data.hash(&mut hasher);
+        let key = (message.source.clone(), hasher.finish());
+        let (gossipsub, floodsub, at) = self.pending.entry(key.clone()).or_insert((0, 0, now));
+        let (mine, other) = if flooded {
+            (floodsub, gossipsub)
+        } else {
+            (gossipsub, floodsub)
+        };
+        if *other > 0 {
+            *other -= 1;
+            if *other == 0 && *mine == 0 {
+                self.pending.remove(&key);
+            }
+            return true;
+        }
+        *mine = mine.saturating_add(1);
+        *at = now;
+        false
+    }
+
+    /// Forget the messages whose copy is overdue.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.pending
+            .retain(|_, (_, _, at)| now.duration_since(*at) < TWIN_WINDOW);
+    }
+}
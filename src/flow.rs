@@ -0,0 +1,436 @@
+//! Flow control between the gossipsub mesh and the consumers of its messages: local
+//! subscriptions, and the external systems messages are relayed to.
+//!
+//! Every [`Subscription`](crate::Subscription) is a queue with [`Bounds`], so that a slow
+//! consumer cannot make the node hoard messages.
+//!
+//! The mesh cannot be asked to slow down, so the messages that bridges, sinks and gateways take
+//! out of it wait in a bounded outbox until the external side is ready for them. [`FlowControl`]
+//! sets the size of the outbox and what happens once it is full: wait for room, which leaves the
+//! messages in the subscription, or drop a message, optionally republishing it on a dead-letter
+//! topic so another node can deal with it. In the other direction, bridges stop reading from the
+//! external system while the messages already read have not been published into the mesh.
+
+use crate::{Client, Message};
+use async_std::task;
+use futures::{
+    future::{self, Either},
+    prelude::*,
+    stream::FusedStream,
+};
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+/// What to do with a message arriving at a full queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Wait for room before taking the next message.
+    Block,
+    /// Drop the message that has been waiting the longest to make room.
+    #[default]
+    DropOldest,
+    /// Drop the arriving message.
+    DropNewest,
+}
+
+/// Bounds the messages waiting to leave the mesh.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlowControl {
+    /// Messages that may wait before `overflow` applies.
+    pub capacity: usize,
+    pub overflow: Overflow,
+    /// Topic dropped messages are republished on, unchanged.
+    pub dead_letter: Option<String>,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        FlowControl {
+            capacity: 1024,
+            overflow: Overflow::default(),
+            dead_letter: None,
+        }
+    }
+}
+
+/// Bounds of a queue of messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bounds {
+    /// Messages that may wait before `overflow` applies.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+/// State of a bounded queue of messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStatus {
+    /// Messages waiting to be taken.
+    pub queued: usize,
+    pub capacity: usize,
+    /// Messages dropped by the overflow policy so far.
+    pub dropped: u64,
+    /// How long the oldest waiting message has been waiting.
+    pub lag: Duration,
+}
+
+/// Take the messages of `messages` into a bounded outbox as configured by `flow`, on a background
+/// task. The outbox ends once `messages` does.
+#[cfg_attr(
+    not(any(
+        feature = "gateway",
+        feature = "mqtt",
+        feature = "multicast",
+        feature = "nats",
+        feature = "exec",
+        feature = "kafka",
+        feature = "webhook"
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn outbox<S>(client: &Client, messages: S, flow: &FlowControl) -> Receiver<Message>
+where
+    S: Stream<Item = Message> + Send + Unpin + 'static,
+{
+    let (sender, receiver) = channel(flow.capacity, flow.overflow);
+    let client = client.clone();
+    let dead_letter = flow.dead_letter.clone();
+    task::spawn(async move {
+        let mut messages = messages;
+        let mut dropped = 0u64;
+        loop {
+            // Let go of `messages` as soon as the outbox is gone or closed.
+            let message = match future::select(messages.next(), sender.closed()).await {
+                Either::Left((Some(message), _)) => message,
+                _ => return,
+            };
+            let message = match sender.send(message).await {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(_) => return,
+            };
+            dropped += 1;
+            if dropped.is_power_of_two() {
+                log::warn!("outbox full, {} messages dropped so far", dropped);
+            }
+            if let Some(topic) = &dead_letter {
+                if let Err(e) = client.publish(topic, message.data) {
+                    log::debug!("failed to dead-letter a message on {}: {}", topic, e);
+                }
+            }
+        }
+    });
+    receiver
+}
+
+struct Shared<T> {
+    /// Queued items, and when they were queued.
+    queue: VecDeque<(Instant, T)>,
+    capacity: usize,
+    overflow: Overflow,
+    dropped: u64,
+    senders: usize,
+    /// Whether the queue was closed through a [`Closer`].
+    closed: bool,
+    /// Whether the receiver has ended.
+    ended: bool,
+    receiver_gone: bool,
+    receiver: Option<Waker>,
+    /// Senders waiting for room, or for the queue to close.
+    blocked: Vec<Waker>,
+}
+
+impl<T> Shared<T> {
+    /// Queue `item` if there is room or the overflow policy makes some. With `Block`, a full
+    /// queue hands the item back and registers `cx` to be woken once there is room.
+    fn offer(&mut self, item: T, cx: Option<&mut Context>) -> TrySend<T> {
+        if self.receiver_gone || self.closed {
+            return TrySend::Closed(item);
+        }
+        let dropped = if self.queue.len() < self.capacity {
+            None
+        } else {
+            match self.overflow {
+                Overflow::Block => {
+                    if let Some(cx) = cx {
+                        if !self.blocked.iter().any(|w| w.will_wake(cx.waker())) {
+                            self.blocked.push(cx.waker().clone());
+                        }
+                    }
+                    return TrySend::Full(item);
+                }
+                Overflow::DropOldest => self.queue.pop_front().map(|(_, item)| item),
+                Overflow::DropNewest => {
+                    self.dropped += 1;
+                    return TrySend::Queued(Some(item));
+                }
+            }
+        };
+        if dropped.is_some() {
+            self.dropped += 1;
+        }
+        self.queue.push_back((Instant::now(), item));
+        if let Some(waker) = self.receiver.take() {
+            waker.wake();
+        }
+        TrySend::Queued(dropped)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        (self.senders == 0 || self.closed) && self.queue.is_empty()
+    }
+
+    fn status(&self) -> QueueStatus {
+        QueueStatus {
+            queued: self.queue.len(),
+            capacity: self.capacity,
+            dropped: self.dropped,
+            lag: self
+                .queue
+                .front()
+                .map_or(Duration::ZERO, |(queued_at, _)| queued_at.elapsed()),
+        }
+    }
+}
+
+/// Create a queue holding at most `capacity` items, at least one.
+pub(crate) fn channel<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::new(),
+        capacity: capacity.max(1),
+        overflow,
+        dropped: 0,
+        senders: 1,
+        closed: false,
+        ended: false,
+        receiver_gone: false,
+        receiver: None,
+        blocked: Vec::new(),
+    }));
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+/// Outcome of [`Sender::try_send`].
+pub(crate) enum TrySend<T> {
+    /// The item was queued, or dropped by the overflow policy. Holds the item dropped, if any.
+    Queued(Option<T>),
+    /// The queue is full and its policy is `Block`.
+    Full(T),
+    /// The receiver is gone, or the queue was closed.
+    Closed(T),
+}
+
+/// Sending half of a [`channel`].
+pub(crate) struct Sender<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Sender<T> {
+    /// Queue `item`, applying the overflow policy if the queue is full. Returns the item dropped
+    /// to make room, if any, or gives `item` back if the receiver is gone.
+    pub(crate) async fn send(&self, item: T) -> Result<Option<T>, T> {
+        let mut item = Some(item);
+        future::poll_fn(|cx| {
+            let arriving = item.take().expect("polled after completion");
+            match self.0.lock().unwrap().offer(arriving, Some(cx)) {
+                TrySend::Queued(dropped) => Poll::Ready(Ok(dropped)),
+                TrySend::Closed(arriving) => Poll::Ready(Err(arriving)),
+                TrySend::Full(arriving) => {
+                    item = Some(arriving);
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Queue `item` without waiting for room.
+    pub(crate) fn try_send(&self, item: T) -> TrySend<T> {
+        self.0.lock().unwrap().offer(item, None)
+    }
+
+    /// Whether the receiver is gone, or the queue was closed.
+    pub(crate) fn is_closed(&self) -> bool {
+        let shared = self.0.lock().unwrap();
+        shared.receiver_gone || shared.closed
+    }
+
+    /// Wait until the receiver is gone or the queue is closed.
+    #[cfg_attr(
+        not(any(
+            feature = "gateway",
+            feature = "mqtt",
+            feature = "multicast",
+            feature = "nats",
+            feature = "exec",
+            feature = "kafka",
+            feature = "webhook"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn closed(&self) -> impl Future<Output = ()> + Unpin + '_ {
+        future::poll_fn(move |cx| {
+            let mut shared = self.0.lock().unwrap();
+            if shared.receiver_gone || shared.closed {
+                return Poll::Ready(());
+            }
+            if !shared.blocked.iter().any(|w| w.will_wake(cx.waker())) {
+                shared.blocked.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+    }
+
+    /// Whether `self` and `other` send to the same receiver.
+    pub(crate) fn same_channel(&self, other: &Sender<T>) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn status(&self) -> QueueStatus {
+        self.0.lock().unwrap().status()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().unwrap().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            if let Some(waker) = shared.receiver.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Receiving half of a [`channel`]. Ends once every sender is gone, or the queue was closed, and
+/// the queue is empty.
+pub(crate) struct Receiver<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Receiver<T> {
+    pub(crate) fn status(&self) -> QueueStatus {
+        self.0.lock().unwrap().status()
+    }
+
+    /// Whether the receiver has nothing left to take, and will end when next polled.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.0.lock().unwrap().is_exhausted()
+    }
+}
+
+impl<T: Send + 'static> Receiver<T> {
+    /// A handle reading the status of the queue, which does not keep it open.
+    #[cfg_attr(
+        not(any(
+            feature = "mqtt",
+            feature = "multicast",
+            feature = "nats",
+            feature = "kafka"
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn monitor(&self) -> Monitor {
+        let shared = self.0.clone();
+        Monitor(Arc::new(move || shared.lock().unwrap().status()))
+    }
+
+    /// A handle closing the queue, which does not keep it open.
+    #[cfg_attr(
+        not(any(feature = "mqtt", feature = "multicast", feature = "nats")),
+        allow(dead_code)
+    )]
+    pub(crate) fn closer(&self) -> Closer {
+        let shared = self.0.clone();
+        Closer(Arc::new(move || {
+            let mut shared = shared.lock().unwrap();
+            shared.closed = true;
+            if let Some(waker) = shared.receiver.take() {
+                waker.wake();
+            }
+            for waker in shared.blocked.drain(..) {
+                waker.wake();
+            }
+        }))
+    }
+}
+
+/// Reads the status of a queue, see [`Receiver::monitor`].
+#[derive(Clone)]
+pub(crate) struct Monitor(Arc<dyn Fn() -> QueueStatus + Send + Sync>);
+
+impl Monitor {
+    pub(crate) fn status(&self) -> QueueStatus {
+        (self.0)()
+    }
+}
+
+/// Closes a queue, see [`Receiver::closer`]: senders are turned away, and the receiver ends once
+/// it has taken the items already queued.
+#[derive(Clone)]
+#[cfg_attr(
+    not(any(feature = "mqtt", feature = "multicast", feature = "nats")),
+    allow(dead_code)
+)]
+pub(crate) struct Closer(Arc<dyn Fn() + Send + Sync>);
+
+#[cfg_attr(
+    not(any(feature = "mqtt", feature = "multicast", feature = "nats")),
+    allow(dead_code)
+)]
+impl Closer {
+    pub(crate) fn close(&self) {
+        (self.0)()
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let mut shared = self.0.lock().unwrap();
+        match shared.queue.pop_front() {
+            Some((_, item)) => {
+                for waker in shared.blocked.drain(..) {
+                    waker.wake();
+                }
+                Poll::Ready(Some(item))
+            }
+            None if shared.is_exhausted() => {
+                shared.ended = true;
+                Poll::Ready(None)
+            }
+            None => {
+                shared.receiver = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> FusedStream for Receiver<T> {
+    fn is_terminated(&self) -> bool {
+        self.0.lock().unwrap().ended
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap();
+        shared.receiver_gone = true;
+        for waker in shared.blocked.drain(..) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Local servers exposing a node to clients that do not speak libp2p.
+//!
+//! Each gateway can be spawned with an [`AuthConfig`](auth::AuthConfig) to serve over TLS and
+//! authenticate its clients, see the [`auth`] module.
+//!
+//! The gateways are behind the `gateway` cargo feature, on by default, and the gRPC gateway
+//! behind the `grpc` one too.
+
+pub mod admin;
+pub mod auth;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod http;
+pub mod ipfs;
+mod resume;
+pub mod ws;
+
+use crate::{
+    topology::{self, ComponentKind, Registration},
+    Client, Error,
+};
+use async_std::{io, net::TcpListener, os::unix::net::UnixListener, task};
+use async_tls::TlsAcceptor;
+use auth::{AuthConfig, Gate};
+use futures::{prelude::*, stream};
+use std::{
+    fs,
+    net::ToSocketAddrs,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+/// A connection accepted by a gateway, over TCP, TLS or a unix socket.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Listen on `addr` and run `handle` on its own task for every incoming connection, after the
+/// TLS handshake if `auth` asks for TLS. The server is listed in the topology of the node for as
+/// long as it runs.
+fn serve<F, Fut>(
+    client: &Client,
+    addr: impl ToSocketAddrs,
+    name: &'static str,
+    auth: &AuthConfig,
+    handle: F,
+) -> Result<task::JoinHandle<()>, Error>
+where
+    F: Fn(Client, Box<dyn Connection>, Gate) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let acceptor = auth.acceptor()?;
+    let listener = TcpListener::from(std::net::TcpListener::bind(addr)?);
+    let local_addr = listener.local_addr()?;
+    let tls = if acceptor.is_some() { " over TLS" } else { "" };
+    log::info!("{} listening on {}{}", name, local_addr, tls);
+    let registration = Registration::register(
+        client,
+        ComponentKind::Gateway,
+        format!("{} on {}{}", name, local_addr, tls),
+        topology::config_hash(&(name, local_addr, tls, auth.tokens.len())),
+    )?;
+    let incoming = stream::unfold(listener, |listener| async {
+        let stream = listener.accept().await.map(|(stream, _)| stream);
+        Some((stream, listener))
+    });
+    Ok(accept(
+        client,
+        registration,
+        incoming,
+        name,
+        acceptor,
+        auth.gate(),
+        handle,
+    ))
+}
+
+/// Listen on the unix socket at `path` and run `handle` on its own task for every incoming
+/// connection, like [`serve`]. A socket left over at `path`, e.g. by a node that crashed, is
+/// replaced, and the socket is only accessible to the user running the node, whose connections
+/// are trusted with read-write access.
+fn serve_unix<F, Fut>(
+    client: &Client,
+    path: &Path,
+    name: &'static str,
+    handle: F,
+) -> Result<task::JoinHandle<()>, Error>
+where
+    F: Fn(Client, Box<dyn Connection>, Gate) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(format!("{} exists and is not a socket", path.display()).into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = UnixListener::from(std::os::unix::net::UnixListener::bind(path)?);
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    log::info!("{} listening on {}", name, path.display());
+    let registration = Registration::register(
+        client,
+        ComponentKind::Gateway,
+        format!("{} on {}", name, path.display()),
+        topology::config_hash(&(name, path)),
+    )?;
+    let incoming = stream::unfold(listener, |listener| async {
+        let stream = listener.accept().await.map(|(stream, _)| stream);
+        Some((stream, listener))
+    });
+    Ok(accept(
+        client,
+        registration,
+        incoming,
+        name,
+        None,
+        Gate::open(),
+        handle,
+    ))
+}
+
+/// Run `handle` on its own task for every connection of `incoming`, keeping the server
+/// registered in the topology for as long as it runs.
+fn accept<S, F, Fut>(
+    client: &Client,
+    registration: Registration,
+    incoming: impl Stream<Item = io::Result<S>> + Send + 'static,
+    name: &'static str,
+    acceptor: Option<TlsAcceptor>,
+    gate: Gate,
+    handle: F,
+) -> task::JoinHandle<()>
+where
+    S: Connection + 'static,
+    F: Fn(Client, Box<dyn Connection>, Gate) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let client = client.clone();
+    let handle = std::sync::Arc::new(handle);
+    task::spawn(async move {
+        let _registration = registration;
+        let mut incoming = incoming.boxed();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::warn!("{}: {}", name, e);
+                    continue;
+                }
+            };
+            let (client, acceptor, gate, handle) = (
+                client.clone(),
+                acceptor.clone(),
+                gate.clone(),
+                handle.clone(),
+            );
+            task::spawn(async move {
+                let connection: Box<dyn Connection> = match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => Box::new(stream),
+                        Err(e) => {
+                            log::debug!("{}: TLS handshake failed: {}", name, e);
+                            return;
+                        }
+                    },
+                    None => Box::new(stream),
+                };
+                handle(client, connection, gate).await
+            });
+        }
+    })
+}
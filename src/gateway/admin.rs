@@ -0,0 +1,1440 @@
+//! An HTTP endpoint for operating a node.
+//!
+//! - `GET /metrics` reports the traffic of every connected peer and topic, the messages the node
+//!   handed to its own subscribers on every topic, a histogram of the sizes of the payloads of
+//!   every topic and the payloads dropped for exceeding its limit, the number of peers graylisted
+//!   for exceeding their rate limit, how far behind every local subscription is, how many
+//!   messages wait for the upload limit at every priority and how long they waited, and the
+//!   health of every bridge and sink, and the clock skew of every peer, in the Prometheus text
+//!   format.
+//! - `GET /peers` lists the connected peers as a JSON array of objects holding the `peer` id, the
+//!   `topics` it is subscribed to, its traffic counters, whether it is `graylisted`, whether it is
+//!   an `explicit` peer, the round-trip times of its last pings in `rtt_history_ms`, the oldest
+//!   first, how many of its pings failed in a row in `ping_failures`, see
+//!   [`liveness`](crate::liveness), and how far ahead of the clock of the node its clock is in
+//!   `clock_skew_ms`, see [`clock`](crate::clock). With a `topic` query parameter, it lists the
+//!   connected peers subscribed to that topic or in its fanout instead, as objects holding the
+//!   `peer` id, its `role` (`mesh`, `fanout` or `gossip`), its `agent_version` and the round-trip
+//!   time of its last ping in `rtt_ms`.
+//! - `GET /topics` lists the topics the node or a connected peer is subscribed to, or the node
+//!   publishes on, as a JSON array of objects holding the `topic`, whether the node is
+//!   `subscribed` to it and how many peers are in its `mesh`, its `fanout` or only get `gossip`.
+//! - `GET /mesh` lists the same topics with their peers, as listed by `/peers?topic=`.
+//! - `GET /directory` lists the topics hosted by the nodes taking part in the topic directory,
+//!   see [`directory`](crate::directory), as a JSON array of objects holding the `topic` and how
+//!   many nodes host it as `peers`, of which `subscribers` and `publishers`.
+//! - `GET /messages` lists the last messages handed to local subscribers, the oldest first, as a
+//!   JSON array of objects holding the `id`, `source` and `topic` of the message, the `size` of
+//!   its payload, the first bytes of the payload as lossy UTF-8 in `preview` and when it was
+//!   `received_ms`, in milliseconds since the Unix epoch.
+//! - `GET /topology` lists the components configured on the node as a JSON array of objects
+//!   holding the `kind` of component, its `name`, its `status` and its `config_hash`.
+//! - `GET /healthz` and `GET /readyz` report the health of the node, see
+//!   [`health`](crate::health), answering `200 OK` if it is live, or ready respectively, and
+//!   `503 Service Unavailable` otherwise, for liveness and readiness probes. The body is a JSON
+//!   object holding whether the node is `live` and `ready`, the `problems` keeping it from being
+//!   ready, whether it is `listening` and its `listen_addrs`, its `peers` and the `min_peers` it
+//!   needs, the required `topics`, each an object holding the `topic`, whether the node is
+//!   `subscribed` to it, its `mesh_peers` and the `min_mesh_peers` it needs, and the `journals`
+//!   of the process, each an object holding its `path`, its size in `bytes`, the positions of the
+//!   last `journaled` and `acknowledged` messages and the `error` that made it unusable, if any.
+//! - `GET /reachability` tells whether the node is reachable by its peers, see
+//!   [`autonat`](crate::autonat), as a JSON object holding the `reachability` (`unknown`,
+//!   `public` or `private`) and the `listen_addrs`, `observed_addrs`, `confirmed_addrs` and
+//!   `announce_addrs` of the node.
+//! - `GET /debug` dumps the swarm state of the node, see [`debug`](crate::debug), as an indented
+//!   JSON object holding its `peer_id` and `listen_addrs`, its `connections`, each an object
+//!   holding the `peer`, the `direction` of the connection, its `remote_addr` and `local_addr`,
+//!   its `age_ms`, the `security` and `multiplexer` negotiated, the `protocols` and
+//!   `agent_version` of the peer, the `open`, `inbound` and `outbound` counts of its
+//!   `substreams` and its traffic counters, the `pending_dials`, each an object holding the
+//!   `address` dialed and the `elapsed_ms` since, every topic of the node or its peers, internal
+//!   ones included, as an object holding the `topic`, whether the node is `subscribed` to it and
+//!   the peers of its `mesh` and `fanout` and those subscribed to it as `peers`, and the usage of
+//!   its `caches`.
+//! - `GET /trace` lists the gossip events the node traced as a JSON array, see
+//!   [`trace`](crate::trace), only those naming a message with a `message_id` query parameter.
+//! - `POST /state` brings the node to the desired state in the body, see
+//!   [`reconcile`](crate::reconcile), and answers with what changed as a JSON object listing the
+//!   topics `subscribed` and `unsubscribed`, the peers `peers_added` and `peers_removed`, the
+//!   bridges `bridges_started`, `bridges_stopped` and `bridges_restarted`, and the new `quota` if
+//!   it changed. With `?dry_run=true`, it only answers with what would change.
+//!
+//! - `GET /plugins` lists the [plugins](crate::plugin) installed on the node, in the order they
+//!   run, as a JSON array of objects holding the `name` of the plugin, the `topic` filter it runs
+//!   on, its `hook` (`publish`, `receive` or `both`) and the `hash` of its module and limits.
+//! - `POST /plugins/<name>?topic=<filter>` installs the WebAssembly module in the body as the
+//!   plugin `name`, replacing any plugin of that name, and answers with it as listed by
+//!   `/plugins`. The `hook` query parameter tells which messages it runs on, `receive` by
+//!   default, and `fuel` and `memory` override its default [`Limits`](crate::plugin::Limits). A
+//!   module that does not follow the plugin ABI is answered with `400 Bad Request`.
+//! - `DELETE /plugins/<name>` removes the plugin `name`, answering with it as listed by
+//!   `/plugins`, or with `404 Not Found` if there is none.
+//!
+//! The plugin routes answer `501 Not Implemented` on nodes built without the `wasm` feature.
+//!
+//! - `POST /publish` publishes many messages in one request, the `PublishStream` of the
+//!   PubSubAPI proto: the body holds one JSON object per line with the `topic` and `data` of a
+//!   message, an `"encoding":"base64"` field if `data` is not to be sent as UTF-8, and a
+//!   `correlation_id` of the choice of the client. The answer, in `application/x-ndjson`, holds
+//!   an object per message in the same order, with its `correlation_id` and either the
+//!   `message_id` it was sent with or the `error` that kept the node from sending it, once every
+//!   message is sent. Bodies are limited to 4 MiB.
+//!
+//! Besides TCP, with [`spawn`], the endpoint can be served on a unix socket with [`spawn_unix`],
+//! typically at [`socket_path`], so that local tools can operate the node without it opening a
+//! network port. Only the user running the node can connect to the socket.
+//!
+//! Served with [`spawn_with_auth`], the endpoint asks its clients to authenticate, see the
+//! [`auth`](super::auth) module. Read-only clients can use every endpoint but `POST /state`,
+//! unless they only ask for a dry run, `POST` and `DELETE /plugins/<name>` and `POST /publish`.
+
+use super::{
+    auth::{Access, AuthConfig, Gate},
+    http::{self, Request},
+    ws::Encoding,
+    Connection,
+};
+use crate::{
+    autonat::{Reachability, ReachabilityStatus},
+    bandwidth::Traffic,
+    debug::{ConnectionDump, DebugDump},
+    durable::JournalStatus,
+    flow::QueueStatus,
+    health::{HealthReport, TopicHealth},
+    message_id::Published,
+    reconcile::DesiredState,
+    stats::{
+        BridgeStats, LocalDelivery, MeshPeer, MeshRole, PeerStats, RecentMessage, SequenceStats,
+        Stats, TopicMesh, DELAY_BUCKETS, SIZE_BUCKETS,
+    },
+    topology::{Component, ComponentKind, ComponentStatus},
+    trace, Client, Error,
+};
+#[cfg(feature = "wasm")]
+use crate::{
+    plugin::{Hook, InstalledPlugin, Limits, Plugin},
+    topic::TopicFilter,
+};
+use async_std::task;
+use data_encoding::BASE64;
+use futures::prelude::*;
+use libp2p::{core::ConnectedPoint, gossipsub::protocol::MessageId, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    env,
+    fmt::Write,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Name of the unix socket of the endpoint in the runtime directory of the user.
+pub const SOCKET_NAME: &str = "pubsub-lite.sock";
+
+/// Start serving the endpoint on `addr`.
+pub fn spawn(client: &Client, addr: impl ToSocketAddrs) -> Result<task::JoinHandle<()>, Error> {
+    spawn_with_auth(client, addr, AuthConfig::default())
+}
+
+/// Start serving the endpoint on `addr`, authenticating clients as `auth` says.
+pub fn spawn_with_auth(
+    client: &Client,
+    addr: impl ToSocketAddrs,
+    auth: AuthConfig,
+) -> Result<task::JoinHandle<()>, Error> {
+    super::serve(client, addr, "admin endpoint", &auth, handle)
+}
+
+/// Start serving the endpoint on the unix socket at `path`, replacing a socket left there.
+pub fn spawn_unix(client: &Client, path: impl AsRef<Path>) -> Result<task::JoinHandle<()>, Error> {
+    super::serve_unix(client, path.as_ref(), "admin endpoint", handle)
+}
+
+/// Default path of the unix socket of the endpoint, `$XDG_RUNTIME_DIR/pubsub-lite.sock`, if
+/// `XDG_RUNTIME_DIR` is set.
+pub fn socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join(SOCKET_NAME))
+}
+
+/// A connected peer as listed by `/peers`.
+#[derive(Serialize)]
+struct Peer<'a> {
+    peer: String,
+    topics: &'a [String],
+    bytes_in: u64,
+    bytes_out: u64,
+    messages_in: u64,
+    messages_out: u64,
+    graylisted: bool,
+    explicit: bool,
+    rtt_history_ms: Vec<f64>,
+    ping_failures: u32,
+    clock_skew_ms: Option<f64>,
+}
+
+impl<'a> From<&'a PeerStats> for Peer<'a> {
+    fn from(stats: &'a PeerStats) -> Self {
+        Peer {
+            peer: stats.peer.to_base58(),
+            topics: &stats.topics,
+            bytes_in: stats.traffic.bytes_in,
+            bytes_out: stats.traffic.bytes_out,
+            messages_in: stats.traffic.messages_in,
+            messages_out: stats.traffic.messages_out,
+            graylisted: stats.graylisted,
+            explicit: stats.explicit,
+            rtt_history_ms: stats
+                .rtt_history
+                .iter()
+                .map(|rtt| rtt.as_secs_f64() * 1000.0)
+                .collect(),
+            ping_failures: stats.ping_failures,
+            clock_skew_ms: stats.clock_skew.map(|skew| skew as f64 / 1000.0),
+        }
+    }
+}
+
+/// A peer of a topic as listed by `/peers?topic=` and `/mesh`.
+#[derive(Serialize)]
+struct TopicPeer {
+    peer: String,
+    role: &'static str,
+    agent_version: Option<String>,
+    rtt_ms: Option<f64>,
+}
+
+impl From<&MeshPeer> for TopicPeer {
+    fn from(peer: &MeshPeer) -> Self {
+        TopicPeer {
+            peer: peer.peer.to_base58(),
+            role: match peer.role {
+                MeshRole::Mesh => "mesh",
+                MeshRole::Fanout => "fanout",
+                MeshRole::Gossip => "gossip",
+            },
+            agent_version: peer.agent_version.clone(),
+            rtt_ms: peer.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+/// A topic as listed by `/topics`.
+#[derive(Serialize)]
+struct Topic<'a> {
+    topic: &'a str,
+    subscribed: bool,
+    mesh: usize,
+    fanout: usize,
+    gossip: usize,
+}
+
+impl<'a> From<&'a TopicMesh> for Topic<'a> {
+    fn from(topic: &'a TopicMesh) -> Self {
+        Topic {
+            topic: &topic.topic,
+            subscribed: topic.subscribed,
+            mesh: topic.count(MeshRole::Mesh),
+            fanout: topic.count(MeshRole::Fanout),
+            gossip: topic.count(MeshRole::Gossip),
+        }
+    }
+}
+
+/// A topic with its peers as listed by `/mesh`.
+#[derive(Serialize)]
+struct Mesh<'a> {
+    topic: &'a str,
+    subscribed: bool,
+    peers: Vec<TopicPeer>,
+}
+
+/// A component as listed by `/topology`.
+#[derive(Serialize)]
+struct TopologyEntry<'a> {
+    kind: &'static str,
+    name: &'a str,
+    status: &'static str,
+    config_hash: &'a str,
+}
+
+impl<'a> From<&'a Component> for TopologyEntry<'a> {
+    fn from(component: &'a Component) -> Self {
+        TopologyEntry {
+            kind: match component.kind {
+                ComponentKind::Transport => "transport",
+                ComponentKind::Gateway => "gateway",
+                ComponentKind::Bridge => "bridge",
+                ComponentKind::Sink => "sink",
+                ComponentKind::Plugin => "plugin",
+            },
+            name: &component.name,
+            status: match component.status {
+                ComponentStatus::Running => "running",
+                ComponentStatus::Connected => "connected",
+                ComponentStatus::Disconnected => "disconnected",
+            },
+            config_hash: &component.config_hash,
+        }
+    }
+}
+
+/// A plugin as listed by `/plugins`.
+#[cfg(feature = "wasm")]
+#[derive(Serialize)]
+struct PluginEntry<'a> {
+    name: &'a str,
+    topic: &'a str,
+    hook: &'static str,
+    hash: &'a str,
+}
+
+#[cfg(feature = "wasm")]
+impl<'a> From<&'a InstalledPlugin> for PluginEntry<'a> {
+    fn from(plugin: &'a InstalledPlugin) -> Self {
+        PluginEntry {
+            name: &plugin.name,
+            topic: &plugin.filter,
+            hook: match plugin.hook {
+                Hook::Publish => "publish",
+                Hook::Receive => "receive",
+                Hook::Both => "both",
+            },
+            hash: &plugin.hash,
+        }
+    }
+}
+
+/// A message to publish, a line of the body of `/publish`, whose `correlation_id` is read apart
+/// to acknowledge lines that are not messages too.
+#[derive(Deserialize)]
+struct PublishEntry {
+    topic: String,
+    data: String,
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// The acknowledgement of a message to publish, a line of the answer of `/publish`.
+#[derive(Serialize)]
+struct PublishAck {
+    correlation_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A message as listed by `/messages`.
+#[derive(Serialize)]
+struct MessageEntry<'a> {
+    id: &'a str,
+    source: String,
+    topic: &'a str,
+    size: usize,
+    preview: String,
+    received_ms: u64,
+}
+
+impl<'a> From<&'a RecentMessage> for MessageEntry<'a> {
+    fn from(message: &'a RecentMessage) -> Self {
+        MessageEntry {
+            id: &message.id.0,
+            source: message.source.to_base58(),
+            topic: &message.topic,
+            size: message.size,
+            preview: String::from_utf8_lossy(&message.preview).into_owned(),
+            received_ms: message
+                .received
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        }
+    }
+}
+
+/// The health of the node as reported by `/healthz` and `/readyz`.
+#[derive(Serialize)]
+struct HealthEntry<'a> {
+    live: bool,
+    ready: bool,
+    problems: Vec<String>,
+    listening: bool,
+    listen_addrs: Vec<String>,
+    peers: usize,
+    min_peers: usize,
+    topics: Vec<TopicHealthEntry<'a>>,
+    journals: Vec<JournalEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct TopicHealthEntry<'a> {
+    topic: &'a str,
+    subscribed: bool,
+    mesh_peers: usize,
+    min_mesh_peers: usize,
+}
+
+#[derive(Serialize)]
+struct JournalEntry<'a> {
+    path: String,
+    bytes: u64,
+    journaled: u64,
+    acknowledged: u64,
+    error: Option<&'a str>,
+}
+
+impl<'a> From<&'a HealthReport> for HealthEntry<'a> {
+    fn from(report: &'a HealthReport) -> Self {
+        HealthEntry {
+            live: report.live(),
+            ready: report.ready(),
+            problems: report.problems(),
+            listening: report.listening,
+            listen_addrs: to_strings(&report.listen_addrs),
+            peers: report.peers,
+            min_peers: report.min_peers,
+            topics: report.topics.iter().map(TopicHealthEntry::from).collect(),
+            journals: report.journals.iter().map(JournalEntry::from).collect(),
+        }
+    }
+}
+
+impl<'a> From<&'a TopicHealth> for TopicHealthEntry<'a> {
+    fn from(topic: &'a TopicHealth) -> Self {
+        TopicHealthEntry {
+            topic: &topic.topic,
+            subscribed: topic.subscribed,
+            mesh_peers: topic.mesh_peers,
+            min_mesh_peers: topic.min_mesh_peers,
+        }
+    }
+}
+
+impl<'a> From<&'a JournalStatus> for JournalEntry<'a> {
+    fn from(journal: &'a JournalStatus) -> Self {
+        JournalEntry {
+            path: journal.path.display().to_string(),
+            bytes: journal.bytes,
+            journaled: journal.journaled,
+            acknowledged: journal.acknowledged,
+            error: journal.error.as_deref(),
+        }
+    }
+}
+
+/// The reachability of the node as reported by `/reachability`.
+#[derive(Serialize)]
+struct ReachabilityEntry {
+    reachability: &'static str,
+    listen_addrs: Vec<String>,
+    observed_addrs: Vec<String>,
+    confirmed_addrs: Vec<String>,
+    announce_addrs: Vec<String>,
+}
+
+impl From<&ReachabilityStatus> for ReachabilityEntry {
+    fn from(status: &ReachabilityStatus) -> Self {
+        ReachabilityEntry {
+            reachability: match status.reachability {
+                Reachability::Unknown => "unknown",
+                Reachability::Public => "public",
+                Reachability::Private => "private",
+            },
+            listen_addrs: to_strings(&status.listen_addrs),
+            observed_addrs: to_strings(&status.observed_addrs),
+            confirmed_addrs: to_strings(&status.confirmed_addrs),
+            announce_addrs: to_strings(&status.announce_addrs),
+        }
+    }
+}
+
+/// The swarm state of the node as dumped by `/debug`.
+#[derive(Serialize)]
+struct DebugEntry<'a> {
+    peer_id: String,
+    listen_addrs: Vec<String>,
+    connections: Vec<ConnectionEntry<'a>>,
+    pending_dials: Vec<PendingDialEntry>,
+    topics: Vec<TopicDumpEntry<'a>>,
+    caches: CacheEntry,
+}
+
+/// A connection as dumped by `/debug`.
+#[derive(Serialize)]
+struct ConnectionEntry<'a> {
+    peer: String,
+    direction: &'static str,
+    remote_addr: String,
+    local_addr: Option<String>,
+    age_ms: Option<f64>,
+    security: Option<&'static str>,
+    multiplexer: &'static str,
+    protocols: &'a [String],
+    agent_version: Option<&'a str>,
+    substreams: SubstreamEntry,
+    bytes_in: u64,
+    bytes_out: u64,
+    messages_in: u64,
+    messages_out: u64,
+}
+
+#[derive(Serialize)]
+struct SubstreamEntry {
+    open: u64,
+    inbound: u64,
+    outbound: u64,
+}
+
+#[derive(Serialize)]
+struct PendingDialEntry {
+    address: String,
+    elapsed_ms: f64,
+}
+
+/// A topic as dumped by `/debug`.
+#[derive(Serialize)]
+struct TopicDumpEntry<'a> {
+    topic: &'a str,
+    subscribed: bool,
+    mesh: Vec<String>,
+    fanout: Vec<String>,
+    peers: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CacheEntry {
+    memory_budget: Option<usize>,
+    message_cache_bytes: usize,
+    retained_bytes: usize,
+    evicted: u64,
+    duplicate_cache_entries: usize,
+    reassembly_bytes: usize,
+}
+
+impl<'a> From<&'a DebugDump> for DebugEntry<'a> {
+    fn from(dump: &'a DebugDump) -> Self {
+        DebugEntry {
+            peer_id: dump.peer_id.to_base58(),
+            listen_addrs: to_strings(&dump.listen_addrs),
+            connections: dump.connections.iter().map(ConnectionEntry::from).collect(),
+            pending_dials: dump
+                .pending_dials
+                .iter()
+                .map(|dial| PendingDialEntry {
+                    address: dial.address.to_string(),
+                    elapsed_ms: dial.elapsed.as_secs_f64() * 1000.0,
+                })
+                .collect(),
+            topics: dump
+                .topics
+                .iter()
+                .map(|topic| TopicDumpEntry {
+                    topic: &topic.topic,
+                    subscribed: topic.subscribed,
+                    mesh: topic.mesh.iter().map(PeerId::to_base58).collect(),
+                    fanout: topic.fanout.iter().map(PeerId::to_base58).collect(),
+                    peers: topic.peers.iter().map(PeerId::to_base58).collect(),
+                })
+                .collect(),
+            caches: CacheEntry {
+                memory_budget: dump.caches.memory.budget,
+                message_cache_bytes: dump.caches.memory.message_cache,
+                retained_bytes: dump.caches.memory.retained,
+                evicted: dump.caches.memory.evicted,
+                duplicate_cache_entries: dump.caches.duplicate_cache,
+                reassembly_bytes: dump.caches.reassembly,
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a ConnectionDump> for ConnectionEntry<'a> {
+    fn from(connection: &'a ConnectionDump) -> Self {
+        let (direction, remote_addr, local_addr) = match &connection.endpoint {
+            ConnectedPoint::Dialer { address } => ("outbound", address.to_string(), None),
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => (
+                "inbound",
+                send_back_addr.to_string(),
+                Some(local_addr.to_string()),
+            ),
+        };
+        ConnectionEntry {
+            peer: connection.peer.to_base58(),
+            direction,
+            remote_addr,
+            local_addr,
+            age_ms: connection.age.map(|age| age.as_secs_f64() * 1000.0),
+            security: connection.security,
+            multiplexer: connection.multiplexer,
+            protocols: &connection.protocols,
+            agent_version: connection.agent_version.as_deref(),
+            substreams: SubstreamEntry {
+                open: connection.substreams.open,
+                inbound: connection.substreams.inbound,
+                outbound: connection.substreams.outbound,
+            },
+            bytes_in: connection.traffic.bytes_in,
+            bytes_out: connection.traffic.bytes_out,
+            messages_in: connection.traffic.messages_in,
+            messages_out: connection.traffic.messages_out,
+        }
+    }
+}
+
+fn to_strings(addrs: &[Multiaddr]) -> Vec<String> {
+    addrs.iter().map(Multiaddr::to_string).collect()
+}
+
+async fn handle(client: Client, mut stream: Box<dyn Connection>, gate: Gate) {
+    let request = match http::read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            log::debug!("admin endpoint: bad request: {}", e);
+            return;
+        }
+    };
+    let dry_run = request
+        .query_values("dry_run")
+        .any(|value| value != "false" && value != "0");
+    let required = if request.method == "GET" || dry_run {
+        Access::ReadOnly
+    } else {
+        Access::ReadWrite
+    };
+    match gate.check(&request, required, &mut stream).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return,
+        Err(e) => {
+            log::debug!("admin endpoint: {}", e);
+            return;
+        }
+    }
+    if let Err(e) = call(&client, &request, &mut stream).await {
+        log::debug!("admin endpoint: {}", e);
+        let message = e.to_string();
+        let _ = http::respond(
+            &mut stream,
+            500,
+            "Internal Server Error",
+            "text/plain",
+            message.as_bytes(),
+        )
+        .await;
+    }
+}
+
+async fn call<S: AsyncWrite + Unpin>(
+    client: &Client,
+    request: &Request,
+    stream: &mut S,
+) -> Result<(), Error> {
+    if request.path.trim_end_matches('/') == "/state" {
+        return reconcile(client, request, stream).await;
+    }
+    if request.path.trim_end_matches('/') == "/publish" {
+        return publish(client, request, stream).await;
+    }
+    if let Some(name) = request.path.trim_end_matches('/').strip_prefix("/plugins") {
+        if name.is_empty() || name.starts_with('/') {
+            return plugins(client, request, name.trim_start_matches('/'), stream).await;
+        }
+    }
+    if request.method != "GET" {
+        return Ok(http::respond(
+            stream,
+            405,
+            "Method Not Allowed",
+            "text/plain",
+            b"405 - Method Not Allowed",
+        )
+        .await?);
+    }
+    match request.path.trim_end_matches('/') {
+        "/metrics" => {
+            let body = metrics(&client.stats().await?);
+            Ok(http::respond(
+                stream,
+                200,
+                "OK",
+                "text/plain; version=0.0.4",
+                body.as_bytes(),
+            )
+            .await?)
+        }
+        "/peers" => {
+            if let Some(topic) = request.query_values("topic").next() {
+                let peers = client.list_peers(topic).await?;
+                let peers: Vec<TopicPeer> = peers.iter().map(TopicPeer::from).collect();
+                let body = serde_json::to_vec(&peers)?;
+                return Ok(http::respond(stream, 200, "OK", "application/json", &body).await?);
+            }
+            let mut stats = client.stats().await?;
+            stats.peers.sort_by_key(|peer| peer.peer.to_base58());
+            let peers: Vec<Peer> = stats.peers.iter().map(Peer::from).collect();
+            let body = serde_json::to_vec(&peers)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/topics" => {
+            let info = client.mesh_info().await?;
+            let topics: Vec<Topic> = info.topics.iter().map(Topic::from).collect();
+            let body = serde_json::to_vec(&topics)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/mesh" => {
+            let info = client.mesh_info().await?;
+            let mesh: Vec<Mesh> = info
+                .topics
+                .iter()
+                .map(|topic| Mesh {
+                    topic: &topic.topic,
+                    subscribed: topic.subscribed,
+                    peers: topic.peers.iter().map(TopicPeer::from).collect(),
+                })
+                .collect();
+            let body = serde_json::to_vec(&mesh)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/debug" => {
+            let dump = client.debug_dump().await?;
+            let body = serde_json::to_vec_pretty(&DebugEntry::from(&dump))?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/directory" => {
+            let body = serde_json::to_vec(&client.discover_topics().await?)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        path @ ("/healthz" | "/readyz") => {
+            let report = client.health().await?;
+            let healthy = if path == "/healthz" {
+                report.live()
+            } else {
+                report.ready()
+            };
+            let (status, reason) = if healthy {
+                (200, "OK")
+            } else {
+                (503, "Service Unavailable")
+            };
+            let body = serde_json::to_vec(&HealthEntry::from(&report))?;
+            Ok(http::respond(stream, status, reason, "application/json", &body).await?)
+        }
+        "/reachability" => {
+            let status = client.reachability().await?;
+            let body = serde_json::to_vec(&ReachabilityEntry::from(&status))?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/trace" => {
+            let events = match request.query_values("message_id").next() {
+                Some(id) => client.trace_message(&MessageId(id.to_owned())).await?,
+                None => client.trace().await?,
+            };
+            let body = trace::to_json(&events)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/messages" => {
+            let stats = client.stats().await?;
+            let messages: Vec<MessageEntry> = stats
+                .recent_messages
+                .iter()
+                .map(MessageEntry::from)
+                .collect();
+            let body = serde_json::to_vec(&messages)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        "/topology" => {
+            let topology = client.describe_topology().await?;
+            let components: Vec<TopologyEntry> = topology
+                .components
+                .iter()
+                .map(TopologyEntry::from)
+                .collect();
+            let body = serde_json::to_vec(&components)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        _ => Ok(http::respond(
+            stream,
+            404,
+            "Not Found",
+            "text/plain",
+            b"404 page not found",
+        )
+        .await?),
+    }
+}
+
+/// Bring the node to the desired state in the body of `request`, answering with the diff.
+async fn reconcile<S: AsyncWrite + Unpin>(
+    client: &Client,
+    request: &Request,
+    stream: &mut S,
+) -> Result<(), Error> {
+    if request.method != "POST" {
+        return Ok(http::respond(
+            stream,
+            405,
+            "Method Not Allowed",
+            "text/plain",
+            b"405 - Method Not Allowed",
+        )
+        .await?);
+    }
+    let desired: DesiredState = match serde_json::from_slice(&request.body) {
+        Ok(desired) => desired,
+        Err(e) => {
+            let message = format!("invalid desired state: {}", e);
+            return Ok(
+                http::respond(stream, 400, "Bad Request", "text/plain", message.as_bytes()).await?,
+            );
+        }
+    };
+    let dry_run = request
+        .query_values("dry_run")
+        .any(|value| value != "false" && value != "0");
+    let diff = if dry_run {
+        client.plan(&desired)?
+    } else {
+        client.reconcile(&desired)?
+    };
+    let body = serde_json::to_vec(&diff)?;
+    Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+}
+
+/// Publish every message in the body of `request`, acknowledging each of them in order.
+async fn publish<S: AsyncWrite + Unpin>(
+    client: &Client,
+    request: &Request,
+    stream: &mut S,
+) -> Result<(), Error> {
+    if request.method != "POST" {
+        return Ok(http::respond(
+            stream,
+            405,
+            "Method Not Allowed",
+            "text/plain",
+            b"405 - Method Not Allowed",
+        )
+        .await?);
+    }
+    // Every message is published before waiting for the id of the first one.
+    let published: Vec<_> = request
+        .body
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|line| {
+            let correlation_id = serde_json::from_slice::<Value>(line)
+                .ok()
+                .and_then(|value| value.get("correlation_id")?.as_u64())
+                .unwrap_or_default();
+            (correlation_id, publish_line(client, line))
+        })
+        .collect();
+    let mut body = Vec::new();
+    for (correlation_id, published) in published {
+        let ack = match published {
+            Ok(published) => published.await,
+            Err(e) => Err(e),
+        };
+        let ack = match ack {
+            Ok(id) => PublishAck {
+                correlation_id,
+                message_id: Some(id.0),
+                error: None,
+            },
+            Err(e) => PublishAck {
+                correlation_id,
+                message_id: None,
+                error: Some(e.to_string()),
+            },
+        };
+        serde_json::to_writer(&mut body, &ack)?;
+        body.push(b'\n');
+    }
+    Ok(http::respond(stream, 200, "OK", "application/x-ndjson", &body).await?)
+}
+
+/// Publish the message of a line of the body of a `/publish` request.
+fn publish_line(client: &Client, line: &[u8]) -> Result<Published, Error> {
+    let entry: PublishEntry = serde_json::from_slice(line)?;
+    let data = match entry.encoding {
+        Encoding::Utf8 => entry.data.into_bytes(),
+        Encoding::Base64 => BASE64.decode(entry.data.as_bytes())?,
+    };
+    client.publish_with_id(&entry.topic, data)
+}
+
+/// List the plugins of the node, or install or remove the plugin `name`.
+#[cfg(feature = "wasm")]
+async fn plugins<S: AsyncWrite + Unpin>(
+    client: &Client,
+    request: &Request,
+    name: &str,
+    stream: &mut S,
+) -> Result<(), Error> {
+    let installed = client.list_plugins().await?;
+    match (request.method.as_str(), name) {
+        ("GET", "") => {
+            let plugins: Vec<PluginEntry> = installed.iter().map(PluginEntry::from).collect();
+            let body = serde_json::to_vec(&plugins)?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        ("POST", name) if !name.is_empty() => {
+            let (filter, hook, plugin) = match compile_plugin(request) {
+                Ok(compiled) => compiled,
+                Err(message) => {
+                    return Ok(http::respond(
+                        stream,
+                        400,
+                        "Bad Request",
+                        "text/plain",
+                        message.as_bytes(),
+                    )
+                    .await?);
+                }
+            };
+            client.install_hook(name, &filter, hook, plugin)?;
+            let installed = client.list_plugins().await?;
+            let plugin = installed.iter().find(|plugin| plugin.name == name);
+            let body = serde_json::to_vec(&plugin.map(PluginEntry::from))?;
+            Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+        }
+        ("DELETE", name) if !name.is_empty() => {
+            let plugin = installed.iter().find(|plugin| plugin.name == name);
+            match plugin {
+                Some(plugin) if client.remove_plugin(name).await? => {
+                    let body = serde_json::to_vec(&PluginEntry::from(plugin))?;
+                    Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+                }
+                _ => Ok(http::respond(
+                    stream,
+                    404,
+                    "Not Found",
+                    "text/plain",
+                    b"404 - no such plugin",
+                )
+                .await?),
+            }
+        }
+        _ => Ok(http::respond(
+            stream,
+            405,
+            "Method Not Allowed",
+            "text/plain",
+            b"405 - Method Not Allowed",
+        )
+        .await?),
+    }
+}
+
+/// The topic filter, hook and plugin compiled from the body of a `POST /plugins/<name>`
+/// request, or why they cannot be.
+#[cfg(feature = "wasm")]
+fn compile_plugin(request: &Request) -> Result<(String, Hook, Plugin), String> {
+    let filter = request
+        .query_values("topic")
+        .next()
+        .ok_or("expected a topic query parameter")?;
+    TopicFilter::new(filter).map_err(|e| e.to_string())?;
+    let hook = match request.query_values("hook").next().unwrap_or("receive") {
+        "publish" => Hook::Publish,
+        "receive" => Hook::Receive,
+        "both" => Hook::Both,
+        hook => return Err(format!("unknown hook {:?}", hook)),
+    };
+    let mut limits = Limits::default();
+    if let Some(fuel) = request.query_values("fuel").next() {
+        limits.fuel = fuel
+            .parse()
+            .map_err(|_| format!("invalid fuel {:?}", fuel))?;
+    }
+    if let Some(memory) = request.query_values("memory").next() {
+        limits.memory = memory
+            .parse()
+            .map_err(|_| format!("invalid memory {:?}", memory))?;
+    }
+    let plugin = Plugin::new(&request.body, limits).map_err(|e| e.to_string())?;
+    Ok((filter.to_owned(), hook, plugin))
+}
+
+/// Answer requests for plugins on a node built without them.
+#[cfg(not(feature = "wasm"))]
+async fn plugins<S: AsyncWrite + Unpin>(
+    _: &Client,
+    _: &Request,
+    _: &str,
+    stream: &mut S,
+) -> Result<(), Error> {
+    Ok(http::respond(
+        stream,
+        501,
+        "Not Implemented",
+        "text/plain",
+        b"501 - plugins need a node built with the wasm feature",
+    )
+    .await?)
+}
+
+/// Render `stats` in the Prometheus text format.
+fn metrics(stats: &Stats) -> String {
+    let mut out = String::new();
+    let peers: Vec<(String, &Traffic)> = stats
+        .peers
+        .iter()
+        .map(|peer| (format!("peer=\"{}\"", peer.peer.to_base58()), &peer.traffic))
+        .collect();
+    let topics: Vec<(String, &Traffic)> = stats
+        .topics
+        .iter()
+        .map(|topic| {
+            (
+                format!("topic=\"{}\"", escape(&topic.topic)),
+                &topic.traffic,
+            )
+        })
+        .collect();
+    traffic_metrics(&mut out, "peer", "with each connected peer", &peers);
+    traffic_metrics(&mut out, "topic", "on each topic", &topics);
+    let local: Vec<(String, &LocalDelivery)> = stats
+        .topics
+        .iter()
+        .map(|topic| (format!("topic=\"{}\"", escape(&topic.topic)), &topic.local))
+        .collect();
+    metric(
+        &mut out,
+        "pubsub_topic_local_messages_total",
+        "counter",
+        "Messages published on a topic and handed to local subscribers.",
+        local.iter().map(|(l, d)| (l, d.messages.to_string())),
+    );
+    metric(
+        &mut out,
+        "pubsub_topic_local_bytes_total",
+        "counter",
+        "Payload bytes published on a topic and handed to local subscribers.",
+        local.iter().map(|(l, d)| (l, d.bytes.to_string())),
+    );
+    size_metrics(&mut out, stats);
+    let sequence: Vec<(String, &SequenceStats)> = stats
+        .topics
+        .iter()
+        .map(|topic| {
+            (
+                format!("topic=\"{}\"", escape(&topic.topic)),
+                &topic.sequence,
+            )
+        })
+        .collect();
+    metric(
+        &mut out,
+        "pubsub_topic_lost_messages_total",
+        "counter",
+        "Messages of sequenced publishers on a topic found missing.",
+        sequence.iter().map(|(l, s)| (l, s.lost.to_string())),
+    );
+    metric(
+        &mut out,
+        "pubsub_topic_recovered_messages_total",
+        "counter",
+        "Messages of sequenced publishers on a topic that came after being found missing.",
+        sequence.iter().map(|(l, s)| (l, s.recovered.to_string())),
+    );
+
+    out.push_str("# HELP pubsub_peer_graylisted Whether a connected peer is graylisted.\n");
+    out.push_str("# TYPE pubsub_peer_graylisted gauge\n");
+    for peer in &stats.peers {
+        let _ = writeln!(
+            out,
+            "pubsub_peer_graylisted{{peer=\"{}\"}} {}",
+            peer.peer.to_base58(),
+            peer.graylisted as u8
+        );
+    }
+    out.push_str(
+        "# HELP pubsub_graylistings_total Peers graylisted for exceeding their rate limit.\n",
+    );
+    out.push_str("# TYPE pubsub_graylistings_total counter\n");
+    let _ = writeln!(out, "pubsub_graylistings_total {}", stats.graylistings);
+    metric(
+        &mut out,
+        "pubsub_peer_clock_skew_seconds",
+        "gauge",
+        "How far ahead of the clock of the node that of a connected peer is.",
+        peers.iter().zip(&stats.peers).filter_map(|((l, _), peer)| {
+            peer.clock_skew
+                .map(|skew| (l, (skew as f64 / 1_000_000.0).to_string()))
+        }),
+    );
+    validation_metrics(&mut out, stats);
+    memory_metrics(&mut out, stats);
+    peer_exchange_metrics(&mut out, stats);
+    chaos_metrics(&mut out, stats);
+    subscription_metrics(&mut out, stats);
+    priority_metrics(&mut out, stats);
+    bridge_metrics(&mut out, stats);
+    out
+}
+
+/// Append the bytes of the payloads kept in memory, and the messages evicted to fit the budget.
+fn memory_metrics(out: &mut String, stats: &Stats) {
+    let memory = &stats.memory;
+    out.push_str("# HELP pubsub_memory_bytes Bytes of the payloads kept in memory, by cache.\n");
+    out.push_str("# TYPE pubsub_memory_bytes gauge\n");
+    for (cache, bytes) in &[
+        ("message_cache", memory.message_cache),
+        ("retained", memory.retained),
+    ] {
+        let _ = writeln!(out, "pubsub_memory_bytes{{cache=\"{}\"}} {}", cache, bytes);
+    }
+    if let Some(budget) = memory.budget {
+        out.push_str("# HELP pubsub_memory_budget_bytes Bytes the payloads kept may take.\n");
+        out.push_str("# TYPE pubsub_memory_budget_bytes gauge\n");
+        let _ = writeln!(out, "pubsub_memory_budget_bytes {}", budget);
+    }
+    out.push_str(
+        "# HELP pubsub_memory_evictions_total Messages dropped to stay within the memory budget.\n",
+    );
+    out.push_str("# TYPE pubsub_memory_evictions_total counter\n");
+    let _ = writeln!(out, "pubsub_memory_evictions_total {}", memory.evicted);
+}
+
+/// Append the peers exchanged with the peers pruning the node from meshes.
+fn peer_exchange_metrics(out: &mut String, stats: &Stats) {
+    let peer_exchange = &stats.peer_exchange;
+    out.push_str(
+        "# HELP pubsub_peer_records Connected peers whose signed record is known, to suggest them.\n",
+    );
+    out.push_str("# TYPE pubsub_peer_records gauge\n");
+    let _ = writeln!(out, "pubsub_peer_records {}", peer_exchange.records);
+    out.push_str(
+        "# HELP pubsub_px_suggested_peers_total Peers suggested along with the PRUNEs received.\n",
+    );
+    out.push_str("# TYPE pubsub_px_suggested_peers_total counter\n");
+    let _ = writeln!(
+        out,
+        "pubsub_px_suggested_peers_total {}",
+        peer_exchange.suggested
+    );
+    out.push_str("# HELP pubsub_px_dials_total Addresses of suggested peers dialed.\n");
+    out.push_str("# TYPE pubsub_px_dials_total counter\n");
+    let _ = writeln!(out, "pubsub_px_dials_total {}", peer_exchange.dialed);
+}
+
+/// Append the faults injected into gossip.
+fn chaos_metrics(out: &mut String, stats: &Stats) {
+    let chaos = &stats.chaos;
+    out.push_str("# HELP pubsub_chaos_frames_total Gossipsub frames faults were injected into.\n");
+    out.push_str("# TYPE pubsub_chaos_frames_total counter\n");
+    for (fault, count) in &[
+        ("dropped", chaos.dropped),
+        ("duplicated", chaos.duplicated),
+        ("delayed", chaos.delayed),
+    ] {
+        let _ = writeln!(
+            out,
+            "pubsub_chaos_frames_total{{fault=\"{}\"}} {}",
+            fault, count
+        );
+    }
+}
+
+/// Append the outcomes of the validation of received messages.
+fn validation_metrics(out: &mut String, stats: &Stats) {
+    let validation = &stats.validation;
+    out.push_str("# HELP pubsub_validation_pending Received messages waiting to be validated.\n");
+    out.push_str("# TYPE pubsub_validation_pending gauge\n");
+    let _ = writeln!(out, "pubsub_validation_pending {}", validation.pending);
+    out.push_str(
+        "# HELP pubsub_validated_messages_total Received messages validated, by outcome.\n",
+    );
+    out.push_str("# TYPE pubsub_validated_messages_total counter\n");
+    for (outcome, count) in &[
+        ("accepted", validation.accepted),
+        ("rejected", validation.rejected),
+        ("dropped", validation.dropped),
+    ] {
+        let _ = writeln!(
+            out,
+            "pubsub_validated_messages_total{{outcome=\"{}\"}} {}",
+            outcome, count
+        );
+    }
+}
+
+/// Append the queue and queueing delay metrics of every priority.
+fn priority_metrics(out: &mut String, stats: &Stats) {
+    let priorities: Vec<(String, _)> = stats
+        .priorities
+        .iter()
+        .map(|priority| {
+            (
+                format!("priority=\"{}\"", priority.priority.as_str()),
+                priority,
+            )
+        })
+        .collect();
+    metric(
+        out,
+        "pubsub_priority_queued_messages",
+        "gauge",
+        "Messages of a priority waiting for the upload limit.",
+        priorities.iter().map(|(l, p)| (l, p.queued.to_string())),
+    );
+    metric(
+        out,
+        "pubsub_priority_dropped_total",
+        "counter",
+        "Messages of a priority dropped because too many were waiting.",
+        priorities.iter().map(|(l, p)| (l, p.dropped.to_string())),
+    );
+    out.push_str(
+        "# HELP pubsub_priority_queueing_delay_seconds How long the messages of a priority \
+         waited for the upload limit.\n",
+    );
+    out.push_str("# TYPE pubsub_priority_queueing_delay_seconds histogram\n");
+    for (label, priority) in &priorities {
+        let mut cumulative = 0;
+        for (bound, count) in DELAY_BUCKETS.iter().zip(&priority.delay.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "pubsub_priority_queueing_delay_seconds_bucket{{{},le=\"{}\"}} {}",
+                label,
+                bound.as_secs_f64(),
+                cumulative
+            );
+        }
+        let count = priority.delay.count();
+        let _ = writeln!(
+            out,
+            "pubsub_priority_queueing_delay_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            label, count
+        );
+        let _ = writeln!(
+            out,
+            "pubsub_priority_queueing_delay_seconds_sum{{{}}} {}",
+            label,
+            priority.delay.sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "pubsub_priority_queueing_delay_seconds_count{{{}}} {}",
+            label, count
+        );
+    }
+}
+
+/// Append the health metrics of every bridge and sink.
+fn bridge_metrics(out: &mut String, stats: &Stats) {
+    let bridges: Vec<(String, &BridgeStats)> = stats
+        .bridges
+        .iter()
+        .map(|bridge| (format!("bridge=\"{}\"", escape(&bridge.name)), bridge))
+        .collect();
+    metric(
+        out,
+        "pubsub_bridge_connected",
+        "gauge",
+        "Whether a bridge is connected to its external system.",
+        bridges
+            .iter()
+            .map(|(l, b)| (l, (b.connected as u8).to_string())),
+    );
+    metric(
+        out,
+        "pubsub_bridge_state_seconds",
+        "gauge",
+        "How long a bridge has been connected, or disconnected.",
+        bridges
+            .iter()
+            .map(|(l, b)| (l, b.since.as_secs_f64().to_string())),
+    );
+    metric(
+        out,
+        "pubsub_bridge_retries_total",
+        "counter",
+        "Failed connection attempts and dropped connections of a bridge.",
+        bridges.iter().map(|(l, b)| (l, b.retries.to_string())),
+    );
+    let traffic: Vec<(String, &Traffic)> = bridges
+        .iter()
+        .map(|(labels, bridge)| (labels.clone(), &bridge.traffic))
+        .collect();
+    traffic_metrics(out, "bridge", "across each bridge", &traffic);
+    metric(
+        out,
+        "pubsub_bridge_queued",
+        "gauge",
+        "Mesh messages waiting to cross a bridge.",
+        bridges
+            .iter()
+            .map(|(l, b)| (l, b.outbox.queued.to_string())),
+    );
+    metric(
+        out,
+        "pubsub_bridge_lag_seconds",
+        "gauge",
+        "How long the oldest mesh message waiting to cross a bridge has waited.",
+        bridges
+            .iter()
+            .map(|(l, b)| (l, b.outbox.lag.as_secs_f64().to_string())),
+    );
+    metric(
+        out,
+        "pubsub_bridge_dropped_total",
+        "counter",
+        "Mesh messages dropped because a bridge fell behind.",
+        bridges
+            .iter()
+            .map(|(l, b)| (l, b.outbox.dropped.to_string())),
+    );
+}
+
+/// Append the histogram of the payload sizes of every topic, and its oversized payloads.
+fn size_metrics(out: &mut String, stats: &Stats) {
+    out.push_str(
+        "# HELP pubsub_topic_payload_size_bytes Sizes of the payloads published and received on \
+         a topic.\n",
+    );
+    out.push_str("# TYPE pubsub_topic_payload_size_bytes histogram\n");
+    for topic in &stats.topics {
+        let label = format!("topic=\"{}\"", escape(&topic.topic));
+        let mut cumulative = 0;
+        for (bound, count) in SIZE_BUCKETS.iter().zip(&topic.sizes.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "pubsub_topic_payload_size_bytes_bucket{{{},le=\"{}\"}} {}",
+                label, bound, cumulative
+            );
+        }
+        let count = topic.sizes.count();
+        let _ = writeln!(
+            out,
+            "pubsub_topic_payload_size_bytes_bucket{{{},le=\"+Inf\"}} {}",
+            label, count
+        );
+        let _ = writeln!(
+            out,
+            "pubsub_topic_payload_size_bytes_sum{{{}}} {}",
+            label, topic.sizes.sum
+        );
+        let _ = writeln!(
+            out,
+            "pubsub_topic_payload_size_bytes_count{{{}}} {}",
+            label, count
+        );
+    }
+    let oversized: Vec<(String, u64)> = stats
+        .topics
+        .iter()
+        .map(|topic| {
+            let label = format!("topic=\"{}\"", escape(&topic.topic));
+            (label, topic.sizes.oversized)
+        })
+        .collect();
+    metric(
+        out,
+        "pubsub_topic_oversized_total",
+        "counter",
+        "Payloads received over the size limit of a topic and dropped.",
+        oversized.iter().map(|(l, n)| (l, n.to_string())),
+    );
+}
+
+/// Append the queue metrics of every local subscription.
+fn subscription_metrics(out: &mut String, stats: &Stats) {
+    let queues: Vec<(String, &QueueStatus)> = stats
+        .subscriptions
+        .iter()
+        .map(|s| {
+            let labels = format!("subscription=\"{}\",topic=\"{}\"", s.id, escape(&s.topic));
+            (labels, &s.queue)
+        })
+        .collect();
+    metric(
+        out,
+        "pubsub_subscription_queued",
+        "gauge",
+        "Messages waiting for a local subscriber.",
+        queues.iter().map(|(l, q)| (l, q.queued.to_string())),
+    );
+    metric(
+        out,
+        "pubsub_subscription_lag_seconds",
+        "gauge",
+        "How long the oldest message waiting for a local subscriber has waited.",
+        queues
+            .iter()
+            .map(|(l, q)| (l, q.lag.as_secs_f64().to_string())),
+    );
+    metric(
+        out,
+        "pubsub_subscription_dropped_total",
+        "counter",
+        "Messages dropped because a local subscriber fell behind.",
+        queues.iter().map(|(l, q)| (l, q.dropped.to_string())),
+    );
+}
+
+/// Append the metric `name` of type `kind` with a value for each label set.
+fn metric<'a>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a String, String)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in values {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Append the byte and message counters of `traffic`, each labelled with its label set.
+fn traffic_metrics(out: &mut String, subject: &str, scope: &str, traffic: &[(String, &Traffic)]) {
+    counter(
+        out,
+        &format!("pubsub_{}_bytes_total", subject),
+        &format!("Bytes exchanged {}.", scope),
+        traffic
+            .iter()
+            .map(|(labels, t)| (labels, t.bytes_in, t.bytes_out)),
+    );
+    counter(
+        out,
+        &format!("pubsub_{}_messages_total", subject),
+        &format!("Messages exchanged {}.", scope),
+        traffic
+            .iter()
+            .map(|(labels, t)| (labels, t.messages_in, t.messages_out)),
+    );
+}
+
+/// Append the counter `name` with a received and a sent value for each label set.
+fn counter<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a String, u64, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (labels, received, sent) in values {
+        let _ = writeln!(out, "{}{{{},direction=\"in\"}} {}", name, labels, received);
+        let _ = writeln!(out, "{}{{{},direction=\"out\"}} {}", name, labels, sent);
+    }
+}
+
+/// Escape a Prometheus label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
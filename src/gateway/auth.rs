@@ -0,0 +1,268 @@
+//! Authentication and authorization of the clients of the gateways.
+//!
+//! A gateway spawned with an [`AuthConfig`] can serve over TLS, optionally requiring clients to
+//! present a certificate signed by a given authority, and can require a bearer token, sent in
+//! an `Authorization: Bearer <token>` header or, for browsers opening a WebSocket, an
+//! `access_token` query parameter. Every token grants an [`Access`]:
+//! [`ReadOnly`](Access::ReadOnly) lets a client subscribe and list topics, peers and metrics,
+//! [`ReadWrite`](Access::ReadWrite) also lets it publish and change the state of the node.
+//! Clients authenticated by their certificate get the
+//! [`client_access`](TlsConfig::client_access) of the TLS configuration, unless they also send
+//! a token.
+//!
+//! Without tokens nor client certificates, every client gets read-write access, as is the case
+//! of the default configuration and of the admin endpoint served on a unix socket, which only
+//! its owner can connect to.
+
+use super::http::{self, Request};
+use crate::{Error, PubSubError};
+use async_std::io;
+use async_tls::TlsAcceptor;
+use futures::prelude::*;
+use ring::constant_time;
+use rustls::{
+    internal::pemfile, AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig,
+};
+use std::sync::Arc;
+
+/// What a client may do through a gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Access {
+    /// Subscribe, and list topics, peers and metrics.
+    ReadOnly,
+    /// Also publish, and change the state of the node.
+    ReadWrite,
+}
+
+/// Certificate of a gateway, and of the authority signing the certificates of its clients if
+/// they must present one.
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// Certificate of the gateway followed by the intermediate ones, in PEM.
+    pub cert_chain: Vec<u8>,
+    /// PKCS #8 or RSA private key of the certificate, in PEM.
+    pub private_key: Vec<u8>,
+    /// Certificates of the authorities signing the certificates of clients, in PEM. Clients
+    /// without such a certificate are refused during the handshake.
+    pub client_ca: Option<Vec<u8>>,
+    /// Access of clients authenticated by their certificate.
+    pub client_access: Access,
+}
+
+/// How a gateway authenticates its clients and what it lets them do.
+#[derive(Clone, Default)]
+pub struct AuthConfig {
+    /// Serve over TLS, rather than in the clear.
+    pub tls: Option<TlsConfig>,
+    /// Bearer tokens clients may authenticate with, and the access each grants.
+    pub tokens: Vec<(String, Access)>,
+}
+
+impl AuthConfig {
+    /// Build the acceptor of TLS connections, if the gateway serves over TLS.
+    pub(crate) fn acceptor(&self) -> Result<Option<TlsAcceptor>, Error> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return Ok(None),
+        };
+        let verifier = match &tls.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                match roots.add_pem_file(&mut &client_ca[..]) {
+                    Ok((added, _)) if added > 0 => {}
+                    _ => return Err("no client authority certificate in PEM".into()),
+                }
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+        let cert_chain = pemfile::certs(&mut &tls.cert_chain[..])
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or("no certificate in PEM")?;
+        let private_key = pemfile::pkcs8_private_keys(&mut &tls.private_key[..])
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| pemfile::rsa_private_keys(&mut &tls.private_key[..]).ok())
+            .and_then(|keys| keys.into_iter().next())
+            .ok_or("no private key in PEM")?;
+        let mut config = ServerConfig::new(verifier);
+        config
+            .set_single_cert(cert_chain, private_key)
+            .map_err(PubSubError::config)?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
+    /// The gate of the connections to a gateway configured so.
+    pub(crate) fn gate(&self) -> Gate {
+        let certified = self.tls.as_ref().filter(|tls| tls.client_ca.is_some());
+        let default = match certified {
+            Some(tls) => Some(tls.client_access),
+            None if self.tokens.is_empty() => Some(Access::ReadWrite),
+            None => None,
+        };
+        Gate {
+            tokens: Arc::new(self.tokens.clone()),
+            default,
+        }
+    }
+}
+
+/// Decides what the requests of a connection may do.
+#[derive(Clone)]
+pub(crate) struct Gate {
+    tokens: Arc<Vec<(String, Access)>>,
+    /// Access of requests without a token.
+    default: Option<Access>,
+}
+
+impl Gate {
+    /// The gate of connections trusted with read-write access.
+    pub fn open() -> Self {
+        Gate {
+            tokens: Arc::default(),
+            default: Some(Access::ReadWrite),
+        }
+    }
+
+    /// Access granted to the sender of `request`, if any.
+    fn access(&self, request: &Request) -> Option<Access> {
+        let header = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        self.access_of(header.or_else(|| request.query_values("access_token").next()))
+    }
+
+    /// Access granted to the sender of `token`, or to one sending none, if any.
+    pub fn access_of(&self, token: Option<&str>) -> Option<Access> {
+        let token = match token {
+            Some(token) => token.trim(),
+            None => return self.default,
+        };
+        self.tokens
+            .iter()
+            .find(|(known, _)| {
+                constant_time::verify_slices_are_equal(known.as_bytes(), token.as_bytes()).is_ok()
+            })
+            .map(|(_, access)| *access)
+    }
+
+    /// Check that the sender of `request` has `required` access, returning the access it has,
+    /// or answering it with an error and returning `None`.
+    pub async fn check<S: AsyncWrite + Unpin>(
+        &self,
+        request: &Request,
+        required: Access,
+        stream: &mut S,
+    ) -> io::Result<Option<Access>> {
+        match self.access(request) {
+            Some(access) if access >= required => Ok(Some(access)),
+            Some(_) => {
+                let body = b"403 - Forbidden: read-only access";
+                http::respond(stream, 403, "Forbidden", "text/plain", body).await?;
+                Ok(None)
+            }
+            None => {
+                let body = b"401 - Unauthorized";
+                http::respond_with(
+                    stream,
+                    401,
+                    "Unauthorized",
+                    &[
+                        ("Content-Type", "text/plain"),
+                        ("WWW-Authenticate", "Bearer"),
+                    ],
+                    body,
+                )
+                .await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use futures::io::Cursor;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            tls: None,
+            tokens: vec![
+                ("reader".into(), Access::ReadOnly),
+                ("writer".into(), Access::ReadWrite),
+            ],
+        }
+    }
+
+    /// What `gate` answers a GET carrying `headers`, if it refuses the request.
+    fn answer(gate: &Gate, headers: &str, required: Access) -> Option<String> {
+        let request = format!("GET /topics HTTP/1.1\r\n{}\r\n", headers);
+        let request =
+            task::block_on(http::read_request(&mut Cursor::new(request.into_bytes()))).unwrap();
+        let mut response = Vec::new();
+        let access = task::block_on(gate.check(&request, required, &mut response)).unwrap();
+        match access {
+            Some(_) => None,
+            None => Some(String::from_utf8(response).unwrap()),
+        }
+    }
+
+    #[test]
+    fn tokens_grant_their_access() {
+        let gate = config().gate();
+        assert_eq!(gate.access_of(Some("reader")), Some(Access::ReadOnly));
+        assert_eq!(gate.access_of(Some(" writer ")), Some(Access::ReadWrite));
+        assert_eq!(gate.access_of(Some("writer2")), None);
+        assert_eq!(gate.access_of(Some("")), None);
+        assert_eq!(gate.access_of(None), None);
+        assert_eq!(
+            AuthConfig::default().gate().access_of(None),
+            Some(Access::ReadWrite)
+        );
+    }
+
+    #[test]
+    fn refuses_missing_unknown_and_insufficient_tokens() {
+        let gate = config().gate();
+        let unauthorized = answer(&gate, "", Access::ReadOnly).unwrap();
+        assert!(unauthorized.starts_with("HTTP/1.1 401"), "{}", unauthorized);
+        assert!(unauthorized.contains("WWW-Authenticate: Bearer"));
+        let unknown = answer(
+            &gate,
+            "Authorization: Bearer intruder\r\n",
+            Access::ReadOnly,
+        );
+        assert!(unknown.unwrap().starts_with("HTTP/1.1 401"));
+        let forbidden = answer(&gate, "Authorization: Bearer reader\r\n", Access::ReadWrite);
+        assert!(forbidden.unwrap().starts_with("HTTP/1.1 403"));
+        assert_eq!(
+            answer(&gate, "Authorization: Bearer writer\r\n", Access::ReadWrite),
+            None
+        );
+        let query = "GET /ws?access_token=reader HTTP/1.1\r\n\r\n";
+        let request = task::block_on(http::read_request(&mut Cursor::new(
+            query.as_bytes().to_vec(),
+        )))
+        .unwrap();
+        assert_eq!(gate.access(&request), Some(Access::ReadOnly));
+    }
+
+    #[test]
+    fn client_certificates_grant_the_access_configured() {
+        let config = AuthConfig {
+            tls: Some(TlsConfig {
+                cert_chain: Vec::new(),
+                private_key: Vec::new(),
+                client_ca: Some(Vec::new()),
+                client_access: Access::ReadOnly,
+            }),
+            tokens: Vec::new(),
+        };
+        assert_eq!(config.gate().access_of(None), Some(Access::ReadOnly));
+        // No authority in the PEM given.
+        assert!(config.acceptor().is_err());
+    }
+}
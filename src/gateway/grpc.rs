@@ -0,0 +1,630 @@
+//! A gRPC server of the PubSubAPI service of `src/pb/pubsub.proto`, whose bindings are in the
+//! [`pb`](crate::pb) module, for clients generated from the proto in any language.
+//!
+//! - `PubSub` carries a stream of requests, each answered in order. `PS_GET_TOPICS` answers with
+//!   the topics the node has local subscribers for. `PS_LIST_PEERS` answers with the peers
+//!   subscribed to the `topics` of the request, to any topic if it names none. `PS_SUBSCRIBE`
+//!   subscribes to the `topics` of the request, topics or wildcard filters, answering with the
+//!   `subscriptionID` once subscribed, then pushing every message received on them in a response
+//!   of its own, with its `resumeSeq`. Messages wait for a slow client in a queue bounded like the
+//!   default [`FlowControl`], which drops the oldest of them once it is full. `PS_RESUME` picks up
+//!   a subscription of a stream that ended, like the `resume` request of the
+//!   [WebSocket gateway](super::ws): subscriptions outlive their stream for
+//!   [`RESUME_WINDOW`](super::ws::RESUME_WINDOW), and the messages after the `lastSeq` of the
+//!   request are pushed again, the answer holding the `gapUntil` of the oldest message kept if
+//!   some of them were lost. `PS_PUBLISH` publishes `data` on every topic of the request,
+//!   answering once the node took it in. A request failing ends the stream with its status.
+//! - `PublishStream` publishes every message of a stream of `PublishRequest`s as soon as it
+//!   comes, and answers with a `PublishAck` per message, in the same order, once the node handed
+//!   the message to gossipsub: the ack holds the `messageID` it was sent with, see the
+//!   [`message_id`](crate::message_id) module, or the `error` that kept the node from sending
+//!   it. At most [`MAX_UNACKED`] messages wait for their ack at once, the stream being read no
+//!   further until the oldest is acknowledged.
+//!
+//! The gateway serves gRPC server reflection too, for tools like grpcurl to find the PubSubAPI
+//! and its messages without the proto, from [`FILE_DESCRIPTOR_SET`](crate::pb::FILE_DESCRIPTOR_SET).
+//!
+//! Served with [`spawn_with_auth`], the gateway asks clients to authenticate with an
+//! `authorization: Bearer <token>` metadata entry, see the [`auth`](super::auth) module;
+//! `PS_PUBLISH` requests and `PublishStream` need read-write access.
+//!
+//! Connections are accepted like those of the other gateways, and served by a tokio runtime on
+//! a thread of its own, which tonic needs.
+//!
+//! The gateway is behind the `grpc` cargo feature, on by default.
+
+use super::{
+    auth::{Access, AuthConfig, Gate},
+    resume::{Push, Resumable, Resumables},
+    Connection,
+};
+use crate::{
+    flow::{self, FlowControl},
+    message_id::Published,
+    pb::{
+        pub_sub_api_server::{PubSubApi, PubSubApiServer},
+        reflection::{
+            server_reflection_request::MessageRequest,
+            server_reflection_response::MessageResponse,
+            server_reflection_server::{ServerReflection, ServerReflectionServer},
+            ErrorResponse, FileDescriptorResponse, ListServiceResponse, ServerReflectionRequest,
+            ServerReflectionResponse, ServiceResponse,
+        },
+        Psreqtype, PubSubMessage, PubSubPeer, PubSubRequest, PubSubResponse, PublishAck,
+        PublishRequest,
+    },
+    topic::TopicFilter,
+    Client, Error, Message, PubSubError,
+};
+use async_std::{io, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    stream::{self, FuturesOrdered},
+};
+use prost::Message as _;
+use prost_types::{FileDescriptorProto, FileDescriptorSet};
+use std::{
+    collections::HashMap,
+    net::ToSocketAddrs,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    thread,
+};
+use tonic::{
+    metadata::MetadataMap,
+    transport::{server::Connected, Server},
+    Request, Response, Status, Streaming,
+};
+
+/// Messages of a `PublishStream` published but not acknowledged yet, beyond which the stream is
+/// read no further.
+pub const MAX_UNACKED: usize = 1024;
+
+/// Start serving gRPC clients on `addr`.
+pub fn spawn(client: &Client, addr: impl ToSocketAddrs) -> Result<task::JoinHandle<()>, Error> {
+    spawn_with_auth(client, addr, AuthConfig::default())
+}
+
+/// Start serving gRPC clients on `addr`, authenticating them as `auth` says.
+pub fn spawn_with_auth(
+    client: &Client,
+    addr: impl ToSocketAddrs,
+    auth: AuthConfig,
+) -> Result<task::JoinHandle<()>, Error> {
+    let connections = run(client, auth.gate())?;
+    super::serve(
+        client,
+        addr,
+        "gRPC gateway",
+        &auth,
+        move |_, connection, _| {
+            let _ = connections.unbounded_send(Ok(Io(connection)));
+            future::ready(())
+        },
+    )
+}
+
+/// Start serving gRPC clients on the unix socket at `path`, trusting them with read-write
+/// access.
+pub fn spawn_unix(client: &Client, path: impl AsRef<Path>) -> Result<task::JoinHandle<()>, Error> {
+    let connections = run(client, Gate::open())?;
+    super::serve_unix(
+        client,
+        path.as_ref(),
+        "gRPC gateway",
+        move |_, connection, _| {
+            let _ = connections.unbounded_send(Ok(Io(connection)));
+            future::ready(())
+        },
+    )
+}
+
+/// Serve the connections sent on the returned channel on a thread of their own, until it
+/// closes.
+fn run(client: &Client, gate: Gate) -> Result<mpsc::UnboundedSender<io::Result<Io>>, Error> {
+    let (connections, incoming) = mpsc::unbounded();
+    let files = FileDescriptorSet::decode(crate::pb::FILE_DESCRIPTOR_SET)
+        .map_err(PubSubError::codec)?
+        .file;
+    let reflection = ServerReflectionServer::new(Reflection {
+        gate: gate.clone(),
+        files: Arc::new(files),
+    });
+    let service = PubSubApiServer::new(Service {
+        client: client.clone(),
+        gate,
+        resumables: Resumables::new(),
+    });
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    thread::Builder::new()
+        .name("grpc-gateway".into())
+        .spawn(move || {
+            let server = Server::builder()
+                .add_service(service)
+                .add_service(reflection)
+                .serve_with_incoming(incoming);
+            if let Err(e) = runtime.block_on(server) {
+                log::warn!("gRPC gateway: {}", e);
+            }
+        })?;
+    Ok(connections)
+}
+
+/// A connection accepted by the gateway, read and written by tonic.
+struct Io(Box<dyn Connection>);
+
+impl Connected for Io {}
+
+impl tokio::io::AsyncRead for Io {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for Io {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+struct Service {
+    client: Client,
+    gate: Gate,
+    resumables: Resumables<PubSubResponse>,
+}
+
+impl Service {
+    fn check(&self, metadata: &MetadataMap, required: Access) -> Result<Access, Status> {
+        check(&self.gate, metadata, required)
+    }
+}
+
+/// Check that the sender of a request with `metadata` has `required` access through `gate`,
+/// returning the access it has.
+fn check(gate: &Gate, metadata: &MetadataMap, required: Access) -> Result<Access, Status> {
+    let token = match metadata.get("authorization") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or_else(|| Status::unauthenticated("not a bearer token"))?,
+        ),
+        None => None,
+    };
+    match gate.access_of(token) {
+        Some(access) if access >= required => Ok(access),
+        Some(_) => Err(Status::permission_denied("read-only access")),
+        None => Err(Status::unauthenticated("unknown or missing bearer token")),
+    }
+}
+
+#[tonic::async_trait]
+impl PubSubApi for Service {
+    type PubSubStream = Responses;
+
+    async fn pub_sub(
+        &self,
+        request: Request<Streaming<PubSubRequest>>,
+    ) -> Result<Response<Responses>, Status> {
+        let access = self.check(request.metadata(), Access::ReadOnly)?;
+        let outbox = FlowControl::default();
+        let (answers_out, answers) = mpsc::channel(outbox.capacity);
+        let (pushes_out, pushes) = flow::channel(outbox.capacity, outbox.overflow);
+        let (closed, dropped) = oneshot::channel();
+        let session = session(
+            Session {
+                client: self.client.clone(),
+                access,
+                resumables: self.resumables.clone(),
+                subscriptions: HashMap::new(),
+                pushes: pushes_out,
+            },
+            request.into_inner(),
+            answers_out,
+            dropped,
+        );
+        task::spawn(session);
+        Ok(Response::new(Responses {
+            answers,
+            pushes,
+            _closed: closed,
+        }))
+    }
+
+    type PublishStreamStream = mpsc::Receiver<Result<PublishAck, Status>>;
+
+    async fn publish_stream(
+        &self,
+        request: Request<Streaming<PublishRequest>>,
+    ) -> Result<Response<Self::PublishStreamStream>, Status> {
+        self.check(request.metadata(), Access::ReadWrite)?;
+        let (acks, receiver) = mpsc::channel(FlowControl::default().capacity);
+        task::spawn(publish_stream(
+            self.client.clone(),
+            request.into_inner(),
+            acks,
+        ));
+        Ok(Response::new(receiver))
+    }
+}
+
+/// The responses of a `PubSub` stream: the answers of its requests, ahead of the messages of its
+/// subscriptions. Tells its session when the client goes away.
+pub struct Responses {
+    answers: mpsc::Receiver<Result<PubSubResponse, Status>>,
+    pushes: flow::Receiver<PubSubResponse>,
+    _closed: oneshot::Sender<()>,
+}
+
+impl Stream for Responses {
+    type Item = Result<PubSubResponse, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(answer) = self.answers.poll_next_unpin(cx) {
+            return Poll::Ready(answer);
+        }
+        match self.pushes.poll_next_unpin(cx) {
+            Poll::Ready(Some(push)) => Poll::Ready(Some(Ok(push))),
+            // Subscriptions come and go while the stream lasts.
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl Push for PubSubResponse {
+    fn msg(message: &Message, resume_seq: u64) -> Self {
+        PubSubResponse {
+            request_type: Psreqtype::PsSubscribe as i32,
+            message: vec![PubSubMessage {
+                from: message.source.as_bytes().to_vec(),
+                data: message.data.to_vec(),
+                seqno: message.sequence_number.to_be_bytes().to_vec(),
+                topic_i_ds: vec![message.topic.clone()],
+                resume_seq,
+                ..PubSubMessage::default()
+            }],
+            ..PubSubResponse::default()
+        }
+    }
+}
+
+/// What the requests of a `PubSub` stream are served with.
+struct Session {
+    client: Client,
+    access: Access,
+    resumables: Resumables<PubSubResponse>,
+    /// The subscriptions made or resumed by the stream, by id.
+    subscriptions: HashMap<String, Arc<Resumable<PubSubResponse>>>,
+    /// Queue of the messages of the subscriptions.
+    pushes: flow::Sender<PubSubResponse>,
+}
+
+/// Serve the requests of a `PubSub` stream until the client goes away or a request fails, then
+/// leave its subscriptions to be resumed.
+async fn session(
+    mut session: Session,
+    mut requests: Streaming<PubSubRequest>,
+    mut answers: mpsc::Sender<Result<PubSubResponse, Status>>,
+    closed: oneshot::Receiver<()>,
+) {
+    let mut closed = closed.fuse();
+    // The client may stop sending requests but still receive messages.
+    let mut requests_done = false;
+    loop {
+        let request = futures::select! {
+            request = requests.next().fuse() => request,
+            _ = closed => break,
+        };
+        let request = match request {
+            Some(Ok(request)) => request,
+            Some(Err(status)) => {
+                log::debug!("gRPC gateway: {}", status);
+                break;
+            }
+            None => {
+                requests_done = true;
+                break;
+            }
+        };
+        let answer = session.request(request).await;
+        let failed = answer.is_err();
+        if answers.send(answer).await.is_err() || failed {
+            break;
+        }
+    }
+    if requests_done {
+        let _ = closed.await;
+    }
+    for (_, subscription) in session.subscriptions {
+        subscription.detach(&session.pushes);
+    }
+}
+
+impl Session {
+    /// Carry out one request of a `PubSub` stream, returning the answer to send.
+    async fn request(&mut self, request: PubSubRequest) -> Result<PubSubResponse, Status> {
+        let request_type = Psreqtype::from_i32(request.request_type)
+            .ok_or_else(|| Status::invalid_argument("unknown request type"))?;
+        let mut response = PubSubResponse {
+            request_type: request_type as i32,
+            ..PubSubResponse::default()
+        };
+        let client = &self.client;
+        match request_type {
+            Psreqtype::PsGetTopics => response.topics = client.topics().await.map_err(status)?,
+            Psreqtype::PsListPeers if request.topics.is_empty() => {
+                let peers = client.peers(None).await.map_err(status)?;
+                response.peers = peers
+                    .into_iter()
+                    .map(|peer| PubSubPeer {
+                        topic: String::new(),
+                        peer_id: peer.to_base58(),
+                    })
+                    .collect();
+            }
+            Psreqtype::PsListPeers => {
+                for topic in request.topics {
+                    let peers = client.peers(Some(&topic)).await.map_err(status)?;
+                    response
+                        .peers
+                        .extend(peers.into_iter().map(|peer| PubSubPeer {
+                            topic: topic.clone(),
+                            peer_id: peer.to_base58(),
+                        }));
+                }
+            }
+            Psreqtype::PsSubscribe if request.topics.is_empty() => {
+                return Err(Status::invalid_argument("no topic to subscribe to"));
+            }
+            Psreqtype::PsSubscribe => {
+                let mut subscriptions = Vec::with_capacity(request.topics.len());
+                for topic in &request.topics {
+                    let filter = TopicFilter::new(topic).map_err(|e| status(e.into()))?;
+                    let subscription = match filter.is_wildcard() {
+                        true => client.subscribe_filter(topic),
+                        false => client.subscribe(topic),
+                    };
+                    subscriptions.push(subscription.map_err(status)?);
+                }
+                let resumable = self
+                    .resumables
+                    .spawn(
+                        request.topics,
+                        stream::select_all(subscriptions),
+                        &self.pushes,
+                    )
+                    .map_err(status)?;
+                response.subscription_id = resumable.id.clone();
+                self.subscriptions.insert(resumable.id.clone(), resumable);
+            }
+            Psreqtype::PsResume => {
+                let resumable = self
+                    .resumables
+                    .find(&request.subscription_id)
+                    .map_err(|e| Status::not_found(e.to_string()))?;
+                let gap_until = resumable
+                    .resume(request.last_seq, &self.pushes)
+                    .map_err(|e| Status::failed_precondition(e.to_string()))?;
+                response.subscription_id = resumable.id.clone();
+                response.gap_until = gap_until.unwrap_or(0);
+                self.subscriptions.insert(resumable.id.clone(), resumable);
+            }
+            Psreqtype::PsPublish if self.access < Access::ReadWrite => {
+                return Err(Status::permission_denied("read-only access"));
+            }
+            Psreqtype::PsPublish => {
+                for topic in &request.topics {
+                    client
+                        .publish(topic, request.data.clone())
+                        .map_err(status)?;
+                }
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Publish the messages of a `PublishStream` as they come, sending their acks in order.
+async fn publish_stream(
+    client: Client,
+    mut requests: Streaming<PublishRequest>,
+    mut acks: mpsc::Sender<Result<PublishAck, Status>>,
+) {
+    let mut unacked = FuturesOrdered::new();
+    let mut reading = true;
+    loop {
+        let next = if reading && unacked.is_empty() {
+            Next::Request(requests.next().await)
+        } else if !reading || unacked.len() >= MAX_UNACKED {
+            Next::Ack(unacked.next().await)
+        } else {
+            futures::select! {
+                request = requests.next().fuse() => Next::Request(request),
+                ack = unacked.next().fuse() => Next::Ack(ack),
+            }
+        };
+        match next {
+            Next::Request(Some(Ok(request))) => unacked.push(ack(&client, request)),
+            Next::Request(Some(Err(status))) => {
+                log::debug!("gRPC gateway: {}", status);
+                return;
+            }
+            Next::Request(None) => reading = false,
+            Next::Ack(Some(ack)) => {
+                if acks.send(Ok(ack)).await.is_err() {
+                    return;
+                }
+            }
+            Next::Ack(None) => return,
+        }
+    }
+}
+
+/// What a `PublishStream` got to next: a request of the client, or the ack of the oldest
+/// message, `None` once there is no more.
+enum Next {
+    Request(Option<Result<PublishRequest, Status>>),
+    Ack(Option<PublishAck>),
+}
+
+/// Publish the message of `request`, returning the future of its ack.
+fn ack(client: &Client, request: PublishRequest) -> impl Future<Output = PublishAck> {
+    let correlation_id = request.correlation_id;
+    let published: Result<Published, Error> = client.publish_with_id(&request.topic, request.data);
+    async move {
+        let published = match published {
+            Ok(published) => published.await,
+            Err(e) => Err(e),
+        };
+        match published {
+            Ok(id) => PublishAck {
+                correlation_id,
+                message_id: id.0,
+                error: String::new(),
+            },
+            Err(e) => PublishAck {
+                correlation_id,
+                message_id: String::new(),
+                error: e.to_string(),
+            },
+        }
+    }
+}
+
+/// The server reflection service, answering from the descriptors of `files`.
+struct Reflection {
+    gate: Gate,
+    files: Arc<Vec<FileDescriptorProto>>,
+}
+
+#[tonic::async_trait]
+impl ServerReflection for Reflection {
+    type ServerReflectionInfoStream = mpsc::Receiver<Result<ServerReflectionResponse, Status>>;
+
+    async fn server_reflection_info(
+        &self,
+        request: Request<Streaming<ServerReflectionRequest>>,
+    ) -> Result<Response<Self::ServerReflectionInfoStream>, Status> {
+        check(&self.gate, request.metadata(), Access::ReadOnly)?;
+        let mut requests = request.into_inner();
+        let (mut responses, receiver) = mpsc::channel(1);
+        let files = self.files.clone();
+        task::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let response = request.map(|request| ServerReflectionResponse {
+                    valid_host: request.host.clone(),
+                    message_response: Some(reflect(&files, &request)),
+                    original_request: Some(request),
+                });
+                if responses.send(response).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(receiver))
+    }
+}
+
+/// The answer to `request` from the descriptors of `files`.
+fn reflect(files: &[FileDescriptorProto], request: &ServerReflectionRequest) -> MessageResponse {
+    let file = match &request.message_request {
+        Some(MessageRequest::ListServices(_)) => {
+            return MessageResponse::ListServicesResponse(ListServiceResponse {
+                service: files
+                    .iter()
+                    .flat_map(|file| {
+                        file.service.iter().map(move |service| ServiceResponse {
+                            name: qualified(file, service.name()),
+                        })
+                    })
+                    .collect(),
+            })
+        }
+        Some(MessageRequest::FileByFilename(name)) => files.iter().find(|file| file.name() == name),
+        Some(MessageRequest::FileContainingSymbol(symbol)) => {
+            files.iter().find(|file| defines(file, symbol))
+        }
+        _ => return error(Status::unimplemented("not supported by this server")),
+    };
+    match file {
+        Some(file) => {
+            let mut encoded = Vec::with_capacity(file.encoded_len());
+            file.encode(&mut encoded)
+                .expect("a vector grows to fit the descriptor");
+            MessageResponse::FileDescriptorResponse(FileDescriptorResponse {
+                file_descriptor_proto: vec![encoded],
+            })
+        }
+        None => error(Status::not_found("no such file or symbol")),
+    }
+}
+
+/// `name` qualified with the package of `file`.
+fn qualified(file: &FileDescriptorProto, name: &str) -> String {
+    match file.package() {
+        "" => name.to_owned(),
+        package => format!("{}.{}", package, name),
+    }
+}
+
+/// Whether `file` defines the fully qualified `symbol`: a message, enum or service, or a method
+/// of a service.
+fn defines(file: &FileDescriptorProto, symbol: &str) -> bool {
+    let names = file
+        .message_type
+        .iter()
+        .map(|message| message.name())
+        .chain(file.enum_type.iter().map(|e| e.name()));
+    let is = |name: &str| qualified(file, name) == symbol;
+    names.into_iter().any(is)
+        || file.service.iter().any(|service| {
+            is(service.name())
+                || service
+                    .method
+                    .iter()
+                    .any(|method| is(&format!("{}.{}", service.name(), method.name())))
+        })
+}
+
+/// The response telling the client its request failed with `status`.
+fn error(status: Status) -> MessageResponse {
+    MessageResponse::ErrorResponse(ErrorResponse {
+        error_code: status.code() as i32,
+        error_message: status.message().to_owned(),
+    })
+}
+
+/// The status of a request failing with `e`.
+fn status(e: Error) -> Status {
+    match e {
+        PubSubError::Shutdown => Status::unavailable(e.to_string()),
+        PubSubError::Publish { .. }
+        | PubSubError::Subscribe { .. }
+        | PubSubError::Codec(_)
+        | PubSubError::Config(_) => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
@@ -0,0 +1,272 @@
+//! The small subset of HTTP/1.1 needed by the gateways: one request per connection, request
+//! bodies with a content length or chunked, and plain or chunked responses, over any stream.
+
+use async_std::io;
+use futures::prelude::*;
+
+/// Upper bound on the size of the request line and headers.
+const MAX_HEAD: usize = 64 * 1024;
+/// Upper bound on the size of a request body.
+const MAX_BODY: usize = 4 * 1024 * 1024;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// A parsed request.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of the query parameter `name`, in order.
+    pub fn query_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.query
+            .iter()
+            .filter(move |(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Buffers what has been read from a connection but not consumed yet.
+struct Reader<'a, S> {
+    stream: &'a mut S,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> Reader<'_, S> {
+    /// Read more bytes from the connection into the buffer.
+    async fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 8192];
+        let n = self.stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    async fn take(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        while self.buf.len() < n {
+            self.fill().await?;
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    async fn take_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.take(end + 2).await?;
+                return String::from_utf8(line[..end].to_vec())
+                    .map_err(|_| invalid("invalid chunk header"));
+            }
+            if self.buf.len() > MAX_HEAD {
+                return Err(invalid("line too long"));
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+/// Read one request from `stream`.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Request> {
+    let mut reader = Reader {
+        stream,
+        buf: Vec::new(),
+    };
+    let (request, head_len) = loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&reader.buf) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let target = parsed.path.unwrap_or("/");
+                let (path, query) = match target.find('?') {
+                    Some(i) => (&target[..i], &target[i + 1..]),
+                    None => (target, ""),
+                };
+                let request = Request {
+                    method: parsed.method.unwrap_or_default().to_owned(),
+                    path: path.to_owned(),
+                    query: url::form_urlencoded::parse(query.as_bytes())
+                        .into_owned()
+                        .collect(),
+                    headers: parsed
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            let value = String::from_utf8_lossy(h.value).into_owned();
+                            (h.name.to_owned(), value)
+                        })
+                        .collect(),
+                    body: Vec::new(),
+                };
+                break (request, head_len);
+            }
+            Ok(httparse::Status::Partial) if reader.buf.len() < MAX_HEAD => reader.fill().await?,
+            Ok(httparse::Status::Partial) => return Err(invalid("request head too large")),
+            Err(e) => return Err(invalid(&e.to_string())),
+        }
+    };
+    reader.buf.drain(..head_len);
+
+    let mut request = request;
+    let chunked = request
+        .header("transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    if chunked {
+        loop {
+            let line = reader.take_line().await?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size =
+                usize::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+            // The size comes from the client: checked without adding, not to overflow.
+            if size > MAX_BODY - request.body.len() {
+                return Err(invalid("request body too large"));
+            }
+            if size == 0 {
+                // Skip trailers up to the final empty line.
+                while !reader.take_line().await?.is_empty() {}
+                break;
+            }
+            let chunk = reader.take(size + 2).await?;
+            request.body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = request.header("content-length") {
+        let length: usize = length
+            .trim()
+            .parse()
+            .map_err(|_| invalid("invalid content length"))?;
+        if length > MAX_BODY {
+            return Err(invalid("request body too large"));
+        }
+        request.body = reader.take(length).await?;
+    }
+    Ok(request)
+}
+
+fn head(status: u16, reason: &str, headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {} {}\r\nConnection: close\r\n", status, reason);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.into_bytes()
+}
+
+/// Write a complete response.
+pub async fn respond<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    respond_with(
+        stream,
+        status,
+        reason,
+        &[("Content-Type", content_type)],
+        body,
+    )
+    .await
+}
+
+/// Write a complete response with `headers`, its length aside.
+pub async fn respond_with<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u16,
+    reason: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut response = head(status, reason, headers);
+    response.extend_from_slice(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+    response.extend_from_slice(body);
+    stream.write_all(&response).await?;
+    stream.flush().await
+}
+
+/// Start a `200 OK` response whose body is sent with [`write_chunk`].
+pub async fn start_chunked<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    headers: &[(&str, &str)],
+) -> io::Result<()> {
+    let mut response = head(200, "OK", headers);
+    response.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+    stream.write_all(&response).await?;
+    stream.flush().await
+}
+
+/// Write one chunk of a response started with [`start_chunked`].
+pub async fn write_chunk<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> io::Result<()> {
+    let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(b"\r\n");
+    stream.write_all(&chunk).await?;
+    stream.flush().await
+}
+
+/// Extract the content of the first part of a `multipart/form-data` body.
+pub fn first_multipart_part<'a>(content_type: &str, body: &'a [u8]) -> Option<&'a [u8]> {
+    let boundary = content_type
+        .split(';')
+        .filter_map(|param| param.trim().strip_prefix("boundary="))
+        .next()?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary);
+    let start = find(body, delimiter.as_bytes())? + delimiter.len();
+    let part = &body[start..];
+    let content = find(part, b"\r\n\r\n")? + 4;
+    let end = find(&part[content..], format!("\r\n{}", delimiter).as_bytes())?;
+    Some(&part[content..content + end])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use futures::io::Cursor;
+
+    fn read(request: &str) -> io::Result<Request> {
+        task::block_on(read_request(&mut Cursor::new(request.as_bytes().to_vec())))
+    }
+
+    const CHUNKED: &str = "POST /publish HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[test]
+    fn reads_chunked_bodies() {
+        let request = read(&format!("{}5\r\nhello\r\n1;ext\r\n!\r\n0\r\n\r\n", CHUNKED)).unwrap();
+        assert_eq!(request.body, b"hello!");
+    }
+
+    #[test]
+    fn rejects_oversized_chunk_sizes() {
+        for size in ["ffffffffffffffff", "400001"] {
+            let error = read(&format!("{}{}\r\nhello\r\n0\r\n\r\n", CHUNKED, size))
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", size);
+        }
+        let error = read(&format!("{}2\r\nhi\r\n3fffff\r\n", CHUNKED))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,279 @@
+//! The pubsub endpoints of the go-ipfs HTTP API, so that `ipfs pubsub` and the IPFS HTTP client
+//! libraries can talk to a node unchanged.
+//!
+//! Like go-ipfs 0.11 and later, topics in requests and responses, as well as message payloads
+//! and sequence numbers, are multibase encoded, and the payload of `pub` is sent as the first
+//! part of a `multipart/form-data` body. Responses are always encoded as base64url.
+//!
+//! As an extension, `sub` accepts a `pipeline` argument holding a JSON
+//! [`Pipeline`](crate::pipeline) that the node runs on every message before streaming it.
+//!
+//! Messages wait for a slow `sub` caller in an outbox with the default [`FlowControl`], which
+//! drops the oldest of them once it is full.
+//!
+//! Served with [`spawn_with_auth`], the API asks its callers to authenticate, see the
+//! [`auth`](super::auth) module; `pub` needs read-write access.
+
+use super::{
+    auth::{Access, AuthConfig, Gate},
+    http::{self, Request},
+    Connection,
+};
+use crate::{
+    flow::{self, FlowControl},
+    pipeline::Pipeline,
+    Client, Error,
+};
+use async_std::{io, task};
+use bytes::Bytes;
+use data_encoding::{
+    BASE32_NOPAD, BASE64, BASE64URL, BASE64URL_NOPAD, BASE64_NOPAD, HEXLOWER_PERMISSIVE,
+};
+use futures::prelude::*;
+use serde::Serialize;
+use std::net::ToSocketAddrs;
+
+/// Start serving the API on `addr`, go-ipfs using `127.0.0.1:5001` by default.
+pub fn spawn(client: &Client, addr: impl ToSocketAddrs) -> Result<task::JoinHandle<()>, Error> {
+    spawn_with_auth(client, addr, AuthConfig::default())
+}
+
+/// Start serving the API on `addr`, authenticating callers as `auth` says.
+pub fn spawn_with_auth(
+    client: &Client,
+    addr: impl ToSocketAddrs,
+    auth: AuthConfig,
+) -> Result<task::JoinHandle<()>, Error> {
+    super::serve(client, addr, "IPFS pubsub API", &auth, handle)
+}
+
+/// Failure of an API call, reported to the caller like go-ipfs does.
+enum ApiError {
+    /// The request is malformed.
+    Client(String),
+    /// The node failed to carry out the request.
+    Node(Error),
+}
+
+impl<E: Into<Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        ApiError::Node(e.into())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorBody {
+    message: String,
+    code: u8,
+    #[serde(rename = "Type")]
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Strings {
+    strings: Vec<String>,
+}
+
+/// A message as streamed by `/api/v0/pubsub/sub`.
+#[derive(Serialize)]
+struct PubsubMessage {
+    from: String,
+    data: String,
+    seqno: String,
+    #[serde(rename = "topicIDs")]
+    topic_ids: Vec<String>,
+}
+
+async fn handle(client: Client, mut stream: Box<dyn Connection>, gate: Gate) {
+    let request = match http::read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            log::debug!("IPFS pubsub API: bad request: {}", e);
+            return;
+        }
+    };
+    let required = match request.path.trim_end_matches('/') {
+        "/api/v0/pubsub/pub" => Access::ReadWrite,
+        _ => Access::ReadOnly,
+    };
+    match gate.check(&request, required, &mut stream).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return,
+        Err(e) => {
+            log::debug!("IPFS pubsub API: {}", e);
+            return;
+        }
+    }
+    let result = if request.method != "POST" {
+        http::respond(
+            &mut stream,
+            405,
+            "Method Not Allowed",
+            "text/plain",
+            b"405 - Method Not Allowed",
+        )
+        .await
+    } else {
+        match call(&client, &request, &mut stream).await {
+            Ok(()) => Ok(()),
+            Err(e) => respond_error(&mut stream, e).await,
+        }
+    };
+    if let Err(e) = result {
+        log::debug!("IPFS pubsub API: {}", e);
+    }
+}
+
+async fn call<S: AsyncWrite + Unpin>(
+    client: &Client,
+    request: &Request,
+    stream: &mut S,
+) -> Result<(), ApiError> {
+    match request.path.trim_end_matches('/') {
+        "/api/v0/pubsub/pub" => {
+            let topic = topic_arg(request)?;
+            let data = match request.header("content-type") {
+                Some(content_type) if content_type.starts_with("multipart/form-data") => {
+                    http::first_multipart_part(content_type, &request.body)
+                        .ok_or_else(|| ApiError::Client("malformed multipart body".into()))?
+                }
+                _ => &request.body[..],
+            };
+            client.publish(&topic, Bytes::copy_from_slice(data))?;
+            respond_ok(stream, b"").await
+        }
+        "/api/v0/pubsub/sub" => {
+            let topic = topic_arg(request)?;
+            let subscription = match request.query_values("pipeline").next() {
+                Some(pipeline) => {
+                    let pipeline = Pipeline::from_json(pipeline)
+                        .map_err(|e| ApiError::Client(format!("invalid pipeline: {}", e)))?;
+                    client.subscribe_pipeline(&topic, pipeline)?
+                }
+                None => client.subscribe(&topic)?,
+            };
+            let mut subscription = flow::outbox(client, subscription, &FlowControl::default());
+            http::start_chunked(
+                stream,
+                &[
+                    ("Content-Type", "application/json"),
+                    ("X-Chunked-Output", "1"),
+                ],
+            )
+            .await?;
+            while let Some(message) = subscription.next().await {
+                let message = PubsubMessage {
+                    from: message.source.to_base58(),
+                    data: multibase_encode(&message.data),
+                    seqno: multibase_encode(&message.sequence_number.to_be_bytes()),
+                    topic_ids: vec![multibase_encode(message.topic.as_bytes())],
+                };
+                let mut line = serde_json::to_vec(&message)?;
+                line.push(b'\n');
+                if http::write_chunk(stream, &line).await.is_err() {
+                    // The caller went away; dropping the subscription ends it.
+                    break;
+                }
+            }
+            Ok(())
+        }
+        "/api/v0/pubsub/ls" => {
+            let mut topics = client.topics().await?;
+            topics.sort();
+            let strings = topics
+                .iter()
+                .map(|topic| multibase_encode(topic.as_bytes()))
+                .collect();
+            respond_json(stream, &Strings { strings }).await
+        }
+        "/api/v0/pubsub/peers" => {
+            let topic = match request.query_values("arg").next() {
+                Some(arg) => Some(decode_topic(arg)?),
+                None => None,
+            };
+            let mut strings: Vec<String> = client
+                .peers(topic.as_deref())
+                .await?
+                .iter()
+                .map(|peer| peer.to_base58())
+                .collect();
+            strings.sort();
+            respond_json(stream, &Strings { strings }).await
+        }
+        _ => Ok(http::respond(
+            stream,
+            404,
+            "Not Found",
+            "text/plain",
+            b"404 page not found",
+        )
+        .await?),
+    }
+}
+
+fn topic_arg(request: &Request) -> Result<String, ApiError> {
+    let arg = request
+        .query_values("arg")
+        .next()
+        .ok_or_else(|| ApiError::Client("argument \"topic\" is required".into()))?;
+    decode_topic(arg)
+}
+
+fn decode_topic(arg: &str) -> Result<String, ApiError> {
+    let topic = multibase_decode(arg)
+        .ok_or_else(|| ApiError::Client(format!("topic {:?} is not multibase encoded", arg)))?;
+    String::from_utf8(topic).map_err(|_| ApiError::Client("topic is not valid UTF-8".into()))
+}
+
+async fn respond_ok<S: AsyncWrite + Unpin>(stream: &mut S, body: &[u8]) -> Result<(), ApiError> {
+    Ok(http::respond(stream, 200, "OK", "text/plain", body).await?)
+}
+
+async fn respond_json<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    body: &impl Serialize,
+) -> Result<(), ApiError> {
+    let body = serde_json::to_vec(body)?;
+    Ok(http::respond(stream, 200, "OK", "application/json", &body).await?)
+}
+
+async fn respond_error<S: AsyncWrite + Unpin>(stream: &mut S, error: ApiError) -> io::Result<()> {
+    let (status, reason, message, code) = match error {
+        ApiError::Client(message) => (400, "Bad Request", message, 1),
+        ApiError::Node(e) => (500, "Internal Server Error", e.to_string(), 0),
+    };
+    let body = ErrorBody {
+        message,
+        code,
+        kind: "error",
+    };
+    let body = serde_json::to_vec(&body).unwrap_or_default();
+    http::respond(stream, status, reason, "application/json", &body).await
+}
+
+/// Encode as multibase base64url, the encoding go-ipfs uses in its responses.
+fn multibase_encode(data: &[u8]) -> String {
+    format!("u{}", BASE64URL_NOPAD.encode(data))
+}
+
+/// Decode the multibase encodings in common use.
+fn multibase_decode(text: &str) -> Option<Vec<u8>> {
+    let mut chars = text.chars();
+    let prefix = chars.next()?;
+    let data = chars.as_str();
+    match prefix {
+        'u' => BASE64URL_NOPAD.decode(data.as_bytes()).ok(),
+        'U' => BASE64URL.decode(data.as_bytes()).ok(),
+        'm' => BASE64_NOPAD.decode(data.as_bytes()).ok(),
+        'M' => BASE64.decode(data.as_bytes()).ok(),
+        'b' => BASE32_NOPAD
+            .decode(data.to_ascii_uppercase().as_bytes())
+            .ok(),
+        'B' => BASE32_NOPAD.decode(data.as_bytes()).ok(),
+        'f' | 'F' => HEXLOWER_PERMISSIVE.decode(data.as_bytes()).ok(),
+        'z' => bs58::decode(data).into_vec().ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,206 @@
+//! Subscriptions outliving the session of the gateway client that made them, so that a client
+//! whose connection dropped can pick them up again on a new one and miss nothing, shared by the
+//! [WebSocket](super::ws) and [gRPC](super::grpc) gateways.
+//!
+//! Every message of a subscription gets a `resume_seq`, counting from 1, the last
+//! [`RESUME_BUFFER`] of them being kept. Resuming names the `resume_seq` of the last message the
+//! client received, and the messages after it are pushed again before the new ones. A
+//! subscription nobody resumes within [`RESUME_WINDOW`] ends, and one cannot be resumed while
+//! its session lasts.
+//!
+//! Each gateway keeps its subscriptions in [`Resumables`] of its own, so that a subscription is
+//! only resumed through the gateway that made it.
+
+use crate::{flow, Error, Message, PubSubError};
+use async_std::task;
+use data_encoding::HEXLOWER;
+use futures::{
+    future::{AbortHandle, Abortable},
+    prelude::*,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Messages of a subscription kept to be pushed again when it is resumed.
+pub const RESUME_BUFFER: usize = 1024;
+
+/// How long a subscription outlives its session, waiting to be resumed.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(60);
+
+/// What a session is pushed, made of the messages of its subscriptions.
+pub(super) trait Push: Send + 'static {
+    /// The push of `message`, the `resume_seq`th of its subscription.
+    fn msg(message: &Message, resume_seq: u64) -> Self;
+}
+
+/// The subscriptions of the sessions of a gateway, and those of ended sessions waiting to be
+/// resumed.
+pub(super) struct Resumables<P>(Arc<Mutex<Vec<Arc<Resumable<P>>>>>);
+
+impl<P> Clone for Resumables<P> {
+    fn clone(&self) -> Self {
+        Resumables(self.0.clone())
+    }
+}
+
+impl<P: Push> Resumables<P> {
+    pub(super) fn new() -> Self {
+        Resumables(Arc::new(Mutex::new(Vec::new())))
+    }
+
+    /// Forward the messages of `messages`, a subscription made for `topics`, to the session of
+    /// `pushes`.
+    pub(super) fn spawn(
+        &self,
+        topics: Vec<String>,
+        messages: impl Stream<Item = Message> + Send + 'static,
+        pushes: &flow::Sender<P>,
+    ) -> Result<Arc<Resumable<P>>, Error> {
+        let mut id = [0; 16];
+        SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| PubSubError::other("failed to pick a subscription id"))?;
+        let (forward, registration) = AbortHandle::new_pair();
+        let resumable = Arc::new(Resumable {
+            id: HEXLOWER.encode(&id),
+            topics,
+            forward,
+            buffer: Mutex::new(Buffer {
+                messages: VecDeque::new(),
+                last_seq: 0,
+                session: Some(pushes.clone()),
+                detached: 0,
+            }),
+            resumables: self.clone(),
+        });
+        let pushing = resumable.clone();
+        let forward = async move {
+            futures::pin_mut!(messages);
+            while let Some(message) = messages.next().await {
+                pushing.push(message);
+            }
+        };
+        task::spawn(Abortable::new(forward, registration));
+        self.0.lock().unwrap().push(resumable.clone());
+        Ok(resumable)
+    }
+
+    /// The subscription `id`, if it has not ended.
+    pub(super) fn find(&self, id: &str) -> Result<Arc<Resumable<P>>, Error> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|resumable| resumable.id == id)
+            .cloned()
+            .ok_or_else(|| format!("no subscription {} to resume", id).into())
+    }
+}
+
+/// A subscription of a session, pushing its messages to the session if one is attached and
+/// keeping the last of them to push again once resumed.
+pub(super) struct Resumable<P> {
+    pub(super) id: String,
+    /// Topics or filters of the subscription.
+    pub(super) topics: Vec<String>,
+    forward: AbortHandle,
+    buffer: Mutex<Buffer<P>>,
+    resumables: Resumables<P>,
+}
+
+struct Buffer<P> {
+    /// The last messages, with their `resume_seq`.
+    messages: VecDeque<(u64, Message)>,
+    last_seq: u64,
+    /// Queue of the pushes of the session attached.
+    session: Option<flow::Sender<P>>,
+    /// Times the subscription was detached from a session, to tell whether it was resumed
+    /// since.
+    detached: u64,
+}
+
+impl<P: Push> Resumable<P> {
+    fn push(&self, message: Message) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.last_seq += 1;
+        let resume_seq = buffer.last_seq;
+        if let Some(session) = &buffer.session {
+            // The queue drops its oldest pushes rather than wait for room.
+            if let flow::TrySend::Closed(_) = session.try_send(P::msg(&message, resume_seq)) {
+                buffer.session = None;
+            }
+        }
+        if buffer.messages.len() == RESUME_BUFFER {
+            buffer.messages.pop_front();
+        }
+        buffer.messages.push_back((resume_seq, message));
+    }
+
+    /// Attach the subscription to the session of `pushes`, pushing the messages after
+    /// `last_seq` again and returning the `resume_seq` of the oldest of them if some are lost.
+    pub(super) fn resume(
+        &self,
+        last_seq: u64,
+        pushes: &flow::Sender<P>,
+    ) -> Result<Option<u64>, Error> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer
+            .session
+            .as_ref()
+            .is_some_and(|session| !session.is_closed())
+        {
+            return Err(format!("subscription {} is in use", self.id).into());
+        }
+        let gap_until = buffer
+            .messages
+            .front()
+            .map(|(oldest, _)| *oldest)
+            .filter(|oldest| *oldest > last_seq.saturating_add(1));
+        for (resume_seq, message) in &buffer.messages {
+            if *resume_seq > last_seq {
+                let _ = pushes.try_send(P::msg(message, *resume_seq));
+            }
+        }
+        buffer.session = Some(pushes.clone());
+        Ok(gap_until)
+    }
+
+    /// Detach the subscription from the session of `pushes`, ending it unless it is resumed
+    /// within [`RESUME_WINDOW`].
+    pub(super) fn detach(self: &Arc<Self>, pushes: &flow::Sender<P>) {
+        let mut buffer = self.buffer.lock().unwrap();
+        // Resumed by another session already.
+        if buffer
+            .session
+            .as_ref()
+            .is_some_and(|session| !session.same_channel(pushes))
+        {
+            return;
+        }
+        buffer.session = None;
+        buffer.detached += 1;
+        let detached = buffer.detached;
+        let resumable = self.clone();
+        task::spawn(async move {
+            task::sleep(RESUME_WINDOW).await;
+            let buffer = resumable.buffer.lock().unwrap();
+            if buffer.session.is_none() && buffer.detached == detached {
+                drop(buffer);
+                resumable.end();
+            }
+        });
+    }
+
+    pub(super) fn end(&self) {
+        self.forward.abort();
+        self.resumables
+            .0
+            .lock()
+            .unwrap()
+            .retain(|resumable| resumable.id != self.id);
+    }
+}
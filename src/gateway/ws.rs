@@ -0,0 +1,502 @@
+//! A WebSocket server proxying a simple JSON protocol into the mesh, for dashboards, browsers and
+//! scripts that do not link libp2p.
+//!
+//! Every WebSocket text message sent by a client is one request:
+//!
+//! - `{"op":"sub","topic":"sensors/+/temp"}` subscribes to a topic, or to a wildcard filter. An
+//!   optional `pipeline` holds the stages of a [`Pipeline`](crate::pipeline) run on every message.
+//! - `{"op":"unsub","topic":"sensors/+/temp"}` ends a subscription.
+//! - `{"op":"pub","topic":"chat","data":"hello"}` publishes `data`, which is sent as UTF-8 unless
+//!   `"encoding":"base64"` is given.
+//! - `{"op":"pub_any","topic":"readings","type_url":"type.googleapis.com/acme.Reading","value":...}`
+//!   publishes a `google.protobuf.Any` holding the base64 `value`, refused unless the type URL
+//!   names the message type of the topic, see the [`schema`](crate::schema) module.
+//!
+//! - `{"op":"resume","subscription":"9f2c...","last_seq":41}` picks up a subscription of a
+//!   session that ended, see below.
+//!
+//! Requests may carry an `id`, echoed in the `{"op":"ok"}` or `{"op":"error","message":...}`
+//! reply. Messages received on subscriptions are pushed as
+//! `{"op":"msg","topic":...,"from":...,"seqno":...,"data":...,"resume_seq":...}`, with an
+//! `"encoding":"base64"` field when the payload is not valid UTF-8.
+//!
+//! Messages wait for a slow client in a queue bounded like the default [`FlowControl`], which
+//! drops the oldest of them once it is full.
+//!
+//! Subscriptions outlive their session for [`RESUME_WINDOW`], so that a client whose connection
+//! dropped misses nothing. The `ok` reply of a `sub` request names the `subscription`, and every
+//! message of a subscription has a `resume_seq`, counting from 1, the last [`RESUME_BUFFER`] of
+//! them being kept. A `resume` request on a new session names the subscription and the
+//! `resume_seq` of the last message the client received, 0 if none, and the messages after it
+//! are pushed again before the new ones. If some of them were not kept anymore, the `ok` reply
+//! holds the `gap_until` of the oldest message kept. A subscription nobody resumes in time ends,
+//! and one cannot be resumed while its session lasts, nor through another gateway.
+//!
+//! Served with [`spawn_with_auth`], the gateway asks clients to authenticate when opening the
+//! WebSocket, see the [`auth`](super::auth) module; `pub` and `pub_any` requests need read-write
+//! access.
+
+use super::{
+    auth::{Access, AuthConfig, Gate},
+    http,
+    resume::{Push, Resumable, Resumables},
+    Connection,
+};
+use crate::{
+    flow::{self, FlowControl},
+    pipeline::{Pipeline, Stage},
+    schema::Any,
+    size::MessageTooLarge,
+    topic::TopicFilter,
+    Client, Error, Message, PubSubError,
+};
+use async_std::{io, task};
+use data_encoding::BASE64;
+use futures::{
+    channel::mpsc,
+    future::{AbortHandle, Abortable},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, net::ToSocketAddrs, sync::Arc};
+use tungstenite::handshake::server::create_response;
+
+/// Upper bound on the size of a message received from a client.
+const MAX_MESSAGE: usize = 4 * 1024 * 1024;
+
+pub use super::resume::{RESUME_BUFFER, RESUME_WINDOW};
+
+/// Start serving WebSocket clients on `addr`.
+pub fn spawn(client: &Client, addr: impl ToSocketAddrs) -> Result<task::JoinHandle<()>, Error> {
+    spawn_with_auth(client, addr, AuthConfig::default())
+}
+
+/// Start serving WebSocket clients on `addr`, authenticating them as `auth` says.
+pub fn spawn_with_auth(
+    client: &Client,
+    addr: impl ToSocketAddrs,
+    auth: AuthConfig,
+) -> Result<task::JoinHandle<()>, Error> {
+    let resumables = Resumables::new();
+    super::serve(
+        client,
+        addr,
+        "WebSocket gateway",
+        &auth,
+        move |client, stream, gate| handle(client, stream, gate, resumables.clone()),
+    )
+}
+
+/// A request sent by a client.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Sub {
+        topic: String,
+        #[serde(default)]
+        pipeline: Option<Vec<Stage>>,
+    },
+    Unsub {
+        topic: String,
+    },
+    Pub {
+        topic: String,
+        data: String,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    PubAny {
+        topic: String,
+        type_url: String,
+        value: String,
+    },
+    Resume {
+        subscription: String,
+        #[serde(default)]
+        last_seq: u64,
+    },
+}
+
+/// How the `data` of a message is encoded, shared with the admin endpoint.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+/// A message pushed to a client.
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Reply {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<Value>,
+        #[serde(flatten)]
+        done: Done,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<Value>,
+        message: String,
+    },
+    Msg {
+        topic: String,
+        from: String,
+        /// Decimal, as JavaScript numbers cannot hold every `u64`.
+        seqno: String,
+        data: String,
+        #[serde(skip_serializing_if = "is_utf8")]
+        encoding: Encoding,
+        resume_seq: u64,
+    },
+}
+
+/// What the `ok` reply of a request tells besides its `id`.
+#[derive(Default, Serialize)]
+struct Done {
+    /// The subscription made or resumed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscription: Option<String>,
+    /// The `resume_seq` of the oldest message kept, when those after the `last_seq` of a
+    /// `resume` request were not all kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gap_until: Option<u64>,
+}
+
+fn is_utf8(encoding: &Encoding) -> bool {
+    *encoding == Encoding::Utf8
+}
+
+impl Push for Reply {
+    fn msg(message: &Message, resume_seq: u64) -> Self {
+        let (data, encoding) = match std::str::from_utf8(&message.data) {
+            Ok(data) => (data.to_owned(), Encoding::Utf8),
+            Err(_) => (BASE64.encode(&message.data), Encoding::Base64),
+        };
+        Reply::Msg {
+            topic: message.topic.clone(),
+            from: message.source.to_base58(),
+            seqno: message.sequence_number.to_string(),
+            data,
+            encoding,
+            resume_seq,
+        }
+    }
+}
+
+async fn handle(
+    client: Client,
+    mut stream: Box<dyn Connection>,
+    gate: Gate,
+    resumables: Resumables<Reply>,
+) {
+    let access = match upgrade(&mut stream, &gate).await {
+        Ok(Some(access)) => access,
+        Ok(None) => return,
+        Err(e) => {
+            log::debug!("WebSocket gateway: handshake failed: {}", e);
+            return;
+        }
+    };
+    let (reader, mut writer) = stream.split();
+    let (frames_in, frames) = mpsc::channel(FlowControl::default().capacity);
+    let (reading, registration) = AbortHandle::new_pair();
+    task::spawn(Abortable::new(read_frames(reader, frames_in), registration));
+    if let Err(e) = session(&client, &mut writer, frames, access, &resumables).await {
+        log::debug!("WebSocket gateway: {}", e);
+    }
+    reading.abort();
+    let _ = writer.close().await;
+}
+
+/// Answer the opening handshake, returning the access of the client. Returns `None` if the
+/// request was not a WebSocket upgrade or not authorized, and has been answered with an error.
+async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    gate: &Gate,
+) -> Result<Option<Access>, Error> {
+    let request = http::read_request(stream).await?;
+    let access = match gate.check(&request, Access::ReadOnly, stream).await? {
+        Some(access) => access,
+        None => return Ok(None),
+    };
+    let mut builder = tungstenite::http::Request::builder()
+        .method(request.method.as_str())
+        .uri(request.path.as_str());
+    for (name, value) in &request.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    let response = match create_response(&builder.body(()).map_err(PubSubError::codec)?) {
+        Ok(response) => response,
+        Err(e) => {
+            let message = format!("not a WebSocket handshake: {}", e);
+            http::respond(stream, 400, "Bad Request", "text/plain", message.as_bytes()).await?;
+            return Ok(None);
+        }
+    };
+    let mut head = String::from("HTTP/1.1 101 Switching Protocols\r\n");
+    for (name, value) in response.headers() {
+        head.push_str(&format!(
+            "{}: {}\r\n",
+            name,
+            value.to_str().map_err(PubSubError::codec)?
+        ));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(Some(access))
+}
+
+/// Serve requests and push subscription messages until the client goes away.
+async fn session<S: AsyncWrite + Unpin>(
+    client: &Client,
+    stream: &mut S,
+    mut frames: mpsc::Receiver<io::Result<Frame>>,
+    access: Access,
+    resumables: &Resumables<Reply>,
+) -> Result<(), Error> {
+    let outbox = FlowControl::default();
+    let (replies_out, mut replies) = flow::channel::<Reply>(outbox.capacity, outbox.overflow);
+    let mut subscriptions: HashMap<String, Arc<Resumable<Reply>>> = HashMap::new();
+    // Fragments of a message split over several frames.
+    let mut fragments: Vec<u8> = Vec::new();
+    let result = loop {
+        let frame = futures::select! {
+            frame = frames.next() => frame,
+            reply = replies.next() => {
+                if let Some(reply) = reply {
+                    write_reply(stream, &reply).await?;
+                }
+                continue;
+            }
+        };
+        let frame = match frame {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => break Err(e.into()),
+            None => break Ok(()),
+        };
+        match frame.opcode {
+            OPCODE_CLOSE => {
+                let _ = write_frame(stream, OPCODE_CLOSE, &[]).await;
+                break Ok(());
+            }
+            OPCODE_PING => write_frame(stream, OPCODE_PONG, &frame.payload).await?,
+            OPCODE_PONG => {}
+            _ => {
+                fragments.extend_from_slice(&frame.payload);
+                if fragments.len() > MAX_MESSAGE {
+                    // Too large to tell the topic of a publish request.
+                    break Err(PubSubError::other(MessageTooLarge {
+                        topic: String::new(),
+                        size: fragments.len(),
+                        limit: MAX_MESSAGE,
+                    }));
+                }
+                if frame.is_final {
+                    let text = std::mem::take(&mut fragments);
+                    let reply = request(
+                        client,
+                        &text,
+                        access,
+                        resumables,
+                        &mut subscriptions,
+                        &replies_out,
+                    );
+                    write_reply(stream, &reply).await?;
+                }
+            }
+        }
+    };
+    for (_, subscription) in subscriptions {
+        subscription.detach(&replies_out);
+    }
+    result
+}
+
+/// Carry out one request, returning the reply to send.
+fn request(
+    client: &Client,
+    text: &[u8],
+    access: Access,
+    resumables: &Resumables<Reply>,
+    subscriptions: &mut HashMap<String, Arc<Resumable<Reply>>>,
+    replies: &flow::Sender<Reply>,
+) -> Reply {
+    let id = serde_json::from_slice::<Value>(text)
+        .ok()
+        .and_then(|value| value.get("id").cloned());
+    let result =
+        serde_json::from_slice(text)
+            .map_err(Error::from)
+            .and_then(|request| match request {
+                Request::Sub { topic, pipeline } => {
+                    let subscription = match pipeline {
+                        Some(stages) => {
+                            client.subscribe_pipeline(&topic, Pipeline::new(stages)?)?
+                        }
+                        None if TopicFilter::new(&topic)?.is_wildcard() => {
+                            client.subscribe_filter(&topic)?
+                        }
+                        None => client.subscribe(&topic)?,
+                    };
+                    let resumable = resumables.spawn(vec![topic.clone()], subscription, replies)?;
+                    let done = Done {
+                        subscription: Some(resumable.id.clone()),
+                        gap_until: None,
+                    };
+                    if let Some(previous) = subscriptions.insert(topic, resumable) {
+                        previous.end();
+                    }
+                    Ok(done)
+                }
+                Request::Unsub { topic } => match subscriptions.remove(&topic) {
+                    Some(subscription) => {
+                        subscription.end();
+                        Ok(Done::default())
+                    }
+                    None => Err(format!("not subscribed to {}", topic).into()),
+                },
+                Request::Resume {
+                    subscription,
+                    last_seq,
+                } => {
+                    let resumable = resumables.find(&subscription)?;
+                    let gap_until = resumable.resume(last_seq, replies)?;
+                    let topic = resumable.topics[0].clone();
+                    if let Some(previous) = subscriptions.insert(topic, resumable) {
+                        previous.end();
+                    }
+                    Ok(Done {
+                        subscription: Some(subscription),
+                        gap_until,
+                    })
+                }
+                Request::Pub { .. } | Request::PubAny { .. } if access < Access::ReadWrite => {
+                    Err("not allowed to publish with read-only access".into())
+                }
+                Request::Pub {
+                    topic,
+                    data,
+                    encoding,
+                } => {
+                    let data = match encoding {
+                        Encoding::Utf8 => data.into_bytes(),
+                        Encoding::Base64 => BASE64.decode(data.as_bytes())?,
+                    };
+                    client.publish(&topic, data).map(|()| Done::default())
+                }
+                Request::PubAny {
+                    topic,
+                    type_url,
+                    value,
+                } => {
+                    let any = Any {
+                        type_url,
+                        value: BASE64.decode(value.as_bytes())?,
+                    };
+                    client.publish_any(&topic, &any).map(|()| Done::default())
+                }
+            });
+    match result {
+        Ok(done) => Reply::Ok { id, done },
+        Err(e) => Reply::Error {
+            id,
+            message: e.to_string(),
+        },
+    }
+}
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// A frame received from a client, unmasked.
+struct Frame {
+    is_final: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+async fn read_frames<S: AsyncRead + Unpin>(
+    mut stream: S,
+    mut frames: mpsc::Sender<io::Result<Frame>>,
+) {
+    loop {
+        let frame = read_frame(&mut stream).await;
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Frame> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            stream.read_exact(&mut len).await?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > MAX_MESSAGE as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame too large",
+        ));
+    }
+    let mut mask = [0u8; 4];
+    if header[1] & 0x80 != 0 {
+        stream.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        is_final: header[0] & 0x80 != 0,
+        opcode: header[0] & 0x0f,
+        payload,
+    })
+}
+
+/// Write a single unmasked, final frame.
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+async fn write_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: &Reply) -> Result<(), Error> {
+    write_frame(stream, OPCODE_TEXT, &serde_json::to_vec(reply)?).await?;
+    Ok(())
+}
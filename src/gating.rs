@@ -0,0 +1,66 @@
+//! Gating of topic meshes by what peers identify themselves as, to keep the peers running an
+//! incompatible version of the protocol out of them.
+//!
+//! Through identify, every peer tells the node its agent version, [`AGENT_VERSION`] for nodes
+//! built on this crate, and the protocols it supports. The [`MeshGate`] of a topic, set per topic
+//! filter in [`NodeConfig::mesh_gates`](crate::NodeConfig::mesh_gates) or with the `mesh` option
+//! of a topic in a [manifest](crate::manifest), admits to the mesh and fanout of the topic the
+//! peers whose agent version matches [`MeshGate::agent`] and that support every protocol of
+//! [`MeshGate::protocols`]. The others, and the peers not identified yet, are pruned from the
+//! mesh and never grafted, and their grafts are answered with a prune: messages no longer flow
+//! between them and the node through the mesh, only through gossip if at all. Peers are checked
+//! again whenever they are identified anew.
+//!
+//! Agent versions are matched against patterns in which `*` stands for any run of characters,
+//! e.g. `pubsub-lite/1.*` for the 1.x releases of this crate. The well-known topics every node is
+//! in are never gated.
+
+use serde::Deserialize;
+
+/// Agent version nodes built on this crate identify themselves with.
+pub const AGENT_VERSION: &str = concat!("pubsub-lite/", env!("CARGO_PKG_VERSION"));
+
+/// Which peers are admitted to the mesh of a topic.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeshGate {
+    /// Pattern the agent version of the peers must match, if any.
+    pub agent: Option<String>,
+    /// Protocols the peers must all support.
+    pub protocols: Vec<String>,
+}
+
+impl MeshGate {
+    /// Whether a peer identified with `agent_version` and `protocols` is admitted.
+    pub fn admits(&self, agent_version: &str, protocols: &[String]) -> bool {
+        self.agent
+            .as_ref()
+            .is_none_or(|pattern| matches(pattern, agent_version))
+            && self
+                .protocols
+                .iter()
+                .all(|required| protocols.contains(required))
+    }
+}
+
+/// Whether `text` matches `pattern`, in which `*` stands for any run of characters.
+fn matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
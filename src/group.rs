@@ -0,0 +1,259 @@
+//! Queue groups: subscribers of a topic sharing its messages, each delivered to one of them.
+//!
+//! The members of the group `group` on `topic`, taken from [`Client::subscribe_group`], run on
+//! any nodes, several on the same node too. Every node receives every message of the topic, as
+//! with a plain subscription, but each message is delivered to a single member of the group: the
+//! one ranking first for the message by rendezvous hashing of the message id with the ids of the
+//! members, so that every member picks the same one without a word, and that members joining or
+//! leaving only take or hand over their share of the messages. Unlike the jobs of a
+//! [`queue`](crate::queue), messages are neither claimed nor acknowledged, and a member failing
+//! to process one loses it.
+//!
+//! Members learn of each other over the shadow topic `pubsub-lite/group/<group>/<topic>`: every
+//! [`HEARTBEAT_INTERVAL`] they announce themselves on it, answering new members right away, and
+//! they say goodbye once dropped. A member whose heartbeats stop for [`MISSED_HEARTBEATS`]
+//! intervals is considered gone, and its messages in the meantime are lost. While members do
+//! not agree on who is in the group yet, e.g. for the round trip after one joins, a message may
+//! be delivered to two of them, or to none. The members on the same node, which do not hear
+//! each other over gossip, know of each other in the process. Gossipsub does not deliver the
+//! messages of a node to itself, so they go to the members on other nodes, unless local
+//! delivery is on.
+//!
+//! [`Client::subscribe_group`]: crate::Client::subscribe_group
+
+use crate::{Client, Error, Message, Subscription};
+use async_std::{stream, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select,
+};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// How often a member of a group announces itself.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of heartbeats a member may miss before it is considered gone.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// The members of the process, as the shadow topic of their group and their id.
+static LOCAL_MEMBERS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Announcement exchanged on the shadow topic of a group, by one of its members.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Announcement {
+    /// `member` is in the group, and announces itself again within `interval_ms` milliseconds.
+    Heartbeat { member: String, interval_ms: u64 },
+    /// `member` leaves the group.
+    Goodbye { member: String },
+}
+
+/// Messages of a topic delivered to this member of a queue group, returned by
+/// [`Client::subscribe_group`](crate::Client::subscribe_group). The member leaves the group when
+/// the stream is dropped.
+pub struct GroupSubscription {
+    member: String,
+    receiver: mpsc::UnboundedReceiver<Message>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl GroupSubscription {
+    /// Id of this member, unique among the members of the group.
+    pub fn member(&self) -> &str {
+        &self.member
+    }
+}
+
+impl Stream for GroupSubscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Message>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for GroupSubscription {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Rank of `member` for the message `id`, the member ranking highest taking the message.
+pub(crate) fn rank(member: &str, id: &str) -> u64 {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(member.as_bytes());
+    context.update(b"\0");
+    context.update(id.as_bytes());
+    let hash = context.finish();
+    u64::from_be_bytes(
+        hash.as_ref()[..8]
+            .try_into()
+            .expect("SHA-256 is 32 bytes long"),
+    )
+}
+
+/// This member of a queue group.
+struct Member {
+    client: Client,
+    member: String,
+    group_topic: String,
+    messages: Subscription,
+    announcements: Subscription,
+    /// The other members heard of, with when each is considered gone.
+    members: HashMap<String, Instant>,
+    deliveries: mpsc::UnboundedSender<Message>,
+}
+
+impl Member {
+    fn announce(&self, announcement: Announcement) -> Result<(), Error> {
+        self.client
+            .publish(&self.group_topic, serde_json::to_vec(&announcement)?)
+    }
+
+    fn heartbeat(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        self.members.retain(|_, expires| *expires > now);
+        self.announce(Announcement::Heartbeat {
+            member: self.member.clone(),
+            interval_ms: HEARTBEAT_INTERVAL.as_millis() as u64,
+        })
+    }
+
+    fn receive_announcement(&mut self, message: Message) -> Result<(), Error> {
+        match serde_json::from_slice(&message.data) {
+            Ok(Announcement::Heartbeat {
+                member,
+                interval_ms,
+            }) if member != self.member => {
+                let expires =
+                    Instant::now() + Duration::from_millis(interval_ms) * MISSED_HEARTBEATS;
+                if self.members.insert(member, expires).is_none() {
+                    // Let the newcomer know of this member without waiting for a heartbeat.
+                    self.heartbeat()?;
+                }
+            }
+            Ok(Announcement::Goodbye { member }) => {
+                self.members.remove(&member);
+            }
+            Ok(Announcement::Heartbeat { .. }) => {}
+            Err(e) => log::warn!("ignoring malformed group announcement: {}", e),
+        }
+        Ok(())
+    }
+
+    /// Deliver `message` if this member ranks highest for it among the live members.
+    fn receive_message(&mut self, message: Message) {
+        let now = Instant::now();
+        let own = (rank(&self.member, &message.id.0), &self.member);
+        let local_peer = format!("{}/", self.client.local_peer_id().to_base58());
+        let local = LOCAL_MEMBERS.lock().unwrap_or_else(|e| e.into_inner());
+        let siblings = local
+            .iter()
+            .filter(|(topic, member)| *topic == self.group_topic && member.starts_with(&local_peer))
+            .map(|(_, member)| member);
+        let taken = self
+            .members
+            .iter()
+            .filter(|(_, expires)| **expires > now)
+            .map(|(member, _)| member)
+            .chain(siblings)
+            .any(|member| (rank(member, &message.id.0), member) > own);
+        drop(local);
+        if !taken {
+            let _ = self.deliveries.unbounded_send(message);
+        }
+    }
+
+    /// Share the messages of the topic with the other members until `stop` fires.
+    async fn run(mut self, stop: oneshot::Receiver<()>) {
+        let mut heartbeats = stream::interval(HEARTBEAT_INTERVAL);
+        let mut stop = stop.fuse();
+        if let Err(e) = self.heartbeat() {
+            log::warn!("failed to join group on {}: {}", self.group_topic, e);
+        }
+        loop {
+            select! {
+                message = self.messages.next().fuse() => match message {
+                    Some(message) => self.receive_message(message),
+                    None => return,
+                },
+                message = self.announcements.next().fuse() => match message {
+                    Some(message) => {
+                        if let Err(e) = self.receive_announcement(message) {
+                            log::warn!("failed to announce group member on {}: {}", self.group_topic, e);
+                        }
+                    }
+                    None => return,
+                },
+                _ = heartbeats.next().fuse() => {
+                    if let Err(e) = self.heartbeat() {
+                        log::warn!("failed to announce group member on {}: {}", self.group_topic, e);
+                    }
+                }
+                _ = stop => {
+                    let member = self.member.clone();
+                    if let Err(e) = self.announce(Announcement::Goodbye { member }) {
+                        log::warn!("failed to leave group on {}: {}", self.group_topic, e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        let mut local = LOCAL_MEMBERS.lock().unwrap_or_else(|e| e.into_inner());
+        local.retain(|(_, member)| *member != self.member);
+    }
+}
+
+/// Join the queue group `group` on `topic`.
+pub(crate) fn join(client: &Client, topic: &str, group: &str) -> Result<GroupSubscription, Error> {
+    static NEXT_MEMBER: AtomicU64 = AtomicU64::new(1);
+    let member = format!(
+        "{}/{}",
+        client.local_peer_id().to_base58(),
+        NEXT_MEMBER.fetch_add(1, Ordering::Relaxed)
+    );
+    let group_topic = format!("pubsub-lite/group/{}/{}", group, topic);
+    let (deliveries, receiver) = mpsc::unbounded();
+    let (stop, stopped) = oneshot::channel();
+    let messages = client.subscribe(topic)?;
+    let announcements = client.subscribe(&group_topic)?;
+    // Registered once subscribed, so that a failed join leaves no member behind.
+    LOCAL_MEMBERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((group_topic.clone(), member.clone()));
+    let participant = Member {
+        client: client.clone(),
+        member: member.clone(),
+        messages,
+        announcements,
+        group_topic,
+        members: HashMap::new(),
+        deliveries,
+    };
+    task::spawn(participant.run(stopped));
+    Ok(GroupSubscription {
+        member,
+        receiver,
+        stop: Some(stop),
+    })
+}
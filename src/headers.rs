@@ -0,0 +1,295 @@
+//! Headers carried by messages, and subscriptions filtering messages on them, so that consumers
+//! of a busy topic only get the slice they care about.
+//!
+//! A message published with [`Client::publish_with_headers`](crate::Client::publish_with_headers)
+//! carries a map of [`Headers`], such as `region: eu` or `severity: warn`, which subscribers get
+//! as [`Message::headers`](crate::Message::headers). A subscription made with
+//! [`Client::subscribe_where`](crate::Client::subscribe_where) only delivers the messages whose
+//! headers satisfy all of its predicates:
+//!
+//! ```ignore
+//! let warnings = client.subscribe_where("logs", &["region=eu", "severity>=warn"])?;
+//! ```
+//!
+//! A predicate compares a header with a value by `=`, `!=`, `<`, `<=`, `>` or `>=`, and fails if
+//! the message lacks the header. Values compare as numbers if both are numbers, as log
+//! severities if both are, from `trace` to `debug`, `info`, `warn`, `error` and `fatal`, and as
+//! text otherwise. Messages are filtered by the node before they are queued, so those left out
+//! cost the subscriber nothing.
+//!
+//! The headers travel in an envelope: a marker, the length of the JSON object holding them, the
+//! object, then the payload. The envelope goes inside access tokens, replay nonces and
+//! signatures, which cover it, around the trace context of the message. Messages without headers
+//! have no envelope.
+
+use crate::{Error, PubSubError};
+use bytes::Bytes;
+use std::{cmp::Ordering, collections::BTreeMap, fmt, str::FromStr};
+
+/// Start of a payload carrying headers. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plh";
+
+/// The headers of a message, by name.
+pub type Headers = BTreeMap<String, String>;
+
+/// How a predicate compares a header with its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Operators, those that another one starts with last.
+const OPS: &[(&str, Op)] = &[
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+];
+
+/// A comparison of a header with a value, such as `severity>=warn`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Predicate {
+    name: String,
+    op: Op,
+    value: String,
+}
+
+impl Predicate {
+    /// Whether `headers` satisfy the predicate.
+    pub fn matches(&self, headers: &Headers) -> bool {
+        let header = match headers.get(&self.name) {
+            Some(header) => header,
+            None => return false,
+        };
+        let ordering = compare(header, &self.value);
+        match self.op {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = InvalidPredicate;
+
+    fn from_str(predicate: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| InvalidPredicate {
+            predicate: predicate.to_owned(),
+            reason,
+        };
+        let (at, token, op) = OPS
+            .iter()
+            .filter_map(|(token, op)| Some((predicate.find(token)?, *token, *op)))
+            .min_by_key(|(at, ..)| *at)
+            .ok_or_else(|| invalid("expected one of = != < <= > >="))?;
+        let name = predicate[..at].trim();
+        let value = predicate[at + token.len()..].trim();
+        if name.is_empty() {
+            return Err(invalid("header name is empty"));
+        }
+        if value.starts_with(['=', '<', '>', '!']) {
+            return Err(invalid("unknown operator"));
+        }
+        Ok(Predicate {
+            name: name.to_owned(),
+            op,
+            value: value.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let token = OPS
+            .iter()
+            .find(|(_, op)| *op == self.op)
+            .map_or("=", |(token, _)| *token);
+        write!(f, "{}{}{}", self.name, token, self.value)
+    }
+}
+
+/// Predicates all of which the headers of a message must satisfy, see the
+/// [module documentation](self).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderFilter {
+    predicates: Vec<Predicate>,
+}
+
+impl HeaderFilter {
+    /// Parse `predicates`, such as `region=eu` and `severity>=warn`.
+    pub fn new(predicates: &[&str]) -> Result<Self, InvalidPredicate> {
+        Ok(HeaderFilter {
+            predicates: predicates
+                .iter()
+                .map(|predicate| predicate.parse())
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether `headers` satisfy every predicate.
+    pub fn matches(&self, headers: &Headers) -> bool {
+        self.predicates
+            .iter()
+            .all(|predicate| predicate.matches(headers))
+    }
+}
+
+/// Parses predicates separated by commas, such as `region=eu,severity>=warn`.
+impl FromStr for HeaderFilter {
+    type Err = InvalidPredicate;
+
+    fn from_str(predicates: &str) -> Result<Self, Self::Err> {
+        let predicates: Vec<&str> = predicates
+            .split(',')
+            .filter(|predicate| !predicate.trim().is_empty())
+            .collect();
+        HeaderFilter::new(&predicates)
+    }
+}
+
+/// Error returned when parsing a malformed [`Predicate`].
+#[derive(Debug)]
+pub struct InvalidPredicate {
+    predicate: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for InvalidPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid header predicate {:?}: {}",
+            self.predicate, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidPredicate {}
+
+/// Order of two header values, as numbers, log severities or text.
+fn compare(a: &str, b: &str) -> Ordering {
+    if let (Ok(a), Ok(b)) = (a.parse::<f64>(), b.parse::<f64>()) {
+        return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+    }
+    if let (Some(a), Some(b)) = (severity(a), severity(b)) {
+        return a.cmp(&b);
+    }
+    a.cmp(b)
+}
+
+/// Rank of a log severity.
+fn severity(value: &str) -> Option<u8> {
+    match value.to_ascii_lowercase().as_str() {
+        "trace" => Some(0),
+        "debug" => Some(1),
+        "info" => Some(2),
+        "warn" | "warning" => Some(3),
+        "error" => Some(4),
+        "fatal" | "critical" => Some(5),
+        _ => None,
+    }
+}
+
+/// Put `data` in an envelope carrying `headers`, if there are any.
+pub(crate) fn attach(headers: &Headers, data: Bytes) -> Result<Bytes, Error> {
+    if headers.is_empty() {
+        return Ok(data);
+    }
+    let header = serde_json::to_vec(headers)?;
+    let mut envelope = Vec::with_capacity(MARKER.len() + 4 + header.len() + data.len());
+    envelope.extend_from_slice(MARKER);
+    envelope.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    envelope.extend_from_slice(&header);
+    envelope.extend_from_slice(&data);
+    Ok(envelope.into())
+}
+
+/// Take the envelope off a payload carrying headers, returning them, or no headers and the
+/// payload as is otherwise.
+pub(crate) fn strip(data: Bytes) -> Result<(Headers, Bytes), Error> {
+    if !data.starts_with(MARKER) {
+        return Ok((Headers::new(), data));
+    }
+    let malformed = || PubSubError::codec("malformed message headers");
+    let rest = &data[MARKER.len()..];
+    let len = rest.get(..4).ok_or_else(malformed)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let header = rest.get(4..4 + len).ok_or_else(malformed)?;
+    let headers = serde_json::from_slice(header)?;
+    Ok((headers, data.slice(MARKER.len() + 4 + len..)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_every_operator() {
+        for (predicate, op) in [
+            ("a=1", Op::Eq),
+            ("a!=1", Op::Ne),
+            ("a<1", Op::Lt),
+            ("a<=1", Op::Le),
+            ("a>1", Op::Gt),
+            ("a >= 1", Op::Ge),
+        ] {
+            let parsed: Predicate = predicate.parse().unwrap();
+            assert_eq!(parsed.op, op, "{}", predicate);
+            assert_eq!((parsed.name.as_str(), parsed.value.as_str()), ("a", "1"));
+        }
+        assert_eq!("a >= 1".parse::<Predicate>().unwrap().to_string(), "a>=1");
+    }
+
+    #[test]
+    fn rejects_malformed_predicates() {
+        for predicate in ["region", "=eu", "a==1", "a<>1", "a=>1"] {
+            assert!(predicate.parse::<Predicate>().is_err(), "{}", predicate);
+        }
+    }
+
+    #[test]
+    fn compares_numbers_severities_then_text() {
+        assert_eq!(compare("9", "10"), Ordering::Less);
+        assert_eq!(compare("1.0", "1"), Ordering::Equal);
+        assert_eq!(compare("warn", "ERROR"), Ordering::Less);
+        assert_eq!(compare("warning", "warn"), Ordering::Equal);
+        assert_eq!(compare("9", "warn"), Ordering::Less);
+        assert_eq!(compare("eu", "us"), Ordering::Less);
+    }
+
+    #[test]
+    fn filter_needs_every_predicate_and_header() {
+        let filter: HeaderFilter = "region=eu, severity>=warn".parse().unwrap();
+        assert!(filter.matches(&headers(&[("region", "eu"), ("severity", "error")])));
+        assert!(!filter.matches(&headers(&[("region", "eu"), ("severity", "info")])));
+        assert!(!filter.matches(&headers(&[("region", "eu")])));
+        assert!(HeaderFilter::default().matches(&Headers::new()));
+    }
+
+    #[test]
+    fn strips_what_attach_put() {
+        let data = Bytes::from_static(b"payload");
+        let carried = headers(&[("region", "eu")]);
+        let envelope = attach(&carried, data.clone()).unwrap();
+        assert_eq!(strip(envelope).unwrap(), (carried, data.clone()));
+        assert_eq!(attach(&Headers::new(), data.clone()).unwrap(), data);
+    }
+}
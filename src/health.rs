@@ -0,0 +1,110 @@
+//! Health of a node, for liveness and readiness probes such as those of Kubernetes.
+//!
+//! [`Client::health`](crate::Client::health) reports whether the node listens, how many peers it
+//! is connected to, the meshes of the topics its [`Readiness`] requires and the state of the
+//! journals of the [durable subscriptions](crate::durable) of the process, along with the
+//! thresholds they are held to. A node is [live](HealthReport::live) while it answers and listens
+//! on an address, and [ready](HealthReport::ready) once it is live, connected to enough peers,
+//! subscribed to every required topic with enough peers in its mesh, and none of the journals
+//! failed. The admin endpoint of the `gateway` feature serves the report as `/healthz` and
+//! `/readyz`.
+//!
+//! An embedded node has no listener, peers or meshes: it is live, and ready once subscribed to
+//! the required topics if its journals are fine.
+
+use crate::durable::JournalStatus;
+use libp2p::Multiaddr;
+
+/// What a node needs to be ready, besides listening and having its journals fine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Readiness {
+    /// Connected and identified peers the node needs.
+    pub min_peers: usize,
+    /// Topics the node needs to be subscribed to, each with
+    /// [`min_mesh_peers`](Self::min_mesh_peers) in its mesh.
+    pub required_topics: Vec<String>,
+    pub min_mesh_peers: usize,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness {
+            min_peers: 0,
+            required_topics: Vec::new(),
+            min_mesh_peers: 1,
+        }
+    }
+}
+
+/// Health of a node, returned by [`Client::health`](crate::Client::health).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthReport {
+    /// Whether the node listens on an address, or is embedded.
+    pub listening: bool,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Connected and identified peers.
+    pub peers: usize,
+    pub min_peers: usize,
+    /// The required topics.
+    pub topics: Vec<TopicHealth>,
+    /// The journals of the durable subscriptions open in the process.
+    pub journals: Vec<JournalStatus>,
+}
+
+/// Mesh of a required topic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicHealth {
+    pub topic: String,
+    /// Whether the node is subscribed to the topic.
+    pub subscribed: bool,
+    pub mesh_peers: usize,
+    pub min_mesh_peers: usize,
+}
+
+impl TopicHealth {
+    pub fn ready(&self) -> bool {
+        self.subscribed && self.mesh_peers >= self.min_mesh_peers
+    }
+}
+
+impl HealthReport {
+    /// Whether the node is live: it answered, and listens.
+    pub fn live(&self) -> bool {
+        self.listening
+    }
+
+    /// Whether the node is ready to serve, which is when there are no [problems](Self::problems).
+    pub fn ready(&self) -> bool {
+        self.problems().is_empty()
+    }
+
+    /// Why the node is not ready, if it is not.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.listening {
+            problems.push("not listening on any address".to_owned());
+        }
+        if self.peers < self.min_peers {
+            problems.push(format!(
+                "{} peers connected, {} needed",
+                self.peers, self.min_peers
+            ));
+        }
+        for topic in &self.topics {
+            if !topic.subscribed {
+                problems.push(format!("not subscribed to {}", topic.topic));
+            } else if !topic.ready() {
+                problems.push(format!(
+                    "{} peers in the mesh of {}, {} needed",
+                    topic.mesh_peers, topic.topic, topic.min_mesh_peers
+                ));
+            }
+        }
+        for journal in &self.journals {
+            if let Some(error) = &journal.error {
+                problems.push(format!("journal {}: {}", journal.path.display(), error));
+            }
+        }
+        problems
+    }
+}
@@ -0,0 +1,210 @@
+//! Setting up the repo of a node, and a private network to join, in one step.
+//!
+//! [`init`] creates the directory of a repo, `$IPFS_PATH` or `~/.ipfs` by default, and writes to
+//! it, readable by its owner only:
+//!
+//! - a `config` file in the format of go-ipfs, holding a new Ed25519 identity as
+//!   `Identity.PrivKey`, the address to listen on as `Addresses.Swarm`, every IPv4 interface on
+//!   [`InitOptions::port`], and the peers to dial as `Bootstrap`;
+//! - a `swarm.key`, the pre-shared key of the private network, generated unless one is given.
+//!
+//! The node reads both when started on the repo, see
+//! [`get_ipfs_config`](crate::transport::get_ipfs_config) and
+//! [`get_psk`](crate::transport::get_psk), so that it keeps its peer id across restarts: the
+//! [start command](Initialized::start_command) has it take the identity of the config as its own
+//! and listen on its addresses, no go-ipfs daemon running on the repo. The
+//! [`Initialized`] repo tells the command setting up the next node of the network, with the swarm
+//! key and this node to bootstrap from:
+//!
+//! ```text
+//! pub init --swarm-key <hex> --bootstrap /ip4/192.0.2.7/tcp/4001/p2p/12D3KooW...
+//! ```
+//!
+//! The addresses other nodes reach this one at are guessed from the interface of the default
+//! route unless given: a node behind NAT should be given its public address. Whoever sees the
+//! command can join the network, so it is best passed on as a secret.
+
+use crate::{
+    transport::{encode_ipfs_private_key, supported},
+    Error,
+};
+use data_encoding::HEXLOWER;
+use libp2p::{identity, multiaddr::Protocol, pnet::PreSharedKey, Multiaddr, PeerId};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    net::{IpAddr, Ipv4Addr, UdpSocket},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Port nodes listen on unless told otherwise, the one of go-ipfs.
+pub const DEFAULT_PORT: u16 = 4001;
+
+/// How to set up a repo.
+#[derive(Clone, Debug)]
+pub struct InitOptions {
+    /// Port to listen on. Zero picks any free port on every start, which other nodes cannot
+    /// bootstrap from.
+    pub port: u16,
+    /// Swarm key of the network to join, a new network being created if `None`.
+    pub swarm_key: Option<PreSharedKey>,
+    /// Peers of the network to dial on start.
+    pub bootstrap: Vec<Multiaddr>,
+    /// Addresses other nodes reach this one at, guessed if empty.
+    pub announce: Vec<Multiaddr>,
+    /// Whether to overwrite the config and swarm key of an existing repo, and with them its
+    /// identity.
+    pub force: bool,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            port: DEFAULT_PORT,
+            swarm_key: None,
+            bootstrap: Vec::new(),
+            announce: Vec::new(),
+            force: false,
+        }
+    }
+}
+
+/// A repo set up by [`init`].
+#[derive(Clone, Debug)]
+pub struct Initialized {
+    pub path: PathBuf,
+    pub peer_id: PeerId,
+    pub swarm_key: PreSharedKey,
+    /// Whether the swarm key was generated, creating a network, rather than given.
+    pub new_network: bool,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses other nodes bootstrap from, ending with the peer id of the node.
+    pub join_addrs: Vec<Multiaddr>,
+}
+
+impl Initialized {
+    /// The command starting the node on the repo, with the identity and addresses of its config.
+    pub fn start_command(&self) -> String {
+        format!(
+            "PUBSUB_IDENTITY=ipfs PUBSUB_LISTEN=ipfs IPFS_PATH={} pub",
+            self.path.display()
+        )
+    }
+
+    /// The command setting up another node of the network, bootstrapping from this one.
+    pub fn join_command(&self) -> String {
+        let mut command = format!("pub init --swarm-key {}", swarm_key_hex(&self.swarm_key));
+        for addr in &self.join_addrs {
+            command.push_str(" --bootstrap ");
+            command.push_str(&addr.to_string());
+        }
+        command
+    }
+}
+
+/// The hex key of a swarm key, the last line of its file.
+fn swarm_key_hex(key: &PreSharedKey) -> String {
+    key.to_string()
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// The swarm key whose key is `hex`, 64 hex digits.
+pub fn parse_swarm_key(hex: &str) -> Result<PreSharedKey, Error> {
+    PreSharedKey::from_str(&format!("/key/swarm/psk/1.0.0/\n/base16/\n{}", hex.trim()))
+        .map_err(|e| format!("invalid swarm key: {:?}", e).into())
+}
+
+fn generate_swarm_key() -> Result<PreSharedKey, Error> {
+    let mut key = [0; 32];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| "failed to generate a swarm key")?;
+    parse_swarm_key(&HEXLOWER.encode(&key))
+}
+
+/// Write `contents` to the file at `path`, readable by its owner only.
+fn write_private(path: &Path, contents: &str) -> Result<(), Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// The address of the interface of the default route, without sending anything, or loopback if
+/// there is none.
+fn default_route_ip() -> IpAddr {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            // A documentation address: connecting a UDP socket only picks a route.
+            socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9))?;
+            socket.local_addr()
+        })
+        .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |addr| addr.ip())
+}
+
+/// Set up the repo at `path`, see the [module documentation](self). Fails if it has a config
+/// already, unless [`InitOptions::force`] is set.
+pub fn init(path: &Path, options: &InitOptions) -> Result<Initialized, Error> {
+    let config_path = path.join("config");
+    if config_path.exists() && !options.force {
+        return Err(Error::config(format!(
+            "{} exists already, pass --force to overwrite it and the identity it holds",
+            config_path.display()
+        )));
+    }
+    if let Some(addr) = options.bootstrap.iter().find(|addr| !supported(addr)) {
+        return Err(Error::config(format!(
+            "cannot bootstrap from unsupported address {}",
+            addr
+        )));
+    }
+    fs::create_dir_all(path)?;
+    let keypair = identity::Keypair::generate_ed25519();
+    let peer_id = PeerId::from(keypair.public());
+    let (swarm_key, new_network) = match options.swarm_key {
+        Some(key) => (key, false),
+        None => (generate_swarm_key()?, true),
+    };
+    // Not on `/ip6/::` too: the dual-stack socket would take the IPv4 connections of the port.
+    let listen_addrs =
+        vec![Multiaddr::from(Ipv4Addr::UNSPECIFIED).with(Protocol::Tcp(options.port))];
+    let config = json!({
+        "Identity": {
+            "PeerID": peer_id.to_base58(),
+            "PrivKey": encode_ipfs_private_key(&keypair)?,
+        },
+        "Addresses": {
+            "Swarm": listen_addrs.iter().map(Multiaddr::to_string).collect::<Vec<_>>(),
+        },
+        "Bootstrap": options.bootstrap.iter().map(Multiaddr::to_string).collect::<Vec<_>>(),
+    });
+    write_private(&config_path, &serde_json::to_string_pretty(&config)?)?;
+    write_private(&path.join("swarm.key"), &swarm_key.to_string())?;
+    let announce = match options.announce.is_empty() {
+        true => vec![Multiaddr::from(default_route_ip()).with(Protocol::Tcp(options.port))],
+        false => options.announce.clone(),
+    };
+    let join_addrs = announce
+        .into_iter()
+        .map(|addr| match addr.iter().last() {
+            Some(Protocol::P2p(_)) => addr,
+            _ => addr.with(Protocol::P2p(peer_id.clone().into())),
+        })
+        .collect();
+    Ok(Initialized {
+        path: path.to_owned(),
+        peer_id,
+        swarm_key,
+        new_network,
+        listen_addrs,
+        join_addrs,
+    })
+}
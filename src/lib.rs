@@ -0,0 +1,136 @@
+//! A lightweight pubsub node built on rust-libp2p gossipsub.
+//!
+//! [`node::spawn`] starts the swarm on a background task and hands back a [`Client`] used to
+//! publish and subscribe.
+//!
+//! Everything beyond the node and its client is behind cargo features: the
+//! [gateways](crate::gateway) and the schema registry client are on by default, the
+//! [bridges](crate::bridge), [sinks](crate::sink), plugins and the terminal monitor off. A build
+//! with `--no-default-features --features minimal` leaves them all out, for small binaries such as
+//! those of embedded Linux gateways; the items of the features left out are then missing, each
+//! documented with the feature it is behind. Such builds authenticate connections with noise only,
+//! and fail to compile when another feature leaving them bigger is enabled along with `minimal`,
+//! naming that feature.
+
+// The select! of the node run loop expands past the default limit.
+#![recursion_limit = "256"]
+
+/// Fails to compile `minimal` builds that enable one of `$feature` too, naming it.
+macro_rules! minimal_leaves_out {
+    ($($feature:literal),*) => {
+        $(
+            #[cfg(all(feature = "minimal", feature = $feature))]
+            compile_error!(concat!(
+                "the `minimal` feature leaves out the `",
+                $feature,
+                "` feature: build with `--no-default-features --features minimal` and without `",
+                $feature,
+                "`"
+            ));
+        )*
+    };
+}
+
+minimal_leaves_out!(
+    "gateway",
+    "grpc",
+    "registry",
+    "mqtt",
+    "multicast",
+    "nats",
+    "exec",
+    "kafka",
+    "webhook",
+    "wasm",
+    "tui",
+    "websocket",
+    "sled"
+);
+
+pub mod acl;
+pub mod archive;
+pub mod attestation;
+pub mod autonat;
+pub mod bandwidth;
+pub mod bench;
+pub mod bridge;
+pub mod broker;
+pub mod chaos;
+mod chunking;
+pub mod client;
+pub mod clock;
+mod codec;
+pub mod compat;
+pub mod compression;
+pub mod crypto;
+pub mod daemon;
+pub mod debug;
+pub mod delegation;
+pub mod direct;
+pub mod directory;
+pub mod dns;
+pub mod durable;
+pub mod error;
+pub mod floodsub;
+pub mod flow;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+pub mod gating;
+pub mod group;
+pub mod headers;
+pub mod health;
+pub mod init;
+pub mod liveness;
+pub mod lock;
+pub mod manifest;
+pub mod message_id;
+pub mod migration;
+pub mod node;
+pub mod nodeset;
+pub mod observer;
+pub mod offload;
+pub mod pacing;
+#[cfg(feature = "grpc")]
+pub mod pb;
+pub mod peer_exchange;
+pub mod peering;
+pub mod peerstore;
+pub mod pipeline;
+#[cfg(feature = "wasm")]
+pub mod plugin;
+pub mod presence;
+pub mod preset;
+pub mod priority;
+pub mod queue;
+pub mod reconcile;
+pub mod relay;
+pub mod replay;
+pub mod retention;
+pub mod rotation;
+pub mod routing;
+pub mod schema;
+pub mod sequence;
+pub mod shard;
+pub mod sidecar;
+pub mod sink;
+pub mod size;
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod top;
+pub mod topic;
+pub mod topology;
+pub mod trace;
+pub mod trace_context;
+pub mod transport;
+pub mod validation;
+
+pub use bytes::Bytes;
+pub use client::{Client, Message, NodeEvent, Subscription};
+pub use error::PubSubError;
+pub use node::NodeConfig;
+
+/// Error type used throughout the crate, see the [`error`] module.
+pub type Error = PubSubError;
@@ -0,0 +1,157 @@
+//! Tracking of the round-trip time of peers, and eviction of those too slow or gone silent.
+//!
+//! Every connected peer is pinged every 15 seconds; the node keeps the round-trip times of the
+//! last [`HISTORY_LEN`] pings of each, and how many pings in a row failed since the last that
+//! succeeded. Both are listed by [`Client::stats`](crate::Client::stats) and the `/peers`
+//! endpoint of the admin gateway.
+//!
+//! The [`EvictionPolicy`] of [`NodeConfig::eviction`](crate::NodeConfig::eviction) disconnects
+//! the peers failing [`EvictionPolicy::max_failures`] pings in a row, and those whose median
+//! round-trip time over their history exceeds [`EvictionPolicy::max_rtt`], once at least
+//! [`EvictionPolicy::min_samples`] pings succeeded. A peer evicted is banned for
+//! [`EvictionPolicy::ban`], so that its connection slot and its place in the mesh of its topics
+//! go to healthier peers rather than to it dialing again. Explicit peers are never evicted for
+//! their round-trip time, and are disconnected without being banned when their pings fail, to
+//! be redialed as usual.
+
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Number of round-trip times kept per peer.
+pub const HISTORY_LEN: usize = 16;
+
+/// When to disconnect a peer for its pings.
+#[derive(Clone, Debug)]
+pub struct EvictionPolicy {
+    /// Longest median round-trip time tolerated, if any.
+    pub max_rtt: Option<Duration>,
+    /// Pings failing in a row before the peer is disconnected, at least 1.
+    pub max_failures: u32,
+    /// Round-trip times needed before the median is checked against `max_rtt`.
+    pub min_samples: usize,
+    /// How long an evicted peer is refused before it may connect again.
+    pub ban: Duration,
+}
+
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy {
+            max_rtt: None,
+            max_failures: 1,
+            min_samples: 3,
+            ban: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What the pings of a peer told so far.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PeerLiveness {
+    /// Round-trip times of the last successful pings, the oldest first.
+    history: VecDeque<Duration>,
+    /// Pings failed since the last that succeeded.
+    failures: u32,
+}
+
+impl PeerLiveness {
+    pub fn succeeded(&mut self, rtt: Duration) {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(rtt);
+        self.failures = 0;
+    }
+
+    pub fn failed(&mut self) {
+        self.failures += 1;
+    }
+
+    /// Round-trip time of the last successful ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.history.back().copied()
+    }
+
+    pub fn history(&self) -> Vec<Duration> {
+        self.history.iter().copied().collect()
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Median of the round-trip times kept, the lower one for an even number of them.
+    pub(crate) fn median(&self) -> Option<Duration> {
+        let mut sorted = self.history();
+        sorted.sort_unstable();
+        sorted.get(sorted.len().saturating_sub(1) / 2).copied()
+    }
+}
+
+/// Why a peer is evicted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Eviction {
+    /// Its pings failed too many times in a row.
+    Unresponsive(u32),
+    /// Its median round-trip time is too long.
+    Slow(Duration),
+}
+
+/// The eviction policy of a node, with the peers it banned.
+pub(crate) struct Liveness {
+    policy: EvictionPolicy,
+    /// When every banned peer may connect again.
+    banned: HashMap<PeerId, Instant>,
+}
+
+impl Liveness {
+    pub fn new(policy: EvictionPolicy) -> Self {
+        Liveness {
+            policy,
+            banned: HashMap::new(),
+        }
+    }
+
+    /// Pings failing in a row before the peer is disconnected.
+    pub fn max_failures(&self) -> u32 {
+        self.policy.max_failures.max(1)
+    }
+
+    /// Whether the pings of a peer call for its eviction, and why. Explicit peers are only
+    /// evicted for failing pings.
+    pub fn check(&self, liveness: &PeerLiveness, explicit: bool) -> Option<Eviction> {
+        if liveness.failures >= self.max_failures() {
+            return Some(Eviction::Unresponsive(liveness.failures));
+        }
+        let max_rtt = self.policy.max_rtt.filter(|_| !explicit)?;
+        if liveness.history.len() < self.policy.min_samples.max(1) {
+            return None;
+        }
+        liveness
+            .median()
+            .filter(|median| *median > max_rtt)
+            .map(Eviction::Slow)
+    }
+
+    /// Ban `peer` for the time the policy says.
+    pub fn ban(&mut self, peer: PeerId) {
+        self.banned.insert(peer, Instant::now() + self.policy.ban);
+    }
+
+    /// The peers whose ban is over, forgetting them.
+    pub fn expired(&mut self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in &expired {
+            self.banned.remove(peer);
+        }
+        expired
+    }
+}
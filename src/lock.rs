@@ -0,0 +1,338 @@
+//! Lease-based distributed locks with fencing tokens.
+//!
+//! Every lock is arbitrated on its own coordination topic. The holder re-announces its lease
+//! every third of the ttl and the other nodes consider the lock free once a lease expires
+//! without being renewed. There is no consensus round underneath, so two nodes that cannot hear
+//! each other may both believe they hold the same lock. Each grant therefore carries a fencing
+//! token that strictly increases from one holder to the next: resources guarded by a lock should
+//! remember the highest token they have seen and reject requests carrying a lower one.
+
+use crate::{Client, Error, PubSubError, Subscription};
+use async_std::{future::timeout, task};
+use futures::{channel::oneshot, future, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Announcement exchanged on a lock's coordination topic.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Announcement {
+    /// `holder` claims (or renews) the lock for `ttl_ms` milliseconds under fencing `token`.
+    Claim {
+        holder: String,
+        token: u64,
+        ttl_ms: u64,
+    },
+    /// `holder` gives up the lease it was granted under `token`.
+    Release { holder: String, token: u64 },
+}
+
+/// The lease a node currently believes in.
+struct Lease {
+    holder: String,
+    token: u64,
+    expires: Instant,
+}
+
+/// Local view of a single lock, built from the announcements heard on its topic.
+#[derive(Default)]
+struct LockState {
+    lease: Option<Lease>,
+}
+
+impl LockState {
+    fn apply(&mut self, announcement: Announcement) {
+        let now = Instant::now();
+        match announcement {
+            Announcement::Claim {
+                holder,
+                token,
+                ttl_ms,
+            } => {
+                let accepted = match &self.lease {
+                    None => true,
+                    // A higher token always wins: its holder has seen every earlier grant.
+                    Some(lease) if token > lease.token => true,
+                    // Concurrent claims for the same token are settled by the lowest peer id.
+                    Some(lease) if token == lease.token => holder <= lease.holder,
+                    Some(_) => false,
+                };
+                if accepted {
+                    self.lease = Some(Lease {
+                        holder,
+                        token,
+                        expires: now + Duration::from_millis(ttl_ms),
+                    });
+                }
+            }
+            Announcement::Release { holder, token } => {
+                if let Some(lease) = &mut self.lease {
+                    if lease.holder == holder && lease.token == token {
+                        lease.expires = now;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The lease that has not expired yet, if any.
+    fn live_lease(&self) -> Option<&Lease> {
+        self.lease
+            .as_ref()
+            .filter(|lease| lease.expires > Instant::now())
+    }
+
+    fn is_held_by(&self, holder: &str, token: u64) -> bool {
+        self.live_lease()
+            .is_some_and(|lease| lease.holder == holder && lease.token == token)
+    }
+
+    fn next_token(&self) -> u64 {
+        self.lease.as_ref().map_or(1, |lease| lease.token + 1)
+    }
+}
+
+/// One participant of a lock: the coordination topic plus the state heard on it.
+struct Participant {
+    client: Client,
+    topic: String,
+    holder: String,
+    ttl: Duration,
+    subscription: Subscription,
+    state: LockState,
+}
+
+impl Participant {
+    /// Publish an announcement and apply it right away: the node hands our own messages back to
+    /// us only with local delivery on, and only after the announcement returns.
+    fn announce(&mut self, announcement: Announcement) -> Result<(), Error> {
+        self.client
+            .publish(&self.topic, serde_json::to_vec(&announcement)?)?;
+        self.state.apply(announcement);
+        Ok(())
+    }
+
+    fn claim(&mut self, token: u64) -> Result<(), Error> {
+        self.announce(Announcement::Claim {
+            holder: self.holder.clone(),
+            token,
+            ttl_ms: self.ttl.as_millis() as u64,
+        })
+    }
+
+    /// Apply every announcement received until `deadline`.
+    async fn observe_until(&mut self, deadline: Instant) -> Result<(), Error> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            match timeout(deadline - now, self.subscription.next()).await {
+                Ok(Some(message)) => match serde_json::from_slice(&message.data) {
+                    Ok(announcement) => self.state.apply(announcement),
+                    Err(e) => log::warn!("ignoring malformed lock announcement: {}", e),
+                },
+                Ok(None) => return Err(Error::Shutdown),
+                Err(_) => return Ok(()),
+            }
+        }
+    }
+
+    /// Keep renewing the lease granted under `token` until `stop` fires or the lease is lost.
+    async fn renew(mut self, token: u64, lost: Arc<AtomicBool>, mut stop: oneshot::Receiver<()>) {
+        let interval = self.ttl / 3;
+        loop {
+            let stopped = {
+                let renewal = self.observe_until(Instant::now() + interval);
+                futures::pin_mut!(renewal);
+                match future::select(&mut stop, renewal).await {
+                    future::Either::Left(_) => Ok(true),
+                    future::Either::Right((result, _)) => result.map(|()| false),
+                }
+            };
+            match stopped {
+                Ok(true) => {
+                    let release = Announcement::Release {
+                        holder: self.holder.clone(),
+                        token,
+                    };
+                    if let Err(e) = self.announce(release) {
+                        log::warn!("failed to release lock {}: {}", self.topic, e);
+                    }
+                    return;
+                }
+                Ok(false)
+                    if self.state.is_held_by(&self.holder, token) && self.claim(token).is_ok() =>
+                {
+                    continue
+                }
+                _ => {}
+            }
+            lost.store(true, Ordering::SeqCst);
+            return;
+        }
+    }
+}
+
+/// A granted lock. The lease is renewed in the background until the guard is dropped.
+pub struct LockGuard {
+    name: String,
+    token: u64,
+    lost: Arc<AtomicBool>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl LockGuard {
+    /// Name of the lock.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Fencing token of this grant, strictly greater than the token of every earlier holder.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Whether the lease is still held. Turns false once a renewal fails or another node's
+    /// claim supersedes ours, e.g. after a network partition heals.
+    pub fn is_held(&self) -> bool {
+        !self.lost.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Shortest ttl of a lock. Leases are announced in milliseconds and renewed every third of the
+/// ttl, which a shorter ttl would make nothing or a busy loop.
+const MIN_TTL: Duration = Duration::from_millis(3);
+
+/// Acquire the lock `name`, waiting for the current holder's lease to expire if necessary.
+///
+/// Every participant of a lock should use the same `ttl`, of at least 3ms.
+pub(crate) async fn acquire(
+    client: &Client,
+    name: &str,
+    ttl: Duration,
+) -> Result<LockGuard, Error> {
+    if ttl < MIN_TTL {
+        return Err(PubSubError::config(format!(
+            "the ttl of lock {} must be at least {:?}, not {:?}",
+            name, MIN_TTL, ttl
+        )));
+    }
+    let topic = format!("pubsub-lite/lock/{}", name);
+    let mut participant = Participant {
+        client: client.clone(),
+        subscription: client.subscribe(&topic)?,
+        topic,
+        holder: client.local_peer_id().to_base58(),
+        ttl,
+        state: LockState::default(),
+    };
+
+    // Listen for a whole ttl, i.e. several renewals, to learn about an existing holder before
+    // claiming.
+    participant.observe_until(Instant::now() + ttl).await?;
+    loop {
+        let busy_until = participant.state.live_lease().map(|lease| lease.expires);
+        if let Some(expires) = busy_until {
+            // Re-check every renewal interval so that a release is noticed early.
+            let deadline = expires.min(Instant::now() + ttl / 3);
+            participant.observe_until(deadline).await?;
+            continue;
+        }
+
+        let token = participant.state.next_token();
+        participant.claim(token)?;
+        // Give concurrent claimants the chance to be heard before considering the lock ours.
+        participant.observe_until(Instant::now() + ttl / 3).await?;
+        if participant.state.is_held_by(&participant.holder, token) {
+            participant.claim(token)?;
+            let lost = Arc::new(AtomicBool::new(false));
+            let (stop, stopped) = oneshot::channel();
+            task::spawn(participant.renew(token, lost.clone(), stopped));
+            return Ok(LockGuard {
+                name: name.to_owned(),
+                token,
+                lost,
+                stop: Some(stop),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{node, NodeConfig};
+
+    fn claim(holder: &str, token: u64) -> Announcement {
+        Announcement::Claim {
+            holder: holder.to_owned(),
+            token,
+            ttl_ms: 60_000,
+        }
+    }
+
+    #[test]
+    fn higher_tokens_win() {
+        let mut state = LockState::default();
+        assert_eq!(state.next_token(), 1);
+        state.apply(claim("b", 1));
+        assert!(state.is_held_by("b", 1));
+        state.apply(claim("a", 0));
+        assert!(state.is_held_by("b", 1));
+        state.apply(claim("c", 2));
+        assert!(state.is_held_by("c", 2));
+        assert_eq!(state.next_token(), 3);
+    }
+
+    #[test]
+    fn lowest_holder_wins_a_tie() {
+        let mut state = LockState::default();
+        state.apply(claim("b", 1));
+        state.apply(claim("c", 1));
+        assert!(state.is_held_by("b", 1));
+        state.apply(claim("a", 1));
+        assert!(state.is_held_by("a", 1));
+    }
+
+    #[test]
+    fn release_frees_the_lock_but_not_its_token() {
+        let mut state = LockState::default();
+        state.apply(claim("a", 1));
+        state.apply(Announcement::Release {
+            holder: "b".to_owned(),
+            token: 1,
+        });
+        assert!(state.is_held_by("a", 1));
+        state.apply(Announcement::Release {
+            holder: "a".to_owned(),
+            token: 1,
+        });
+        assert!(state.live_lease().is_none());
+        assert_eq!(state.next_token(), 2);
+    }
+
+    #[test]
+    fn short_ttl_is_rejected() {
+        let client = node::spawn(NodeConfig {
+            embedded: true,
+            ..NodeConfig::default()
+        })
+        .unwrap();
+        let result = task::block_on(client.lock("short", Duration::from_millis(1)));
+        assert!(matches!(result, Err(PubSubError::Config(_))));
+    }
+}
@@ -0,0 +1,330 @@
+//! Topics declared up front, with options of their own, for a node to join when it starts.
+//!
+//! A manifest is JSON, mapping topics or wildcard filters to their options, naming the gossipsub
+//! [`preset`](crate::preset) of the node if any, sizing its [`caches`](Caches) and listing the
+//! [`routes`](crate::routing) it republishes messages along:
+//!
+//! ```json
+//! {
+//!     "preset": "many-topics",
+//!     "message_ids": "content",
+//!     "caches": {"history_length": 10, "duplicates": 100000, "memory_budget": 268435456},
+//!     "topics": {
+//!         "chat": {},
+//!         "alerts": {"retained": 100, "max_message_size": 4096, "handler": ["notify-send", "alert"]},
+//!         "sensors/+/temp": {"validation": {"signed": {"org": "8f0e...c3"}}},
+//!         "orders": {"mesh": {"agent": "pubsub-lite/1.*"}, "priority": "control", "required": true}
+//!     },
+//!     "routes": [{"from": ["sensors/+/temp"], "to": "temps"}],
+//!     "upload_limit": {"bytes_per_second": 1048576, "burst": 262144, "aging_ms": 1000}
+//! }
+//! ```
+//!
+//! - `validation` is `"none"`, the default, `{"signed": {"org": <public key>}}` to drop the
+//!   messages not published under the organisation, see
+//!   [`NodeConfig::trusted_orgs`](crate::NodeConfig::trusted_orgs), `{"owned": {"owner": <public
+//!   key>}}` to reject the messages of publishers holding no access token of the owner, see
+//!   [`NodeConfig::topic_owners`](crate::NodeConfig::topic_owners), or, with the `wasm` feature,
+//!   `{"plugin": {"path": <module>}}` to run a validator [`plugin`](crate::plugin);
+//! - `retained` is how many messages are kept for later subscribers, see the
+//!   [`retention`](crate::retention) module;
+//! - `max_message_size` is the limit on the payloads of the topic, and `oversize` what becomes
+//!   of larger ones published, `"reject"`, the default, `"truncate"` or `"chunk"`, see the
+//!   [`size`](crate::size) module;
+//! - `mesh` is the gate of the mesh of the topic, admitting the peers whose `agent` version
+//!   matches a pattern and that support every one of its `protocols`, see the
+//!   [`gating`](crate::gating) module;
+//! - `priority` is `"control"`, `"high"`, `"normal"`, the default, or `"bulk"`, see the
+//!   [`priority`](crate::priority) module;
+//! - `required` tells whether the node is ready only once the mesh of the topic has peers, see
+//!   the [`health`](crate::health) module. Wildcard filters cannot be required;
+//! - `handler` is a command run for every message, with the payload on its standard input and
+//!   the topic, source and id of the message in the `PUBSUB_TOPIC`, `PUBSUB_SOURCE` and
+//!   `PUBSUB_MESSAGE_ID` environment variables. The messages of a topic are handled one at a
+//!   time, in order.
+//!
+//! `message_ids` names the function computing the ids of messages, `"publisher"` or
+//! `"content"`, see the [`message_id`](crate::message_id) module.
+//!
+//! An `upload_limit` section sets the [budget](crate::priority::UploadLimit) of the upload of the
+//! node, its defaults for the fields not given: `bytes_per_second`, `burst`, `max_queued`, and
+//! `aging_ms`, how many milliseconds a waiting message takes to move up a priority, `0` for
+//! strict priorities. See the [`priority`](crate::priority) module.
+//!
+//! A `chaos` section injects faults into the gossip of the node to test applications, see the
+//! [`chaos`](crate::chaos) module.
+//!
+//! [`Manifest::apply`] sets the preset and the message ids, then the cache sizes, upload limit and
+//! faults, and adds the routes and the options the node enforces to its configuration before it
+//! is spawned, and [`Manifest::start`] then
+//! subscribes to every topic.
+
+#[cfg(feature = "wasm")]
+use crate::plugin::{Limits, Plugin};
+use crate::{
+    chaos::ChaosConfig,
+    delegation::PublicKey,
+    gating::MeshGate,
+    message_id::MessageIds,
+    preset::Preset,
+    priority::{Priority, UploadLimit},
+    retention::RetentionPolicy,
+    routing::Route,
+    size::OversizePolicy,
+    topic::TopicFilter,
+    Client, Error, Message, NodeConfig, Subscription,
+};
+use async_std::task;
+use futures::prelude::*;
+use serde::Deserialize;
+#[cfg(feature = "wasm")]
+use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    process::{self, Stdio},
+    time::Duration,
+};
+
+/// Topics to join, by topic or wildcard filter.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Manifest {
+    /// Gossipsub parameters of the node, if not those it is configured with.
+    pub preset: Option<Preset>,
+    /// How the node computes the ids of messages, if not as it is configured to.
+    pub message_ids: Option<MessageIds>,
+    /// Sizes of the caches of the node, if not those it is configured with or of the preset.
+    pub caches: Caches,
+    pub topics: BTreeMap<String, TopicSpec>,
+    /// Routes of the node, after those it is configured with.
+    pub routes: Vec<Route>,
+    /// Budget of the upload of the node, if not the one it is configured with.
+    pub upload_limit: Option<Upload>,
+    /// Faults injected into the gossip of the node, for testing only.
+    pub chaos: Option<ChaosConfig>,
+}
+
+/// Sizes of the caches of a node, each left as configured unless given.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Caches {
+    /// Heartbeats gossipsub keeps messages for, see
+    /// [`GossipsubConfig::history_length`](libp2p::gossipsub::GossipsubConfig::history_length).
+    pub history_length: Option<usize>,
+    /// Heartbeats gossipsub gossips about messages for, see
+    /// [`GossipsubConfig::history_gossip`](libp2p::gossipsub::GossipsubConfig::history_gossip).
+    pub history_gossip: Option<usize>,
+    /// Message ids remembered to drop duplicates, see
+    /// [`GossipsubConfig::duplicate_cache_size`](libp2p::gossipsub::GossipsubConfig::duplicate_cache_size).
+    pub duplicates: Option<usize>,
+    /// See [`NodeConfig::memory_budget`].
+    pub memory_budget: Option<usize>,
+}
+
+/// Budget of the upload of a node, see [`UploadLimit`].
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Upload {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+    pub max_queued: usize,
+    /// Milliseconds a waiting message takes to move up a priority, none if zero.
+    pub aging_ms: u64,
+}
+
+impl Default for Upload {
+    fn default() -> Self {
+        let limit = UploadLimit::default();
+        Upload {
+            bytes_per_second: limit.bytes_per_second,
+            burst: limit.burst,
+            max_queued: limit.max_queued,
+            aging_ms: limit.aging.map_or(0, |aging| aging.as_millis() as u64),
+        }
+    }
+}
+
+impl From<Upload> for UploadLimit {
+    fn from(upload: Upload) -> Self {
+        UploadLimit {
+            bytes_per_second: upload.bytes_per_second,
+            burst: upload.burst,
+            max_queued: upload.max_queued,
+            aging: Some(Duration::from_millis(upload.aging_ms)).filter(|aging| !aging.is_zero()),
+        }
+    }
+}
+
+/// Options of a declared topic.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopicSpec {
+    pub validation: Validation,
+    /// Messages retained for later subscribers, none if zero.
+    pub retained: usize,
+    /// Limit on the size of payloads in bytes, if stricter than the node default.
+    pub max_message_size: Option<usize>,
+    /// What becomes of payloads published over the limit, if not rejected.
+    pub oversize: Option<OversizePolicy>,
+    /// Gate of the mesh of the topic, if any.
+    pub mesh: Option<MeshGate>,
+    /// Priority of the messages of the topic, if not normal.
+    pub priority: Option<Priority>,
+    /// Whether the node needs the topic to be ready.
+    pub required: bool,
+    /// Program run for every message, followed by its arguments.
+    pub handler: Option<Vec<String>>,
+}
+
+/// Which messages of a topic are delivered.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Validation {
+    /// All of them.
+    #[default]
+    None,
+    /// Those published under the organisation `org`.
+    Signed { org: PublicKey },
+    /// Those published by the peers holding an access token of `owner`.
+    Owned { owner: PublicKey },
+    /// Those the validator plugin at `path` accepts.
+    #[cfg(feature = "wasm")]
+    Plugin { path: PathBuf },
+}
+
+impl Manifest {
+    pub fn from_json(text: &str) -> Result<Self, Error> {
+        let manifest: Manifest = serde_json::from_str(text)?;
+        for (topic, spec) in &manifest.topics {
+            if TopicFilter::new(topic)?.is_wildcard() && spec.required {
+                return Err(format!("wildcard filter {} cannot be required", topic).into());
+            }
+            if let Some(handler) = &spec.handler {
+                if handler.is_empty() {
+                    return Err(format!("handler of {} has no program", topic).into());
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Set the gossipsub parameters of the preset and the message ids on `config`, if any, the
+    /// sizes of the caches given, the upload limit and the faults to inject, and add the routes, and the retention,
+    /// size limits, trusted organisations, topic owners, mesh gates, priorities and readiness of
+    /// the declared topics, after those it already has.
+    pub fn apply(&self, config: &mut NodeConfig) {
+        if let Some(preset) = self.preset {
+            preset.apply(&mut config.gossipsub);
+        }
+        if let Some(message_ids) = self.message_ids {
+            message_ids.apply(&mut config.gossipsub);
+        }
+        let caches = self.caches;
+        if let Some(history_length) = caches.history_length {
+            config.gossipsub.history_length = history_length;
+        }
+        if let Some(history_gossip) = caches.history_gossip {
+            config.gossipsub.history_gossip = history_gossip;
+        }
+        if let Some(duplicates) = caches.duplicates {
+            config.gossipsub.duplicate_cache_size = duplicates;
+        }
+        if caches.memory_budget.is_some() {
+            config.memory_budget = caches.memory_budget;
+        }
+        if let Some(upload) = self.upload_limit {
+            config.upload_limit = Some(upload.into());
+        }
+        if self.chaos.is_some() {
+            config.chaos = self.chaos;
+        }
+        config.routes.extend(self.routes.iter().cloned());
+        for (topic, spec) in &self.topics {
+            if spec.retained > 0 {
+                let policy = RetentionPolicy {
+                    max_messages: spec.retained,
+                    max_age: None,
+                };
+                config.retention.push((topic.clone(), policy));
+            }
+            if let Some(limit) = spec.max_message_size {
+                config.max_message_size.push((topic.clone(), limit));
+            }
+            if let Some(policy) = spec.oversize {
+                config.oversize.push((topic.clone(), policy));
+            }
+            match &spec.validation {
+                Validation::Signed { org } => config.trusted_orgs.push((topic.clone(), *org)),
+                Validation::Owned { owner } => config.topic_owners.push((topic.clone(), *owner)),
+                _ => {}
+            }
+            if let Some(gate) = &spec.mesh {
+                config.mesh_gates.push((topic.clone(), gate.clone()));
+            }
+            if let Some(priority) = spec.priority {
+                config.priorities.push((topic.clone(), priority));
+            }
+            if spec.required {
+                config.readiness.required_topics.push(topic.clone());
+            }
+        }
+    }
+
+    /// Install the validator plugins of the declared topics and subscribe to every one of them,
+    /// running their handlers on tasks of their own. Returns the subscriptions of the topics
+    /// without a handler.
+    pub fn start(&self, client: &Client) -> Result<Vec<Subscription>, Error> {
+        let mut unhandled = Vec::new();
+        for (topic, spec) in &self.topics {
+            #[cfg(feature = "wasm")]
+            {
+                if let Validation::Plugin { path } = &spec.validation {
+                    let plugin = Plugin::new(&std::fs::read(path)?, Limits::default())?;
+                    client.install_plugin(&format!("manifest:{}", topic), topic, plugin)?;
+                }
+            }
+            let subscription = if TopicFilter::new(topic)?.is_wildcard() {
+                client.subscribe_filter(topic)?
+            } else {
+                client.subscribe(topic)?
+            };
+            match &spec.handler {
+                Some(handler) => {
+                    task::spawn(run_handler(handler.clone(), subscription));
+                }
+                None => unhandled.push(subscription),
+            }
+        }
+        Ok(unhandled)
+    }
+}
+
+/// Run `handler` for every message of `subscription`, one at a time.
+async fn run_handler(handler: Vec<String>, mut subscription: Subscription) {
+    while let Some(message) = subscription.next().await {
+        let handler = handler.clone();
+        let (topic, id) = (message.topic.clone(), message.id.clone());
+        if let Err(e) = task::spawn_blocking(move || handle(&handler, message)).await {
+            log::warn!("handler of {} failed on message {}: {}", topic, id, e);
+        }
+    }
+}
+
+fn handle(handler: &[String], message: Message) -> Result<(), Error> {
+    let mut child = process::Command::new(&handler[0])
+        .args(&handler[1..])
+        .env("PUBSUB_TOPIC", &message.topic)
+        .env("PUBSUB_SOURCE", message.source.to_base58())
+        .env("PUBSUB_MESSAGE_ID", message.id.to_string())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&message.data)?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("exited with {}", status).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! Message ids: how nodes tell messages apart, and drop the copies they receive again.
+//!
+//! Gossipsub names every message with the id computed by
+//! [`NodeConfig::gossipsub`](crate::NodeConfig::gossipsub)`.message_id_fn`, and a node delivers
+//! and forwards only the first message of an id it receives within its
+//! [duplicate cache](crate::manifest::Caches::duplicates). The function is a callback of the
+//! library, any closure of a [`GossipsubMessage`] will do:
+//!
+//! ```ignore
+//! config.gossipsub.message_id_fn = Arc::new(|message: &GossipsubMessage| {
+//!     MessageId(format!("{}/{}", message.source, dedup_key(&message.data)))
+//! });
+//! ```
+//!
+//! Two are built in, selected by name with the `message_ids` of a [manifest](crate::manifest),
+//! or set with [`MessageIds::apply`]:
+//!
+//! - `publisher`, the default, names a message by the peer id of its publisher and the sequence
+//!   number the publisher gave it, so that every publish is a message of its own;
+//! - `content` names a message by the SHA-256 hash of its topics and payload, see
+//!   [`content_addressed`], so that identical payloads published by several peers, such as
+//!   replicas relaying the same event, are delivered once.
+//!
+//! The payload hashed is the one sent, after the envelopes of the node: payloads carrying
+//! timestamps, sequence numbers, access tokens, trace contexts or signatures of their publisher,
+//! or sealed with a random nonce, are never identical. Every node of a mesh must name messages
+//! alike, or the ids gossiped by one mean nothing to the others.
+//!
+//! The id of a message a node publishes is only known once the node hands it to gossipsub, after
+//! its [pacing](crate::pacing) and [upload limit](crate::priority) let it through:
+//! [`Client::publish_with_id`](crate::Client::publish_with_id) waits for it. Messages batched
+//! together share the id of their batch, and a message split in chunks gets the id of its last
+//! chunk.
+
+use crate::{Error, PubSubError};
+use data_encoding::HEXLOWER;
+use futures::{channel::oneshot, prelude::*};
+use libp2p::gossipsub::{protocol::MessageId, GossipsubConfig, GossipsubMessage};
+use ring::digest;
+use serde::Deserialize;
+use std::{
+    fmt,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// A built-in message id function, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageIds {
+    Publisher,
+    Content,
+}
+
+impl MessageIds {
+    /// Set the message id function on `config`.
+    pub fn apply(self, config: &mut GossipsubConfig) {
+        config.message_id_fn = match self {
+            MessageIds::Publisher => Arc::new(by_publisher),
+            MessageIds::Content => Arc::new(content_addressed),
+        };
+    }
+}
+
+impl FromStr for MessageIds {
+    type Err = Error;
+
+    fn from_str(message_ids: &str) -> Result<Self, Error> {
+        match message_ids {
+            "publisher" => Ok(MessageIds::Publisher),
+            "content" => Ok(MessageIds::Content),
+            _ => Err(format!("unknown message ids {}", message_ids).into()),
+        }
+    }
+}
+
+/// The id of `message` by its publisher: its source, then its sequence number.
+pub fn by_publisher(message: &GossipsubMessage) -> MessageId {
+    MessageId(format!(
+        "{}{}",
+        message.source.to_base58(),
+        message.sequence_number
+    ))
+}
+
+/// The id of `message` by its content: the SHA-256 hash of its topics, each followed by a NUL
+/// byte, and its payload, in lowercase hex.
+pub fn content_addressed(message: &GossipsubMessage) -> MessageId {
+    let mut context = digest::Context::new(&digest::SHA256);
+    for topic in &message.topics {
+        context.update(topic.as_str().as_bytes());
+        context.update(&[0]);
+    }
+    context.update(&message.data);
+    MessageId(HEXLOWER.encode(context.finish().as_ref()))
+}
+
+/// The id of a message published with
+/// [`Client::publish_with_id`](crate::Client::publish_with_id), resolved once the node hands
+/// the message to gossipsub, or with a [`PubSubError::Publish`] if the node dropped it first.
+pub struct Published {
+    topic: String,
+    id: oneshot::Receiver<Result<MessageId, String>>,
+}
+
+impl Future for Published {
+    type Output = Result<MessageId, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let result = match futures::ready!(self.id.poll_unpin(cx)) {
+            Ok(Ok(id)) => Ok(id),
+            Ok(Err(cause)) => Err(PubSubError::publish(self.topic.as_str(), cause)),
+            Err(oneshot::Canceled) => Err(PubSubError::publish(
+                self.topic.as_str(),
+                "dropped before being sent",
+            )),
+        };
+        Poll::Ready(result)
+    }
+}
+
+/// The publishers waiting for the id of a message; dropping them tells them it was dropped.
+#[derive(Default)]
+pub(crate) struct Receipts(Vec<oneshot::Sender<Result<MessageId, String>>>);
+
+impl Receipts {
+    /// The receipt of a message published on `topic`, and the future its publisher waits on.
+    pub fn new(topic: &str) -> (Self, Published) {
+        let (sender, id) = oneshot::channel();
+        let published = Published {
+            topic: topic.to_owned(),
+            id,
+        };
+        (Receipts(vec![sender]), published)
+    }
+
+    /// Wait for the id of the message of `other` too, sent as part of this one.
+    pub fn append(&mut self, mut other: Receipts) {
+        self.0.append(&mut other.0);
+    }
+
+    /// Tell the publishers the id their message was sent with.
+    pub fn send(self, id: &MessageId) {
+        for sender in self.0 {
+            let _ = sender.send(Ok(id.clone()));
+        }
+    }
+
+    /// Tell the publishers their message was not sent, for `cause`.
+    pub fn fail(self, cause: &dyn fmt::Display) {
+        for sender in self.0 {
+            let _ = sender.send(Err(cause.to_string()));
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Migrating a topic to a new name without losing the messages of publishers that still use the
+//! old one, for evolving topic naming schemes in production.
+//!
+//! A node runs a migration for as long as it holds the [`Migration`] returned by
+//! [`Client::migrate_topic`](crate::Client::migrate_topic): for the
+//! [`overlap`](MigrationConfig::overlap) window, it subscribes to both the old topic and the new
+//! one and republishes every message received on the old topic on the new one, its headers
+//! included, so that subscribers can move to the new topic right away while publishers move over
+//! at their own pace. The migration reports [`MigrationEvent::Ceased`] once no message came on
+//! the old topic for [`quiet_after`](MigrationConfig::quiet_after), telling that the last
+//! publisher has moved and the old topic can be retired, and ends with
+//! [`MigrationEvent::Ended`] when the window is over.
+//!
+//! Republished messages carry a [`MIGRATED_FROM`] header naming the old topic. Messages carrying
+//! that header are never republished again, nor count as traffic on the old topic, so that
+//! migrations run the other way round, or chained, do not loop. Payloads already received on
+//! the new topic within the [`loop_window`](MigrationConfig::loop_window), from publishers
+//! writing to both topics during the move, are not republished either, as with the
+//! [`LoopGuard`] of bridges.
+//!
+//! The republished messages are published by the migrating node under its own identity, subject
+//! to the size limits and message types of the new topic. One node per topic is enough to run a
+//! migration: every node running it republishes every message once more. The messages a node
+//! publishes on the old topic itself are only republished with
+//! [`local_delivery`](crate::NodeConfig::local_delivery).
+
+use crate::{
+    bridge::LoopGuard,
+    topic::{self, TopicFilter},
+    Client, Error, Message, Subscription,
+};
+use async_std::{stream, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select,
+};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Header naming the topic a republished message was migrated from.
+pub const MIGRATED_FROM: &str = "migrated-from";
+
+/// How often a migration checks whether the old topic fell quiet, or the window is over.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A migration of one topic to another, see the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct MigrationConfig {
+    /// Topic migrated from.
+    pub from: String,
+    /// Topic migrated to.
+    pub to: String,
+    /// How long the node republishes the messages of the old topic.
+    pub overlap: Duration,
+    /// How long the old topic must be without messages for its traffic to have ceased.
+    pub quiet_after: Duration,
+    /// Payloads received on the new topic within this window are not republished.
+    pub loop_window: Duration,
+}
+
+impl MigrationConfig {
+    /// Migrate `from` to `to` for a day, the traffic of `from` ceasing after 10 minutes without
+    /// messages.
+    pub fn new(from: &str, to: &str) -> Self {
+        MigrationConfig {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            overlap: Duration::from_secs(24 * 3600),
+            quiet_after: Duration::from_secs(600),
+            loop_window: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Change in a migration, reported by [`Migration`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrationEvent {
+    /// No message came on the old topic for [`quiet_after`](MigrationConfig::quiet_after).
+    Ceased {
+        /// Messages republished so far.
+        republished: u64,
+    },
+    /// Messages came on the old topic again after it had ceased.
+    Resumed,
+    /// The overlap window is over: the node no longer republishes the messages of the old
+    /// topic, and left both topics. The stream ends after this event.
+    Ended {
+        republished: u64,
+        /// Whether the traffic on the old topic had ceased.
+        ceased: bool,
+    },
+}
+
+/// Progress of a migration, see [`Migration::status`].
+#[derive(Clone, Debug, Default)]
+pub struct MigrationStatus {
+    /// Messages of the old topic republished on the new one.
+    pub republished: u64,
+    /// Messages of the old topic not republished: migrated there, already on the new topic, or
+    /// failing the checks of the new topic.
+    pub skipped: u64,
+    /// When the last message not migrated there came on the old topic.
+    pub last_message: Option<Instant>,
+    /// Whether the traffic on the old topic has ceased.
+    pub ceased: bool,
+    /// Whether the overlap window is over.
+    pub ended: bool,
+}
+
+/// Stream of the changes in a migration, returned by
+/// [`Client::migrate_topic`](crate::Client::migrate_topic). The node runs the migration until
+/// its window is over or the stream is dropped.
+pub struct Migration {
+    receiver: mpsc::UnboundedReceiver<MigrationEvent>,
+    status: Arc<Mutex<MigrationStatus>>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Migration {
+    /// Progress of the migration so far.
+    pub fn status(&self) -> MigrationStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+impl Stream for Migration {
+    type Item = MigrationEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MigrationEvent>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Migration {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// This node migrating a topic.
+struct Migrator {
+    client: Client,
+    config: MigrationConfig,
+    old: Subscription,
+    new: Subscription,
+    guard: LoopGuard,
+    status: Arc<Mutex<MigrationStatus>>,
+    events: mpsc::UnboundedSender<MigrationEvent>,
+}
+
+impl Migrator {
+    /// Key of the migration for the [`LoopGuard`], the same for both topics.
+    fn route(&self) -> String {
+        format!("{} {}", self.config.from, self.config.to)
+    }
+
+    fn receive_old(&mut self, message: Message) {
+        if message.headers.contains_key(MIGRATED_FROM) {
+            self.status.lock().unwrap().skipped += 1;
+            return;
+        }
+        let resumed = {
+            let mut status = self.status.lock().unwrap();
+            status.last_message = Some(Instant::now());
+            std::mem::replace(&mut status.ceased, false)
+        };
+        if resumed {
+            log::info!("traffic on {} resumed", self.config.from);
+            let _ = self.events.unbounded_send(MigrationEvent::Resumed);
+        }
+        let republished = self.guard.admit(&self.route(), &message.data) && {
+            let mut headers = message.headers;
+            headers.insert(MIGRATED_FROM.to_owned(), self.config.from.clone());
+            match self
+                .client
+                .publish_with_headers(&self.config.to, message.data, &headers)
+            {
+                Ok(()) => true,
+                Err(e) => {
+                    log::debug!(
+                        "dropping a message migrated from {}: {}",
+                        self.config.from,
+                        e
+                    );
+                    false
+                }
+            }
+        };
+        let mut status = self.status.lock().unwrap();
+        if republished {
+            status.republished += 1;
+        } else {
+            status.skipped += 1;
+        }
+    }
+
+    fn receive_new(&mut self, message: Message) {
+        // Only to recognize the payloads publishers write to both topics.
+        if !message.headers.contains_key(MIGRATED_FROM) {
+            let route = self.route();
+            self.guard.admit(&route, &message.data);
+        }
+    }
+
+    /// Report the old topic quiet if it is. Returns whether the window is over.
+    fn check(&mut self, started: Instant) -> bool {
+        let mut status = self.status.lock().unwrap();
+        let since = status.last_message.unwrap_or(started);
+        if !status.ceased && since.elapsed() >= self.config.quiet_after {
+            status.ceased = true;
+            log::info!("traffic on {} ceased", self.config.from);
+            let _ = self.events.unbounded_send(MigrationEvent::Ceased {
+                republished: status.republished,
+            });
+        }
+        if started.elapsed() < self.config.overlap {
+            return false;
+        }
+        status.ended = true;
+        log::info!(
+            "migration of {} to {} ended, {} messages republished",
+            self.config.from,
+            self.config.to,
+            status.republished
+        );
+        let _ = self.events.unbounded_send(MigrationEvent::Ended {
+            republished: status.republished,
+            ceased: status.ceased,
+        });
+        true
+    }
+
+    /// Republish the messages of the old topic until the window is over or `stop` fires.
+    async fn run(mut self, stop: oneshot::Receiver<()>) {
+        let started = Instant::now();
+        let mut checks = stream::interval(CHECK_INTERVAL.min(self.config.quiet_after));
+        let mut stop = stop.fuse();
+        loop {
+            select! {
+                message = self.old.next().fuse() => match message {
+                    Some(message) => self.receive_old(message),
+                    None => return,
+                },
+                message = self.new.next().fuse() => match message {
+                    Some(message) => self.receive_new(message),
+                    None => return,
+                },
+                _ = checks.next().fuse() => if self.check(started) {
+                    return;
+                },
+                _ = stop => return,
+            }
+        }
+    }
+}
+
+/// Start migrating `config.from` to `config.to` on the node of `client`.
+pub(crate) fn start(client: &Client, config: MigrationConfig) -> Result<Migration, Error> {
+    for name in &[&config.from, &config.to] {
+        if TopicFilter::new(name)?.is_wildcard() || topic::is_internal(name) {
+            return Err(format!("cannot migrate topic {}", name).into());
+        }
+    }
+    if config.from == config.to {
+        return Err(format!("cannot migrate {} to itself", config.from).into());
+    }
+    let (events, receiver) = mpsc::unbounded();
+    let (stop, stopped) = oneshot::channel();
+    let status = Arc::new(Mutex::new(MigrationStatus::default()));
+    log::info!(
+        "migrating {} to {} for {:?}",
+        config.from,
+        config.to,
+        config.overlap
+    );
+    let migrator = Migrator {
+        client: client.clone(),
+        old: client.subscribe(&config.from)?,
+        new: client.subscribe(&config.to)?,
+        guard: LoopGuard::new(config.loop_window),
+        status: status.clone(),
+        events,
+        config,
+    };
+    task::spawn(migrator.run(stopped));
+    Ok(Migration {
+        receiver,
+        status,
+        stop: Some(stop),
+    })
+}
@@ -0,0 +1,3330 @@
+use crate::acl::{self, Access, AccessToken};
+use crate::attestation::{Attester, SignatureStatus};
+use crate::autonat::{AutoNat, AutoNatEvent, ReachabilityStatus, AUTONAT_TOPIC};
+use crate::bandwidth::{Metered, RateLimit, Traffic};
+use crate::bridge::Health;
+use crate::broker;
+use crate::chaos::{Chaos, ChaosConfig};
+use crate::chunking::{self, Reassembler};
+use crate::client::{BridgeAlert, ChangeEvent, Client, Message, NodeEvent, ProtocolEvent};
+use crate::clock::{self, Clock, ClockConfig, CLOCK_TOPIC};
+use crate::codec::{self, decode};
+use crate::compat;
+use crate::compression::CompressionPolicy;
+use crate::crypto::TopicKey;
+use crate::debug::{CacheUsage, ConnectionDump, ConnectionTracker, DebugDump, TopicDump};
+use crate::delegation::{self, Delegation, IdentityKey, PublicKey, Signer};
+use crate::direct::{Direct, Inbox};
+use crate::directory::{Announcement, Directory, TopicListing, DIRECTORY_TOPIC};
+use crate::floodsub::{self, Router, Twins, FLOODSUB_PROTOCOL};
+use crate::flow::{self, Bounds, Overflow, TrySend};
+use crate::gating::{MeshGate, AGENT_VERSION};
+use crate::headers::{self, HeaderFilter, Headers};
+use crate::health::{HealthReport, Readiness, TopicHealth};
+use crate::liveness::{Eviction, EvictionPolicy, Liveness, PeerLiveness};
+use crate::message_id::Receipts;
+use crate::offload::{self, OffloadConfig, Offloaded, Offloader};
+use crate::pacing::{Pacer, PacingPolicy};
+use crate::peer_exchange::{PeerExchange, PeerExchangeConfig, PEER_RECORD_TOPIC};
+use crate::peering::Peering;
+use crate::peerstore::PeerStore;
+use crate::pipeline::Pipeline;
+#[cfg(feature = "wasm")]
+use crate::plugin::{self, Hook, InstalledPlugin, Plugin};
+use crate::presence::Activity;
+use crate::priority::{Priority, Scheduler, UploadLimit};
+use crate::relay::{self, RelayServerConfig};
+use crate::replay::{self, ReplayGuard, ReplayPolicy};
+use crate::retention::{Replay, Retained, RetentionPolicy};
+use crate::rotation::{Continuity, ContinuityRecord, CONTINUITY_TOPIC};
+use crate::routing::{Route, Routed, Routes};
+use crate::schema;
+use crate::sequence::{
+    self, Received, ResendRequest, SequencePolicy, Sequencer, Sequences, RESEND_TOPIC,
+};
+use crate::size::{OversizePolicy, SizeLimits};
+use crate::stats::{
+    LocalDelivery, MemoryStats, MeshInfo, MeshPeer, MeshRole, PeerStats, RecentMessages,
+    SequenceStats, SizeStats, Stats, SubscriptionStats, TopicMesh, TopicStats, ValidationStats,
+};
+use crate::storage::Storage;
+use crate::topic::{self, TopicFilter, ANNOUNCE_TOPIC};
+use crate::topology::{self, Component, ComponentKind, ComponentStatus, Registration, Topology};
+use crate::trace::{TraceConfig, TraceEvent, Tracer};
+use crate::trace_context::{self, TraceContext};
+use crate::transport::build_transport;
+use crate::validation::{Job, Policies, Pool, Validated, ValidationConfig, Validations};
+use crate::{Error, PubSubError};
+use async_std::{stream, task};
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use libp2p::{
+    core::ConnectedPoint,
+    floodsub::{Floodsub, FloodsubEvent, Topic as FloodsubTopic},
+    gossipsub::{
+        protocol::MessageId, Gossipsub, GossipsubConfig, GossipsubConfigBuilder, GossipsubEvent,
+        GossipsubMessage, MessageAcceptance, MessageIdFn, Topic, TopicHash,
+    },
+    identify::{Identify, IdentifyEvent},
+    identity,
+    ping::{self, Ping, PingConfig, PingEvent},
+    pnet::PreSharedKey,
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        NetworkBehaviourEventProcess, PollParameters, SwarmEvent,
+    },
+    Multiaddr, NetworkBehaviour, PeerId, Swarm,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use void::Void;
+
+/// How often the health of bridges is checked for alerts, and the subscriptions of gone
+/// subscribers are dropped.
+pub(crate) const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything needed to start a node.
+pub struct NodeConfig {
+    /// Identity of the node.
+    pub keypair: identity::Keypair,
+    /// Holder of the Ed25519 private key of the identity of the node, used instead of `keypair`
+    /// so that the key stays off the disk of the node, see the
+    /// [`delegation`](crate::delegation) module. Secio and direct messages need the private key
+    /// in memory: nodes with `legacy_secio` fail to spawn, and others cannot receive direct
+    /// messages.
+    pub identity_signer: Option<Arc<dyn Signer>>,
+    /// Identity the node had before rotating to `keypair`, if it rotated lately. The node then
+    /// announces that it moved from one to the other, see the [`rotation`](crate::rotation)
+    /// module.
+    pub previous_keypair: Option<identity::Keypair>,
+    /// Swarm key of the private network to join, if any.
+    pub psk: Option<PreSharedKey>,
+    /// Whether to offer secio, after noise, to authenticate connections, to reach the peers that
+    /// speak nothing else, such as nodes of earlier releases of this crate. Off by default:
+    /// secio is deprecated, and current libp2p releases no longer speak it. Builds with the
+    /// `minimal` feature speak noise only, and fail to spawn nodes with it on.
+    pub legacy_secio: bool,
+    /// Addresses to listen on, e.g. `/ip4/0.0.0.0/tcp/4001` and `/ip6/::/tcp/4001` for both IPv4
+    /// and IPv6 on a fixed port, or `/ip4/0.0.0.0/tcp/4002/ws` for browsers and proxies speaking
+    /// WebSocket only, with the `websocket` feature.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses announced to peers through identify in place of those listened on and observed,
+    /// if not empty: the public ones of a load balancer or port forwarding in front of the node.
+    pub announce_addrs: Vec<Multiaddr>,
+    /// Addresses dialed once the node has started.
+    pub bootstrap: Vec<Multiaddr>,
+    /// Peers kept in the mesh of every topic they share with the node, as addresses ending with
+    /// `/p2p/<peer id>`, dialed once the node has started and redialed whenever disconnected.
+    /// See the [`peering`](crate::peering) module.
+    pub explicit_peers: Vec<Multiaddr>,
+    /// Relays to listen through, as `/ip4/<relay>/tcp/<port>/p2p-circuit` addresses, so that
+    /// peers that cannot dial this node directly reach it through them. See the
+    /// [`relay`](crate::relay) module.
+    pub relays: Vec<Multiaddr>,
+    /// Relay to run for other nodes, if any.
+    pub relay_server: Option<RelayServerConfig>,
+    /// Peers suggested to the peers this node prunes from meshes, and dialed when it is pruned,
+    /// if any, so that meshes heal without bootstrap nodes. See the
+    /// [`peer_exchange`](crate::peer_exchange) module.
+    pub peer_exchange: Option<PeerExchangeConfig>,
+    /// Estimation of the clock skew of peers and timestamps of the messages of topics, if any,
+    /// for the latency of messages across the mesh to be measured. See the
+    /// [`clock`](crate::clock) module.
+    pub clock: Option<ClockConfig>,
+    /// Whether the messages this node publishes are also handed to its own subscribers, right
+    /// away and besides being sent to the mesh. Gossipsub never delivers them back.
+    pub local_delivery: bool,
+    /// Whether this node only watches the swarm, forwarding messages but never publishing any.
+    /// See the [`observer`](crate::observer) module.
+    pub observer: bool,
+    /// Rules republishing the messages of some topics on another, see the
+    /// [`routing`](crate::routing) module.
+    pub routes: Vec<Route>,
+    /// Gossipsub parameters, best set together with a [`Preset`](crate::preset::Preset). Its
+    /// `message_id_fn` names messages, see the [`message_id`](crate::message_id) module.
+    pub gossipsub: GossipsubConfig,
+    /// Protocols carrying the messages of the topics of the node, gossipsub unless floodsub
+    /// peers must be reached. See the [`floodsub`](crate::floodsub) module.
+    pub router: Router,
+    /// How often the topics this node publishes on are announced for wildcard subscribers.
+    pub topic_announce_interval: Duration,
+    /// Whether this node takes part in the topic directory, announcing the topics it hosts and
+    /// collecting those of its peers. See the [`directory`](crate::directory) module.
+    pub topic_directory: bool,
+    /// Compression of the messages this node publishes, as pairs of topic filter and policy. The
+    /// first matching filter applies; messages on other topics are sent uncompressed.
+    pub compression: Vec<(String, CompressionPolicy)>,
+    /// Keys encrypting the messages of topics end to end, as pairs of topic filter and key. The
+    /// key of the first matching filter applies; messages on other topics are sent in the clear.
+    /// See the [`crypto`](crate::crypto) module.
+    pub encryption: Vec<(String, TopicKey)>,
+    /// Identities this node publishes under, as pairs of topic filter and delegation. Messages
+    /// on a topic are signed with the delegation of the first matching filter; messages on other
+    /// topics are sent unsigned. See the [`delegation`](crate::delegation) module.
+    pub delegation: Vec<(String, Delegation)>,
+    /// Organisations the messages of topics must be published under, as pairs of topic filter
+    /// and organisation key. Messages on a topic are dropped unless signed under the key of the
+    /// first matching filter; messages on other topics are delivered without an origin.
+    pub trusted_orgs: Vec<(String, PublicKey)>,
+    /// Tokens letting this node publish on topics owned by others. Messages on a topic carry
+    /// the first token whose filter matches it; messages on other topics carry none. See the
+    /// [`acl`](crate::acl) module.
+    pub access_tokens: Vec<AccessToken>,
+    /// Owners of topics, as pairs of topic filter and owner key. Messages on a topic are
+    /// rejected, and not forwarded, unless their source holds a token of the owner of the first
+    /// matching filter; messages on other topics are delivered as they come.
+    pub topic_owners: Vec<(String, PublicKey)>,
+    /// Filters of the topics whose messages this node signs with the keypair of its peer
+    /// identity, so that subscribers can verify their source. Messages on other topics are sent
+    /// unsigned. See the [`attestation`](crate::attestation) module.
+    pub attested_topics: Vec<String>,
+    /// Chunking of the messages this node publishes, as pairs of topic filter and chunk size in
+    /// bytes. Messages larger than the chunk size of the first matching filter are split into
+    /// chunks; messages on other topics are sent whole. Applies after compression and
+    /// encryption.
+    pub chunking: Vec<(String, usize)>,
+    /// Offloading of large payloads to IPFS, and fetching of those offloaded by peers, if any.
+    /// See the [`offload`](crate::offload) module.
+    pub offload: Option<OffloadConfig>,
+    /// Peers admitted to the mesh of topics, as pairs of topic filter and gate. The gate of the
+    /// first matching filter applies; the meshes of other topics take any peer. See the
+    /// [`gating`](crate::gating) module.
+    pub mesh_gates: Vec<(String, MeshGate)>,
+    /// How long the chunks of a message may take to arrive before the message is dropped.
+    pub reassembly_timeout: Duration,
+    /// Messages retained for the subscribers joining a topic later, as pairs of topic filter and
+    /// policy. The first matching filter applies; messages on other topics are not retained. See
+    /// the [`retention`](crate::retention) module.
+    pub retention: Vec<(String, RetentionPolicy)>,
+    /// Most retained messages replayed to a subscriber joining a topic, whatever it asks for.
+    pub max_replay: usize,
+    /// Where to keep the retained messages, if not in memory, see the
+    /// [`storage`](crate::storage) module.
+    pub retention_storage: Option<Arc<dyn Storage>>,
+    /// Bytes the payloads the node keeps in memory may take, if limited: those of the messages
+    /// gossipsub keeps to gossip about, for the
+    /// [`history_length`](GossipsubConfig::history_length) of [`gossipsub`](Self::gossipsub),
+    /// and those of the [retained](Self::retention) messages. Past the budget, the oldest
+    /// messages kept for gossip are dropped first, then the oldest retained ones. How many
+    /// message ids gossipsub remembers to drop duplicates is its
+    /// [`duplicate_cache_size`](GossipsubConfig::duplicate_cache_size).
+    pub memory_budget: Option<usize>,
+    /// Limits on the size of payloads, as pairs of topic filter and size in bytes, at most the
+    /// `max_transmit_size` of [`gossipsub`](Self::gossipsub). Payloads larger than the limit of
+    /// the first matching filter are neither published nor delivered, unless the
+    /// [`oversize`](Self::oversize) policy of the topic says otherwise; payloads on other topics
+    /// are only limited by gossipsub. See the [`size`](crate::size) module.
+    pub max_message_size: Vec<(String, usize)>,
+    /// What becomes of payloads published over the [size limit](Self::max_message_size) of their
+    /// topic, as pairs of topic filter and policy. The first matching filter applies; publishing
+    /// larger payloads on other topics fails. See the [`size`](crate::size) module.
+    pub oversize: Vec<(String, OversizePolicy)>,
+    /// Protobuf message types of topics, as pairs of topic filter and fully qualified message
+    /// name. Payloads published on a topic matching a filter must be `google.protobuf.Any`
+    /// messages of the type of the first matching one. See the [`schema`](crate::schema) module.
+    pub message_types: Vec<(String, String)>,
+    /// Batching and rate limiting of the messages this node publishes, as pairs of topic filter
+    /// and policy. The first matching filter applies; messages on other topics are sent right
+    /// away. See the [`pacing`](crate::pacing) module.
+    pub pacing: Vec<(String, PacingPolicy)>,
+    /// Sequence numbering of the messages this node publishes, and recovery of those it misses,
+    /// as pairs of topic filter and policy. The first matching filter applies; messages on other
+    /// topics are not numbered, and their losses go unnoticed. See the
+    /// [`sequence`](crate::sequence) module.
+    pub sequencing: Vec<(String, SequencePolicy)>,
+    /// Replay protection of the messages this node publishes and receives, as pairs of topic
+    /// filter and policy. The first matching filter applies; messages on other topics carry no
+    /// nonce, and their replays are delivered once out of the gossipsub caches. See the
+    /// [`replay`](crate::replay) module.
+    pub replay_protection: Vec<(String, ReplayPolicy)>,
+    /// Priorities of topics, as pairs of topic filter and priority. The first matching filter
+    /// applies; other topics are of normal priority. See the [`priority`](crate::priority)
+    /// module.
+    pub priorities: Vec<(String, Priority)>,
+    /// Budget of the upload of the node, if any, past which the messages it publishes are sent
+    /// by the priority of their topic.
+    pub upload_limit: Option<UploadLimit>,
+    /// Limit on the gossipsub traffic each peer may send, if any. See the
+    /// [`bandwidth`](crate::bandwidth) module.
+    pub rate_limit: Option<RateLimit>,
+    /// Worker pool validating received messages off the task driving the swarm, if any. See the
+    /// [`validation`](crate::validation) module.
+    pub validation: Option<ValidationConfig>,
+    /// When to disconnect peers for their pings, see the [`liveness`](crate::liveness) module.
+    pub eviction: EvictionPolicy,
+    /// What the node needs to report itself ready, see the [`health`](crate::health) module.
+    pub readiness: Readiness,
+    /// JSON file remembering the peers met and their addresses across restarts, if any. See the
+    /// [`peerstore`](crate::peerstore) module.
+    pub peer_store: Option<PathBuf>,
+    /// Tracing of the gossip of the node, if any. See the [`trace`](crate::trace) module.
+    pub trace: Option<TraceConfig>,
+    /// Faults injected into the gossip of the node, for testing only. See the
+    /// [`chaos`](crate::chaos) module.
+    pub chaos: Option<ChaosConfig>,
+    /// Bounds of the queue of every subscription not given its own, see
+    /// [`Client::subscribe_bounded`].
+    pub subscription_bounds: Bounds,
+    /// Whether to run without any networking, delivering the messages published on the node to
+    /// its own subscribers. See the [`broker`](crate::broker) module.
+    pub embedded: bool,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            keypair: identity::Keypair::generate_ed25519(),
+            identity_signer: None,
+            previous_keypair: None,
+            psk: None,
+            legacy_secio: false,
+            listen_addrs: vec!["/ip4/0.0.0.0/tcp/0".parse().unwrap()],
+            announce_addrs: Vec::new(),
+            bootstrap: Vec::new(),
+            explicit_peers: Vec::new(),
+            relays: Vec::new(),
+            relay_server: None,
+            peer_exchange: None,
+            clock: None,
+            local_delivery: true,
+            observer: false,
+            routes: Vec::new(),
+            gossipsub: GossipsubConfigBuilder::default()
+                .max_transmit_size(262144)
+                .build(),
+            router: Router::default(),
+            topic_announce_interval: Duration::from_secs(30),
+            topic_directory: false,
+            compression: Vec::new(),
+            encryption: Vec::new(),
+            delegation: Vec::new(),
+            trusted_orgs: Vec::new(),
+            access_tokens: Vec::new(),
+            topic_owners: Vec::new(),
+            attested_topics: Vec::new(),
+            chunking: Vec::new(),
+            offload: None,
+            mesh_gates: Vec::new(),
+            reassembly_timeout: Duration::from_secs(60),
+            retention: Vec::new(),
+            retention_storage: None,
+            max_replay: 1000,
+            memory_budget: None,
+            max_message_size: Vec::new(),
+            oversize: Vec::new(),
+            message_types: Vec::new(),
+            pacing: Vec::new(),
+            sequencing: Vec::new(),
+            replay_protection: Vec::new(),
+            priorities: Vec::new(),
+            upload_limit: None,
+            rate_limit: None,
+            validation: None,
+            eviction: EvictionPolicy::default(),
+            readiness: Readiness::default(),
+            peer_store: None,
+            trace: None,
+            chaos: None,
+            subscription_bounds: Bounds {
+                capacity: 8192,
+                overflow: Overflow::DropOldest,
+            },
+            embedded: false,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// The key of the identity of the node: that of `identity_signer` if set, else `keypair`.
+    pub fn identity(&self) -> Result<IdentityKey, Error> {
+        match &self.identity_signer {
+            Some(signer) => IdentityKey::from_signer(signer.clone()),
+            None => Ok(self.keypair.clone().into()),
+        }
+    }
+}
+
+/// A local subscription: where to deliver messages, and how to transform them first.
+#[derive(Clone)]
+pub(crate) struct Subscriber {
+    /// Unique among the subscriptions of the node.
+    pub id: u64,
+    /// Topic or topic filter subscribed to.
+    pub topic: String,
+    pub sender: flow::Sender<Message>,
+    pub pipeline: Option<Arc<Pipeline>>,
+    /// Whether only the messages whose source is verified are delivered, see
+    /// [`Client::subscribe_verified`].
+    pub verified_only: bool,
+    /// Predicates the headers of the messages delivered satisfy, see
+    /// [`Client::subscribe_where`].
+    pub headers: Option<HeaderFilter>,
+}
+
+impl Subscriber {
+    /// Whether the subscriber takes `message`, as far as its source and headers tell.
+    pub fn accepts(&self, message: &Message) -> bool {
+        (!self.verified_only || message.verified_source().is_some())
+            && self
+                .headers
+                .as_ref()
+                .is_none_or(|filter| filter.matches(&message.headers))
+    }
+}
+
+/// A stage of the startup of a node that applications can wait for.
+pub(crate) enum Milestone {
+    /// The node listens on an address.
+    Ready,
+    /// At least this many peers are connected and identified, which leaves out the connections
+    /// other nodes open to probe reachability.
+    PeerQuorum(usize),
+    /// The node is subscribed to the topic and has at least this many peers in its mesh.
+    TopicJoined(String, usize),
+}
+
+/// What identify and ping told about a connected peer.
+#[derive(Default)]
+struct PeerInfo {
+    agent_version: Option<String>,
+    liveness: PeerLiveness,
+}
+
+/// Requests sent from a [`Client`] to the swarm task.
+pub(crate) enum Command {
+    /// Publish `data` on `topic`, telling `receipts` the id it is sent with.
+    Publish {
+        topic: String,
+        data: Bytes,
+        trace_context: Option<TraceContext>,
+        headers: Headers,
+        receipts: Receipts,
+    },
+    /// Publish `data` on `topic` to `peers` only.
+    PublishTo {
+        topic: String,
+        peers: Vec<PeerId>,
+        data: Bytes,
+    },
+    /// Subscribe to `topic`, replaying the retained messages `replay` asks for first.
+    Subscribe {
+        topic: String,
+        subscriber: Subscriber,
+        replay: Replay,
+    },
+    SubscribeFilter {
+        filter: TopicFilter,
+        subscriber: Subscriber,
+        replay: Replay,
+    },
+    /// List the topics with local subscribers.
+    Topics { reply: oneshot::Sender<Vec<String>> },
+    /// Tell whether this node is subscribed to `topic` and when it last published on it.
+    TopicActivity {
+        topic: String,
+        reply: oneshot::Sender<Activity>,
+    },
+    /// List the connected peers subscribed to `topic`, or to any topic.
+    Peers {
+        topic: Option<String>,
+        reply: oneshot::Sender<Vec<PeerId>>,
+    },
+    /// List the topics the connected peer `peer` is subscribed to.
+    PeerTopics {
+        peer: PeerId,
+        reply: oneshot::Sender<Vec<String>>,
+    },
+    /// Take a snapshot of the state of the node.
+    Stats { reply: oneshot::Sender<Stats> },
+    /// Take a snapshot of the gossip state of the node.
+    MeshInfo { reply: oneshot::Sender<MeshInfo> },
+    /// Take a snapshot of the swarm state of the node, for debugging.
+    DebugDump { reply: oneshot::Sender<DebugDump> },
+    /// Read the traced gossip events, only those naming `message_id` if given, or `None` if
+    /// gossip is not traced.
+    Trace {
+        message_id: Option<MessageId>,
+        reply: oneshot::Sender<Option<Vec<TraceEvent>>>,
+    },
+    /// List the connected peers subscribed to `topic` or in its fanout, and their role.
+    ListPeers {
+        topic: String,
+        reply: oneshot::Sender<Vec<MeshPeer>>,
+    },
+    /// List the connected peers supporting `protocol`.
+    PeersSupporting {
+        protocol: String,
+        reply: oneshot::Sender<Vec<PeerId>>,
+    },
+    /// Report the connected peers supporting `protocol` to `watcher` as they come and go.
+    WatchProtocol {
+        protocol: String,
+        watcher: mpsc::UnboundedSender<ProtocolEvent>,
+    },
+    /// Report changes in the membership of topics to `watcher`.
+    WatchChanges {
+        watcher: mpsc::UnboundedSender<ChangeEvent>,
+    },
+    /// Report the health of a bridge running on the node.
+    #[cfg_attr(
+        not(any(
+            feature = "mqtt",
+            feature = "multicast",
+            feature = "nats",
+            feature = "kafka"
+        )),
+        allow(dead_code)
+    )]
+    RegisterBridge { health: Health },
+    /// Report the events of the node to `watcher`.
+    WatchEvents { watcher: flow::Sender<NodeEvent> },
+    /// Report alerts about bridges to `watcher`.
+    WatchBridges {
+        watcher: mpsc::UnboundedSender<BridgeAlert>,
+    },
+    /// Reply with `data` sealed to `peers`, see the [`direct`](crate::direct) module.
+    SealDirect {
+        peers: Vec<PeerId>,
+        data: Bytes,
+        reply: oneshot::Sender<Result<Bytes, Error>>,
+    },
+    /// Reply with the keys opening the direct messages sealed to this node.
+    OpenInbox {
+        reply: oneshot::Sender<Result<Inbox, Error>>,
+    },
+    /// Reply with the latest peer id `peer` rotated to, if it did.
+    Successor {
+        peer: PeerId,
+        reply: oneshot::Sender<Option<PeerId>>,
+    },
+    /// List the topics of the topic directory, or `None` if the node does not take part.
+    DiscoverTopics {
+        reply: oneshot::Sender<Option<Vec<TopicListing>>>,
+    },
+    /// Report the continuity records the node learns to `watcher`.
+    WatchRotations {
+        watcher: mpsc::UnboundedSender<ContinuityRecord>,
+    },
+    /// List a component running on the node in its topology.
+    RegisterComponent { registration: Registration },
+    /// Reply once the node has reached `milestone`, or with why it never will.
+    WaitFor {
+        milestone: Milestone,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    /// Report the health of the node, but for the journals of the process.
+    Health {
+        reply: oneshot::Sender<HealthReport>,
+    },
+    /// Tell whether the node is reachable by its peers, and at which addresses.
+    Reachability {
+        reply: oneshot::Sender<ReachabilityStatus>,
+    },
+    /// Describe the components configured on the node.
+    DescribeTopology { reply: oneshot::Sender<Topology> },
+    /// Check the compatibility vectors against the receive path of the node.
+    CheckCompat {
+        reply: oneshot::Sender<compat::Report>,
+    },
+    /// Dial `addr`, logging a failure.
+    Dial { addr: Multiaddr },
+    /// Replace the rate limit of peers, or restore the configured one if `None`.
+    SetRateLimit { rate_limit: Option<RateLimit> },
+    /// Pause or resume gossip, see [`Client::pause_gossip`].
+    PauseGossip { paused: bool },
+    /// Run `plugin` on the messages of topics matching `filter` as `hook` tells, replacing the
+    /// plugin `name`.
+    #[cfg(feature = "wasm")]
+    InstallPlugin {
+        name: String,
+        filter: TopicFilter,
+        hook: Hook,
+        plugin: Arc<Plugin>,
+    },
+    /// Remove the plugin `name`, replying whether it was installed.
+    #[cfg(feature = "wasm")]
+    RemovePlugin {
+        name: String,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Reply with the installed plugins.
+    #[cfg(feature = "wasm")]
+    ListPlugins {
+        reply: oneshot::Sender<Vec<InstalledPlugin>>,
+    },
+}
+
+/// An extra network behaviour run by a node next to its own, such as a custom request-response
+/// protocol or app-specific gossip, plugged in with [`spawn_with_extension`].
+///
+/// Extensions process the events of their behaviours themselves and emit none, as is the case
+/// of a struct deriving `NetworkBehaviour` without an `out_event`. Several extensions are
+/// combined by deriving `NetworkBehaviour` on a struct holding them.
+///
+/// The protocols the handlers of an extension listen on are advertised to other peers through
+/// identify. In turn, [`inject_identified`](Extension::inject_identified) tells the extension
+/// which protocols each connected peer supports, so that layered protocols only start talking
+/// to compatible peers.
+pub trait Extension: NetworkBehaviour<OutEvent = ()> + Send + 'static {
+    /// Called when the connected peer `peer_id` has been identified as supporting `protocols`,
+    /// again whenever it is identified anew.
+    fn inject_identified(&mut self, _peer_id: &PeerId, _protocols: &[String]) {}
+}
+
+impl Extension for NoExtension {}
+
+/// The extension of a node without any.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoExtension;
+
+impl NetworkBehaviour for NoExtension {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = ();
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: PeerId, _: ConnectedPoint) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId, _: ConnectedPoint) {}
+
+    fn inject_node_event(&mut self, _: PeerId, _: Void) {}
+
+    fn poll(
+        &mut self,
+        _: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Void, ()>> {
+        Poll::Pending
+    }
+}
+
+/// The network behaviour of a node, combining gossipsub, floodsub, identify, ping and an
+/// [`Extension`].
+#[derive(NetworkBehaviour)]
+pub struct Behaviour<E: Extension> {
+    pub gossipsub: Metered,
+    /// Idle unless the router of the node includes floodsub.
+    pub floodsub: Floodsub,
+    pub identify: AutoNat,
+    pub ping: Ping,
+    pub extension: E,
+    #[behaviour(ignore)]
+    local_peer_id: PeerId,
+    #[behaviour(ignore)]
+    router: Router,
+    /// Messages received through one router only, when running both.
+    #[behaviour(ignore)]
+    twins: Twins,
+    /// Explicit peers, and when to redial them.
+    #[behaviour(ignore)]
+    peering: Peering,
+    /// Local subscribers, by topic.
+    #[behaviour(ignore)]
+    subscribers: HashMap<TopicHash, Vec<Subscriber>>,
+    /// Local wildcard subscribers.
+    #[behaviour(ignore)]
+    filters: Vec<(TopicFilter, Subscriber)>,
+    /// Topics this node has published on, announced periodically, with when it last did.
+    #[behaviour(ignore)]
+    published: HashMap<String, Instant>,
+    /// Whether published messages are handed to local subscribers.
+    #[behaviour(ignore)]
+    local_delivery: bool,
+    /// Whether this node is an observer, publishing nothing.
+    #[behaviour(ignore)]
+    observer: bool,
+    /// Messages handed to local subscribers by topic, see [`local_delivery`](Self::local_delivery).
+    #[behaviour(ignore)]
+    delivered_locally: HashMap<String, LocalDelivery>,
+    /// Limits on the size of payloads, by topic filter.
+    #[behaviour(ignore)]
+    size_limits: SizeLimits,
+    /// Sizes of the payloads published and received, by topic.
+    #[behaviour(ignore)]
+    sizes: HashMap<String, SizeStats>,
+    /// Messages retained for the subscribers joining later.
+    #[behaviour(ignore)]
+    retained: Retained,
+    /// Bytes the payloads kept for gossip and retention may take, if limited.
+    #[behaviour(ignore)]
+    memory_budget: Option<usize>,
+    /// Messages dropped to stay within the memory budget.
+    #[behaviour(ignore)]
+    evicted: u64,
+    /// The last messages handed to subscribers.
+    #[behaviour(ignore)]
+    recent: RecentMessages,
+    #[behaviour(ignore)]
+    message_id_fn: MessageIdFn,
+    /// Sequence number of the last message delivered locally.
+    #[behaviour(ignore)]
+    next_sequence_number: u64,
+    /// Every topic this node has heard of, locally or through announcements.
+    #[behaviour(ignore)]
+    known_topics: HashSet<String>,
+    /// Whether the node has listened on any address yet.
+    #[behaviour(ignore)]
+    listening: bool,
+    /// Clients waiting for the node to reach a milestone.
+    #[behaviour(ignore)]
+    milestone_waiters: Vec<(Milestone, oneshot::Sender<Result<(), Error>>)>,
+    /// Topics each connected peer is subscribed to.
+    #[behaviour(ignore)]
+    peer_topics: HashMap<PeerId, HashSet<TopicHash>>,
+    /// Protocols each identified connected peer supports.
+    #[behaviour(ignore)]
+    peer_protocols: HashMap<PeerId, Vec<String>>,
+    /// Agent version and ping round-trip times of each connected peer, as far as they are known.
+    #[behaviour(ignore)]
+    peer_info: HashMap<PeerId, PeerInfo>,
+    /// Dials in flight and substreams of the connections, counted by the transport.
+    #[behaviour(ignore)]
+    connections: ConnectionTracker,
+    /// Eviction policy, with the peers banned for their pings.
+    #[behaviour(ignore)]
+    liveness: Liveness,
+    /// What the node needs to report itself ready.
+    #[behaviour(ignore)]
+    readiness: Readiness,
+    /// Address book of the peers met, if kept.
+    #[behaviour(ignore)]
+    peer_store: Option<PeerStore>,
+    /// Watchers of the peers supporting a protocol.
+    #[behaviour(ignore)]
+    protocol_watchers: Vec<(String, mpsc::UnboundedSender<ProtocolEvent>)>,
+    /// Watchers of the changes in the membership of topics.
+    #[behaviour(ignore)]
+    change_watchers: Vec<mpsc::UnboundedSender<ChangeEvent>>,
+    #[behaviour(ignore)]
+    event_watchers: Vec<flow::Sender<NodeEvent>>,
+    /// Compression policies of published messages, by topic filter.
+    #[behaviour(ignore)]
+    compression: Vec<(TopicFilter, CompressionPolicy)>,
+    /// Delegations signing published messages, by topic filter.
+    #[behaviour(ignore)]
+    delegation: Vec<(TopicFilter, Delegation)>,
+    /// Tokens attached to published messages, if this node has some.
+    #[behaviour(ignore)]
+    access: Option<Access>,
+    /// Topics whose published messages are signed with the keypair of this node.
+    #[behaviour(ignore)]
+    attester: Attester,
+    /// Encryption keys, trusted organisations, topic owners and plugins validating received
+    /// messages.
+    #[behaviour(ignore)]
+    policies: Arc<Policies>,
+    /// Whether gossipsub waits for received messages to be validated before forwarding them.
+    #[behaviour(ignore)]
+    manual_propagation: bool,
+    /// Workers validating received messages, if not validated on the swarm task.
+    #[behaviour(ignore)]
+    validator: Option<Pool>,
+    #[behaviour(ignore)]
+    validation_stats: ValidationStats,
+    /// Chunk sizes of published messages, by topic filter.
+    #[behaviour(ignore)]
+    chunking: Vec<(TopicFilter, usize)>,
+    /// Peers admitted to topic meshes, by topic filter.
+    #[behaviour(ignore)]
+    mesh_gates: Vec<(TopicFilter, MeshGate)>,
+    /// Id of the next message this node splits into chunks.
+    #[behaviour(ignore)]
+    next_chunked_id: u64,
+    /// Chunks of the messages being received.
+    #[behaviour(ignore)]
+    reassembler: Reassembler,
+    /// Stores large payloads on IPFS and fetches those of peers.
+    #[behaviour(ignore)]
+    offloader: Option<Offloader>,
+    /// Published messages waiting to be batched or for the rate limit of their topic.
+    #[behaviour(ignore)]
+    pacer: Pacer,
+    /// Messages waiting for the upload limit, by priority.
+    #[behaviour(ignore)]
+    scheduler: Scheduler,
+    /// Numbers of the messages published on sequenced topics.
+    #[behaviour(ignore)]
+    sequencer: Sequencer,
+    /// Numbers of the messages received from every publisher on sequenced topics.
+    #[behaviour(ignore)]
+    sequences: Sequences,
+    /// Messages found lost and recovered by topic, see [`sequences`](Self::sequences).
+    #[behaviour(ignore)]
+    sequence_stats: HashMap<String, SequenceStats>,
+    /// Nonces of the messages published and received on topics protected from replays.
+    #[behaviour(ignore)]
+    replay: ReplayGuard,
+    /// Health of the bridges running on the node.
+    #[behaviour(ignore)]
+    bridges: Vec<Health>,
+    /// Watchers of the alerts about bridges.
+    #[behaviour(ignore)]
+    bridge_watchers: Vec<mpsc::UnboundedSender<BridgeAlert>>,
+    /// The transport of the node, as listed in its topology.
+    #[behaviour(ignore)]
+    transport: Component,
+    /// Components other than bridges and plugins running on the node.
+    #[behaviour(ignore)]
+    components: Vec<Registration>,
+    /// Messages for subscribers with a full `Block` queue, delivered before the swarm is polled
+    /// again.
+    #[behaviour(ignore)]
+    blocked: Vec<(flow::Sender<Message>, Message)>,
+    /// Continuity records learned from rotating peers.
+    #[behaviour(ignore)]
+    continuity: Continuity,
+    /// Encoded continuity record of the last rotation of this node, if it rotated lately.
+    #[behaviour(ignore)]
+    continuity_record: Option<Vec<u8>>,
+    /// Topics hosted by the nodes taking part in the topic directory, if this node does.
+    #[behaviour(ignore)]
+    directory: Option<Directory>,
+    /// Signed peer records of this node and its peers, and the peers to dial, if it exchanges
+    /// peers.
+    #[behaviour(ignore)]
+    peer_exchange: Option<PeerExchange>,
+    /// Readings of the clocks of peers and timestamped topics, if this node estimates skews.
+    #[behaviour(ignore)]
+    clock: Option<Clock>,
+    /// The public keys of the peers this node can send direct messages to.
+    #[behaviour(ignore)]
+    direct: Direct,
+}
+
+impl<E: Extension> Behaviour<E> {
+    fn publish(
+        &mut self,
+        topic: String,
+        data: Bytes,
+        trace_context: Option<TraceContext>,
+        headers: Headers,
+        receipts: Receipts,
+    ) {
+        #[cfg(feature = "wasm")]
+        let data = match self.policies.run_plugins(Hook::publish, &topic, data) {
+            Some(data) => data,
+            None => return receipts.fail(&"dropped by a plugin"),
+        };
+        self.sizes
+            .entry(topic.clone())
+            .or_default()
+            .record(data.len());
+        let local = self.local_delivery.then(|| data.clone());
+        let data = match &self.clock {
+            Some(clock) => clock.stamp(&topic, data),
+            None => data,
+        };
+        let data = match &trace_context {
+            Some(context) => trace_context::attach(context, data),
+            None => data,
+        };
+        let data = match headers::attach(&headers, data) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("dropping a message published on {}: {}", topic, e);
+                return receipts.fail(&e);
+            }
+        };
+        let data = match &self.access {
+            Some(access) => match access.attach(&topic, data) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("dropping a message published on {}: {}", topic, e);
+                    return receipts.fail(&e);
+                }
+            },
+            None => data,
+        };
+        let data = self.replay.attach(&topic, data);
+        let data = match self.attester.attach(&topic, data) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("dropping a message published on {}: {}", topic, e);
+                return receipts.fail(&e);
+            }
+        };
+        let data = self.sequencer.stamp(&topic, data);
+        if let Some((data, receipts)) = self.pacer.push(&topic, data, receipts) {
+            self.send(&topic, data, receipts);
+        }
+        self.release();
+        if self
+            .published
+            .insert(topic.clone(), Instant::now())
+            .is_none()
+        {
+            self.learn_topic(topic.clone());
+            self.announce(vec![topic.clone()]);
+            self.announce_directory(None);
+        }
+        if let Some(data) = local {
+            self.deliver_locally(&topic, data, trace_context, headers);
+        }
+    }
+
+    /// Hand a message this node publishes to its own subscribers, bypassing pacing and the
+    /// network but going through plugins and pipelines like any received message.
+    fn deliver_locally(
+        &mut self,
+        topic: &str,
+        data: Bytes,
+        trace_context: Option<TraceContext>,
+        headers: Headers,
+    ) {
+        let topic_hash = Topic::new(topic.to_owned()).no_hash();
+        if !self.subscribers.contains_key(&topic_hash) {
+            return;
+        }
+        let delivered = self.delivered_locally.entry(topic.to_owned()).or_default();
+        delivered.messages += 1;
+        delivered.bytes += data.len() as u64;
+        self.next_sequence_number = self.next_sequence_number.wrapping_add(1);
+        let mut message = GossipsubMessage {
+            source: self.local_peer_id.clone(),
+            data,
+            sequence_number: self.next_sequence_number,
+            topics: vec![topic_hash],
+            targeted: false,
+        };
+        let id = (self.message_id_fn)(&message);
+        // Sign and check the message as subscribers elsewhere would, to tell its origin.
+        let origin = acl::check_local(&self.policies.topic_owners, self.access.as_ref(), topic)
+            .and_then(|()| {
+                delegation::sign(&self.delegation, topic, std::mem::take(&mut message.data))
+            })
+            .and_then(|data| delegation::verify(&self.policies.trusted_orgs, topic, data));
+        match origin {
+            Ok((origin, data)) => {
+                let deliveries = self.policies.deliveries(&message.topics, &data);
+                let published_at = self
+                    .clock
+                    .as_ref()
+                    .filter(|clock| clock.is_timestamped(topic))
+                    .map(|_| SystemTime::now());
+                let signature = match self.attester.attests(topic) {
+                    true => SignatureStatus::Verified,
+                    false => SignatureStatus::Unsigned,
+                };
+                let message = Message {
+                    id,
+                    source: message.source,
+                    topic: topic.to_owned(),
+                    data,
+                    sequence_number: message.sequence_number,
+                    origin,
+                    signature,
+                    published_at,
+                    trace_context,
+                    headers,
+                };
+                self.dispatch(message, deliveries);
+            }
+            Err(e) => log::debug!("dropping a message published on {}: {}", topic, e),
+        }
+    }
+
+    /// Publish the paced messages whose turn has come, and send the scheduled ones the upload
+    /// limit lets through.
+    fn release(&mut self) {
+        for (topic, data, receipts) in self.pacer.release() {
+            self.send(&topic, data, receipts);
+        }
+        for (topic, data, receipts) in self.scheduler.release(self.gossipsub.bytes_out()) {
+            self.transmit(&topic, data, receipts);
+        }
+    }
+
+    /// Encode a message to publish and hand its payloads to the mesh, or to IPFS, the receipts
+    /// of its publishers going with the last of them.
+    fn send(&mut self, topic: &str, data: Bytes, receipts: Receipts) {
+        if let Some(offloader) = &self.offloader {
+            if !topic::is_internal(topic) && offloader.offloads(topic, data.len()) {
+                let encoded = codec::encode(
+                    &self.compression,
+                    &self.delegation,
+                    &self.policies.encryption,
+                    None,
+                    0,
+                    topic,
+                    data,
+                );
+                match encoded {
+                    Ok(encoded) => with_receipts(encoded, receipts)
+                        .for_each(|(data, receipts)| offloader.store(topic, data, receipts)),
+                    Err(e) => {
+                        receipts.fail(&e);
+                        self.drop_unencodable(topic, e);
+                    }
+                }
+                return;
+            }
+        }
+        let chunks = self.encode(topic, data);
+        for (chunk, receipts) in with_receipts(chunks, receipts) {
+            self.broadcast(topic, chunk, receipts);
+        }
+    }
+
+    /// Publish a payload as sent on the wire to the mesh, once the upload limit lets it through.
+    fn broadcast(&mut self, topic: &str, data: Bytes, receipts: Receipts) {
+        if let Some((data, receipts)) = self.scheduler.push(topic, data, receipts) {
+            self.transmit(topic, data, receipts);
+        }
+    }
+
+    fn transmit(&mut self, topic: &str, data: Bytes, receipts: Receipts) {
+        let internal = topic::is_internal(topic);
+        if self.router.floodsub() && !internal && !self.observer {
+            self.floodsub
+                .publish_any(FloodsubTopic::new(topic.to_owned()), data.to_vec());
+        }
+        if !self.router.gossipsub() && !internal {
+            return receipts.fail(&"sent with floodsub only, whose messages have no id");
+        }
+        match self.gossipsub.publish(&Topic::new(topic.to_owned()), data) {
+            Some(id) => receipts.send(&id),
+            None => receipts.fail(&"dropped by a read-only node"),
+        }
+    }
+
+    /// Publish the pointer to a payload stored on IPFS, or deliver a payload fetched from it.
+    fn offloaded(&mut self, offloaded: Offloaded) {
+        match offloaded {
+            Offloaded::Stored {
+                topic,
+                pointer,
+                receipts,
+            } => self.broadcast(&topic, pointer, receipts),
+            Offloaded::Fetched { id, message } => self.deliver_payload(id, message, None),
+        }
+    }
+
+    /// Publish to some peers only, bypassing pacing. The message is stamped like published ones
+    /// but carries neither headers nor a trace context, and is left out of the sequence of its
+    /// topic, the other subscribers never seeing it.
+    fn publish_to(&mut self, topic: String, peers: Vec<PeerId>, data: Bytes) {
+        #[cfg(feature = "wasm")]
+        let data = match self.policies.run_plugins(Hook::publish, &topic, data) {
+            Some(data) => data,
+            None => return,
+        };
+        self.sizes
+            .entry(topic.clone())
+            .or_default()
+            .record(data.len());
+        let local =
+            (self.local_delivery && peers.contains(&self.local_peer_id)).then(|| data.clone());
+        let data = match &self.clock {
+            Some(clock) => clock.stamp(&topic, data),
+            None => data,
+        };
+        let data = match &self.access {
+            Some(access) => match access.attach(&topic, data) {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("dropping a message published on {}: {}", topic, e);
+                    return;
+                }
+            },
+            None => data,
+        };
+        let data = self.replay.attach(&topic, data);
+        let data = match self.attester.attach(&topic, data) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("dropping a message published on {}: {}", topic, e);
+                return;
+            }
+        };
+        let gossipsub_topic = Topic::new(topic.clone());
+        for chunk in self.encode(&topic, data) {
+            self.gossipsub.publish_to(&gossipsub_topic, &peers, chunk);
+        }
+        if let Some(data) = local {
+            self.deliver_locally(&topic, data, None, Headers::new());
+        }
+    }
+
+    /// Compress, sign, encrypt and split a payload to publish on `topic` as the policies of the
+    /// topic require, returning the payloads of the messages to send. Topics without a chunk size
+    /// of their own split payloads over their size limit if they chunk oversized ones.
+    fn encode(&mut self, topic: &str, data: Bytes) -> Vec<Bytes> {
+        self.next_chunked_id = self.next_chunked_id.wrapping_add(1);
+        let chunk_size = match self.chunking.iter().find(|(f, _)| f.matches(topic)) {
+            Some((_, chunk_size)) => Some(*chunk_size),
+            None => self.size_limits.chunk_size(topic),
+        };
+        codec::encode(
+            &self.compression,
+            &self.delegation,
+            &self.policies.encryption,
+            chunk_size,
+            self.next_chunked_id,
+            topic,
+            data,
+        )
+        .unwrap_or_else(|e| {
+            self.drop_unencodable(topic, e);
+            Vec::new()
+        })
+    }
+
+    /// Report a message to publish on `topic` that could not be encoded, and is dropped.
+    fn drop_unencodable(&mut self, topic: &str, e: Error) {
+        log::warn!("not publishing a message on {}: {}", topic, e);
+        self.notify_event(NodeEvent::Error(Arc::new(PubSubError::publish(topic, e))));
+    }
+
+    fn subscribe(&mut self, topic: String, subscriber: Subscriber) {
+        self.learn_topic(topic.clone());
+        let mut subscribed = false;
+        if self.router.floodsub() {
+            subscribed |= self.floodsub.subscribe(FloodsubTopic::new(topic.clone()));
+        }
+        let topic = Topic::new(topic);
+        if self.router.gossipsub() {
+            subscribed |= self.gossipsub.subscribe(topic.clone());
+        }
+        if subscribed {
+            self.subscription_changed(self.local_peer_id.clone(), topic.no_hash(), true);
+        }
+        self.subscribers
+            .entry(topic.no_hash())
+            .or_default()
+            .push(subscriber);
+        if subscribed {
+            self.announce_directory(None);
+        }
+    }
+
+    /// Hand the retained messages that `replay` asks for on the topics `matches` accepts to a
+    /// new subscriber.
+    fn replay(&mut self, matches: impl Fn(&str) -> bool, replay: Replay, subscriber: &Subscriber) {
+        for message in self.retained.replay(matches, replay) {
+            if !subscriber.accepts(&message) {
+                continue;
+            }
+            let data = match &subscriber.pipeline {
+                Some(pipeline) => match pipeline.apply(&message.data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        log::debug!("pipeline dropped a message on {}: {}", message.topic, e);
+                        continue;
+                    }
+                },
+                None => message.data.clone(),
+            };
+            let message = Message { data, ..message };
+            if self
+                .blocked
+                .iter()
+                .any(|(sender, _)| sender.same_channel(&subscriber.sender))
+            {
+                self.blocked.push((subscriber.sender.clone(), message));
+                continue;
+            }
+            if let TrySend::Full(message) = subscriber.sender.try_send(message) {
+                self.blocked.push((subscriber.sender.clone(), message));
+            }
+        }
+    }
+
+    fn subscribe_filter(&mut self, filter: TopicFilter, subscriber: Subscriber) {
+        let matching: Vec<String> = self
+            .known_topics
+            .iter()
+            .filter(|topic| filter.matches(topic))
+            .cloned()
+            .collect();
+        for topic in matching {
+            self.subscribe(topic, subscriber.clone());
+        }
+        self.filters.push((filter, subscriber));
+    }
+
+    /// Record a topic, subscribing every wildcard subscriber whose filter matches it.
+    fn learn_topic(&mut self, topic: String) {
+        if !self.known_topics.insert(topic.clone()) {
+            return;
+        }
+        self.filters
+            .retain(|(_, subscriber)| !subscriber.sender.is_closed());
+        let subscribers: Vec<_> = self
+            .filters
+            .iter()
+            .filter(|(filter, _)| filter.matches(&topic))
+            .map(|(_, subscriber)| subscriber.clone())
+            .collect();
+        for subscriber in subscribers {
+            self.subscribe(topic.clone(), subscriber);
+        }
+    }
+
+    /// Announce topics on the well-known announce topic.
+    fn announce(&mut self, topics: Vec<String>) {
+        match serde_json::to_vec(&topics) {
+            Ok(data) => {
+                self.gossipsub
+                    .publish(&Topic::new(ANNOUNCE_TOPIC.to_owned()), data);
+            }
+            Err(e) => log::warn!("failed to encode topic announcement: {}", e),
+        }
+    }
+
+    fn announce_published(&mut self) {
+        if !self.published.is_empty() {
+            self.announce(self.published.keys().cloned().collect());
+        }
+    }
+
+    /// The topics this node hosts, as announced in the topic directory.
+    fn hosted(&self, directory: &Directory) -> Announcement {
+        directory.announcement(self.topics(), self.published.iter())
+    }
+
+    /// Announce the topics this node hosts in the topic directory, if it takes part, to `peer`
+    /// only if given.
+    fn announce_directory(&mut self, peer: Option<&PeerId>) {
+        let announcement = match &self.directory {
+            Some(directory) => self.hosted(directory),
+            None => return,
+        };
+        let data = match serde_json::to_vec(&announcement) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("failed to encode directory announcement: {}", e);
+                return;
+            }
+        };
+        let topic = Topic::new(DIRECTORY_TOPIC.to_owned());
+        match peer {
+            Some(peer) => self
+                .gossipsub
+                .publish_to(&topic, std::slice::from_ref(peer), data),
+            None => {
+                self.gossipsub.publish(&topic, data);
+            }
+        }
+    }
+
+    /// The topics of the topic directory, those of this node as they are now included.
+    fn discover_topics(&mut self) -> Option<Vec<TopicListing>> {
+        let announcement = self.hosted(self.directory.as_ref()?);
+        let directory = self.directory.as_mut()?;
+        directory.record(self.local_peer_id.clone(), announcement);
+        Some(directory.listings())
+    }
+
+    /// Sign a new peer record of this node if its addresses changed, and send it to the peers
+    /// keeping records, if it exchanges peers.
+    fn update_peer_record(&mut self, listen_addrs: Vec<Multiaddr>) {
+        let status = self.identify.status(listen_addrs);
+        let addresses = if status.announce_addrs.is_empty() {
+            let mut addresses = status.confirmed_addrs;
+            for addr in status.listen_addrs {
+                if !addresses.contains(&addr) {
+                    addresses.push(addr);
+                }
+            }
+            addresses
+        } else {
+            status.announce_addrs
+        };
+        let record = match &mut self.peer_exchange {
+            Some(peer_exchange) => peer_exchange.update(addresses),
+            None => return,
+        };
+        let peers = self.peers(Some(PEER_RECORD_TOPIC.to_owned()));
+        if let (Some(record), false) = (record, peers.is_empty()) {
+            self.gossipsub
+                .publish_to(&Topic::new(PEER_RECORD_TOPIC.to_owned()), &peers, record);
+        }
+    }
+
+    /// Send the time this node reads to `peers`, if it estimates skews.
+    fn send_clock_reading(&mut self, peers: &[PeerId]) {
+        if let (Some(clock), false) = (&self.clock, peers.is_empty()) {
+            let reading = clock.reading();
+            self.gossipsub
+                .publish_to(&Topic::new(CLOCK_TOPIC.to_owned()), peers, reading);
+        }
+    }
+
+    /// Send the time this node reads to every peer, if readings are due.
+    fn send_clock_readings(&mut self) {
+        if self.clock.as_mut().is_some_and(Clock::due) {
+            let peers = self.peers(Some(CLOCK_TOPIC.to_owned()));
+            self.send_clock_reading(&peers);
+        }
+    }
+
+    /// Send the peer record of this node, if it has one yet, to `peer`.
+    fn send_peer_record(&mut self, peer: &PeerId) {
+        if let Some(record) = self.peer_exchange.as_ref().and_then(PeerExchange::record) {
+            let record = record.to_vec();
+            self.gossipsub.publish_to(
+                &Topic::new(PEER_RECORD_TOPIC.to_owned()),
+                std::slice::from_ref(peer),
+                record,
+            );
+        }
+    }
+
+    /// Keep a peer record heard on [`PEER_RECORD_TOPIC`] to suggest its peer, if it is a later
+    /// record of a connected peer.
+    fn accept_peer_record(&mut self, data: &[u8]) {
+        let peer_exchange = match &mut self.peer_exchange {
+            Some(peer_exchange) => peer_exchange,
+            None => return,
+        };
+        match peer_exchange.accept(data) {
+            Ok(Some(peer)) => {
+                if !self.gossipsub.set_peer_record(&peer, data.to_vec()) {
+                    peer_exchange.forget(&peer);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::debug!("ignoring invalid peer record: {}", e),
+        }
+    }
+
+    /// Announce the rotation of the keypair of this node, if it rotated lately.
+    fn announce_continuity(&mut self) {
+        if let Some(record) = &self.continuity_record {
+            self.gossipsub
+                .publish(&Topic::new(CONTINUITY_TOPIC.to_owned()), record.clone());
+        }
+    }
+
+    fn topics(&self) -> Vec<String> {
+        self.subscribers
+            .iter()
+            .filter(|(_, subscribers)| {
+                subscribers
+                    .iter()
+                    .any(|subscriber| !subscriber.sender.is_closed())
+            })
+            .map(|(topic, _)| topic.as_str().to_owned())
+            .collect()
+    }
+
+    fn peers(&self, topic: Option<String>) -> Vec<PeerId> {
+        let topic = topic.map(|topic| Topic::new(topic).no_hash());
+        self.peer_topics
+            .iter()
+            .filter(|(_, topics)| match &topic {
+                Some(topic) => topics.contains(topic),
+                None => !topics.is_empty(),
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    fn peer_topics(&self, peer: &PeerId) -> Vec<String> {
+        self.peer_topics
+            .get(peer)
+            .into_iter()
+            .flatten()
+            .filter(|topic| !topic::is_internal(topic.as_str()))
+            .map(|topic| topic.as_str().to_owned())
+            .collect()
+    }
+
+    fn stats(&self) -> Stats {
+        let peers = self
+            .gossipsub
+            .peers()
+            .map(|(peer, traffic)| PeerStats {
+                peer: peer.clone(),
+                topics: self.peer_topics(peer),
+                traffic: *traffic,
+                graylisted: self.gossipsub.is_graylisted(peer),
+                explicit: self.peering.contains(peer),
+                rtt_history: self
+                    .peer_info
+                    .get(peer)
+                    .map(|info| info.liveness.history())
+                    .unwrap_or_default(),
+                ping_failures: self
+                    .peer_info
+                    .get(peer)
+                    .map_or(0, |info| info.liveness.failures()),
+                clock_skew: self.clock.as_ref().and_then(|clock| {
+                    clock.skew(peer, self.peer_info.get(peer).map(|info| &info.liveness))
+                }),
+            })
+            .collect();
+        let mut topics: Vec<TopicStats> = self
+            .gossipsub
+            .topics()
+            .map(|(topic, traffic)| TopicStats {
+                topic: topic.as_str().to_owned(),
+                traffic: *traffic,
+                local: self
+                    .delivered_locally
+                    .get(topic.as_str())
+                    .copied()
+                    .unwrap_or_default(),
+                sizes: self.sizes.get(topic.as_str()).copied().unwrap_or_default(),
+                sequence: self
+                    .sequence_stats
+                    .get(topic.as_str())
+                    .copied()
+                    .unwrap_or_default(),
+            })
+            .collect();
+        let untraced = self
+            .delivered_locally
+            .keys()
+            .chain(self.sizes.keys())
+            .chain(self.sequence_stats.keys());
+        for topic in untraced {
+            if !topics.iter().any(|stats| stats.topic == *topic) {
+                topics.push(TopicStats {
+                    topic: topic.clone(),
+                    traffic: Traffic::default(),
+                    local: self
+                        .delivered_locally
+                        .get(topic)
+                        .copied()
+                        .unwrap_or_default(),
+                    sizes: self.sizes.get(topic).copied().unwrap_or_default(),
+                    sequence: self.sequence_stats.get(topic).copied().unwrap_or_default(),
+                });
+            }
+        }
+        let mut subscriptions: Vec<SubscriptionStats> = Vec::new();
+        let subscribers = self
+            .subscribers
+            .values()
+            .flatten()
+            .chain(self.filters.iter().map(|(_, subscriber)| subscriber));
+        for subscriber in subscribers {
+            if subscriber.sender.is_closed() || subscriptions.iter().any(|s| s.id == subscriber.id)
+            {
+                continue;
+            }
+            subscriptions.push(SubscriptionStats {
+                id: subscriber.id,
+                topic: subscriber.topic.clone(),
+                queue: subscriber.sender.status(),
+            });
+        }
+        subscriptions.sort_by_key(|subscription| subscription.id);
+        Stats {
+            peers,
+            topics,
+            subscriptions,
+            bridges: self.bridges.iter().map(Health::stats).collect(),
+            graylistings: self.gossipsub.graylistings(),
+            validation: self.validation_stats,
+            memory: self.memory_stats(),
+            peer_exchange: self
+                .peer_exchange
+                .as_ref()
+                .map(PeerExchange::stats)
+                .unwrap_or_default(),
+            chaos: self.gossipsub.chaos(),
+            priorities: self.scheduler.stats(),
+            recent_messages: self.recent.list(),
+        }
+    }
+
+    fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            budget: self.memory_budget,
+            message_cache: self.gossipsub.message_cache_bytes(),
+            retained: self.retained.bytes(),
+            evicted: self.evicted,
+        }
+    }
+
+    fn debug_dump(&self, peer_id: PeerId, listen_addrs: Vec<Multiaddr>) -> DebugDump {
+        let traffic: HashMap<&PeerId, &Traffic> = self.gossipsub.peers().collect();
+        let mut connections: Vec<ConnectionDump> = self
+            .identify
+            .endpoints()
+            .map(|(peer, endpoint)| {
+                let tracked = self.connections.connection(peer);
+                ConnectionDump {
+                    peer: peer.clone(),
+                    endpoint: endpoint.clone(),
+                    age: tracked.map(|(opened, _, _)| opened.elapsed()),
+                    security: tracked.and_then(|(_, security, _)| security),
+                    multiplexer: "yamux",
+                    protocols: self.peer_protocols.get(peer).cloned().unwrap_or_default(),
+                    agent_version: self
+                        .peer_info
+                        .get(peer)
+                        .and_then(|info| info.agent_version.clone()),
+                    substreams: tracked
+                        .map(|(_, _, substreams)| substreams)
+                        .unwrap_or_default(),
+                    traffic: traffic
+                        .get(peer)
+                        .map(|traffic| **traffic)
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.peer.to_base58());
+        let topics: BTreeMap<&str, &TopicHash> = self
+            .gossipsub
+            .mesh()
+            .keys()
+            .chain(self.gossipsub.fanout().keys())
+            .chain(self.peer_topics.values().flatten())
+            .map(|topic| (topic.as_str(), topic))
+            .collect();
+        let topics = topics
+            .into_iter()
+            .map(|(name, topic)| TopicDump {
+                topic: name.to_owned(),
+                // Gossipsub keeps a mesh, possibly empty, for every topic it is subscribed to.
+                subscribed: self.gossipsub.mesh().contains_key(topic),
+                mesh: self
+                    .gossipsub
+                    .mesh()
+                    .get(topic)
+                    .cloned()
+                    .unwrap_or_default(),
+                fanout: self
+                    .gossipsub
+                    .fanout()
+                    .get(topic)
+                    .cloned()
+                    .unwrap_or_default(),
+                peers: self
+                    .peer_topics
+                    .iter()
+                    .filter(|(_, topics)| topics.contains(topic))
+                    .map(|(peer, _)| peer.clone())
+                    .collect(),
+            })
+            .collect();
+        DebugDump {
+            peer_id,
+            listen_addrs,
+            connections,
+            pending_dials: self.connections.pending_dials(),
+            topics,
+            caches: CacheUsage {
+                memory: self.memory_stats(),
+                duplicate_cache: self.gossipsub.duplicate_cache_len(),
+                reassembly: self.reassembler.buffered(),
+            },
+        }
+    }
+
+    fn mesh_info(&self) -> MeshInfo {
+        let topics: BTreeMap<&str, &TopicHash> = self
+            .gossipsub
+            .mesh()
+            .keys()
+            .chain(self.gossipsub.fanout().keys())
+            .chain(self.peer_topics.values().flatten())
+            .filter(|topic| !topic::is_internal(topic.as_str()))
+            .map(|topic| (topic.as_str(), topic))
+            .collect();
+        MeshInfo {
+            topics: topics
+                .values()
+                .map(|topic| self.topic_mesh(topic))
+                .collect(),
+        }
+    }
+
+    /// The connected peers subscribed to `topic` or in its fanout, and how messages are
+    /// exchanged with each.
+    fn topic_mesh(&self, topic: &TopicHash) -> TopicMesh {
+        let mesh = self.gossipsub.mesh().get(topic);
+        let fanout = self.gossipsub.fanout().get(topic);
+        let subscribed = self
+            .peer_topics
+            .iter()
+            .filter(|(_, topics)| topics.contains(topic))
+            .map(|(peer, _)| peer);
+        let peers: HashSet<&PeerId> = mesh
+            .into_iter()
+            .flatten()
+            .chain(fanout.into_iter().flatten())
+            .chain(subscribed)
+            .collect();
+        let mut peers: Vec<&PeerId> = peers.into_iter().collect();
+        peers.sort_by_cached_key(|peer| peer.to_base58());
+        let peers = peers
+            .into_iter()
+            .map(|peer| {
+                let role = if mesh.is_some_and(|mesh| mesh.contains(peer)) {
+                    MeshRole::Mesh
+                } else if fanout.is_some_and(|fanout| fanout.contains(peer)) {
+                    MeshRole::Fanout
+                } else {
+                    MeshRole::Gossip
+                };
+                let info = self.peer_info.get(peer);
+                MeshPeer {
+                    peer: peer.clone(),
+                    role,
+                    agent_version: info.and_then(|info| info.agent_version.clone()),
+                    rtt: info.and_then(|info| info.liveness.rtt()),
+                }
+            })
+            .collect();
+        TopicMesh {
+            topic: topic.as_str().to_owned(),
+            subscribed: mesh.is_some(),
+            peers,
+        }
+    }
+
+    fn topology(&mut self) -> Topology {
+        self.components.retain(|component| !component.is_orphan());
+        self.bridges.retain(|health| !health.is_orphan());
+        let mut components = vec![self.transport.clone()];
+        components.extend(self.components.iter().map(Registration::component));
+        components.extend(self.bridges.iter().map(Health::component));
+        #[cfg(feature = "wasm")]
+        components.extend(
+            self.policies
+                .plugins
+                .iter()
+                .map(|(name, filter, hook, plugin)| Component {
+                    kind: ComponentKind::Plugin,
+                    name: format!("{} on {} ({})", name, filter, hook),
+                    status: ComponentStatus::Running,
+                    config_hash: plugin.hash().to_owned(),
+                }),
+        );
+        components.sort_by_key(|component| component.kind);
+        Topology { components }
+    }
+
+    /// Check the compatibility vectors against the receive path of the node. The vectors bring
+    /// their own keys, the policies of the node being no business of theirs.
+    fn check_compat(&mut self) -> compat::Report {
+        let reassembler = &mut self.reassembler;
+        compat::check_with(|encryption, trusted_orgs, source, topic, frame| {
+            decode(
+                reassembler,
+                encryption,
+                trusted_orgs,
+                source,
+                &[topic],
+                frame,
+            )
+        })
+    }
+
+    #[cfg(feature = "wasm")]
+    fn install_plugin(
+        &mut self,
+        name: String,
+        filter: TopicFilter,
+        hook: Hook,
+        plugin: Arc<Plugin>,
+    ) {
+        self.remove_plugin(&name);
+        Arc::make_mut(&mut self.policies)
+            .plugins
+            .push((name, filter, hook, plugin));
+    }
+
+    #[cfg(feature = "wasm")]
+    fn remove_plugin(&mut self, name: &str) -> bool {
+        let plugins = &mut Arc::make_mut(&mut self.policies).plugins;
+        let installed = plugins.len();
+        plugins.retain(|(installed, _, _, _)| installed != name);
+        plugins.len() != installed
+    }
+
+    #[cfg(feature = "wasm")]
+    fn list_plugins(&self) -> Vec<InstalledPlugin> {
+        plugin::list(&self.policies.plugins)
+    }
+
+    fn peers_supporting(&self, protocol: &str) -> Vec<PeerId> {
+        self.peer_protocols
+            .iter()
+            .filter(|(_, protocols)| protocols.iter().any(|p| p == protocol))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    fn watch_protocol(&mut self, protocol: String, watcher: mpsc::UnboundedSender<ProtocolEvent>) {
+        for peer in self.peers_supporting(&protocol) {
+            let _ = watcher.unbounded_send(ProtocolEvent::Available(peer));
+        }
+        self.protocol_watchers.push((protocol, watcher));
+    }
+
+    /// Record the protocols `peer` supports now, telling watchers about the protocols it gained
+    /// and lost.
+    fn update_peer_protocols(&mut self, peer: PeerId, protocols: Vec<String>) {
+        let previous = if protocols.is_empty() {
+            self.peer_protocols.remove(&peer)
+        } else {
+            self.peer_protocols.insert(peer.clone(), protocols.clone())
+        };
+        let previous = previous.unwrap_or_default();
+        self.protocol_watchers.retain(|(protocol, watcher)| {
+            let event = match (previous.contains(protocol), protocols.contains(protocol)) {
+                (false, true) => ProtocolEvent::Available(peer.clone()),
+                (true, false) => ProtocolEvent::Unavailable(peer.clone()),
+                _ => return !watcher.is_closed(),
+            };
+            watcher.unbounded_send(event).is_ok()
+        });
+        self.check_milestones();
+    }
+
+    /// Raise the alerts of bridges that crossed their alert threshold or recovered, and forget
+    /// the bridges that have stopped.
+    fn check_bridges(&mut self) {
+        self.bridges.retain(|health| !health.is_orphan());
+        for alert in self.bridges.iter().filter_map(Health::check) {
+            match &alert {
+                BridgeAlert::Disconnected { bridge, since } => {
+                    log::warn!("{} has been disconnected for {:?}", bridge, since)
+                }
+                BridgeAlert::Reconnected { bridge } => log::info!("{} reconnected", bridge),
+            }
+            self.bridge_watchers
+                .retain(|watcher| watcher.unbounded_send(alert.clone()).is_ok());
+        }
+    }
+
+    /// Tell watchers about a change in the membership of topics.
+    fn notify_change(&mut self, event: ChangeEvent) {
+        self.change_watchers
+            .retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+    }
+
+    fn notify_event(&mut self, event: NodeEvent) {
+        self.event_watchers
+            .retain(|watcher| !matches!(watcher.try_send(event.clone()), TrySend::Closed(_)));
+    }
+
+    /// Tell watchers that `peer` subscribed to `topic` or unsubscribed from it, unless it is one
+    /// of the well-known topics every node is in.
+    /// Admit `peer` to the mesh of `topic` or bar it from it, as the gate of the topic says given
+    /// what the peer identified itself as. Peers not identified yet are barred.
+    fn gate(&mut self, peer: &PeerId, topic: &TopicHash) {
+        if topic::is_internal(topic.as_str()) {
+            return;
+        }
+        let gate = match self
+            .mesh_gates
+            .iter()
+            .find(|(filter, _)| filter.matches(topic.as_str()))
+        {
+            Some((_, gate)) => gate,
+            None => return,
+        };
+        let agent_version = self
+            .peer_info
+            .get(peer)
+            .and_then(|info| info.agent_version.as_deref());
+        let admitted = match (agent_version, self.peer_protocols.get(peer)) {
+            (Some(agent_version), Some(protocols)) => gate.admits(agent_version, protocols),
+            _ => false,
+        };
+        if admitted {
+            if self.gossipsub.admit_to_mesh(topic, peer) {
+                log::debug!("admitting {} to the mesh of {}", peer.to_base58(), topic);
+            }
+        } else if self.gossipsub.bar_from_mesh(topic, peer) {
+            log::debug!(
+                "keeping {} ({}) out of the mesh of {}",
+                peer.to_base58(),
+                agent_version.unwrap_or("not identified"),
+                topic
+            );
+        }
+    }
+
+    fn subscription_changed(&mut self, peer: PeerId, topic: TopicHash, subscribed: bool) {
+        if topic::is_internal(topic.as_str()) {
+            return;
+        }
+        let topic = topic.into_string();
+        self.notify_event(if subscribed {
+            NodeEvent::Subscribed {
+                peer: peer.clone(),
+                topic: topic.clone(),
+            }
+        } else {
+            NodeEvent::Unsubscribed {
+                peer: peer.clone(),
+                topic: topic.clone(),
+            }
+        });
+        self.notify_change(ChangeEvent::SubscriptionChanged {
+            peer,
+            topic,
+            subscribed,
+        });
+    }
+
+    fn reached(&self, milestone: &Milestone) -> bool {
+        match milestone {
+            Milestone::Ready => self.listening,
+            Milestone::PeerQuorum(quorum) => self.peer_protocols.len() >= *quorum,
+            Milestone::TopicJoined(topic, peers) => {
+                let topic = Topic::new(topic.clone()).no_hash();
+                self.subscribers.contains_key(&topic)
+                    && self
+                        .gossipsub
+                        .mesh()
+                        .get(&topic)
+                        .is_some_and(|mesh| mesh.len() >= *peers)
+            }
+        }
+    }
+
+    /// The health of the node listening on `listen_addrs`, but for the journals of the process.
+    fn health(&self, listen_addrs: Vec<Multiaddr>) -> HealthReport {
+        let subscribed = self.topics();
+        HealthReport {
+            listening: !listen_addrs.is_empty(),
+            listen_addrs,
+            peers: self.peer_protocols.len(),
+            min_peers: self.readiness.min_peers,
+            topics: self
+                .readiness
+                .required_topics
+                .iter()
+                .map(|topic| TopicHealth {
+                    topic: topic.clone(),
+                    subscribed: subscribed.contains(topic),
+                    mesh_peers: self
+                        .gossipsub
+                        .mesh()
+                        .get(&Topic::new(topic.clone()).no_hash())
+                        .map_or(0, |mesh| mesh.len()),
+                    min_mesh_peers: self.readiness.min_mesh_peers,
+                })
+                .collect(),
+            journals: Vec::new(),
+        }
+    }
+
+    /// Reply to `reply` once the node has reached `milestone`.
+    fn wait_for(&mut self, milestone: Milestone, reply: oneshot::Sender<Result<(), Error>>) {
+        if self.reached(&milestone) {
+            let _ = reply.send(Ok(()));
+        } else if !reply.is_canceled() {
+            self.milestone_waiters.push((milestone, reply));
+        }
+    }
+
+    /// Reply to the clients waiting for milestones the node has reached since.
+    fn check_milestones(&mut self) {
+        for (milestone, reply) in std::mem::take(&mut self.milestone_waiters) {
+            self.wait_for(milestone, reply);
+        }
+    }
+
+    /// Forget what was known of a peer that disconnected.
+    fn disconnected(&mut self, peer: PeerId) {
+        self.peering.disconnected(&peer);
+        if let Some(peer_exchange) = &mut self.peer_exchange {
+            peer_exchange.forget(&peer);
+        }
+        if let Some(clock) = &mut self.clock {
+            clock.forget(&peer);
+        }
+        self.floodsub.remove_node_from_partial_view(&peer);
+        for topic in self.peer_topics.remove(&peer).unwrap_or_default() {
+            self.subscription_changed(peer.clone(), topic, false);
+        }
+        self.peer_info.remove(&peer);
+        self.update_peer_protocols(peer, Vec::new());
+    }
+
+    /// Hand a received message to every local subscriber of its topics, fetching its payload
+    /// first if it was offloaded. `propagation_source` is the peer gossipsub received the
+    /// message from, if it waits for the message to be validated to forward it.
+    fn deliver(
+        &mut self,
+        id: MessageId,
+        message: GossipsubMessage,
+        propagation_source: Option<PeerId>,
+    ) {
+        if !offload::is_pointer(&message.data) {
+            return self.deliver_payload(id, message, propagation_source);
+        }
+        // The pointer is all there is to validate before the payload is fetched.
+        self.report(&id, propagation_source.as_ref(), MessageAcceptance::Accept);
+        match &self.offloader {
+            Some(offloader) => offloader.fetch(id, message),
+            None => log::debug!(
+                "dropping a message from {}: nowhere to fetch offloaded payloads from",
+                message.source
+            ),
+        }
+    }
+
+    /// Reassemble a received message, then have it validated, by the workers of the node if it
+    /// has some. A chunked message is delivered with the id and sequence number of the chunk
+    /// completing it.
+    fn deliver_payload(
+        &mut self,
+        id: MessageId,
+        mut message: GossipsubMessage,
+        propagation_source: Option<PeerId>,
+    ) {
+        let data = std::mem::take(&mut message.data);
+        message.data = match self.reassembler.accept(&message.source, data) {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                // Chunks cannot be validated before the message is whole.
+                self.report(&id, propagation_source.as_ref(), MessageAcceptance::Accept);
+                return;
+            }
+            Err(e) => {
+                log::debug!("dropping a message from {}: {}", message.source, e);
+                self.report(&id, propagation_source.as_ref(), MessageAcceptance::Reject);
+                self.validation_stats.rejected += 1;
+                return;
+            }
+        };
+        let job = Job {
+            id,
+            message,
+            propagation_source,
+            policies: self.policies.clone(),
+        };
+        let validator = match &self.validator {
+            Some(validator) => validator,
+            None => return self.validated(job.run()),
+        };
+        match validator.submit(job) {
+            Ok(()) => self.validation_stats.pending += 1,
+            Err(job) => {
+                log::debug!(
+                    "dropping a message from {}: too many messages to validate",
+                    job.message.source
+                );
+                self.report(
+                    &job.id,
+                    job.propagation_source.as_ref(),
+                    MessageAcceptance::Ignore,
+                );
+                self.validation_stats.dropped += 1;
+            }
+        }
+    }
+
+    /// Tell gossipsub whether to forward a message it received from `propagation_source`, if it
+    /// waits for the message to be validated.
+    fn report(
+        &mut self,
+        id: &MessageId,
+        propagation_source: Option<&PeerId>,
+        acceptance: MessageAcceptance,
+    ) {
+        if let Some(source) = propagation_source {
+            self.gossipsub
+                .report_message_validation_result(id, source, acceptance);
+        }
+    }
+
+    /// Hand a validated message to every local subscriber of its topics, after the pipelines of
+    /// the subscribers, and drop the gossipsub subscription of topics nobody listens to anymore.
+    fn validated(&mut self, validated: Validated) {
+        if self.validator.is_some() {
+            self.validation_stats.pending -= 1;
+        }
+        let mut acceptance = validated.acceptance();
+        let replayed = self.check_replays(&validated);
+        if let Err(e) = replayed {
+            log::debug!(
+                "rejecting a message from {}: {}",
+                validated.message.source,
+                e
+            );
+            acceptance = MessageAcceptance::Reject;
+            self.validation_stats.replayed += 1;
+            if let Some(peer) = &validated.propagation_source {
+                let topic = validated.message.topics.first().map_or("", |t| t.as_str());
+                self.replay.strike(peer, topic);
+            }
+        }
+        self.report(
+            &validated.id,
+            validated.propagation_source.as_ref(),
+            acceptance,
+        );
+        match acceptance {
+            MessageAcceptance::Accept => self.validation_stats.accepted += 1,
+            _ => self.validation_stats.rejected += 1,
+        }
+        let Validated {
+            id,
+            message,
+            outcome,
+            ..
+        } = validated;
+        let (origin, payloads) = match outcome {
+            Ok(_) if replayed.is_err() => return,
+            Ok(outcome) => outcome,
+            Err(e) => {
+                log::debug!("dropping a message from {}: {}", message.source, e);
+                return;
+            }
+        };
+        let topic = message.topics.first().map_or("", |topic| topic.as_str());
+        for payload in payloads {
+            let data = payload.data;
+            if let Some(stamp) = payload.stamp {
+                if !self.track_sequence(&message.source, topic, stamp) {
+                    continue;
+                }
+            }
+            let sizes = self.sizes.entry(topic.to_owned()).or_default();
+            if let Err(e) = self.size_limits.check(topic, data.len()) {
+                log::debug!("dropping a message from {}: {}", message.source, e);
+                sizes.oversized += 1;
+                continue;
+            }
+            sizes.record(data.len());
+            let published_at = payload.published_at.map(|micros| {
+                let skew = self.clock.as_ref().and_then(|clock| {
+                    clock.skew(
+                        &message.source,
+                        self.peer_info
+                            .get(&message.source)
+                            .map(|info| &info.liveness),
+                    )
+                });
+                clock::published_at(micros, skew)
+            });
+            let received = Message {
+                id: id.clone(),
+                source: message.source.clone(),
+                topic: topic.to_owned(),
+                data,
+                sequence_number: message.sequence_number,
+                origin,
+                signature: payload.signature,
+                published_at,
+                trace_context: payload.trace_context,
+                headers: payload.headers,
+            };
+            if !self.event_watchers.is_empty() {
+                for topic in &message.topics {
+                    if topic::is_internal(topic.as_str()) {
+                        continue;
+                    }
+                    self.notify_event(NodeEvent::MessageReceived(Message {
+                        topic: topic.as_str().to_owned(),
+                        ..received.clone()
+                    }));
+                }
+            }
+            self.dispatch(received, payload.deliveries);
+        }
+    }
+
+    /// Record the nonces of the payloads of a valid message on a topic protected from replays,
+    /// under the key that signed it, or its source if unsigned. Fails if one is a replay.
+    fn check_replays(&mut self, validated: &Validated) -> Result<(), replay::Replay> {
+        let (origin, payloads) = match &validated.outcome {
+            Ok(outcome) => outcome,
+            Err(_) => return Ok(()),
+        };
+        let topic = validated
+            .message
+            .topics
+            .first()
+            .map_or("", |topic| topic.as_str());
+        let publisher = match origin {
+            Some(origin) => origin.key.to_string(),
+            None => validated.message.source.to_base58(),
+        };
+        for payload in payloads {
+            self.replay.check(&publisher, topic, payload.nonce)?;
+        }
+        Ok(())
+    }
+
+    /// Record the number of a message from `source` on `topic`, telling watchers of the messages
+    /// it shows lost and asking for them if the policy of the topic says so. Returns whether to
+    /// deliver the message, not received already.
+    fn track_sequence(&mut self, source: &PeerId, topic: &str, stamp: sequence::Stamp) -> bool {
+        let stats = self.sequence_stats.entry(topic.to_owned()).or_default();
+        let missing = match self.sequences.receive(source, topic, stamp) {
+            Received::InOrder => return true,
+            Received::Late => {
+                stats.recovered += 1;
+                return true;
+            }
+            Received::Duplicate => {
+                log::debug!("dropping a message from {} received already", source);
+                return false;
+            }
+            Received::AfterGap(missing) => missing,
+        };
+        stats.lost += missing.end - missing.start;
+        log::debug!(
+            "messages {:?} of {} on {} are missing",
+            missing,
+            source,
+            topic
+        );
+        let request_resend = self
+            .sequencer
+            .policy(topic)
+            .is_some_and(|policy| policy.request_resend);
+        if request_resend {
+            let request = ResendRequest {
+                publisher: source.to_base58(),
+                topic: topic.to_owned(),
+                epoch: stamp.epoch,
+                from: missing.start,
+                to: missing.end,
+            };
+            let request = serde_json::to_vec(&request).expect("resend requests serialize");
+            self.gossipsub.publish_to(
+                &Topic::new(RESEND_TOPIC.to_owned()),
+                std::slice::from_ref(source),
+                request,
+            );
+        }
+        self.notify_event(NodeEvent::GapDetected {
+            peer: source.clone(),
+            topic: topic.to_owned(),
+            missing,
+        });
+        true
+    }
+
+    /// Send `peer` the messages it asks for with `request`, if this node published them and
+    /// still keeps them.
+    fn resend(&mut self, peer: &PeerId, request: ResendRequest) {
+        if request.publisher != self.local_peer_id.to_base58() {
+            return;
+        }
+        let topic = Topic::new(request.topic.clone());
+        for data in self.sequencer.resend(&request) {
+            for chunk in self.encode(&request.topic, data) {
+                self.gossipsub
+                    .publish_to(&topic, std::slice::from_ref(peer), chunk);
+            }
+        }
+    }
+
+    /// Hand one payload of a received message to the local subscribers of the topics of
+    /// `deliveries`, as the plugins of every topic made it.
+    fn dispatch(&mut self, message: Message, deliveries: Vec<(TopicHash, Bytes)>) {
+        for (topic, data) in deliveries {
+            let delivered = Message {
+                topic: topic.as_str().to_owned(),
+                data,
+                ..message.clone()
+            };
+            let subscribers = match self.subscribers.get_mut(&topic) {
+                Some(subscribers) => subscribers,
+                None => continue,
+            };
+            self.retained.record(&delivered);
+            self.recent.record(&delivered);
+            let blocked = &mut self.blocked;
+            subscribers.retain(|subscriber| {
+                if !subscriber.accepts(&delivered) {
+                    return !subscriber.sender.is_closed();
+                }
+                let data = match &subscriber.pipeline {
+                    Some(pipeline) => match pipeline.apply(&delivered.data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            log::debug!("pipeline dropped a message on {}: {}", topic, e);
+                            return !subscriber.sender.is_closed();
+                        }
+                    },
+                    None => delivered.data.clone(),
+                };
+                let message = Message {
+                    data,
+                    ..delivered.clone()
+                };
+                // Keep the messages of a blocked subscriber in order behind those it waits on.
+                if blocked
+                    .iter()
+                    .any(|(sender, _)| sender.same_channel(&subscriber.sender))
+                {
+                    blocked.push((subscriber.sender.clone(), message));
+                    return true;
+                }
+                match subscriber.sender.try_send(message) {
+                    TrySend::Queued(_) => true,
+                    TrySend::Full(message) => {
+                        blocked.push((subscriber.sender.clone(), message));
+                        true
+                    }
+                    TrySend::Closed(_) => false,
+                }
+            });
+            if subscribers.is_empty() {
+                self.abandon(&topic);
+            }
+        }
+        self.enforce_memory_budget();
+    }
+
+    /// Drop the oldest messages kept for gossip, then the oldest retained ones, until the
+    /// payloads the node keeps fit its memory budget.
+    fn enforce_memory_budget(&mut self) {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        let retained = self.retained.bytes();
+        if self.gossipsub.message_cache_bytes() + retained <= budget {
+            return;
+        }
+        let evicted = self
+            .gossipsub
+            .shrink_message_cache(budget.saturating_sub(retained));
+        let cached = self.gossipsub.message_cache_bytes();
+        let evicted = evicted + self.retained.shrink_to(budget.saturating_sub(cached));
+        log::debug!(
+            "dropped {} messages to stay within the memory budget",
+            evicted
+        );
+        self.evicted += evicted as u64;
+    }
+
+    /// Forget the local subscribers that are gone, and drop the gossipsub subscription of topics
+    /// nobody listens to anymore.
+    fn prune_subscribers(&mut self) {
+        self.filters
+            .retain(|(_, subscriber)| !subscriber.sender.is_closed());
+        let mut abandoned = Vec::new();
+        for (topic, subscribers) in &mut self.subscribers {
+            subscribers.retain(|subscriber| !subscriber.sender.is_closed());
+            if subscribers.is_empty() {
+                abandoned.push(topic.clone());
+            }
+        }
+        for topic in &abandoned {
+            self.abandon(topic);
+        }
+    }
+
+    /// Drop the gossipsub subscription of `topic`, which has no local subscriber left.
+    fn abandon(&mut self, topic: &TopicHash) {
+        self.subscribers.remove(topic);
+        let unsubscribed = self
+            .floodsub
+            .unsubscribe(FloodsubTopic::new(topic.as_str().to_owned()));
+        if self
+            .gossipsub
+            .unsubscribe(Topic::new(topic.as_str().to_owned()))
+            || unsubscribed
+        {
+            self.subscription_changed(self.local_peer_id.clone(), topic.clone(), false);
+        }
+    }
+}
+
+/// Pair the payloads of a message with the receipts of its publishers, going with the last one.
+fn with_receipts(
+    payloads: Vec<Bytes>,
+    receipts: Receipts,
+) -> impl Iterator<Item = (Bytes, Receipts)> {
+    let last = payloads.len().saturating_sub(1);
+    let mut receipts = Some(receipts);
+    payloads
+        .into_iter()
+        .enumerate()
+        .map(move |(index, data)| match index == last {
+            true => (data, receipts.take().unwrap_or_default()),
+            false => (data, Receipts::default()),
+        })
+}
+
+impl<E: Extension> NetworkBehaviourEventProcess<GossipsubEvent> for Behaviour<E> {
+    // Called when `gossipsub` produces an event.
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        match event {
+            GossipsubEvent::Message(propagation_source, id, message) => {
+                // Gossipsub has cached the message already, unless it was sent to chosen peers.
+                self.enforce_memory_budget();
+                // Clock readings are only of use straight from the peer that read the time.
+                let direct = propagation_source == message.source;
+                // Gossipsub waits for the messages to be validated when the node has workers
+                // for it or topic owners, except those of the node itself, which it trusts as
+                // they come. Messages sent to chosen peers are not forwarded either way.
+                let propagation_source = match self.manual_propagation {
+                    _ if message.targeted => None,
+                    true if message
+                        .topics
+                        .iter()
+                        .any(|t| topic::is_internal(t.as_str())) =>
+                    {
+                        self.report(&id, Some(&propagation_source), MessageAcceptance::Accept);
+                        None
+                    }
+                    true => Some(propagation_source),
+                    false => None,
+                };
+                if message.topics.iter().any(|t| t.as_str() == ANNOUNCE_TOPIC) {
+                    match serde_json::from_slice::<Vec<String>>(&message.data) {
+                        Ok(topics) => topics.into_iter().for_each(|t| self.learn_topic(t)),
+                        Err(e) => log::debug!("ignoring malformed topic announcement: {}", e),
+                    }
+                }
+                if message.topics.iter().any(|t| t.as_str() == AUTONAT_TOPIC) {
+                    self.identify.inject_probe(&message.source, &message.data);
+                }
+                if message
+                    .topics
+                    .iter()
+                    .any(|t| t.as_str() == CONTINUITY_TOPIC)
+                {
+                    match ContinuityRecord::decode(&message.data) {
+                        Ok(record) => self.continuity.accept(record),
+                        Err(e) => log::debug!("ignoring invalid continuity record: {}", e),
+                    }
+                }
+                if message.topics.iter().any(|t| t.as_str() == DIRECTORY_TOPIC) {
+                    if let Some(directory) = &mut self.directory {
+                        if let Err(e) = directory.accept(message.source.clone(), &message.data) {
+                            log::debug!("ignoring malformed directory announcement: {}", e);
+                        }
+                    }
+                }
+                if message
+                    .topics
+                    .iter()
+                    .any(|t| t.as_str() == PEER_RECORD_TOPIC)
+                {
+                    self.accept_peer_record(&message.data);
+                }
+                if message.topics.iter().any(|t| t.as_str() == CLOCK_TOPIC) {
+                    if let (Some(clock), true) = (&mut self.clock, direct) {
+                        if let Err(e) = clock.accept(&message.source, &message.data) {
+                            log::debug!("ignoring invalid clock reading: {}", e);
+                        }
+                    }
+                }
+                if message.topics.iter().any(|t| t.as_str() == RESEND_TOPIC) {
+                    match serde_json::from_slice::<ResendRequest>(&message.data) {
+                        Ok(request) => self.resend(&message.source, request),
+                        Err(e) => log::debug!("ignoring malformed resend request: {}", e),
+                    }
+                }
+                if self.router == Router::Both && self.twins.is_twin(&message, false) {
+                    self.report(&id, propagation_source.as_ref(), MessageAcceptance::Accept);
+                    return;
+                }
+                self.deliver(id, message, propagation_source);
+            }
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                let topics = self.peer_topics.entry(peer_id.clone()).or_default();
+                if topics.insert(topic.clone()) {
+                    // Tell a peer joining the topic directory what this node hosts right away,
+                    // rather than on the next announcement.
+                    if topic.as_str() == DIRECTORY_TOPIC {
+                        self.announce_directory(Some(&peer_id));
+                    }
+                    if topic.as_str() == PEER_RECORD_TOPIC {
+                        self.send_peer_record(&peer_id);
+                    }
+                    if topic.as_str() == CLOCK_TOPIC {
+                        self.send_clock_reading(std::slice::from_ref(&peer_id));
+                    }
+                    self.gate(&peer_id, &topic);
+                    self.subscription_changed(peer_id, topic, true);
+                }
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                let removed = match self.peer_topics.get_mut(&peer_id) {
+                    Some(topics) => topics.remove(&topic),
+                    None => false,
+                };
+                if removed {
+                    self.subscription_changed(peer_id, topic, false);
+                }
+            }
+            GossipsubEvent::MeshChanged {
+                topic,
+                added,
+                removed,
+            } => {
+                self.check_milestones();
+                if !topic::is_internal(topic.as_str()) {
+                    self.notify_change(ChangeEvent::TopicMeshChanged {
+                        topic: topic.into_string(),
+                        added,
+                        removed,
+                    });
+                }
+            }
+            GossipsubEvent::PeerExchange { peer_id, peers, .. } => {
+                if let Some(peer_exchange) = &mut self.peer_exchange {
+                    log::debug!(
+                        "{} pruned this node, suggesting {} peers",
+                        peer_id.to_base58(),
+                        peers.len()
+                    );
+                    let identify = &self.identify;
+                    peer_exchange.suggest(
+                        peers
+                            .into_iter()
+                            .map(|info| (info.peer_id, info.signed_peer_record)),
+                        |peer| identify.endpoint(peer).is_some(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl<E: Extension> NetworkBehaviourEventProcess<FloodsubEvent> for Behaviour<E> {
+    // Called when `floodsub` produces an event.
+    fn inject_event(&mut self, event: FloodsubEvent) {
+        match event {
+            FloodsubEvent::Message(message) => {
+                let message = floodsub::to_gossipsub(message);
+                if !self.router.floodsub()
+                    || message
+                        .topics
+                        .iter()
+                        .any(|t| topic::is_internal(t.as_str()))
+                    || (self.router == Router::Both && self.twins.is_twin(&message, true))
+                {
+                    return;
+                }
+                let id = (self.message_id_fn)(&message);
+                self.deliver(id, message, None);
+            }
+            FloodsubEvent::Subscribed { peer_id, topic } => {
+                let topic = TopicHash::from_raw(topic.id());
+                let topics = self.peer_topics.entry(peer_id.clone()).or_default();
+                if topics.insert(topic.clone()) {
+                    self.subscription_changed(peer_id, topic, true);
+                }
+            }
+            FloodsubEvent::Unsubscribed { peer_id, topic } => {
+                let topic = TopicHash::from_raw(topic.id());
+                let removed = match self.peer_topics.get_mut(&peer_id) {
+                    Some(topics) => topics.remove(&topic),
+                    None => false,
+                };
+                if removed {
+                    self.subscription_changed(peer_id, topic, false);
+                }
+            }
+        }
+    }
+}
+
+impl<E: Extension> NetworkBehaviourEventProcess<AutoNatEvent> for Behaviour<E> {
+    // Called when `identify` produces an event.
+    fn inject_event(&mut self, event: AutoNatEvent) {
+        match event {
+            AutoNatEvent::Identify(event) => {
+                log::debug!("identify: {:?}", event);
+                if let IdentifyEvent::Received { peer_id, info, .. } = *event {
+                    self.extension.inject_identified(&peer_id, &info.protocols);
+                    self.direct.learn(&peer_id, &info.public_key);
+                    if let Some(store) = &mut self.peer_store {
+                        store.identified(&peer_id, &info.listen_addrs);
+                    }
+                    self.peer_info
+                        .entry(peer_id.clone())
+                        .or_default()
+                        .agent_version = Some(info.agent_version);
+                    if self.router.floodsub()
+                        && info.protocols.iter().any(|p| p == FLOODSUB_PROTOCOL)
+                    {
+                        self.floodsub.add_node_to_partial_view(peer_id.clone());
+                    }
+                    self.update_peer_protocols(peer_id.clone(), info.protocols);
+                    if self
+                        .peer_topics
+                        .get(&peer_id)
+                        .is_some_and(|topics| topics.iter().any(|t| t.as_str() == CLOCK_TOPIC))
+                    {
+                        self.send_clock_reading(std::slice::from_ref(&peer_id));
+                    }
+                    let topics: Vec<TopicHash> = self
+                        .peer_topics
+                        .get(&peer_id)
+                        .into_iter()
+                        .flatten()
+                        .cloned()
+                        .collect();
+                    for topic in topics {
+                        self.gate(&peer_id, &topic);
+                    }
+                }
+            }
+            AutoNatEvent::Send { peer, data } => {
+                self.gossipsub
+                    .publish_to(&Topic::new(AUTONAT_TOPIC.to_owned()), &[peer], data)
+            }
+        }
+    }
+}
+
+impl<E: Extension> NetworkBehaviourEventProcess<PingEvent> for Behaviour<E> {
+    // Called when `ping` produces an event.
+    fn inject_event(&mut self, event: PingEvent) {
+        use ping::handler::{PingFailure, PingSuccess};
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                let info = self.peer_info.entry(event.peer).or_default();
+                info.liveness.succeeded(rtt);
+            }
+            Ok(PingSuccess::Pong) => {}
+            Err(failure) => {
+                match failure {
+                    PingFailure::Timeout => {
+                        log::info!("ping: timeout to {}", event.peer.to_base58())
+                    }
+                    PingFailure::Other { error } => {
+                        log::info!("ping: failure with {}: {}", event.peer.to_base58(), error)
+                    }
+                }
+                let info = self.peer_info.entry(event.peer).or_default();
+                info.liveness.failed();
+            }
+        }
+    }
+}
+
+impl<E: Extension> NetworkBehaviourEventProcess<()> for Behaviour<E> {
+    // Extensions emit no events.
+    fn inject_event(&mut self, _: ()) {}
+}
+
+/// Start a node on a background task and return a [`Client`] to control it.
+pub fn spawn(config: NodeConfig) -> Result<Client, Error> {
+    spawn_with_extension(config, NoExtension)
+}
+
+/// Start a node running `extension` next to its own network behaviour, like [`spawn`]. An
+/// [embedded](NodeConfig::embedded) node, having no network behaviour, does not run it.
+pub fn spawn_with_extension<E: Extension>(
+    mut config: NodeConfig,
+    extension: E,
+) -> Result<Client, Error> {
+    let routes = Routes::new(&config.routes)?;
+    if config.embedded {
+        return broker::spawn(config, routes);
+    }
+    #[cfg(feature = "minimal")]
+    if config.legacy_secio {
+        return Err(PubSubError::config(
+            "legacy_secio needs a build without the `minimal` feature, which speaks noise only",
+        ));
+    }
+    let compression = config
+        .compression
+        .iter()
+        .map(|(filter, policy)| Ok((TopicFilter::new(filter)?, *policy)))
+        .collect::<Result<_, Error>>()?;
+    let encryption = config
+        .encryption
+        .iter()
+        .map(|(filter, key)| Ok((TopicFilter::new(filter)?, key.clone())))
+        .collect::<Result<_, Error>>()?;
+    let delegation = config
+        .delegation
+        .iter()
+        .map(|(filter, delegation)| Ok((TopicFilter::new(filter)?, delegation.clone())))
+        .collect::<Result<_, Error>>()?;
+    let trusted_orgs = config
+        .trusted_orgs
+        .iter()
+        .map(|(filter, org)| Ok((TopicFilter::new(filter)?, *org)))
+        .collect::<Result<_, Error>>()?;
+    let topic_owners: Vec<(TopicFilter, PublicKey)> = config
+        .topic_owners
+        .iter()
+        .map(|(filter, owner)| Ok((TopicFilter::new(filter)?, *owner)))
+        .collect::<Result<_, Error>>()?;
+    let identity = config.identity()?;
+    let access = match config.access_tokens.is_empty() {
+        true => None,
+        false => Some(Access::new(identity.clone(), &config.access_tokens)?),
+    };
+    let attester = Attester::new(identity.clone(), &config.attested_topics)?;
+    let chunking = config
+        .chunking
+        .iter()
+        .map(|(filter, chunk_size)| {
+            if *chunk_size <= chunking::HEADER_LEN {
+                return Err(format!(
+                    "chunk size of {} must exceed {} bytes",
+                    filter,
+                    chunking::HEADER_LEN
+                )
+                .into());
+            }
+            Ok((TopicFilter::new(filter)?, *chunk_size))
+        })
+        .collect::<Result<_, Error>>()?;
+    let mesh_gates = config
+        .mesh_gates
+        .iter()
+        .map(|(filter, gate)| Ok((TopicFilter::new(filter)?, gate.clone())))
+        .collect::<Result<_, Error>>()?;
+    let pacing = config
+        .pacing
+        .iter()
+        .map(|(filter, policy)| {
+            if policy
+                .max_rate
+                .is_some_and(|rate| !(rate > 0.0 && rate.is_finite()))
+            {
+                return Err(format!("maximum rate of {} must be positive", filter).into());
+            }
+            Ok((TopicFilter::new(filter)?, *policy))
+        })
+        .collect::<Result<_, Error>>()?;
+    let (offload_sender, offloaded) = mpsc::unbounded();
+    let offloader = config
+        .offload
+        .clone()
+        .map(|offload| Offloader::new(offload, offload_sender))
+        .transpose()?;
+    if config
+        .upload_limit
+        .is_some_and(|limit| limit.bytes_per_second == 0)
+    {
+        return Err("upload limit must be positive".into());
+    }
+    let priorities = config
+        .priorities
+        .iter()
+        .map(|(filter, priority)| Ok((TopicFilter::new(filter)?, *priority)))
+        .collect::<Result<_, Error>>()?;
+    let replay_protection: Vec<(TopicFilter, ReplayPolicy)> = config
+        .replay_protection
+        .iter()
+        .map(|(filter, policy)| Ok((TopicFilter::new(filter)?, *policy)))
+        .collect::<Result<_, Error>>()?;
+    let sequencing = config
+        .sequencing
+        .iter()
+        .map(|(filter, policy)| Ok((TopicFilter::new(filter)?, *policy)))
+        .collect::<Result<_, Error>>()?;
+    let size_limits = SizeLimits::parse(
+        &config.max_message_size,
+        &config.oversize,
+        config.gossipsub.max_transmit_size,
+    )?;
+    let message_types = schema::parse_types(&config.message_types)?;
+    let retention = config
+        .retention
+        .iter()
+        .map(|(filter, policy)| Ok((TopicFilter::new(filter)?, *policy)))
+        .collect::<Result<_, Error>>()?;
+    // Start chunked message ids from the clock, so that they do not repeat across restarts.
+    let next_chunked_id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64);
+    let local_peer_id = PeerId::from(identity.public());
+    let psk = config.psk.map(|psk| psk.fingerprint().to_string());
+    let transport_component = Component {
+        kind: ComponentKind::Transport,
+        name: format!(
+            "TCP{}/{}/yamux on {}",
+            if psk.is_some() { "/pnet" } else { "" },
+            if config.legacy_secio {
+                "noise+secio"
+            } else {
+                "noise"
+            },
+            config
+                .listen_addrs
+                .iter()
+                .map(Multiaddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        status: ComponentStatus::Running,
+        config_hash: topology::config_hash(&(
+            &config.listen_addrs,
+            &config.announce_addrs,
+            &config.bootstrap,
+            &config.explicit_peers,
+            &config.relays,
+            &psk,
+            config.legacy_secio,
+        )),
+    };
+    let directory = config
+        .topic_directory
+        .then(|| Directory::new(config.topic_announce_interval));
+    let mut continuity = Continuity::default();
+    let continuity_record = match &config.previous_keypair {
+        Some(previous) => {
+            let record = ContinuityRecord::new(previous, &identity)?;
+            let encoded = record.encode()?;
+            continuity.accept(record);
+            Some(encoded)
+        }
+        None => None,
+    };
+    let peering = Peering::new(&config.explicit_peers)?;
+    let peer_store = config.peer_store.map(PeerStore::open).transpose()?;
+    let connections = ConnectionTracker::default();
+    let transport = build_transport(
+        identity.clone(),
+        config.psk,
+        config.legacy_secio,
+        connections.clone(),
+    )?;
+    let (validator, validations) = match &config.validation {
+        Some(validation) => {
+            let (pool, validations) = Pool::start(validation)?;
+            // Messages are forwarded once validated.
+            config.gossipsub.manual_propagation = true;
+            (Some(pool), validations)
+        }
+        None => (None, Box::new(futures::stream::pending()) as Validations),
+    };
+    if !topic_owners.is_empty() {
+        // Messages without a valid token must not be forwarded either.
+        config.gossipsub.manual_propagation = true;
+    }
+    if !replay_protection.is_empty() {
+        // Nor replays, whose forwarders are banned.
+        config.gossipsub.manual_propagation = true;
+    }
+    if config.gossipsub.history_length == 0
+        || config.gossipsub.history_gossip > config.gossipsub.history_length
+    {
+        return Err(PubSubError::config(format!(
+            "history of {} heartbeats cannot be gossiped about for {}",
+            config.gossipsub.history_length, config.gossipsub.history_gossip
+        )));
+    }
+    if config.gossipsub.duplicate_cache_size == 0 {
+        return Err(PubSubError::config(
+            "the duplicate cache must hold at least one message id",
+        ));
+    }
+    let peer_exchange = match config.peer_exchange {
+        Some(peer_exchange) => {
+            config.gossipsub.do_px = true;
+            config.gossipsub.prune_peers = peer_exchange.peers;
+            Some(PeerExchange::new(peer_exchange, identity.clone()))
+        }
+        None => None,
+    };
+    let clock = config.clock.as_ref().map(Clock::new).transpose()?;
+    if config.observer {
+        config.gossipsub.read_only = true;
+    }
+    let message_id_fn = config.gossipsub.message_id_fn.clone();
+    let manual_propagation = config.gossipsub.manual_propagation;
+    let mut gossipsub = Metered::new(
+        Gossipsub::new(local_peer_id.clone(), config.gossipsub),
+        config.rate_limit,
+    );
+    if let Some(trace) = config.trace {
+        gossipsub.set_tracer(Tracer::new(trace, message_id_fn.clone()));
+    }
+    if let Some(chaos) = config.chaos {
+        gossipsub.set_chaos(Chaos::new(chaos)?);
+    }
+    let behaviour = Behaviour {
+        gossipsub,
+        floodsub: Floodsub::new(local_peer_id.clone()),
+        identify: AutoNat::new(
+            Identify::new(
+                "/ipfs/0.1.0".into(),
+                AGENT_VERSION.into(),
+                identity.public(),
+            ),
+            local_peer_id.clone(),
+            config.psk,
+            config.legacy_secio,
+            config.announce_addrs,
+        ),
+        // Failing pings are counted by the behaviour, which disconnects the peer as the
+        // eviction policy says, rather than by the handler.
+        ping: Ping::new(PingConfig::new().with_max_failures(NonZeroU32::new(u32::MAX).unwrap())),
+        extension,
+        local_peer_id: local_peer_id.clone(),
+        router: config.router,
+        twins: Twins::default(),
+        peering,
+        subscribers: HashMap::new(),
+        filters: Vec::new(),
+        published: HashMap::new(),
+        local_delivery: config.local_delivery,
+        observer: config.observer,
+        delivered_locally: HashMap::new(),
+        size_limits: size_limits.clone(),
+        sizes: HashMap::new(),
+        retained: Retained::new(
+            retention,
+            config.max_replay,
+            config.retention_storage.clone(),
+        ),
+        memory_budget: config.memory_budget,
+        evicted: 0,
+        recent: RecentMessages::default(),
+        message_id_fn,
+        next_sequence_number: next_chunked_id,
+        known_topics: HashSet::new(),
+        listening: false,
+        milestone_waiters: Vec::new(),
+        peer_topics: HashMap::new(),
+        peer_protocols: HashMap::new(),
+        peer_info: HashMap::new(),
+        connections,
+        liveness: Liveness::new(config.eviction),
+        readiness: config.readiness,
+        peer_store,
+        protocol_watchers: Vec::new(),
+        change_watchers: Vec::new(),
+        event_watchers: Vec::new(),
+        compression,
+        policies: Arc::new(Policies {
+            encryption,
+            trusted_orgs,
+            topic_owners,
+            #[cfg(feature = "wasm")]
+            plugins: Vec::new(),
+        }),
+        manual_propagation,
+        access,
+        attester,
+        validator,
+        validation_stats: ValidationStats::default(),
+        delegation,
+        chunking,
+        mesh_gates,
+        next_chunked_id,
+        reassembler: Reassembler::new(config.reassembly_timeout),
+        offloader,
+        pacer: Pacer::new(pacing),
+        scheduler: Scheduler::new(priorities, config.upload_limit),
+        sequencer: Sequencer::new(sequencing),
+        sequences: Sequences::default(),
+        sequence_stats: HashMap::new(),
+        replay: ReplayGuard::new(replay_protection),
+        bridges: Vec::new(),
+        bridge_watchers: Vec::new(),
+        transport: transport_component,
+        components: Vec::new(),
+        blocked: Vec::new(),
+        continuity,
+        continuity_record,
+        directory,
+        peer_exchange,
+        clock,
+        direct: Direct::new(identity),
+    };
+    let mut swarm = Swarm::new(transport, behaviour, local_peer_id.clone());
+    // Join the well-known topics before dialing anyone, so that peers learn about them on
+    // connect.
+    for topic in &[
+        ANNOUNCE_TOPIC,
+        AUTONAT_TOPIC,
+        CONTINUITY_TOPIC,
+        RESEND_TOPIC,
+    ] {
+        swarm.gossipsub.subscribe(Topic::new((*topic).to_owned()));
+    }
+    if swarm.directory.is_some() {
+        swarm
+            .gossipsub
+            .subscribe(Topic::new(DIRECTORY_TOPIC.to_owned()));
+    }
+    if swarm.peer_exchange.is_some() {
+        swarm
+            .gossipsub
+            .subscribe(Topic::new(PEER_RECORD_TOPIC.to_owned()));
+    }
+    if swarm.clock.is_some() {
+        swarm
+            .gossipsub
+            .subscribe(Topic::new(CLOCK_TOPIC.to_owned()));
+    }
+    let explicit_peers: Vec<PeerId> = swarm.peering.peers().cloned().collect();
+    for peer in &explicit_peers {
+        swarm.gossipsub.add_explicit_peer(peer);
+    }
+
+    for addr in config.listen_addrs {
+        Swarm::listen_on(&mut swarm, addr).map_err(PubSubError::transport)?;
+    }
+    for relay in config.relays {
+        Swarm::listen_on(&mut swarm, relay).map_err(PubSubError::transport)?;
+    }
+    for addr in config.bootstrap {
+        Swarm::dial_addr(&mut swarm, addr.clone())
+            .map_err(|e| PubSubError::dial(addr.clone(), e))?;
+        log::info!("Dialed {:?}", addr);
+    }
+    redial_explicit_peers(&mut swarm);
+    dial_remembered_peers(&mut swarm);
+
+    let (sender, receiver) = mpsc::unbounded();
+    let client = Client::new(
+        sender,
+        local_peer_id,
+        config.subscription_bounds,
+        size_limits,
+        message_types,
+        config.observer,
+    );
+    let routed = routes.start(&client)?;
+    task::spawn(run(
+        swarm,
+        receiver,
+        routed,
+        offloaded,
+        validations,
+        config.topic_announce_interval,
+    ));
+    if let Some(relay_server) = config.relay_server {
+        relay::spawn(&client, relay_server)?;
+    }
+    Ok(client)
+}
+
+/// Drive the swarm and execute client commands until every client has been dropped, then save
+/// the address book.
+async fn run<E: Extension>(
+    mut swarm: Swarm<Behaviour<E>>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut routed: Routed,
+    mut offloaded: mpsc::UnboundedReceiver<Offloaded>,
+    mut validations: Validations,
+    announce_interval: Duration,
+) {
+    let mut announce = stream::interval(announce_interval);
+    let mut housekeeping = stream::interval(HOUSEKEEPING_INTERVAL);
+    let tick = swarm
+        .pacer
+        .tick()
+        .into_iter()
+        .chain(swarm.scheduler.tick())
+        .min();
+    let mut pace: Box<dyn Stream<Item = ()> + Send + Unpin> = match tick {
+        Some(tick) => Box::new(stream::interval(tick)),
+        None => Box::new(futures::stream::pending()),
+    };
+    loop {
+        if !swarm.blocked.is_empty() {
+            let blocked = std::mem::take(&mut swarm.blocked);
+            if !unblock(&mut swarm, &mut commands, blocked).await {
+                break;
+            }
+        }
+        futures::select! {
+            command = commands.next() => match command {
+                Some(command) => handle_command(&mut swarm, command),
+                None => break,
+            },
+            event = swarm.next_event().fuse() => handle_event(&mut swarm, event),
+            routed = routed.next().fuse() => if let Some((topic, data)) = routed {
+                swarm.publish(topic, data, None, Headers::new(), Receipts::default());
+            },
+            offloaded = offloaded.next() => if let Some(offloaded) = offloaded {
+                swarm.offloaded(offloaded);
+            },
+            validated = validations.next().fuse() => if let Some(validated) = validated {
+                swarm.validated(validated);
+            },
+            _ = announce.next().fuse() => {
+                swarm.announce_published();
+                swarm.announce_continuity();
+                swarm.announce_directory(None);
+                update_peer_record(&mut swarm);
+            }
+            _ = pace.next().fuse() => swarm.release(),
+            _ = housekeeping.next().fuse() => housekeep(&mut swarm),
+        }
+        // Published messages are cached by gossipsub.
+        swarm.enforce_memory_budget();
+        dial_exchanged_peers(&mut swarm);
+    }
+    if let Some(store) = &mut swarm.peer_store {
+        store.save(true);
+    }
+}
+
+/// Check the health of bridges, drop the subscriptions of gone subscribers, and redial and evict
+/// peers as due.
+fn housekeep<E: Extension>(swarm: &mut Swarm<Behaviour<E>>) {
+    swarm.check_bridges();
+    swarm.prune_subscribers();
+    swarm.twins.expire();
+    swarm.sequences.expire();
+    swarm.replay.expire();
+    swarm.connections.expire();
+    swarm.send_clock_readings();
+    redial_explicit_peers(swarm);
+    evict_peers(swarm);
+    if let Some(store) = &mut swarm.peer_store {
+        store.save(false);
+    }
+}
+
+/// Wait for blocked subscribers to take their messages, executing commands meanwhile. The swarm
+/// is not polled in the meantime, which holds up the messages received from peers. Returns
+/// `false` once every client has been dropped.
+async fn unblock<E: Extension>(
+    swarm: &mut Swarm<Behaviour<E>>,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    blocked: Vec<(flow::Sender<Message>, Message)>,
+) -> bool {
+    let delivery = async move {
+        for (sender, message) in blocked {
+            // A subscriber gone meanwhile is dropped on the next delivery.
+            let _ = sender.send(message).await;
+        }
+    }
+    .fuse();
+    futures::pin_mut!(delivery);
+    loop {
+        futures::select! {
+            _ = delivery => return true,
+            command = commands.next() => match command {
+                Some(command) => {
+                    handle_command(swarm, command);
+                    swarm.enforce_memory_budget();
+                }
+                None => return false,
+            },
+        }
+    }
+}
+
+/// Dial the explicit peers that are disconnected and due for it.
+fn redial_explicit_peers<E: Extension>(swarm: &mut Swarm<Behaviour<E>>) {
+    for addr in swarm.peering.due() {
+        match Swarm::dial_addr(swarm, addr.clone()) {
+            Ok(()) => log::debug!("Dialed explicit peer {}", addr),
+            Err(e) => {
+                log::warn!("failed to dial explicit peer {}: {}", addr, e);
+                swarm.notify_event(NodeEvent::Error(Arc::new(PubSubError::dial(addr, e))));
+            }
+        }
+    }
+}
+
+/// Dial the peers suggested through peer exchange that are not connected yet, each at the
+/// first of its addresses a dial starts for.
+fn dial_exchanged_peers<E: Extension>(swarm: &mut Swarm<Behaviour<E>>) {
+    let dials = match &mut swarm.peer_exchange {
+        Some(peer_exchange) => peer_exchange.dials(),
+        None => return,
+    };
+    for (peer, addrs) in dials {
+        if Swarm::connection_info(swarm, &peer).is_some() {
+            continue;
+        }
+        for addr in addrs {
+            if let Some(peer_exchange) = &mut swarm.peer_exchange {
+                peer_exchange.dialed();
+            }
+            match Swarm::dial_addr(swarm, addr.clone()) {
+                Ok(()) => {
+                    log::debug!("Dialed suggested peer {}", addr);
+                    break;
+                }
+                Err(e) => log::debug!("failed to dial suggested peer {}: {}", addr, e),
+            }
+        }
+    }
+}
+
+/// Sign a new peer record of this node if its addresses changed, see
+/// [`Behaviour::update_peer_record`].
+fn update_peer_record<E: Extension>(swarm: &mut Swarm<Behaviour<E>>) {
+    let listen_addrs = Swarm::listeners(swarm).cloned().collect();
+    swarm.update_peer_record(listen_addrs);
+}
+
+/// Dial the peers of the address book last reached, each at its most promising address.
+fn dial_remembered_peers<E: Extension>(swarm: &mut Swarm<Behaviour<E>>) {
+    let peers = match &swarm.peer_store {
+        Some(store) => store.startup_peers(),
+        None => return,
+    };
+    for peer in peers {
+        if swarm.peering.contains(&peer) {
+            continue;
+        }
+        if let Some(addr) = swarm
+            .peer_store
+            .as_mut()
+            .and_then(|store| store.dial(&peer))
+        {
+            dial_remembered(swarm, addr);
+        }
+    }
+}
+
+/// Dial a peer of the address book at `addr`, going on with its next address while dialing
+/// fails at once.
+fn dial_remembered<E: Extension>(swarm: &mut Swarm<Behaviour<E>>, addr: Multiaddr) {
+    let mut next = Some(addr);
+    while let Some(addr) = next.take() {
+        match Swarm::dial_addr(swarm, addr.clone()) {
+            Ok(()) => log::debug!("Dialed remembered peer {}", addr),
+            Err(e) => {
+                log::debug!("failed to dial remembered peer {}: {}", addr, e);
+                next = swarm
+                    .peer_store
+                    .as_mut()
+                    .and_then(|store| store.unreachable(&addr));
+            }
+        }
+    }
+}
+
+/// Disconnect the peers whose pings call for it, or that forwarded too many replays, banning
+/// those the policy says, and let those whose ban is over connect again.
+fn evict_peers<E: Extension>(swarm: &mut Swarm<Behaviour<E>>) {
+    for peer in swarm.liveness.expired() {
+        log::debug!("ban of {} is over", peer.to_base58());
+        Swarm::unban_peer_id(swarm, peer);
+    }
+    let evictions: Vec<(PeerId, Eviction, bool)> = swarm
+        .peer_info
+        .iter()
+        .filter_map(|(peer, info)| {
+            let explicit = swarm.peering.contains(peer);
+            let eviction = swarm.liveness.check(&info.liveness, explicit)?;
+            Some((peer.clone(), eviction, explicit))
+        })
+        .collect();
+    for peer in swarm.replay.offenders() {
+        log::info!(
+            "disconnecting {}: forwarded replayed messages",
+            peer.to_base58()
+        );
+        let explicit = swarm.peering.contains(&peer);
+        ban_peer(swarm, peer, explicit);
+    }
+    for (peer, eviction, explicit) in evictions {
+        match eviction {
+            Eviction::Unresponsive(failures) => log::info!(
+                "disconnecting {}: {} pings failed in a row",
+                peer.to_base58(),
+                failures
+            ),
+            Eviction::Slow(median) => log::info!(
+                "disconnecting {}: median round-trip time of {:?}",
+                peer.to_base58(),
+                median
+            ),
+        }
+        ban_peer(swarm, peer, explicit);
+    }
+}
+
+/// Disconnect `peer` and ban it for the time the eviction policy says, unless it is explicit.
+fn ban_peer<E: Extension>(swarm: &mut Swarm<Behaviour<E>>, peer: PeerId, explicit: bool) {
+    // Banning closes the connection without the behaviours being told, so they are told here as
+    // if the peer had disconnected. Explicit peers are let in again right away.
+    let endpoint = swarm.identify.endpoint(&peer).cloned();
+    Swarm::ban_peer_id(swarm, peer.clone());
+    if explicit {
+        Swarm::unban_peer_id(swarm, peer.clone());
+    } else {
+        swarm.liveness.ban(peer.clone());
+    }
+    swarm.peer_info.remove(&peer);
+    if let Some(endpoint) = endpoint {
+        NetworkBehaviour::inject_disconnected(&mut **swarm, &peer, endpoint);
+        swarm.notify_event(NodeEvent::PeerDisconnected(peer.clone()));
+        swarm.disconnected(peer);
+    }
+}
+
+fn handle_event<E: Extension>(swarm: &mut Swarm<Behaviour<E>>, event: SwarmEvent<()>) {
+    match event {
+        SwarmEvent::NewListenAddr(addr) => {
+            log::info!("Address {}/ipfs/{}", addr, Swarm::local_peer_id(swarm));
+            swarm.listening = true;
+            swarm.check_milestones();
+            update_peer_record(swarm);
+            swarm.notify_event(NodeEvent::ListenAddr(addr));
+        }
+        SwarmEvent::Connected(peer) => {
+            swarm.peering.connected(&peer);
+            let behaviour = &mut **swarm;
+            if let (Some(store), Some(endpoint)) = (
+                &mut behaviour.peer_store,
+                behaviour.identify.endpoint(&peer),
+            ) {
+                store.connected(&peer, endpoint);
+            }
+            swarm.notify_event(NodeEvent::PeerConnected(peer));
+        }
+        SwarmEvent::Disconnected(peer) => {
+            swarm.notify_event(NodeEvent::PeerDisconnected(peer.clone()));
+            swarm.disconnected(peer);
+        }
+        SwarmEvent::UnreachableAddr { address, error, .. } => {
+            log::debug!("{} unreachable: {}", address, error);
+            let next = swarm
+                .peer_store
+                .as_mut()
+                .and_then(|store| store.unreachable(&address));
+            if let Some(next) = next {
+                dial_remembered(swarm, next);
+            }
+            swarm.notify_event(NodeEvent::Error(Arc::new(PubSubError::dial(
+                address,
+                error.to_string(),
+            ))));
+        }
+        event => log::debug!("{:?}", event),
+    }
+}
+
+fn handle_command<E: Extension>(swarm: &mut Swarm<Behaviour<E>>, command: Command) {
+    match command {
+        Command::Publish { topic, .. } | Command::PublishTo { topic, .. } if swarm.observer => {
+            log::warn!("observer dropping a message published on {}", topic);
+        }
+        Command::Publish {
+            topic,
+            data,
+            trace_context,
+            headers,
+            receipts,
+        } => swarm.publish(topic, data, trace_context, headers, receipts),
+        Command::PublishTo { topic, peers, data } => swarm.publish_to(topic, peers, data),
+        Command::Subscribe {
+            topic,
+            subscriber,
+            replay,
+        } => {
+            swarm.replay(|retained| retained == topic, replay, &subscriber);
+            swarm.subscribe(topic, subscriber);
+        }
+        Command::SubscribeFilter {
+            filter,
+            subscriber,
+            replay,
+        } => {
+            swarm.replay(|retained| filter.matches(retained), replay, &subscriber);
+            swarm.subscribe_filter(filter, subscriber);
+        }
+        Command::Topics { reply } => {
+            let _ = reply.send(swarm.topics());
+        }
+        Command::TopicActivity { topic, reply } => {
+            let _ = reply.send(Activity {
+                subscribed: swarm.topics().contains(&topic),
+                last_published: swarm.published.get(&topic).copied(),
+            });
+        }
+        Command::Peers { topic, reply } => {
+            let _ = reply.send(swarm.peers(topic));
+        }
+        Command::PeerTopics { peer, reply } => {
+            let _ = reply.send(swarm.peer_topics(&peer));
+        }
+        Command::Stats { reply } => {
+            let _ = reply.send(swarm.stats());
+        }
+        Command::MeshInfo { reply } => {
+            let _ = reply.send(swarm.mesh_info());
+        }
+        Command::DebugDump { reply } => {
+            let peer_id = Swarm::local_peer_id(swarm).clone();
+            let listen_addrs = Swarm::listeners(swarm).cloned().collect();
+            let _ = reply.send(swarm.debug_dump(peer_id, listen_addrs));
+        }
+        Command::Trace { message_id, reply } => {
+            let _ = reply.send(swarm.gossipsub.trace(message_id.as_ref()));
+        }
+        Command::ListPeers { topic, reply } => {
+            let topic = Topic::new(topic).no_hash();
+            let _ = reply.send(swarm.topic_mesh(&topic).peers);
+        }
+        Command::PeersSupporting { protocol, reply } => {
+            let _ = reply.send(swarm.peers_supporting(&protocol));
+        }
+        Command::WatchProtocol { protocol, watcher } => swarm.watch_protocol(protocol, watcher),
+        Command::WatchChanges { watcher } => swarm.change_watchers.push(watcher),
+        Command::WatchEvents { watcher } => swarm.event_watchers.push(watcher),
+        Command::RegisterBridge { health } => swarm.bridges.push(health),
+        Command::WatchBridges { watcher } => swarm.bridge_watchers.push(watcher),
+        Command::SealDirect { peers, data, reply } => {
+            let _ = reply.send(swarm.direct.seal(&peers, &data));
+        }
+        Command::OpenInbox { reply } => {
+            let _ = reply.send(swarm.direct.inbox());
+        }
+        Command::Successor { peer, reply } => {
+            let _ = reply.send(swarm.continuity.successor(&peer));
+        }
+        Command::DiscoverTopics { reply } => {
+            let _ = reply.send(swarm.discover_topics());
+        }
+        Command::WatchRotations { watcher } => swarm.continuity.watch(watcher),
+        Command::RegisterComponent { registration } => swarm.components.push(registration),
+        Command::WaitFor { milestone, reply } => swarm.wait_for(milestone, reply),
+        Command::Health { reply } => {
+            let listen_addrs = Swarm::listeners(swarm).cloned().collect();
+            let _ = reply.send(swarm.health(listen_addrs));
+        }
+        Command::Reachability { reply } => {
+            let listen_addrs = Swarm::listeners(swarm).cloned().collect();
+            let _ = reply.send(swarm.identify.status(listen_addrs));
+        }
+        Command::DescribeTopology { reply } => {
+            let _ = reply.send(swarm.topology());
+        }
+        Command::CheckCompat { reply } => {
+            let _ = reply.send(swarm.check_compat());
+        }
+        Command::Dial { addr } => {
+            if let Err(e) = Swarm::dial_addr(swarm, addr.clone()) {
+                log::warn!("failed to dial {}: {}", addr, e);
+                swarm.notify_event(NodeEvent::Error(Arc::new(PubSubError::dial(addr, e))));
+            }
+        }
+        Command::SetRateLimit { rate_limit } => swarm.gossipsub.set_rate_limit(rate_limit),
+        Command::PauseGossip { paused } => swarm.gossipsub.set_gossip_paused(paused),
+        #[cfg(feature = "wasm")]
+        Command::InstallPlugin {
+            name,
+            filter,
+            hook,
+            plugin,
+        } => swarm.install_plugin(name, filter, hook, plugin),
+        #[cfg(feature = "wasm")]
+        Command::RemovePlugin { name, reply } => {
+            let _ = reply.send(swarm.remove_plugin(&name));
+        }
+        #[cfg(feature = "wasm")]
+        Command::ListPlugins { reply } => {
+            let _ = reply.send(swarm.list_plugins());
+        }
+    }
+}
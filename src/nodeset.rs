@@ -0,0 +1,169 @@
+//! Several isolated nodes in one process, each in a pubsub network of its own.
+//!
+//! Nodes spawned in the same process share nothing but the executor: each has its own keypair,
+//! swarm key, connections and topics, so an edge gateway can be at the same time a node of a
+//! private LAN swarm and one of a public swarm. A [`NodeSet`] keeps track of them by name, and
+//! refuses to spawn two nodes under the same name or identity, which would make peers of the two
+//! networks take them for one another.
+//!
+//! [`NodeSet::bridge`] relays the messages on the topics matching a filter from one node of the
+//! set to another, or both ways, so that chosen topics cross between networks and nothing else
+//! does. A message relayed is published anew by the node it is relayed to, under its identity:
+//! the networks never learn of each other's peers. Payloads that crossed within the last
+//! [`BRIDGE_LOOP_WINDOW`] are not relayed back.
+
+use crate::{
+    bridge::{Direction, LoopGuard},
+    node,
+    topic::TopicFilter,
+    Client, Error, NodeConfig,
+};
+use async_std::task;
+use futures::prelude::*;
+use libp2p::PeerId;
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How long a payload relayed by a bridge between nodes of a set is kept from being relayed
+/// back.
+pub const BRIDGE_LOOP_WINDOW: Duration = Duration::from_secs(10);
+
+/// A node of a [`NodeSet`], under its name.
+#[derive(Clone)]
+pub struct NodeHandle {
+    name: String,
+    client: Client,
+}
+
+impl NodeHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl Deref for NodeHandle {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+/// Named nodes running in this process, see the [module documentation](self).
+#[derive(Default)]
+pub struct NodeSet {
+    nodes: BTreeMap<String, NodeHandle>,
+}
+
+impl NodeSet {
+    pub fn new() -> Self {
+        NodeSet::default()
+    }
+
+    /// Spawn a node called `name`, failing if the set already has a node of that name or
+    /// identity.
+    pub fn spawn(&mut self, name: &str, config: NodeConfig) -> Result<NodeHandle, Error> {
+        if self.nodes.contains_key(name) {
+            return Err(format!("node set already has a node called {}", name).into());
+        }
+        let peer_id = PeerId::from(config.identity()?.public());
+        if let Some(other) = self
+            .nodes
+            .values()
+            .find(|node| *node.local_peer_id() == peer_id)
+        {
+            return Err(format!(
+                "node {} has the identity {} of node {} already",
+                name, peer_id, other.name
+            )
+            .into());
+        }
+        let handle = NodeHandle {
+            name: name.to_owned(),
+            client: node::spawn(config)?,
+        };
+        self.nodes.insert(name.to_owned(), handle.clone());
+        Ok(handle)
+    }
+
+    /// The node called `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&NodeHandle> {
+        self.nodes.get(name)
+    }
+
+    /// The nodes of the set, by name.
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeHandle> {
+        self.nodes.values()
+    }
+
+    /// Take the node called `name` out of the set. It stops once its handle and every client of
+    /// it has been dropped, those of its bridges included.
+    pub fn remove(&mut self, name: &str) -> Option<NodeHandle> {
+        self.nodes.remove(name)
+    }
+
+    /// Relay the messages on the topics matching `filter` from the node `from` to the node `to`,
+    /// back as well if `direction` is [`Direction::Both`], or only from `to` to `from` if
+    /// [`Direction::Inbound`]. The bridge keeps both nodes running until the task returned is
+    /// cancelled.
+    pub fn bridge(
+        &self,
+        from: &str,
+        to: &str,
+        filter: &str,
+        direction: Direction,
+    ) -> Result<task::JoinHandle<()>, Error> {
+        let node = |name: &str| {
+            self.nodes
+                .get(name)
+                .map(|node| node.client.clone())
+                .ok_or_else(|| Error::from(format!("node set has no node called {}", name)))
+        };
+        if from == to {
+            return Err(format!("cannot bridge node {} to itself", from).into());
+        }
+        let (from, to) = (node(from)?, node(to)?);
+        let guard = Arc::new(Mutex::new(LoopGuard::new(BRIDGE_LOOP_WINDOW)));
+        let mut relays = Vec::new();
+        if direction.outbound() {
+            relays.push(relay(&from, to.clone(), filter, guard.clone())?);
+        }
+        if direction.inbound() {
+            relays.push(relay(&to, from, filter, guard)?);
+        }
+        Ok(task::spawn(future::join_all(relays).map(|_| ())))
+    }
+}
+
+/// Relay the messages on the topics matching `filter` from the node of `source` to that of
+/// `sink`.
+fn relay(
+    source: &Client,
+    sink: Client,
+    filter: &str,
+    guard: Arc<Mutex<LoopGuard>>,
+) -> Result<impl Future<Output = ()>, Error> {
+    let mut messages = if TopicFilter::new(filter)?.is_wildcard() {
+        source.subscribe_filter(filter)?
+    } else {
+        source.subscribe(filter)?
+    };
+    Ok(async move {
+        while let Some(message) = messages.next().await {
+            if !guard.lock().unwrap().admit(&message.topic, &message.data) {
+                continue;
+            }
+            if let Err(e) = sink.publish(&message.topic, message.data) {
+                log::warn!("failed to relay a message on {}: {}", message.topic, e);
+            }
+        }
+    })
+}
@@ -0,0 +1,35 @@
+//! Observer mode: nodes that watch the traffic of a swarm but never add to it, for monitoring
+//! and auditing.
+//!
+//! A node spawned with [`NodeConfig::observer`](crate::NodeConfig::observer) set subscribes,
+//! delivers messages to its subscribers and forwards those of its meshes like any other node,
+//! but originates none, at every layer:
+//!
+//! - its clients fail to publish with a [`PubSubError::Publish`](crate::PubSubError::Publish)
+//!   caused by [`ReadOnly`], whatever the call, and so do the direct messages, queues, locks,
+//!   presence, bridges and gateways built on them;
+//! - the node drops the publish commands reaching it nonetheless;
+//! - its gossipsub behaviour is read-only, dropping any message the node would originate, its
+//!   own announcements, clock readings, reachability probes and continuity records included,
+//!   and it never publishes on floodsub.
+//!
+//! Gossipsub still sends the control messages of the node, its subscriptions, the grafts and
+//! prunes of its meshes and the gossip about the messages it forwards, none of which carries a
+//! payload of its own. An observer hears the announcements of its peers but tells them nothing:
+//! it is missing from their topic directories, clock skews and peer records.
+
+use std::fmt;
+
+/// Error returned when publishing through an observer, as the cause of a
+/// [`PubSubError::Publish`](crate::PubSubError::Publish), see
+/// [`PubSubError::cause`](crate::PubSubError::cause).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadOnly;
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("node is a read-only observer")
+    }
+}
+
+impl std::error::Error for ReadOnly {}
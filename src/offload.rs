@@ -0,0 +1,356 @@
+//! Offloading of large payloads to IPFS, keeping them out of the gossip mesh.
+//!
+//! Every message is copied to each peer of the mesh and kept in the gossip history, which large
+//! blobs such as images or firmware make costly. With
+//! [`NodeConfig::offload`](crate::NodeConfig::offload) set, a payload published on a topic
+//! above the [threshold](OffloadConfig::thresholds) of the topic is stored on an IPFS node
+//! through its [HTTP API](OffloadConfig::api), and only a pointer to it is published: a marker,
+//! then JSON giving the CID of the content, its size and its SHA-256 digest. Subscribers fetch
+//! the content from their own IPFS node, which finds it through Bitswap, check it against the
+//! digest, and deliver it as if it had come whole, with the id and sequence number of the
+//! pointer.
+//!
+//! What is stored is the payload as sent on the wire, compressed, signed and sealed as the
+//! policies of the topic require, so that encrypted topics stay encrypted on IPFS; batches of
+//! [paced](crate::pacing) payloads are offloaded whole. Payloads are offloaded instead of being
+//! split into chunks, and are not bound by the `max_transmit_size` of gossipsub, only by the
+//! [`max_fetch_size`](OffloadConfig::max_fetch_size) of subscribers. Storing and fetching take
+//! a round trip to IPFS, so offloaded payloads may be delivered after smaller ones published
+//! later. Nodes without `offload` drop the pointers they receive.
+
+use crate::{message_id::Receipts, topic::TopicFilter, Error};
+use async_std::{future::timeout, net::TcpStream, task};
+use bytes::Bytes;
+use data_encoding::HEXLOWER;
+use futures::{channel::mpsc, prelude::*};
+use libp2p::{
+    gossipsub::{protocol::MessageId, GossipsubMessage},
+    multiaddr::Protocol,
+    Multiaddr,
+};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Start of a pointer. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plo";
+
+/// Largest pointer, beyond which a payload starting with the marker is not taken for one.
+const MAX_POINTER: usize = 1024;
+
+/// Fetches a node runs at once; the pointers received beyond are dropped.
+pub const MAX_FETCHES: usize = 16;
+
+/// Separates the parts of the bodies of `add` requests.
+const BOUNDARY: &str = "pubsub-lite-offload";
+
+/// Where and when to offload payloads.
+#[derive(Clone, Debug)]
+pub struct OffloadConfig {
+    /// Address of the HTTP API of the IPFS node storing and fetching payloads, such as
+    /// `/ip4/127.0.0.1/tcp/5001` or `/dns4/ipfs/tcp/5001`.
+    pub api: Multiaddr,
+    /// Payloads offloaded, as pairs of topic filter and size in bytes. Payloads larger than the
+    /// size of the first filter matching their topic are offloaded; payloads on other topics are
+    /// published as usual.
+    pub thresholds: Vec<(String, usize)>,
+    /// Largest content fetched for a pointer; larger ones are dropped.
+    pub max_fetch_size: usize,
+    /// How long storing or fetching a payload may take.
+    pub request_timeout: Duration,
+}
+
+impl Default for OffloadConfig {
+    fn default() -> Self {
+        OffloadConfig {
+            api: "/ip4/127.0.0.1/tcp/5001".parse().unwrap(),
+            thresholds: Vec::new(),
+            max_fetch_size: 64 * 1024 * 1024,
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What is published in place of an offloaded payload.
+#[derive(Serialize, Deserialize)]
+struct Pointer {
+    cid: String,
+    size: usize,
+    /// Hex SHA-256 digest of the content.
+    sha256: String,
+}
+
+/// Whether `data` is a pointer to offloaded content.
+pub(crate) fn is_pointer(data: &[u8]) -> bool {
+    data.starts_with(MARKER) && data.len() <= MAX_POINTER
+}
+
+/// What an offloading task hands back to the node.
+pub(crate) enum Offloaded {
+    /// The payload for `topic` was stored; `pointer` is to be published, telling `receipts`.
+    Stored {
+        topic: String,
+        pointer: Bytes,
+        receipts: Receipts,
+    },
+    /// The content `message` points to was fetched and put in its place.
+    Fetched {
+        id: MessageId,
+        message: GossipsubMessage,
+    },
+}
+
+/// Stores and fetches the payloads of a node.
+pub(crate) struct Offloader {
+    api: Arc<IpfsApi>,
+    thresholds: Vec<(TopicFilter, usize)>,
+    max_fetch_size: usize,
+    fetches: Arc<AtomicUsize>,
+    sender: mpsc::UnboundedSender<Offloaded>,
+}
+
+impl Offloader {
+    pub fn new(
+        config: OffloadConfig,
+        sender: mpsc::UnboundedSender<Offloaded>,
+    ) -> Result<Self, Error> {
+        let thresholds = config
+            .thresholds
+            .iter()
+            .map(|(filter, threshold)| Ok((TopicFilter::new(filter)?, *threshold)))
+            .collect::<Result<_, Error>>()?;
+        Ok(Offloader {
+            api: Arc::new(IpfsApi::new(&config.api, config.request_timeout)?),
+            thresholds,
+            max_fetch_size: config.max_fetch_size,
+            fetches: Arc::new(AtomicUsize::new(0)),
+            sender,
+        })
+    }
+
+    /// Whether a payload of `size` bytes published on `topic` is to be offloaded.
+    pub fn offloads(&self, topic: &str, size: usize) -> bool {
+        self.thresholds
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .is_some_and(|(_, threshold)| size > *threshold)
+    }
+
+    /// Store `data`, the payload of a message on `topic` as sent on the wire, handing back the
+    /// pointer to publish with the `receipts` of its publishers.
+    pub fn store(&self, topic: &str, data: Bytes, receipts: Receipts) {
+        let api = self.api.clone();
+        let sender = self.sender.clone();
+        let topic = topic.to_owned();
+        task::spawn(async move {
+            let pointer = Pointer {
+                cid: String::new(),
+                size: data.len(),
+                sha256: HEXLOWER.encode(digest::digest(&digest::SHA256, &data).as_ref()),
+            };
+            match api.add(data).await {
+                Ok(cid) => {
+                    let mut data = MARKER.to_vec();
+                    serde_json::to_writer(&mut data, &Pointer { cid, ..pointer })
+                        .expect("pointers serialize");
+                    let _ = sender.unbounded_send(Offloaded::Stored {
+                        topic,
+                        pointer: data.into(),
+                        receipts,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("not publishing a message on {}: {}", topic, e);
+                    receipts.fail(&e);
+                }
+            }
+        });
+    }
+
+    /// Fetch the content the pointer `message` carries, handing back the message with it.
+    pub fn fetch(&self, id: MessageId, mut message: GossipsubMessage) {
+        let pointer: Pointer = match serde_json::from_slice(&message.data[MARKER.len()..]) {
+            Ok(pointer) => pointer,
+            Err(e) => {
+                log::debug!("dropping a message from {}: {}", message.source, e);
+                return;
+            }
+        };
+        if pointer.size > self.max_fetch_size {
+            log::debug!(
+                "dropping a message from {} offloading {} bytes",
+                message.source,
+                pointer.size
+            );
+            return;
+        }
+        if self.fetches.fetch_add(1, Ordering::SeqCst) >= MAX_FETCHES {
+            self.fetches.fetch_sub(1, Ordering::SeqCst);
+            log::warn!(
+                "dropping a message from {}: too many fetches running",
+                message.source
+            );
+            return;
+        }
+        let api = self.api.clone();
+        let fetches = self.fetches.clone();
+        let sender = self.sender.clone();
+        task::spawn(async move {
+            match api.cat(&pointer.cid, pointer.size).await {
+                Ok(data)
+                    if HEXLOWER.encode(digest::digest(&digest::SHA256, &data).as_ref())
+                        == pointer.sha256 =>
+                {
+                    message.data = data.into();
+                    let _ = sender.unbounded_send(Offloaded::Fetched { id, message });
+                }
+                Ok(_) => log::debug!(
+                    "dropping a message from {}: content of {} does not match",
+                    message.source,
+                    pointer.cid
+                ),
+                Err(e) => log::debug!(
+                    "dropping a message from {}: failed to fetch {}: {}",
+                    message.source,
+                    pointer.cid,
+                    e
+                ),
+            }
+            fetches.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Client of the HTTP API of an IPFS node.
+struct IpfsApi {
+    host: String,
+    port: u16,
+    request_timeout: Duration,
+}
+
+/// Body of the response of `add`.
+#[derive(Deserialize)]
+struct Added {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+impl IpfsApi {
+    fn new(addr: &Multiaddr, request_timeout: Duration) -> Result<Self, Error> {
+        let mut protocols = addr.iter();
+        let host = match protocols.next() {
+            Some(Protocol::Ip4(ip)) => ip.to_string(),
+            Some(Protocol::Ip6(ip)) => ip.to_string(),
+            Some(Protocol::Dns4(host)) | Some(Protocol::Dns6(host)) => host.into_owned(),
+            _ => return Err(format!("unsupported IPFS API address {}", addr).into()),
+        };
+        let port = match (protocols.next(), protocols.next()) {
+            (Some(Protocol::Tcp(port)), None) => port,
+            _ => return Err(format!("unsupported IPFS API address {}", addr).into()),
+        };
+        Ok(IpfsApi {
+            host,
+            port,
+            request_timeout,
+        })
+    }
+
+    /// Store `data` and pin it, returning its CID.
+    async fn add(&self, data: Bytes) -> Result<String, Error> {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"payload\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            BOUNDARY
+        )
+        .into_bytes();
+        body.extend_from_slice(&data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        let path = "/api/v0/add?pin=true&cid-version=1&quieter=true";
+        let response = self.post(path, &content_type, &body, 64 * 1024).await?;
+        let added = response
+            .split(|&byte| byte == b'\n')
+            .rfind(|line| !line.is_empty())
+            .ok_or("IPFS answered no CID")?;
+        Ok(serde_json::from_slice::<Added>(added)?.hash)
+    }
+
+    /// Fetch the content of `cid`, expected to be `size` bytes long.
+    async fn cat(&self, cid: &str, size: usize) -> Result<Vec<u8>, Error> {
+        if cid.is_empty() || !cid.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+            return Err(format!("invalid CID {:?}", cid).into());
+        }
+        let path = format!("/api/v0/cat?arg={}", cid);
+        let data = self.post(&path, "text/plain", &[], size).await?;
+        if data.len() != size {
+            return Err(format!("{} is not {} bytes long", cid, size).into());
+        }
+        Ok(data)
+    }
+
+    /// Post `body` to `path`, returning the body of the response, at most `max_body` bytes.
+    async fn post(
+        &self,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+        max_body: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let exchange = async {
+            let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            // HTTP/1.0, for the response to end when the connection closes rather than come
+            // in chunks.
+            let head = format!(
+                "POST {} HTTP/1.0\r\nHost: {}:{}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                path,
+                self.host,
+                self.port,
+                content_type,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body).await?;
+            stream.flush().await?;
+            let mut response = Vec::new();
+            stream
+                .take((max_body + 64 * 1024) as u64)
+                .read_to_end(&mut response)
+                .await?;
+            Ok::<_, Error>(response)
+        };
+        let response = timeout(self.request_timeout, exchange)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        let head_len = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or("truncated IPFS response")?
+            + 4;
+        let head = String::from_utf8_lossy(&response[..head_len]);
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| format!("malformed IPFS response {:?}", head))?;
+        let body = response[head_len..].to_vec();
+        if status != 200 {
+            return Err(format!(
+                "IPFS answered {}: {}",
+                status,
+                String::from_utf8_lossy(&body).trim()
+            )
+            .into());
+        }
+        if body.len() > max_body {
+            return Err("IPFS response too large".into());
+        }
+        Ok(body)
+    }
+}
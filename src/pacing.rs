@@ -0,0 +1,256 @@
+//! Pacing of the messages a node publishes, so that a bursting upstream source does not flood
+//! the mesh.
+//!
+//! Messages published on a topic with a [`PacingPolicy`] are coalesced: the small messages
+//! published within the batch window are sent as one gossipsub message, a batch. A batch travels
+//! in an envelope: a marker, then every message as a big-endian `u32` length followed by its
+//! payload. Receiving nodes unwrap the envelope and deliver its messages one by one, in order,
+//! with the id and sequence number of the batch. The policy may also cap the rate at which
+//! messages, batches counting as one, are sent on the topic; those over the rate wait their turn,
+//! the oldest being dropped once too many are waiting.
+//!
+//! Batches are compressed, encrypted and split into chunks like any other message.
+
+use crate::{message_id::Receipts, topic::TopicFilter, Error, PubSubError};
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Start of a batch. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plb";
+
+/// Shortest interval at which paced topics are looked at.
+const MIN_TICK: Duration = Duration::from_millis(1);
+
+/// How the messages published on a topic are batched and rate limited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PacingPolicy {
+    /// How long a batch stays open for more messages after its first one. Zero disables
+    /// batching.
+    pub batch_window: Duration,
+    /// A batch is sent as soon as it would grow past this many bytes. Larger messages are sent
+    /// on their own.
+    pub max_batch_size: usize,
+    /// Upper bound on the messages sent per second on the topic, if any.
+    pub max_rate: Option<f64>,
+    /// Messages waiting for the rate limit beyond which the oldest is dropped.
+    pub max_pending: usize,
+}
+
+impl Default for PacingPolicy {
+    fn default() -> Self {
+        PacingPolicy {
+            batch_window: Duration::from_millis(20),
+            max_batch_size: 64 * 1024,
+            max_rate: None,
+            max_pending: 10_000,
+        }
+    }
+}
+
+/// Paced state of a topic.
+struct Lane {
+    policy: PacingPolicy,
+    /// Messages of the open batch, the receipts of their publishers, and when it was opened.
+    batch: Vec<Bytes>,
+    batch_receipts: Receipts,
+    batch_size: usize,
+    opened: Instant,
+    /// Payloads waiting for the rate limit.
+    pending: VecDeque<(Bytes, Receipts)>,
+    /// Messages that may be sent right away, and when they were counted.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Lane {
+    fn new(policy: PacingPolicy, now: Instant) -> Self {
+        Lane {
+            policy,
+            batch: Vec::new(),
+            batch_receipts: Receipts::default(),
+            batch_size: MARKER.len(),
+            opened: now,
+            pending: VecDeque::new(),
+            tokens: burst(&policy),
+            updated: now,
+        }
+    }
+
+    fn push(&mut self, topic: &str, data: Bytes, receipts: Receipts, now: Instant) {
+        let size = 4 + data.len();
+        if self.policy.batch_window == Duration::ZERO
+            || MARKER.len() + size > self.policy.max_batch_size
+        {
+            self.close(topic);
+            self.queue(topic, data, receipts);
+            return;
+        }
+        if self.batch_size + size > self.policy.max_batch_size {
+            self.close(topic);
+        }
+        if self.batch.is_empty() {
+            self.opened = now;
+        }
+        self.batch_size += size;
+        self.batch.push(data);
+        self.batch_receipts.append(receipts);
+    }
+
+    /// Queue the open batch for sending.
+    fn close(&mut self, topic: &str) {
+        let batch = std::mem::take(&mut self.batch);
+        let receipts = std::mem::take(&mut self.batch_receipts);
+        self.batch_size = MARKER.len();
+        match batch.len() {
+            0 => {}
+            1 => self.queue(topic, batch.into_iter().next().unwrap(), receipts),
+            _ => {
+                let mut envelope = Vec::with_capacity(
+                    MARKER.len() + batch.iter().map(|data| 4 + data.len()).sum::<usize>(),
+                );
+                envelope.extend_from_slice(MARKER);
+                for data in batch {
+                    envelope.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                    envelope.extend_from_slice(&data);
+                }
+                self.queue(topic, envelope.into(), receipts);
+            }
+        }
+    }
+
+    fn queue(&mut self, topic: &str, data: Bytes, receipts: Receipts) {
+        if self.pending.len() >= self.policy.max_pending.max(1) {
+            self.pending.pop_front();
+            log::warn!("dropping a message waiting to be published on {}", topic);
+        }
+        self.pending.push_back((data, receipts));
+    }
+
+    /// Take the payloads that may be sent at `now`.
+    fn release(&mut self, topic: &str, now: Instant, out: &mut Vec<(String, Bytes, Receipts)>) {
+        if !self.batch.is_empty() && now.duration_since(self.opened) >= self.policy.batch_window {
+            self.close(topic);
+        }
+        let rate = match self.policy.max_rate {
+            Some(rate) => rate,
+            None => {
+                out.extend(
+                    self.pending
+                        .drain(..)
+                        .map(|(data, receipts)| (topic.to_owned(), data, receipts)),
+                );
+                return;
+            }
+        };
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate)
+            .min(burst(&self.policy));
+        self.updated = now;
+        while self.tokens >= 1.0 {
+            match self.pending.pop_front() {
+                Some((data, receipts)) => out.push((topic.to_owned(), data, receipts)),
+                None => break,
+            }
+            self.tokens -= 1.0;
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.batch.is_empty() && self.pending.is_empty()
+    }
+}
+
+/// Messages a topic may send at once after being quiet: a second's worth, at least one.
+fn burst(policy: &PacingPolicy) -> f64 {
+    policy.max_rate.map_or(1.0, |rate| rate.max(1.0))
+}
+
+/// Batches and rate limits the messages published on paced topics.
+pub(crate) struct Pacer {
+    policies: Vec<(TopicFilter, PacingPolicy)>,
+    lanes: HashMap<String, Lane>,
+}
+
+impl Pacer {
+    pub(crate) fn new(policies: Vec<(TopicFilter, PacingPolicy)>) -> Self {
+        Pacer {
+            policies,
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// How often [`release`](Self::release) should be called, or `None` if no topic is paced.
+    pub(crate) fn tick(&self) -> Option<Duration> {
+        self.policies
+            .iter()
+            .flat_map(|(_, policy)| {
+                let batching = Some(policy.batch_window).filter(|w| *w > Duration::ZERO);
+                let rate = policy
+                    .max_rate
+                    .map(|rate| Duration::from_secs_f64(1.0 / rate));
+                batching.into_iter().chain(rate)
+            })
+            .min()
+            .map(|tick| tick.max(MIN_TICK))
+    }
+
+    /// Take `data` published on `topic`, and the `receipts` of its publisher, if the topic is
+    /// paced, or give them back to be sent right away.
+    pub(crate) fn push(
+        &mut self,
+        topic: &str,
+        data: Bytes,
+        receipts: Receipts,
+    ) -> Option<(Bytes, Receipts)> {
+        let policy = match self.policies.iter().find(|(f, _)| f.matches(topic)) {
+            Some((_, policy)) => *policy,
+            None => return Some((data, receipts)),
+        };
+        let now = Instant::now();
+        self.lanes
+            .entry(topic.to_owned())
+            .or_insert_with(|| Lane::new(policy, now))
+            .push(topic, data, receipts, now);
+        None
+    }
+
+    /// Take the payloads that may be sent now, with their topic and the receipts of their
+    /// publishers.
+    pub(crate) fn release(&mut self) -> Vec<(String, Bytes, Receipts)> {
+        let now = Instant::now();
+        let mut out = Vec::new();
+        for (topic, lane) in &mut self.lanes {
+            lane.release(topic, now, &mut out);
+        }
+        // Forget idle topics whose bucket is full again.
+        self.lanes.retain(|_, lane| {
+            !lane.is_idle() || (lane.policy.max_rate.is_some() && lane.tokens < burst(&lane.policy))
+        });
+        out
+    }
+}
+
+/// Split a received payload into the messages it carries: those of a batch, sharing its buffer,
+/// or itself.
+pub(crate) fn unbatch(data: Bytes) -> Result<Vec<Bytes>, Error> {
+    if !data.starts_with(MARKER) {
+        return Ok(vec![data]);
+    }
+    let mut messages = Vec::new();
+    let mut at = MARKER.len();
+    while at < data.len() {
+        let rest = &data[at..];
+        if rest.len() < 4 {
+            return Err(PubSubError::codec("truncated batch"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() - 4 < len {
+            return Err(PubSubError::codec("truncated batch"));
+        }
+        messages.push(data.slice(at + 4..at + 4 + len));
+        at += 4 + len;
+    }
+    Ok(messages)
+}
@@ -0,0 +1,22 @@
+//! Bindings of the PubSubAPI gRPC service of `src/pb/pubsub.proto`, generated when building the
+//! crate, served by the [gRPC gateway](crate::gateway::grpc).
+//!
+//! The bindings are behind the `grpc` cargo feature, on by default. The `client` feature adds
+//! the client, [`pub_sub_api_client::PubSubApiClient`].
+//!
+//! Clients in other languages are generated from the proto, or from [`FILE_DESCRIPTOR_SET`]
+//! (`pub descriptor` writes it out), or the gateway itself through its server reflection.
+
+tonic::include_proto!("pb");
+
+/// The `FileDescriptorSet` of `src/pb/pubsub.proto` and of the server reflection protocol,
+/// encoded.
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/pubsub.bin"));
+
+/// Bindings of the gRPC server reflection protocol, of `src/pb/reflection.proto`, served by the
+/// gRPC gateway.
+// The variant names are those of the protocol.
+#[allow(clippy::enum_variant_names)]
+pub mod reflection {
+    tonic::include_proto!("grpc.reflection.v1alpha");
+}
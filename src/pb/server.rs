@@ -1,61 +0,0 @@
-#[macro_use]
-extern crate log;
-
-#[path = "../log_util.rs"]
-mod log_util;
-
-use std::io::Read;
-use std::sync::Arc;
-use std::{io, thread};
-
-use futures::channel::oneshot;
-use futures::executor::block_on;
-use futures::prelude::*;
-use grpcio::{ChannelBuilder, Environment, ResourceQuota, RpcContext, ServerBuilder, UnarySink};
-
-use grpcio_proto::example::helloworld::{HelloReply, HelloRequest};
-use grpcio_proto::example::helloworld_grpc::{create_greeter, Greeter};
-
-#[derive(Clone)]
-struct GreeterService;
-
-impl Greeter for GreeterService {
-    fn say_hello(&mut self, ctx: RpcContext<'_>, req: HelloRequest, sink: UnarySink<HelloReply>) {
-        let msg = format!("Hello {}", req.get_name());
-        let mut resp = HelloReply::default();
-        resp.set_message(msg);
-        let f = sink
-            .success(resp)
-            .map_err(move |e| error!("failed to reply {:?}: {:?}", req, e))
-            .map(|_| ());
-        ctx.spawn(f)
-    }
-}
-
-fn main() {
-    let _guard = log_util::init_log(None);
-    let env = Arc::new(Environment::new(1));
-    let service = create_greeter(GreeterService);
-
-    let quota = ResourceQuota::new(Some("HelloServerQuota")).resize_memory(1024 * 1024);
-    let ch_builder = ChannelBuilder::new(env.clone()).set_resource_quota(quota);
-
-    let mut server = ServerBuilder::new(env)
-        .register_service(service)
-        .bind("127.0.0.1", 50_051)
-        .channel_args(ch_builder.build_args())
-        .build()
-        .unwrap();
-    server.start();
-    for (host, port) in server.bind_addrs() {
-        info!("listening on {}:{}", host, port);
-    }
-    let (tx, rx) = oneshot::channel();
-    thread::spawn(move || {
-        info!("Press ENTER to exit...");
-        let _ = io::stdin().read(&mut [0]).unwrap();
-        tx.send(())
-    });
-    let _ = block_on(rx);
-    let _ = block_on(server.shutdown());
-}
\ No newline at end of file
@@ -0,0 +1,412 @@
+//! Peer exchange, healing the meshes of topics without bootstrap nodes.
+//!
+//! A node pruned from the mesh of a topic, because the mesh of its peer is full or because the
+//! peer left the topic, loses a path to the rest of the network. If it connected through a few
+//! bootstrap nodes that went away since, it may have no other. With
+//! [`NodeConfig::peer_exchange`](crate::NodeConfig::peer_exchange) set, a node pruning a peer
+//! suggests up to [`PeerExchangeConfig::peers`] other peers of the topic along with the PRUNE, as
+//! gossipsub v1.1 does, and a pruned node dials those it is not connected to yet, at most
+//! [`PeerExchangeConfig::max_dials`] addresses per PRUNE.
+//!
+//! Peers are suggested with their signed peer record: the addresses they listen on, signed with
+//! their own key in a libp2p signed envelope, so that the pruning peer cannot send the pruned one
+//! to addresses of its choosing. Nodes send their record to every peer subscribing to
+//! [`PEER_RECORD_TOPIC`], and again to all of them whenever their addresses change; a node keeps
+//! the latest record of each of its connected peers, checking its signature, to suggest them.
+//! Peers whose record is unknown are suggested last, and cannot be dialed. A suggested peer is
+//! dialed at most once every [`REDIAL_INTERVAL`], however often it is suggested meanwhile.
+
+use crate::{delegation::IdentityKey, stats::PeerExchangeStats, Error, PubSubError};
+use libp2p::{identity, Multiaddr, PeerId};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Well-known topic on which nodes send their peer record to their peers.
+pub const PEER_RECORD_TOPIC: &str = "pubsub-lite/peer-records";
+
+/// Time before a suggested peer is dialed again.
+pub const REDIAL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Domain separating the signatures of peer records from other signatures of the same key.
+const DOMAIN: &[u8] = b"libp2p-peer-record";
+
+/// Multicodec of a peer record, as the payload type of its envelope.
+const PAYLOAD_TYPE: &[u8] = &[0x03, 0x01];
+
+/// Peers suggested to pruned peers and addresses dialed when pruned, see the
+/// [module documentation](self).
+#[derive(Clone, Copy, Debug)]
+pub struct PeerExchangeConfig {
+    /// Most peers suggested to a peer pruned from the mesh of a topic.
+    pub peers: usize,
+    /// Most addresses of suggested peers dialed per PRUNE received.
+    pub max_dials: usize,
+}
+
+impl Default for PeerExchangeConfig {
+    fn default() -> Self {
+        PeerExchangeConfig {
+            peers: 16,
+            max_dials: 4,
+        }
+    }
+}
+
+/// The addresses a peer listens on, signed with its key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerRecord {
+    pub peer_id: PeerId,
+    /// Number of the record, greater in every later record of the peer.
+    pub seq: u64,
+    pub addresses: Vec<Multiaddr>,
+}
+
+impl PeerRecord {
+    /// Sign the addresses of the holder of `key`, numbering the record with the clock.
+    pub fn sign(key: &IdentityKey, addresses: Vec<Multiaddr>) -> Result<Vec<u8>, Error> {
+        let record = wire::PeerRecord {
+            peer_id: PeerId::from(key.public()).into_bytes(),
+            seq: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos() as u64),
+            addresses: addresses
+                .into_iter()
+                .map(|addr| wire::AddressInfo {
+                    multiaddr: addr.to_vec(),
+                })
+                .collect(),
+        };
+        let payload = wire::encode(&record);
+        let signature = key.sign(&signed(&payload))?;
+        Ok(wire::encode(&wire::Envelope {
+            public_key: key.public().into_protobuf_encoding(),
+            payload_type: PAYLOAD_TYPE.to_vec(),
+            payload,
+            signature,
+        }))
+    }
+
+    /// Decode a signed record, checking that the peer it describes signed it.
+    pub fn verify(data: &[u8]) -> Result<Self, Error> {
+        let envelope: wire::Envelope = wire::decode(data)?;
+        if envelope.payload_type != PAYLOAD_TYPE {
+            return Err(PubSubError::codec(
+                "signed envelope does not hold a peer record",
+            ));
+        }
+        let key = identity::PublicKey::from_protobuf_encoding(&envelope.public_key)
+            .map_err(PubSubError::codec)?;
+        if !key.verify(&signed(&envelope.payload), &envelope.signature) {
+            return Err(PubSubError::codec("peer record has an invalid signature"));
+        }
+        let record: wire::PeerRecord = wire::decode(&envelope.payload)?;
+        let peer_id = PeerId::from_bytes(record.peer_id)
+            .map_err(|_| PubSubError::codec("peer record has an invalid peer id"))?;
+        if peer_id != PeerId::from(key) {
+            return Err(PubSubError::codec(format!(
+                "peer record of {} is not signed by it",
+                peer_id
+            )));
+        }
+        let addresses = record
+            .addresses
+            .into_iter()
+            .map(|info| Multiaddr::try_from(info.multiaddr).map_err(PubSubError::codec))
+            .collect::<Result<_, Error>>()?;
+        Ok(PeerRecord {
+            peer_id,
+            seq: record.seq,
+            addresses,
+        })
+    }
+}
+
+/// What the key of a peer signs to vouch for its record: the domain, the payload type and the
+/// payload, each prefixed with its length.
+fn signed(payload: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(DOMAIN.len() + PAYLOAD_TYPE.len() + payload.len() + 6);
+    for field in &[DOMAIN, PAYLOAD_TYPE, payload] {
+        prost::encoding::encode_varint(field.len() as u64, &mut signed);
+        signed.extend_from_slice(field);
+    }
+    signed
+}
+
+/// The record of a node, the records of its peers and the peers it was suggested.
+pub(crate) struct PeerExchange {
+    config: PeerExchangeConfig,
+    key: IdentityKey,
+    /// The addresses in the record of this node, and the record, once signed.
+    record: Option<(Vec<Multiaddr>, Vec<u8>)>,
+    /// Number of the latest record of every connected peer.
+    seqs: HashMap<PeerId, u64>,
+    /// Suggested peers to dial, with their addresses to try in turn.
+    dials: Vec<(PeerId, Vec<Multiaddr>)>,
+    /// When suggested peers were last queued to dial.
+    dialed_at: HashMap<PeerId, Instant>,
+    suggested: u64,
+    dialed: u64,
+}
+
+impl PeerExchange {
+    pub fn new(config: PeerExchangeConfig, key: IdentityKey) -> Self {
+        PeerExchange {
+            config,
+            key,
+            record: None,
+            seqs: HashMap::new(),
+            dials: Vec::new(),
+            dialed_at: HashMap::new(),
+            suggested: 0,
+            dialed: 0,
+        }
+    }
+
+    /// The signed record of this node, if it has addresses yet.
+    pub fn record(&self) -> Option<&[u8]> {
+        self.record.as_ref().map(|(_, record)| &record[..])
+    }
+
+    /// Sign a new record of this node if its addresses changed, returning it.
+    pub fn update(&mut self, addresses: Vec<Multiaddr>) -> Option<Vec<u8>> {
+        if addresses.is_empty()
+            || self
+                .record
+                .as_ref()
+                .is_some_and(|(known, _)| *known == addresses)
+        {
+            return None;
+        }
+        match PeerRecord::sign(&self.key, addresses.clone()) {
+            Ok(record) => {
+                self.record = Some((addresses, record.clone()));
+                Some(record)
+            }
+            Err(e) => {
+                log::warn!("failed to sign peer record: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Check a record heard on [`PEER_RECORD_TOPIC`], returning the peer it describes if it is
+    /// later than the one known.
+    pub fn accept(&mut self, data: &[u8]) -> Result<Option<PeerId>, Error> {
+        let record = PeerRecord::verify(data)?;
+        if let Some(&seq) = self.seqs.get(&record.peer_id) {
+            if seq >= record.seq {
+                return Ok(None);
+            }
+        }
+        self.seqs.insert(record.peer_id.clone(), record.seq);
+        Ok(Some(record.peer_id))
+    }
+
+    /// Forget the record of a peer that disconnected, or whose record was of no use.
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.seqs.remove(peer);
+    }
+
+    /// Queue dials to the peers suggested along with a PRUNE, up to
+    /// [`max_dials`](PeerExchangeConfig::max_dials) addresses, skipping those `connected` and
+    /// those without a valid record or dialed lately.
+    pub fn suggest(
+        &mut self,
+        peers: impl IntoIterator<Item = (PeerId, Option<Vec<u8>>)>,
+        connected: impl Fn(&PeerId) -> bool,
+    ) {
+        let local_peer_id = PeerId::from(self.key.public());
+        let mut budget = self.config.max_dials;
+        self.dialed_at
+            .retain(|_, dialed_at| dialed_at.elapsed() < REDIAL_INTERVAL);
+        for (peer, record) in peers {
+            self.suggested += 1;
+            if budget == 0
+                || peer == local_peer_id
+                || connected(&peer)
+                || self.dialed_at.contains_key(&peer)
+            {
+                continue;
+            }
+            let record = match record.as_deref().map(PeerRecord::verify) {
+                Some(Ok(record)) if record.peer_id == peer => record,
+                Some(Ok(_)) => {
+                    log::debug!("ignoring peer record suggested for another peer");
+                    continue;
+                }
+                Some(Err(e)) => {
+                    log::debug!("ignoring invalid suggested peer record: {}", e);
+                    continue;
+                }
+                None => continue,
+            };
+            let mut addresses = record.addresses;
+            addresses.truncate(budget);
+            budget -= addresses.len();
+            if !addresses.is_empty() {
+                self.dialed_at.insert(peer.clone(), Instant::now());
+                self.dials.push((peer, addresses));
+            }
+        }
+    }
+
+    /// Take the suggested peers to dial.
+    pub fn dials(&mut self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        std::mem::take(&mut self.dials)
+    }
+
+    /// Count an address of a suggested peer dialed.
+    pub fn dialed(&mut self) {
+        self.dialed += 1;
+    }
+
+    pub fn stats(&self) -> PeerExchangeStats {
+        PeerExchangeStats {
+            records: self.seqs.len(),
+            suggested: self.suggested,
+            dialed: self.dialed,
+        }
+    }
+}
+
+/// Wire format of signed peer records, as in the libp2p specifications.
+mod wire {
+    use crate::{Error, PubSubError};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Envelope {
+        /// Public key of the signer, protobuf encoded.
+        #[prost(bytes, tag = "1")]
+        pub public_key: Vec<u8>,
+        #[prost(bytes, tag = "2")]
+        pub payload_type: Vec<u8>,
+        #[prost(bytes, tag = "3")]
+        pub payload: Vec<u8>,
+        #[prost(bytes, tag = "5")]
+        pub signature: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PeerRecord {
+        #[prost(bytes, tag = "1")]
+        pub peer_id: Vec<u8>,
+        #[prost(uint64, tag = "2")]
+        pub seq: u64,
+        #[prost(message, repeated, tag = "3")]
+        pub addresses: Vec<AddressInfo>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AddressInfo {
+        #[prost(bytes, tag = "1")]
+        pub multiaddr: Vec<u8>,
+    }
+
+    pub fn encode(message: &impl prost::Message) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(message.encoded_len());
+        // Encoding only fails for lack of room, and a vector grows as needed.
+        let _ = message.encode(&mut bytes);
+        bytes
+    }
+
+    pub fn decode<M: prost::Message + Default>(data: &[u8]) -> Result<M, Error> {
+        M::decode(data).map_err(PubSubError::codec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::keypair_from_seed;
+
+    fn key(name: &str) -> IdentityKey {
+        keypair_from_seed(name).into()
+    }
+
+    fn addrs(ports: &[u16]) -> Vec<Multiaddr> {
+        ports
+            .iter()
+            .map(|port| format!("/ip4/192.0.2.1/tcp/{}", port).parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn signed_records_round_trip() {
+        let key = key("px a");
+        let data = PeerRecord::sign(&key, addrs(&[4001, 4002])).unwrap();
+        let record = PeerRecord::verify(&data).unwrap();
+        assert_eq!(record.peer_id, PeerId::from(key.public()));
+        assert_eq!(record.addresses, addrs(&[4001, 4002]));
+    }
+
+    #[test]
+    fn rejects_tampered_and_truncated_records() {
+        let data = PeerRecord::sign(&key("px a"), addrs(&[4001])).unwrap();
+        // The port is near the end of the payload, before the signature.
+        let port = data
+            .windows(2)
+            .rposition(|window| window == 4001u16.to_be_bytes())
+            .unwrap();
+        let mut tampered = data.clone();
+        tampered[port + 1] ^= 1;
+        assert!(PeerRecord::verify(&tampered).is_err());
+        assert!(PeerRecord::verify(&data[..data.len() - 1]).is_err());
+        assert!(PeerRecord::verify(&data[..data.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn accepts_only_later_records() {
+        let mut exchange = PeerExchange::new(PeerExchangeConfig::default(), key("px local"));
+        let peer = key("px a");
+        let first = PeerRecord::sign(&peer, addrs(&[4001])).unwrap();
+        let second = PeerRecord::sign(&peer, addrs(&[4002])).unwrap();
+        let peer_id = PeerId::from(peer.public());
+        assert_eq!(exchange.accept(&second).unwrap(), Some(peer_id.clone()));
+        assert_eq!(exchange.accept(&first).unwrap(), None);
+        assert_eq!(exchange.accept(&second).unwrap(), None);
+        exchange.forget(&peer_id);
+        assert_eq!(exchange.accept(&first).unwrap(), Some(peer_id));
+    }
+
+    #[test]
+    fn dials_verified_suggestions_within_budget() {
+        let config = PeerExchangeConfig {
+            peers: 16,
+            max_dials: 3,
+        };
+        let local = key("px local");
+        let mut exchange = PeerExchange::new(config, local.clone());
+        let peer = |name: &str, ports: &[u16]| {
+            let key = key(name);
+            let record = PeerRecord::sign(&key, addrs(ports)).unwrap();
+            (PeerId::from(key.public()), Some(record))
+        };
+        let (a, b, connected) = (
+            peer("px a", &[1, 2]),
+            peer("px b", &[3, 4]),
+            peer("px c", &[5]),
+        );
+        let local_id = PeerId::from(local.public());
+        let suggestions = vec![
+            (local_id, PeerRecord::sign(&local, addrs(&[6])).ok()),
+            connected.clone(),
+            // The record of a, passed off as the one of b.
+            (b.0.clone(), a.1.clone()),
+            (peer("px d", &[]).0, None),
+            a.clone(),
+            b.clone(),
+        ];
+        exchange.suggest(suggestions, |peer| *peer == connected.0);
+        assert_eq!(
+            exchange.dials(),
+            vec![(a.0.clone(), addrs(&[1, 2])), (b.0.clone(), addrs(&[3]))]
+        );
+        // Dialed lately.
+        exchange.suggest(vec![a, b], |_| false);
+        assert!(exchange.dials().is_empty());
+        assert_eq!(exchange.stats().suggested, 8);
+    }
+}
@@ -0,0 +1,107 @@
+//! Explicit peering, for hub-and-spoke deployments to keep their backbone links.
+//!
+//! The peers of [`NodeConfig::explicit_peers`](crate::NodeConfig::explicit_peers) are grafted to
+//! the mesh of every topic they share with the node, and never pruned from it however many
+//! peers the mesh has, so messages keep flowing along these links whatever gossipsub makes of
+//! the other connections. They are dialed once the node has started, and redialed while
+//! disconnected, waiting twice as long after every attempt up to [`MAX_REDIAL_BACKOFF`].
+//!
+//! Explicit peering bears on the mesh of the node only: a peer pruning the node from its own
+//! mesh stops sending to it. A backbone link is kept both ways when both of its ends have the
+//! other as explicit peer, e.g. spokes their hubs and hubs their spokes and each other.
+
+use crate::Error;
+use libp2p::{core::multiaddr::Protocol, Multiaddr, PeerId};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How long to wait before redialing an explicit peer that just disconnected.
+pub const REDIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between two attempts to redial an explicit peer.
+pub const MAX_REDIAL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The peer id an explicit peer address ends with.
+pub fn peer_id(addr: &Multiaddr) -> Result<PeerId, Error> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => {
+            PeerId::from_multihash(hash).map_err(|_| format!("invalid peer id in {}", addr).into())
+        }
+        _ => Err(format!(
+            "explicit peer address {} does not end with /p2p/<peer id>",
+            addr
+        )
+        .into()),
+    }
+}
+
+/// The explicit peers of a node, with when to dial them next.
+pub(crate) struct Peering {
+    peers: HashMap<PeerId, ExplicitPeer>,
+}
+
+struct ExplicitPeer {
+    addr: Multiaddr,
+    connected: bool,
+    next_dial: Instant,
+    backoff: Duration,
+}
+
+impl Peering {
+    pub fn new(addrs: &[Multiaddr]) -> Result<Self, Error> {
+        let now = Instant::now();
+        let peers = addrs
+            .iter()
+            .map(|addr| {
+                let peer = ExplicitPeer {
+                    addr: addr.clone(),
+                    connected: false,
+                    next_dial: now,
+                    backoff: REDIAL_BACKOFF,
+                };
+                Ok((peer_id(addr)?, peer))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Peering { peers })
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains_key(peer)
+    }
+
+    pub fn connected(&mut self, peer: &PeerId) {
+        if let Some(explicit) = self.peers.get_mut(peer) {
+            explicit.connected = true;
+            explicit.backoff = REDIAL_BACKOFF;
+        }
+    }
+
+    /// Redial `peer` after [`REDIAL_BACKOFF`] if it is an explicit peer.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        if let Some(explicit) = self.peers.get_mut(peer) {
+            explicit.connected = false;
+            explicit.next_dial = Instant::now() + REDIAL_BACKOFF;
+            explicit.backoff = REDIAL_BACKOFF;
+        }
+    }
+
+    /// The addresses of the disconnected peers due for dialing, backing off their next attempt.
+    pub fn due(&mut self) -> Vec<Multiaddr> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for explicit in self.peers.values_mut() {
+            if !explicit.connected && explicit.next_dial <= now {
+                due.push(explicit.addr.clone());
+                explicit.next_dial = now + explicit.backoff;
+                explicit.backoff = (explicit.backoff * 2).min(MAX_REDIAL_BACKOFF);
+            }
+        }
+        due
+    }
+}
@@ -0,0 +1,391 @@
+//! An address book of the peers a node met, kept across restarts.
+//!
+//! With [`NodeConfig::peer_store`](crate::NodeConfig::peer_store) set, a node remembers the
+//! peers it connected to in a JSON file, with the addresses they can be dialed at: those it
+//! reached them at, and those they listen on as they identify. For every address it counts the
+//! dials that got through and those that failed since the last that did. The file is written
+//! every [`SAVE_INTERVAL`] when anything changed, and when the node stops.
+//!
+//! On start, the node dials the [`STARTUP_DIALS`] peers of its book it last reached, each at its
+//! most promising address: the ones that never failed since they last got through first, the
+//! most recently successful of them first, then the ones never dialed, then the others. When a
+//! dial fails, the next address of the peer is tried, until one gets through or none remain. An
+//! address failing [`MAX_FAILURES`] dials in a row is forgotten.
+//!
+//! The book of a node seeds new ones: [`AddressBook::to_json`] exports it, and
+//! [`AddressBook::merge`] adds the peers and addresses of another book to one, as the
+//! `pub peers export` and `pub peers import <file>` commands of the `pub` example do.
+
+use crate::{Error, PubSubError};
+use libp2p::{core::ConnectedPoint, multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How often a node writes its address book, when anything changed.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of remembered peers dialed on start.
+pub const STARTUP_DIALS: usize = 16;
+
+/// Dials failing in a row after which an address is forgotten.
+pub const MAX_FAILURES: u32 = 5;
+
+/// Most addresses remembered per peer.
+pub const MAX_ADDRS: usize = 8;
+
+/// Most peers remembered, those reached the longest ago being forgotten first.
+pub const MAX_PEERS: usize = 1024;
+
+/// What a node knows of an address of a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressRecord {
+    pub addr: Multiaddr,
+    /// Dials to the address that got through.
+    pub successes: u32,
+    /// Dials to the address that failed since the last that got through.
+    pub failures: u32,
+    /// When a dial to the address last got through, if ever.
+    pub last_success: Option<SystemTime>,
+}
+
+impl AddressRecord {
+    fn new(addr: Multiaddr) -> Self {
+        AddressRecord {
+            addr,
+            successes: 0,
+            failures: 0,
+            last_success: None,
+        }
+    }
+}
+
+/// The peers a node met, with their addresses, the most promising first.
+#[derive(Clone, Debug, Default)]
+pub struct AddressBook {
+    peers: HashMap<PeerId, Vec<AddressRecord>>,
+}
+
+/// The JSON form of an [`AddressBook`].
+#[derive(Serialize, Deserialize)]
+struct BookFile {
+    peers: Vec<PeerEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerEntry {
+    peer_id: String,
+    addrs: Vec<AddressEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AddressEntry {
+    addr: String,
+    #[serde(default)]
+    successes: u32,
+    #[serde(default)]
+    failures: u32,
+    /// Seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_success: Option<u64>,
+}
+
+impl AddressBook {
+    /// Parse a book from its JSON form, as exported by [`to_json`](Self::to_json).
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let file: BookFile = serde_json::from_str(json)?;
+        let mut book = AddressBook::default();
+        for entry in file.peers {
+            let peer: PeerId = entry
+                .peer_id
+                .parse()
+                .map_err(|_| format!("invalid peer id {}", entry.peer_id))?;
+            let records = entry
+                .addrs
+                .into_iter()
+                .map(|address| {
+                    Ok(AddressRecord {
+                        addr: address.addr.parse().map_err(PubSubError::codec)?,
+                        successes: address.successes,
+                        failures: address.failures,
+                        last_success: address
+                            .last_success
+                            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            book.merge_records(peer, records);
+        }
+        Ok(book)
+    }
+
+    /// The JSON form of the book.
+    pub fn to_json(&self) -> String {
+        let mut peers: Vec<PeerEntry> = self
+            .peers
+            .iter()
+            .map(|(peer, records)| PeerEntry {
+                peer_id: peer.to_base58(),
+                addrs: records
+                    .iter()
+                    .map(|record| AddressEntry {
+                        addr: record.addr.to_string(),
+                        successes: record.successes,
+                        failures: record.failures,
+                        last_success: record.last_success.map(|at| {
+                            at.duration_since(UNIX_EPOCH)
+                                .map_or(0, |since| since.as_secs())
+                        }),
+                    })
+                    .collect(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        serde_json::to_string_pretty(&BookFile { peers }).unwrap_or_default()
+    }
+
+    /// Read the book at `path`, empty if there is no file there yet.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(json) => AddressBook::from_json(&json)
+                .map_err(|e| format!("invalid peer store {}: {}", path.display(), e).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(AddressBook::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the book to `path`, replacing what was there at once.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.to_json())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Add the peers and addresses of `other` this book does not know of yet.
+    pub fn merge(&mut self, other: AddressBook) {
+        for (peer, records) in other.peers {
+            self.merge_records(peer, records);
+        }
+    }
+
+    /// Number of peers in the book.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// The peers in the book.
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.keys()
+    }
+
+    /// The addresses of `peer`, the most promising first.
+    pub fn addresses(&self, peer: &PeerId) -> &[AddressRecord] {
+        self.peers.get(peer).map_or(&[], Vec::as_slice)
+    }
+
+    fn merge_records(&mut self, peer: PeerId, records: Vec<AddressRecord>) {
+        let known = self.peers.entry(peer.clone()).or_default();
+        for record in records {
+            if !known.iter().any(|other| other.addr == record.addr) {
+                known.push(record);
+            }
+        }
+        self.tidy(&peer);
+    }
+
+    /// Remember that `peer` may be dialed at `addr`, returning whether it was new.
+    fn learn(&mut self, peer: &PeerId, addr: Multiaddr) -> bool {
+        let known = self.peers.entry(peer.clone()).or_default();
+        if known.iter().any(|record| record.addr == addr) {
+            return false;
+        }
+        known.push(AddressRecord::new(addr));
+        self.tidy(peer);
+        true
+    }
+
+    fn succeeded(&mut self, peer: &PeerId, addr: Multiaddr) {
+        let known = self.peers.entry(peer.clone()).or_default();
+        let record = match known.iter().position(|record| record.addr == addr) {
+            Some(at) => &mut known[at],
+            None => {
+                known.push(AddressRecord::new(addr));
+                known.last_mut().unwrap()
+            }
+        };
+        record.successes = record.successes.saturating_add(1);
+        record.failures = 0;
+        record.last_success = Some(SystemTime::now());
+        self.tidy(peer);
+    }
+
+    /// Count a failed dial to `addr`, if known, returning whether it was.
+    fn failed(&mut self, peer: &PeerId, addr: &Multiaddr) -> bool {
+        match self.record_mut(peer, addr) {
+            Some(record) => {
+                record.failures += 1;
+                self.tidy(peer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn record_mut(&mut self, peer: &PeerId, addr: &Multiaddr) -> Option<&mut AddressRecord> {
+        self.peers
+            .get_mut(peer)?
+            .iter_mut()
+            .find(|record| record.addr == *addr)
+    }
+
+    /// When `peer` was last reached, if ever.
+    fn last_success(&self, peer: &PeerId) -> Option<SystemTime> {
+        self.addresses(peer)
+            .iter()
+            .filter_map(|record| record.last_success)
+            .max()
+    }
+
+    /// Rank the addresses of `peer`, forgetting those failing too often and the least promising
+    /// beyond [`MAX_ADDRS`], and the peer itself without any left.
+    fn tidy(&mut self, peer: &PeerId) {
+        let records = match self.peers.get_mut(peer) {
+            Some(records) => records,
+            None => return,
+        };
+        records.retain(|record| record.failures < MAX_FAILURES);
+        records.sort_by_key(|record| {
+            (
+                record.failures,
+                std::cmp::Reverse(record.last_success),
+                std::cmp::Reverse(record.successes),
+            )
+        });
+        records.truncate(MAX_ADDRS);
+        if records.is_empty() {
+            self.peers.remove(peer);
+        }
+        if self.peers.len() > MAX_PEERS {
+            if let Some(oldest) = self
+                .peers
+                .keys()
+                .min_by_key(|peer| self.last_success(peer))
+                .cloned()
+            {
+                self.peers.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The address book of a node, with where it is saved and the dials it is going through.
+pub(crate) struct PeerStore {
+    path: PathBuf,
+    book: AddressBook,
+    /// Whether the book changed since it was last saved.
+    dirty: bool,
+    saved: Instant,
+    /// Addresses yet to try for each peer being dialed from the book.
+    dialing: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl PeerStore {
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        Ok(PeerStore {
+            book: AddressBook::load(&path)?,
+            path,
+            dirty: false,
+            saved: Instant::now(),
+            dialing: HashMap::new(),
+        })
+    }
+
+    /// The peers to dial on start, those last reached first.
+    pub fn startup_peers(&self) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self.book.peers().cloned().collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(self.book.last_success(peer)));
+        peers.truncate(STARTUP_DIALS);
+        peers
+    }
+
+    /// Start dialing `peer` from the book, returning the first address to try.
+    pub fn dial(&mut self, peer: &PeerId) -> Option<Multiaddr> {
+        let mut addrs: Vec<Multiaddr> = self
+            .book
+            .addresses(peer)
+            .iter()
+            .map(|record| with_peer_id(&record.addr, peer))
+            .collect();
+        addrs.reverse();
+        let first = addrs.pop()?;
+        self.dialing.insert(peer.clone(), addrs);
+        Some(first)
+    }
+
+    /// Count a failed dial to `addr`, returning the next address of the peer to try if it was
+    /// being dialed from the book.
+    pub fn unreachable(&mut self, addr: &Multiaddr) -> Option<Multiaddr> {
+        let (peer, addr) = split_peer_id(addr)?;
+        self.dirty |= self.book.failed(&peer, &addr);
+        let remaining = self.dialing.get_mut(&peer)?;
+        let next = remaining.pop();
+        if remaining.is_empty() {
+            self.dialing.remove(&peer);
+        }
+        next
+    }
+
+    /// Remember the address `peer` was reached at, if dialed.
+    pub fn connected(&mut self, peer: &PeerId, endpoint: &ConnectedPoint) {
+        self.dialing.remove(peer);
+        if let ConnectedPoint::Dialer { address } = endpoint {
+            let addr = split_peer_id(address).map_or_else(|| address.clone(), |(_, addr)| addr);
+            self.book.succeeded(peer, addr);
+            self.dirty = true;
+        }
+    }
+
+    /// Remember the addresses `peer` listens on.
+    pub fn identified(&mut self, peer: &PeerId, listen_addrs: &[Multiaddr]) {
+        for addr in listen_addrs {
+            self.dirty |= self.book.learn(peer, addr.clone());
+        }
+    }
+
+    /// Save the book if it changed, and either `force` or it was last saved [`SAVE_INTERVAL`]
+    /// ago.
+    pub fn save(&mut self, force: bool) {
+        if !self.dirty || (!force && self.saved.elapsed() < SAVE_INTERVAL) {
+            return;
+        }
+        match self.book.save(&self.path) {
+            Ok(()) => self.dirty = false,
+            Err(e) => log::warn!("failed to save peer store {}: {}", self.path.display(), e),
+        }
+        self.saved = Instant::now();
+    }
+}
+
+/// `addr` followed by `/p2p/<peer>`.
+fn with_peer_id(addr: &Multiaddr, peer: &PeerId) -> Multiaddr {
+    addr.clone().with(Protocol::P2p(peer.clone().into()))
+}
+
+/// The peer id an address ends with, and the address without it.
+fn split_peer_id(addr: &Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    let mut addr = addr.clone();
+    match addr.pop()? {
+        Protocol::P2p(hash) => Some((PeerId::from_multihash(hash).ok()?, addr)),
+        _ => None,
+    }
+}
@@ -0,0 +1,176 @@
+//! Transformation pipelines run by the node on a subscription's messages before delivering them.
+//!
+//! A pipeline is a list of stages applied in order, declared as JSON when it comes from a
+//! gateway, e.g.
+//! `[{"decompress":"gzip"},{"project":{"fields":["device","reading.celsius"]}},{"rename":{"from":"device","to":"id"}}]`.
+//! Messages a pipeline fails on are not delivered to that subscription.
+
+use crate::{Error, PubSubError};
+use bytes::Bytes;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use flate2::read::GzDecoder;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::io::Read;
+
+/// Compression formats understood by [`Stage::Decompress`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+}
+
+/// One step of a [`Pipeline`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Decompress the payload.
+    Decompress(Compression),
+    /// Decrypt a ChaCha20-Poly1305 payload laid out as the 12 byte nonce followed by the
+    /// ciphertext and tag. `key` is the hex encoded 32 byte key.
+    Decrypt { key: String },
+    /// Keep only the given fields of a JSON object payload. Nested fields are addressed with
+    /// dotted paths and keep their nesting.
+    Project { fields: Vec<String> },
+    /// Rename a field of a JSON object payload, addressed with a dotted path.
+    Rename { from: String, to: String },
+}
+
+/// A stage ready to run, with its key material parsed.
+enum Step {
+    Decompress(Compression),
+    Decrypt(Box<LessSafeKey>),
+    Project(Vec<String>),
+    Rename { from: String, to: String },
+}
+
+/// A validated list of stages.
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+impl Pipeline {
+    /// Check the stages, e.g. that keys are well formed.
+    pub fn new(stages: Vec<Stage>) -> Result<Self, Error> {
+        let steps = stages
+            .into_iter()
+            .map(|stage| {
+                Ok(match stage {
+                    Stage::Decompress(compression) => Step::Decompress(compression),
+                    Stage::Decrypt { key } => {
+                        let key = HEXLOWER_PERMISSIVE
+                            .decode(key.as_bytes())
+                            .map_err(|e| format!("invalid decryption key: {}", e))?;
+                        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key)
+                            .map_err(|_| "decryption key must be 32 bytes long")?;
+                        Step::Decrypt(Box::new(LessSafeKey::new(key)))
+                    }
+                    Stage::Project { fields } => Step::Project(fields),
+                    Stage::Rename { from, to } => Step::Rename { from, to },
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Pipeline { steps })
+    }
+
+    /// Parse a pipeline declared as a JSON array of stages.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        Pipeline::new(serde_json::from_str(json)?)
+    }
+
+    /// Run every stage on `payload`, which an empty pipeline returns without a copy.
+    pub fn apply(&self, payload: &Bytes) -> Result<Bytes, Error> {
+        let mut payload = payload.clone();
+        for step in &self.steps {
+            payload = match step {
+                Step::Decompress(Compression::Gzip) => {
+                    let mut decompressed = Vec::new();
+                    GzDecoder::new(&payload[..]).read_to_end(&mut decompressed)?;
+                    decompressed.into()
+                }
+                Step::Decrypt(key) => decrypt(key, &payload)?.into(),
+                Step::Project(fields) => {
+                    let object = parse_object(&payload)?;
+                    let mut projected = Map::new();
+                    for field in fields {
+                        if let Some(value) = get(&object, field) {
+                            insert(&mut projected, field, value.clone());
+                        }
+                    }
+                    serde_json::to_vec(&projected)?.into()
+                }
+                Step::Rename { from, to } => {
+                    let mut object = parse_object(&payload)?;
+                    if let Some(value) = remove(&mut object, from) {
+                        insert(&mut object, to, value);
+                    }
+                    serde_json::to_vec(&object)?.into()
+                }
+            };
+        }
+        Ok(payload)
+    }
+}
+
+fn decrypt(key: &LessSafeKey, payload: &[u8]) -> Result<Vec<u8>, Error> {
+    if payload.len() < NONCE_LEN {
+        return Err(PubSubError::codec("encrypted payload is too short"));
+    }
+    let mut ciphertext = payload[NONCE_LEN..].to_vec();
+    let nonce = Nonce::try_assume_unique_for_key(&payload[..NONCE_LEN])
+        .map_err(|_| PubSubError::codec("invalid nonce"))?;
+    let plaintext = key
+        .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+        .map_err(|_| PubSubError::codec("decryption failed"))?;
+    Ok(plaintext.to_vec())
+}
+
+fn parse_object(payload: &[u8]) -> Result<Map<String, Value>, Error> {
+    match serde_json::from_slice(payload)? {
+        Value::Object(object) => Ok(object),
+        _ => Err("payload is not a JSON object".into()),
+    }
+}
+
+fn get<'a>(object: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    let mut levels = path.split('.');
+    let mut value = object.get(levels.next()?)?;
+    for level in levels {
+        value = value.as_object()?.get(level)?;
+    }
+    Some(value)
+}
+
+fn remove(object: &mut Map<String, Value>, path: &str) -> Option<Value> {
+    match path.rfind('.') {
+        Some(i) => {
+            let parent = path[..i].split('.').try_fold(object, |object, level| {
+                object.get_mut(level)?.as_object_mut()
+            })?;
+            parent.remove(&path[i + 1..])
+        }
+        None => object.remove(path),
+    }
+}
+
+/// Insert `value` at `path`, creating intermediate objects and replacing non-object values in
+/// the way.
+fn insert(object: &mut Map<String, Value>, path: &str, value: Value) {
+    let mut levels: Vec<&str> = path.split('.').collect();
+    let last = levels.pop().unwrap_or_default();
+    let mut object = object;
+    for level in levels {
+        let entry = object
+            .entry(level)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !entry.is_object() {
+            *entry = Value::Object(Map::new());
+        }
+        object = match entry {
+            Value::Object(nested) => nested,
+            _ => unreachable!(),
+        };
+    }
+    object.insert(last.to_owned(), value);
+}
@@ -0,0 +1,255 @@
+//! Sandboxed WebAssembly plugins filtering and transforming the messages a node publishes and
+//! delivers.
+//!
+//! Plugins change what a running node sends and hands to its subscribers without rebuilding it:
+//! [`Client::install_plugin`](crate::Client::install_plugin) attaches a plugin to the messages
+//! received on the topics matched by a filter, and
+//! [`Client::install_hook`](crate::Client::install_hook) to those published on them as well or
+//! instead, see [`Hook`]. Either replaces any plugin previously installed under the same name.
+//!
+//! A plugin is a WebAssembly module without imports, so it can touch nothing but its own memory.
+//! It exports its `memory`, an `alloc(len: i32) -> i32` function returning a new buffer at every
+//! call, which the node copies the payload, and the topic, into, and any of
+//!
+//! - `validate(ptr: i32, len: i32) -> i32`, returning zero to reject the message;
+//! - `filter(topic_ptr: i32, topic_len: i32, ptr: i32, len: i32) -> i32`, given the topic of the
+//!   message too and returning an action: `0` drops the message and `1` passes it on;
+//! - `transform(ptr: i32, len: i32) -> i64`, returning the address of the new payload in the high
+//!   32 bits and its length in the low 32 bits, or a negative value to drop the message.
+//!
+//! They are called in this order, the payload going on to the next plugin installed for the
+//! topic. Every message is handled by a fresh instance, within the fuel and memory [`Limits`] of
+//! the plugin. A plugin that traps, returns an unknown action or runs out of fuel or memory drops
+//! the message.
+//!
+//! Publish hooks run before the message is paced, signed and sent, so what they drop or rewrite
+//! never reaches the network. Gossipsub forwards messages before the node sees them, though, so
+//! receive hooks only affect local delivery, unless the node [validates](crate::validation)
+//! messages before forwarding them: it then forwards none whose every payload they drop. A
+//! message the node publishes to itself goes through both.
+
+use crate::{error::Cause, topic::TopicFilter, topology, Error, PubSubError};
+use std::{convert::TryFrom, fmt, sync::Arc};
+use wasmi::{
+    core::ValueType, Config, Engine, ExternType, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+/// Resources a plugin may use on each message.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Fuel available to a call, roughly one unit per instruction executed.
+    pub fuel: u64,
+    /// Size in bytes the plugin's memory may grow to.
+    pub memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            fuel: 10_000_000,
+            memory: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Which messages of its topics a plugin runs on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Hook {
+    /// Those the node publishes.
+    Publish,
+    /// Those the node delivers to its subscribers.
+    #[default]
+    Receive,
+    /// Both.
+    Both,
+}
+
+impl Hook {
+    pub fn publish(self) -> bool {
+        self != Hook::Receive
+    }
+
+    pub fn receive(self) -> bool {
+        self != Hook::Publish
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Hook::Publish => "publish",
+            Hook::Receive => "receive",
+            Hook::Both => "publish and receive",
+        })
+    }
+}
+
+/// A plugin installed on a node, as listed by
+/// [`Client::list_plugins`](crate::Client::list_plugins).
+#[derive(Clone, Debug)]
+pub struct InstalledPlugin {
+    pub name: String,
+    /// Filter of the topics the plugin runs on.
+    pub filter: String,
+    pub hook: Hook,
+    /// Hash of the module and limits of the plugin.
+    pub hash: String,
+}
+
+/// List `plugins`, as pairs of name and topic filter with their hook and plugin.
+pub(crate) fn list(plugins: &[(String, TopicFilter, Hook, Arc<Plugin>)]) -> Vec<InstalledPlugin> {
+    plugins
+        .iter()
+        .map(|(name, filter, hook, plugin)| InstalledPlugin {
+            name: name.clone(),
+            filter: filter.as_str().to_owned(),
+            hook: *hook,
+            hash: plugin.hash().to_owned(),
+        })
+        .collect()
+}
+
+/// A compiled plugin.
+pub struct Plugin {
+    engine: Engine,
+    module: Module,
+    limits: Limits,
+    validates: bool,
+    filters: bool,
+    transforms: bool,
+    /// Hash of the module and limits, listed in the topology of the node.
+    hash: String,
+}
+
+impl Plugin {
+    /// Compile a plugin from its WebAssembly binary, checking that it follows the plugin ABI.
+    pub fn new(wasm: &[u8], limits: Limits) -> Result<Self, Error> {
+        Self::compile(wasm, limits).map_err(PubSubError::config)
+    }
+
+    fn compile(wasm: &[u8], limits: Limits) -> Result<Self, Cause> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+        if let Some(import) = module.imports().next() {
+            return Err(format!(
+                "plugins cannot import anything, found {}::{}",
+                import.module(),
+                import.name()
+            )
+            .into());
+        }
+        let pair = [ValueType::I32, ValueType::I32];
+        if !matches!(module.get_export("memory"), Some(ExternType::Memory(_)))
+            || !has_function(&module, "alloc", &[ValueType::I32], &[ValueType::I32])?
+        {
+            return Err("plugins must export memory and alloc".into());
+        }
+        let validates = has_function(&module, "validate", &pair, &[ValueType::I32])?;
+        let filters = has_function(&module, "filter", &[ValueType::I32; 4], &[ValueType::I32])?;
+        let transforms = has_function(&module, "transform", &pair, &[ValueType::I64])?;
+        if !validates && !filters && !transforms {
+            return Err("plugins must export validate, filter or transform".into());
+        }
+        Ok(Plugin {
+            engine,
+            module,
+            limits,
+            validates,
+            filters,
+            transforms,
+            hash: topology::config_hash(&(topology::hash(wasm), limits)),
+        })
+    }
+
+    /// Hash of the module and limits of the plugin.
+    pub(crate) fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Run the plugin on `payload`, a message on `topic`, returning the payload to pass on, or
+    /// `None` if the message is rejected.
+    pub fn apply(&self, topic: &str, payload: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.run(topic, payload).map_err(PubSubError::other)
+    }
+
+    fn run(&self, topic: &str, payload: &[u8]) -> Result<Option<Vec<u8>>, Cause> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.memory)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store
+            .add_fuel(self.limits.fuel)
+            .map_err(|e| e.to_string())?;
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, &self.module)?
+            .start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or("plugin has no memory")?;
+
+        let len = i32::try_from(payload.len())?;
+        let ptr = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")?
+            .call(&mut store, len)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, payload)
+            .map_err(|e| e.to_string())?;
+        if self.validates {
+            let valid = instance
+                .get_typed_func::<(i32, i32), i32>(&store, "validate")?
+                .call(&mut store, (ptr, len))?;
+            if valid == 0 {
+                return Ok(None);
+            }
+        }
+        if self.filters {
+            let topic_len = i32::try_from(topic.len())?;
+            let topic_ptr = instance
+                .get_typed_func::<i32, i32>(&store, "alloc")?
+                .call(&mut store, topic_len)?;
+            memory
+                .write(&mut store, topic_ptr as u32 as usize, topic.as_bytes())
+                .map_err(|e| e.to_string())?;
+            let action = instance
+                .get_typed_func::<(i32, i32, i32, i32), i32>(&store, "filter")?
+                .call(&mut store, (topic_ptr, topic_len, ptr, len))?;
+            match action {
+                0 => return Ok(None),
+                1 => {}
+                action => return Err(format!("unknown action {}", action).into()),
+            }
+        }
+        if !self.transforms {
+            return Ok(Some(payload.to_vec()));
+        }
+        let transformed = instance
+            .get_typed_func::<(i32, i32), i64>(&store, "transform")?
+            .call(&mut store, (ptr, len))?;
+        if transformed < 0 {
+            return Ok(None);
+        }
+        let mut data = vec![0; transformed as u32 as usize];
+        memory
+            .read(&store, (transformed >> 32) as usize, &mut data)
+            .map_err(|e| e.to_string())?;
+        Ok(Some(data))
+    }
+}
+
+/// Whether `module` exports the function `name`, which must then have the given signature.
+fn has_function(
+    module: &Module,
+    name: &str,
+    params: &[ValueType],
+    results: &[ValueType],
+) -> Result<bool, Cause> {
+    match module.get_export(name) {
+        Some(ExternType::Func(ty)) if ty.params() == params && ty.results() == results => Ok(true),
+        Some(_) => Err(format!("plugin export {} has the wrong type", name).into()),
+        None => Ok(false),
+    }
+}
@@ -0,0 +1,258 @@
+//! Presence: which peers are around on a topic, for building chat rosters and device fleets.
+//!
+//! Presence is opt-in. A node takes part in the presence of a topic for as long as it holds the
+//! [`Presence`] returned by [`Client::presence`](crate::Client::presence): every
+//! [`HEARTBEAT_INTERVAL`] it publishes a heartbeat on the shadow topic
+//! `pubsub-lite/presence/<topic>`, telling whether it is subscribed to the topic and whether it
+//! published on it lately, and it says goodbye once the handle is dropped. A peer whose
+//! heartbeats stop for [`MISSED_HEARTBEATS`] intervals, e.g. because it crashed or the network
+//! split, is considered gone.
+//!
+//! Only peers taking part report their presence, this node included.
+
+use crate::{node::Command, Client, Error, Message, Subscription};
+use async_std::{stream, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select,
+};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// How often a node taking part in the presence of a topic announces itself.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of heartbeats a peer may miss before it is considered gone.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// Announcement exchanged on the presence topic of a topic, by the peer publishing it.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Announcement {
+    /// The peer is around, and announces itself again within `interval_ms` milliseconds.
+    Heartbeat {
+        subscribed: bool,
+        publishing: bool,
+        interval_ms: u64,
+    },
+    /// The peer leaves.
+    Goodbye,
+}
+
+/// What a peer taking part in the presence of a topic does on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerPresence {
+    pub peer: PeerId,
+    /// Whether the peer is subscribed to the topic.
+    pub subscribed: bool,
+    /// Whether the peer published on the topic within the last [`MISSED_HEARTBEATS`]
+    /// heartbeat intervals.
+    pub publishing: bool,
+}
+
+/// Change in the peers present on a topic, reported by [`Presence`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresenceEvent {
+    /// A peer announced itself for the first time, or again after leaving.
+    Joined(PeerPresence),
+    /// A present peer subscribed, unsubscribed, started or stopped publishing.
+    Changed(PeerPresence),
+    /// A peer left, saying goodbye or, if `timed_out`, missing its heartbeats.
+    Left { peer: PeerId, timed_out: bool },
+}
+
+/// Whether this node is subscribed to a topic and when it last published on it.
+pub(crate) struct Activity {
+    pub subscribed: bool,
+    pub last_published: Option<Instant>,
+}
+
+/// Stream of the changes in the peers present on a topic, returned by
+/// [`Client::presence`](crate::Client::presence). This node takes part in the presence of the
+/// topic until the stream is dropped.
+pub struct Presence {
+    receiver: mpsc::UnboundedReceiver<PresenceEvent>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Stream for Presence {
+    type Item = PresenceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<PresenceEvent>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Peers heard on a presence topic, with when each is considered gone.
+#[derive(Default)]
+struct Roster {
+    peers: HashMap<PeerId, (PeerPresence, Instant)>,
+}
+
+impl Roster {
+    fn apply(&mut self, peer: PeerId, announcement: Announcement) -> Option<PresenceEvent> {
+        match announcement {
+            Announcement::Heartbeat {
+                subscribed,
+                publishing,
+                interval_ms,
+            } => {
+                let presence = PeerPresence {
+                    peer: peer.clone(),
+                    subscribed,
+                    publishing,
+                };
+                let expires =
+                    Instant::now() + Duration::from_millis(interval_ms) * MISSED_HEARTBEATS;
+                match self.peers.insert(peer, (presence.clone(), expires)) {
+                    None => Some(PresenceEvent::Joined(presence)),
+                    Some((previous, _)) if previous != presence => {
+                        Some(PresenceEvent::Changed(presence))
+                    }
+                    Some(_) => None,
+                }
+            }
+            Announcement::Goodbye => self.peers.remove(&peer).map(|_| PresenceEvent::Left {
+                peer,
+                timed_out: false,
+            }),
+        }
+    }
+
+    /// Forget the peers whose heartbeats stopped.
+    fn expire(&mut self) -> Vec<PresenceEvent> {
+        let now = Instant::now();
+        let gone: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        gone.into_iter()
+            .map(|peer| {
+                self.peers.remove(&peer);
+                PresenceEvent::Left {
+                    peer,
+                    timed_out: true,
+                }
+            })
+            .collect()
+    }
+}
+
+/// This node taking part in the presence of a topic.
+struct Participant {
+    client: Client,
+    topic: String,
+    presence_topic: String,
+    subscription: Subscription,
+    roster: Roster,
+    events: mpsc::UnboundedSender<PresenceEvent>,
+}
+
+impl Participant {
+    /// Publish an announcement and apply it right away: the node hands our own messages back to
+    /// us only with local delivery on, and only after the announcement returns.
+    fn announce(&mut self, announcement: Announcement) -> Result<(), Error> {
+        self.client
+            .publish(&self.presence_topic, serde_json::to_vec(&announcement)?)?;
+        let peer = self.client.local_peer_id().clone();
+        self.apply(peer, announcement);
+        Ok(())
+    }
+
+    fn apply(&mut self, peer: PeerId, announcement: Announcement) {
+        if let Some(event) = self.roster.apply(peer, announcement) {
+            let _ = self.events.unbounded_send(event);
+        }
+    }
+
+    fn receive(&mut self, message: Message) {
+        match serde_json::from_slice(&message.data) {
+            Ok(announcement) => self.apply(message.source, announcement),
+            Err(e) => log::warn!("ignoring malformed presence announcement: {}", e),
+        }
+    }
+
+    async fn heartbeat(&mut self) -> Result<(), Error> {
+        for event in self.roster.expire() {
+            let _ = self.events.unbounded_send(event);
+        }
+        let (reply, activity) = oneshot::channel();
+        self.client.send(Command::TopicActivity {
+            topic: self.topic.clone(),
+            reply,
+        })?;
+        let activity = activity.await.map_err(|_| Error::Shutdown)?;
+        let window = HEARTBEAT_INTERVAL * MISSED_HEARTBEATS;
+        self.announce(Announcement::Heartbeat {
+            subscribed: activity.subscribed,
+            publishing: activity
+                .last_published
+                .is_some_and(|published| published.elapsed() < window),
+            interval_ms: HEARTBEAT_INTERVAL.as_millis() as u64,
+        })
+    }
+
+    /// Announce this node and follow the announcements of the others until `stop` fires.
+    async fn run(mut self, stop: oneshot::Receiver<()>) {
+        let mut heartbeats = stream::interval(HEARTBEAT_INTERVAL);
+        let mut stop = stop.fuse();
+        if let Err(e) = self.heartbeat().await {
+            log::warn!("failed to announce presence on {}: {}", self.topic, e);
+        }
+        loop {
+            select! {
+                message = self.subscription.next().fuse() => match message {
+                    Some(message) => self.receive(message),
+                    None => return,
+                },
+                _ = heartbeats.next().fuse() => {
+                    if let Err(e) = self.heartbeat().await {
+                        log::warn!("failed to announce presence on {}: {}", self.topic, e);
+                    }
+                }
+                _ = stop => {
+                    if let Err(e) = self.announce(Announcement::Goodbye) {
+                        log::warn!("failed to leave presence of {}: {}", self.topic, e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Take part in the presence of `topic`, following the peers present on it.
+pub(crate) fn watch(client: &Client, topic: &str) -> Result<Presence, Error> {
+    let presence_topic = format!("pubsub-lite/presence/{}", topic);
+    let (events, receiver) = mpsc::unbounded();
+    let (stop, stopped) = oneshot::channel();
+    let participant = Participant {
+        client: client.clone(),
+        topic: topic.to_owned(),
+        subscription: client.subscribe(&presence_topic)?,
+        presence_topic,
+        roster: Roster::default(),
+        events,
+    };
+    task::spawn(participant.run(stopped));
+    Ok(Presence {
+        receiver,
+        stop: Some(stop),
+    })
+}
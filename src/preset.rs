@@ -0,0 +1,98 @@
+//! Named sets of gossipsub parameters, for the common trade-offs.
+//!
+//! The heartbeat interval, the mesh degrees, the length of the message history and the largest
+//! message gossipsub transmits only make sense together: a mesh degree above the high watermark,
+//! or a history gossiped for longer than it is kept, is rejected or quietly misbehaves. A
+//! [`Preset`] sets them all at once, coherently, on
+//! [`NodeConfig::gossipsub`](crate::NodeConfig::gossipsub), leaving the protocol id and message
+//! ids alone. It is selected by name with the `preset` of a [manifest](crate::manifest), or
+//! applied with [`Preset::apply`]:
+//!
+//! - `low-latency` heartbeats five times a second and keeps a wide mesh, for messages to spread
+//!   within a few hops and missed ones to be gossiped about fast, at the cost of more traffic;
+//! - `low-bandwidth` heartbeats every other second over a narrow mesh and a short history, for
+//!   metered or constrained links;
+//! - `large-messages` lets messages of up to 4 MiB through a narrow mesh, keeping them in the
+//!   history for a short while only, as every mesh peer gets a copy of each and the history holds
+//!   them all;
+//! - `many-topics` keeps the mesh of every topic small and drops fanouts sooner, so that the
+//!   connections and heartbeats of a node in hundreds of topics stay affordable;
+//! - `constrained` is for nodes on metered links, such as 4G routers: it keeps two peers in the
+//!   mesh of every topic, heartbeats every five seconds, so that the control messages of a
+//!   heartbeat go out together and seldom, and advertises at most [`GOSSIP_BUDGET`] message ids
+//!   per heartbeat. When the application detects that its link is metered, it can also pause
+//!   gossip altogether with [`Client::pause_gossip`](crate::Client::pause_gossip), leaving
+//!   messages to the eager push of the mesh, and resume it once the link is not.
+//!
+//! A [rate limit](crate::NodeConfig::rate_limit) must still let the largest messages through,
+//! and the [size limits](crate::NodeConfig::max_message_size) of topics must not exceed the
+//! largest message of the preset.
+
+use crate::Error;
+use libp2p::gossipsub::GossipsubConfig;
+use serde::Deserialize;
+use std::{str::FromStr, time::Duration};
+
+/// Most message ids a node with the `constrained` preset advertises per heartbeat.
+pub const GOSSIP_BUDGET: usize = 64;
+
+/// A named set of gossipsub parameters, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    LowLatency,
+    LowBandwidth,
+    LargeMessages,
+    ManyTopics,
+    Constrained,
+}
+
+impl Preset {
+    /// Set the parameters of the preset on `config`.
+    pub fn apply(self, config: &mut GossipsubConfig) {
+        let (heartbeat, mesh_n_low, mesh_n, mesh_n_high, history_length, history_gossip) =
+            match self {
+                Preset::LowLatency => (Duration::from_millis(200), 6, 8, 12, 10, 5),
+                Preset::LowBandwidth => (Duration::from_secs(2), 3, 4, 6, 4, 2),
+                Preset::LargeMessages => (Duration::from_secs(1), 3, 4, 8, 3, 2),
+                Preset::ManyTopics => (Duration::from_secs(1), 2, 4, 6, 5, 3),
+                Preset::Constrained => (Duration::from_secs(5), 1, 2, 3, 3, 1),
+            };
+        config.heartbeat_interval = heartbeat;
+        config.heartbeat_initial_delay = heartbeat.max(Duration::from_secs(1));
+        config.mesh_n_low = mesh_n_low;
+        config.mesh_n = mesh_n;
+        config.mesh_n_high = mesh_n_high;
+        config.gossip_lazy = mesh_n;
+        config.history_length = history_length;
+        config.history_gossip = history_gossip;
+        config.max_transmit_size = match self {
+            Preset::LowBandwidth | Preset::Constrained => 64 * 1024,
+            Preset::LargeMessages => 4 * 1024 * 1024,
+            Preset::LowLatency | Preset::ManyTopics => 256 * 1024,
+        };
+        config.fanout_ttl = match self {
+            Preset::ManyTopics | Preset::Constrained => Duration::from_secs(15),
+            _ => Duration::from_secs(60),
+        };
+        config.gossip_budget = match self {
+            Preset::Constrained => Some(GOSSIP_BUDGET),
+            _ => None,
+        };
+    }
+}
+
+impl FromStr for Preset {
+    type Err = Error;
+
+    fn from_str(preset: &str) -> Result<Self, Error> {
+        match preset {
+            "low-latency" => Ok(Preset::LowLatency),
+            "low-bandwidth" => Ok(Preset::LowBandwidth),
+            "large-messages" => Ok(Preset::LargeMessages),
+            "many-topics" => Ok(Preset::ManyTopics),
+            "constrained" => Ok(Preset::Constrained),
+            _ => Err(format!("unknown preset {}", preset).into()),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Priorities of topics, and scheduling of the messages a node publishes when its upload is
+//! constrained.
+//!
+//! Every topic has a [`Priority`], that of the first filter of
+//! [`NodeConfig::priorities`](crate::NodeConfig::priorities) matching it, [`Priority::Normal`]
+//! otherwise; internal topics are always [`Priority::Control`]. With an [`UploadLimit`], the
+//! messages handed to the mesh, after compression, encryption and chunking, go through a
+//! scheduler: a token bucket filled with bytes at the configured rate, from which every message
+//! sent takes its size. While the bucket is empty, messages wait in a queue per priority, and
+//! those of the most urgent queue are sent first once it fills again, so that control traffic
+//! overtakes bulk telemetry. Within a priority, messages are sent in order.
+//!
+//! Waiting messages age: a message moves up one priority for every
+//! [`aging`](UploadLimit::aging) it waited, up to [`Priority::Control`], and messages of the same
+//! priority are sent the oldest first. Sustained urgent traffic therefore delays less urgent
+//! messages but cannot starve them. The limit and its aging are also set by the `upload_limit`
+//! section of a [manifest](crate::manifest).
+//!
+//! The bucket is also drained by the rest of the gossipsub traffic of the node, the messages it
+//! forwards for its peers and the control messages included, as [measured](crate::bandwidth)
+//! every [`SCHEDULER_TICK`], and by every copy of the messages it publishes: the budget is that
+//! of the whole upload of the node, not of its own payloads only. That traffic is not scheduled
+//! itself. Neither are the messages published to chosen peers, resent ones among them.
+//!
+//! A queue holding [`max_queued`](UploadLimit::max_queued) messages drops its oldest to take
+//! another; dropping a chunk loses the whole message it belongs to. How long messages waited, and
+//! how many were dropped, are in the [statistics](crate::stats::PriorityStats) of the node.
+
+use crate::{
+    message_id::Receipts,
+    stats::{DelayStats, PriorityStats},
+    topic::{self, TopicFilter},
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How often the queues of a node with an upload limit are looked at.
+pub const SCHEDULER_TICK: Duration = Duration::from_millis(10);
+
+/// How urgent the messages of a topic are, the most urgent first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Control plane traffic, that of internal topics included.
+    Control,
+    High,
+    #[default]
+    Normal,
+    /// Bulk traffic, telemetry and the like, sent last.
+    Bulk,
+}
+
+impl Priority {
+    /// Every priority, the most urgent first.
+    pub const ALL: [Priority; 4] = [
+        Priority::Control,
+        Priority::High,
+        Priority::Normal,
+        Priority::Bulk,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Control => "control",
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Bulk => "bulk",
+        }
+    }
+}
+
+/// Budget of the upload of a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UploadLimit {
+    /// Rate at which the bucket fills up.
+    pub bytes_per_second: u64,
+    /// Capacity of the bucket, the largest burst the node may send.
+    pub burst: u64,
+    /// Messages waiting in the queue of each priority beyond which the oldest is dropped.
+    pub max_queued: usize,
+    /// How long a message waits before it moves up a priority, or `None` for strict priorities.
+    pub aging: Option<Duration>,
+}
+
+impl Default for UploadLimit {
+    fn default() -> Self {
+        UploadLimit {
+            bytes_per_second: 1024 * 1024,
+            burst: 256 * 1024,
+            max_queued: 10_000,
+            aging: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// A message waiting for the bucket, the receipts of its publishers, and since when.
+struct Queued {
+    topic: String,
+    data: Bytes,
+    receipts: Receipts,
+    since: Instant,
+}
+
+/// The priority `queued`, of a topic of priority `lane`, has aged to at `now`, as an index of
+/// [`Priority::ALL`].
+fn aged(lane: usize, queued: &Queued, aging: Option<Duration>, now: Instant) -> usize {
+    let levels = match aging {
+        Some(aging) if !aging.is_zero() => {
+            now.duration_since(queued.since).as_nanos() / aging.as_nanos()
+        }
+        _ => 0,
+    };
+    lane.saturating_sub(levels.min(lane as u128) as usize)
+}
+
+/// Queue and statistics of a priority.
+#[derive(Default)]
+struct Lane {
+    queue: VecDeque<Queued>,
+    sent: u64,
+    dropped: u64,
+    delay: DelayStats,
+}
+
+/// Schedules the messages a node sends by the priority of their topic.
+pub(crate) struct Scheduler {
+    priorities: Vec<(TopicFilter, Priority)>,
+    limit: Option<UploadLimit>,
+    /// Bytes that may be sent right away, and when they were counted.
+    tokens: f64,
+    updated: Instant,
+    /// Gossipsub bytes sent by the node when last measured, and those taken by the messages
+    /// scheduled since.
+    measured: u64,
+    scheduled: u64,
+    lanes: [Lane; 4],
+}
+
+impl Scheduler {
+    pub fn new(priorities: Vec<(TopicFilter, Priority)>, limit: Option<UploadLimit>) -> Self {
+        Scheduler {
+            priorities,
+            limit,
+            tokens: limit.map_or(0.0, |limit| limit.burst as f64),
+            updated: Instant::now(),
+            measured: 0,
+            scheduled: 0,
+            lanes: Default::default(),
+        }
+    }
+
+    /// How often [`release`](Self::release) should be called, or `None` without a limit.
+    pub fn tick(&self) -> Option<Duration> {
+        self.limit.map(|_| SCHEDULER_TICK)
+    }
+
+    /// The priority of `topic`.
+    pub fn priority(&self, topic: &str) -> Priority {
+        if topic::is_internal(topic) {
+            return Priority::Control;
+        }
+        self.priorities
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .map_or(Priority::Normal, |(_, priority)| *priority)
+    }
+
+    /// Take `data` to send on `topic`, and the `receipts` of its publishers, if it has to wait,
+    /// or give them back to be sent right away.
+    pub fn push(
+        &mut self,
+        topic: &str,
+        data: Bytes,
+        receipts: Receipts,
+    ) -> Option<(Bytes, Receipts)> {
+        self.push_at(topic, data, receipts, Instant::now())
+    }
+
+    /// [`push`](Self::push) at `now`.
+    fn push_at(
+        &mut self,
+        topic: &str,
+        data: Bytes,
+        receipts: Receipts,
+        now: Instant,
+    ) -> Option<(Bytes, Receipts)> {
+        let priority = self.priority(topic);
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                let lane = &mut self.lanes[priority as usize];
+                lane.sent += 1;
+                lane.delay.record(Duration::ZERO);
+                return Some((data, receipts));
+            }
+        };
+        self.refill(now, limit);
+        let waiting = self.lanes.iter().enumerate().any(|(index, lane)| {
+            lane.queue
+                .front()
+                .is_some_and(|queued| aged(index, queued, limit.aging, now) <= priority as usize)
+        });
+        if !waiting && self.tokens > 0.0 {
+            self.take(data.len());
+            let lane = &mut self.lanes[priority as usize];
+            lane.sent += 1;
+            lane.delay.record(Duration::ZERO);
+            return Some((data, receipts));
+        }
+        let lane = &mut self.lanes[priority as usize];
+        if lane.queue.len() >= limit.max_queued.max(1) {
+            lane.queue.pop_front();
+            lane.dropped += 1;
+            log::warn!("dropping a message waiting to be sent on {}", topic);
+        }
+        lane.queue.push_back(Queued {
+            topic: topic.to_owned(),
+            data,
+            receipts,
+            since: now,
+        });
+        None
+    }
+
+    /// Take the messages that may be sent now, the most urgent first, with their topic and the
+    /// receipts of their publishers. `bytes_out` is the gossipsub traffic sent by the node since
+    /// it started.
+    pub fn release(&mut self, bytes_out: u64) -> Vec<(String, Bytes, Receipts)> {
+        self.release_at(bytes_out, Instant::now())
+    }
+
+    /// [`release`](Self::release) at `now`.
+    fn release_at(&mut self, bytes_out: u64, now: Instant) -> Vec<(String, Bytes, Receipts)> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Vec::new(),
+        };
+        self.refill(now, limit);
+        // The traffic not scheduled since the last measure: forwarded and control messages, and
+        // the copies of scheduled ones sent to more than one peer.
+        let unscheduled = bytes_out
+            .saturating_sub(self.measured)
+            .saturating_sub(self.scheduled);
+        self.tokens -= unscheduled as f64;
+        self.measured = bytes_out;
+        self.scheduled = 0;
+        let mut out = Vec::new();
+        while self.tokens > 0.0 {
+            // The head of a queue is its oldest message, and so the most aged.
+            let next = self
+                .lanes
+                .iter()
+                .enumerate()
+                .filter_map(|(index, lane)| {
+                    let queued = lane.queue.front()?;
+                    Some((aged(index, queued, limit.aging, now), queued.since, index))
+                })
+                .min();
+            let priority = match next {
+                Some((_, _, index)) => index,
+                None => break,
+            };
+            let queued = self.lanes[priority]
+                .queue
+                .pop_front()
+                .expect("non-empty queue");
+            self.take(queued.data.len());
+            let lane = &mut self.lanes[priority];
+            lane.sent += 1;
+            lane.delay.record(now.duration_since(queued.since));
+            out.push((queued.topic, queued.data, queued.receipts));
+        }
+        out
+    }
+
+    /// The state of the queue of every priority.
+    pub fn stats(&self) -> Vec<PriorityStats> {
+        Priority::ALL
+            .iter()
+            .zip(&self.lanes)
+            .map(|(priority, lane)| PriorityStats {
+                priority: *priority,
+                queued: lane.queue.len(),
+                sent: lane.sent,
+                dropped: lane.dropped,
+                delay: lane.delay,
+            })
+            .collect()
+    }
+
+    fn refill(&mut self, now: Instant, limit: UploadLimit) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.bytes_per_second as f64).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Take a message of `size` bytes out of the bucket, which may leave it owing.
+    fn take(&mut self, size: usize) {
+        self.tokens -= size as f64;
+        self.scheduled += size as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queue a bulk message behind high priority ones published faster than the upload allows,
+    /// returning after how many ticks the bulk message was sent, if within `ticks`.
+    fn bulk_sent_after(aging: Option<Duration>, ticks: usize) -> Option<usize> {
+        let limit = UploadLimit {
+            bytes_per_second: 10_000,
+            burst: 100,
+            max_queued: 1000,
+            aging,
+        };
+        let priorities = vec![
+            (TopicFilter::new("alerts").unwrap(), Priority::High),
+            (TopicFilter::new("telemetry").unwrap(), Priority::Bulk),
+        ];
+        let mut scheduler = Scheduler::new(priorities, Some(limit));
+        let start = scheduler.updated;
+        let payload = Bytes::from(vec![0; 100]);
+        // Leave the bucket owing, for the bulk message to wait.
+        let alert = vec![0; 1000].into();
+        assert!(scheduler
+            .push_at("alerts", alert, Receipts::default(), start)
+            .is_some());
+        assert!(scheduler
+            .push_at("telemetry", payload.clone(), Receipts::default(), start)
+            .is_none());
+        for tick in 0..ticks {
+            let now = start + SCHEDULER_TICK * tick as u32;
+            for _ in 0..5 {
+                scheduler.push_at("alerts", payload.clone(), Receipts::default(), now);
+            }
+            let sent = scheduler.release_at(0, now + SCHEDULER_TICK / 2);
+            if sent.iter().any(|(topic, _, _)| topic == "telemetry") {
+                return Some(tick);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn aging_sends_bulk_messages_under_sustained_urgent_load() {
+        assert!(bulk_sent_after(Some(Duration::from_millis(20)), 200).is_some());
+    }
+
+    #[test]
+    fn strict_priorities_starve_bulk_messages() {
+        assert_eq!(bulk_sent_after(None, 50), None);
+    }
+}
@@ -0,0 +1,360 @@
+//! Work queues with visibility timeouts, distributing jobs across the workers of a consumer
+//! group.
+//!
+//! Jobs are published on the queue's topic and every consumer group receives each of them. Within
+//! a group, a worker claims a job before processing it, which hides the job from the group's other
+//! workers for the visibility timeout. A job that is not acknowledged before its claim runs out
+//! becomes visible again and is redelivered, possibly to another worker. Like the
+//! [`lock`](crate::lock) module, claims are settled without a consensus round, so delivery is
+//! at least once: a job may be processed twice, e.g. when workers cannot hear each other.
+//!
+//! Gossipsub does not deliver a node's own messages back to it, so jobs are only consumed by
+//! workers running on other nodes than the producer. Workers of a node each have their own id and
+//! hand their claims and acknowledgements to each other directly, so that several of them can
+//! share the jobs of a group. Workers only learn about jobs enqueued while they are running.
+
+use crate::{Client, Error, Subscription};
+use async_std::future::timeout;
+use futures::{channel::mpsc, prelude::*, select};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a worker listens for competing claims before processing a job.
+const CLAIM_SETTLE: Duration = Duration::from_millis(500);
+
+/// The workers of the process, as the topic of their queue, their id, starting with the peer id
+/// of their node, and where to hand them the announcements of their siblings on the same node.
+static LOCAL_WORKERS: Mutex<Vec<(String, String, mpsc::UnboundedSender<Announcement>)>> =
+    Mutex::new(Vec::new());
+
+/// Announcement exchanged on a queue's topic.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Announcement {
+    /// A new job.
+    Enqueue { job: String, data: Vec<u8> },
+    /// `worker` of `group` claims its `attempt`th delivery of `job` for `visibility_ms`.
+    Claim {
+        group: String,
+        job: String,
+        worker: String,
+        attempt: u32,
+        visibility_ms: u64,
+    },
+    /// `job` has been processed by `group`.
+    Ack { group: String, job: String },
+}
+
+fn queue_topic(queue: &str) -> String {
+    format!("pubsub-lite/queue/{}", queue)
+}
+
+/// Publish `data` as a new job on `queue`, returning the id of the job.
+pub(crate) fn enqueue(client: &Client, queue: &str, data: Vec<u8>) -> Result<String, Error> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let job = format!(
+        "{}-{}-{}",
+        client.local_peer_id().to_base58(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let announcement = Announcement::Enqueue {
+        job: job.clone(),
+        data,
+    };
+    client.publish(&queue_topic(queue), serde_json::to_vec(&announcement)?)?;
+    Ok(job)
+}
+
+/// The current claim on a job.
+struct Claim {
+    worker: String,
+    attempt: u32,
+    expires: Instant,
+}
+
+/// Local view of a single job, built from the announcements heard on the queue's topic.
+struct JobState {
+    /// Payload of the job. Missing when a claim was heard before the job itself.
+    data: Option<Vec<u8>>,
+    /// Order in which jobs were received, to hand them out first in, first out.
+    received: u64,
+    claim: Option<Claim>,
+    acked_at: Option<Instant>,
+}
+
+impl JobState {
+    fn is_visible(&self, now: Instant) -> bool {
+        self.data.is_some()
+            && self.acked_at.is_none()
+            && self.claim.as_ref().is_none_or(|claim| claim.expires <= now)
+    }
+
+    fn is_claimed_by(&self, worker: &str, attempt: u32, now: Instant) -> bool {
+        self.acked_at.is_none()
+            && self.claim.as_ref().is_some_and(|claim| {
+                claim.worker == worker && claim.attempt == attempt && claim.expires > now
+            })
+    }
+}
+
+/// A job handed out to a worker.
+#[derive(Clone, Debug)]
+pub struct Job {
+    id: String,
+    data: Vec<u8>,
+    attempt: u32,
+}
+
+impl Job {
+    /// Id of the job, unique across the mesh.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Payload of the job.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// How many times the job has been delivered to the group, starting at 1.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+/// A worker of a consumer group, returned by [`Client::consume`].
+pub struct Consumer {
+    client: Client,
+    topic: String,
+    group: String,
+    worker: String,
+    visibility_timeout: Duration,
+    subscription: Subscription,
+    /// Announcements of the other workers of the node on the queue.
+    siblings: mpsc::UnboundedReceiver<Announcement>,
+    jobs: HashMap<String, JobState>,
+    received: u64,
+}
+
+impl Consumer {
+    pub(crate) fn new(
+        client: &Client,
+        queue: &str,
+        group: &str,
+        visibility_timeout: Duration,
+    ) -> Result<Self, Error> {
+        static NEXT_WORKER: AtomicU64 = AtomicU64::new(1);
+        let topic = queue_topic(queue);
+        let subscription = client.subscribe(&topic)?;
+        let worker = format!(
+            "{}/{}",
+            client.local_peer_id().to_base58(),
+            NEXT_WORKER.fetch_add(1, Ordering::Relaxed)
+        );
+        let (sender, siblings) = mpsc::unbounded();
+        LOCAL_WORKERS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((topic.clone(), worker.clone(), sender));
+        Ok(Consumer {
+            client: client.clone(),
+            subscription,
+            siblings,
+            topic,
+            group: group.to_owned(),
+            worker,
+            visibility_timeout,
+            jobs: HashMap::new(),
+            received: 0,
+        })
+    }
+
+    /// Wait for the next job visible to the group and claim it. The job is hidden from the
+    /// group's other workers until it is acknowledged or the visibility timeout runs out.
+    pub async fn next(&mut self) -> Result<Job, Error> {
+        loop {
+            self.prune();
+            let now = Instant::now();
+            let visible = self
+                .jobs
+                .iter()
+                .filter(|(_, job)| job.is_visible(now))
+                .min_by_key(|(_, job)| job.received)
+                .map(|(id, job)| (id.clone(), job.claim.as_ref().map_or(0, |c| c.attempt)));
+            let (id, previous_attempt) = match visible {
+                Some(visible) => visible,
+                None => {
+                    // Wake up when a claim expires, if nothing else arrives before.
+                    let deadline = self
+                        .jobs
+                        .values()
+                        .filter_map(|job| job.claim.as_ref().map(|claim| claim.expires))
+                        .filter(|expires| *expires > now)
+                        .min()
+                        .unwrap_or(now + self.visibility_timeout);
+                    self.observe_until(deadline, true).await?;
+                    continue;
+                }
+            };
+
+            let attempt = previous_attempt + 1;
+            self.announce(Announcement::Claim {
+                group: self.group.clone(),
+                job: id.clone(),
+                worker: self.worker.clone(),
+                attempt,
+                visibility_ms: self.visibility_timeout.as_millis() as u64,
+            })?;
+            // Give concurrent claimants the chance to be heard before handing the job out.
+            self.observe_until(Instant::now() + CLAIM_SETTLE, false)
+                .await?;
+            if let Some(job) = self.jobs.get(&id) {
+                if job.is_claimed_by(&self.worker, attempt, Instant::now()) {
+                    return Ok(Job {
+                        id,
+                        data: job.data.clone().unwrap_or_default(),
+                        attempt,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Acknowledge that `job` has been processed, so that it is never delivered to the group
+    /// again.
+    pub fn ack(&mut self, job: &Job) -> Result<(), Error> {
+        self.announce(Announcement::Ack {
+            group: self.group.clone(),
+            job: job.id.clone(),
+        })
+    }
+
+    /// Publish an announcement and apply it right away, handing it to the other workers of the
+    /// process too: the node hands our own messages back to us only with local delivery on, and
+    /// only after the announcement returns.
+    fn announce(&mut self, announcement: Announcement) -> Result<(), Error> {
+        self.client
+            .publish(&self.topic, serde_json::to_vec(&announcement)?)?;
+        let local_peer = format!("{}/", self.client.local_peer_id().to_base58());
+        let local = LOCAL_WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, _, sibling) in local.iter().filter(|(topic, worker, _)| {
+            *topic == self.topic && worker.starts_with(&local_peer) && *worker != self.worker
+        }) {
+            let _ = sibling.unbounded_send(announcement.clone());
+        }
+        drop(local);
+        self.apply(announcement);
+        Ok(())
+    }
+
+    fn apply(&mut self, announcement: Announcement) {
+        let now = Instant::now();
+        match announcement {
+            Announcement::Enqueue { job, data } => {
+                let state = self.job_state(job);
+                if state.data.is_none() {
+                    state.data = Some(data);
+                }
+            }
+            Announcement::Claim {
+                group,
+                job,
+                worker,
+                attempt,
+                visibility_ms,
+            } if group == self.group => {
+                let state = self.job_state(job);
+                let accepted = match &state.claim {
+                    None => true,
+                    // A later attempt always wins: its worker saw the earlier claim expire.
+                    Some(claim) if attempt > claim.attempt => true,
+                    // Concurrent claims for the same attempt are settled by the lowest worker id.
+                    Some(claim) if attempt == claim.attempt => worker <= claim.worker,
+                    Some(_) => false,
+                };
+                if accepted {
+                    state.claim = Some(Claim {
+                        worker,
+                        attempt,
+                        expires: now + Duration::from_millis(visibility_ms),
+                    });
+                }
+            }
+            Announcement::Ack { group, job } if group == self.group => {
+                self.job_state(job).acked_at.get_or_insert(now);
+            }
+            Announcement::Claim { .. } | Announcement::Ack { .. } => {}
+        }
+    }
+
+    fn job_state(&mut self, job: String) -> &mut JobState {
+        let received = &mut self.received;
+        self.jobs.entry(job).or_insert_with(|| {
+            *received += 1;
+            JobState {
+                data: None,
+                received: *received,
+                claim: None,
+                acked_at: None,
+            }
+        })
+    }
+
+    /// Forget acknowledged jobs once late announcements about them are unlikely, and claims
+    /// heard for jobs that never arrived.
+    fn prune(&mut self) {
+        let now = Instant::now();
+        let retention = self.visibility_timeout * 2;
+        self.jobs.retain(|_, job| match (job.acked_at, &job.claim) {
+            (Some(acked_at), _) => now.duration_since(acked_at) < retention,
+            (None, Some(claim)) if job.data.is_none() => claim.expires + retention > now,
+            _ => true,
+        });
+    }
+
+    /// Apply every announcement received until `deadline`. When `until_visible` is set, return
+    /// early as soon as a job becomes visible.
+    async fn observe_until(&mut self, deadline: Instant, until_visible: bool) -> Result<(), Error> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            let (subscription, siblings) = (&mut self.subscription, &mut self.siblings);
+            let next = async move {
+                select! {
+                    message = subscription.next().fuse() => {
+                        message.map(|message| serde_json::from_slice(&message.data))
+                    }
+                    announcement = siblings.next().fuse() => announcement.map(Ok),
+                }
+            };
+            match timeout(deadline - now, next).await {
+                Ok(Some(Ok(announcement))) => self.apply(announcement),
+                Ok(Some(Err(e))) => log::warn!("ignoring malformed queue announcement: {}", e),
+                Ok(None) => return Err(Error::Shutdown),
+                Err(_) => return Ok(()),
+            }
+            if until_visible && self.jobs.values().any(|job| job.is_visible(Instant::now())) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        let mut local = LOCAL_WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+        local.retain(|(_, worker, _)| *worker != self.worker);
+    }
+}
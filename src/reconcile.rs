@@ -0,0 +1,364 @@
+//! Declarative management of a node: [`Client::reconcile`](crate::Client::reconcile) brings the
+//! node to a [`DesiredState`] and reports what it changed, so that fleets can be driven from
+//! documents kept under version control.
+//!
+//! Reconciling is idempotent: only the differences between the desired state and the state left
+//! by the previous reconciliation are applied, and reconciling the same document twice changes
+//! nothing the second time. Only what the document describes is managed; subscriptions,
+//! bridges and plugins set up through the other APIs of the node are left alone.
+//!
+//! A document is JSON:
+//!
+//! ```json
+//! {
+//!     "topics": ["sensors", "alerts"],
+//!     "peers": ["/dnsaddr/bootstrap.example.com/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN"],
+//!     "bridges": {
+//!         "plant": {"mqtt": {"broker": "10.0.0.5:1883", "routes": [
+//!             {"mqtt_topic": "plant/alerts", "gossipsub_topic": "alerts", "direction": "inbound"}
+//!         ]}},
+//!         "ops": {"nats": {"server": "10.0.0.6:4222", "rule": [{"topic": "alerts"}]}}
+//!     },
+//!     "quota": {"bytes_per_second": 1048576, "burst": 4194304, "graylist_secs": 60}
+//! }
+//! ```
+//!
+//! Bridges are named, a bridge whose configuration changed being restarted. Their
+//! configurations are those of [`MqttConfig`](crate::bridge::mqtt::MqttConfig),
+//! [`MulticastConfig`](crate::bridge::multicast::MulticastConfig) and
+//! [`NatsConfig`](crate::bridge::nats::NatsConfig), behind the cargo features of the same name.
+//!
+//! [`Client::watch_config`](crate::Client::watch_config) keeps a node reconciled with a document
+//! on disk, applying it again whenever it is edited, every [`WATCH_INTERVAL`] at most, so that the
+//! topics, peers, bridges and quota of a node change without restarting it and losing its mesh.
+
+#[cfg(feature = "mqtt")]
+use crate::bridge::mqtt::{self, MqttConfig};
+#[cfg(feature = "multicast")]
+use crate::bridge::multicast::{self, MulticastConfig};
+#[cfg(feature = "nats")]
+use crate::bridge::nats::{self, NatsConfig};
+use crate::{
+    bandwidth::RateLimit, node::Command, transport::parse_legacy_multiaddr, Client, Error,
+};
+#[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+use crate::{flow::Closer, topology};
+use async_std::{fs, stream, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    prelude::*,
+    select,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// How often [`Client::watch_config`](crate::Client::watch_config) checks whether the document
+/// changed.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What a node should be running.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DesiredState {
+    /// Topics the node subscribes to, so that it joins and relays their mesh.
+    pub topics: Vec<String>,
+    /// Addresses of peers to dial, such as bootstrap peers, legacy `/ipfs/` ones included. A peer
+    /// is dialed once added; removing it forgets the address but keeps any connection to it.
+    pub peers: Vec<String>,
+    /// Bridges to run, by name.
+    #[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+    pub bridges: BTreeMap<String, BridgeSpec>,
+    /// Rate limit of the gossipsub traffic of each peer, replacing that of the node
+    /// configuration. Without it, the configured one applies.
+    pub quota: Option<Quota>,
+}
+
+/// A bridge, by kind.
+#[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeSpec {
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttConfig),
+    #[cfg(feature = "multicast")]
+    Multicast(MulticastConfig),
+    #[cfg(feature = "nats")]
+    Nats(NatsConfig),
+}
+
+#[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+impl BridgeSpec {
+    /// Start the bridge, returning the handle stopping it.
+    fn start(self, client: &Client) -> Result<Closer, Error> {
+        match self {
+            #[cfg(feature = "mqtt")]
+            BridgeSpec::Mqtt(config) => Ok(mqtt::start(client, config)?.1),
+            #[cfg(feature = "multicast")]
+            BridgeSpec::Multicast(config) => Ok(multicast::start(client, config)?.1),
+            #[cfg(feature = "nats")]
+            BridgeSpec::Nats(config) => Ok(nats::start(client, config)?.1),
+        }
+    }
+
+    /// Hash of the configuration, the one listed in the topology of the node once the bridge
+    /// runs.
+    fn config_hash(&self) -> String {
+        match self {
+            #[cfg(feature = "mqtt")]
+            BridgeSpec::Mqtt(config) => topology::config_hash(config),
+            #[cfg(feature = "multicast")]
+            BridgeSpec::Multicast(config) => topology::config_hash(config),
+            #[cfg(feature = "nats")]
+            BridgeSpec::Nats(config) => topology::config_hash(config),
+        }
+    }
+}
+
+/// Token bucket limiting the gossipsub traffic each peer sends, see
+/// [`RateLimit`](crate::bandwidth::RateLimit).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+    /// How many seconds a peer overflowing its bucket is ignored.
+    pub graylist_secs: u64,
+}
+
+impl From<Quota> for RateLimit {
+    fn from(quota: Quota) -> Self {
+        RateLimit {
+            bytes_per_second: quota.bytes_per_second,
+            burst: quota.burst,
+            graylist: Duration::from_secs(quota.graylist_secs),
+        }
+    }
+}
+
+/// What reconciling changed, or would change.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StateDiff {
+    pub subscribed: Vec<String>,
+    pub unsubscribed: Vec<String>,
+    /// Peer addresses, as written in the document.
+    pub peers_added: Vec<String>,
+    pub peers_removed: Vec<String>,
+    /// Bridges by name.
+    pub bridges_started: Vec<String>,
+    pub bridges_stopped: Vec<String>,
+    /// Bridges whose configuration changed.
+    pub bridges_restarted: Vec<String>,
+    /// The quota now applying, if it changed, `None` standing for the configured rate limit.
+    pub quota: Option<Option<Quota>>,
+}
+
+impl StateDiff {
+    /// Whether the node already was in the desired state.
+    pub fn is_empty(&self) -> bool {
+        *self == StateDiff::default()
+    }
+}
+
+/// The state a node was brought to by reconciling.
+#[derive(Default)]
+pub(crate) struct Managed {
+    /// Topics subscribed to, each with the handle stopping the task draining its subscription.
+    topics: BTreeMap<String, oneshot::Sender<()>>,
+    /// Addresses of the peers dialed.
+    peers: Vec<String>,
+    /// Running bridges, with the hash of their configuration.
+    #[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+    bridges: BTreeMap<String, (String, Closer)>,
+    quota: Option<Quota>,
+}
+
+impl Managed {
+    /// Work out what bringing the node to `desired` changes, applying it if `apply`. Stops at the
+    /// first failure, what was applied until then staying applied.
+    pub(crate) fn reconcile(
+        &mut self,
+        client: &Client,
+        desired: &DesiredState,
+        apply: bool,
+    ) -> Result<StateDiff, Error> {
+        let mut diff = StateDiff::default();
+
+        let stale: Vec<String> = self
+            .topics
+            .keys()
+            .filter(|topic| !desired.topics.contains(topic))
+            .cloned()
+            .collect();
+        for topic in stale {
+            if apply {
+                self.topics.remove(&topic);
+            }
+            diff.unsubscribed.push(topic);
+        }
+        for topic in &desired.topics {
+            if self.topics.contains_key(topic) || diff.subscribed.contains(topic) {
+                continue;
+            }
+            if apply {
+                let subscription = client.subscribe(topic)?;
+                let (stop, stopped) = oneshot::channel();
+                task::spawn(future::select(
+                    subscription.for_each(|_| future::ready(())),
+                    stopped,
+                ));
+                self.topics.insert(topic.clone(), stop);
+            }
+            diff.subscribed.push(topic.clone());
+        }
+
+        for peer in &self.peers {
+            if !desired.peers.contains(peer) {
+                diff.peers_removed.push(peer.clone());
+            }
+        }
+        if apply {
+            self.peers.retain(|peer| desired.peers.contains(peer));
+        }
+        for peer in &desired.peers {
+            if self.peers.contains(peer) || diff.peers_added.contains(peer) {
+                continue;
+            }
+            let addr = parse_legacy_multiaddr(peer)?;
+            if apply {
+                client.send(Command::Dial { addr })?;
+                self.peers.push(peer.clone());
+            }
+            diff.peers_added.push(peer.clone());
+        }
+
+        #[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+        self.reconcile_bridges(client, desired, apply, &mut diff)?;
+
+        if self.quota != desired.quota {
+            if apply {
+                client.send(Command::SetRateLimit {
+                    rate_limit: desired.quota.map(RateLimit::from),
+                })?;
+                self.quota = desired.quota;
+            }
+            diff.quota = Some(desired.quota);
+        }
+        Ok(diff)
+    }
+
+    #[cfg(any(feature = "mqtt", feature = "multicast", feature = "nats"))]
+    fn reconcile_bridges(
+        &mut self,
+        client: &Client,
+        desired: &DesiredState,
+        apply: bool,
+        diff: &mut StateDiff,
+    ) -> Result<(), Error> {
+        let stale: Vec<String> = self
+            .bridges
+            .keys()
+            .filter(|name| !desired.bridges.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in stale {
+            if apply {
+                if let Some((_, closer)) = self.bridges.remove(&name) {
+                    closer.close();
+                }
+            }
+            diff.bridges_stopped.push(name);
+        }
+        for (name, spec) in &desired.bridges {
+            let hash = spec.config_hash();
+            let restart = match self.bridges.get(name) {
+                Some((running, _)) if *running == hash => continue,
+                Some(_) => true,
+                None => false,
+            };
+            if apply {
+                if let Some((_, closer)) = self.bridges.remove(name) {
+                    closer.close();
+                }
+                let closer = spec.clone().start(client)?;
+                self.bridges.insert(name.clone(), (hash, closer));
+            }
+            if restart {
+                diff.bridges_restarted.push(name.clone());
+            } else {
+                diff.bridges_started.push(name.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stream of the outcomes of reloading a document, returned by
+/// [`Client::watch_config`](crate::Client::watch_config): what every load changed, or why it
+/// failed, the node then staying as the failure left it. Watching stops once the stream is
+/// dropped.
+pub struct ConfigWatch {
+    receiver: mpsc::UnboundedReceiver<Result<StateDiff, Error>>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl Stream for ConfigWatch {
+    type Item = Result<StateDiff, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for ConfigWatch {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Reconcile `client` with the document at `path` now and whenever it changes, until `stop`
+/// fires.
+pub(crate) fn watch(client: &Client, path: PathBuf) -> ConfigWatch {
+    let (outcomes, receiver) = mpsc::unbounded();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let client = client.clone();
+    task::spawn(async move {
+        let mut checks = stream::interval(WATCH_INTERVAL);
+        let mut stopped = stopped.fuse();
+        let mut loaded = None;
+        loop {
+            match fs::read(&path).await {
+                Ok(document) if loaded.as_ref() != Some(&document) => {
+                    let outcome = serde_json::from_slice(&document)
+                        .map_err(Error::from)
+                        .and_then(|desired| client.reconcile(&desired));
+                    if let Err(e) = &outcome {
+                        log::warn!("failed to apply {}: {}", path.display(), e);
+                    }
+                    loaded = Some(document);
+                    if outcomes.unbounded_send(outcome).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("failed to read {}: {}", path.display(), e),
+            }
+            select! {
+                _ = checks.next().fuse() => {}
+                _ = stopped => return,
+            }
+        }
+    });
+    ConfigWatch {
+        receiver,
+        stop: Some(stop),
+    }
+}
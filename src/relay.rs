@@ -0,0 +1,483 @@
+//! Circuit relay, so that peers that cannot be dialed, e.g. from behind a symmetric NAT, still
+//! join the mesh through a relay that can.
+//!
+//! A node given [`NodeConfig::relays`](crate::NodeConfig::relays) holds a slot at each of them
+//! over a connection it keeps open, and listens on `/ip4/<relay>/tcp/<port>/p2p-circuit/p2p/<peer
+//! id>`, which its peers learn through identify. Dialing that address connects to the relay,
+//! which asks the node for a connection back and splices the two. The relayed connection is then
+//! secured and multiplexed end to end like any other, so the relay sees neither the messages nor
+//! the swarm key of a private network, and cannot pass for the peer dialed.
+//!
+//! A node runs a relay, on a port of its own, when given
+//! [`NodeConfig::relay_server`](crate::NodeConfig::relay_server). libp2p 0.16 implements no
+//! circuit relay, so the protocol is that of this crate, spoken by its nodes only. It is
+//! line-based:
+//!
+//! - a node takes a slot with `LISTEN <peer id>`, answered with `OK` or `ERR <reason>`. It is then
+//!   sent `PING` every [`PING_INTERVAL`], and `INCOMING <token>` for every peer dialing it, which
+//!   it accepts by opening a new connection with `ACCEPT <token>`. The slot of a peer id goes to
+//!   the last node claiming it, an impostor only getting connections that fail their handshake;
+//! - a peer dials with `CONNECT <peer id>`, answered with `OK` once the node dialed accepted, or
+//!   `ERR <reason>`.
+//!
+//! Past `ACCEPT` and `OK`, the connections carry the bytes of the relayed connection.
+
+use crate::{
+    topology::{self, ComponentKind, Registration},
+    Client, Error,
+};
+use async_std::{
+    io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    stream, task,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, BoxFuture},
+    prelude::*,
+    select,
+    stream::BoxStream,
+};
+use libp2p::{
+    core::transport::{ListenerEvent, TransportError},
+    multiaddr::Protocol,
+    Multiaddr, PeerId, Transport,
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How often a relay pings the nodes holding a slot, keeping their NAT mappings open.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the handshake of a connection to or from a relay may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a node waits before taking its slot at a relay again after losing it.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Upper bound on the length of a line of the relay protocol.
+const MAX_LINE: usize = 128;
+
+/// The relay a node runs, see [`NodeConfig::relay_server`](crate::NodeConfig::relay_server).
+#[derive(Clone, Debug)]
+pub struct RelayServerConfig {
+    /// Address to accept relay connections on.
+    pub listen_addr: SocketAddr,
+    /// Maximum number of nodes holding a slot at once.
+    pub max_reservations: usize,
+    /// Maximum number of connections relayed at once, those waiting to be accepted included.
+    pub max_circuits: usize,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        RelayServerConfig {
+            listen_addr: ([0, 0, 0, 0], 4002).into(),
+            max_reservations: 128,
+            max_circuits: 512,
+        }
+    }
+}
+
+/// Transport dialing and listening on circuit addresses, see the [module documentation](self).
+#[derive(Clone)]
+pub struct RelayTransport {
+    local_peer_id: PeerId,
+}
+
+impl RelayTransport {
+    pub fn new(local_peer_id: PeerId) -> Self {
+        RelayTransport { local_peer_id }
+    }
+}
+
+impl Transport for RelayTransport {
+    type Output = TcpStream;
+    type Error = io::Error;
+    type Listener =
+        BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, io::Error>, io::Error>>;
+    type ListenerUpgrade = BoxFuture<'static, io::Result<TcpStream>>;
+    type Dial = BoxFuture<'static, io::Result<TcpStream>>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<io::Error>> {
+        let relay = match parse(&addr) {
+            Some((relay, None)) => relay,
+            _ => return Err(TransportError::MultiaddrNotSupported(addr)),
+        };
+        let (events, listener) = mpsc::unbounded();
+        task::spawn(listen(relay, addr, self.local_peer_id, events));
+        Ok(listener.map(Ok).boxed())
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<io::Error>> {
+        match parse(&addr) {
+            Some((relay, Some(peer))) => Ok(dial(relay, peer).boxed()),
+            _ => Err(TransportError::MultiaddrNotSupported(addr)),
+        }
+    }
+}
+
+/// Split a circuit address into the address of its relay and the peer it leads to, if any.
+fn parse(addr: &Multiaddr) -> Option<(SocketAddr, Option<PeerId>)> {
+    let mut protocols = addr.iter();
+    let ip = match protocols.next()? {
+        Protocol::Ip4(ip) => IpAddr::from(ip),
+        Protocol::Ip6(ip) => IpAddr::from(ip),
+        _ => return None,
+    };
+    let port = match protocols.next()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    if protocols.next()? != Protocol::P2pCircuit {
+        return None;
+    }
+    let peer = match protocols.next() {
+        Some(Protocol::P2p(hash)) => Some(PeerId::from_multihash(hash).ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    if protocols.next().is_some() {
+        return None;
+    }
+    Some((SocketAddr::new(ip, port), peer))
+}
+
+/// Whether `addr` goes through a relay.
+pub(crate) fn is_circuit(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| protocol == Protocol::P2pCircuit)
+}
+
+type ListenerSender =
+    mpsc::UnboundedSender<ListenerEvent<BoxFuture<'static, io::Result<TcpStream>>, io::Error>>;
+
+/// Hold a slot at `relay`, taking it again whenever it is lost, and report the connections
+/// relayed to this node to `events` until the listener is dropped.
+async fn listen(relay: SocketAddr, addr: Multiaddr, local_peer_id: PeerId, events: ListenerSender) {
+    let listen_addr = addr
+        .clone()
+        .with(Protocol::P2p(local_peer_id.clone().into()));
+    loop {
+        match io::timeout(HANDSHAKE_TIMEOUT, reserve(relay, &local_peer_id)).await {
+            Ok(control) => {
+                log::info!("listening through relay {}", relay);
+                if events
+                    .unbounded_send(ListenerEvent::NewAddress(listen_addr.clone()))
+                    .is_err()
+                {
+                    return;
+                }
+                let result = follow(control, relay, &addr, &listen_addr, &events).await;
+                if events.is_closed() {
+                    return;
+                }
+                if let Err(e) = result {
+                    log::warn!("lost slot at relay {}: {}", relay, e);
+                }
+                let _ = events.unbounded_send(ListenerEvent::AddressExpired(listen_addr.clone()));
+            }
+            Err(e) => log::warn!("failed to take a slot at relay {}: {}", relay, e),
+        }
+        task::sleep(RECONNECT_INTERVAL).await;
+        if events.is_closed() {
+            return;
+        }
+    }
+}
+
+async fn reserve(relay: SocketAddr, local_peer_id: &PeerId) -> io::Result<TcpStream> {
+    let mut control = TcpStream::connect(relay).await?;
+    write_line(
+        &mut control,
+        &format!("LISTEN {}", local_peer_id.to_base58()),
+    )
+    .await?;
+    expect_ok(&mut control).await?;
+    Ok(control)
+}
+
+/// Follow the control connection of a slot, until the relay drops it or the listener is
+/// dropped.
+async fn follow(
+    mut control: TcpStream,
+    relay: SocketAddr,
+    addr: &Multiaddr,
+    listen_addr: &Multiaddr,
+    events: &ListenerSender,
+) -> io::Result<()> {
+    loop {
+        let line = io::timeout(PING_INTERVAL * 2, read_line(&mut control)).await?;
+        if events.is_closed() {
+            return Ok(());
+        }
+        match line.split_once(' ') {
+            _ if line == "PING" => {}
+            Some(("INCOMING", token)) => {
+                let token = token.to_owned();
+                let upgrade = async move {
+                    let mut stream = TcpStream::connect(relay).await?;
+                    stream.set_nodelay(true)?;
+                    write_line(&mut stream, &format!("ACCEPT {}", token)).await?;
+                    Ok(stream)
+                };
+                let _ = events.unbounded_send(ListenerEvent::Upgrade {
+                    upgrade: upgrade.boxed(),
+                    local_addr: listen_addr.clone(),
+                    remote_addr: addr.clone(),
+                });
+            }
+            _ => return Err(invalid_data(&line)),
+        }
+    }
+}
+
+async fn dial(relay: SocketAddr, peer: PeerId) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(relay).await?;
+    stream.set_nodelay(true)?;
+    write_line(&mut stream, &format!("CONNECT {}", peer.to_base58())).await?;
+    expect_ok(&mut stream).await?;
+    Ok(stream)
+}
+
+async fn expect_ok(stream: &mut TcpStream) -> io::Result<()> {
+    let line = read_line(stream).await?;
+    match line.strip_prefix("ERR ") {
+        _ if line == "OK" => Ok(()),
+        Some(reason) => Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason)),
+        None => Err(invalid_data(&line)),
+    }
+}
+
+/// Read a line of the relay protocol, byte by byte so as not to read past it.
+async fn read_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).await?;
+        match byte[0] {
+            b'\n' => break,
+            _ if line.len() == MAX_LINE => return Err(invalid_data("line too long")),
+            byte => line.push(byte),
+        }
+    }
+    String::from_utf8(line).map_err(|_| invalid_data("line not UTF-8"))
+}
+
+async fn write_line(stream: &mut TcpStream, line: &str) -> io::Result<()> {
+    stream.write_all(format!("{}\n", line).as_bytes()).await
+}
+
+fn invalid_data(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected relay line: {}", line),
+    )
+}
+
+/// The state of a relay.
+struct Relay {
+    config: RelayServerConfig,
+    /// Nodes holding a slot, by peer id, each with the id of its slot and where to send the
+    /// tokens of the connections relayed to it.
+    reservations: HashMap<String, (u64, mpsc::UnboundedSender<u64>)>,
+    /// Dialing peers waiting for the node dialed to accept, by token.
+    pending: HashMap<u64, oneshot::Sender<TcpStream>>,
+    /// Connections relayed, those waiting to be accepted included.
+    circuits: usize,
+    next_id: u64,
+}
+
+impl Relay {
+    /// Give `peer` a slot, returning its id, unless every slot is taken.
+    fn reserve(&mut self, peer: &str, tokens: mpsc::UnboundedSender<u64>) -> Option<u64> {
+        if !self.reservations.contains_key(peer)
+            && self.reservations.len() >= self.config.max_reservations
+        {
+            return None;
+        }
+        self.next_id += 1;
+        self.reservations
+            .insert(peer.to_owned(), (self.next_id, tokens));
+        Some(self.next_id)
+    }
+
+    /// Free the slot `id` of `peer`, unless another node took it since.
+    fn release(&mut self, peer: &str, id: u64) {
+        if self
+            .reservations
+            .get(peer)
+            .is_some_and(|(held, _)| *held == id)
+        {
+            self.reservations.remove(peer);
+        }
+    }
+
+    /// Ask the node holding the slot of `peer` to accept a connection, returning its token and
+    /// where the connection it opens arrives.
+    fn open(&mut self, peer: &str) -> Result<(u64, oneshot::Receiver<TcpStream>), &'static str> {
+        if self.circuits >= self.config.max_circuits {
+            return Err("too many circuits");
+        }
+        let (_, tokens) = self.reservations.get(peer).ok_or("peer holds no slot")?;
+        let token = self.next_id + 1;
+        tokens
+            .unbounded_send(token)
+            .map_err(|_| "peer holds no slot")?;
+        self.next_id = token;
+        let (sender, accepted) = oneshot::channel();
+        self.pending.insert(token, sender);
+        self.circuits += 1;
+        Ok((token, accepted))
+    }
+}
+
+/// Start the relay of a node on a background task. It is listed in the topology of the node for
+/// as long as it runs.
+pub(crate) fn spawn(client: &Client, config: RelayServerConfig) -> Result<(), Error> {
+    let listener = TcpListener::from(std::net::TcpListener::bind(config.listen_addr)?);
+    let local_addr = listener.local_addr()?;
+    log::info!("circuit relay listening on {}", local_addr);
+    let registration = Registration::register(
+        client,
+        ComponentKind::Transport,
+        format!("circuit relay on {}", local_addr),
+        topology::config_hash(&config),
+    )?;
+    let relay = Arc::new(Mutex::new(Relay {
+        config,
+        reservations: HashMap::new(),
+        pending: HashMap::new(),
+        circuits: 0,
+        next_id: 0,
+    }));
+    task::spawn(async move {
+        let _registration = registration;
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    task::spawn(handle(relay.clone(), stream));
+                }
+                Err(e) => log::warn!("circuit relay: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle(relay: Arc<Mutex<Relay>>, mut stream: TcpStream) {
+    let peer_addr = stream.peer_addr();
+    let result = async {
+        stream.set_nodelay(true)?;
+        let line = io::timeout(HANDSHAKE_TIMEOUT, read_line(&mut stream)).await?;
+        match line.split_once(' ') {
+            Some(("LISTEN", peer)) => hold(&relay, stream, peer).await,
+            Some(("CONNECT", peer)) => connect(&relay, stream, peer).await,
+            Some(("ACCEPT", token)) => {
+                let token = token.parse().map_err(|_| invalid_data(&line))?;
+                let pending = relay.lock().unwrap().pending.remove(&token);
+                match pending {
+                    Some(pending) => {
+                        let _ = pending.send(stream);
+                        Ok(())
+                    }
+                    None => Err(invalid_data(&line)),
+                }
+            }
+            _ => Err(invalid_data(&line)),
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        log::debug!("circuit relay connection from {:?}: {}", peer_addr, e);
+    }
+}
+
+/// Hold the slot of `peer` for the node on `control`, until it disconnects or another node
+/// takes the slot.
+async fn hold(relay: &Mutex<Relay>, mut control: TcpStream, peer: &str) -> io::Result<()> {
+    let (tokens, mut incoming) = mpsc::unbounded();
+    let reservation = relay.lock().unwrap().reserve(peer, tokens);
+    let id = match reservation {
+        Some(id) => id,
+        None => return write_line(&mut control, "ERR no slot left").await,
+    };
+    log::debug!("{} holds a slot", peer);
+    let mut pings = stream::interval(PING_INTERVAL);
+    let reader = control.clone();
+    let closed = async move {
+        // Nodes send nothing on their control connection once they hold a slot.
+        let mut byte = [0];
+        let _ = (&reader).read(&mut byte).await;
+    }
+    .fuse();
+    futures::pin_mut!(closed);
+    let result = async {
+        write_line(&mut control, "OK").await?;
+        loop {
+            let line = select! {
+                token = incoming.next() => match token {
+                    Some(token) => format!("INCOMING {}", token),
+                    None => return Ok(()),
+                },
+                _ = pings.next().fuse() => "PING".to_owned(),
+                _ = closed => return Ok(()),
+            };
+            write_line(&mut control, &line).await?;
+        }
+    }
+    .await;
+    relay.lock().unwrap().release(peer, id);
+    log::debug!("{} released its slot", peer);
+    result
+}
+
+/// Relay the connection of a peer dialing `peer` on `stream`.
+async fn connect(relay: &Mutex<Relay>, mut stream: TcpStream, peer: &str) -> io::Result<()> {
+    let opened = relay.lock().unwrap().open(peer);
+    let (token, accepted) = match opened {
+        Ok(opened) => opened,
+        Err(reason) => return write_line(&mut stream, &format!("ERR {}", reason)).await,
+    };
+    let result = async {
+        let accepted = io::timeout(HANDSHAKE_TIMEOUT, async {
+            accepted
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "not accepted"))
+        })
+        .await;
+        match accepted {
+            Ok(other) => {
+                write_line(&mut stream, "OK").await?;
+                splice(stream, other).await
+            }
+            Err(e) => {
+                write_line(&mut stream, &format!("ERR {}", e)).await?;
+                Err(e)
+            }
+        }
+    }
+    .await;
+    let mut relay = relay.lock().unwrap();
+    relay.pending.remove(&token);
+    relay.circuits -= 1;
+    result
+}
+
+/// Copy bytes both ways between two connections, until either is closed.
+async fn splice(a: TcpStream, b: TcpStream) -> io::Result<()> {
+    let (mut a_in, mut a_out, mut b_in, mut b_out) = (&a, &a, &b, &b);
+    let forward = io::copy(&mut a_in, &mut b_out);
+    let backward = io::copy(&mut b_in, &mut a_out);
+    futures::pin_mut!(forward, backward);
+    let result = match future::select(forward, backward).await {
+        future::Either::Left((result, _)) | future::Either::Right((result, _)) => result,
+    };
+    let _ = a.shutdown(Shutdown::Both);
+    let _ = b.shutdown(Shutdown::Both);
+    result.map(|_| ())
+}
@@ -0,0 +1,333 @@
+//! Replay protection: rejecting the signed or sealed messages an attacker captured and sends
+//! again, once gossip caches have forgotten them.
+//!
+//! Gossipsub only drops the copies of a message it receives within its duplicate cache, for a
+//! few minutes at most: the same message replayed later is delivered anew, even if it is signed
+//! by its [organisation](crate::delegation) or sealed with the [key of its topic](crate::crypto),
+//! neither of which tells when it was published. The messages a node publishes on a topic with a
+//! [`ReplayPolicy`] carry a nonce, in an envelope: a marker, then the big-endian `u64` time of
+//! publication in microseconds since the Unix epoch, made to increase strictly from one message
+//! of the node to the next. The envelope goes inside signatures and encryption, so that it
+//! cannot be changed without breaking them, and inside batches, so that every payload has one.
+//!
+//! A node receiving messages on a topic with a policy tracks the nonces of every publisher, the
+//! key of its organisation certificate if the message is signed, its peer id otherwise, and
+//! rejects the messages:
+//!
+//! - without a nonce;
+//! - with a nonce older than [`max_age`](ReplayPolicy::max_age) by its own clock, so that
+//!   publishers whose clock is behind by as much are rejected too;
+//! - with a nonce it received already, or further than [`window`](ReplayPolicy::window) behind
+//!   the latest nonce of the publisher on the topic, which lets messages arriving out of order
+//!   within the window through.
+//!
+//! Rejected messages are neither delivered nor forwarded, and count against the peer forwarding
+//! them: a peer forwarding [`max_replays`](ReplayPolicy::max_replays) replays within
+//! [`max_age`](ReplayPolicy::max_age) is disconnected and banned for the
+//! [`ban`](crate::liveness::EvictionPolicy::ban) of the eviction policy. Peers not protecting a
+//! topic themselves forward its replays like any message, and may get banned for them too.
+//!
+//! The nonces of a publisher are forgotten once it has been silent for
+//! [`max_age`](ReplayPolicy::max_age), when its replays are too old anyway; those of a node that
+//! restarted are too, so that the messages published within [`max_age`](ReplayPolicy::max_age)
+//! before the restart can be replayed to it once. Only signed or sealed messages are protected
+//! from anyone but their publisher: the envelope of other messages can be rewritten with a new
+//! nonce at will.
+
+use crate::topic::{self, TopicFilter};
+use bytes::Bytes;
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryInto,
+    fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Start of a payload carrying a nonce. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plr";
+
+const HEADER_LEN: usize = MARKER.len() + 8;
+
+/// Nonces remembered per publisher and topic within the window. The oldest are rejected past
+/// that, as if out of the window.
+const MAX_SEEN: usize = 4096;
+
+/// How the messages published on a topic are protected from replays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayPolicy {
+    /// How far behind the latest nonce of a publisher its messages may arrive.
+    pub window: Duration,
+    /// How old the nonce of a message may be.
+    pub max_age: Duration,
+    /// Replays a peer may forward within `max_age` before it is banned. Zero never bans.
+    pub max_replays: u32,
+}
+
+impl Default for ReplayPolicy {
+    fn default() -> Self {
+        ReplayPolicy {
+            window: Duration::from_secs(30),
+            max_age: Duration::from_secs(300),
+            max_replays: 3,
+        }
+    }
+}
+
+/// Why a message was rejected as a replay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Replay {
+    /// The message carries no nonce.
+    Missing,
+    /// The nonce is older than the policy allows.
+    Stale(u64),
+    /// The nonce was received already.
+    Duplicate(u64),
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Replay::Missing => f.write_str("message has no nonce"),
+            Replay::Stale(nonce) => write!(f, "nonce {} is too old", nonce),
+            Replay::Duplicate(nonce) => write!(f, "nonce {} was received already", nonce),
+        }
+    }
+}
+
+/// Take the envelope off a payload carrying a nonce, returning it as is otherwise.
+pub(crate) fn strip(data: Bytes) -> (Option<u64>, Bytes) {
+    if data.len() < HEADER_LEN || !data.starts_with(MARKER) {
+        return (None, data);
+    }
+    let nonce = u64::from_be_bytes(data[MARKER.len()..HEADER_LEN].try_into().unwrap());
+    (Some(nonce), data.slice(HEADER_LEN..))
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+/// Nonces received from a publisher on a topic.
+struct Window {
+    /// Nonces up to this one are rejected.
+    floor: u64,
+    latest: u64,
+    /// Nonces received past the floor.
+    seen: BTreeSet<u64>,
+    max_age: Duration,
+    last: Instant,
+}
+
+/// Replays forwarded by a peer.
+struct Strikes {
+    count: u32,
+    since: Instant,
+}
+
+/// Puts nonces on the messages this node publishes, and checks those of the messages it
+/// receives.
+pub(crate) struct ReplayGuard {
+    policies: Vec<(TopicFilter, ReplayPolicy)>,
+    last_nonce: u64,
+    windows: HashMap<(String, String), Window>,
+    strikes: HashMap<PeerId, Strikes>,
+    offenders: Vec<PeerId>,
+}
+
+impl ReplayGuard {
+    pub fn new(policies: Vec<(TopicFilter, ReplayPolicy)>) -> Self {
+        ReplayGuard {
+            policies,
+            last_nonce: 0,
+            windows: HashMap::new(),
+            strikes: HashMap::new(),
+            offenders: Vec::new(),
+        }
+    }
+
+    /// The policy of the first filter matching `topic`, if any.
+    pub fn policy(&self, topic: &str) -> Option<ReplayPolicy> {
+        if topic::is_internal(topic) {
+            return None;
+        }
+        self.policies
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .map(|(_, policy)| *policy)
+    }
+
+    /// Put a nonce on a payload published on `topic`, if its policy says so.
+    pub fn attach(&mut self, topic: &str, data: Bytes) -> Bytes {
+        if self.policy(topic).is_none() {
+            return data;
+        }
+        self.last_nonce = now_micros().max(self.last_nonce + 1);
+        let mut envelope = Vec::with_capacity(HEADER_LEN + data.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&self.last_nonce.to_be_bytes());
+        envelope.extend_from_slice(&data);
+        envelope.into()
+    }
+
+    /// Record the nonce of a payload from `publisher` on `topic`, failing if the payload is a
+    /// replay by the policy of the topic.
+    pub fn check(
+        &mut self,
+        publisher: &str,
+        topic: &str,
+        nonce: Option<u64>,
+    ) -> Result<(), Replay> {
+        let policy = match self.policy(topic) {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let nonce = nonce.ok_or(Replay::Missing)?;
+        let max_age = policy.max_age.as_micros() as u64;
+        if nonce.saturating_add(max_age) < now_micros() {
+            return Err(Replay::Stale(nonce));
+        }
+        let window = self
+            .windows
+            .entry((publisher.to_owned(), topic.to_owned()))
+            .or_insert_with(|| Window {
+                floor: 0,
+                latest: nonce,
+                seen: BTreeSet::new(),
+                max_age: policy.max_age,
+                last: Instant::now(),
+            });
+        if nonce <= window.floor {
+            return Err(Replay::Stale(nonce));
+        }
+        if !window.seen.insert(nonce) {
+            return Err(Replay::Duplicate(nonce));
+        }
+        window.last = Instant::now();
+        window.latest = window.latest.max(nonce);
+        let floor = window
+            .latest
+            .saturating_sub(policy.window.as_micros() as u64);
+        if floor > window.floor {
+            window.floor = floor;
+            window.seen = window.seen.split_off(&(floor + 1));
+        }
+        while window.seen.len() > MAX_SEEN {
+            window.floor = window.seen.pop_first().unwrap_or(window.floor);
+        }
+        Ok(())
+    }
+
+    /// Count a replay forwarded by `peer` on `topic`, marking the peer for a ban once it
+    /// forwarded as many as the policy of the topic allows.
+    pub fn strike(&mut self, peer: &PeerId, topic: &str) {
+        let policy = match self.policy(topic) {
+            Some(policy) if policy.max_replays > 0 => policy,
+            _ => return,
+        };
+        let strikes = self.strikes.entry(peer.clone()).or_insert(Strikes {
+            count: 0,
+            since: Instant::now(),
+        });
+        if strikes.since.elapsed() >= policy.max_age {
+            strikes.count = 0;
+            strikes.since = Instant::now();
+        }
+        strikes.count += 1;
+        if strikes.count >= policy.max_replays {
+            self.strikes.remove(peer);
+            self.offenders.push(peer.clone());
+        }
+    }
+
+    /// The peers to ban for the replays they forwarded, forgetting them.
+    pub fn offenders(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.offenders)
+    }
+
+    /// Forget the publishers silent, and the strikes of the peers, for longer than the maximum
+    /// age of their nonces.
+    pub fn expire(&mut self) {
+        self.windows
+            .retain(|_, window| window.last.elapsed() < window.max_age);
+        let max_age = self
+            .policies
+            .iter()
+            .map(|(_, policy)| policy.max_age)
+            .max()
+            .unwrap_or_default();
+        self.strikes
+            .retain(|_, strikes| strikes.since.elapsed() < max_age);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> ReplayGuard {
+        let policy = ReplayPolicy {
+            window: Duration::from_secs(1),
+            max_age: Duration::from_secs(60),
+            max_replays: 2,
+        };
+        ReplayGuard::new(vec![(TopicFilter::new("orders").unwrap(), policy)])
+    }
+
+    #[test]
+    fn attaches_increasing_nonces() {
+        let mut guard = guard();
+        let data = Bytes::from_static(b"order");
+        assert_eq!(guard.attach("other", data.clone()), data);
+        let (first, payload) = strip(guard.attach("orders", data.clone()));
+        let (second, _) = strip(guard.attach("orders", data.clone()));
+        assert_eq!(payload, data);
+        assert!(first.unwrap() < second.unwrap());
+    }
+
+    #[test]
+    fn rejects_missing_stale_and_duplicate_nonces() {
+        let mut guard = guard();
+        let now = now_micros();
+        assert_eq!(guard.check("a", "other", None), Ok(()));
+        assert_eq!(guard.check("a", "orders", None), Err(Replay::Missing));
+        let old = now - 120_000_000;
+        assert_eq!(
+            guard.check("a", "orders", Some(old)),
+            Err(Replay::Stale(old))
+        );
+        assert_eq!(guard.check("a", "orders", Some(now)), Ok(()));
+        assert_eq!(
+            guard.check("a", "orders", Some(now)),
+            Err(Replay::Duplicate(now))
+        );
+        assert_eq!(guard.check("b", "orders", Some(now)), Ok(()));
+    }
+
+    #[test]
+    fn accepts_out_of_order_nonces_within_the_window() {
+        let mut guard = guard();
+        let now = now_micros();
+        assert_eq!(guard.check("a", "orders", Some(now)), Ok(()));
+        assert_eq!(guard.check("a", "orders", Some(now - 500_000)), Ok(()));
+        let outside = now - 2_000_000;
+        assert_eq!(
+            guard.check("a", "orders", Some(outside)),
+            Err(Replay::Stale(outside))
+        );
+    }
+
+    #[test]
+    fn bans_peers_forwarding_replays() {
+        let mut guard = guard();
+        let peer = PeerId::random();
+        guard.strike(&peer, "orders");
+        assert!(guard.offenders().is_empty());
+        guard.strike(&peer, "other");
+        guard.strike(&peer, "orders");
+        assert_eq!(guard.offenders(), vec![peer]);
+        assert!(guard.offenders().is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+//! Retained messages, replayed to subscribers joining a topic.
+//!
+//! A node keeps the last messages it hands to its subscribers on the topics matching its
+//! [`NodeConfig::retention`](crate::NodeConfig::retention), whether received from peers or
+//! published by the node itself, in memory. A subscriber asks
+//! for them when joining with [`Client::subscribe_replay`](crate::Client::subscribe_replay),
+//! telling how much history it wants as a [`Replay`]; other subscriptions get none. The node
+//! replays at most [`NodeConfig::max_replay`](crate::NodeConfig::max_replay) messages to any
+//! subscriber, the most recent ones, so that joining a busy topic does not flood a new
+//! consumer.
+//!
+//! Replayed messages go through the pipeline of the subscription and are delivered before any
+//! new message, in the order they were delivered on the node.
+//!
+//! Retained messages count towards the [memory budget](crate::NodeConfig::memory_budget) of the
+//! node, which drops the oldest, whatever their topic, once the messages it keeps for gossip are
+//! gone and the budget is still exceeded.
+//!
+//! With a [`NodeConfig::retention_storage`](crate::NodeConfig::retention_storage), retained
+//! messages are kept in that [`Storage`] instead, in the log `retained/<topic>` of their topic,
+//! outside of the memory budget: with a storage on disk, they are replayed after the node
+//! restarts too. See the [`storage`](crate::storage) module.
+
+use crate::{storage::Storage, topic::TopicFilter, Message};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// How many of the messages of a topic a node retains.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Most messages retained on each topic, the oldest being dropped first.
+    pub max_messages: usize,
+    /// How long messages are retained, if not for as long as there is room for them.
+    pub max_age: Option<Duration>,
+}
+
+/// How much of the retained history a subscriber wants when it joins a topic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Replay {
+    /// Only new messages.
+    #[default]
+    None,
+    /// The last `n` retained messages.
+    Last(usize),
+    /// The retained messages delivered on the node since the given time.
+    Since(SystemTime),
+}
+
+/// Messages retained by a node, by topic.
+pub(crate) struct Retained {
+    policies: Vec<(TopicFilter, RetentionPolicy)>,
+    max_replay: usize,
+    topics: HashMap<String, VecDeque<(SystemTime, Message)>>,
+    /// Bytes of the payloads of the retained messages.
+    bytes: usize,
+    /// Where messages are retained, if not in `topics`.
+    storage: Option<Arc<dyn Storage>>,
+}
+
+/// The log of the messages retained on `topic` in a storage.
+fn log(topic: &str) -> String {
+    format!("retained/{}", topic)
+}
+
+impl Retained {
+    pub fn new(
+        policies: Vec<(TopicFilter, RetentionPolicy)>,
+        max_replay: usize,
+        storage: Option<Arc<dyn Storage>>,
+    ) -> Self {
+        Retained {
+            policies,
+            max_replay,
+            topics: HashMap::new(),
+            bytes: 0,
+            storage,
+        }
+    }
+
+    /// Retain `message` if its topic has a retention policy.
+    pub fn record(&mut self, message: &Message) {
+        let policy = match self.policy(&message.topic) {
+            Some(policy) if policy.max_messages > 0 => policy,
+            _ => return,
+        };
+        if let Some(storage) = &self.storage {
+            let log = log(&message.topic);
+            let stored = storage.append(&log, message).and_then(|seq| {
+                let keep_from = (seq + 1).saturating_sub(policy.max_messages as u64);
+                storage.prune(&log, keep_from)
+            });
+            if let Err(e) = stored {
+                log::warn!("failed to retain a message on {}: {}", message.topic, e);
+            }
+            return;
+        }
+        let retained = self.topics.entry(message.topic.clone()).or_default();
+        while retained.len() >= policy.max_messages {
+            if let Some((_, dropped)) = retained.pop_front() {
+                self.bytes -= dropped.data.len();
+            }
+        }
+        self.bytes += message.data.len();
+        retained.push_back((SystemTime::now(), message.clone()));
+    }
+
+    /// Bytes of the payloads of the retained messages.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Drop the oldest retained messages, whatever their topic, until the payloads of those
+    /// left take at most `bytes`. Returns the number of messages dropped.
+    pub fn shrink_to(&mut self, bytes: usize) -> usize {
+        let mut dropped = 0;
+        while self.bytes > bytes {
+            let oldest = self
+                .topics
+                .iter()
+                .filter_map(|(topic, retained)| Some((retained.front()?.0, topic)))
+                .min()
+                .map(|(_, topic)| topic.clone());
+            let retained = match oldest.and_then(|topic| self.topics.get_mut(&topic)) {
+                Some(retained) => retained,
+                None => break,
+            };
+            if let Some((_, message)) = retained.pop_front() {
+                self.bytes -= message.data.len();
+                dropped += 1;
+            }
+        }
+        self.topics.retain(|_, retained| !retained.is_empty());
+        dropped
+    }
+
+    /// The retained messages of the topics `matches` accepts that `replay` asks for, oldest
+    /// first, at most `max_replay` of them.
+    pub fn replay(&mut self, matches: impl Fn(&str) -> bool, replay: Replay) -> Vec<Message> {
+        if replay == Replay::None {
+            return Vec::new();
+        }
+        self.expire();
+        let stored = match &self.storage {
+            Some(storage) => match self.stored(storage.as_ref(), &matches) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    log::warn!("failed to read retained messages: {}", e);
+                    Vec::new()
+                }
+            },
+            None => Vec::new(),
+        };
+        let mut history: Vec<&(SystemTime, Message)> = self
+            .topics
+            .iter()
+            .filter(|(topic, _)| matches(topic))
+            .flat_map(|(_, retained)| retained)
+            .chain(&stored)
+            .filter(|(at, _)| match replay {
+                Replay::Since(since) => *at >= since,
+                _ => true,
+            })
+            .collect();
+        history.sort_by_key(|(at, _)| *at);
+        let wanted = match replay {
+            Replay::Last(n) => n.min(self.max_replay),
+            _ => self.max_replay,
+        };
+        let skipped = history.len().saturating_sub(wanted);
+        history
+            .into_iter()
+            .skip(skipped)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    /// The messages retained in `storage` on the topics `matches` accepts, dropping those older
+    /// than the maximum age of their topic.
+    fn stored(
+        &self,
+        storage: &dyn Storage,
+        matches: impl Fn(&str) -> bool,
+    ) -> Result<Vec<(SystemTime, Message)>, crate::Error> {
+        let now = SystemTime::now();
+        let mut stored = Vec::new();
+        for log in storage.logs()? {
+            let topic = match log.strip_prefix("retained/") {
+                Some(topic) if matches(topic) => topic,
+                _ => continue,
+            };
+            let max_age = self.policy(topic).and_then(|policy| policy.max_age);
+            let messages = storage.scan(&log, 0..u64::MAX)?;
+            let expired = messages.iter().take_while(|message| {
+                max_age.is_some_and(|max_age| {
+                    now.duration_since(message.stored_at)
+                        .is_ok_and(|age| age > max_age)
+                })
+            });
+            if let Some(last_expired) = expired.last() {
+                storage.prune(&log, last_expired.seq + 1)?;
+            }
+            stored.extend(
+                messages
+                    .into_iter()
+                    .filter(|message| {
+                        max_age.is_none_or(|max_age| {
+                            now.duration_since(message.stored_at)
+                                .map_or(true, |age| age <= max_age)
+                        })
+                    })
+                    .map(|message| (message.stored_at, message.message)),
+            );
+        }
+        Ok(stored)
+    }
+
+    /// Drop the messages older than the maximum age of their topic.
+    fn expire(&mut self) {
+        let now = SystemTime::now();
+        let policies = &self.policies;
+        let bytes = &mut self.bytes;
+        self.topics.retain(|topic, retained| {
+            let max_age = policies
+                .iter()
+                .find(|(filter, _)| filter.matches(topic))
+                .and_then(|(_, policy)| policy.max_age);
+            if let Some(max_age) = max_age {
+                retained.retain(|(at, message)| {
+                    let keep = now.duration_since(*at).map_or(true, |age| age <= max_age);
+                    if !keep {
+                        *bytes -= message.data.len();
+                    }
+                    keep
+                });
+            }
+            !retained.is_empty()
+        });
+    }
+
+    fn policy(&self, topic: &str) -> Option<RetentionPolicy> {
+        self.policies
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .map(|(_, policy)| *policy)
+    }
+}
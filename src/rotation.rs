@@ -0,0 +1,179 @@
+//! Rotating the keypair of a node without losing track of it.
+//!
+//! A peer id is the hash of a public key, so a node that rotates its keypair comes back as a
+//! stranger. To carry its standing over, a node started with
+//! [`NodeConfig::previous_keypair`](crate::NodeConfig::previous_keypair) announces a
+//! [`ContinuityRecord`] on [`CONTINUITY_TOPIC`], signed by both its previous and its current key,
+//! every [`topic_announce_interval`](crate::NodeConfig::topic_announce_interval). Every node
+//! checks the records it hears, keeps the valid ones, reports them through
+//! [`Client::rotations`](crate::Client::rotations), and follows them in
+//! [`Client::successor`](crate::Client::successor), so that applications can move access lists
+//! and peer stores over to the new peer id. Only the holder of both keys can link them: a record
+//! cannot hijack the standing of a peer.
+
+use crate::{delegation::IdentityKey, Error, PubSubError};
+use data_encoding::HEXLOWER_PERMISSIVE;
+use futures::{channel::mpsc, prelude::*};
+use libp2p::{identity, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Well-known topic on which nodes announce the rotations of their keypair.
+pub const CONTINUITY_TOPIC: &str = "pubsub-lite/continuity";
+
+/// Statement, signed by both keys, that the node of peer id `old` now runs as `new`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContinuityRecord {
+    pub old: PeerId,
+    pub new: PeerId,
+    /// Unix time in seconds at which the node rotated its keypair.
+    pub issued_at: u64,
+    old_key: identity::PublicKey,
+    new_key: identity::PublicKey,
+    old_signature: Vec<u8>,
+    new_signature: Vec<u8>,
+}
+
+/// Wire format of a [`ContinuityRecord`], the peer ids following from the keys.
+#[derive(Serialize, Deserialize)]
+struct Wire {
+    old_key: String,
+    new_key: String,
+    issued_at: u64,
+    old_signature: String,
+    new_signature: String,
+}
+
+impl ContinuityRecord {
+    /// Link the keypair `old` to the identity key `new`, signing with both.
+    pub fn new(old: &identity::Keypair, new: &IdentityKey) -> Result<Self, Error> {
+        let (old_key, new_key) = (old.public(), new.public());
+        let issued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let signed = signed(&old_key, &new_key, issued_at);
+        Ok(ContinuityRecord {
+            old: PeerId::from(old_key.clone()),
+            new: PeerId::from(new_key.clone()),
+            issued_at,
+            old_signature: old.sign(&signed).map_err(PubSubError::other)?,
+            new_signature: new.sign(&signed)?,
+            old_key,
+            new_key,
+        })
+    }
+
+    /// Check that both keys signed the record.
+    pub fn verify(&self) -> Result<(), Error> {
+        if self.old == self.new {
+            return Err("a continuity record links two different peer ids".into());
+        }
+        let signed = signed(&self.old_key, &self.new_key, self.issued_at);
+        if !self.old_key.verify(&signed, &self.old_signature) {
+            return Err(format!("continuity record is not signed by {}", self.old).into());
+        }
+        if !self.new_key.verify(&signed, &self.new_signature) {
+            return Err(format!("continuity record is not signed by {}", self.new).into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(&Wire {
+            old_key: HEXLOWER_PERMISSIVE.encode(&self.old_key.clone().into_protobuf_encoding()),
+            new_key: HEXLOWER_PERMISSIVE.encode(&self.new_key.clone().into_protobuf_encoding()),
+            issued_at: self.issued_at,
+            old_signature: HEXLOWER_PERMISSIVE.encode(&self.old_signature),
+            new_signature: HEXLOWER_PERMISSIVE.encode(&self.new_signature),
+        })?)
+    }
+
+    /// Decode a record heard on [`CONTINUITY_TOPIC`], checking its signatures.
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, Error> {
+        let wire: Wire = serde_json::from_slice(data)?;
+        let hex = |text: &str| HEXLOWER_PERMISSIVE.decode(text.as_bytes());
+        let old_key = identity::PublicKey::from_protobuf_encoding(&hex(&wire.old_key)?)
+            .map_err(PubSubError::codec)?;
+        let new_key = identity::PublicKey::from_protobuf_encoding(&hex(&wire.new_key)?)
+            .map_err(PubSubError::codec)?;
+        let record = ContinuityRecord {
+            old: PeerId::from(old_key.clone()),
+            new: PeerId::from(new_key.clone()),
+            issued_at: wire.issued_at,
+            old_signature: hex(&wire.old_signature)?,
+            new_signature: hex(&wire.new_signature)?,
+            old_key,
+            new_key,
+        };
+        record.verify()?;
+        Ok(record)
+    }
+}
+
+/// What both keys of a continuity record sign.
+fn signed(old: &identity::PublicKey, new: &identity::PublicKey, issued_at: u64) -> Vec<u8> {
+    let mut signed = b"pubsub-lite/continuity\0".to_vec();
+    signed.extend_from_slice(&old.clone().into_protobuf_encoding());
+    signed.push(0);
+    signed.extend_from_slice(&new.clone().into_protobuf_encoding());
+    signed.extend_from_slice(&issued_at.to_be_bytes());
+    signed
+}
+
+/// Stream of the continuity records this node learns, returned by
+/// [`Client::rotations`](crate::Client::rotations).
+pub struct Rotations {
+    pub(crate) receiver: mpsc::UnboundedReceiver<ContinuityRecord>,
+}
+
+impl Stream for Rotations {
+    type Item = ContinuityRecord;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ContinuityRecord>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// The continuity records a node knows, by the peer id they retire.
+#[derive(Default)]
+pub(crate) struct Continuity {
+    records: HashMap<PeerId, ContinuityRecord>,
+    watchers: Vec<mpsc::UnboundedSender<ContinuityRecord>>,
+}
+
+impl Continuity {
+    /// Keep a checked record, telling the watchers about it unless it was known. Of two records
+    /// retiring the same peer id, the latest issued wins.
+    pub fn accept(&mut self, record: ContinuityRecord) {
+        if let Some(known) = self.records.get(&record.old) {
+            if known.issued_at >= record.issued_at {
+                return;
+            }
+        }
+        self.watchers
+            .retain(|watcher| watcher.unbounded_send(record.clone()).is_ok());
+        self.records.insert(record.old.clone(), record);
+    }
+
+    pub fn watch(&mut self, watcher: mpsc::UnboundedSender<ContinuityRecord>) {
+        self.watchers.push(watcher);
+    }
+
+    /// The latest peer id `peer` rotated to, if it did.
+    pub fn successor(&self, peer: &PeerId) -> Option<PeerId> {
+        let mut seen = HashSet::new();
+        let mut current = peer;
+        while let Some(record) = self.records.get(current) {
+            if !seen.insert(current) {
+                break;
+            }
+            current = &record.new;
+        }
+        Some(current.clone()).filter(|current| current != peer)
+    }
+}
@@ -0,0 +1,150 @@
+//! Routing rules republishing the messages of some topics on another, for aggregation gateways.
+//!
+//! A [`Route`] of [`NodeConfig::routes`](crate::NodeConfig::routes), or of the `routes` of a
+//! [manifest](crate::manifest), takes the messages the node receives on the topics matching any
+//! of its `from` filters, runs them through its `transform` [pipeline](crate::pipeline) and
+//! publishes the result on its `to` topic, e.g. to fan the readings of every sensor into one
+//! topic:
+//!
+//! ```json
+//! {"from": ["sensors/+/temp", "legacy/temp"], "to": "temps", "transform": [{"project": {"fields": ["device", "celsius"]}}]}
+//! ```
+//!
+//! Routes are evaluated by the node itself, on the messages it receives from peers and, with
+//! [`local_delivery`](crate::NodeConfig::local_delivery), on those it publishes, routed ones
+//! included: the output of one route may feed another. Routes feeding each other in a loop would
+//! republish the same messages forever, so the node refuses to start with routes in which a `to`
+//! topic leads, through the `from` filters of one or more routes, back to the route it comes
+//! from. Loops through routes of several nodes cannot be told apart from legitimate traffic and
+//! are left to those configuring them.
+//!
+//! The routed messages are published by the node under its own identity, subject to the size
+//! limits and message types of their `to` topic; those failing a transform or a check are
+//! dropped. Routes queue at most [`ROUTE_CAPACITY`] messages, dropping the oldest past that.
+
+use crate::{
+    client::Client,
+    flow::{Bounds, Overflow},
+    pipeline::{Pipeline, Stage},
+    topic::{self, TopicFilter},
+    Error,
+};
+use bytes::Bytes;
+use futures::{prelude::*, stream};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Messages waiting to be routed by each route before the oldest are dropped.
+pub const ROUTE_CAPACITY: usize = 1024;
+
+/// A routing rule, see the [module documentation](self).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Topics or wildcard filters whose messages are routed.
+    pub from: Vec<String>,
+    /// Topic the routed messages are published on.
+    pub to: String,
+    /// Stages the payloads go through before they are published.
+    #[serde(default)]
+    pub transform: Vec<Stage>,
+}
+
+/// Topics and payloads of the messages to publish for the routes of a node.
+pub(crate) type Routed = Box<dyn Stream<Item = (String, Bytes)> + Send + Unpin>;
+
+/// The routes of a node, checked.
+pub(crate) struct Routes {
+    routes: Vec<(Vec<TopicFilter>, String, Arc<Pipeline>)>,
+}
+
+impl Routes {
+    /// Check the filters and transforms of `routes`, and that they do not loop.
+    pub fn new(routes: &[Route]) -> Result<Self, Error> {
+        let routes = routes
+            .iter()
+            .map(|route| {
+                if route.from.is_empty() {
+                    return Err(format!("route to {} has no topic to route from", route.to).into());
+                }
+                if TopicFilter::new(&route.to)?.is_wildcard() || topic::is_internal(&route.to) {
+                    return Err(format!("cannot route to {}", route.to).into());
+                }
+                let from = route
+                    .from
+                    .iter()
+                    .map(|filter| TopicFilter::new(filter))
+                    .collect::<Result<_, _>>()?;
+                let transform = Pipeline::new(route.transform.clone())?;
+                Ok((from, route.to.clone(), Arc::new(transform)))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let routes = Routes { routes };
+        if let Some(route) = (0..routes.routes.len()).find(|&start| routes.loops(start)) {
+            return Err(format!(
+                "route to {} loops back to itself through the routes",
+                routes.routes[route].1
+            )
+            .into());
+        }
+        Ok(routes)
+    }
+
+    /// Whether the messages routed by the route at `start` come back to it.
+    fn loops(&self, start: usize) -> bool {
+        let mut visited = vec![false; self.routes.len()];
+        let mut pending = vec![start];
+        while let Some(route) = pending.pop() {
+            let to = &self.routes[route].1;
+            for (next, (from, _, _)) in self.routes.iter().enumerate() {
+                if !from.iter().any(|filter| filter.matches(to)) {
+                    continue;
+                }
+                if next == start {
+                    return true;
+                }
+                if !visited[next] {
+                    visited[next] = true;
+                    pending.push(next);
+                }
+            }
+        }
+        false
+    }
+
+    /// Subscribe to the topics of every route on the node of `client`, returning the messages to
+    /// publish for them. The stream never ends, without routes too.
+    pub fn start(self, client: &Client) -> Result<Routed, Error> {
+        let bounds = Bounds {
+            capacity: ROUTE_CAPACITY,
+            overflow: Overflow::DropOldest,
+        };
+        let mut routed = stream::SelectAll::new();
+        for (from, to, transform) in self.routes {
+            for (index, filter) in from.iter().enumerate() {
+                let subscription = client.subscribe_bounded(filter.as_str(), bounds)?;
+                // Messages on a topic several filters match are routed for the first only.
+                let earlier = from[..index].to_vec();
+                let checks = client.publish_checks();
+                let to = to.clone();
+                let transform = transform.clone();
+                routed.push(subscription.filter_map(move |message| {
+                    if earlier.iter().any(|filter| filter.matches(&message.topic)) {
+                        return future::ready(None);
+                    }
+                    let routed = transform
+                        .apply(&message.data)
+                        .and_then(|data| checks.check(&to, data));
+                    future::ready(match routed {
+                        Ok(data) => Some((to.clone(), data)),
+                        Err(e) => {
+                            log::debug!("dropping a message routed from {}: {}", message.topic, e);
+                            None
+                        }
+                    })
+                }));
+            }
+        }
+        Ok(Box::new(routed.chain(stream::pending())))
+    }
+}
@@ -0,0 +1,208 @@
+//! Schemas of typed payloads, resolved by id from a [`SchemaProvider`].
+//!
+//! Producers of typed payloads commonly frame them in the Confluent wire format: a zero magic
+//! byte, the id of the schema as a big-endian 32-bit integer, then the encoded value.
+//! [`SchemaProvider::resolve`] reads that header and looks the schema up, so that consumers need
+//! not be deployed with every schema. Two providers come with the crate:
+//!
+//! - [`SchemaDir`] reads schemas from local files named after their id, such as `42.avsc`;
+//! - [`ConfluentRegistry`] fetches them from a Confluent-compatible schema registry over HTTP or
+//!   HTTPS, keeping those it fetched, as the schema of an id never changes. It is behind the
+//!   `registry` cargo feature, on by default.
+//!
+//! Topics can also be typed with a Protobuf message with
+//! [`NodeConfig::message_types`](crate::NodeConfig::message_types): their payloads must then be
+//! [`Any`] messages whose type URL names that message, such as
+//! `type.googleapis.com/acme.sensors.Reading` for `acme.sensors.Reading`. Publishing anything
+//! else on them, through [`Client::publish_any`](crate::Client::publish_any), the other publish
+//! methods or the gateways, fails with [`TypeMismatch`].
+
+use crate::{topic::TopicFilter, Error};
+use async_std::{fs, io};
+use futures::{future::BoxFuture, prelude::*};
+use serde::Deserialize;
+use std::{convert::TryFrom, fmt, path::PathBuf, sync::Arc};
+
+#[cfg(feature = "registry")]
+mod registry;
+#[cfg(feature = "registry")]
+pub use registry::{ConfluentRegistry, RegistryConfig};
+
+/// Language a schema is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SchemaKind {
+    Avro,
+    Json,
+    Protobuf,
+}
+
+/// A schema, as registered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    pub id: u32,
+    pub kind: SchemaKind,
+    /// Text of the schema.
+    pub definition: String,
+}
+
+/// A `google.protobuf.Any`: a serialized Protobuf message and the URL of its type.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Any {
+    /// URL whose last segment is the fully qualified name of the message type.
+    #[prost(string, tag = "1")]
+    pub type_url: String,
+    #[prost(bytes, tag = "2")]
+    pub value: Vec<u8>,
+}
+
+impl Any {
+    /// The serialized message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(prost::Message::encoded_len(self));
+        // Encoding only fails for lack of room, and a vector grows as needed.
+        let _ = prost::Message::encode(self, &mut bytes);
+        bytes
+    }
+
+    /// Fully qualified name of the message type.
+    pub fn type_name(&self) -> &str {
+        self.type_url.rsplit('/').next().unwrap_or_default()
+    }
+}
+
+/// Error returned when publishing on a typed topic a payload that is not an [`Any`] of its
+/// type, as the cause of a [`PubSubError::Publish`](crate::PubSubError::Publish), see
+/// [`PubSubError::cause`](crate::PubSubError::cause).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub topic: String,
+    /// Message type of the topic.
+    pub expected: String,
+    /// Type URL of the payload, `None` if it is not an `Any`.
+    pub type_url: Option<String>,
+}
+
+impl fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.type_url {
+            Some(type_url) => write!(
+                f,
+                "payload of type {} does not match the type {} of {}",
+                type_url, self.expected, self.topic
+            ),
+            None => write!(
+                f,
+                "payload is not a google.protobuf.Any of the type {} of {}",
+                self.expected, self.topic
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TypeMismatch {}
+
+/// Parse the message types of a configuration.
+pub(crate) fn parse_types(types: &[(String, String)]) -> Result<Vec<(TopicFilter, String)>, Error> {
+    types
+        .iter()
+        .map(|(filter, name)| {
+            if name.is_empty() || name.contains('/') {
+                return Err(format!("invalid message type {:?} for {}", name, filter).into());
+            }
+            Ok((TopicFilter::new(filter)?, name.clone()))
+        })
+        .collect()
+}
+
+/// Check that `payload` is an [`Any`] of the type of the first filter matching `topic`, if any.
+pub(crate) fn check_type(
+    types: &[(TopicFilter, String)],
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), TypeMismatch> {
+    let expected = match types.iter().find(|(filter, _)| filter.matches(topic)) {
+        Some((_, expected)) => expected,
+        None => return Ok(()),
+    };
+    let any = <Any as prost::Message>::decode(payload).ok();
+    match any {
+        Some(any) if any.type_url.contains('/') && any.type_name() == expected => Ok(()),
+        _ => Err(TypeMismatch {
+            topic: topic.to_owned(),
+            expected: expected.clone(),
+            type_url: any.map(|any| any.type_url),
+        }),
+    }
+}
+
+/// Split a payload framed in the Confluent wire format into the id of its schema and the
+/// encoded value, or return `None` if it is not framed so.
+pub fn split_framed(payload: &[u8]) -> Option<(u32, &[u8])> {
+    match payload {
+        [0, id @ ..] if id.len() >= 4 => {
+            let (id, value) = id.split_at(4);
+            Some((u32::from_be_bytes(<[u8; 4]>::try_from(id).ok()?), value))
+        }
+        _ => None,
+    }
+}
+
+/// A payload framed in the Confluent wire format, with its schema resolved.
+#[derive(Clone, Debug)]
+pub struct Typed<'a> {
+    pub schema: Arc<Schema>,
+    /// The encoded value, after the header.
+    pub value: &'a [u8],
+}
+
+/// Source of the schemas of typed payloads, by id.
+pub trait SchemaProvider: Send + Sync {
+    /// The schema registered under `id`.
+    fn schema(&self, id: u32) -> BoxFuture<'_, Result<Arc<Schema>, Error>>;
+
+    /// The schema of a payload framed in the Confluent wire format, and its encoded value.
+    fn resolve<'a>(&'a self, payload: &'a [u8]) -> BoxFuture<'a, Result<Typed<'a>, Error>> {
+        async move {
+            let (id, value) = split_framed(payload).ok_or("payload has no schema id")?;
+            let schema = self.schema(id).await?;
+            Ok(Typed { schema, value })
+        }
+        .boxed()
+    }
+}
+
+/// Schemas kept as files in a directory, named after their id with the extension of their
+/// kind: `.avsc` for Avro, `.json` for JSON Schema and `.proto` for Protobuf.
+#[derive(Clone, Debug)]
+pub struct SchemaDir {
+    pub path: PathBuf,
+}
+
+impl SchemaProvider for SchemaDir {
+    fn schema(&self, id: u32) -> BoxFuture<'_, Result<Arc<Schema>, Error>> {
+        async move {
+            let kinds = [
+                ("avsc", SchemaKind::Avro),
+                ("json", SchemaKind::Json),
+                ("proto", SchemaKind::Protobuf),
+            ];
+            for (extension, kind) in &kinds {
+                let file = self.path.join(format!("{}.{}", id, extension));
+                match fs::read_to_string(&file).await {
+                    Ok(definition) => {
+                        return Ok(Arc::new(Schema {
+                            id,
+                            kind: *kind,
+                            definition,
+                        }))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(format!("{}: {}", file.display(), e).into()),
+                }
+            }
+            Err(format!("no schema {} in {}", id, self.path.display()).into())
+        }
+        .boxed()
+    }
+}
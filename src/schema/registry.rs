@@ -0,0 +1,193 @@
+//! Client of Confluent-compatible schema registries, behind the `registry` cargo feature.
+
+use super::{Schema, SchemaKind, SchemaProvider};
+use crate::{Error, PubSubError};
+use async_std::{future::timeout, net::TcpStream};
+use async_tls::TlsConnector;
+use data_encoding::BASE64;
+use futures::{future::BoxFuture, prelude::*};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use url::{Position, Url};
+
+/// Upper bound on the size of a response from a registry.
+const MAX_RESPONSE: usize = 4 * 1024 * 1024;
+
+/// Configuration of a [`ConfluentRegistry`].
+#[derive(Clone, Debug)]
+pub struct RegistryConfig {
+    /// URL of the registry, e.g. `http://registry:8081`.
+    pub url: String,
+    /// Username and password of HTTP basic authentication, such as an API key and secret.
+    pub basic_auth: Option<(String, String)>,
+    /// How long to wait for the registry to answer.
+    pub request_timeout: Duration,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            url: "http://127.0.0.1:8081".into(),
+            basic_auth: None,
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Client of a Confluent-compatible schema registry.
+pub struct ConfluentRegistry {
+    url: Url,
+    authorization: Option<String>,
+    request_timeout: Duration,
+    cache: Mutex<HashMap<u32, Arc<Schema>>>,
+}
+
+/// Body of `GET /schemas/ids/{id}`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisteredSchema {
+    schema: String,
+    /// Absent for Avro, the kind registries started with.
+    schema_type: Option<SchemaKind>,
+}
+
+impl ConfluentRegistry {
+    pub fn new(config: RegistryConfig) -> Result<Self, Error> {
+        let url = Url::parse(&config.url).map_err(PubSubError::config)?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(format!("unsupported registry scheme {}", url.scheme()).into());
+        }
+        if url.host_str().is_none() {
+            return Err("registry URL has no host".into());
+        }
+        let authorization = config.basic_auth.map(|(username, password)| {
+            let credentials = format!("{}:{}", username, password);
+            format!("Basic {}", BASE64.encode(credentials.as_bytes()))
+        });
+        Ok(ConfluentRegistry {
+            url,
+            authorization,
+            request_timeout: config.request_timeout,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn fetch(&self, id: u32) -> Result<Schema, Error> {
+        let path = format!(
+            "{}/schemas/ids/{}",
+            self.url.path().trim_end_matches('/'),
+            id
+        );
+        let host = self.url.host_str().unwrap_or_default();
+        let authority = &self.url[Position::BeforeHost..Position::AfterPort];
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/vnd.schemaregistry.v1+json\r\n\
+             Connection: close\r\n",
+            path, authority
+        );
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let stream = TcpStream::connect((host, port)).await?;
+        let response = if self.url.scheme() == "https" {
+            let stream = TlsConnector::default().connect(host, stream)?.await?;
+            exchange(stream, request.as_bytes()).await?
+        } else {
+            exchange(stream, request.as_bytes()).await?
+        };
+        let (status, body) = parse_response(&response)?;
+        if status != 200 {
+            return Err(format!(
+                "registry answered {} for schema {}: {}",
+                status,
+                id,
+                String::from_utf8_lossy(&body)
+            )
+            .into());
+        }
+        let registered: RegisteredSchema = serde_json::from_slice(&body)?;
+        Ok(Schema {
+            id,
+            kind: registered.schema_type.unwrap_or(SchemaKind::Avro),
+            definition: registered.schema,
+        })
+    }
+}
+
+impl SchemaProvider for ConfluentRegistry {
+    fn schema(&self, id: u32) -> BoxFuture<'_, Result<Arc<Schema>, Error>> {
+        async move {
+            if let Some(schema) = self.cache.lock().unwrap().get(&id) {
+                return Ok(schema.clone());
+            }
+            let schema = timeout(self.request_timeout, self.fetch(id)).await??;
+            let schema = Arc::new(schema);
+            self.cache.lock().unwrap().insert(id, schema.clone());
+            Ok(schema)
+        }
+        .boxed()
+    }
+}
+
+/// Send `request` and read the response until the registry closes the connection.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> Result<Vec<u8>, Error> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE as u64 + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() > MAX_RESPONSE {
+        return Err("registry response too large".into());
+    }
+    Ok(response)
+}
+
+/// Status and body of a complete response.
+fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), Error> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed.parse(response).map_err(PubSubError::codec)? {
+        httparse::Status::Complete(head_len) => head_len,
+        httparse::Status::Partial => return Err("truncated registry response".into()),
+    };
+    let status = parsed.code.unwrap_or_default();
+    let chunked = parsed.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("transfer-encoding")
+            && String::from_utf8_lossy(h.value).eq_ignore_ascii_case("chunked")
+    });
+    let body = &response[head_len..];
+    if !chunked {
+        return Ok((status, body.to_vec()));
+    }
+    let mut rest = body;
+    let mut dechunked = Vec::new();
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or("truncated chunk")?;
+        let size = String::from_utf8_lossy(&rest[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| "invalid chunk size")?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Ok((status, dechunked));
+        }
+        if rest.len() < size {
+            return Err("truncated chunk".into());
+        }
+        dechunked.extend_from_slice(&rest[..size]);
+        rest = rest.get(size + 2..).unwrap_or_default();
+    }
+}
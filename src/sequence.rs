@@ -0,0 +1,326 @@
+//! Sequence numbers of the messages published on topics, for subscribers to learn of those lost.
+//!
+//! Gossip is best effort: a message a peer misses for want of a mesh, an overflowing queue or a
+//! flaky link is simply never delivered, and nothing tells its subscribers. The messages a node
+//! publishes on a topic with a [`SequencePolicy`] carry a sequence number counting the messages
+//! published on the topic since the node started, in an envelope: a marker, then the big-endian
+//! `u64` epoch of the node, the time it started at in nanoseconds, and the `u64` number.
+//! Receiving nodes take the envelope off whatever their own policies, and track the numbers of
+//! every publisher on every topic: a number past the next one expected means the ones in between
+//! were lost, and is reported as a
+//! [`NodeEvent::GapDetected`](crate::client::NodeEvent::GapDetected) to the watchers of
+//! [`Client::events`](crate::Client::events) and counted in the
+//! [statistics](crate::stats::SequenceStats) of the topic. Messages arriving late, in the gaps,
+//! are delivered; those arriving twice are not. A new epoch is a publisher that restarted, and
+//! starts afresh.
+//!
+//! A node whose policy for a topic has [`request_resend`](SequencePolicy::request_resend) asks
+//! the publisher, on [`RESEND_TOPIC`], for the messages missing as soon as a gap shows, and a
+//! publisher with a [`resend_buffer`](SequencePolicy::resend_buffer) sends those it still keeps
+//! to the node asking, and to it only. Requests only reach publishers connected to the node
+//! asking; resent messages go through the envelopes of the topic again but not through pacing.
+//!
+//! The envelope goes inside batches, so that the messages of a [paced](crate::pacing) topic are
+//! numbered one by one; the messages pacing drops are among those reported lost. The messages a
+//! node [delivers to itself](crate::NodeConfig::local_delivery) and those published to chosen
+//! peers are not numbered.
+
+use crate::topic::{self, TopicFilter};
+use bytes::Bytes;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    ops::Range,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Start of a numbered message. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plq";
+
+const HEADER_LEN: usize = MARKER.len() + 16;
+
+/// Gaps remembered per publisher and topic, for the messages arriving late to be told from
+/// duplicates. The oldest are forgotten past that.
+const MAX_GAPS: usize = 64;
+
+/// Internal topic resend requests are sent on.
+pub const RESEND_TOPIC: &str = "pubsub-lite/resend";
+
+/// How long the sequence of a publisher on a topic is remembered after its last message.
+pub const SEQUENCE_IDLE: Duration = Duration::from_secs(600);
+
+/// How the messages published on a topic are numbered, and lost ones recovered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequencePolicy {
+    /// Messages published on the topic kept to be resent to the peers missing them. Zero
+    /// disables resending.
+    pub resend_buffer: usize,
+    /// Whether to ask publishers for the messages missing from the topic.
+    pub request_resend: bool,
+}
+
+/// Epoch and number of a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub epoch: u64,
+    pub number: u64,
+}
+
+/// Take the envelope off a numbered payload, returning it as is otherwise.
+pub(crate) fn strip(data: Bytes) -> (Option<Stamp>, Bytes) {
+    if data.len() < HEADER_LEN || !data.starts_with(MARKER) {
+        return (None, data);
+    }
+    let epoch = u64::from_be_bytes(data[MARKER.len()..MARKER.len() + 8].try_into().unwrap());
+    let number = u64::from_be_bytes(data[MARKER.len() + 8..HEADER_LEN].try_into().unwrap());
+    (Some(Stamp { epoch, number }), data.slice(HEADER_LEN..))
+}
+
+/// Numbered state of a topic this node publishes on.
+struct Lane {
+    next: u64,
+    /// Numbered payloads published last, oldest first, sharing the buffers of those sent.
+    sent: VecDeque<(u64, Bytes)>,
+}
+
+/// Numbers the messages this node publishes.
+pub(crate) struct Sequencer {
+    policies: Vec<(TopicFilter, SequencePolicy)>,
+    epoch: u64,
+    lanes: HashMap<String, Lane>,
+}
+
+impl Sequencer {
+    pub fn new(policies: Vec<(TopicFilter, SequencePolicy)>) -> Self {
+        Sequencer {
+            policies,
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos() as u64),
+            lanes: HashMap::new(),
+        }
+    }
+
+    /// The policy of the first filter matching `topic`, if any.
+    pub fn policy(&self, topic: &str) -> Option<SequencePolicy> {
+        if topic::is_internal(topic) {
+            return None;
+        }
+        self.policies
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .map(|(_, policy)| *policy)
+    }
+
+    /// Number a payload published on `topic`, if its policy says so.
+    pub fn stamp(&mut self, topic: &str, data: Bytes) -> Bytes {
+        let policy = match self.policy(topic) {
+            Some(policy) => policy,
+            None => return data,
+        };
+        let lane = self.lanes.entry(topic.to_owned()).or_insert(Lane {
+            next: 1,
+            sent: VecDeque::new(),
+        });
+        let number = lane.next;
+        lane.next += 1;
+        let mut envelope = Vec::with_capacity(HEADER_LEN + data.len());
+        envelope.extend_from_slice(MARKER);
+        envelope.extend_from_slice(&self.epoch.to_be_bytes());
+        envelope.extend_from_slice(&number.to_be_bytes());
+        envelope.extend_from_slice(&data);
+        let envelope = Bytes::from(envelope);
+        if policy.resend_buffer > 0 {
+            if lane.sent.len() >= policy.resend_buffer {
+                lane.sent.pop_front();
+            }
+            lane.sent.push_back((number, envelope.clone()));
+        }
+        envelope
+    }
+
+    /// The numbered payloads `request` asks for that are still kept.
+    pub fn resend(&self, request: &ResendRequest) -> Vec<Bytes> {
+        if request.epoch != self.epoch {
+            return Vec::new();
+        }
+        let lane = match self.lanes.get(&request.topic) {
+            Some(lane) => lane,
+            None => return Vec::new(),
+        };
+        lane.sent
+            .iter()
+            .filter(|(number, _)| (request.from..request.to).contains(number))
+            .map(|(_, data)| data.clone())
+            .collect()
+    }
+}
+
+/// Request for the messages a publisher numbered from `from` up to `to` excluded on a topic.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ResendRequest {
+    /// Base58 id of the publisher.
+    pub publisher: String,
+    pub topic: String,
+    pub epoch: u64,
+    pub from: u64,
+    pub to: u64,
+}
+
+/// What the number of a received message tells.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Received {
+    /// The next message, or the first of a publisher.
+    InOrder,
+    /// A message following a gap, whose missing messages are given.
+    AfterGap(Range<u64>),
+    /// A message of an earlier gap.
+    Late,
+    /// A message received already.
+    Duplicate,
+}
+
+/// Numbered messages received from a publisher on a topic.
+struct Sequence {
+    epoch: u64,
+    next: u64,
+    /// Numbers still missing, oldest first.
+    gaps: VecDeque<Range<u64>>,
+    seen: Instant,
+}
+
+/// Tracks the numbers of the messages of every publisher on every topic.
+#[derive(Default)]
+pub(crate) struct Sequences {
+    sequences: HashMap<(PeerId, String), Sequence>,
+}
+
+impl Sequences {
+    /// Record a message numbered `stamp` from `source` on `topic`.
+    pub fn receive(&mut self, source: &PeerId, topic: &str, stamp: Stamp) -> Received {
+        let now = Instant::now();
+        let sequence = self
+            .sequences
+            .entry((source.clone(), topic.to_owned()))
+            .or_insert(Sequence {
+                epoch: stamp.epoch,
+                next: stamp.number,
+                gaps: VecDeque::new(),
+                seen: now,
+            });
+        sequence.seen = now;
+        if sequence.epoch != stamp.epoch {
+            *sequence = Sequence {
+                epoch: stamp.epoch,
+                next: stamp.number,
+                gaps: VecDeque::new(),
+                seen: now,
+            };
+        }
+        if stamp.number >= sequence.next {
+            let missing = sequence.next..stamp.number;
+            sequence.next = stamp.number.saturating_add(1);
+            if missing.is_empty() {
+                return Received::InOrder;
+            }
+            if sequence.gaps.len() >= MAX_GAPS {
+                sequence.gaps.pop_front();
+            }
+            sequence.gaps.push_back(missing.clone());
+            return Received::AfterGap(missing);
+        }
+        let index = match sequence
+            .gaps
+            .iter()
+            .position(|gap| gap.contains(&stamp.number))
+        {
+            Some(index) => index,
+            None => return Received::Duplicate,
+        };
+        let gap = sequence.gaps.remove(index).unwrap();
+        let after = stamp.number + 1..gap.end;
+        if !after.is_empty() {
+            sequence.gaps.insert(index, after);
+        }
+        let before = gap.start..stamp.number;
+        if !before.is_empty() {
+            sequence.gaps.insert(index, before);
+        }
+        Received::Late
+    }
+
+    /// Forget the publishers silent for longer than [`SEQUENCE_IDLE`].
+    pub fn expire(&mut self) {
+        self.sequences
+            .retain(|_, sequence| sequence.seen.elapsed() < SEQUENCE_IDLE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(epoch: u64, number: u64) -> Stamp {
+        Stamp { epoch, number }
+    }
+
+    #[test]
+    fn stamps_and_resends_numbered_payloads() {
+        let policy = SequencePolicy {
+            resend_buffer: 2,
+            request_resend: false,
+        };
+        let mut sequencer = Sequencer::new(vec![(TopicFilter::new("telemetry").unwrap(), policy)]);
+        let data = Bytes::from_static(b"reading");
+        assert_eq!(sequencer.stamp("other", data.clone()), data);
+        let sent: Vec<_> = (0..3)
+            .map(|_| sequencer.stamp("telemetry", data.clone()))
+            .collect();
+        let (first, payload) = strip(sent[0].clone());
+        assert_eq!(first, Some(stamp(sequencer.epoch, 1)));
+        assert_eq!(payload, data);
+        let request = ResendRequest {
+            publisher: String::new(),
+            topic: "telemetry".to_owned(),
+            epoch: sequencer.epoch,
+            from: 1,
+            to: 4,
+        };
+        assert_eq!(sequencer.resend(&request), sent[1..]);
+    }
+
+    #[test]
+    fn tracks_gaps_late_and_duplicate_messages() {
+        let source = PeerId::random();
+        let mut sequences = Sequences::default();
+        let mut receive = |number| sequences.receive(&source, "telemetry", stamp(1, number));
+        assert_eq!(receive(5), Received::InOrder);
+        assert_eq!(receive(6), Received::InOrder);
+        assert_eq!(receive(10), Received::AfterGap(7..10));
+        assert_eq!(receive(8), Received::Late);
+        assert_eq!(receive(8), Received::Duplicate);
+        assert_eq!(receive(7), Received::Late);
+        assert_eq!(receive(9), Received::Late);
+        assert_eq!(receive(6), Received::Duplicate);
+    }
+
+    #[test]
+    fn new_epoch_starts_afresh() {
+        let source = PeerId::random();
+        let mut sequences = Sequences::default();
+        assert_eq!(
+            sequences.receive(&source, "telemetry", stamp(1, 40)),
+            Received::InOrder
+        );
+        assert_eq!(
+            sequences.receive(&source, "telemetry", stamp(2, 1)),
+            Received::InOrder
+        );
+        assert_eq!(
+            sequences.receive(&source, "telemetry", stamp(2, 3)),
+            Received::AfterGap(2..3)
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Sharded topics: a logical topic spread over several shard topics, for consumers to split its
+//! traffic between them and scale out.
+//!
+//! [`Shards`] maps a logical topic and a key, such as the id of a customer or a device, to one of
+//! its shard topics, the topic followed by a dot and the number of the shard: with 8 shards,
+//! every message on `orders` goes to one of `orders.0` to `orders.7`, the messages of a key
+//! always to the same one, so that their order is kept. Publishers send through
+//! [`Client::publish_sharded`](crate::Client::publish_sharded).
+//!
+//! The consumers of a sharded topic, taken from
+//! [`Client::subscribe_shards`](crate::Client::subscribe_shards), run on any nodes, several on
+//! the same node too, and share its shards within a group: each shard is owned by the consumer
+//! ranking first for it by rendezvous hashing of the shard topic with the ids of the consumers,
+//! and each consumer subscribes to the shards it owns only, so that a node receives none of the
+//! traffic of the other shards.
+//!
+//! Consumers learn of each other over the coordination topic `pubsub-lite/shards/<group>/<topic>`:
+//! every [`HEARTBEAT_INTERVAL`] they announce themselves on it, answering new consumers right
+//! away, and they say goodbye once dropped. A consumer whose heartbeats stop for
+//! [`MISSED_HEARTBEATS`] intervals is considered gone. Whenever the consumers heard of change,
+//! every consumer rebalances: it subscribes to the shards it takes over, and unsubscribes from
+//! those it hands over, so that consumers joining or leaving only move their share of the shards.
+//! Nothing is handed over with a shard: the messages published while consumers do not agree on
+//! who owns it, or while the new owner joins the mesh of the shard, may be delivered to two of
+//! them, or to none. Consumers announcing another number of shards are ignored. The consumers on
+//! the same node, which do not hear each other over gossip, know of each other in the process.
+
+use crate::{group, Client, Error, Message, Subscription};
+use async_std::{stream, task};
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+    prelude::*,
+    select,
+};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// How often a consumer of a sharded topic announces itself.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Number of heartbeats a consumer may miss before it is considered gone.
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// The consumers of the process, as the coordination topic of their group, their id and their
+/// number of shards.
+static LOCAL_CONSUMERS: Mutex<Vec<(String, String, u32)>> = Mutex::new(Vec::new());
+
+/// A logical topic spread over a number of shard topics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Shards {
+    topic: String,
+    count: u32,
+}
+
+impl Shards {
+    /// Spread `topic` over `count` shards, numbered from 0.
+    pub fn new(topic: &str, count: u32) -> Result<Self, Error> {
+        if topic.is_empty() {
+            return Err(Error::config("sharded topic is empty"));
+        }
+        if count == 0 {
+            return Err(Error::config(format!("no shards for topic {}", topic)));
+        }
+        Ok(Shards {
+            topic: topic.to_owned(),
+            count,
+        })
+    }
+
+    /// The logical topic.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// The number of shards.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The shard of `key`: the first 8 bytes of its SHA-256 hash, big-endian, modulo the number
+    /// of shards.
+    pub fn shard_of(&self, key: &[u8]) -> u32 {
+        let hash = digest::digest(&digest::SHA256, key);
+        let hash = u64::from_be_bytes(
+            hash.as_ref()[..8]
+                .try_into()
+                .expect("SHA-256 is 32 bytes long"),
+        );
+        (hash % u64::from(self.count)) as u32
+    }
+
+    /// The topic of the shard `shard`, e.g. `orders.3`.
+    pub fn shard_topic(&self, shard: u32) -> String {
+        format!("{}.{}", self.topic, shard)
+    }
+
+    /// The topic of the shard of `key`.
+    pub fn topic_of(&self, key: &[u8]) -> String {
+        self.shard_topic(self.shard_of(key))
+    }
+
+    /// The topics of all the shards, in order.
+    pub fn shard_topics(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.count).map(move |shard| self.shard_topic(shard))
+    }
+}
+
+/// Announcement exchanged on the coordination topic of a sharded topic, by one of its consumers.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Announcement {
+    /// `consumer`, sharing `shards` shards, is in the group, and announces itself again within
+    /// `interval_ms` milliseconds.
+    Heartbeat {
+        consumer: String,
+        shards: u32,
+        interval_ms: u64,
+    },
+    /// `consumer` leaves the group.
+    Goodbye { consumer: String },
+}
+
+/// Messages of the shards owned by this consumer of a sharded topic, returned by
+/// [`Client::subscribe_shards`](crate::Client::subscribe_shards). The consumer leaves the group,
+/// handing its shards over, when the stream is dropped.
+pub struct ShardSubscription {
+    consumer: String,
+    owned: Arc<Mutex<Vec<u32>>>,
+    receiver: mpsc::UnboundedReceiver<Message>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl ShardSubscription {
+    /// Id of this consumer, unique among the consumers of the group.
+    pub fn consumer(&self) -> &str {
+        &self.consumer
+    }
+
+    /// The shards this consumer owns as of its last rebalance, in order.
+    pub fn owned(&self) -> Vec<u32> {
+        self.owned.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl Stream for ShardSubscription {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Message>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for ShardSubscription {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// The next message of any of `subscriptions`, or `None` once one of them ends.
+fn next_message(
+    subscriptions: &mut BTreeMap<u32, Subscription>,
+) -> impl Future<Output = Option<Message>> + '_ {
+    future::poll_fn(move |cx| {
+        for subscription in subscriptions.values_mut() {
+            if let Poll::Ready(message) = subscription.poll_next_unpin(cx) {
+                return Poll::Ready(message);
+            }
+        }
+        Poll::Pending
+    })
+}
+
+/// This consumer of a sharded topic.
+struct Consumer {
+    client: Client,
+    consumer: String,
+    shards: Shards,
+    coordination_topic: String,
+    announcements: Subscription,
+    /// The other consumers heard of, with when each is considered gone.
+    consumers: HashMap<String, Instant>,
+    /// Subscriptions to the shards owned.
+    subscriptions: BTreeMap<u32, Subscription>,
+    owned: Arc<Mutex<Vec<u32>>>,
+    deliveries: mpsc::UnboundedSender<Message>,
+}
+
+impl Consumer {
+    fn announce(&self, announcement: Announcement) -> Result<(), Error> {
+        self.client
+            .publish(&self.coordination_topic, serde_json::to_vec(&announcement)?)
+    }
+
+    fn heartbeat(&mut self) -> Result<(), Error> {
+        self.announce(Announcement::Heartbeat {
+            consumer: self.consumer.clone(),
+            shards: self.shards.count,
+            interval_ms: HEARTBEAT_INTERVAL.as_millis() as u64,
+        })
+    }
+
+    fn receive_announcement(&mut self, message: Message) -> Result<(), Error> {
+        match serde_json::from_slice(&message.data) {
+            Ok(Announcement::Heartbeat {
+                consumer,
+                shards,
+                interval_ms,
+            }) if consumer != self.consumer => {
+                if shards != self.shards.count {
+                    log::warn!(
+                        "ignoring consumer {} of {} sharing {} shards rather than {}",
+                        consumer,
+                        self.shards.topic,
+                        shards,
+                        self.shards.count
+                    );
+                    return Ok(());
+                }
+                let expires =
+                    Instant::now() + Duration::from_millis(interval_ms) * MISSED_HEARTBEATS;
+                if self.consumers.insert(consumer, expires).is_none() {
+                    // Let the newcomer know of this consumer without waiting for a heartbeat.
+                    self.rebalance()?;
+                    self.heartbeat()?;
+                }
+            }
+            Ok(Announcement::Goodbye { consumer }) => {
+                if self.consumers.remove(&consumer).is_some() {
+                    self.rebalance()?;
+                }
+            }
+            Ok(Announcement::Heartbeat { .. }) => {}
+            Err(e) => log::warn!("ignoring malformed shard announcement: {}", e),
+        }
+        Ok(())
+    }
+
+    /// Subscribe to the shards this consumer ranks highest for among the live consumers, and
+    /// unsubscribe from the others.
+    fn rebalance(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        self.consumers.retain(|_, expires| *expires > now);
+        let local_peer = format!("{}/", self.client.local_peer_id().to_base58());
+        let local = LOCAL_CONSUMERS.lock().unwrap_or_else(|e| e.into_inner());
+        let siblings = local
+            .iter()
+            .filter(|(topic, consumer, shards)| {
+                *topic == self.coordination_topic
+                    && *shards == self.shards.count
+                    && consumer.starts_with(&local_peer)
+                    && *consumer != self.consumer
+            })
+            .map(|(_, consumer, _)| consumer.clone());
+        let consumers: Vec<String> = self.consumers.keys().cloned().chain(siblings).collect();
+        drop(local);
+        for shard in 0..self.shards.count {
+            let topic = self.shards.shard_topic(shard);
+            let own = (group::rank(&self.consumer, &topic), &self.consumer);
+            let owned = !consumers
+                .iter()
+                .any(|consumer| (group::rank(consumer, &topic), consumer) > own);
+            if owned && !self.subscriptions.contains_key(&shard) {
+                log::debug!("consumer {} takes over {}", self.consumer, topic);
+                self.subscriptions
+                    .insert(shard, self.client.subscribe(&topic)?);
+            } else if !owned && self.subscriptions.remove(&shard).is_some() {
+                log::debug!("consumer {} hands over {}", self.consumer, topic);
+            }
+        }
+        *self.owned.lock().unwrap_or_else(|e| e.into_inner()) =
+            self.subscriptions.keys().copied().collect();
+        Ok(())
+    }
+
+    /// Rebalance, catching the consumers gone silent and the local ones coming and going, then
+    /// announce this consumer.
+    fn tick(&mut self) {
+        if let Err(e) = self.rebalance() {
+            log::warn!("failed to rebalance shards of {}: {}", self.shards.topic, e);
+        }
+        if let Err(e) = self.heartbeat() {
+            log::warn!(
+                "failed to announce consumer of {}: {}",
+                self.shards.topic,
+                e
+            );
+        }
+    }
+
+    /// Consume the shards owned, rebalancing as consumers come and go, until `stop` fires.
+    async fn run(mut self, stop: oneshot::Receiver<()>) {
+        let mut heartbeats = stream::interval(HEARTBEAT_INTERVAL);
+        let mut stop = stop.fuse();
+        self.tick();
+        loop {
+            select! {
+                message = next_message(&mut self.subscriptions).fuse() => match message {
+                    Some(message) => {
+                        let _ = self.deliveries.unbounded_send(message);
+                    }
+                    None => return,
+                },
+                message = self.announcements.next().fuse() => match message {
+                    Some(message) => {
+                        if let Err(e) = self.receive_announcement(message) {
+                            log::warn!("failed to rebalance shards of {}: {}", self.shards.topic, e);
+                        }
+                    }
+                    None => return,
+                },
+                _ = heartbeats.next().fuse() => {
+                    self.tick();
+                }
+                _ = stop => {
+                    let consumer = self.consumer.clone();
+                    if let Err(e) = self.announce(Announcement::Goodbye { consumer }) {
+                        log::warn!("failed to leave consumers of {}: {}", self.shards.topic, e);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        let mut local = LOCAL_CONSUMERS.lock().unwrap_or_else(|e| e.into_inner());
+        local.retain(|(_, consumer, _)| *consumer != self.consumer);
+    }
+}
+
+/// Join the consumers of `shards` in the group `group`.
+pub(crate) fn join(
+    client: &Client,
+    shards: &Shards,
+    group: &str,
+) -> Result<ShardSubscription, Error> {
+    static NEXT_CONSUMER: AtomicU64 = AtomicU64::new(1);
+    let consumer = format!(
+        "{}/{}",
+        client.local_peer_id().to_base58(),
+        NEXT_CONSUMER.fetch_add(1, Ordering::Relaxed)
+    );
+    let coordination_topic = format!("pubsub-lite/shards/{}/{}", group, shards.topic);
+    let (deliveries, receiver) = mpsc::unbounded();
+    let (stop, stopped) = oneshot::channel();
+    let owned = Arc::new(Mutex::new(Vec::new()));
+    let announcements = client.subscribe(&coordination_topic)?;
+    // Registered once subscribed, so that a failed join leaves no consumer behind.
+    LOCAL_CONSUMERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push((coordination_topic.clone(), consumer.clone(), shards.count));
+    let participant = Consumer {
+        client: client.clone(),
+        consumer: consumer.clone(),
+        shards: shards.clone(),
+        announcements,
+        coordination_topic,
+        consumers: HashMap::new(),
+        subscriptions: BTreeMap::new(),
+        owned: owned.clone(),
+        deliveries,
+    };
+    task::spawn(participant.run(stopped));
+    Ok(ShardSubscription {
+        consumer,
+        owned,
+        receiver,
+        stop: Some(stop),
+    })
+}
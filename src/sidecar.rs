@@ -0,0 +1,332 @@
+//! A line-delimited JSON protocol driving a node through a pair of byte streams, typically the
+//! standard input and output of the process, so that programs in any language can run it as a
+//! sidecar.
+//!
+//! Every line of the input is a command, an object naming its `op`, and every line of the output
+//! an event, an object naming its `event`:
+//!
+//! ```json
+//! {"op": "sub", "topic": "sensors/+/temp", "id": 1}
+//! {"op": "pub", "topic": "chat", "data": "hello"}
+//! {"op": "pub", "topic": "blobs", "data_base64": "AAEC"}
+//! {"op": "pub", "topic": "logs", "data": "disk full", "headers": {"severity": "error"}}
+//! {"op": "sub", "topic": "logs", "where": ["severity>=warn"]}
+//! {"op": "unsub", "topic": "sensors/+/temp"}
+//! ```
+//!
+//! - `pub` publishes the UTF-8 `data`, or the binary payload in `data_base64`, on `topic`, with
+//!   the `headers` given if any;
+//! - `sub` subscribes to `topic`, a topic or a wildcard filter, until `unsub` names it again,
+//!   only delivering the messages whose headers satisfy the predicates of `where` if given, see
+//!   the [`headers`](crate::headers) module.
+//!
+//! Every command is answered with `{"event": "ok", "id": ...}`, or
+//! `{"event": "error", "id": ..., "message": ...}` if it failed or could not be parsed, in the
+//! order the commands came; the `id` is that of the command, any JSON value, `null` without one.
+//! Other events come as they happen:
+//!
+//! - `{"event": "ready", "peer"}` first, with the id of the node;
+//! - `{"event": "message", "topic", "source", "id", "sequence_number", "signature", "data"}` for
+//!   every message on a topic subscribed to, `signature` being `verified` if the source signed
+//!   it and `unsigned` otherwise, see the [`attestation`](crate::attestation) module, and the
+//!   payload in `data_base64` instead of `data` unless it is UTF-8, with the `headers` of the
+//!   message if it has any;
+//! - `{"event": "peer_connected", "peer"}` and `{"event": "peer_disconnected", "peer"}`;
+//! - `{"event": "peer_subscribed", "peer", "topic"}` and
+//!   `{"event": "peer_unsubscribed", "peer", "topic"}`, this node included;
+//! - `{"event": "listening", "addr"}` when the node listens on a new address;
+//! - `{"event": "node_error", "message"}` when something failed that the node carries on from.
+//!
+//! Messages wait in the queue of their subscription while the output is not read, and are dropped
+//! as its [bounds](crate::NodeConfig::subscription_bounds) say once it is full. [`serve`] returns
+//! once the input ends, dropping the subscriptions.
+
+use crate::{
+    attestation::SignatureStatus, client::NodeEvent, headers::Headers, topic::TopicFilter, Client,
+    Error, Message, Subscription,
+};
+use data_encoding::BASE64;
+use futures::{
+    channel::mpsc,
+    future::{self, AbortHandle},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Events waiting to be written before the subscriptions and the node stop being read.
+const OUTPUT_BUFFER: usize = 1024;
+
+/// A command read from the input.
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Command {
+    Pub {
+        topic: String,
+        data: Option<String>,
+        data_base64: Option<String>,
+        #[serde(default)]
+        headers: Headers,
+    },
+    Sub {
+        topic: String,
+        #[serde(default, rename = "where")]
+        predicates: Vec<String>,
+    },
+    Unsub {
+        topic: String,
+    },
+}
+
+/// An event written to the output.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Ready {
+        peer: String,
+    },
+    Ok {
+        id: Value,
+    },
+    Error {
+        id: Value,
+        message: String,
+    },
+    Message {
+        topic: String,
+        source: String,
+        id: String,
+        sequence_number: u64,
+        signature: SignatureStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data_base64: Option<String>,
+        #[serde(skip_serializing_if = "Headers::is_empty")]
+        headers: Headers,
+    },
+    PeerConnected {
+        peer: String,
+    },
+    PeerDisconnected {
+        peer: String,
+    },
+    PeerSubscribed {
+        peer: String,
+        topic: String,
+    },
+    PeerUnsubscribed {
+        peer: String,
+        topic: String,
+    },
+    Listening {
+        addr: String,
+    },
+    NodeError {
+        message: String,
+    },
+}
+
+impl From<Message> for Event {
+    fn from(message: Message) -> Self {
+        let (data, data_base64) = match std::str::from_utf8(&message.data) {
+            Ok(text) => (Some(text.to_owned()), None),
+            Err(_) => (None, Some(BASE64.encode(&message.data))),
+        };
+        Event::Message {
+            topic: message.topic,
+            source: message.source.to_base58(),
+            id: message.id.0,
+            sequence_number: message.sequence_number,
+            signature: message.signature,
+            data,
+            data_base64,
+            headers: message.headers,
+        }
+    }
+}
+
+impl Event {
+    /// The event reported for a node event, if any.
+    fn from_node(event: NodeEvent) -> Option<Self> {
+        Some(match event {
+            NodeEvent::PeerConnected(peer) => Event::PeerConnected {
+                peer: peer.to_base58(),
+            },
+            NodeEvent::PeerDisconnected(peer) => Event::PeerDisconnected {
+                peer: peer.to_base58(),
+            },
+            NodeEvent::Subscribed { peer, topic } => Event::PeerSubscribed {
+                peer: peer.to_base58(),
+                topic,
+            },
+            NodeEvent::Unsubscribed { peer, topic } => Event::PeerUnsubscribed {
+                peer: peer.to_base58(),
+                topic,
+            },
+            NodeEvent::ListenAddr(addr) => Event::Listening {
+                addr: addr.to_string(),
+            },
+            NodeEvent::Error(error) => Event::NodeError {
+                message: error.to_string(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// Execute the commands of `input` on the node of `client`, writing the events to `output`, until
+/// `input` ends. `subscriptions`, such as those of a [manifest](crate::manifest), are served as if
+/// subscribed to by `sub` commands.
+pub async fn serve<R, W>(
+    client: &Client,
+    subscriptions: Vec<Subscription>,
+    input: R,
+    mut output: W,
+) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let node_events = client.events()?;
+    let ready = Event::Ready {
+        peer: client.local_peer_id().to_base58(),
+    };
+    write(&mut output, &ready).await?;
+    let (sender, mut events) = mpsc::channel(OUTPUT_BUFFER);
+    let mut forwarded: HashMap<String, AbortHandle> = HashMap::new();
+    let node_events = node_events.filter_map(|event| future::ready(Event::from_node(event)));
+    let watching = forward(node_events, sender.clone());
+    for subscription in subscriptions {
+        let topic = subscription.topic().to_owned();
+        let handle = forward(subscription.map(Event::from), sender.clone());
+        if let Some(previous) = forwarded.insert(topic, handle) {
+            previous.abort();
+        }
+    }
+    let mut lines = input.lines().fuse();
+    let result = loop {
+        let event = futures::select! {
+            line = lines.next() => match line {
+                Some(Ok(line)) if line.trim().is_empty() => continue,
+                Some(Ok(line)) => execute(client, &line, &mut forwarded, &sender),
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            },
+            event = events.next() => match event {
+                Some(event) => event,
+                None => continue,
+            },
+        };
+        if let Err(e) = write(&mut output, &event).await {
+            break Err(e);
+        }
+    };
+    for handle in forwarded.values() {
+        handle.abort();
+    }
+    watching.abort();
+    result
+}
+
+async fn write<W: AsyncWrite + Unpin>(output: &mut W, event: &Event) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    output.write_all(&line).await?;
+    Ok(output.flush().await?)
+}
+
+/// Execute the command on `line`, returning its answer.
+fn execute(
+    client: &Client,
+    line: &str,
+    forwarded: &mut HashMap<String, AbortHandle>,
+    sender: &mpsc::Sender<Event>,
+) -> Event {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            // Answer with the id of the command if it has one, even if the rest is invalid.
+            let id = serde_json::from_str::<Value>(line)
+                .ok()
+                .and_then(|value| value.get("id").cloned())
+                .unwrap_or(Value::Null);
+            return Event::Error {
+                id,
+                message: format!("invalid command: {}", e),
+            };
+        }
+    };
+    let result = match request.command {
+        Command::Pub {
+            topic,
+            data,
+            data_base64,
+            headers,
+        } => match (data, data_base64) {
+            (Some(data), None) => client.publish_with_headers(&topic, data, &headers),
+            (None, Some(data)) => BASE64
+                .decode(data.as_bytes())
+                .map_err(Error::from)
+                .and_then(|data| client.publish_with_headers(&topic, data, &headers)),
+            _ => Err("expected either data or data_base64".into()),
+        },
+        Command::Sub { topic, predicates } => {
+            subscribe(client, &topic, &predicates).map(|subscription| {
+                let handle = forward(subscription.map(Event::from), sender.clone());
+                if let Some(previous) = forwarded.insert(topic, handle) {
+                    previous.abort();
+                }
+            })
+        }
+        Command::Unsub { topic } => match forwarded.remove(&topic) {
+            Some(handle) => {
+                handle.abort();
+                Ok(())
+            }
+            None => Err(format!("not subscribed to {}", topic).into()),
+        },
+    };
+    match result {
+        Ok(()) => Event::Ok { id: request.id },
+        Err(e) => Event::Error {
+            id: request.id,
+            message: e.to_string(),
+        },
+    }
+}
+
+fn subscribe(client: &Client, topic: &str, predicates: &[String]) -> Result<Subscription, Error> {
+    let wildcard = TopicFilter::new(topic)?.is_wildcard();
+    if !predicates.is_empty() {
+        if wildcard {
+            return Err("header predicates need a topic without wildcards".into());
+        }
+        let predicates: Vec<&str> = predicates.iter().map(String::as_str).collect();
+        return client.subscribe_where(topic, &predicates);
+    }
+    if wildcard {
+        client.subscribe_filter(topic)
+    } else {
+        client.subscribe(topic)
+    }
+}
+
+/// Forward the events of `events` to `sender` on a task of their own, until aborted.
+fn forward(
+    events: impl Stream<Item = Event> + Send + 'static,
+    sender: mpsc::Sender<Event>,
+) -> AbortHandle {
+    let (forwarding, handle) = future::abortable(events.map(Ok).forward(sender));
+    async_std::task::spawn(forwarding);
+    handle
+}
@@ -0,0 +1,10 @@
+//! Sinks copying messages from the gossipsub mesh into external systems for safekeeping.
+//!
+//! Each sink lives in its own module behind a cargo feature of the same name.
+
+#[cfg(feature = "exec")]
+pub mod exec;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "webhook")]
+pub mod webhook;
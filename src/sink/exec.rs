@@ -0,0 +1,122 @@
+//! Sink piping every message of a topic to a command, for automation without writing Rust.
+//!
+//! The command is run once per message, with the payload on its standard input and the
+//! environment variables
+//!
+//! - `PUBSUB_TOPIC`, the topic the message was received on;
+//! - `PUBSUB_SOURCE`, the base58 id of the peer that published it;
+//! - `PUBSUB_MESSAGE_ID`, its id.
+//!
+//! Messages are handled one at a time, in order, and wait for the command in an outbox bounded
+//! by [`ExecConfig::flow`]. A command exiting with a failure status is not run again for the
+//! message, which could have been partly handled; failures are logged, and count as the sink
+//! being disconnected for the [alerts](crate::Client::bridge_alerts) of the node.
+
+use crate::{
+    bridge::Health,
+    flow::{self, FlowControl},
+    topic::TopicFilter,
+    topology::{self, ComponentKind},
+    Client, Error, Message,
+};
+use async_std::task;
+use futures::prelude::*;
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// Configuration of an exec sink.
+#[derive(Clone, Debug)]
+pub struct ExecConfig {
+    /// Gossipsub topic, or topic filter with `+` and `#` wildcards.
+    pub topic: String,
+    /// Program to run, followed by its arguments.
+    pub command: Vec<String>,
+    pub flow: FlowControl,
+    /// How long the command may keep failing before the sink is alerted on, see
+    /// [`Client::bridge_alerts`].
+    pub alert_after: Duration,
+}
+
+impl Default for ExecConfig {
+    fn default() -> Self {
+        ExecConfig {
+            topic: String::new(),
+            command: Vec::new(),
+            flow: FlowControl::default(),
+            alert_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Start running the command for the messages of the topic on a background task.
+pub fn spawn(client: &Client, config: ExecConfig) -> Result<task::JoinHandle<()>, Error> {
+    if config.command.is_empty() {
+        return Err("no command configured".into());
+    }
+    let filter = TopicFilter::new(&config.topic)?;
+    let messages = if filter.is_wildcard() {
+        client.subscribe_filter(filter.as_str())?
+    } else {
+        client.subscribe(filter.as_str())?
+    };
+    let outbox = flow::outbox(client, messages, &config.flow);
+    let health = Health::register(
+        client,
+        ComponentKind::Sink,
+        format!("exec sink {} for {}", config.command[0], config.topic),
+        topology::config_hash(&config),
+        config.alert_after,
+        outbox.monitor(),
+    )?;
+    health.connected();
+    Ok(task::spawn(run(config.command, outbox, health)))
+}
+
+async fn run(command: Vec<String>, mut messages: flow::Receiver<Message>, health: Health) {
+    while let Some(message) = messages.next().await {
+        let command = command.clone();
+        let (topic, id, size) = (
+            message.topic.clone(),
+            message.id.clone(),
+            message.data.len(),
+        );
+        match task::spawn_blocking(move || execute(&command, message)).await {
+            Ok(()) => {
+                health.connected();
+                health.relayed_out(size);
+            }
+            Err(e) => {
+                health.disconnected();
+                log::warn!("exec sink: message {} on {}: {}", id, topic, e);
+            }
+        }
+    }
+}
+
+/// Run `command` with `message` on its standard input, waiting for it to exit.
+fn execute(command: &[String], message: Message) -> Result<(), Error> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .env("PUBSUB_TOPIC", &message.topic)
+        .env("PUBSUB_SOURCE", message.source.to_base58())
+        .env("PUBSUB_MESSAGE_ID", message.id.to_string())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(&message.data),
+        None => Ok(()),
+    };
+    let status = child.wait()?;
+    // A command may well exit without reading its input.
+    match written {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+        _ => {}
+    }
+    if !status.success() {
+        return Err(format!("{} exited with {}", command[0], status).into());
+    }
+    Ok(())
+}
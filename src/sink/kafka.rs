@@ -0,0 +1,593 @@
+//! Sink mirroring gossipsub topics into Kafka topics for long-term analytics.
+//!
+//! The sink speaks just enough of the Kafka protocol to produce record batches to Kafka 0.11 and
+//! later: it looks up the partition leaders of every Kafka topic, buffers the messages of each
+//! route and sends them as one batch once `batch_size` of them are waiting or `linger` has
+//! elapsed, to the next partition of the topic in turn. Records hold the payload as value, no
+//! key, and the headers
+//!
+//! - `gossipsub-topic`, the topic the message was received on;
+//! - `peer-id`, the base58 id of the peer that published it;
+//! - `timestamp`, when the node received it, in milliseconds since the Unix epoch, which is
+//!   also the timestamp of the record.
+//!
+//! Messages wait for their batch in an outbox bounded by [`KafkaConfig::flow`], which also
+//! bounds what piles up while the brokers are slow or unreachable.
+
+use crate::{
+    bridge::Health,
+    flow::{self, FlowControl},
+    topic::TopicFilter,
+    topology::{self, ComponentKind},
+    Client, Error, Message, PubSubError,
+};
+use async_std::{future::timeout, io, net::TcpStream, stream, task};
+use bytes::Bytes;
+use futures::{prelude::*, stream::SelectAll};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Mirrors the messages of gossipsub topics into a Kafka topic.
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// Gossipsub topic, or topic filter with `+` and `#` wildcards.
+    pub gossipsub_topic: String,
+    pub kafka_topic: String,
+}
+
+/// What the sink guarantees about messages reaching Kafka.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Delivery {
+    /// Batches are sent once, without waiting for the broker to acknowledge them.
+    AtMostOnce,
+    /// Batches are resent until every in-sync replica has acknowledged them, so a batch whose
+    /// acknowledgement got lost is written twice.
+    #[default]
+    AtLeastOnce,
+}
+
+/// Configuration of a Kafka sink.
+#[derive(Clone, Debug)]
+pub struct KafkaConfig {
+    /// Brokers to discover the cluster from, as `host:port`.
+    pub brokers: Vec<String>,
+    pub client_id: String,
+    pub routes: Vec<Route>,
+    /// Number of messages for a Kafka topic that triggers sending them.
+    pub batch_size: usize,
+    /// Longest time a message waits for its batch to fill up.
+    pub linger: Duration,
+    pub delivery: Delivery,
+    /// How long to wait for a broker to answer.
+    pub request_timeout: Duration,
+    pub flow: FlowControl,
+    /// How long producing may keep failing before the sink is alerted on, see
+    /// [`Client::bridge_alerts`].
+    pub alert_after: Duration,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            brokers: vec!["127.0.0.1:9092".into()],
+            client_id: "pubsub-lite-sink".into(),
+            routes: Vec::new(),
+            batch_size: 100,
+            linger: Duration::from_secs(1),
+            delivery: Delivery::default(),
+            request_timeout: Duration::from_secs(30),
+            flow: FlowControl::default(),
+            alert_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Start mirroring messages according to the configured routes on a background task.
+pub fn spawn(client: &Client, config: KafkaConfig) -> Result<task::JoinHandle<()>, Error> {
+    if config.brokers.is_empty() {
+        return Err("no Kafka brokers configured".into());
+    }
+    if config.batch_size == 0 {
+        return Err("batch size must be at least 1".into());
+    }
+    let mut routes = Vec::new();
+    let mut messages = SelectAll::new();
+    for route in &config.routes {
+        let filter = TopicFilter::new(&route.gossipsub_topic)?;
+        messages.push(if filter.is_wildcard() {
+            client.subscribe_filter(filter.as_str())?
+        } else {
+            client.subscribe(filter.as_str())?
+        });
+        routes.push((filter, route.kafka_topic.clone()));
+    }
+    let outbox = flow::outbox(client, messages, &config.flow);
+    let health = Health::register(
+        client,
+        ComponentKind::Sink,
+        format!(
+            "Kafka sink {}@{}",
+            config.client_id,
+            config.brokers.join(",")
+        ),
+        topology::config_hash(&config),
+        config.alert_after,
+        outbox.monitor(),
+    )?;
+    // Brokers are connected to on demand, so the sink counts as connected until producing fails.
+    health.connected();
+    Ok(task::spawn(run(config, routes, outbox, health)))
+}
+
+/// A message waiting to be sent.
+struct Record {
+    topic: String,
+    source: String,
+    timestamp: i64,
+    data: Bytes,
+}
+
+async fn run(
+    config: KafkaConfig,
+    routes: Vec<(TopicFilter, String)>,
+    mut messages: flow::Receiver<Message>,
+    health: Health,
+) {
+    let mut producer = Producer::new(&config, health);
+    let mut batches: HashMap<String, Vec<Record>> = HashMap::new();
+    let mut ticks = stream::interval(config.linger);
+    loop {
+        let message = futures::select! {
+            message = messages.next() => message,
+            _ = ticks.next().fuse() => {
+                flush(&mut producer, &mut batches, config.delivery).await;
+                continue;
+            }
+        };
+        let message = match message {
+            Some(message) => message,
+            None => break,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis() as i64);
+        for (filter, kafka_topic) in &routes {
+            if !filter.matches(&message.topic) {
+                continue;
+            }
+            let records = batches.entry(kafka_topic.clone()).or_default();
+            records.push(record(&message, timestamp));
+            if records.len() >= config.batch_size {
+                let records = std::mem::take(records);
+                producer.send(kafka_topic, records, config.delivery).await;
+            }
+        }
+    }
+    // The node has shut down: send what is left.
+    flush(&mut producer, &mut batches, config.delivery).await;
+}
+
+/// Send every pending batch.
+async fn flush(
+    producer: &mut Producer,
+    batches: &mut HashMap<String, Vec<Record>>,
+    delivery: Delivery,
+) {
+    for (kafka_topic, records) in batches {
+        if !records.is_empty() {
+            let records = std::mem::take(records);
+            producer.send(kafka_topic, records, delivery).await;
+        }
+    }
+}
+
+fn record(message: &Message, timestamp: i64) -> Record {
+    Record {
+        topic: message.topic.clone(),
+        source: message.source.to_base58(),
+        timestamp,
+        data: message.data.clone(),
+    }
+}
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+
+/// Upper bound on the size of a response from a broker.
+const MAX_RESPONSE: usize = 16 * 1024 * 1024;
+
+/// Connections to the cluster and what is known of its layout.
+struct Producer {
+    bootstrap: Vec<String>,
+    client_id: String,
+    request_timeout: Duration,
+    /// Address of every broker, by node id.
+    nodes: HashMap<i32, String>,
+    /// Node id of the leader of every partition, by Kafka topic.
+    leaders: HashMap<String, Vec<i32>>,
+    connections: HashMap<String, TcpStream>,
+    correlation_id: i32,
+    next_partition: usize,
+    health: Health,
+}
+
+impl Producer {
+    fn new(config: &KafkaConfig, health: Health) -> Self {
+        Producer {
+            bootstrap: config.brokers.clone(),
+            client_id: config.client_id.clone(),
+            request_timeout: config.request_timeout,
+            nodes: HashMap::new(),
+            leaders: HashMap::new(),
+            connections: HashMap::new(),
+            correlation_id: 0,
+            next_partition: 0,
+            health,
+        }
+    }
+
+    /// Send `records` to `topic` as the delivery guarantee requires, retrying with a backoff if
+    /// needed.
+    async fn send(&mut self, topic: &str, records: Vec<Record>, delivery: Delivery) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let result = self.produce(topic, &records, delivery).await;
+            let e = match result {
+                Ok(()) => {
+                    self.health.connected();
+                    for record in &records {
+                        self.health.relayed_out(record.data.len());
+                    }
+                    return;
+                }
+                Err(e) => e,
+            };
+            self.health.disconnected();
+            // The layout of the cluster may have changed: look it up again.
+            self.leaders.remove(topic);
+            self.connections.clear();
+            if delivery == Delivery::AtMostOnce {
+                log::warn!(
+                    "Kafka sink: dropped {} messages for {}: {}",
+                    records.len(),
+                    topic,
+                    e
+                );
+                return;
+            }
+            log::warn!("Kafka sink: producing to {}: {}", topic, e);
+            task::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_secs(60));
+        }
+    }
+
+    async fn produce(
+        &mut self,
+        topic: &str,
+        records: &[Record],
+        delivery: Delivery,
+    ) -> Result<(), Error> {
+        if !self.leaders.contains_key(topic) {
+            self.lookup(topic).await?;
+        }
+        let leaders = &self.leaders[topic];
+        let partition = self.next_partition % leaders.len();
+        self.next_partition = self.next_partition.wrapping_add(1);
+        let addr = self
+            .nodes
+            .get(&leaders[partition])
+            .ok_or_else(|| format!("partition {} of {} has no leader", partition, topic))?
+            .clone();
+
+        let acks = match delivery {
+            Delivery::AtMostOnce => 0,
+            Delivery::AtLeastOnce => -1,
+        };
+        let mut body = Vec::new();
+        put_i16(&mut body, -1); // No transactional id.
+        put_i16(&mut body, acks);
+        put_i32(&mut body, self.request_timeout.as_millis() as i32);
+        put_i32(&mut body, 1);
+        put_string(&mut body, topic);
+        put_i32(&mut body, 1);
+        put_i32(&mut body, partition as i32);
+        put_bytes(&mut body, &record_batch(records));
+        let response = match self
+            .request(&addr, API_PRODUCE, 3, &body, acks != 0)
+            .await?
+        {
+            Some(response) => response,
+            None => return Ok(()),
+        };
+
+        let mut response = Reader(&response);
+        for _ in 0..response.i32()? {
+            response.string()?;
+            for _ in 0..response.i32()? {
+                let _partition = response.i32()?;
+                let error_code = response.i16()?;
+                let _base_offset = response.i64()?;
+                let _log_append_time = response.i64()?;
+                if error_code != 0 {
+                    return Err(format!("broker returned error code {}", error_code).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up the partition leaders of `topic` from the bootstrap brokers.
+    async fn lookup(&mut self, topic: &str) -> Result<(), Error> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_string(&mut body, topic);
+        let mut error = Error::from("no Kafka brokers configured");
+        for addr in self.bootstrap.clone() {
+            match self.request(&addr, API_METADATA, 1, &body, true).await {
+                Ok(Some(response)) => return self.parse_metadata(topic, &response),
+                Ok(None) => {}
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn parse_metadata(&mut self, topic: &str, response: &[u8]) -> Result<(), Error> {
+        let mut response = Reader(response);
+        for _ in 0..response.i32()? {
+            let node_id = response.i32()?;
+            let host = response.string()?;
+            let port = response.i32()?;
+            let _rack = response.string()?;
+            self.nodes.insert(node_id, format!("{}:{}", host, port));
+        }
+        let _controller_id = response.i32()?;
+        for _ in 0..response.i32()? {
+            let error_code = response.i16()?;
+            let name = response.string()?;
+            let _is_internal = response.i8()?;
+            let mut leaders = Vec::new();
+            for _ in 0..response.i32()? {
+                let _error_code = response.i16()?;
+                let partition = response.i32()?;
+                let leader = response.i32()?;
+                for _ in 0..2 {
+                    // Replicas and in-sync replicas.
+                    let count = response.i32()?;
+                    response.take(4 * usize::try_from(count).unwrap_or_default())?;
+                }
+                leaders.push((partition, leader));
+            }
+            if name != topic {
+                continue;
+            }
+            if error_code != 0 {
+                return Err(format!("broker returned error code {}", error_code).into());
+            }
+            if leaders.is_empty() {
+                return Err(format!("{} has no partitions", topic).into());
+            }
+            leaders.sort_unstable();
+            let leaders = leaders.into_iter().map(|(_, leader)| leader).collect();
+            self.leaders.insert(name, leaders);
+            return Ok(());
+        }
+        Err(format!("broker did not describe {}", topic).into())
+    }
+
+    /// Send a request to the broker at `addr`, returning the body of the response if one is
+    /// expected.
+    async fn request(
+        &mut self,
+        addr: &str,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+        expect_response: bool,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let correlation_id = self.correlation_id;
+        let mut request = Vec::new();
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, api_version);
+        put_i32(&mut request, correlation_id);
+        put_string(&mut request, &self.client_id);
+        request.extend_from_slice(body);
+        let mut frame = Vec::with_capacity(4 + request.len());
+        put_i32(&mut frame, request.len() as i32);
+        frame.extend_from_slice(&request);
+
+        let mut stream = match self.connections.get(addr) {
+            Some(stream) => stream.clone(),
+            None => {
+                let stream = timeout(self.request_timeout, TcpStream::connect(addr)).await??;
+                self.connections.insert(addr.to_owned(), stream.clone());
+                stream
+            }
+        };
+        let exchange = async {
+            stream.write_all(&frame).await?;
+            if !expect_response {
+                return Ok(None);
+            }
+            let mut size = [0u8; 4];
+            stream.read_exact(&mut size).await?;
+            let size = i32::from_be_bytes(size) as usize;
+            if !(4..=MAX_RESPONSE).contains(&size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bad response size",
+                ));
+            }
+            let mut response = vec![0u8; size];
+            stream.read_exact(&mut response).await?;
+            Ok(Some(response))
+        };
+        let response = timeout(self.request_timeout, exchange)
+            .await
+            .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::TimedOut, e)));
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                self.connections.remove(addr);
+                return Err(e.into());
+            }
+        };
+        Ok(match response {
+            Some(mut response) => {
+                if response[..4] != correlation_id.to_be_bytes() {
+                    self.connections.remove(addr);
+                    return Err("response does not match the request".into());
+                }
+                Some(response.split_off(4))
+            }
+            None => None,
+        })
+    }
+}
+
+/// Encode `records` as a record batch, in the format introduced by Kafka 0.11.
+fn record_batch(records: &[Record]) -> Vec<u8> {
+    let first_timestamp = records
+        .iter()
+        .map(|r| r.timestamp)
+        .min()
+        .unwrap_or_default();
+    let max_timestamp = records
+        .iter()
+        .map(|r| r.timestamp)
+        .max()
+        .unwrap_or_default();
+    // Everything after the checksum, which covers it.
+    let mut body = Vec::new();
+    put_i16(&mut body, 0); // Attributes: no compression.
+    put_i32(&mut body, records.len() as i32 - 1); // Last offset delta.
+    put_i64(&mut body, first_timestamp);
+    put_i64(&mut body, max_timestamp);
+    put_i64(&mut body, -1); // No producer id,
+    put_i16(&mut body, -1); // epoch
+    put_i32(&mut body, -1); // or sequence number.
+    put_i32(&mut body, records.len() as i32);
+    for (offset_delta, record) in records.iter().enumerate() {
+        let timestamp = record.timestamp.to_string();
+        let headers: [(&str, &[u8]); 3] = [
+            ("gossipsub-topic", record.topic.as_bytes()),
+            ("peer-id", record.source.as_bytes()),
+            ("timestamp", timestamp.as_bytes()),
+        ];
+        let mut encoded = vec![0]; // Attributes.
+        put_varint(&mut encoded, record.timestamp - first_timestamp);
+        put_varint(&mut encoded, offset_delta as i64);
+        put_varint(&mut encoded, -1); // No key.
+        put_varint(&mut encoded, record.data.len() as i64);
+        encoded.extend_from_slice(&record.data);
+        put_varint(&mut encoded, headers.len() as i64);
+        for (key, value) in &headers {
+            put_varint(&mut encoded, key.len() as i64);
+            encoded.extend_from_slice(key.as_bytes());
+            put_varint(&mut encoded, value.len() as i64);
+            encoded.extend_from_slice(value);
+        }
+        put_varint(&mut body, encoded.len() as i64);
+        body.extend_from_slice(&encoded);
+    }
+
+    let mut batch = Vec::with_capacity(21 + body.len());
+    put_i64(&mut batch, 0); // Base offset, assigned by the broker.
+    put_i32(&mut batch, (9 + body.len()) as i32);
+    put_i32(&mut batch, -1); // Partition leader epoch.
+    batch.push(2); // Magic.
+    batch.extend_from_slice(&crc32c(&body).to_be_bytes());
+    batch.extend_from_slice(&body);
+    batch
+}
+
+/// CRC-32C (Castagnoli), the checksum of record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn put_i16(buf: &mut Vec<u8>, value: i16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, value: i32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(buf: &mut Vec<u8>, value: i64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    put_i16(buf, value.len() as i16);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    put_i32(buf, value.len() as i32);
+    buf.extend_from_slice(value);
+}
+
+/// Append a zigzag encoded variable-length integer, as used within record batches.
+fn put_varint(buf: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Decodes the fields of a response in turn.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err("truncated response".into());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i8(&mut self) -> Result<i8, Error> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        Ok(i16::from_be_bytes(
+            <[u8; 2]>::try_from(self.take(2)?).map_err(PubSubError::codec)?,
+        ))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(
+            <[u8; 4]>::try_from(self.take(4)?).map_err(PubSubError::codec)?,
+        ))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_be_bytes(
+            <[u8; 8]>::try_from(self.take(8)?).map_err(PubSubError::codec)?,
+        ))
+    }
+
+    /// A string, empty if null.
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.i16()?;
+        let bytes = self.take(usize::try_from(len).unwrap_or_default())?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
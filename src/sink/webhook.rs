@@ -0,0 +1,222 @@
+//! Sink POSTing every message of a topic to an HTTP endpoint, for automation without writing
+//! Rust.
+//!
+//! Every message is sent as its own `POST` request to [`WebhookConfig::url`], over TLS for
+//! `https` URLs, with the payload as `application/octet-stream` body and the headers
+//!
+//! - `X-Pubsub-Topic`, the topic the message was received on, with characters other than
+//!   printable ASCII escaped as in Rust strings;
+//! - `X-Pubsub-Source`, the base58 id of the peer that published it;
+//! - `X-Pubsub-Message-Id`, its id.
+//!
+//! A `2xx` response delivers the message. Requests failing to connect, timing out or answered
+//! with `408`, `429` or `5xx` are retried up to [`WebhookConfig::retries`] times, waiting twice
+//! as long before every retry; other responses reject the message, which is dropped. Messages
+//! are sent one at a time, in order, and wait in an outbox bounded by [`WebhookConfig::flow`],
+//! which also bounds what piles up while the endpoint is down.
+
+use crate::{
+    bridge::Health,
+    flow::{self, FlowControl},
+    topic::TopicFilter,
+    topology::{self, ComponentKind},
+    Client, Error, Message, PubSubError,
+};
+use async_std::{future::timeout, io, net::TcpStream, task};
+use async_tls::TlsConnector;
+use futures::prelude::*;
+use std::time::Duration;
+use url::{Position, Url};
+
+/// Upper bound on the size of the status line of a response.
+const MAX_STATUS_LINE: usize = 8192;
+
+/// Configuration of a webhook sink.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// Gossipsub topic, or topic filter with `+` and `#` wildcards.
+    pub topic: String,
+    /// `http` or `https` URL messages are posted to.
+    pub url: String,
+    /// Extra headers of every request, e.g. for authorization.
+    pub headers: Vec<(String, String)>,
+    /// How many times a failed request is retried before the message is dropped.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling up to a minute for the next ones.
+    pub backoff: Duration,
+    /// How long to wait for the endpoint to answer.
+    pub request_timeout: Duration,
+    pub flow: FlowControl,
+    /// How long requests may keep failing before the sink is alerted on, see
+    /// [`Client::bridge_alerts`].
+    pub alert_after: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            topic: String::new(),
+            url: String::new(),
+            headers: Vec::new(),
+            retries: 5,
+            backoff: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(30),
+            flow: FlowControl::default(),
+            alert_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Start posting the messages of the topic on a background task.
+pub fn spawn(client: &Client, config: WebhookConfig) -> Result<task::JoinHandle<()>, Error> {
+    let url = Url::parse(&config.url).map_err(PubSubError::config)?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("unsupported webhook scheme {}", url.scheme()).into());
+    }
+    if url.host_str().is_none() {
+        return Err("webhook URL has no host".into());
+    }
+    let filter = TopicFilter::new(&config.topic)?;
+    let messages = if filter.is_wildcard() {
+        client.subscribe_filter(filter.as_str())?
+    } else {
+        client.subscribe(filter.as_str())?
+    };
+    let outbox = flow::outbox(client, messages, &config.flow);
+    let health = Health::register(
+        client,
+        ComponentKind::Sink,
+        format!("webhook sink {} for {}", url, config.topic),
+        topology::config_hash(&config),
+        config.alert_after,
+        outbox.monitor(),
+    )?;
+    // The endpoint is connected to for every message, so the sink counts as connected until
+    // posting fails.
+    health.connected();
+    Ok(task::spawn(run(config, url, outbox, health)))
+}
+
+async fn run(
+    config: WebhookConfig,
+    url: Url,
+    mut messages: flow::Receiver<Message>,
+    health: Health,
+) {
+    while let Some(message) = messages.next().await {
+        deliver(&config, &url, &message, &health).await;
+    }
+}
+
+/// Post `message`, retrying as configured.
+async fn deliver(config: &WebhookConfig, url: &Url, message: &Message, health: &Health) {
+    let request = request(config, url, message);
+    let mut backoff = config.backoff;
+    let mut attempt = 0;
+    loop {
+        let e = match timeout(config.request_timeout, post(url, &request)).await {
+            Ok(Ok(status)) if (200..300).contains(&status) => {
+                health.connected();
+                health.relayed_out(message.data.len());
+                return;
+            }
+            Ok(Ok(status)) if status == 408 || status == 429 || status >= 500 => {
+                format!("endpoint answered {}", status).into()
+            }
+            Ok(Ok(status)) => {
+                health.connected();
+                log::warn!(
+                    "webhook sink: endpoint rejected message {} on {} with {}",
+                    message.id,
+                    message.topic,
+                    status
+                );
+                return;
+            }
+            Ok(Err(e)) => e,
+            Err(e) => Error::from(e),
+        };
+        health.disconnected();
+        if attempt == config.retries {
+            log::warn!(
+                "webhook sink: dropped message {} on {}: {}",
+                message.id,
+                message.topic,
+                e
+            );
+            return;
+        }
+        log::debug!("webhook sink: posting to {}: {}", url, e);
+        attempt += 1;
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
+
+/// Encode the request posting `message` to `url`.
+fn request(config: &WebhookConfig, url: &Url, message: &Message) -> Vec<u8> {
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_owned(),
+    };
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\nX-Pubsub-Topic: {}\r\nX-Pubsub-Source: {}\r\n\
+         X-Pubsub-Message-Id: {}\r\nConnection: close\r\n",
+        &url[Position::BeforePath..Position::AfterQuery],
+        host,
+        message.data.len(),
+        message.topic.escape_default(),
+        message.source.to_base58(),
+        message.id,
+    );
+    for (name, value) in &config.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let mut request = head.into_bytes();
+    request.extend_from_slice(&message.data);
+    request
+}
+
+/// Send `request` to the endpoint at `url`, returning the status of the response.
+async fn post(url: &Url, request: &[u8]) -> Result<u16, Error> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await?;
+    if url.scheme() == "https" {
+        let stream = TlsConnector::default().connect(host, stream)?.await?;
+        exchange(stream, request).await
+    } else {
+        exchange(stream, request).await
+    }
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> Result<u16, Error> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    let mut response = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let end = loop {
+        if let Some(end) = response.windows(2).position(|w| w == b"\r\n") {
+            break end;
+        }
+        if response.len() > MAX_STATUS_LINE {
+            return Err("status line too long".into());
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        response.extend_from_slice(&chunk[..n]);
+    };
+    let line = String::from_utf8_lossy(&response[..end]);
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next().map(str::parse)) {
+        (Some(version), Some(Ok(status))) if version.starts_with("HTTP/") => Ok(status),
+        _ => Err(format!("malformed status line {:?}", line).into()),
+    }
+}
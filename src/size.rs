@@ -0,0 +1,174 @@
+//! Limits on the size of the payloads of topics, stricter than the node default.
+//!
+//! Gossipsub drops any message larger than its `max_transmit_size`, whatever its topic. With
+//! [`NodeConfig::max_message_size`](crate::NodeConfig::max_message_size), a topic gets a smaller
+//! limit of its own: publishing a larger payload on it fails with [`MessageTooLarge`], and larger
+//! payloads received on it are dropped and counted as
+//! [`oversized`](crate::stats::SizeStats::oversized). Sizes are those of payloads as published,
+//! before compression, encryption and chunking.
+//!
+//! [`NodeConfig::oversize`](crate::NodeConfig::oversize) handles larger payloads otherwise, by
+//! topic filter, with an [`OversizePolicy`]: they can be cut down to the limit instead, or
+//! published whole in chunks of at most the limit, so that a topic carries payloads larger than
+//! the `max_transmit_size` of gossipsub without any message on the wire exceeding its limit.
+//!
+//! The sizes of the payloads published and received on every topic are reported by
+//! [`Client::stats`](crate::Client::stats), see [`SizeStats`](crate::stats::SizeStats).
+
+use crate::{chunking, topic::TopicFilter, Error};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::fmt;
+
+/// Error returned when publishing a payload larger than the limit of its topic, as the cause of
+/// a [`PubSubError::Publish`](crate::PubSubError::Publish), see
+/// [`PubSubError::cause`](crate::PubSubError::cause).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    /// Topic of the payload, empty for messages of gateway clients too large to be read.
+    pub topic: String,
+    /// Size of the payload in bytes.
+    pub size: usize,
+    /// Limit of the topic in bytes.
+    pub limit: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "payload of {} bytes exceeds the limit of {} bytes",
+            self.size, self.limit
+        )?;
+        match self.topic.is_empty() {
+            true => Ok(()),
+            false => write!(f, " on {}", self.topic),
+        }
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// What becomes of a payload published over the limit of its topic.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizePolicy {
+    /// Publishing it fails with [`MessageTooLarge`].
+    #[default]
+    Reject,
+    /// It is cut down to the limit, and the rest is lost.
+    Truncate,
+    /// It is split into chunks of at most the limit, headers included, unless the topic has a
+    /// [chunk size](crate::NodeConfig::chunking) of its own, and received whole. Payloads are
+    /// then limited by the reassembly of chunks alone, to 16 MiB, and limits must exceed the
+    /// 20 bytes of the header of a chunk.
+    Chunk,
+}
+
+/// The limits on the size of the payloads of topics, and what becomes of larger ones.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SizeLimits {
+    limits: Vec<(TopicFilter, usize)>,
+    policies: Vec<(TopicFilter, OversizePolicy)>,
+}
+
+impl SizeLimits {
+    /// Parse the limits and policies of a configuration. No limit may exceed
+    /// `max_transmit_size`.
+    pub fn parse(
+        limits: &[(String, usize)],
+        policies: &[(String, OversizePolicy)],
+        max_transmit_size: usize,
+    ) -> Result<Self, Error> {
+        let limits = limits
+            .iter()
+            .map(|(filter, limit)| {
+                if *limit == 0 || *limit > max_transmit_size {
+                    return Err(format!(
+                        "maximum message size of {} must be between 1 and the max_transmit_size \
+                         of {} bytes",
+                        filter, max_transmit_size
+                    )
+                    .into());
+                }
+                Ok((TopicFilter::new(filter)?, *limit))
+            })
+            .collect::<Result<_, Error>>()?;
+        let policies = policies
+            .iter()
+            .map(|(filter, policy)| Ok((TopicFilter::new(filter)?, *policy)))
+            .collect::<Result<_, Error>>()?;
+        Ok(SizeLimits { limits, policies })
+    }
+
+    /// The limit of the first filter matching `topic`, and the policy of the first matching
+    /// one, if the topic has a limit.
+    fn limit(&self, topic: &str) -> Option<(usize, OversizePolicy)> {
+        let (_, limit) = self
+            .limits
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))?;
+        let policy = self
+            .policies
+            .iter()
+            .find(|(filter, _)| filter.matches(topic))
+            .map_or(OversizePolicy::Reject, |(_, policy)| *policy);
+        Some((*limit, policy))
+    }
+
+    /// Check a payload to publish on `topic`, cutting it down to the limit if the topic
+    /// truncates larger ones.
+    pub fn admit(&self, topic: &str, data: Bytes) -> Result<Bytes, MessageTooLarge> {
+        let (limit, policy) = match self.limit(topic) {
+            Some((limit, policy)) if data.len() > limit => (limit, policy),
+            _ => return Ok(data),
+        };
+        let too_large = |limit| MessageTooLarge {
+            topic: topic.to_owned(),
+            size: data.len(),
+            limit,
+        };
+        match policy {
+            OversizePolicy::Truncate => {
+                log::debug!(
+                    "truncating a payload of {} bytes published on {} to {} bytes",
+                    data.len(),
+                    topic,
+                    limit
+                );
+                Ok(data.slice(..limit))
+            }
+            OversizePolicy::Chunk if limit > chunking::HEADER_LEN => {
+                if data.len() > chunking::MAX_REASSEMBLED {
+                    return Err(too_large(chunking::MAX_REASSEMBLED));
+                }
+                Ok(data)
+            }
+            _ => Err(too_large(limit)),
+        }
+    }
+
+    /// Check a payload of `size` bytes received on `topic`. Topics that chunk larger payloads
+    /// take them whole.
+    pub fn check(&self, topic: &str, size: usize) -> Result<(), MessageTooLarge> {
+        match self.limit(topic) {
+            Some((limit, policy)) if size > limit && policy != OversizePolicy::Chunk => {
+                Err(MessageTooLarge {
+                    topic: topic.to_owned(),
+                    size,
+                    limit,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The size of the chunks of payloads published on `topic` over its limit, if it chunks
+    /// them.
+    pub fn chunk_size(&self, topic: &str) -> Option<usize> {
+        match self.limit(topic)? {
+            (limit, OversizePolicy::Chunk) if limit > chunking::HEADER_LEN => Some(limit),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,482 @@
+//! Key-value state shared by the nodes of a network: a last-writer-wins map replicated over a
+//! topic, for the configuration, flags and small records every node of a fleet needs a copy of.
+//!
+//! Every node holding the [`StateMap`] named `<name>`, returned by
+//! [`Client::state`](crate::Client::state), keeps a replica of it and follows the shadow topic
+//! `pubsub-lite/state/<name>`:
+//!
+//! - [`set`](StateMap::set) and [`remove`](StateMap::remove) change the local replica at once
+//!   and publish the changed entry, a delta, to the other replicas;
+//! - a node seeing a peer subscribe to the topic, on joining the map or reconnecting after a
+//!   split, publishes its whole replica within a [`SYNC_DELAY`], once the mesh took the peer in,
+//!   so that the replicas catch up on the changes they missed;
+//! - every [`DIGEST_INTERVAL`], each replica publishes a digest of its entries, and the replicas
+//!   whose digest differs publish their whole replica, repairing deltas lost on the way.
+//!
+//! Each entry carries the version it was written at: the time of the write in milliseconds,
+//! made greater than every version the writing replica saw, and the peer id of the replica. The
+//! entry with the greatest version wins, whatever order the replicas receive them in, so that
+//! they all end up holding the same map. A write made on the heels of a write on another node
+//! may lose to it if their clocks are far apart, the newer write by wall time not necessarily
+//! being the one kept.
+//!
+//! A removed key is kept as a tombstone, so that the removal wins over the older values other
+//! replicas may still send: maps with many short-lived keys grow with them. The whole replica
+//! is published as one message, bound by the [size limits](crate::size) of the node.
+
+use crate::{client::Events, Bytes, Client, Error, Message, NodeEvent, Subscription};
+use async_std::{stream, task};
+use data_encoding::{BASE64, HEXLOWER};
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select,
+};
+use ring::digest::{Context as Digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long after a peer subscribes to the topic of a map its replica is published to it.
+pub const SYNC_DELAY: Duration = Duration::from_secs(1);
+
+/// How often each replica of a map publishes the digest of its entries.
+pub const DIGEST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An entry of a map as exchanged on its topic, without a value if the key was removed.
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: Option<String>,
+    time: u64,
+    replica: String,
+}
+
+/// Update exchanged on the topic of a map.
+#[derive(Serialize, Deserialize)]
+enum Update {
+    /// Entries written, or the whole replica of the publisher.
+    Entries(Vec<Entry>),
+    /// Digest of the whole replica of the publisher.
+    Digest(String),
+}
+
+/// A key of a replica, with the version it was last written at.
+#[derive(Clone)]
+struct Record {
+    value: Option<Bytes>,
+    time: u64,
+    replica: String,
+}
+
+impl Record {
+    /// Whether `self` wins over `other`, the greater version winning, then the greater value if
+    /// one replica wrote twice at the same version.
+    fn wins_over(&self, other: &Record) -> bool {
+        (self.time, &self.replica, &self.value) > (other.time, &other.replica, &other.value)
+    }
+}
+
+/// Change of a key of a map, reported by [`StateWatch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateChange {
+    pub key: String,
+    /// The new value, `None` if the key was removed.
+    pub value: Option<Bytes>,
+    /// Whether the change was made through this node rather than received from another.
+    pub local: bool,
+}
+
+/// The replica of a map held by this node.
+struct Replica {
+    id: String,
+    records: BTreeMap<String, Record>,
+    /// Greatest version time written or received.
+    clock: u64,
+    watchers: Vec<mpsc::UnboundedSender<StateChange>>,
+}
+
+impl Replica {
+    /// The version time of a write made now.
+    fn tick(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.clock = now.max(self.clock + 1);
+        self.clock
+    }
+
+    /// Keep `record` for `key` if it wins over the one held, telling the watchers.
+    fn merge(&mut self, key: String, record: Record, local: bool) {
+        self.clock = self.clock.max(record.time);
+        if let Some(held) = self.records.get(&key) {
+            if !record.wins_over(held) {
+                return;
+            }
+            if held.value == record.value {
+                self.records.insert(key, record);
+                return;
+            }
+        }
+        let change = StateChange {
+            key: key.clone(),
+            value: record.value.clone(),
+            local,
+        };
+        self.records.insert(key, record);
+        self.watchers
+            .retain(|watcher| watcher.unbounded_send(change.clone()).is_ok());
+    }
+
+    /// Write `value` for `key` locally, returning the entry to publish.
+    fn write(&mut self, key: &str, value: Option<Bytes>) -> Entry {
+        let record = Record {
+            value,
+            time: self.tick(),
+            replica: self.id.clone(),
+        };
+        let entry = entry(key, &record);
+        self.merge(key.to_owned(), record, true);
+        entry
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        self.records
+            .iter()
+            .map(|(key, record)| entry(key, record))
+            .collect()
+    }
+
+    /// SHA-256 of the entries of the replica, equal for replicas holding the same entries.
+    fn digest(&self) -> String {
+        let mut digest = Digest::new(&SHA256);
+        for (key, record) in &self.records {
+            digest.update(&(key.len() as u64).to_be_bytes());
+            digest.update(key.as_bytes());
+            digest.update(&record.time.to_be_bytes());
+            digest.update(record.replica.as_bytes());
+            match &record.value {
+                Some(value) => {
+                    digest.update(&(value.len() as u64).to_be_bytes());
+                    digest.update(value);
+                }
+                None => digest.update(&[0xff; 8]),
+            }
+        }
+        HEXLOWER.encode(digest.finish().as_ref())
+    }
+}
+
+fn entry(key: &str, record: &Record) -> Entry {
+    Entry {
+        key: key.to_owned(),
+        value: record.value.as_ref().map(|value| BASE64.encode(value)),
+        time: record.time,
+        replica: record.replica.clone(),
+    }
+}
+
+/// A map replicated among the nodes holding it, returned by
+/// [`Client::state`](crate::Client::state). This node keeps its replica in sync until the map is
+/// dropped. See the [module documentation](self).
+pub struct StateMap {
+    client: Client,
+    name: String,
+    state_topic: String,
+    replica: Arc<Mutex<Replica>>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+impl StateMap {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The value of `key`, if it is set.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let replica = self.replica.lock().unwrap();
+        replica
+            .records
+            .get(key)
+            .and_then(|record| record.value.clone())
+    }
+
+    /// Set `key` to `value`, and publish the change to the other replicas.
+    pub fn set(&self, key: &str, value: impl Into<Bytes>) -> Result<(), Error> {
+        self.write(key, Some(value.into()))
+    }
+
+    /// Remove `key`, and publish the removal to the other replicas.
+    pub fn remove(&self, key: &str) -> Result<(), Error> {
+        self.write(key, None)
+    }
+
+    fn write(&self, key: &str, value: Option<Bytes>) -> Result<(), Error> {
+        let entry = self.replica.lock().unwrap().write(key, value);
+        let update = serde_json::to_vec(&Update::Entries(vec![entry]))?;
+        self.client.publish(&self.state_topic, update)
+    }
+
+    /// The keys set and their values, by key.
+    pub fn entries(&self) -> BTreeMap<String, Bytes> {
+        let replica = self.replica.lock().unwrap();
+        replica
+            .records
+            .iter()
+            .filter_map(|(key, record)| Some((key.clone(), record.value.clone()?)))
+            .collect()
+    }
+
+    /// Follow the changes of the map from now on, made locally or received.
+    pub fn watch(&self) -> StateWatch {
+        let (watcher, receiver) = mpsc::unbounded();
+        self.replica.lock().unwrap().watchers.push(watcher);
+        StateWatch { receiver }
+    }
+}
+
+impl Drop for StateMap {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// Stream of the changes of a map, returned by [`StateMap::watch`]. It ends when the map is
+/// dropped.
+pub struct StateWatch {
+    receiver: mpsc::UnboundedReceiver<StateChange>,
+}
+
+impl Stream for StateWatch {
+    type Item = StateChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<StateChange>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// This node keeping its replica of a map in sync.
+struct Syncer {
+    client: Client,
+    name: String,
+    state_topic: String,
+    replica: Arc<Mutex<Replica>>,
+    /// When to publish the whole replica, a peer having joined or a digest having differed.
+    sync_at: Option<Instant>,
+}
+
+impl Syncer {
+    fn publish(&self, update: &Update) -> Result<(), Error> {
+        self.client
+            .publish(&self.state_topic, serde_json::to_vec(update)?)
+    }
+
+    fn receive(&mut self, message: Message) {
+        if &message.source == self.client.local_peer_id() {
+            return;
+        }
+        match serde_json::from_slice(&message.data) {
+            Ok(Update::Entries(entries)) => {
+                let mut replica = self.replica.lock().unwrap();
+                for entry in entries {
+                    let value = match entry.value.map(|value| BASE64.decode(value.as_bytes())) {
+                        Some(Ok(value)) => Some(value.into()),
+                        Some(Err(e)) => {
+                            log::warn!("ignoring malformed value of {}: {}", entry.key, e);
+                            continue;
+                        }
+                        None => None,
+                    };
+                    let record = Record {
+                        value,
+                        time: entry.time,
+                        replica: entry.replica,
+                    };
+                    replica.merge(entry.key, record, false);
+                }
+            }
+            Ok(Update::Digest(digest)) => {
+                if digest != self.replica.lock().unwrap().digest() {
+                    self.sync_soon();
+                }
+            }
+            Err(e) => log::warn!("ignoring malformed update of state {}: {}", self.name, e),
+        }
+    }
+
+    fn sync_soon(&mut self) {
+        let at = Instant::now() + SYNC_DELAY;
+        self.sync_at = Some(self.sync_at.map_or(at, |sync_at| sync_at.min(at)));
+    }
+
+    fn tick(&mut self, last_digest: &mut Instant) -> Result<(), Error> {
+        if self.sync_at.is_some_and(|at| at <= Instant::now()) {
+            self.sync_at = None;
+            let entries = self.replica.lock().unwrap().entries();
+            if !entries.is_empty() {
+                self.publish(&Update::Entries(entries))?;
+            }
+        }
+        if last_digest.elapsed() >= DIGEST_INTERVAL {
+            *last_digest = Instant::now();
+            let digest = self.replica.lock().unwrap().digest();
+            self.publish(&Update::Digest(digest))?;
+        }
+        Ok(())
+    }
+
+    /// Follow the updates of the other replicas and the peers joining until `stop` fires.
+    async fn run(
+        mut self,
+        subscription: Subscription,
+        events: Events,
+        stop: oneshot::Receiver<()>,
+    ) {
+        let mut events = events.fuse();
+        let mut subscription = subscription.fuse();
+        let mut ticks = stream::interval(SYNC_DELAY / 4).fuse();
+        let mut stop = stop.fuse();
+        let mut last_digest = Instant::now();
+        loop {
+            select! {
+                message = subscription.next() => match message {
+                    Some(message) => self.receive(message),
+                    None => return,
+                },
+                event = events.next() => match event {
+                    Some(NodeEvent::Subscribed { peer, topic })
+                        if topic == self.state_topic && &peer != self.client.local_peer_id() =>
+                    {
+                        self.sync_soon()
+                    }
+                    Some(_) => {}
+                    None => return,
+                },
+                _ = ticks.next() => {
+                    if let Err(e) = self.tick(&mut last_digest) {
+                        log::warn!("failed to sync state {}: {}", self.name, e);
+                    }
+                }
+                _ = stop => return,
+            }
+        }
+    }
+}
+
+/// Hold a replica of the map `name`, kept in sync with the other nodes holding it.
+pub(crate) fn join(client: &Client, name: &str) -> Result<StateMap, Error> {
+    let state_topic = format!("pubsub-lite/state/{}", name);
+    let replica = Arc::new(Mutex::new(Replica {
+        id: client.local_peer_id().to_base58(),
+        records: BTreeMap::new(),
+        clock: 0,
+        watchers: Vec::new(),
+    }));
+    let (stop, stopped) = oneshot::channel();
+    let syncer = Syncer {
+        client: client.clone(),
+        name: name.to_owned(),
+        state_topic: state_topic.clone(),
+        replica: replica.clone(),
+        sync_at: None,
+    };
+    // Watching events first, not to miss the peers seen subscribed when subscribing.
+    let events = client.events()?;
+    task::spawn(syncer.run(client.subscribe(&state_topic)?, events, stopped));
+    Ok(StateMap {
+        client: client.clone(),
+        name: name.to_owned(),
+        state_topic,
+        replica,
+        stop: Some(stop),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replica(id: &str) -> Replica {
+        Replica {
+            id: id.to_owned(),
+            records: BTreeMap::new(),
+            clock: 0,
+            watchers: Vec::new(),
+        }
+    }
+
+    fn record(value: Option<&'static str>, time: u64, replica: &str) -> Record {
+        Record {
+            value: value.map(Bytes::from),
+            time,
+            replica: replica.to_owned(),
+        }
+    }
+
+    #[test]
+    fn greatest_version_wins_in_any_order() {
+        let records = [
+            record(Some("old"), 1, "b"),
+            record(Some("new"), 2, "a"),
+            record(Some("tie"), 2, "b"),
+            record(None, 1, "c"),
+        ];
+        let mut forward = replica("x");
+        let mut backward = replica("y");
+        for record in records.iter() {
+            forward.merge("key".to_owned(), record.clone(), false);
+        }
+        for record in records.iter().rev() {
+            backward.merge("key".to_owned(), record.clone(), false);
+        }
+        let held = &forward.records["key"];
+        assert_eq!(held.value, Some(Bytes::from_static(b"tie")));
+        assert_eq!(forward.digest(), backward.digest());
+        assert_eq!(forward.clock, 2);
+    }
+
+    #[test]
+    fn tombstones_win_over_older_values() {
+        let mut replica = replica("a");
+        replica.write("key", Some(Bytes::from_static(b"value")));
+        replica.write("key", None);
+        let stale = record(Some("stale"), replica.clock - 1, "b");
+        replica.merge("key".to_owned(), stale, false);
+        assert_eq!(replica.records["key"].value, None);
+        assert!(replica.entries()[0].value.is_none());
+    }
+
+    #[test]
+    fn writes_follow_the_greatest_version_seen() {
+        let mut replica = replica("a");
+        let ahead = u64::MAX / 2;
+        replica.merge("key".to_owned(), record(Some("remote"), ahead, "b"), false);
+        let entry = replica.write("key", Some(Bytes::from_static(b"local")));
+        assert_eq!(entry.time, ahead + 1);
+        assert_eq!(
+            replica.records["key"].value,
+            Some(Bytes::from_static(b"local"))
+        );
+    }
+
+    #[test]
+    fn watchers_see_changes_only() {
+        let mut replica = replica("a");
+        let (watcher, mut changes) = mpsc::unbounded();
+        replica.watchers.push(watcher);
+        replica.merge("key".to_owned(), record(Some("v"), 1, "b"), false);
+        replica.merge("key".to_owned(), record(Some("v"), 2, "c"), false);
+        replica.merge("key".to_owned(), record(Some("w"), 1, "c"), false);
+        let change = changes.try_next().unwrap().unwrap();
+        assert_eq!(change.value, Some(Bytes::from_static(b"v")));
+        assert!(!change.local);
+        assert!(changes.try_next().is_err());
+    }
+}
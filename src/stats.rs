@@ -0,0 +1,360 @@
+//! Snapshots of the state of a node, returned by [`Client::stats`](crate::Client::stats).
+
+use crate::bandwidth::Traffic;
+use crate::client::Message;
+use crate::flow::QueueStatus;
+use crate::priority::Priority;
+use libp2p::{gossipsub::protocol::MessageId, PeerId};
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime},
+};
+
+/// Messages listed in [`Stats::recent_messages`].
+pub const RECENT_MESSAGES: usize = 32;
+
+/// Bytes of the payload of a [`RecentMessage`] kept in its preview.
+pub const PREVIEW_SIZE: usize = 256;
+
+/// State of a node at some point in time.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Connected peers.
+    pub peers: Vec<PeerStats>,
+    /// Topics traffic was exchanged on since the node started.
+    pub topics: Vec<TopicStats>,
+    /// Local subscriptions, by id.
+    pub subscriptions: Vec<SubscriptionStats>,
+    /// Bridges and sinks running on the node.
+    pub bridges: Vec<BridgeStats>,
+    /// Messages sent at every priority, the most urgent first, see the
+    /// [`priority`](crate::priority) module.
+    pub priorities: Vec<PriorityStats>,
+    /// The last [`RECENT_MESSAGES`] messages handed to local subscribers, the oldest first.
+    pub recent_messages: Vec<RecentMessage>,
+    /// Number of times a peer has been graylisted for exceeding its rate limit.
+    pub graylistings: u64,
+    /// Received messages validated since the node started.
+    pub validation: ValidationStats,
+    /// Payloads kept by the node for gossip and retention.
+    pub memory: MemoryStats,
+    /// Peers exchanged with the peers pruning the node from meshes, see the
+    /// [`peer_exchange`](crate::peer_exchange) module.
+    pub peer_exchange: PeerExchangeStats,
+    /// Faults injected into the gossip of the node, see the [`chaos`](crate::chaos) module.
+    pub chaos: ChaosStats,
+}
+
+/// State of a connected peer.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub peer: PeerId,
+    /// Topics the peer is subscribed to.
+    pub topics: Vec<String>,
+    /// Gossipsub traffic exchanged with the peer since it connected.
+    pub traffic: Traffic,
+    /// Whether the peer is ignored for exceeding its rate limit.
+    pub graylisted: bool,
+    /// Whether the peer is an explicit peer, kept in the mesh of every topic, see the
+    /// [`peering`](crate::peering) module.
+    pub explicit: bool,
+    /// Round-trip times of the last successful pings of the peer, the oldest first, see the
+    /// [`liveness`](crate::liveness) module.
+    pub rtt_history: Vec<Duration>,
+    /// Pings of the peer failed since the last that succeeded.
+    pub ping_failures: u32,
+    /// How far ahead of the clock of this node that of the peer is, in microseconds, negative
+    /// if behind, once estimated, see the [`clock`](crate::clock) module.
+    pub clock_skew: Option<i64>,
+}
+
+/// State of a topic.
+#[derive(Clone, Debug)]
+pub struct TopicStats {
+    pub topic: String,
+    /// Payload bytes and messages sent and received on the topic.
+    pub traffic: Traffic,
+    /// Messages published by the node and handed to its own subscribers.
+    pub local: LocalDelivery,
+    /// Sizes of the payloads published and received on the topic.
+    pub sizes: SizeStats,
+    /// Messages of sequenced publishers found lost on the topic.
+    pub sequence: SequenceStats,
+}
+
+/// Upper bounds in bytes of the buckets of a [`SizeStats`] histogram.
+pub const SIZE_BUCKETS: [usize; 8] = [64, 256, 1024, 4096, 16384, 65536, 262_144, 1_048_576];
+
+/// Histogram of the sizes of the payloads of a topic, as published, before compression,
+/// encryption and chunking.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeStats {
+    /// Number of payloads by size: `buckets[i]` counts those larger than the bound of the
+    /// previous bucket and at most [`SIZE_BUCKETS[i]`](SIZE_BUCKETS) bytes, the last one those
+    /// larger than every bound.
+    pub buckets: [u64; SIZE_BUCKETS.len() + 1],
+    /// Total size of the payloads in bytes.
+    pub sum: u64,
+    /// Payloads received over the limit of the topic and dropped, see the
+    /// [`size`](crate::size) module.
+    pub oversized: u64,
+}
+
+impl SizeStats {
+    /// Number of payloads counted by the histogram.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub(crate) fn record(&mut self, size: usize) {
+        let bucket = SIZE_BUCKETS
+            .iter()
+            .position(|bound| size <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += size as u64;
+    }
+}
+
+/// Messages a node handed to its own subscribers without a network round trip, see
+/// [`NodeConfig::local_delivery`](crate::NodeConfig::local_delivery).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LocalDelivery {
+    pub messages: u64,
+    /// Payload bytes of the messages.
+    pub bytes: u64,
+}
+
+/// Messages of the publishers numbering those they publish on a topic that a node found missing,
+/// see the [`sequence`](crate::sequence) module.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    /// Messages missing from the sequences received, those recovered later included.
+    pub lost: u64,
+    /// Messages that came late, after being counted lost, resent or not.
+    pub recovered: u64,
+}
+
+/// Whole messages a node received and validated, see the [`validation`](crate::validation)
+/// module.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValidationStats {
+    /// Messages waiting for a worker or being validated.
+    pub pending: usize,
+    pub accepted: u64,
+    /// Messages failing to reassemble, decrypt or verify, or whose every payload the plugins of
+    /// their topics dropped.
+    pub rejected: u64,
+    /// Messages dropped unvalidated because too many were waiting for a worker.
+    pub dropped: u64,
+    /// Messages rejected as replays, see the [`replay`](crate::replay) module, counted among
+    /// those rejected.
+    pub replayed: u64,
+}
+
+/// Payloads a node keeps in memory, see
+/// [`NodeConfig::memory_budget`](crate::NodeConfig::memory_budget).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Bytes the payloads may take, if limited.
+    pub budget: Option<usize>,
+    /// Bytes of the payloads of the messages gossipsub keeps to gossip about.
+    pub message_cache: usize,
+    /// Bytes of the payloads of the messages retained for later subscribers, see the
+    /// [`retention`](crate::retention) module.
+    pub retained: usize,
+    /// Messages dropped from either to stay within the budget.
+    pub evicted: u64,
+}
+
+/// Peer exchange since the node started, see
+/// [`NodeConfig::peer_exchange`](crate::NodeConfig::peer_exchange).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerExchangeStats {
+    /// Connected peers whose signed record is known, to suggest them.
+    pub records: usize,
+    /// Peers suggested along with the PRUNEs received.
+    pub suggested: u64,
+    /// Addresses of suggested peers dialed.
+    pub dialed: u64,
+}
+
+/// Gossipsub frames faults were injected into since the node started, see
+/// [`NodeConfig::chaos`](crate::NodeConfig::chaos).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChaosStats {
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+}
+
+impl MemoryStats {
+    /// Bytes of the payloads kept.
+    pub fn used(&self) -> usize {
+        self.message_cache + self.retained
+    }
+}
+
+/// Messages a node sent at a priority, see the [`priority`](crate::priority) module.
+#[derive(Clone, Copy, Debug)]
+pub struct PriorityStats {
+    pub priority: Priority,
+    /// Messages waiting for the upload limit of the node.
+    pub queued: usize,
+    pub sent: u64,
+    /// Messages dropped because too many were waiting.
+    pub dropped: u64,
+    /// How long the messages sent waited.
+    pub delay: DelayStats,
+}
+
+/// Upper bounds of the buckets of a [`DelayStats`] histogram.
+pub const DELAY_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// Histogram of the time messages spent queued.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DelayStats {
+    /// Number of messages by delay: `buckets[i]` counts those that waited longer than the bound
+    /// of the previous bucket and at most [`DELAY_BUCKETS[i]`](DELAY_BUCKETS), the last one those
+    /// that waited longer than every bound.
+    pub buckets: [u64; DELAY_BUCKETS.len() + 1],
+    /// Total time the messages waited.
+    pub sum: Duration,
+}
+
+impl DelayStats {
+    /// Number of messages counted by the histogram.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub(crate) fn record(&mut self, delay: Duration) {
+        let bucket = DELAY_BUCKETS
+            .iter()
+            .position(|bound| delay <= *bound)
+            .unwrap_or(DELAY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += delay;
+    }
+}
+
+/// A message handed to local subscribers lately.
+#[derive(Clone, Debug)]
+pub struct RecentMessage {
+    pub id: MessageId,
+    pub source: PeerId,
+    pub topic: String,
+    /// Size of the payload in bytes.
+    pub size: usize,
+    /// The first [`PREVIEW_SIZE`] bytes of the payload.
+    pub preview: Vec<u8>,
+    pub received: SystemTime,
+}
+
+/// The last messages handed to local subscribers.
+#[derive(Default)]
+pub(crate) struct RecentMessages(VecDeque<RecentMessage>);
+
+impl RecentMessages {
+    pub(crate) fn record(&mut self, message: &Message) {
+        if self.0.len() >= RECENT_MESSAGES {
+            self.0.pop_front();
+        }
+        self.0.push_back(RecentMessage {
+            id: message.id.clone(),
+            source: message.source.clone(),
+            topic: message.topic.clone(),
+            size: message.data.len(),
+            preview: message.data[..message.data.len().min(PREVIEW_SIZE)].to_vec(),
+            received: SystemTime::now(),
+        });
+    }
+
+    pub(crate) fn list(&self) -> Vec<RecentMessage> {
+        self.0.iter().cloned().collect()
+    }
+}
+
+/// State of a local subscription.
+#[derive(Clone, Debug)]
+pub struct SubscriptionStats {
+    /// Unique among the subscriptions of the node.
+    pub id: u64,
+    /// Topic or topic filter subscribed to.
+    pub topic: String,
+    /// Messages waiting for the subscriber, and those dropped because it fell behind.
+    pub queue: QueueStatus,
+}
+
+/// State of a bridge or sink.
+#[derive(Clone, Debug)]
+pub struct BridgeStats {
+    /// The kind of bridge, its name or client id and the address of the external system.
+    pub name: String,
+    /// Whether the bridge is connected to the external system.
+    pub connected: bool,
+    /// How long the bridge has been connected, or disconnected.
+    pub since: Duration,
+    /// Messages relayed into the mesh, counted as received, and out of it, counted as sent.
+    pub traffic: Traffic,
+    /// Failed connection attempts and dropped connections.
+    pub retries: u64,
+    /// Mesh messages waiting to cross the bridge. Its lag is the end-to-end lag of the bridge.
+    pub outbox: QueueStatus,
+}
+
+/// Gossip state of the node, returned by [`Client::mesh_info`](crate::Client::mesh_info): which
+/// peers it exchanges messages with on each topic.
+#[derive(Clone, Debug, Default)]
+pub struct MeshInfo {
+    /// Topics the node or a connected peer is subscribed to, or the node publishes on, by name.
+    pub topics: Vec<TopicMesh>,
+}
+
+/// Gossip state of a topic.
+#[derive(Clone, Debug)]
+pub struct TopicMesh {
+    pub topic: String,
+    /// Whether the node is subscribed to the topic, and so has a mesh for it.
+    pub subscribed: bool,
+    /// Connected peers subscribed to the topic or in its fanout, by peer id.
+    pub peers: Vec<MeshPeer>,
+}
+
+impl TopicMesh {
+    /// Number of peers with the given role.
+    pub fn count(&self, role: MeshRole) -> usize {
+        self.peers.iter().filter(|peer| peer.role == role).count()
+    }
+}
+
+/// How the node exchanges the messages of a topic with a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshRole {
+    /// The peer is in the mesh of the topic: messages are forwarded to it in full.
+    Mesh,
+    /// The node publishes on the topic without being subscribed, through the peer.
+    Fanout,
+    /// The peer is subscribed to the topic but only hears of its messages through gossip.
+    Gossip,
+}
+
+/// A peer as seen on a topic.
+#[derive(Clone, Debug)]
+pub struct MeshPeer {
+    pub peer: PeerId,
+    pub role: MeshRole,
+    /// Agent version the peer identified itself with, if it has been identified.
+    pub agent_version: Option<String>,
+    /// Round-trip time of the last successful ping of the peer.
+    pub rtt: Option<Duration>,
+}
@@ -0,0 +1,278 @@
+//! Storage backends of the messages a node keeps: the [retained](crate::retention) messages
+//! replayed to late subscribers, and the journals of [durable](crate::durable) subscriptions.
+//!
+//! A [`Storage`] holds logs of messages, each named and numbering its messages from 1 in the
+//! order they were appended, scanned by range of sequence numbers and pruned from the oldest.
+//! Retained messages go to the log `retained/<topic>` of their topic, and the messages of a
+//! durable subscription opened with
+//! [`Client::subscribe_durable_in`](crate::Client::subscribe_durable_in) to the log
+//! `journal/<name>` of its journal, along with its cursor.
+//!
+//! Two backends are built in:
+//!
+//! - [`MemoryStorage`], keeping everything in memory until the process exits, for tests and
+//!   embedders whose persistence lives elsewhere;
+//! - `SledStorage`, behind the cargo feature `sled`, keeping everything in a
+//!   [sled](https://docs.rs/sled) database on disk, so that retained messages survive restarts.
+//!
+//! Embedders implement [`Storage`] for their own backends, such as RocksDB or an object store.
+//! The node calls storages from its event loop, so calls should return fast: a slow backend is
+//! best fronted by a cache.
+
+use crate::{Error, Message};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// A message read from a [`Storage`].
+#[derive(Clone, Debug)]
+pub struct Stored {
+    /// Number of the message in its log.
+    pub seq: u64,
+    /// When the message was appended.
+    pub stored_at: SystemTime,
+    pub message: Message,
+}
+
+/// A backend storing logs of messages, see the [module documentation](self).
+pub trait Storage: Send + Sync {
+    /// Append `message` to `log`, returning its sequence number: one past the last appended to
+    /// the log, even if pruned, 1 for the first.
+    fn append(&self, log: &str, message: &Message) -> Result<u64, Error>;
+
+    /// The messages of `log` numbered within `range`, in order.
+    fn scan(&self, log: &str, range: Range<u64>) -> Result<Vec<Stored>, Error>;
+
+    /// Drop the messages of `log` numbered before `seq`, returning how many were dropped.
+    fn prune(&self, log: &str, seq: u64) -> Result<usize, Error>;
+
+    /// Sequence number of the last message appended to `log`, 0 if none was.
+    fn last(&self, log: &str) -> Result<u64, Error>;
+
+    /// The logs with messages.
+    fn logs(&self) -> Result<Vec<String>, Error>;
+
+    /// The cursor stored as `name`, 0 if none was.
+    fn cursor(&self, name: &str) -> Result<u64, Error>;
+
+    /// Store `position` as the cursor `name`.
+    fn set_cursor(&self, name: &str, position: u64) -> Result<(), Error>;
+}
+
+#[derive(Default)]
+struct Log {
+    last: u64,
+    messages: BTreeMap<u64, (SystemTime, Message)>,
+}
+
+/// A [`Storage`] keeping everything in memory.
+#[derive(Default)]
+pub struct MemoryStorage {
+    logs: Mutex<HashMap<String, Log>>,
+    cursors: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn append(&self, log: &str, message: &Message) -> Result<u64, Error> {
+        let mut logs = self.logs.lock().unwrap();
+        let log = logs.entry(log.to_owned()).or_default();
+        log.last += 1;
+        log.messages
+            .insert(log.last, (SystemTime::now(), message.clone()));
+        Ok(log.last)
+    }
+
+    fn scan(&self, log: &str, range: Range<u64>) -> Result<Vec<Stored>, Error> {
+        let logs = self.logs.lock().unwrap();
+        Ok(logs.get(log).map_or_else(Vec::new, |log| {
+            log.messages
+                .range(range)
+                .map(|(seq, (stored_at, message))| Stored {
+                    seq: *seq,
+                    stored_at: *stored_at,
+                    message: message.clone(),
+                })
+                .collect()
+        }))
+    }
+
+    fn prune(&self, log: &str, seq: u64) -> Result<usize, Error> {
+        let mut logs = self.logs.lock().unwrap();
+        Ok(logs.get_mut(log).map_or(0, |log| {
+            let kept = log.messages.split_off(&seq);
+            std::mem::replace(&mut log.messages, kept).len()
+        }))
+    }
+
+    fn last(&self, log: &str) -> Result<u64, Error> {
+        Ok(self.logs.lock().unwrap().get(log).map_or(0, |log| log.last))
+    }
+
+    fn logs(&self) -> Result<Vec<String>, Error> {
+        let logs = self.logs.lock().unwrap();
+        Ok(logs
+            .iter()
+            .filter(|(_, log)| !log.messages.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    fn cursor(&self, name: &str) -> Result<u64, Error> {
+        Ok(self.cursors.lock().unwrap().get(name).copied().unwrap_or(0))
+    }
+
+    fn set_cursor(&self, name: &str, position: u64) -> Result<(), Error> {
+        self.cursors
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), position);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled")]
+pub use self::sled_storage::SledStorage;
+
+#[cfg(feature = "sled")]
+mod sled_storage {
+    use super::{Storage, Stored};
+    use crate::{durable::Entry, Error, Message, PubSubError};
+    use std::{convert::TryInto, ops::Range, path::Path};
+
+    /// A [`Storage`] keeping everything in a sled database. Every append is flushed to disk
+    /// before it returns.
+    pub struct SledStorage {
+        messages: sled::Tree,
+        last: sled::Tree,
+        cursors: sled::Tree,
+    }
+
+    impl SledStorage {
+        /// Open the database at `path`, creating it if there is none.
+        pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+            let db = sled::open(path).map_err(PubSubError::other)?;
+            SledStorage::with_db(&db)
+        }
+
+        /// Keep everything in trees of `db`, named after `pubsub-lite/`.
+        pub fn with_db(db: &sled::Db) -> Result<Self, Error> {
+            let tree = |name: &str| {
+                db.open_tree(format!("pubsub-lite/{}", name))
+                    .map_err(PubSubError::other)
+            };
+            Ok(SledStorage {
+                messages: tree("messages")?,
+                last: tree("last")?,
+                cursors: tree("cursors")?,
+            })
+        }
+    }
+
+    /// The key of the message `seq` of `log`, sorting the messages of a log by number.
+    fn key(log: &str, seq: u64) -> Vec<u8> {
+        let mut key = Vec::with_capacity(log.len() + 9);
+        key.extend_from_slice(log.as_bytes());
+        key.push(0);
+        key.extend_from_slice(&seq.to_be_bytes());
+        key
+    }
+
+    /// Encode a stored message as in journal files.
+    fn encode(seq: u64, message: &Message) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(&Entry::new(seq, message))?)
+    }
+
+    fn decode(data: &[u8]) -> Result<Stored, Error> {
+        let entry: Entry = serde_json::from_slice(data)?;
+        Ok(Stored {
+            seq: entry.position,
+            stored_at: entry.journaled_at(),
+            message: entry.message()?,
+        })
+    }
+
+    fn number(value: &[u8]) -> u64 {
+        value.try_into().map_or(0, u64::from_be_bytes)
+    }
+
+    impl Storage for SledStorage {
+        fn append(&self, log: &str, message: &Message) -> Result<u64, Error> {
+            let last = self
+                .last
+                .update_and_fetch(log, |last| {
+                    Some((last.map_or(0, number) + 1).to_be_bytes().to_vec())
+                })
+                .map_err(PubSubError::other)?;
+            let seq = last.as_deref().map_or(1, number);
+            self.messages
+                .insert(key(log, seq), encode(seq, message)?)
+                .map_err(PubSubError::other)?;
+            self.messages.flush().map_err(PubSubError::other)?;
+            Ok(seq)
+        }
+
+        fn scan(&self, log: &str, range: Range<u64>) -> Result<Vec<Stored>, Error> {
+            if range.start >= range.end {
+                return Ok(Vec::new());
+            }
+            self.messages
+                .range(key(log, range.start)..key(log, range.end))
+                .map(|entry| decode(&entry.map_err(PubSubError::other)?.1))
+                .collect()
+        }
+
+        fn prune(&self, log: &str, seq: u64) -> Result<usize, Error> {
+            let mut batch = sled::Batch::default();
+            let mut pruned = 0;
+            for entry in self.messages.range(key(log, 0)..key(log, seq)) {
+                batch.remove(entry.map_err(PubSubError::other)?.0);
+                pruned += 1;
+            }
+            self.messages
+                .apply_batch(batch)
+                .map_err(PubSubError::other)?;
+            Ok(pruned)
+        }
+
+        fn last(&self, log: &str) -> Result<u64, Error> {
+            let last = self.last.get(log).map_err(PubSubError::other)?;
+            Ok(last.as_deref().map_or(0, number))
+        }
+
+        fn logs(&self) -> Result<Vec<String>, Error> {
+            let mut logs = Vec::new();
+            for entry in self.last.iter() {
+                let log =
+                    String::from_utf8_lossy(&entry.map_err(PubSubError::other)?.0).into_owned();
+                let mut prefix = log.clone().into_bytes();
+                prefix.push(0);
+                if self.messages.scan_prefix(prefix).next().is_some() {
+                    logs.push(log);
+                }
+            }
+            Ok(logs)
+        }
+
+        fn cursor(&self, name: &str) -> Result<u64, Error> {
+            let cursor = self.cursors.get(name).map_err(PubSubError::other)?;
+            Ok(cursor.as_deref().map_or(0, number))
+        }
+
+        fn set_cursor(&self, name: &str, position: u64) -> Result<(), Error> {
+            self.cursors
+                .insert(name, &position.to_be_bytes())
+                .map_err(PubSubError::other)?;
+            self.cursors.flush().map_err(PubSubError::other)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Harness running networks of nodes within the process, for integration tests of the crate and
+//! of applications built on it.
+//!
+//! A [`TestNetwork`] spawns nodes listening on `/memory/` addresses, which the libp2p memory
+//! transport connects without sockets, and wires them with [`TestNetwork::connect`] or
+//! [`TestNetwork::connect_all`]. Rather than sleeping until gossipsub has settled, tests wait for
+//! what they need: [`TestNetwork::join`] subscribes every node to a topic and returns once every
+//! mesh is formed, and [`TestNetwork::publish`] returns the message as every subscription
+//! received it. Waits fail after [`TIMEOUT`] instead of hanging the test. Nodes leave and come
+//! back with [`TestNetwork::stop`] and [`TestNetwork::restart`], as churn would have them.
+//!
+//! Nodes are configured by [`test_config`], deriving the keypair of every node from its index so
+//! that peer ids are the same on every run, and with a heartbeat fast enough for meshes to form
+//! in a fraction of a second.
+
+use crate::{
+    node::{self, Command},
+    transport::keypair_from_seed,
+    Client, Error, Message, NodeConfig, Subscription,
+};
+use async_std::future::timeout;
+use bytes::Bytes;
+use futures::{future, prelude::*};
+use libp2p::{core::multiaddr::Protocol, Multiaddr};
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// How long waiting for the network may take before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Memory port of the next node, shared by the networks of the process so that tests running
+/// concurrently do not collide.
+static NEXT_PORT: AtomicU64 = AtomicU64::new(1);
+
+/// Configuration of the node at `index` in a test network.
+pub fn test_config(index: usize) -> NodeConfig {
+    let mut config = NodeConfig {
+        keypair: keypair_from_seed(format!("test node {}", index)),
+        ..NodeConfig::default()
+    };
+    config.gossipsub.heartbeat_initial_delay = Duration::from_millis(10);
+    config.gossipsub.heartbeat_interval = Duration::from_millis(100);
+    config
+}
+
+/// Nodes running in this process, connected through the memory transport.
+pub struct TestNetwork {
+    nodes: Vec<TestNode>,
+}
+
+struct TestNode {
+    /// `None` while the node is stopped.
+    client: Option<Client>,
+    addr: Multiaddr,
+    mesh_n_low: usize,
+    /// Indexes of the nodes connected to this one.
+    links: HashSet<usize>,
+}
+
+impl TestNetwork {
+    /// Spawn `size` unconnected nodes configured by [`test_config`].
+    pub fn new(size: usize) -> Result<Self, Error> {
+        TestNetwork::with_config(size, test_config)
+    }
+
+    /// Spawn `size` unconnected nodes, configured by `config` given the index of each, whose
+    /// listen addresses are replaced by a `/memory/` one.
+    pub fn with_config(
+        size: usize,
+        mut config: impl FnMut(usize) -> NodeConfig,
+    ) -> Result<Self, Error> {
+        let nodes = (0..size)
+            .map(|index| {
+                let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+                let addr: Multiaddr = Protocol::Memory(port).into();
+                let config = NodeConfig {
+                    listen_addrs: vec![addr.clone()],
+                    ..config(index)
+                };
+                let mesh_n_low = config.gossipsub.mesh_n_low;
+                Ok(TestNode {
+                    client: Some(node::spawn(config)?),
+                    addr,
+                    mesh_n_low,
+                    links: HashSet::new(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(TestNetwork { nodes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The client of the node at `index`. Panics if the node is stopped.
+    pub fn node(&self, index: usize) -> &Client {
+        self.nodes[index]
+            .client
+            .as_ref()
+            .expect("the node is stopped")
+    }
+
+    /// The clients of the running nodes.
+    pub fn nodes(&self) -> impl Iterator<Item = &Client> {
+        self.nodes.iter().filter_map(|node| node.client.as_ref())
+    }
+
+    pub fn is_running(&self, index: usize) -> bool {
+        self.nodes[index].client.is_some()
+    }
+
+    /// Shut the node at `index` down, as if it left the network: the node stops once the
+    /// subscriptions and clients taken from it are dropped too, closing its connections.
+    pub fn stop(&mut self, index: usize) {
+        self.nodes[index].client = None;
+    }
+
+    /// Spawn the node at `index` anew with `config`, listening on a new `/memory/` address, and
+    /// have it dial the running nodes it was connected to.
+    pub fn restart(&mut self, index: usize, config: NodeConfig) -> Result<(), Error> {
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        let addr: Multiaddr = Protocol::Memory(port).into();
+        let config = NodeConfig {
+            listen_addrs: vec![addr.clone()],
+            ..config
+        };
+        let node = &mut self.nodes[index];
+        node.mesh_n_low = config.gossipsub.mesh_n_low;
+        node.client = Some(node::spawn(config)?);
+        node.addr = addr;
+        let links: Vec<usize> = node.links.iter().copied().collect();
+        for to in links {
+            if self.is_running(to) {
+                let addr = self.nodes[to].addr.clone();
+                self.node(index).send(Command::Dial { addr })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The address the node at `index` listens on.
+    pub fn addr(&self, index: usize) -> &Multiaddr {
+        &self.nodes[index].addr
+    }
+
+    /// Have node `from` dial node `to`.
+    pub fn connect(&mut self, from: usize, to: usize) -> Result<(), Error> {
+        if from == to {
+            return Err("a node cannot connect to itself".into());
+        }
+        let addr = self.nodes[to].addr.clone();
+        self.node(from).send(Command::Dial { addr })?;
+        self.nodes[from].links.insert(to);
+        self.nodes[to].links.insert(from);
+        Ok(())
+    }
+
+    /// Connect every node to every other.
+    pub fn connect_all(&mut self) -> Result<(), Error> {
+        for from in 0..self.nodes.len() {
+            for to in from + 1..self.nodes.len() {
+                self.connect(from, to)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe every running node to `topic` and wait until its mesh holds as many of the
+    /// running nodes it is connected to as gossipsub grafts, all of them up to `mesh_n_low`.
+    pub async fn join(&self, topic: &str) -> Result<Vec<Subscription>, Error> {
+        let subscriptions = self
+            .nodes()
+            .map(|client| client.subscribe(topic))
+            .collect::<Result<Vec<_>, _>>()?;
+        let joined = self.nodes.iter().filter_map(|node| {
+            let client = node.client.as_ref()?;
+            let links = node.links.iter().filter(|&&to| self.is_running(to));
+            let peers = links.count().min(node.mesh_n_low);
+            Some(client.on_mesh_peers(topic, peers))
+        });
+        timeout(TIMEOUT, future::try_join_all(joined))
+            .await
+            .map_err(|_| format!("meshes of {} not formed in time", topic))??;
+        Ok(subscriptions)
+    }
+
+    /// Publish `data` on `topic` from the node at `from`, and wait for every subscription to
+    /// receive it, returning the messages they received.
+    pub async fn publish(
+        &self,
+        from: usize,
+        topic: &str,
+        data: impl Into<Bytes>,
+        subscriptions: &mut [Subscription],
+    ) -> Result<Vec<Message>, Error> {
+        self.node(from).publish(topic, data)?;
+        future::try_join_all(subscriptions.iter_mut().map(next_message)).await
+    }
+}
+
+/// The next message of `subscription`, failing if none comes within [`TIMEOUT`].
+pub async fn next_message(subscription: &mut Subscription) -> Result<Message, Error> {
+    let topic = subscription.topic().to_owned();
+    match timeout(TIMEOUT, subscription.next()).await {
+        Ok(Some(message)) => Ok(message),
+        Ok(None) => Err(Error::Shutdown),
+        Err(_) => Err(format!("no message on {} in time", topic).into()),
+    }
+}
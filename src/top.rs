@@ -0,0 +1,518 @@
+//! A terminal monitor of a running node, `pub top` in the example command line.
+//!
+//! [`run`] takes over the terminal and polls the [admin endpoint](crate::gateway::admin) of the
+//! node every [`refresh`](TopConfig::refresh), showing the topics of the node with their mesh,
+//! fanout and gossip peers and their message and byte rates, the connected peers with their
+//! bandwidth and round-trip time, and the last messages handed to its subscribers. Rates are
+//! those between the last two polls. `q`, `Esc` or `Ctrl-C` quit.
+//!
+//! The endpoint is reached over plain TCP or the unix socket it is served on; one served over TLS
+//! is not. A node that stops answering keeps its last figures on screen, with the error, until it
+//! answers again.
+//!
+//! The monitor is behind the `tui` cargo feature.
+
+use crate::{gateway::admin, Error, PubSubError};
+use async_std::{io, net::TcpStream, os::unix::net::UnixStream, prelude::*, task};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Cell, Row, Table},
+    DefaultTerminal, Frame,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long a request to the admin endpoint may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the admin endpoint of the node is served.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A TCP address, as `host:port`.
+    Tcp(String),
+    /// A unix socket.
+    Unix(PathBuf),
+}
+
+/// What to monitor, and how.
+#[derive(Clone, Debug)]
+pub struct TopConfig {
+    pub endpoint: Endpoint,
+    /// Bearer token of the endpoint, if it asks for one. Read-only access is enough.
+    pub token: Option<String>,
+    /// How often the node is polled.
+    pub refresh: Duration,
+}
+
+impl Default for TopConfig {
+    /// The endpoint on the [default unix socket](admin::socket_path), polled every second.
+    fn default() -> Self {
+        TopConfig {
+            endpoint: Endpoint::Unix(
+                admin::socket_path().unwrap_or_else(|| PathBuf::from(admin::SOCKET_NAME)),
+            ),
+            token: None,
+            refresh: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A topic as listed by `/topics`.
+#[derive(Deserialize)]
+struct TopicEntry {
+    topic: String,
+    subscribed: bool,
+    mesh: usize,
+    fanout: usize,
+    gossip: usize,
+}
+
+/// A peer as listed by `/peers`.
+#[derive(Deserialize)]
+struct PeerEntry {
+    peer: String,
+    topics: Vec<String>,
+    bytes_in: u64,
+    bytes_out: u64,
+    graylisted: bool,
+    explicit: bool,
+    rtt_history_ms: Vec<f64>,
+}
+
+/// A message as listed by `/messages`.
+#[derive(Deserialize)]
+struct MessageEntry {
+    source: String,
+    topic: String,
+    size: usize,
+    preview: String,
+    received_ms: u64,
+}
+
+/// Messages and bytes received and sent on a topic, as counted by `/metrics`.
+#[derive(Clone, Copy, Default)]
+struct Counters {
+    messages_in: u64,
+    messages_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// What the node answered to a poll.
+struct Snapshot {
+    at: Instant,
+    topics: Vec<TopicEntry>,
+    peers: Vec<PeerEntry>,
+    traffic: HashMap<String, Counters>,
+    messages: Vec<MessageEntry>,
+}
+
+/// The state of the monitor.
+struct Top {
+    config: TopConfig,
+    current: Option<Snapshot>,
+    previous: Option<Snapshot>,
+    error: Option<String>,
+}
+
+/// Monitor the node `config` names until the user quits, restoring the terminal on the way out.
+pub fn run(config: TopConfig) -> Result<(), Error> {
+    let mut top = Top {
+        config,
+        current: None,
+        previous: None,
+        error: None,
+    };
+    // Fail before taking over the terminal if the node cannot be reached at all.
+    top.poll()?;
+    let mut terminal = ratatui::init();
+    let result = top.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl Top {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Error> {
+        let mut polled = Instant::now();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let timeout = self.config.refresh.saturating_sub(polled.elapsed());
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL)
+                        && key.code == KeyCode::Char('c');
+                    if key.kind == KeyEventKind::Press
+                        && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc))
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+            if polled.elapsed() >= self.config.refresh {
+                polled = Instant::now();
+                self.error = self.poll().err().map(|e| e.to_string());
+            }
+        }
+    }
+
+    /// Take a snapshot of the node.
+    fn poll(&mut self) -> Result<(), Error> {
+        let snapshot = task::block_on(async {
+            let topics = serde_json::from_slice(&self.get("/topics").await?)?;
+            let peers = serde_json::from_slice(&self.get("/peers").await?)?;
+            let metrics =
+                String::from_utf8(self.get("/metrics").await?).map_err(PubSubError::codec)?;
+            let messages = serde_json::from_slice(&self.get("/messages").await?)?;
+            Ok::<_, Error>(Snapshot {
+                at: Instant::now(),
+                topics,
+                peers,
+                traffic: topic_traffic(&metrics),
+                messages,
+            })
+        })?;
+        self.previous = self.current.replace(snapshot);
+        Ok(())
+    }
+
+    /// The body of the answer of the endpoint to a GET of `path`.
+    async fn get(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n", path);
+        if let Some(token) = &self.config.token {
+            let _ = write!(request, "Authorization: Bearer {}\r\n", token);
+        }
+        request.push_str("\r\n");
+        let exchange = async {
+            let mut response = Vec::new();
+            match &self.config.endpoint {
+                Endpoint::Tcp(addr) => {
+                    let mut stream = TcpStream::connect(addr.as_str()).await?;
+                    stream.write_all(request.as_bytes()).await?;
+                    stream.read_to_end(&mut response).await?;
+                }
+                Endpoint::Unix(path) => {
+                    let mut stream = UnixStream::connect(path).await?;
+                    stream.write_all(request.as_bytes()).await?;
+                    stream.read_to_end(&mut response).await?;
+                }
+            }
+            Ok(response)
+        };
+        let response = io::timeout(REQUEST_TIMEOUT, exchange).await?;
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or("truncated answer from the admin endpoint")?;
+        let head = String::from_utf8_lossy(&response[..end]);
+        let status = head.lines().next().unwrap_or_default();
+        if status.split(' ').nth(1) != Some("200") {
+            return Err(format!("admin endpoint answered {} to {}", status, path).into());
+        }
+        Ok(response[end + 4..].to_vec())
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, tables, messages, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Percentage(55),
+            Constraint::Min(4),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [topics, peers] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)])
+                .areas(tables);
+        self.draw_header(frame, header);
+        self.draw_topics(frame, topics);
+        self.draw_peers(frame, peers);
+        self.draw_messages(frame, messages);
+        let status = match &self.error {
+            Some(error) => Line::from(format!(" {}", error)).red(),
+            None => Line::from(" q quit").dim(),
+        };
+        frame.render_widget(status, footer);
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let endpoint = match &self.config.endpoint {
+            Endpoint::Tcp(addr) => addr.clone(),
+            Endpoint::Unix(path) => path.display().to_string(),
+        };
+        let mut line = format!(" pubsub-lite top - {}", endpoint);
+        if let Some(current) = &self.current {
+            let (bytes_in, bytes_out) = current.peers.iter().fold((0, 0), |(i, o), peer| {
+                let (rate_in, rate_out) = self.peer_rate(peer);
+                (i + rate_in, o + rate_out)
+            });
+            let _ = write!(
+                line,
+                " - {} peers, {} topics, in {}/s, out {}/s",
+                current.peers.len(),
+                current.topics.len(),
+                bytes(bytes_in),
+                bytes(bytes_out)
+            );
+        }
+        frame.render_widget(Line::from(line).bold(), area);
+    }
+
+    fn draw_topics(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.current.iter().flat_map(|current| {
+            current.topics.iter().map(move |topic| {
+                let rate = self.topic_rate(&topic.topic);
+                Row::new(vec![
+                    Cell::from(topic.topic.clone()),
+                    Cell::from(if topic.subscribed { "yes" } else { "" }),
+                    Cell::from(format!("{}/{}/{}", topic.mesh, topic.fanout, topic.gossip)),
+                    Cell::from(format!("{:.1}", rate.0)),
+                    Cell::from(format!("{:.1}", rate.1)),
+                    Cell::from(bytes(rate.2 as u64)),
+                    Cell::from(bytes(rate.3 as u64)),
+                ])
+            })
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(4),
+                Constraint::Length(9),
+                Constraint::Length(9),
+                Constraint::Length(9),
+                Constraint::Length(9),
+                Constraint::Length(9),
+            ],
+        )
+        .header(header(&[
+            "topic",
+            "sub",
+            "m/f/g",
+            "msg in/s",
+            "msg out/s",
+            "B in/s",
+            "B out/s",
+        ]))
+        .block(Block::bordered().title(" Topics "));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.current.iter().flat_map(|current| {
+            current.peers.iter().map(move |peer| {
+                let (rate_in, rate_out) = self.peer_rate(peer);
+                let rtt = peer
+                    .rtt_history_ms
+                    .last()
+                    .map_or_else(String::new, |rtt| format!("{:.0}ms", rtt));
+                let mut flags = String::new();
+                if peer.explicit {
+                    flags.push('E');
+                }
+                if peer.graylisted {
+                    flags.push('G');
+                }
+                let row = Row::new(vec![
+                    Cell::from(short(&peer.peer)),
+                    Cell::from(peer.topics.len().to_string()),
+                    Cell::from(rtt),
+                    Cell::from(bytes(rate_in)),
+                    Cell::from(bytes(rate_out)),
+                    Cell::from(flags),
+                ]);
+                if peer.graylisted {
+                    row.red()
+                } else {
+                    row
+                }
+            })
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(6),
+                Constraint::Length(7),
+                Constraint::Length(9),
+                Constraint::Length(9),
+                Constraint::Length(5),
+            ],
+        )
+        .header(header(&[
+            "peer", "topics", "rtt", "B in/s", "B out/s", "flags",
+        ]))
+        .block(Block::bordered().title(" Peers "));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        // The newest first, as many as fit.
+        let rows = self.current.iter().flat_map(|current| {
+            current.messages.iter().rev().map(move |message| {
+                let age = Duration::from_millis(now.saturating_sub(message.received_ms));
+                let preview: String = message
+                    .preview
+                    .chars()
+                    .map(|c| if c.is_control() { ' ' } else { c })
+                    .collect();
+                Row::new(vec![
+                    Cell::from(format!("{}s ago", age.as_secs())),
+                    Cell::from(message.topic.clone()),
+                    Cell::from(short(&message.source)),
+                    Cell::from(bytes(message.size as u64)),
+                    Cell::from(preview),
+                ])
+            })
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(9),
+                Constraint::Length(20),
+                Constraint::Length(14),
+                Constraint::Length(9),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(header(&["age", "topic", "source", "size", "payload"]))
+        .block(Block::bordered().title(" Recent messages "));
+        frame.render_widget(table, area);
+    }
+
+    /// Messages received and sent, and bytes received and sent, per second on `topic`.
+    fn topic_rate(&self, topic: &str) -> (f64, f64, f64, f64) {
+        let (current, previous) = match (&self.current, &self.previous) {
+            (Some(current), Some(previous)) => (current, previous),
+            _ => return (0.0, 0.0, 0.0, 0.0),
+        };
+        let now = current.traffic.get(topic).copied().unwrap_or_default();
+        let then = previous.traffic.get(topic).copied().unwrap_or_default();
+        let elapsed = current.at.duration_since(previous.at).as_secs_f64();
+        let rate = |now: u64, then: u64| now.saturating_sub(then) as f64 / elapsed;
+        (
+            rate(now.messages_in, then.messages_in),
+            rate(now.messages_out, then.messages_out),
+            rate(now.bytes_in, then.bytes_in),
+            rate(now.bytes_out, then.bytes_out),
+        )
+    }
+
+    /// Bytes received from and sent to `peer` per second.
+    fn peer_rate(&self, peer: &PeerEntry) -> (u64, u64) {
+        let (current, previous) = match (&self.current, &self.previous) {
+            (Some(current), Some(previous)) => (current, previous),
+            _ => return (0, 0),
+        };
+        let then = match previous.peers.iter().find(|then| then.peer == peer.peer) {
+            Some(then) => then,
+            None => return (0, 0),
+        };
+        let elapsed = current.at.duration_since(previous.at).as_secs_f64();
+        let rate = |now: u64, then: u64| (now.saturating_sub(then) as f64 / elapsed) as u64;
+        (
+            rate(peer.bytes_in, then.bytes_in),
+            rate(peer.bytes_out, then.bytes_out),
+        )
+    }
+}
+
+fn header(titles: &[&'static str]) -> Row<'static> {
+    Row::new(titles.iter().copied()).style(Style::new().add_modifier(Modifier::BOLD))
+}
+
+/// The end of a base58 peer id, enough to tell peers apart.
+fn short(peer: &str) -> String {
+    let start = peer.len().saturating_sub(12);
+    format!("…{}", &peer[start..])
+}
+
+/// A byte count in binary units.
+fn bytes(count: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = count as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", count, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// The message and byte counters of every topic in the Prometheus text of `/metrics`.
+fn topic_traffic(metrics: &str) -> HashMap<String, Counters> {
+    let mut traffic: HashMap<String, Counters> = HashMap::new();
+    for line in metrics.lines() {
+        let (name, rest) = match line.split_once('{') {
+            Some(split) => split,
+            None => continue,
+        };
+        if name != "pubsub_topic_messages_total" && name != "pubsub_topic_bytes_total" {
+            continue;
+        }
+        let (labels, value) = match rest.rsplit_once("} ") {
+            Some(split) => split,
+            None => continue,
+        };
+        let value: u64 = match value.trim().parse() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let labels = parse_labels(labels);
+        let (topic, direction) = match (labels.get("topic"), labels.get("direction")) {
+            (Some(topic), Some(direction)) => (topic, direction),
+            _ => continue,
+        };
+        let counters = traffic.entry(topic.clone()).or_default();
+        let counter = match (name, direction.as_str()) {
+            ("pubsub_topic_messages_total", "in") => &mut counters.messages_in,
+            ("pubsub_topic_messages_total", "out") => &mut counters.messages_out,
+            ("pubsub_topic_bytes_total", "in") => &mut counters.bytes_in,
+            ("pubsub_topic_bytes_total", "out") => &mut counters.bytes_out,
+            _ => continue,
+        };
+        *counter = value;
+    }
+    traffic
+}
+
+/// The values of a Prometheus label set, `a="x",b="y"`, by name.
+fn parse_labels(labels: &str) -> HashMap<String, String> {
+    let mut parsed = HashMap::new();
+    let mut chars = labels.chars().peekable();
+    loop {
+        let name: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if name.is_empty() || chars.next() != Some('"') {
+            return parsed;
+        }
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c) => value.push(c),
+                    None => return parsed,
+                },
+                c => value.push(c),
+            }
+        }
+        parsed.insert(name.trim_start_matches(',').to_owned(), value);
+        if chars.peek() == Some(&',') {
+            chars.next();
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Hierarchical topic names and wildcard topic filters.
+//!
+//! Topic names are split into levels by `/`. In a filter, `+` matches exactly one level and `#`
+//! matches any number of trailing levels, including none: `sensors/+/temp` matches
+//! `sensors/kitchen/temp`, and `logs/#` matches `logs`, `logs/app` and `logs/app/error`.
+//!
+//! Gossipsub only delivers messages for topics a node is subscribed to, so a wildcard cannot be
+//! subscribed to directly. Instead, every node periodically announces the topics it publishes on
+//! over [`ANNOUNCE_TOPIC`], and nodes holding wildcard subscriptions subscribe to each announced
+//! topic that matches one of their filters.
+
+use crate::autonat::AUTONAT_TOPIC;
+use crate::clock::CLOCK_TOPIC;
+use crate::directory::DIRECTORY_TOPIC;
+use crate::peer_exchange::PEER_RECORD_TOPIC;
+use crate::rotation::CONTINUITY_TOPIC;
+use crate::sequence::RESEND_TOPIC;
+use std::{error::Error, fmt, str::FromStr};
+
+/// Well-known topic on which nodes announce the topics they publish on.
+pub const ANNOUNCE_TOPIC: &str = "pubsub-lite/topics";
+
+const SINGLE_LEVEL: &str = "+";
+const MULTI_LEVEL: &str = "#";
+
+/// Whether `topic` is one of the well-known topics every node is in, kept out of the topics
+/// listed to users.
+pub(crate) fn is_internal(topic: &str) -> bool {
+    topic == ANNOUNCE_TOPIC
+        || topic == AUTONAT_TOPIC
+        || topic == CLOCK_TOPIC
+        || topic == CONTINUITY_TOPIC
+        || topic == DIRECTORY_TOPIC
+        || topic == PEER_RECORD_TOPIC
+        || topic == RESEND_TOPIC
+}
+
+/// A topic name pattern, possibly containing `+` and `#` wildcards.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TopicFilter {
+    pattern: String,
+}
+
+impl TopicFilter {
+    /// Parse a filter, checking that wildcards occupy whole levels and that `#` comes last.
+    pub fn new(pattern: &str) -> Result<Self, InvalidFilter> {
+        if pattern.is_empty() {
+            return Err(InvalidFilter::new(pattern, "filter is empty"));
+        }
+        let levels: Vec<&str> = pattern.split('/').collect();
+        for (i, level) in levels.iter().enumerate() {
+            if *level == MULTI_LEVEL && i != levels.len() - 1 {
+                return Err(InvalidFilter::new(pattern, "`#` must be the last level"));
+            }
+            if level.len() > 1 && (level.contains(SINGLE_LEVEL) || level.contains(MULTI_LEVEL)) {
+                return Err(InvalidFilter::new(
+                    pattern,
+                    "wildcards must occupy a whole level",
+                ));
+            }
+        }
+        Ok(TopicFilter {
+            pattern: pattern.to_owned(),
+        })
+    }
+
+    /// The filter as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the filter contains any wildcard.
+    pub fn is_wildcard(&self) -> bool {
+        self.pattern
+            .split('/')
+            .any(|level| level == SINGLE_LEVEL || level == MULTI_LEVEL)
+    }
+
+    /// Whether `topic` is matched by this filter. Topic names containing wildcard characters
+    /// never match.
+    pub fn matches(&self, topic: &str) -> bool {
+        if topic.contains(SINGLE_LEVEL) || topic.contains(MULTI_LEVEL) {
+            return false;
+        }
+        let mut topic_levels = topic.split('/');
+        for level in self.pattern.split('/') {
+            if level == MULTI_LEVEL {
+                return true;
+            }
+            match topic_levels.next() {
+                Some(_) if level == SINGLE_LEVEL => {}
+                Some(topic_level) if topic_level == level => {}
+                _ => return false,
+            }
+        }
+        topic_levels.next().is_none()
+    }
+}
+
+impl FromStr for TopicFilter {
+    type Err = InvalidFilter;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        TopicFilter::new(pattern)
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Error returned when parsing a malformed [`TopicFilter`].
+#[derive(Debug)]
+pub struct InvalidFilter {
+    pattern: String,
+    reason: &'static str,
+}
+
+impl InvalidFilter {
+    fn new(pattern: &str, reason: &'static str) -> Self {
+        InvalidFilter {
+            pattern: pattern.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for InvalidFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid topic filter {:?}: {}",
+            self.pattern, self.reason
+        )
+    }
+}
+
+impl Error for InvalidFilter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, topic: &str) -> bool {
+        TopicFilter::new(filter).unwrap().matches(topic)
+    }
+
+    #[test]
+    fn single_level_wildcard_matches_one_level() {
+        assert!(matches("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(!matches("sensors/+/temp", "sensors/temp"));
+        assert!(!matches("sensors/+/temp", "sensors/kitchen/oven/temp"));
+        assert!(matches("+", "sensors"));
+        assert!(!matches("+", "sensors/kitchen"));
+    }
+
+    #[test]
+    fn trailing_multi_level_wildcard_matches_any_levels() {
+        assert!(matches("logs/#", "logs"));
+        assert!(matches("logs/#", "logs/app"));
+        assert!(matches("logs/#", "logs/app/error"));
+        assert!(!matches("logs/#", "logging"));
+        assert!(matches("#", "anything/at/all"));
+    }
+
+    #[test]
+    fn multi_level_wildcard_must_come_last() {
+        assert!(TopicFilter::new("logs/#/error").is_err());
+        assert!(TopicFilter::new("logs/app#").is_err());
+        assert!(TopicFilter::new("sensors/+kitchen").is_err());
+    }
+
+    #[test]
+    fn empty_levels_are_levels() {
+        assert!(matches("a//b", "a//b"));
+        assert!(!matches("a//b", "a/b"));
+        assert!(matches("a/+/b", "a//b"));
+        assert!(matches("a/+", "a/"));
+        assert!(matches("a/#", "a/"));
+        assert!(!matches("a/b", "a/b/"));
+    }
+
+    #[test]
+    fn topics_with_wildcards_never_match() {
+        assert!(!matches("sensors/+/temp", "sensors/+/temp"));
+        assert!(!matches("#", "logs/#"));
+    }
+}
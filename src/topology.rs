@@ -0,0 +1,107 @@
+//! The components configured on a node, returned by
+//! [`Client::describe_topology`](crate::Client::describe_topology), so that fleet tooling can
+//! check what is deployed against what should be.
+//!
+//! Every component comes with the hash of its configuration: the SHA-256 digest of its `Debug`
+//! rendering, which for a plugin holds the digest of its module and its limits. Two nodes running
+//! the same build with the same configuration report the same hashes.
+
+use crate::{node::Command, Client, Error};
+use ring::digest;
+use std::{
+    fmt::{Debug, Write},
+    sync::Arc,
+};
+
+/// The components of a node.
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    /// Components by kind, in the order they were started.
+    pub components: Vec<Component>,
+}
+
+/// A component of a node.
+#[derive(Clone, Debug)]
+pub struct Component {
+    pub kind: ComponentKind,
+    /// What the component is and where it connects to or listens on.
+    pub name: String,
+    pub status: ComponentStatus,
+    /// Hex SHA-256 digest of the configuration of the component.
+    pub config_hash: String,
+}
+
+/// What a component does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ComponentKind {
+    /// How the node talks to its peers.
+    Transport,
+    /// A server exposing the node to local clients, see [`gateway`](crate::gateway).
+    Gateway,
+    /// A bridge to an external messaging system, see [`bridge`](crate::bridge).
+    Bridge,
+    /// A sink writing messages to an external system, see [`sink`](crate::sink).
+    Sink,
+    /// An installed WebAssembly plugin.
+    Plugin,
+}
+
+/// Whether a component is doing its job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComponentStatus {
+    /// Running, with nothing to connect to.
+    Running,
+    /// Connected to its external system.
+    Connected,
+    /// Trying to reach its external system.
+    Disconnected,
+}
+
+/// A component running on a node, listed by the node for as long as the component holds on to
+/// it.
+#[derive(Clone)]
+pub(crate) struct Registration(Arc<Component>);
+
+impl Registration {
+    /// Register a running component with the node of `client`.
+    pub(crate) fn register(
+        client: &Client,
+        kind: ComponentKind,
+        name: String,
+        config_hash: String,
+    ) -> Result<Self, Error> {
+        let registration = Registration(Arc::new(Component {
+            kind,
+            name,
+            status: ComponentStatus::Running,
+            config_hash,
+        }));
+        client.send(Command::RegisterComponent {
+            registration: registration.clone(),
+        })?;
+        Ok(registration)
+    }
+
+    /// Whether the component is gone, only the node holding on to its registration.
+    pub(crate) fn is_orphan(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+
+    pub(crate) fn component(&self) -> Component {
+        (*self.0).clone()
+    }
+}
+
+/// Hash of a configuration, see the [module documentation](self).
+pub(crate) fn config_hash(config: &impl Debug) -> String {
+    hash(format!("{:?}", config).as_bytes())
+}
+
+/// Hex SHA-256 digest of `data`.
+pub(crate) fn hash(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in digest::digest(&digest::SHA256, data).as_ref() {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
@@ -0,0 +1,341 @@
+//! Tracing of gossip, to analyse how messages propagate across the mesh.
+//!
+//! With [`NodeConfig::trace`](crate::NodeConfig::trace) set, a node records the gossipsub RPCs it
+//! exchanges with its peers as [`TraceEvent`]s: every message received, the first time as a
+//! delivery and then as duplicates, every message sent, be it published by the node or
+//! forwarded, the IHAVE and IWANT gossip naming messages, and the GRAFT and PRUNE building the
+//! mesh of topics. The last [`TraceConfig::capacity`] events are kept, to be read with
+//! [`Client::trace`](crate::Client::trace), those naming a message with
+//! [`Client::trace_message`](crate::Client::trace_message), and exported with [`to_json`] or the
+//! `/trace` endpoint of the admin gateway.
+//!
+//! Events are timestamped in microseconds since the Unix epoch, so that the traces of several
+//! nodes, merged by message id, show how long every hop took, as far as their clocks agree. To
+//! gather them in one place, [`TraceConfig::collector`] streams the events of a node as they
+//! happen to a remote collector, as lines of JSON over TCP, reconnecting whenever the connection
+//! fails; events waiting for the collector are bounded by the capacity too, the oldest being
+//! dropped.
+//!
+//! The JSON of an event holds its `timestamp_us`, the `peer` it came from or went to, its
+//! `direction` (`sent` or `received`) and its `type`: `message` and `duplicate` with the
+//! `message_id`, `source` and `topics` of the message, `ihave` with a `topic` and
+//! `message_ids`, `iwant` with `message_ids`, `graft` and `prune` with a `topic`.
+//!
+//! Only gossipsub is traced: messages exchanged through floodsub, and those of embedded nodes,
+//! are not.
+
+use crate::{
+    flow::{self, Overflow},
+    Error,
+};
+use async_std::{net::TcpStream, task};
+use futures::prelude::*;
+use libp2p::{
+    gossipsub::{
+        protocol::{GossipsubControlAction, MessageId},
+        GossipsubRpc, MessageIdFn, TopicHash,
+    },
+    PeerId,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long to wait before reconnecting to a collector, doubling up to a minute.
+const COLLECTOR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Configuration of the tracing of a node.
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+    /// How many events are kept, and wait for the collector.
+    pub capacity: usize,
+    /// Address of a collector to stream the events to, as `host:port`, if any.
+    pub collector: Option<String>,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            capacity: 10_000,
+            collector: None,
+        }
+    }
+}
+
+/// Whether an RPC was sent to or received from the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// Something the node exchanged with a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// Microseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub peer: PeerId,
+    pub direction: Direction,
+    pub kind: TraceKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceKind {
+    /// A message, received for the first time or sent.
+    Message {
+        id: MessageId,
+        source: PeerId,
+        topics: Vec<TopicHash>,
+    },
+    /// A message received again.
+    Duplicate {
+        id: MessageId,
+        source: PeerId,
+        topics: Vec<TopicHash>,
+    },
+    IHave {
+        topic: TopicHash,
+        ids: Vec<MessageId>,
+    },
+    IWant {
+        ids: Vec<MessageId>,
+    },
+    Graft {
+        topic: TopicHash,
+    },
+    Prune {
+        topic: TopicHash,
+    },
+}
+
+impl TraceEvent {
+    /// Whether the event names the message `id`.
+    pub fn names(&self, id: &MessageId) -> bool {
+        match &self.kind {
+            TraceKind::Message { id: message, .. } | TraceKind::Duplicate { id: message, .. } => {
+                message == id
+            }
+            TraceKind::IHave { ids, .. } | TraceKind::IWant { ids } => ids.contains(id),
+            TraceKind::Graft { .. } | TraceKind::Prune { .. } => false,
+        }
+    }
+}
+
+/// An event as exported.
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp_us: u64,
+    peer: String,
+    direction: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topics: Option<Vec<&'a str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_ids: Option<Vec<&'a str>>,
+}
+
+impl<'a> From<&'a TraceEvent> for Entry<'a> {
+    fn from(event: &'a TraceEvent) -> Self {
+        let mut entry = Entry {
+            timestamp_us: event.timestamp,
+            peer: event.peer.to_base58(),
+            direction: match event.direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            },
+            kind: "",
+            message_id: None,
+            source: None,
+            topics: None,
+            topic: None,
+            message_ids: None,
+        };
+        let ids = |ids: &'a [MessageId]| Some(ids.iter().map(|id| id.0.as_str()).collect());
+        match &event.kind {
+            TraceKind::Message { id, source, topics }
+            | TraceKind::Duplicate { id, source, topics } => {
+                entry.kind = match event.kind {
+                    TraceKind::Message { .. } => "message",
+                    _ => "duplicate",
+                };
+                entry.message_id = Some(&id.0);
+                entry.source = Some(source.to_base58());
+                entry.topics = Some(topics.iter().map(TopicHash::as_str).collect());
+            }
+            TraceKind::IHave { topic, ids: names } => {
+                entry.kind = "ihave";
+                entry.topic = Some(topic.as_str());
+                entry.message_ids = ids(names);
+            }
+            TraceKind::IWant { ids: names } => {
+                entry.kind = "iwant";
+                entry.message_ids = ids(names);
+            }
+            TraceKind::Graft { topic } => {
+                entry.kind = "graft";
+                entry.topic = Some(topic.as_str());
+            }
+            TraceKind::Prune { topic } => {
+                entry.kind = "prune";
+                entry.topic = Some(topic.as_str());
+            }
+        }
+        entry
+    }
+}
+
+/// The events as a JSON array.
+pub fn to_json(events: &[TraceEvent]) -> Result<Vec<u8>, Error> {
+    let entries: Vec<Entry> = events.iter().map(Entry::from).collect();
+    Ok(serde_json::to_vec(&entries)?)
+}
+
+/// Records the events of a node, and streams them to its collector.
+pub(crate) struct Tracer {
+    capacity: usize,
+    events: VecDeque<TraceEvent>,
+    message_id_fn: MessageIdFn,
+    /// Messages received or sent, to tell duplicates, the oldest first in `seen_order`.
+    seen: HashSet<MessageId>,
+    seen_order: VecDeque<MessageId>,
+    collector: Option<flow::Sender<TraceEvent>>,
+}
+
+impl Tracer {
+    /// Start tracing, streaming events to the collector of `config` on a background task.
+    pub fn new(config: TraceConfig, message_id_fn: MessageIdFn) -> Self {
+        let capacity = config.capacity;
+        let collector = config.collector.map(|addr| {
+            let (sender, receiver) = flow::channel(capacity, Overflow::DropOldest);
+            task::spawn(stream_to(addr, receiver));
+            sender
+        });
+        Tracer {
+            capacity,
+            events: VecDeque::new(),
+            message_id_fn,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            collector,
+        }
+    }
+
+    /// Record what `rpc` carried.
+    pub fn record(&mut self, peer: &PeerId, rpc: &GossipsubRpc, direction: Direction) {
+        for message in &rpc.messages {
+            let id = (self.message_id_fn)(message);
+            let first = self.see(id.clone());
+            let (source, topics) = (message.source.clone(), message.topics.clone());
+            let kind = if first || direction == Direction::Sent {
+                TraceKind::Message { id, source, topics }
+            } else {
+                TraceKind::Duplicate { id, source, topics }
+            };
+            self.push(peer, direction, kind);
+        }
+        for control in &rpc.control_msgs {
+            let kind = match control {
+                GossipsubControlAction::IHave {
+                    topic_hash,
+                    message_ids,
+                } => TraceKind::IHave {
+                    topic: topic_hash.clone(),
+                    ids: message_ids.clone(),
+                },
+                GossipsubControlAction::IWant { message_ids } => TraceKind::IWant {
+                    ids: message_ids.clone(),
+                },
+                GossipsubControlAction::Graft { topic_hash } => TraceKind::Graft {
+                    topic: topic_hash.clone(),
+                },
+                GossipsubControlAction::Prune { topic_hash, .. } => TraceKind::Prune {
+                    topic: topic_hash.clone(),
+                },
+            };
+            self.push(peer, direction, kind);
+        }
+    }
+
+    /// The events kept, oldest first, only those naming `id` if given.
+    pub fn events(&self, id: Option<&MessageId>) -> Vec<TraceEvent> {
+        self.events
+            .iter()
+            .filter(|event| id.is_none_or(|id| event.names(id)))
+            .cloned()
+            .collect()
+    }
+
+    /// Remember `id`, returning whether it is new.
+    fn see(&mut self, id: MessageId) -> bool {
+        if !self.seen.insert(id.clone()) {
+            return false;
+        }
+        self.seen_order.push_back(id);
+        if self.seen_order.len() > self.capacity {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    fn push(&mut self, peer: &PeerId, direction: Direction, kind: TraceKind) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+        let event = TraceEvent {
+            timestamp,
+            peer: peer.clone(),
+            direction,
+            kind,
+        };
+        if let Some(collector) = &self.collector {
+            collector.try_send(event.clone());
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+/// Write the events to the collector at `addr`, one JSON line each, until the node stops.
+async fn stream_to(addr: String, mut events: flow::Receiver<TraceEvent>) {
+    let mut backoff = COLLECTOR_BACKOFF;
+    while !events.is_exhausted() {
+        let mut stream = match TcpStream::connect(addr.as_str()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::debug!("trace collector {}: {}", addr, e);
+                task::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(60));
+                continue;
+            }
+        };
+        log::info!("streaming traces to {}", addr);
+        backoff = COLLECTOR_BACKOFF;
+        while let Some(event) = events.next().await {
+            let mut line = match serde_json::to_vec(&Entry::from(&event)) {
+                Ok(line) => line,
+                Err(e) => {
+                    log::warn!("failed to encode a trace event: {}", e);
+                    continue;
+                }
+            };
+            line.push(b'\n');
+            if let Err(e) = stream.write_all(&line).await {
+                log::warn!("trace collector {}: {}", addr, e);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! W3C trace context carried by messages, so that the trace of a request spans its publisher and
+//! its subscribers across the mesh.
+//!
+//! A message published with [`Client::publish_traced`](crate::Client::publish_traced) carries the
+//! [`TraceContext`] of the span publishing it, as a `traceparent` header of the [W3C Trace
+//! Context](https://www.w3.org/TR/trace-context/) recommendation would: the id of the trace, the
+//! id of the span and whether the trace is sampled. Subscribers get it as
+//! [`Message::trace_context`](crate::Message::trace_context), whatever their own configuration,
+//! and continue the trace from it, e.g. by handing [`TraceContext::child`] to the services they
+//! call in turn, or [`to_string`](ToString::to_string) as a `traceparent` HTTP header.
+//!
+//! With the `tracing` feature, [`Message::span`](crate::Message::span) opens a
+//! [`tracing`](https://docs.rs/tracing) span for the processing of a delivered message, holding
+//! the `trace_id` and `parent_span_id` it continues and the `traceparent` it came with, for an
+//! OpenTelemetry layer or any other subscriber of `tracing` to link it to the span of its
+//! publisher.
+//!
+//! The context travels in an envelope: a marker, then the version, the trace id, the span id and
+//! the flags of the `traceparent` header, as 26 bytes. The envelope goes inside batches,
+//! sequence numbers and access tokens, around the timestamp of the message.
+
+use crate::{Error, PubSubError};
+use bytes::Bytes;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{fmt, str::FromStr};
+
+/// Start of a payload carrying a trace context. JSON and UTF-8 text never start with a NUL byte.
+const MARKER: &[u8] = b"\0plw";
+
+/// Version of the `traceparent` format supported.
+const VERSION: u8 = 0;
+
+const HEADER_LEN: usize = MARKER.len() + 1 + 16 + 8 + 1;
+
+/// Flag of sampled traces.
+const SAMPLED: u8 = 0x01;
+
+/// Position of a span in a distributed trace, as a W3C `traceparent` header tells it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// Id of the span the context was taken from, the parent of the spans continuing it.
+    pub span_id: [u8; 8],
+    /// Whether the trace is recorded, by the `sampled` flag.
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The context of the root span of a new trace.
+    pub fn new(sampled: bool) -> Result<Self, Error> {
+        let mut trace_id = [0; 16];
+        fill(&mut trace_id)?;
+        Ok(TraceContext {
+            trace_id,
+            span_id: new_span_id()?,
+            sampled,
+        })
+    }
+
+    /// The context of a new span of the same trace, child of this one.
+    pub fn child(&self) -> Result<Self, Error> {
+        Ok(TraceContext {
+            span_id: new_span_id()?,
+            ..*self
+        })
+    }
+
+    /// The id of the trace in lowercase hex, as in a `traceparent` header.
+    pub fn trace_id_hex(&self) -> String {
+        HEXLOWER_PERMISSIVE.encode(&self.trace_id)
+    }
+
+    /// The id of the span in lowercase hex, as in a `traceparent` header.
+    pub fn span_id_hex(&self) -> String {
+        HEXLOWER_PERMISSIVE.encode(&self.span_id)
+    }
+
+    fn flags(&self) -> u8 {
+        if self.sampled {
+            SAMPLED
+        } else {
+            0
+        }
+    }
+}
+
+fn fill(id: &mut [u8]) -> Result<(), Error> {
+    // All-zero ids are invalid.
+    while id.iter().all(|byte| *byte == 0) {
+        SystemRandom::new()
+            .fill(id)
+            .map_err(|_| "failed to generate a trace id")?;
+    }
+    Ok(())
+}
+
+fn new_span_id() -> Result<[u8; 8], Error> {
+    let mut span_id = [0; 8];
+    fill(&mut span_id)?;
+    Ok(span_id)
+}
+
+/// Formats the context as the value of a `traceparent` header.
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}-{}-{}-{:02x}",
+            VERSION,
+            self.trace_id_hex(),
+            self.span_id_hex(),
+            self.flags()
+        )
+    }
+}
+
+/// Parses the value of a `traceparent` header.
+impl FromStr for TraceContext {
+    type Err = Error;
+
+    fn from_str(traceparent: &str) -> Result<Self, Error> {
+        let invalid = || PubSubError::codec(format!("invalid traceparent {}", traceparent));
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        let (version, trace_id, span_id, flags) = match fields.as_slice() {
+            [version, trace_id, span_id, flags]
+                if version.len() == 2
+                    && trace_id.len() == 32
+                    && span_id.len() == 16
+                    && flags.len() == 2 =>
+            {
+                (*version, *trace_id, *span_id, *flags)
+            }
+            _ => return Err(invalid()),
+        };
+        let hex = |field: &str| {
+            if field.bytes().any(|byte| byte.is_ascii_uppercase()) {
+                return Err(invalid());
+            }
+            HEXLOWER_PERMISSIVE
+                .decode(field.as_bytes())
+                .map_err(|_| invalid())
+        };
+        if hex(version)? != [VERSION] {
+            return Err(PubSubError::codec(format!(
+                "unsupported traceparent version {}",
+                version
+            )));
+        }
+        let mut context = TraceContext {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+            sampled: hex(flags)?[0] & SAMPLED != 0,
+        };
+        context.trace_id.copy_from_slice(&hex(trace_id)?);
+        context.span_id.copy_from_slice(&hex(span_id)?);
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return Err(invalid());
+        }
+        Ok(context)
+    }
+}
+
+/// Put `data` in an envelope carrying `context`.
+pub(crate) fn attach(context: &TraceContext, data: Bytes) -> Bytes {
+    let mut envelope = Vec::with_capacity(HEADER_LEN + data.len());
+    envelope.extend_from_slice(MARKER);
+    envelope.push(VERSION);
+    envelope.extend_from_slice(&context.trace_id);
+    envelope.extend_from_slice(&context.span_id);
+    envelope.push(context.flags());
+    envelope.extend_from_slice(&data);
+    envelope.into()
+}
+
+/// Take the envelope off a payload carrying a trace context, returning the context, or the
+/// payload as is otherwise. A context of an unknown version is dropped along with its envelope.
+pub(crate) fn strip(data: Bytes) -> (Option<TraceContext>, Bytes) {
+    if data.len() < HEADER_LEN || !data.starts_with(MARKER) {
+        return (None, data);
+    }
+    let header = &data[MARKER.len()..HEADER_LEN];
+    let mut context = TraceContext {
+        trace_id: [0; 16],
+        span_id: [0; 8],
+        sampled: header[25] & SAMPLED != 0,
+    };
+    context.trace_id.copy_from_slice(&header[1..17]);
+    context.span_id.copy_from_slice(&header[17..25]);
+    let context = Some(context).filter(|_| header[0] == VERSION);
+    (context, data.slice(HEADER_LEN..))
+}
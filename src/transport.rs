@@ -0,0 +1,409 @@
+use crate::debug::{ConnectionTracker, TrackDials};
+use crate::delegation::IdentityKey;
+use crate::dns::DnsaddrTransport;
+use crate::relay::RelayTransport;
+use async_std::io;
+use data_encoding::BASE64;
+use futures::future;
+#[cfg(feature = "websocket")]
+use libp2p::websocket::WsConfig;
+use libp2p::{
+    core::{
+        either::{EitherOutput, EitherTransport},
+        transport::{upgrade::Version, MemoryTransport, TransportError},
+        upgrade::{InboundUpgradeExt, OptionalUpgrade, OutboundUpgradeExt, SelectUpgrade},
+        ConnectedPoint, StreamMuxer,
+    },
+    dns::DnsConfig,
+    identity::{self, ed25519},
+    multiaddr::Protocol,
+    noise::{self, NoiseConfig, X25519},
+    pnet::{PnetConfig, PreSharedKey},
+    secio::SecioConfig,
+    tcp::TcpConfig,
+    yamux::Config as YamuxConfig,
+    Multiaddr, PeerId, Transport,
+};
+use ring::digest;
+use serde::Deserialize;
+use std::{env, error::Error, fs, path::Path, str::FromStr, time::Duration};
+
+/// Builds the transport that serves as a common ground for all connections. Addresses are
+/// resolved when dialed, be they `/dns4/`, `/dns6/` or `/dnsaddr/` ones. Dialing an address
+/// ending with a peer id fails unless the remote authenticates as that peer. `/memory/` addresses
+/// reach the nodes of the same process, as those of the [`testing`](crate::testing) harness.
+/// With the `websocket` feature, `/ws` addresses are listened on and dialed too.
+///
+/// Connections are authenticated and encrypted with noise, as current libp2p releases require,
+/// `key` signing its handshake. With `legacy_secio`, secio is offered after noise, to reach the
+/// peers that speak nothing else, such as nodes of earlier releases of this crate, except in
+/// builds with the `minimal` feature. Secio needs the key pair in memory, and fails to build with
+/// a key held by a [`Signer`](crate::delegation::Signer).
+///
+/// The dials in flight and the substreams of every connection are counted for `tracker`, see
+/// the [`debug`](crate::debug) module.
+pub fn build_transport(
+    key: IdentityKey,
+    psk: Option<PreSharedKey>,
+    legacy_secio: bool,
+    tracker: ConnectionTracker,
+) -> io::Result<
+    impl Transport<
+            Output = (
+                PeerId,
+                impl StreamMuxer<
+                        OutboundSubstream = impl Send,
+                        Substream = impl Send,
+                        Error = impl Into<io::Error>,
+                    > + Send
+                    + Sync,
+            ),
+            Error = impl Error + Send,
+            Listener = impl Send,
+            Dial = impl Send,
+            ListenerUpgrade = impl Send,
+        > + Clone,
+> {
+    let local_peer_id = PeerId::from(key.public());
+    let noise_keys = noise::Keypair::<X25519>::new()
+        .into_authentic_with(key.public(), |dh_public| key.sign(dh_public))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let secio_config = match (legacy_secio && !cfg!(feature = "minimal"), key.keypair()) {
+        (true, Some(key_pair)) => OptionalUpgrade::some(SecioConfig::new(key_pair.clone())),
+        (true, None) => {
+            return Err(io::Error::other(
+                "secio needs the identity key in memory, not in a signer",
+            ))
+        }
+        (false, _) => OptionalUpgrade::none(),
+    };
+    let security = SelectUpgrade::new(
+        NoiseConfig::xx(noise_keys).into_authenticated(),
+        secio_config,
+    )
+    .map_inbound({
+        let tracker = tracker.clone();
+        move |output| either_peer(output, &tracker)
+    })
+    .map_outbound({
+        let tracker = tracker.clone();
+        move |output| either_peer(output, &tracker)
+    });
+    let yamux_config = YamuxConfig::default();
+
+    let base_transport = DnsConfig::new(
+        TcpConfig::new()
+            .nodelay(true)
+            .or_transport(RelayTransport::new(local_peer_id))
+            .or_transport(MemoryTransport),
+    )?;
+    // Tried first, as the DNS transport takes every DNS address for its own.
+    #[cfg(feature = "websocket")]
+    let base_transport =
+        WsConfig::new(DnsConfig::new(TcpConfig::new().nodelay(true))?).or_transport(base_transport);
+    let base_transport = DnsaddrTransport::new(WithoutPeerId(base_transport));
+    let maybe_encrypted = match psk {
+        Some(psk) => EitherTransport::Left(
+            base_transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+        ),
+        None => EitherTransport::Right(base_transport),
+    };
+    let muxers = tracker.clone();
+    let transport = maybe_encrypted
+        .upgrade(Version::V1)
+        .authenticate(security)
+        .multiplex(yamux_config)
+        .and_then(move |(peer_id, muxer), endpoint| {
+            future::ready(match expected_peer_id(&endpoint) {
+                Some(expected) if expected != peer_id => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("dialed {} but reached {}", expected, peer_id),
+                )),
+                _ => Ok((peer_id.clone(), muxers.track(&peer_id, muxer))),
+            })
+        })
+        .timeout(Duration::from_secs(20));
+    Ok(TrackDials::new(transport, tracker))
+}
+
+/// The peer authenticated by either security protocol, and the stream it secured, telling
+/// `tracker` which protocol it was.
+fn either_peer<A, B>(
+    output: EitherOutput<(PeerId, A), (PeerId, B)>,
+    tracker: &ConnectionTracker,
+) -> (PeerId, EitherOutput<A, B>) {
+    let (peer_id, stream, security) = match output {
+        EitherOutput::First((peer_id, stream)) => (peer_id, EitherOutput::First(stream), "noise"),
+        EitherOutput::Second((peer_id, stream)) => (peer_id, EitherOutput::Second(stream), "secio"),
+    };
+    tracker.secured(&peer_id, security);
+    (peer_id, stream)
+}
+
+/// The peer id an outgoing connection was dialed with, if any.
+fn expected_peer_id(endpoint: &ConnectedPoint) -> Option<PeerId> {
+    match endpoint {
+        ConnectedPoint::Dialer { address } => match address.iter().last()? {
+            Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+            _ => None,
+        },
+        ConnectedPoint::Listener { .. } => None,
+    }
+}
+
+/// Transport dialing addresses with their peer id stripped, for the transport it wraps to
+/// support them. The peer id is checked once the connection is authenticated.
+#[derive(Clone)]
+struct WithoutPeerId<T>(T);
+
+impl<T: Transport> Transport for WithoutPeerId<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type Listener = T::Listener;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<T::Error>> {
+        self.0.listen_on(addr)
+    }
+
+    fn dial(self, mut addr: Multiaddr) -> Result<Self::Dial, TransportError<T::Error>> {
+        strip_peer_id(&mut addr);
+        self.0.dial(addr)
+    }
+}
+
+/// Get the current ipfs repo path, either from the IPFS_PATH environment variable or
+/// from the default $HOME/.ipfs
+pub fn get_ipfs_path() -> Box<Path> {
+    env::var("IPFS_PATH")
+        .map(|ipfs_path| Path::new(&ipfs_path).into())
+        .unwrap_or_else(|_| {
+            env::var("HOME")
+                .map(|home| Path::new(&home).join(".ipfs"))
+                .expect("could not determine home directory")
+                .into()
+        })
+}
+
+/// Derive an Ed25519 keypair from `seed`, the same on every run, so that test fixtures and
+/// simulations get stable peer ids. Anyone knowing the seed holds the key: production nodes
+/// should generate theirs.
+pub fn keypair_from_seed(seed: impl AsRef<[u8]>) -> identity::Keypair {
+    derive_keypair(b"pubsub-lite/seed\0", seed.as_ref())
+}
+
+/// The identity of a node started on a repo whose `Identity.PrivKey` is `identity`: derived from
+/// it, the same on every run, but another peer id than the one of a go-ipfs daemon of the repo,
+/// so that peers do not see two hosts with one identity.
+pub fn sidecar_keypair(identity: &identity::Keypair) -> Result<identity::Keypair, crate::Error> {
+    match identity {
+        identity::Keypair::Ed25519(keypair) => Ok(derive_keypair(
+            b"pubsub-lite/sidecar\0",
+            keypair.secret().as_ref(),
+        )),
+        _ => Err("only Ed25519 identities can be derived from".into()),
+    }
+}
+
+/// Whether the go-ipfs daemon of the repo at `path` runs, as told by the `api` file it writes on
+/// start and removes on exit. A daemon that crashed leaves it behind.
+pub fn ipfs_daemon_running(path: &Path) -> bool {
+    path.join("api").exists()
+}
+
+fn derive_keypair(domain: &[u8], seed: &[u8]) -> identity::Keypair {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(domain);
+    context.update(seed);
+    let mut secret = context.finish().as_ref().to_vec();
+    let secret = ed25519::SecretKey::from_bytes(&mut secret)
+        .expect("any 32 bytes are an Ed25519 secret key");
+    identity::Keypair::Ed25519(secret.into())
+}
+
+/// Protobuf header of an Ed25519 private key as go-ipfs keeps it: key type 1, then 64 bytes of
+/// secret and public key.
+const IPFS_ED25519_HEADER: [u8; 4] = [0x08, 0x01, 0x12, 0x40];
+
+/// The `Identity.PrivKey` of a go-ipfs config holding `keypair`: the base64 protobuf encoding of
+/// the key. Only Ed25519 keys are supported.
+pub fn encode_ipfs_private_key(keypair: &identity::Keypair) -> Result<String, crate::Error> {
+    match keypair {
+        identity::Keypair::Ed25519(keypair) => {
+            let mut encoded = IPFS_ED25519_HEADER.to_vec();
+            encoded.extend_from_slice(&keypair.encode());
+            Ok(BASE64.encode(&encoded))
+        }
+        _ => Err("only Ed25519 identities can be saved".into()),
+    }
+}
+
+/// The keypair of the `Identity.PrivKey` of a go-ipfs config. Only Ed25519 keys are supported.
+pub fn decode_ipfs_private_key(text: &str) -> Result<identity::Keypair, crate::Error> {
+    let mut encoded = BASE64
+        .decode(text.as_bytes())
+        .map_err(crate::PubSubError::config)?;
+    if encoded.len() != IPFS_ED25519_HEADER.len() + 64 || !encoded.starts_with(&IPFS_ED25519_HEADER)
+    {
+        return Err("identity is not an Ed25519 private key".into());
+    }
+    let keypair = ed25519::Keypair::decode(&mut encoded[IPFS_ED25519_HEADER.len()..])
+        .map_err(crate::PubSubError::config)?;
+    Ok(identity::Keypair::Ed25519(keypair))
+}
+
+/// Read the pre shared key file from the given ipfs directory
+pub fn get_psk(path: &Path) -> std::io::Result<Option<String>> {
+    let swarm_key_file = path.join("swarm.key");
+    match fs::read_to_string(swarm_key_file) {
+        Ok(text) => Ok(Some(text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The addresses in the config file of a go-ipfs repo, to run a node alongside a private IPFS
+/// network with the same peers, and the identity of the repo.
+#[derive(Clone, Default)]
+pub struct IpfsConfig {
+    /// The `Identity.PrivKey` keypair, if it is an Ed25519 one.
+    pub identity: Option<identity::Keypair>,
+    /// The `Bootstrap` peers, to dial.
+    pub bootstrap: Vec<Multiaddr>,
+    /// The `Addresses.Swarm` addresses, which the go-ipfs daemon of the repo listens on: a node
+    /// running alongside it must listen elsewhere.
+    pub swarm: Vec<Multiaddr>,
+}
+
+impl std::fmt::Debug for IpfsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("IpfsConfig")
+            .field(
+                "identity",
+                &self
+                    .identity
+                    .as_ref()
+                    .map(|keypair| PeerId::from(keypair.public())),
+            )
+            .field("bootstrap", &self.bootstrap)
+            .field("swarm", &self.swarm)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawIpfsConfig {
+    #[serde(default)]
+    identity: RawIdentity,
+    #[serde(default)]
+    bootstrap: Option<Vec<String>>,
+    #[serde(default)]
+    addresses: RawAddresses,
+}
+
+#[derive(Default, Deserialize)]
+struct RawIdentity {
+    #[serde(rename = "PrivKey", default)]
+    priv_key: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawAddresses {
+    #[serde(default)]
+    swarm: Option<Vec<String>>,
+}
+
+/// Read the addresses and identity in the config file of the given ipfs directory. Addresses the
+/// transport cannot use, such as QUIC ones, are skipped, and so are identities other than
+/// Ed25519 ones, such as the RSA keys of older repos.
+pub fn get_ipfs_config(path: &Path) -> Result<Option<IpfsConfig>, crate::Error> {
+    let text = match fs::read_to_string(path.join("config")) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let raw: RawIpfsConfig = serde_json::from_str(&text).map_err(crate::PubSubError::config)?;
+    let usable = |addrs: Option<Vec<String>>| {
+        addrs
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|text| match parse_legacy_multiaddr(text) {
+                Ok(addr) if supported(&addr) => Some(addr),
+                _ => {
+                    log::debug!("skipping unsupported address {} of the ipfs config", text);
+                    None
+                }
+            })
+            .collect()
+    };
+    let identity = raw
+        .identity
+        .priv_key
+        .and_then(|text| match decode_ipfs_private_key(&text) {
+            Ok(keypair) => Some(keypair),
+            Err(e) => {
+                log::warn!("skipping the identity of the ipfs config: {}", e);
+                None
+            }
+        });
+    Ok(Some(IpfsConfig {
+        identity,
+        bootstrap: usable(raw.bootstrap),
+        swarm: usable(raw.addresses.swarm),
+    }))
+}
+
+/// Whether the transport built by [`build_transport`] can listen on or dial `addr`.
+pub(crate) fn supported(addr: &Multiaddr) -> bool {
+    addr.iter().all(|protocol| {
+        matches!(
+            protocol,
+            Protocol::Ip4(_)
+                | Protocol::Ip6(_)
+                | Protocol::Dns4(_)
+                | Protocol::Dns6(_)
+                | Protocol::Dnsaddr(_)
+                | Protocol::Tcp(_)
+                | Protocol::Memory(_)
+                | Protocol::P2pCircuit
+                | Protocol::P2p(_)
+        ) || (cfg!(feature = "websocket") && matches!(protocol, Protocol::Ws(_)))
+    })
+}
+
+/// for a multiaddr that ends with a peer id, this strips this suffix. Rust-libp2p
+/// only supports dialing to an address without providing the peer id. The peer id of a
+/// circuit address, naming the peer to relay to, is kept, and so is that of a `/dnsaddr/`
+/// address, picking which of the addresses it resolves to are dialed.
+pub fn strip_peer_id(addr: &mut Multiaddr) {
+    let last = addr.pop();
+    let keep = addr.iter().last() == Some(Protocol::P2pCircuit)
+        || matches!(addr.iter().next(), Some(Protocol::Dnsaddr(_)));
+    match last {
+        Some(Protocol::P2p(peer_id)) if !keep => {
+            let mut addr = Multiaddr::empty();
+            addr.push(Protocol::P2p(peer_id));
+            log::debug!(
+                "removing peer id {} so this address can be dialed by rust-libp2p",
+                addr
+            );
+        }
+        Some(other) => addr.push(other),
+        _ => {}
+    }
+}
+
+/// parse a legacy multiaddr (replace ipfs with p2p). The peer id is kept, so that dialing the
+/// address checks the identity of the remote. `/dns4/`, `/dns6/` and `/dnsaddr/` addresses, as
+/// found in the bootstrap lists of go-ipfs configs, are kept as such, to be resolved when dialed.
+pub fn parse_legacy_multiaddr(text: &str) -> Result<Multiaddr, crate::Error> {
+    let sanitized = text
+        .split('/')
+        .map(|part| if part == "ipfs" { "p2p" } else { part })
+        .collect::<Vec<_>>()
+        .join("/");
+    Multiaddr::from_str(&sanitized).map_err(crate::PubSubError::config)
+}
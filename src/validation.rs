@@ -0,0 +1,293 @@
+//! Validation of received messages off the swarm task.
+//!
+//! Before a node hands a received message to its subscribers, it decrypts it, verifies the
+//! signature of its publisher and runs the receive [plugins](crate::plugin) installed for its
+//! topics, which reject what they do not validate. By default this happens on the task driving
+//! the swarm, so that a burst of messages holds up the gossipsub heartbeats, and the rest of the
+//! traffic, until every one of them has been handled.
+//!
+//! With a [`ValidationConfig`] in [`NodeConfig::validation`](crate::NodeConfig::validation), the
+//! node hands this work to a pool of worker threads instead, reassembling chunked messages first,
+//! and goes on with the swarm meanwhile. Gossipsub then waits for the result to forward a message
+//! to the mesh: an accepted message is forwarded and delivered, a rejected one is neither, and is
+//! no longer gossiped either. At most [`queue`](ValidationConfig::queue) messages wait for a
+//! worker; those received past that are dropped unvalidated, neither forwarded nor delivered,
+//! rather than queued without bound. Chunks, offloaded payloads and messages on the internal
+//! topics of the node are forwarded as they come.
+//!
+//! [`Stats::validation`](crate::stats::Stats::validation) counts the messages of every outcome.
+
+#[cfg(feature = "wasm")]
+use crate::plugin::{Hook, Plugin};
+use crate::{
+    acl,
+    attestation::{self, SignatureStatus},
+    clock,
+    codec::{self, Decoded},
+    crypto::TopicKey,
+    delegation::{Origin, PublicKey},
+    headers::{self, Headers},
+    replay,
+    sequence::{self, Stamp},
+    topic::TopicFilter,
+    trace_context::{self, TraceContext},
+    Error,
+};
+use bytes::Bytes;
+use futures::{channel::mpsc, prelude::*, stream};
+use libp2p::{
+    gossipsub::{protocol::MessageId, GossipsubMessage, MessageAcceptance, TopicHash},
+    PeerId,
+};
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// Worker pool validating received messages, see the [module documentation](self).
+#[derive(Clone, Copy, Debug)]
+pub struct ValidationConfig {
+    /// Worker threads validating messages.
+    pub workers: usize,
+    /// Messages waiting for a worker, past which received messages are dropped unvalidated.
+    pub queue: usize,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            workers: thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            queue: 1024,
+        }
+    }
+}
+
+/// A plugin installed on a node under a name, for the topics matching a filter.
+#[cfg(feature = "wasm")]
+pub(crate) type Installed = (String, TopicFilter, Hook, Arc<Plugin>);
+
+/// What validating a message takes from the node, shared with the workers.
+#[derive(Clone)]
+pub(crate) struct Policies {
+    /// Encryption keys of topics, by topic filter.
+    pub encryption: Vec<(TopicFilter, TopicKey)>,
+    /// Organisations received messages must be published under, by topic filter.
+    pub trusted_orgs: Vec<(TopicFilter, PublicKey)>,
+    /// Owners whose tokens the sources of received messages must hold, by topic filter.
+    pub topic_owners: Vec<(TopicFilter, PublicKey)>,
+    /// Installed plugins, by name, run in installation order.
+    #[cfg(feature = "wasm")]
+    pub plugins: Vec<Installed>,
+}
+
+impl Policies {
+    /// Run the plugins installed for `topic` with a hook `runs` accepts on `data`, returning
+    /// `None` if one of them drops the message.
+    #[cfg(feature = "wasm")]
+    pub fn run_plugins(
+        &self,
+        runs: fn(Hook) -> bool,
+        topic: &str,
+        mut data: Bytes,
+    ) -> Option<Bytes> {
+        for (name, filter, hook, plugin) in &self.plugins {
+            if !runs(*hook) || !filter.matches(topic) {
+                continue;
+            }
+            data = match plugin.apply(topic, &data) {
+                Ok(data) => data?.into(),
+                Err(e) => {
+                    log::debug!("plugin {} dropped a message on {}: {}", name, topic, e);
+                    return None;
+                }
+            };
+        }
+        Some(data)
+    }
+
+    /// The topics among `topics` whose receive plugins let `data` through, with what they made
+    /// of it.
+    #[cfg_attr(not(feature = "wasm"), allow(clippy::unnecessary_filter_map))]
+    pub fn deliveries(&self, topics: &[TopicHash], data: &Bytes) -> Vec<(TopicHash, Bytes)> {
+        topics
+            .iter()
+            .filter_map(|topic| {
+                #[cfg(feature = "wasm")]
+                let data = self.run_plugins(Hook::receive, topic.as_str(), data.clone())?;
+                #[cfg(not(feature = "wasm"))]
+                let data = data.clone();
+                Some((topic.clone(), data))
+            })
+            .collect()
+    }
+}
+
+/// A whole message to validate.
+pub(crate) struct Job {
+    pub id: MessageId,
+    pub message: GossipsubMessage,
+    /// The peer gossipsub received the message from, to forward it once validated, if it is
+    /// waiting for the result.
+    pub propagation_source: Option<PeerId>,
+    pub policies: Arc<Policies>,
+}
+
+impl Job {
+    /// Take the envelopes off the message, check the access tokens of its payloads and run the
+    /// receive plugins of its topics on every one of them.
+    pub fn run(mut self) -> Validated {
+        let data = std::mem::take(&mut self.message.data);
+        let outcome = codec::open(
+            &self.policies.encryption,
+            &self.policies.trusted_orgs,
+            &self.message.topics,
+            data,
+        )
+        .and_then(|Decoded { origin, payloads }| {
+            let topic = self
+                .message
+                .topics
+                .first()
+                .map_or("", |topic| topic.as_str());
+            let payloads = payloads
+                .into_iter()
+                .map(|data| {
+                    let (stamp, data) = sequence::strip(data);
+                    let (signature, data) = attestation::verify(&self.message.source, topic, data)?;
+                    let (nonce, data) = replay::strip(data);
+                    let data = acl::verify(
+                        &self.policies.topic_owners,
+                        &self.message.source,
+                        topic,
+                        data,
+                    )?;
+                    let (headers, data) = headers::strip(data)?;
+                    let (trace_context, data) = trace_context::strip(data);
+                    let (published_at, data) = clock::strip(data);
+                    let deliveries = self.policies.deliveries(&self.message.topics, &data);
+                    Ok(Payload {
+                        stamp,
+                        signature,
+                        nonce,
+                        published_at,
+                        trace_context,
+                        headers,
+                        data,
+                        deliveries,
+                    })
+                })
+                .collect::<Result<_, Error>>()?;
+            Ok((origin, payloads))
+        });
+        Validated {
+            id: self.id,
+            message: self.message,
+            propagation_source: self.propagation_source,
+            outcome,
+        }
+    }
+}
+
+/// A validated message, its payload taken out.
+pub(crate) struct Validated {
+    pub id: MessageId,
+    pub message: GossipsubMessage,
+    pub propagation_source: Option<PeerId>,
+    /// The origin and payloads of the message, or why it is invalid.
+    pub outcome: Result<(Option<Origin>, Vec<Payload>), Error>,
+}
+
+impl Validated {
+    /// Whether to forward the message: not if its envelopes are invalid, nor if the plugins
+    /// of its topics dropped all of its payloads.
+    pub fn acceptance(&self) -> MessageAcceptance {
+        match &self.outcome {
+            Ok((_, payloads))
+                if payloads.is_empty()
+                    || payloads
+                        .iter()
+                        .any(|payload| !payload.deliveries.is_empty()) =>
+            {
+                MessageAcceptance::Accept
+            }
+            _ => MessageAcceptance::Reject,
+        }
+    }
+}
+
+/// One payload of a validated message.
+pub(crate) struct Payload {
+    /// Number of the payload, if its publisher numbers them.
+    pub stamp: Option<Stamp>,
+    /// Whether the payload is signed by the source of the message.
+    pub signature: SignatureStatus,
+    /// Nonce of the payload, if its publisher protects it from replays.
+    pub nonce: Option<u64>,
+    /// When the payload was published at, in microseconds since the Unix epoch by the clock of
+    /// its publisher, if it is timestamped.
+    pub published_at: Option<u64>,
+    /// Trace context of the span that published the payload, if any.
+    pub trace_context: Option<TraceContext>,
+    /// Headers the payload was published with.
+    pub headers: Headers,
+    pub data: Bytes,
+    /// The topics of the message whose plugins let the payload through, with what they made of
+    /// it.
+    pub deliveries: Vec<(TopicHash, Bytes)>,
+}
+
+/// Messages validated by the workers of a node.
+pub(crate) type Validations = Box<dyn Stream<Item = Validated> + Send + Unpin>;
+
+/// The workers validating the messages of a node.
+pub(crate) struct Pool {
+    jobs: SyncSender<Job>,
+}
+
+impl Pool {
+    /// Start the workers, which stop once the pool is dropped, returning the pool and the
+    /// messages they validate.
+    pub fn start(config: &ValidationConfig) -> Result<(Self, Validations), Error> {
+        if config.workers == 0 {
+            return Err("validation needs at least one worker".into());
+        }
+        let (jobs, queue) = sync_channel(config.queue);
+        let queue = Arc::new(Mutex::new(queue));
+        let (sender, validations) = mpsc::unbounded();
+        for i in 0..config.workers {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::Builder::new()
+                .name(format!("validation-{}", i))
+                .spawn(move || work(&queue, &sender))?;
+        }
+        Ok((
+            Pool { jobs },
+            Box::new(validations.chain(stream::pending())),
+        ))
+    }
+
+    /// Queue `job` for a worker, handing it back if too many are waiting.
+    pub fn submit(&self, job: Job) -> Result<(), Box<Job>> {
+        self.jobs.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => Box::new(job),
+        })
+    }
+}
+
+/// Run the jobs of `queue` until the pool or the node is gone.
+fn work(queue: &Mutex<Receiver<Job>>, validations: &mpsc::UnboundedSender<Validated>) {
+    loop {
+        let job = match queue.lock().map(|queue| queue.recv()) {
+            Ok(Ok(job)) => job,
+            _ => return,
+        };
+        if validations.unbounded_send(job.run()).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Queue groups across the nodes of a [`TestNetwork`]: every message delivered to one member of
+//! each group.
+
+use async_std::{future::timeout, task};
+use futures::{future, prelude::*};
+use rust_crdt::{
+    group::GroupSubscription,
+    testing::{TestNetwork, TIMEOUT},
+};
+use std::collections::HashSet;
+
+const MESSAGES: usize = 20;
+
+/// The payloads `members` received until they got `count` of them between them.
+fn receive(members: &mut [GroupSubscription], count: usize) -> Vec<Vec<String>> {
+    let mut received = vec![Vec::new(); members.len()];
+    task::block_on(async {
+        for _ in 0..count {
+            let next = members.iter_mut().map(|member| member.next());
+            let (message, index, _) = timeout(TIMEOUT, future::select_all(next))
+                .await
+                .expect("messages not delivered in time");
+            let message = message.expect("group left");
+            received[index].push(String::from_utf8(message.data.to_vec()).unwrap());
+        }
+    });
+    received
+}
+
+#[test]
+fn members_on_one_node_share_the_messages() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let mut members = vec![
+        network.node(1).subscribe_group("readings", "g").unwrap(),
+        network.node(1).subscribe_group("readings", "g").unwrap(),
+    ];
+    let _meshes = task::block_on(network.join("readings")).unwrap();
+    for i in 0..MESSAGES {
+        network
+            .node(0)
+            .publish("readings", format!("reading {}", i))
+            .unwrap();
+    }
+
+    let received = receive(&mut members, MESSAGES);
+    let all: HashSet<_> = received.iter().flatten().collect();
+    assert_eq!(all.len(), MESSAGES, "{:?}", received);
+    assert!(
+        received.iter().all(|share| !share.is_empty()),
+        "{:?}",
+        received
+    );
+}
+
+#[test]
+fn every_group_gets_every_message() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let mut members = vec![
+        network.node(1).subscribe_group("events", "audit").unwrap(),
+        network
+            .node(1)
+            .subscribe_group("events", "billing")
+            .unwrap(),
+    ];
+    let _meshes = task::block_on(network.join("events")).unwrap();
+    for i in 0..MESSAGES {
+        network
+            .node(0)
+            .publish("events", format!("event {}", i))
+            .unwrap();
+    }
+
+    let received = receive(&mut members, 2 * MESSAGES);
+    assert_eq!(received[0].len(), MESSAGES);
+    assert_eq!(received[0], received[1]);
+}
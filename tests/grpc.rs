@@ -0,0 +1,102 @@
+//! The gRPC gateway as generated clients see it: a `PublishStream` acknowledging every message
+//! with its id, and the server reflection listing the PubSubAPI.
+
+use futures::{prelude::*, stream};
+use rust_crdt::{
+    gateway::grpc,
+    pb::{
+        pub_sub_api_client::PubSubApiClient,
+        reflection::{
+            server_reflection_client::ServerReflectionClient,
+            server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+            ServerReflectionRequest,
+        },
+        PublishRequest,
+    },
+    testing::{next_message, TestNetwork},
+};
+
+#[test]
+fn publish_stream_acks_every_message_with_its_id() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let mut subscriptions = async_std::task::block_on(network.join("readings")).unwrap();
+    let endpoint = serve(&network);
+
+    let requests = (1..=3).map(|correlation_id| PublishRequest {
+        topic: "readings".into(),
+        data: format!("reading {}", correlation_id).into_bytes(),
+        correlation_id,
+    });
+    let acks: Vec<_> = runtime().block_on(async {
+        let mut client = PubSubApiClient::connect(endpoint).await.unwrap();
+        let acks = client
+            .publish_stream(stream::iter(requests.collect::<Vec<_>>()))
+            .await
+            .unwrap();
+        acks.into_inner().try_collect().await.unwrap()
+    });
+
+    let correlation_ids: Vec<_> = acks.iter().map(|ack| ack.correlation_id).collect();
+    assert_eq!(correlation_ids, [1, 2, 3]);
+    let received = async_std::task::block_on(async {
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(next_message(&mut subscriptions[1]).await.unwrap());
+        }
+        received
+    });
+    for (ack, message) in acks.iter().zip(&received) {
+        assert_eq!(ack.error, "");
+        assert_eq!(ack.message_id, message.id.0);
+    }
+}
+
+#[test]
+fn reflection_lists_the_pubsub_api() {
+    let network = TestNetwork::new(1).unwrap();
+    let endpoint = serve(&network);
+
+    let responses: Vec<_> = runtime().block_on(async {
+        let mut client = ServerReflectionClient::connect(endpoint).await.unwrap();
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(MessageRequest::ListServices(String::new())),
+        };
+        let responses = client
+            .server_reflection_info(stream::iter(vec![request]))
+            .await
+            .unwrap();
+        responses.into_inner().try_collect().await.unwrap()
+    });
+
+    let services = match &responses[0].message_response {
+        Some(MessageResponse::ListServicesResponse(list)) => &list.service,
+        other => panic!("not a list of services: {:?}", other),
+    };
+    let names: Vec<_> = services
+        .iter()
+        .map(|service| service.name.as_str())
+        .collect();
+    assert!(names.contains(&"pb.PubSubAPI"), "{:?}", names);
+}
+
+/// Serve the gRPC gateway of the first node of `network`, returning its endpoint.
+fn serve(network: &TestNetwork) -> String {
+    // The port a listener was just given is free, short of a race with another process.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    grpc::spawn(network.node(0), ("127.0.0.1", port)).unwrap();
+    format!("http://127.0.0.1:{}", port)
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap()
+}
@@ -0,0 +1,40 @@
+//! Delivery across the nodes of a [`TestNetwork`], forwarded by the nodes in between and carried
+//! on once a node restarts.
+
+use async_std::task;
+use rust_crdt::testing::{next_message, test_config, TestNetwork};
+
+#[test]
+fn messages_reach_every_node_of_a_line() {
+    let mut network = TestNetwork::new(3).unwrap();
+    network.connect(0, 1).unwrap();
+    network.connect(1, 2).unwrap();
+    task::block_on(async {
+        let mut subscriptions = network.join("line").await.unwrap();
+        let received = network
+            .publish(0, "line", "hello", &mut subscriptions)
+            .await
+            .unwrap();
+        let source = network.node(0).local_peer_id();
+        for message in received {
+            assert_eq!(&message.data[..], b"hello");
+            assert_eq!(&message.source, source);
+        }
+    });
+}
+
+#[test]
+fn a_restarted_node_receives_again() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let subscriptions = task::block_on(network.join("churn")).unwrap();
+    drop(subscriptions);
+    network.stop(1);
+    network.restart(1, test_config(1)).unwrap();
+    task::block_on(async {
+        let mut subscriptions = network.join("churn").await.unwrap();
+        network.node(0).publish("churn", "again").unwrap();
+        let message = next_message(&mut subscriptions[1]).await.unwrap();
+        assert_eq!(&message.data[..], b"again");
+    });
+}
@@ -0,0 +1,88 @@
+//! Work queues across the nodes of a [`TestNetwork`]: jobs shared by the workers of a group,
+//! and redelivered unless acknowledged in time.
+
+use async_std::{future::timeout, task};
+use futures::{channel::mpsc, future, prelude::*};
+use rust_crdt::{
+    queue::Consumer,
+    testing::{TestNetwork, TIMEOUT},
+};
+use std::{collections::HashSet, time::Duration};
+
+/// Topic the workers of `queue` subscribe to.
+fn queue_topic(queue: &str) -> String {
+    format!("pubsub-lite/queue/{}", queue)
+}
+
+#[test]
+fn sibling_workers_share_the_jobs_of_their_group() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let visibility = Duration::from_secs(30);
+    let workers = vec![
+        network
+            .node(1)
+            .consume("jobs", "workers", visibility)
+            .unwrap(),
+        network
+            .node(1)
+            .consume("jobs", "workers", visibility)
+            .unwrap(),
+    ];
+    let _meshes = task::block_on(network.join(&queue_topic("jobs"))).unwrap();
+    let enqueued: HashSet<_> = (0..4)
+        .map(|i| {
+            network
+                .node(0)
+                .enqueue("jobs", format!("job {}", i))
+                .unwrap()
+        })
+        .collect();
+
+    let (found, handed_out) = mpsc::unbounded();
+    let work = |mut worker: Consumer| {
+        let found = found.clone();
+        async move {
+            loop {
+                let job = worker.next().await.unwrap();
+                worker.ack(&job).unwrap();
+                found.unbounded_send(job.id().to_owned()).unwrap();
+            }
+        }
+    };
+    let work = future::join_all(workers.into_iter().map(work).map(Box::pin));
+    let collect = handed_out.take(4).collect::<Vec<_>>();
+    let handed_out = match task::block_on(timeout(TIMEOUT, future::select(work, collect))) {
+        Ok(future::Either::Right((handed_out, _))) => handed_out,
+        _ => panic!("jobs not handed out in time"),
+    };
+    let distinct: HashSet<_> = handed_out.iter().cloned().collect();
+    assert_eq!(distinct.len(), handed_out.len(), "{:?}", handed_out);
+    assert_eq!(distinct, enqueued);
+}
+
+#[test]
+fn unacknowledged_jobs_are_redelivered() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let mut worker = network
+        .node(1)
+        .consume("retries", "workers", Duration::from_secs(1))
+        .unwrap();
+    let _meshes = task::block_on(network.join(&queue_topic("retries"))).unwrap();
+    let job = network.node(0).enqueue("retries", "flaky").unwrap();
+
+    task::block_on(async {
+        let first = timeout(TIMEOUT, worker.next()).await.unwrap().unwrap();
+        assert_eq!(
+            (first.id(), first.data(), first.attempt()),
+            (&*job, &b"flaky"[..], 1)
+        );
+        let second = timeout(TIMEOUT, worker.next()).await.unwrap().unwrap();
+        assert_eq!((second.id(), second.attempt()), (&*job, 2));
+        worker.ack(&second).unwrap();
+        let next = network.node(0).enqueue("retries", "steady").unwrap();
+        let third = timeout(TIMEOUT, worker.next()).await.unwrap().unwrap();
+        assert_eq!(third.id(), next);
+    });
+}
@@ -0,0 +1,78 @@
+//! Sharded topics across the nodes of a [`TestNetwork`]: shards split between the consumers of a
+//! group, each getting the messages of its own shards, and handed over as consumers leave.
+
+use async_std::{future::timeout, task};
+use futures::{future, prelude::*};
+use rust_crdt::{
+    shard::{ShardSubscription, Shards},
+    testing::{TestNetwork, TIMEOUT},
+};
+use std::time::{Duration, Instant};
+
+/// Wait until the shards owned by `consumers` are every shard of `shards`, each owned once.
+fn settle(shards: &Shards, consumers: &[ShardSubscription]) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let mut owned: Vec<u32> = consumers.iter().flat_map(|c| c.owned()).collect();
+        owned.sort_unstable();
+        if owned == (0..shards.count()).collect::<Vec<_>>() {
+            return;
+        }
+        assert!(Instant::now() < deadline, "shards not settled: {:?}", owned);
+        task::block_on(task::sleep(Duration::from_millis(20)));
+    }
+}
+
+#[test]
+fn consumers_split_the_shards_and_hand_them_over() {
+    let network = TestNetwork::new(1).unwrap();
+    let shards = Shards::new("orders", 32).unwrap();
+    let mut consumers = vec![
+        network.node(0).subscribe_shards(&shards, "g").unwrap(),
+        network.node(0).subscribe_shards(&shards, "g").unwrap(),
+    ];
+    settle(&shards, &consumers);
+    assert!(consumers
+        .iter()
+        .all(|consumer| !consumer.owned().is_empty()));
+
+    consumers.pop();
+    settle(&shards, &consumers);
+}
+
+#[test]
+fn consumers_get_the_messages_of_their_shards() {
+    let mut network = TestNetwork::new(2).unwrap();
+    network.connect_all().unwrap();
+    let shards = Shards::new("payments", 4).unwrap();
+    let mut consumers = vec![
+        network.node(1).subscribe_shards(&shards, "g").unwrap(),
+        network.node(1).subscribe_shards(&shards, "g").unwrap(),
+    ];
+    settle(&shards, &consumers);
+    // Have the publisher learn of the subscriptions to every shard.
+    let _meshes: Vec<_> = shards
+        .shard_topics()
+        .map(|topic| task::block_on(network.join(&topic)).unwrap())
+        .collect();
+
+    let keys: Vec<String> = (0..8).map(|i| format!("customer {}", i)).collect();
+    for key in &keys {
+        network
+            .node(0)
+            .publish_sharded(&shards, key.as_bytes(), key.clone())
+            .unwrap();
+    }
+    task::block_on(async {
+        for _ in &keys {
+            let next = consumers.iter_mut().map(|consumer| consumer.next());
+            let (message, index, _) = timeout(TIMEOUT, future::select_all(next))
+                .await
+                .expect("messages not delivered in time");
+            let message = message.unwrap();
+            let shard = shards.shard_of(&message.data);
+            assert_eq!(message.topic, shards.shard_topic(shard));
+            assert!(consumers[index].owned().contains(&shard));
+        }
+    });
+}
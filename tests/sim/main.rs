@@ -0,0 +1,352 @@
+//! Soak test of the mesh under churn, for the regressions of gossip configuration changes that
+//! only show across many nodes.
+//!
+//! Run with `cargo test --test sim`. The harness spawns a network of in-process nodes, each
+//! connected to a few others at random, all subscribed to one topic. Every round then stops some
+//! running nodes and restarts some stopped ones, waits for the meshes to converge, publishes
+//! messages from running nodes drawn at random, and checks that:
+//!
+//! - within [`TIMEOUT`], the mesh of every running node holds as many of its running neighbours
+//!   as gossipsub grafts,
+//! - every message reaches at least `SIM_MIN_DELIVERY` of the running nodes connected to its
+//!   publisher, all of them by default,
+//! - no subscription receives a message twice, or one that was never published.
+//!
+//! The size of the run is set by the environment:
+//!
+//! ```text
+//! SIM_SEED=1              seed of the run, `random` for a new one every run, printed first
+//! SIM_NODES=24            nodes of the network
+//! SIM_DEGREE=3            connections each node makes
+//! SIM_ROUNDS=8            rounds of churn
+//! SIM_MESSAGES=20         messages published every round
+//! SIM_CHURN=0.15          probability that a node leaves, or comes back, every round
+//! SIM_DROP=0              probability that a node drops a frame it receives, see `chaos`
+//! SIM_MIN_DELIVERY=1      share of the messages that must be delivered
+//! ```
+//!
+//! Gossipsub announces a subscription once, when it connects to a peer, so dropped frames can
+//! keep meshes from ever converging: runs with `SIM_DROP` want a lower `SIM_MIN_DELIVERY` and
+//! may still fail to converge.
+//!
+//! The network, churn, publishers and dropped frames are drawn from the seed, so that a failing
+//! run can be repeated by setting `SIM_SEED` to its seed, as far as the timing of the nodes
+//! allows. The seed is fixed unless set, for `cargo test` to run the same network every time.
+
+use async_std::{future::timeout, task};
+use futures::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use rust_crdt::{
+    chaos::{ChaosConfig, Faults},
+    stats::MeshRole,
+    testing::{test_config, TestNetwork, TIMEOUT},
+    Error, NodeConfig, Subscription,
+};
+use std::{
+    collections::{BTreeSet, HashSet},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+const TOPIC: &str = "sim";
+
+/// Size of a run, see the [module documentation](self).
+struct Sim {
+    seed: u64,
+    nodes: usize,
+    degree: usize,
+    rounds: usize,
+    messages: usize,
+    churn: f64,
+    drop: f64,
+    min_delivery: f64,
+}
+
+impl Sim {
+    fn from_env() -> Result<Self, Error> {
+        let seed = match std::env::var("SIM_SEED").as_deref() {
+            Ok("random") => {
+                let mut seed = [0; 8];
+                SystemRandom::new()
+                    .fill(&mut seed)
+                    .map_err(|_| "no randomness to seed the run with")?;
+                u64::from_le_bytes(seed)
+            }
+            _ => var("SIM_SEED", 1)?,
+        };
+        let sim = Sim {
+            seed,
+            nodes: var("SIM_NODES", 24)?,
+            degree: var("SIM_DEGREE", 3)?,
+            rounds: var("SIM_ROUNDS", 8)?,
+            messages: var("SIM_MESSAGES", 20)?,
+            churn: var("SIM_CHURN", 0.15)?,
+            drop: var("SIM_DROP", 0.0)?,
+            min_delivery: var("SIM_MIN_DELIVERY", 1.0)?,
+        };
+        if sim.nodes < 2 || sim.degree == 0 {
+            return Err("the network needs at least 2 nodes and 1 connection each".into());
+        }
+        Ok(sim)
+    }
+
+    /// Configuration of the node at `index`, dropping frames as `SIM_DROP` tells.
+    fn config(&self, index: usize) -> NodeConfig {
+        let mut config = test_config(index);
+        if self.drop > 0.0 {
+            config.chaos = Some(ChaosConfig {
+                inbound: Faults {
+                    drop: self.drop,
+                    ..Faults::default()
+                },
+                seed: Some(self.seed ^ index as u64),
+                ..ChaosConfig::default()
+            });
+        }
+        config
+    }
+}
+
+/// The value of the variable `name`, `default` if unset.
+fn var<T: FromStr>(name: &str, default: T) -> Result<T, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value.parse().map_err(|e| format!("{}: {}", name, e).into()),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Xorshift generator the run is drawn from, as faults are in `chaos`.
+struct Rng(u64);
+
+impl Rng {
+    /// A number drawn uniformly from `[0, 1)`.
+    fn draw(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A number drawn uniformly from `[0, n)`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.draw() * n as f64) as usize
+    }
+}
+
+/// The network with the subscription of every running node, and what the harness knows of it.
+struct State {
+    network: TestNetwork,
+    subscriptions: Vec<Option<Subscription>>,
+    /// Indexes of the nodes each node was connected to.
+    links: Vec<BTreeSet<usize>>,
+    /// Payloads received by the subscription of every node since it last started.
+    received: Vec<HashSet<Vec<u8>>>,
+    /// Payloads published so far.
+    published: HashSet<Vec<u8>>,
+}
+
+impl State {
+    fn running(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.links.len()).filter(move |&index| self.network.is_running(index))
+    }
+
+    /// The running nodes `index` reaches through running nodes, itself included.
+    fn component(&self, index: usize) -> BTreeSet<usize> {
+        let mut component = BTreeSet::new();
+        let mut next = vec![index];
+        while let Some(index) = next.pop() {
+            if component.insert(index) {
+                next.extend(
+                    self.links[index]
+                        .iter()
+                        .filter(|&&to| self.network.is_running(to)),
+                );
+            }
+        }
+        component
+    }
+}
+
+fn main() -> Result<(), Error> {
+    env_logger::init();
+    let sim = Sim::from_env()?;
+    println!("sim: seed {}", sim.seed);
+    let started = Instant::now();
+    if let Err(e) = task::block_on(run(&sim)) {
+        eprintln!("sim: failed with seed {}: {}", sim.seed, e);
+        return Err(e);
+    }
+    println!("sim: passed in {:.1?}", started.elapsed());
+    Ok(())
+}
+
+async fn run(sim: &Sim) -> Result<(), Error> {
+    let mut rng = Rng(sim.seed.max(1));
+    let mut network = TestNetwork::with_config(sim.nodes, |index| sim.config(index))?;
+
+    // A random tree keeping the network in one piece, and more connections at random.
+    let mut links = vec![BTreeSet::new(); sim.nodes];
+    for from in 0..sim.nodes {
+        let mut targets = Vec::new();
+        if from > 0 {
+            targets.push(rng.below(from));
+        }
+        for _ in 1..sim.degree {
+            targets.push(rng.below(sim.nodes));
+        }
+        for to in targets {
+            if to != from && links[from].insert(to) {
+                links[to].insert(from);
+                network.connect(from, to)?;
+            }
+        }
+    }
+    let subscriptions = network.join(TOPIC).await?;
+    let mut state = State {
+        subscriptions: subscriptions.into_iter().map(Some).collect(),
+        received: vec![HashSet::new(); sim.nodes],
+        published: HashSet::new(),
+        network,
+        links,
+    };
+
+    for round in 0..sim.rounds {
+        let (left, came_back) = churn(sim, &mut state, &mut rng)?;
+        let converged = converge(&state).await?;
+        let (delivered, expected) = publish(sim, &mut state, &mut rng, round).await?;
+        println!(
+            "sim: round {}: {} running, {} left, {} came back, converged in {:.1?}, \
+             delivered {}/{}",
+            round,
+            state.running().count(),
+            left,
+            came_back,
+            converged,
+            delivered,
+            expected
+        );
+        if (delivered as f64) < sim.min_delivery * expected as f64 {
+            return Err(format!(
+                "round {} delivered {} of {} messages, below {}",
+                round, delivered, expected, sim.min_delivery
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Stop running nodes and restart stopped ones at random, keeping at least half of the network
+/// running. Returns how many left and came back.
+fn churn(sim: &Sim, state: &mut State, rng: &mut Rng) -> Result<(usize, usize), Error> {
+    let (mut left, mut came_back) = (0, 0);
+    for index in 0..sim.nodes {
+        if rng.draw() >= sim.churn {
+            continue;
+        }
+        if !state.network.is_running(index) {
+            state.network.restart(index, sim.config(index))?;
+            state.subscriptions[index] = Some(state.network.node(index).subscribe(TOPIC)?);
+            state.received[index].clear();
+            came_back += 1;
+        } else if state.running().count() > sim.nodes / 2 {
+            state.subscriptions[index] = None;
+            state.network.stop(index);
+            left += 1;
+        }
+    }
+    Ok((left, came_back))
+}
+
+/// Wait until the mesh of every running node holds its running neighbours, up to `mesh_n_low`,
+/// returning how long it took.
+async fn converge(state: &State) -> Result<Duration, Error> {
+    let started = Instant::now();
+    loop {
+        let mut lacking = Vec::new();
+        for index in state.running() {
+            let neighbours = state.links[index]
+                .iter()
+                .filter(|&&to| state.network.is_running(to))
+                .count();
+            let wanted = neighbours.min(test_config(index).gossipsub.mesh_n_low);
+            let peers = state.network.node(index).list_peers(TOPIC).await?;
+            let mesh = peers.iter().filter(|peer| peer.role == MeshRole::Mesh);
+            if mesh.count() < wanted {
+                lacking.push(index);
+            }
+        }
+        if lacking.is_empty() {
+            return Ok(started.elapsed());
+        }
+        if started.elapsed() > TIMEOUT {
+            return Err(format!("meshes of nodes {:?} did not converge", lacking).into());
+        }
+        task::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Publish the messages of `round` from running nodes at random and receive them, returning how
+/// many deliveries were made and expected.
+async fn publish(
+    sim: &Sim,
+    state: &mut State,
+    rng: &mut Rng,
+    round: usize,
+) -> Result<(usize, usize), Error> {
+    let running: Vec<usize> = state.running().collect();
+    // Payloads every node is expected to receive.
+    let mut expected = vec![HashSet::new(); sim.nodes];
+    for n in 0..sim.messages {
+        let publisher = running[rng.below(running.len())];
+        let payload = format!("round {} message {} from {}", round, n, publisher).into_bytes();
+        state
+            .network
+            .node(publisher)
+            .publish(TOPIC, payload.clone())?;
+        state.published.insert(payload.clone());
+        // The publisher does not deliver its own messages.
+        for index in state.component(publisher) {
+            if index != publisher {
+                expected[index].insert(payload.clone());
+            }
+        }
+    }
+
+    let deadline = Instant::now() + TIMEOUT;
+    let mut delivered = 0;
+    for index in running {
+        let subscription = state.subscriptions[index].as_mut().expect("running");
+        let received = &mut state.received[index];
+        let mut missing = expected[index].clone();
+        while !missing.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let message =
+                match timeout(left.max(Duration::from_millis(1)), subscription.next()).await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => return Err(format!("subscription of node {} ended", index).into()),
+                    Err(_) => break,
+                };
+            let payload = message.data.to_vec();
+            if !state.published.contains(&payload) {
+                return Err(format!("node {} received a message never published", index).into());
+            }
+            if !received.insert(payload.clone()) {
+                return Err(format!(
+                    "node {} received {:?} twice",
+                    index,
+                    String::from_utf8_lossy(&payload)
+                )
+                .into());
+            }
+            // Late messages of earlier rounds do not count.
+            missing.remove(&payload);
+        }
+        delivered += expected[index].len() - missing.len();
+    }
+    let expected = expected.iter().map(HashSet::len).sum();
+    Ok((delivered, expected))
+}